@@ -6,14 +6,16 @@
 // - Code generation (AST → Rust session types)
 // - Effect interpretation (effect algebra execution)
 
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{black_box, criterion_group, BenchmarkId, Criterion};
 use quote::format_ident;
 use rumpsteak_choreography::{
     ast::*,
     compiler::{codegen::generate_session_type, projection::project},
     effects::{interpret, NoOpHandler, Program},
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+mod profiling;
 
 // Helper to create a simple choreography for benchmarking
 fn create_simple_choreography() -> Choreography {
@@ -43,6 +45,10 @@ fn create_simple_choreography() -> Choreography {
             }),
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     }
 }
 
@@ -71,6 +77,7 @@ fn create_complex_choreography() -> Choreography {
                         Branch {
                             label: format_ident!("Accept"),
                             guard: None,
+                            weight: None,
                             protocol: Protocol::Send {
                                 from: bob.clone(),
                                 to: charlie.clone(),
@@ -85,6 +92,7 @@ fn create_complex_choreography() -> Choreography {
                         Branch {
                             label: format_ident!("Reject"),
                             guard: None,
+                            weight: None,
                             protocol: Protocol::Send {
                                 from: bob.clone(),
                                 to: alice.clone(),
@@ -101,6 +109,10 @@ fn create_complex_choreography() -> Choreography {
             }),
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     }
 }
 
@@ -270,6 +282,10 @@ fn bench_scaling(c: &mut Criterion) {
             roles: vec![alice, bob],
             protocol,
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         group.bench_with_input(
@@ -285,14 +301,12 @@ fn bench_scaling(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(
-    benches,
-    bench_projection,
-    bench_analysis,
-    bench_codegen,
-    bench_effects,
-    bench_validation,
-    bench_scaling
-);
-
-criterion_main!(benches);
+criterion_group! {
+    name = benches;
+    config = profiling::criterion();
+    targets = bench_projection, bench_analysis, bench_codegen, bench_effects, bench_validation, bench_scaling
+}
+
+fn main() {
+    profiling::main(&[benches]);
+}
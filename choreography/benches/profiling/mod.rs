@@ -0,0 +1,68 @@
+// Optional profilers for the benchmarks
+//
+// With `--features profiling`, running a benchmark with `--profile-time
+// <seconds>` writes flamegraph.svg for it under
+// target/criterion/<benchmark>/profile. With `--features dhat-heap`, every
+// allocation is recorded to dhat-heap.json, which dhat's viewer opens.
+
+use criterion::Criterion;
+
+#[cfg(feature = "dhat-heap")]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+/// Samples per second taken while profiling a benchmark
+#[cfg(feature = "profiling")]
+const FREQUENCY: i32 = 997;
+
+/// Criterion profiler that samples with pprof and writes a flamegraph
+#[cfg(feature = "profiling")]
+#[derive(Default)]
+struct Flamegraph {
+    guard: Option<pprof::ProfilerGuard<'static>>,
+}
+
+#[cfg(feature = "profiling")]
+impl criterion::profiler::Profiler for Flamegraph {
+    fn start_profiling(&mut self, _benchmark_id: &str, _benchmark_dir: &std::path::Path) {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .expect("profiler failed to start");
+        self.guard = Some(guard);
+    }
+
+    fn stop_profiling(&mut self, _benchmark_id: &str, benchmark_dir: &std::path::Path) {
+        let Some(guard) = self.guard.take() else {
+            return;
+        };
+        let report = guard.report().build().expect("profiler failed to report");
+        std::fs::create_dir_all(benchmark_dir).expect("cannot create profile directory");
+        let file = std::fs::File::create(benchmark_dir.join("flamegraph.svg"))
+            .expect("cannot create flamegraph");
+        report.flamegraph(file).expect("cannot write flamegraph");
+    }
+}
+
+/// Criterion set up with the profilers that are enabled
+pub fn criterion() -> Criterion {
+    #[cfg(feature = "profiling")]
+    {
+        Criterion::default().with_profiler(Flamegraph::default())
+    }
+    #[cfg(not(feature = "profiling"))]
+    Criterion::default()
+}
+
+/// Run benchmark groups as `criterion_main!` does, recording allocations
+/// while they run when `dhat-heap` is enabled
+pub fn main(groups: &[fn()]) {
+    #[cfg(feature = "dhat-heap")]
+    let _heap = dhat::Profiler::new_heap();
+
+    for group in groups {
+        group();
+    }
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -1,13 +1,15 @@
 // Performance benchmarks for RumpsteakHandler
 
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use criterion::{black_box, criterion_group, BenchmarkId, Criterion, Throughput};
 use rumpsteak_choreography::effects::{
     handlers::rumpsteak::{RumpsteakEndpoint, RumpsteakHandler, SimpleChannel},
-    ChoreoHandler, Label,
+    Batching, ChoreoHandler, Label,
 };
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
 
+mod profiling;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 enum BenchRole {
     Alice,
@@ -157,6 +159,54 @@ fn bench_sequential_messages(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_batched_sequential_messages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batched_sequential_messages");
+
+    for count in [10, 50, 100].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(count), count, |b, &count| {
+            b.iter(|| {
+                let rt = Runtime::new().unwrap();
+                rt.block_on(async {
+                    // Setup
+                    let mut alice_ep = RumpsteakEndpoint::new(BenchRole::Alice);
+                    let mut bob_ep = RumpsteakEndpoint::new(BenchRole::Bob);
+
+                    let (alice_ch, bob_ch) = SimpleChannel::pair();
+                    alice_ep.register_channel(BenchRole::Bob, alice_ch);
+                    bob_ep.register_channel(BenchRole::Alice, bob_ch);
+
+                    let mut alice_handler =
+                        Batching::new(RumpsteakHandler::<BenchRole, BenchMessage>::new());
+                    let mut bob_handler =
+                        Batching::new(RumpsteakHandler::<BenchRole, BenchMessage>::new());
+
+                    // Benchmark - the same messages, sent in batches
+                    let msg = BenchMessage {
+                        data: vec![0u8; 1024],
+                    };
+
+                    for _ in 0..count {
+                        alice_handler
+                            .send(&mut alice_ep, BenchRole::Bob, black_box(&msg))
+                            .await
+                            .unwrap();
+                    }
+                    alice_handler.flush(&mut alice_ep).await.unwrap();
+
+                    for _ in 0..count {
+                        let _received: BenchMessage = bob_handler
+                            .recv(&mut bob_ep, BenchRole::Alice)
+                            .await
+                            .unwrap();
+                    }
+                })
+            });
+        });
+    }
+
+    group.finish();
+}
+
 fn bench_metadata_tracking_overhead(c: &mut Criterion) {
     c.bench_function("metadata_tracking", |b| {
         b.iter(|| {
@@ -198,11 +248,12 @@ fn bench_metadata_tracking_overhead(c: &mut Criterion) {
     });
 }
 
-criterion_group!(
-    benches,
-    bench_send_recv_throughput,
-    bench_choice_overhead,
-    bench_sequential_messages,
-    bench_metadata_tracking_overhead
-);
-criterion_main!(benches);
+criterion_group! {
+    name = benches;
+    config = profiling::criterion();
+    targets = bench_send_recv_throughput, bench_choice_overhead, bench_sequential_messages, bench_batched_sequential_messages, bench_metadata_tracking_overhead
+}
+
+fn main() {
+    profiling::main(&[benches]);
+}
@@ -0,0 +1,132 @@
+// Throughput benchmarks for SessionExecutor
+//
+// Runs many short ping-pong sessions, once on a SessionExecutor and once
+// with one tokio task per session, so the two can be compared directly.
+
+use criterion::{criterion_group, BenchmarkId, Criterion, Throughput};
+use rumpsteak_choreography::effects::{
+    handlers::rumpsteak::{RumpsteakEndpoint, RumpsteakHandler, SimpleChannel},
+    ChoreoHandler,
+};
+use rumpsteak_choreography::runtime::{ExecutorConfig, SessionExecutor};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+mod profiling;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum BenchRole {
+    Alice,
+    Bob,
+}
+
+impl rumpsteak_aura::Role for BenchRole {
+    type Message = BenchMessage;
+
+    fn seal(&mut self) {}
+    fn is_sealed(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct BenchMessage {
+    seq: u32,
+}
+
+impl rumpsteak_aura::Message<Box<dyn std::any::Any + Send>> for BenchMessage {
+    fn upcast(msg: Box<dyn std::any::Any + Send>) -> Self {
+        *msg.downcast::<BenchMessage>().unwrap()
+    }
+
+    fn downcast(self) -> Result<Box<dyn std::any::Any + Send>, Self> {
+        Ok(Box::new(self))
+    }
+}
+
+const ROUNDS: u32 = 10;
+
+/// Both sides of a ping-pong session, run together
+async fn ping_pong() {
+    let mut alice_ep = RumpsteakEndpoint::new(BenchRole::Alice);
+    let mut bob_ep = RumpsteakEndpoint::new(BenchRole::Bob);
+    let (alice_ch, bob_ch) = SimpleChannel::pair();
+    alice_ep.register_channel(BenchRole::Bob, alice_ch);
+    bob_ep.register_channel(BenchRole::Alice, bob_ch);
+    let mut alice = RumpsteakHandler::<BenchRole, BenchMessage>::new();
+    let mut bob = RumpsteakHandler::<BenchRole, BenchMessage>::new();
+
+    let alice_side = async {
+        for seq in 0..ROUNDS {
+            alice
+                .send(&mut alice_ep, BenchRole::Bob, &BenchMessage { seq })
+                .await
+                .unwrap();
+            let _: BenchMessage = alice.recv(&mut alice_ep, BenchRole::Bob).await.unwrap();
+        }
+    };
+    let bob_side = async {
+        for _ in 0..ROUNDS {
+            let msg: BenchMessage = bob.recv(&mut bob_ep, BenchRole::Alice).await.unwrap();
+            bob.send(&mut bob_ep, BenchRole::Alice, &msg).await.unwrap();
+        }
+    };
+    futures::join!(alice_side, bob_side);
+}
+
+fn bench_concurrent_sessions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_sessions");
+    group.sample_size(10);
+    let rt = Runtime::new().unwrap();
+
+    for sessions in [1_000u64, 10_000].iter() {
+        group.throughput(Throughput::Elements(*sessions));
+
+        group.bench_with_input(
+            BenchmarkId::new("executor", sessions),
+            sessions,
+            |b, &sessions| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        let executor = SessionExecutor::new(ExecutorConfig::new());
+                        let handles: Vec<_> = (0..sessions)
+                            .map(|_| executor.submit(ping_pong()))
+                            .collect();
+                        for handle in handles {
+                            handle.await.unwrap();
+                        }
+                        executor.shutdown().await;
+                    })
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("task_per_session", sessions),
+            sessions,
+            |b, &sessions| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        let handles: Vec<_> =
+                            (0..sessions).map(|_| tokio::spawn(ping_pong())).collect();
+                        for handle in handles {
+                            handle.await.unwrap();
+                        }
+                    })
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = profiling::criterion();
+    targets = bench_concurrent_sessions
+}
+
+fn main() {
+    profiling::main(&[benches]);
+}
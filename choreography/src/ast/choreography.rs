@@ -2,7 +2,86 @@
 
 use super::*;
 use proc_macro2::Ident;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+/// Annotation for roles that may receive payload fields tagged `@sensitive`
+pub const TRUSTED: &str = "trusted";
+
+/// Annotation selecting how message payloads are encoded: `@wire(protobuf)`
+pub const WIRE: &str = "wire";
+
+/// Annotation choosing what offers do with labels they do not know:
+/// `@unknown_labels(reject)`, `@unknown_labels(skip)`, or a branch label to
+/// fall back to, as in `@unknown_labels(Other)`
+pub const UNKNOWN_LABELS: &str = "unknown_labels";
+
+/// Annotation capping the encoded size of each message: `@max_size(1MB)`
+pub const MAX_SIZE: &str = "max_size";
+
+/// A `returns Message at Role` declaration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Return {
+    pub role: Role,
+    pub message: Ident,
+}
+
+/// A message sent with `A -> route(B, C): Message`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub from: Role,
+    pub message: Ident,
+    /// Roles the sender picks from, in the order written
+    pub candidates: Vec<Role>,
+}
+
+/// Parse a byte count such as `512`, `64KB`, `1MB` or `2GB`
+///
+/// Units are powers of 1024. Returns `None` for anything else, or for a
+/// count that does not fit in a `usize`.
+pub fn parse_size(text: &str) -> Option<usize> {
+    let digits = text.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let scale: usize = match &text[digits.len()..] {
+        "" | "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        _ => return None,
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<usize>().ok()?.checked_mul(scale)
+}
+
+/// Encoding of message payloads in generated code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// Payloads derive serde and are encoded by the handler, with bincode
+    /// for most of them
+    #[default]
+    Bincode,
+    /// Payloads are prost types, encoded as protobuf inside the handler's
+    /// framing
+    Protobuf,
+}
+
+impl WireFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bincode" => Some(WireFormat::Bincode),
+            "protobuf" => Some(WireFormat::Protobuf),
+            _ => None,
+        }
+    }
+
+    /// The name `@wire(...)` takes for this format
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireFormat::Bincode => "bincode",
+            WireFormat::Protobuf => "protobuf",
+        }
+    }
+}
 
 /// A complete choreographic protocol specification
 #[derive(Debug, Clone)]
@@ -15,11 +94,30 @@ pub struct Choreography {
     pub protocol: Protocol,
     /// Metadata and attributes
     pub attrs: HashMap<String, String>,
+    /// Messages declared with `returns Message at Role`
+    pub returns: Vec<Return>,
+    /// Messages sent with `route`, each listed once
+    pub routes: Vec<Route>,
+    /// Wire ids given to messages and labels with `id Name = n`
+    pub wire_ids: BTreeMap<Ident, u32>,
+    /// Roles marked `@trusted`, either on their declaration
+    /// (`roles: Shopper, @trusted Shop`) or with a choreography-level
+    /// `@trusted(Shop, Audit)`
+    pub trusted: Vec<Role>,
 }
 
 impl Choreography {
-    /// Validate the choreography for correctness
+    /// Validate the choreography for correctness, within the default
+    /// [`ProtocolLimits`]
     pub fn validate(&self) -> Result<(), ValidationError> {
+        self.validate_with_limits(&ProtocolLimits::default())
+    }
+
+    /// Validate the choreography, rejecting it first if it is larger than
+    /// `limits` allow
+    pub fn validate_with_limits(&self, limits: &ProtocolLimits) -> Result<(), ValidationError> {
+        self.check_limits(limits)?;
+
         // Check all roles are used
         for role in &self.roles {
             if !self.protocol.mentions_role(role) {
@@ -32,4 +130,149 @@ impl Choreography {
 
         Ok(())
     }
+
+    /// Check the choreography against `limits`
+    ///
+    /// This does not recurse, so it is safe on protocols too deep for the
+    /// other passes.
+    pub fn check_limits(&self, limits: &ProtocolLimits) -> Result<(), ValidationError> {
+        // A declared `Worker[8]` carries its size as the index, and one
+        // sized by a constant as the parameter
+        let roles = self.roles.iter().fold(0usize, |count, role| {
+            let instances = role
+                .array_size
+                .as_ref()
+                .or(role.param.as_ref())
+                .map(|size| size.to_string().parse().unwrap_or(1))
+                .or(role.index)
+                .unwrap_or(1);
+            count.saturating_add(instances)
+        });
+        if roles > limits.max_roles {
+            return Err(ValidationError::TooManyRoles {
+                count: roles,
+                limit: limits.max_roles,
+            });
+        }
+
+        let shape = self.protocol.shape();
+        if let Some((choice, count)) = shape.widest {
+            if count > limits.max_branches {
+                return Err(ValidationError::TooManyBranches {
+                    choice,
+                    count,
+                    limit: limits.max_branches,
+                });
+            }
+        }
+        if shape.depth > limits.max_depth {
+            return Err(ValidationError::TooDeep {
+                depth: shape.depth,
+                limit: limits.max_depth,
+            });
+        }
+        Ok(())
+    }
+
+    /// The message `role` returns, if it declares one
+    pub fn returned_by(&self, role: &Role) -> Option<&Ident> {
+        self.returns
+            .iter()
+            .find(|r| r.role.name == role.name)
+            .map(|r| &r.message)
+    }
+
+    /// Payload encoding chosen with `@wire(...)`, bincode by default
+    pub fn wire_format(&self) -> WireFormat {
+        self.attrs
+            .get(WIRE)
+            .and_then(|name| WireFormat::from_name(name))
+            .unwrap_or_default()
+    }
+
+    /// Largest encoded message in bytes allowed by `@max_size(...)`, if the
+    /// choreography sets one
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.attrs.get(MAX_SIZE).and_then(|size| parse_size(size))
+    }
+
+    /// The `@unknown_labels(...)` policy, if the choreography sets one
+    pub fn unknown_labels(&self) -> Option<&str> {
+        self.attrs.get(UNKNOWN_LABELS).map(String::as_str)
+    }
+
+    /// Whether `role` may receive sensitive fields; every instance of a
+    /// trusted role array is trusted
+    pub fn is_trusted(&self, role: &Role) -> bool {
+        self.trusted.iter().any(|trusted| trusted.name == role.name)
+    }
+
+    /// The child sessions this choreography spawns, by handle
+    ///
+    /// Each child is a choreography of its own, named after the protocol it
+    /// runs, and is projected separately. Children spawned by a child are
+    /// found through that child.
+    pub fn children(&self) -> Vec<(Ident, Choreography)> {
+        let mut children = Vec::new();
+        collect_children(&self.protocol, &mut children);
+        children
+    }
+}
+
+fn collect_children(protocol: &Protocol, children: &mut Vec<(Ident, Choreography)>) {
+    match protocol {
+        Protocol::Send { continuation, .. }
+        | Protocol::Broadcast { continuation, .. }
+        | Protocol::Await { continuation, .. } => collect_children(continuation, children),
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+            for branch in branches {
+                collect_children(&branch.protocol, children);
+            }
+        }
+        Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => {
+            collect_children(body, children)
+        }
+        Protocol::Parallel { protocols } => {
+            for p in protocols {
+                collect_children(p, children);
+            }
+        }
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => {
+            collect_children(body, children);
+            collect_children(cleanup, children);
+        }
+        Protocol::Spawn {
+            handle,
+            name,
+            roles,
+            body,
+            continuation,
+        } => {
+            children.push((
+                handle.clone(),
+                Choreography {
+                    name: name.clone(),
+                    roles: roles.clone(),
+                    protocol: (**body).clone(),
+                    attrs: HashMap::new(),
+                    returns: Vec::new(),
+                    routes: Vec::new(),
+                    wire_ids: BTreeMap::new(),
+                    trusted: Vec::new(),
+                },
+            ));
+            collect_children(continuation, children);
+        }
+        Protocol::Var(_) | Protocol::End => {}
+    }
 }
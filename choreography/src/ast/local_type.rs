@@ -35,6 +35,12 @@ pub enum LocalType {
     /// Local choice (decision without communication)
     LocalChoice { branches: Vec<(Ident, LocalType)> },
 
+    /// Selective receive over the arms of a race, in priority order
+    ///
+    /// Each arm starts with the send this role may start it with, or the
+    /// receive that tells it the arm was started by a peer.
+    Race { branches: Vec<(Ident, LocalType)> },
+
     /// Loop construct
     Loop {
         condition: Option<super::protocol::Condition>,
@@ -47,6 +53,33 @@ pub enum LocalType {
     /// Variable (reference to recursive type)
     Var(Ident),
 
+    /// Body followed by a cleanup that runs on every exit path
+    Finally {
+        body: Box<LocalType>,
+        cleanup: Box<LocalType>,
+    },
+
+    /// Body whose compensation runs if it fails
+    ///
+    /// `notify` are the peers this role tells when the failure is its own.
+    TryCatch {
+        body: Box<LocalType>,
+        notify: Vec<Role>,
+        compensation: Box<LocalType>,
+    },
+
+    /// Body this role gives `duration` to finish, with what it does if
+    /// the time runs out
+    ///
+    /// `notify` are the peers it tells when it gives up. Peers see the block
+    /// as a `TryCatch` whose compensation is their part of `on_timeout`.
+    Timeout {
+        duration: std::time::Duration,
+        body: Box<LocalType>,
+        notify: Vec<Role>,
+        on_timeout: Box<LocalType>,
+    },
+
     /// Type termination
     End,
 }
@@ -67,7 +100,7 @@ impl LocalType {
             LocalType::Branch { branches, .. } => branches
                 .iter()
                 .all(|(_, ty)| ty.check_well_formed(rec_vars)),
-            LocalType::LocalChoice { branches } => branches
+            LocalType::LocalChoice { branches } | LocalType::Race { branches } => branches
                 .iter()
                 .all(|(_, ty)| ty.check_well_formed(rec_vars)),
             LocalType::Loop { body, .. } => body.check_well_formed(rec_vars),
@@ -78,7 +111,95 @@ impl LocalType {
                 result
             }
             LocalType::Var(label) => rec_vars.contains(label),
+            LocalType::Finally { body, cleanup } => {
+                body.check_well_formed(rec_vars) && cleanup.check_well_formed(rec_vars)
+            }
+            LocalType::TryCatch {
+                body, compensation, ..
+            } => body.check_well_formed(rec_vars) && compensation.check_well_formed(rec_vars),
+            LocalType::Timeout {
+                body, on_timeout, ..
+            } => body.check_well_formed(rec_vars) && on_timeout.check_well_formed(rec_vars),
             LocalType::End => true,
         }
     }
+
+    /// Sequence `next` after this type by replacing its terminal `End`s
+    ///
+    /// Loops are left untouched since their body's `End` marks the end of an
+    /// iteration rather than the end of the protocol.
+    pub fn then(self, next: LocalType) -> LocalType {
+        match self {
+            LocalType::Send {
+                to,
+                message,
+                continuation,
+            } => LocalType::Send {
+                to,
+                message,
+                continuation: Box::new(continuation.then(next)),
+            },
+            LocalType::Receive {
+                from,
+                message,
+                continuation,
+            } => LocalType::Receive {
+                from,
+                message,
+                continuation: Box::new(continuation.then(next)),
+            },
+            LocalType::Select { to, branches } => LocalType::Select {
+                to,
+                branches: then_branches(branches, &next),
+            },
+            LocalType::Branch { from, branches } => LocalType::Branch {
+                from,
+                branches: then_branches(branches, &next),
+            },
+            LocalType::LocalChoice { branches } => LocalType::LocalChoice {
+                branches: then_branches(branches, &next),
+            },
+            LocalType::Race { branches } => LocalType::Race {
+                branches: then_branches(branches, &next),
+            },
+            LocalType::Rec { label, body } => LocalType::Rec {
+                label,
+                body: Box::new(body.then(next)),
+            },
+            LocalType::Finally { body, cleanup } => LocalType::Finally {
+                body,
+                cleanup: Box::new(cleanup.then(next)),
+            },
+            // Both ways out of the try carry on with `next`
+            LocalType::TryCatch {
+                body,
+                notify,
+                compensation,
+            } => LocalType::TryCatch {
+                body: Box::new(body.then(next.clone())),
+                notify,
+                compensation: Box::new(compensation.then(next)),
+            },
+            LocalType::Timeout {
+                duration,
+                body,
+                notify,
+                on_timeout,
+            } => LocalType::Timeout {
+                duration,
+                body: Box::new(body.then(next.clone())),
+                notify,
+                on_timeout: Box::new(on_timeout.then(next)),
+            },
+            LocalType::End => next,
+            other @ (LocalType::Loop { .. } | LocalType::Var(_)) => other,
+        }
+    }
+}
+
+fn then_branches(branches: Vec<(Ident, LocalType)>, next: &LocalType) -> Vec<(Ident, LocalType)> {
+    branches
+        .into_iter()
+        .map(|(label, ty)| (label, ty.then(next.clone())))
+        .collect()
 }
@@ -1,6 +1,31 @@
 //! Message type definitions for choreographic protocols
 
-use proc_macro2::{Ident, TokenStream};
+use proc_macro2::{Ident, Spacing, TokenStream, TokenTree};
+
+/// Tag marking a payload field as personal or secret data
+///
+/// Generated structs print such fields as `<redacted>` in `Debug` output,
+/// and the `Trace` middleware hides them when logging payloads.
+pub const SENSITIVE: &str = "sensitive";
+
+/// A named payload field, e.g. `card: String @sensitive`
+#[derive(Debug, Clone)]
+pub struct PayloadField {
+    pub name: Ident,
+    pub ty: TokenStream,
+    /// Tags written after the type, without the `@`
+    pub tags: Vec<Ident>,
+}
+
+impl PayloadField {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn is_sensitive(&self) -> bool {
+        self.has_tag(SENSITIVE)
+    }
+}
 
 /// Message type with optional payload
 ///
@@ -66,4 +91,101 @@ impl MessageType {
     pub fn to_ident(&self) -> Ident {
         self.name.clone()
     }
+
+    /// Payload fields, if the payload is written as `name: Type, ...`
+    pub fn fields(&self) -> Option<Vec<PayloadField>> {
+        self.payload.as_ref().and_then(payload_fields)
+    }
+
+    /// Names of the payload fields tagged `@sensitive`
+    pub fn sensitive_fields(&self) -> Vec<String> {
+        self.fields()
+            .unwrap_or_default()
+            .iter()
+            .filter(|field| field.is_sensitive())
+            .map(|field| field.name.to_string())
+            .collect()
+    }
+}
+
+/// Split a payload into named fields
+///
+/// Returns `None` unless every comma-separated part has the form
+/// `name: Type @tag ...`, so tuple payloads such as `String` or
+/// `std::string::String` are left alone.
+pub(crate) fn payload_fields(payload: &TokenStream) -> Option<Vec<PayloadField>> {
+    let mut fields = Vec::new();
+    for part in split_top_level(payload) {
+        let mut tokens = part.into_iter();
+        let name = match tokens.next() {
+            Some(TokenTree::Ident(name)) => name,
+            _ => return None,
+        };
+        match tokens.next() {
+            Some(TokenTree::Punct(colon))
+                if colon.as_char() == ':' && colon.spacing() == Spacing::Alone => {}
+            _ => return None,
+        }
+        let rest: Vec<TokenTree> = tokens.collect();
+        let type_end = rest
+            .iter()
+            .position(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == '@'))
+            .unwrap_or(rest.len());
+        if type_end == 0 {
+            return None;
+        }
+        let mut tags = Vec::new();
+        let mut tag_tokens = rest[type_end..].iter();
+        while let Some(at) = tag_tokens.next() {
+            match (at, tag_tokens.next()) {
+                (TokenTree::Punct(p), Some(TokenTree::Ident(tag))) if p.as_char() == '@' => {
+                    tags.push(tag.clone())
+                }
+                _ => return None,
+            }
+        }
+        fields.push(PayloadField {
+            name,
+            ty: rest[..type_end].iter().cloned().collect(),
+            tags,
+        });
+    }
+    (!fields.is_empty()).then_some(fields)
+}
+
+/// Whether a payload uses `@tag` anywhere outside nested groups
+pub(crate) fn has_tags(payload: &TokenStream) -> bool {
+    payload
+        .clone()
+        .into_iter()
+        .any(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == '@'))
+}
+
+/// Split on commas that are not inside `<...>` generics
+pub(crate) fn split_top_level(tokens: &TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![Vec::new()];
+    let mut depth = 0usize;
+    let mut arrow = false;
+    for token in tokens.clone() {
+        if let TokenTree::Punct(p) = &token {
+            let after_dash = std::mem::replace(
+                &mut arrow,
+                p.as_char() == '-' && p.spacing() == Spacing::Joint,
+            );
+            match p.as_char() {
+                '<' => depth += 1,
+                '>' if !after_dash => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => {
+                    parts.push(Vec::new());
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        parts.last_mut().expect("starts with one part").push(token);
+    }
+    if parts.last().is_some_and(|part| part.is_empty()) {
+        parts.pop();
+    }
+    parts
 }
@@ -18,13 +18,19 @@ pub mod protocol;
 /// Role definitions
 pub mod role;
 
+/// Ring and pipeline combinators
+pub mod topology;
+
 /// Validation errors and utilities
 pub mod validation;
 
 // Re-export core AST types explicitly for clarity
-pub use choreography::Choreography;
+pub use choreography::{
+    parse_size, Choreography, Return, Route, WireFormat, MAX_SIZE, TRUSTED, WIRE,
+};
 pub use local_type::LocalType;
-pub use message::MessageType;
+pub use message::{MessageType, PayloadField, SENSITIVE};
 pub use protocol::{Branch, Condition, Protocol};
 pub use role::Role;
-pub use validation::ValidationError;
+pub use topology::{Termination, Topology, TopologyError};
+pub use validation::{ProtocolLimits, ValidationError};
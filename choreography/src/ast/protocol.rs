@@ -19,12 +19,22 @@ pub enum Protocol {
         from: Role,
         to_all: Vec<Role>,
         message: MessageType,
+        /// Whether an `abort` statement expanded to this broadcast, so the
+        /// roles told may take it wherever they wait
+        abort: bool,
         continuation: Box<Protocol>,
     },
 
     /// Choice made by a role
     Choice { role: Role, branches: Vec<Branch> },
 
+    /// Arms that different roles may start at the same point
+    ///
+    /// Each arm starts with a send, labelled by its message name. The first
+    /// arm whose message arrives wins. If arms collide, the one listed
+    /// first wins and the losing initiator follows it.
+    Race { branches: Vec<Branch> },
+
     /// Loop construct
     Loop {
         condition: Option<Condition>,
@@ -40,6 +50,54 @@ pub enum Protocol {
     /// Reference to recursive label
     Var(Ident),
 
+    /// Protocol with a cleanup section that runs on every exit path
+    Finally {
+        body: Box<Protocol>,
+        cleanup: Box<Protocol>,
+    },
+
+    /// Protocol whose compensation runs if a role raises an error in it
+    ///
+    /// `handler_roles` are the roles that take part in the body. The role
+    /// that raises the error tells the others, and each of them then
+    /// follows its part of `compensation` instead of the rest of the body.
+    TryCatch {
+        body: Box<Protocol>,
+        handler_roles: Vec<Role>,
+        compensation: Box<Protocol>,
+    },
+
+    /// Protocol that `role` gives `duration` to finish
+    ///
+    /// If the time runs out, `role` tells the other roles of the block and
+    /// each of them follows its part of `on_timeout` instead of the rest of
+    /// the body.
+    Timeout {
+        role: Role,
+        duration: std::time::Duration,
+        body: Box<Protocol>,
+        on_timeout: Box<Protocol>,
+    },
+
+    /// Child session started by `spawn Name(A, B) as handle`
+    ///
+    /// `body` runs as a separate session between `roles`, with an id of its
+    /// own, while the parent carries on with the continuation.
+    Spawn {
+        handle: Ident,
+        name: Ident,
+        roles: Vec<Role>,
+        body: Box<Protocol>,
+        continuation: Box<Protocol>,
+    },
+
+    /// Point where the `roles` of a spawned child wait for it to finish
+    Await {
+        handle: Ident,
+        roles: Vec<Role>,
+        continuation: Box<Protocol>,
+    },
+
     /// Protocol termination
     End,
 }
@@ -49,6 +107,8 @@ pub enum Protocol {
 pub struct Branch {
     pub label: Ident,
     pub guard: Option<TokenStream>,
+    /// Relative likelihood from `@weight(...)`, used by simulation only
+    pub weight: Option<f64>,
     pub protocol: Protocol,
 }
 
@@ -63,6 +123,32 @@ pub enum Condition {
     Custom(TokenStream),
 }
 
+// TokenStream doesn't implement these traits, so custom conditions compare
+// by their string representation
+impl PartialEq for Condition {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Condition::RoleDecides(a), Condition::RoleDecides(b)) => a == b,
+            (Condition::Count(a), Condition::Count(b)) => a == b,
+            (Condition::Custom(a), Condition::Custom(b)) => a.to_string() == b.to_string(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Condition {}
+
+impl std::hash::Hash for Condition {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Condition::RoleDecides(role) => role.hash(state),
+            Condition::Count(n) => n.hash(state),
+            Condition::Custom(tokens) => tokens.to_string().hash(state),
+        }
+    }
+}
+
 impl Protocol {
     pub fn mentions_role(&self, role: &Role) -> bool {
         match self {
@@ -81,13 +167,104 @@ impl Protocol {
             Protocol::Choice { role: r, branches } => {
                 r == role || branches.iter().any(|b| b.protocol.mentions_role(role))
             }
+            Protocol::Race { branches } => branches.iter().any(|b| b.protocol.mentions_role(role)),
             Protocol::Loop { body, .. } => body.mentions_role(role),
             Protocol::Parallel { protocols } => protocols.iter().any(|p| p.mentions_role(role)),
             Protocol::Rec { body, .. } => body.mentions_role(role),
+            Protocol::Finally { body, cleanup } => {
+                body.mentions_role(role) || cleanup.mentions_role(role)
+            }
+            Protocol::TryCatch {
+                body,
+                handler_roles,
+                compensation,
+            } => {
+                handler_roles.contains(role)
+                    || body.mentions_role(role)
+                    || compensation.mentions_role(role)
+            }
+            Protocol::Timeout {
+                role: r,
+                body,
+                on_timeout,
+                ..
+            } => r == role || body.mentions_role(role) || on_timeout.mentions_role(role),
+            Protocol::Spawn {
+                roles,
+                continuation,
+                ..
+            }
+            | Protocol::Await {
+                roles,
+                continuation,
+                ..
+            } => roles.contains(role) || continuation.mentions_role(role),
             Protocol::Var(_) | Protocol::End => false,
         }
     }
 
+    /// How deeply structures nest in the protocol, and its widest choice
+    ///
+    /// Walks the tree with an explicit stack rather than recursion, so that
+    /// a protocol too deep for the other passes can still be measured.
+    pub(crate) fn shape(&self) -> Shape {
+        let mut shape = Shape::default();
+        let mut stack = vec![(self, 0)];
+        while let Some((protocol, depth)) = stack.pop() {
+            shape.depth = shape.depth.max(depth);
+            let mut widen = |choice: &dyn Fn() -> String, count: usize| {
+                if shape
+                    .widest
+                    .as_ref()
+                    .map_or(true, |(_, widest)| count > *widest)
+                {
+                    shape.widest = Some((choice(), count));
+                }
+            };
+            match protocol {
+                Protocol::Send { continuation, .. }
+                | Protocol::Broadcast { continuation, .. }
+                | Protocol::Await { continuation, .. } => stack.push((continuation, depth)),
+                Protocol::Choice { role, branches } => {
+                    widen(&|| format!("Choice at {}", role.name), branches.len());
+                    stack.extend(branches.iter().map(|b| (&b.protocol, depth + 1)));
+                }
+                Protocol::Race { branches } => {
+                    widen(&|| "Race".to_string(), branches.len());
+                    stack.extend(branches.iter().map(|b| (&b.protocol, depth + 1)));
+                }
+                Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => {
+                    stack.push((body, depth + 1))
+                }
+                Protocol::Parallel { protocols } => {
+                    stack.extend(protocols.iter().map(|p| (p, depth + 1)))
+                }
+                Protocol::Finally { body, cleanup }
+                | Protocol::TryCatch {
+                    body,
+                    compensation: cleanup,
+                    ..
+                }
+                | Protocol::Timeout {
+                    body,
+                    on_timeout: cleanup,
+                    ..
+                } => {
+                    stack.push((body, depth + 1));
+                    stack.push((cleanup, depth + 1));
+                }
+                Protocol::Spawn {
+                    body, continuation, ..
+                } => {
+                    stack.push((body, depth + 1));
+                    stack.push((continuation, depth));
+                }
+                Protocol::Var(_) | Protocol::End => {}
+            }
+        }
+        shape
+    }
+
     pub(crate) fn validate(&self, roles: &[Role]) -> Result<(), ValidationError> {
         match self {
             Protocol::Send {
@@ -124,9 +301,12 @@ impl Protocol {
                 if !roles.contains(role) {
                     return Err(ValidationError::UndefinedRole(role.name.to_string()));
                 }
-                // Validate each branch starts with the choosing role sending
+                // Validate each branch starts with the choosing role sending,
+                // as a broadcast does when the branch aborts
                 for branch in branches {
-                    if let Protocol::Send { from, .. } = &branch.protocol {
+                    if let Protocol::Send { from, .. } | Protocol::Broadcast { from, .. } =
+                        &branch.protocol
+                    {
                         if from != role {
                             return Err(ValidationError::InvalidChoice(role.name.to_string()));
                         }
@@ -136,6 +316,15 @@ impl Protocol {
                 }
                 Ok(())
             }
+            Protocol::Race { branches } => {
+                for branch in branches {
+                    if !matches!(branch.protocol, Protocol::Send { .. }) {
+                        return Err(ValidationError::InvalidRace(branch.label.to_string()));
+                    }
+                    branch.protocol.validate(roles)?;
+                }
+                Ok(())
+            }
             Protocol::Loop { body, .. } => body.validate(roles),
             Protocol::Parallel { protocols } => {
                 for p in protocols {
@@ -144,7 +333,80 @@ impl Protocol {
                 Ok(())
             }
             Protocol::Rec { body, .. } => body.validate(roles),
+            Protocol::Finally { body, cleanup } => {
+                body.validate(roles)?;
+                cleanup.validate(roles)
+            }
+            Protocol::TryCatch {
+                body,
+                handler_roles,
+                compensation,
+            } => {
+                if let Some(role) = handler_roles.iter().find(|r| !roles.contains(r)) {
+                    return Err(ValidationError::UndefinedRole(role.name.to_string()));
+                }
+                // Roles outside the body never hear about the error
+                if let Some(role) = roles
+                    .iter()
+                    .find(|r| !handler_roles.contains(r) && compensation.mentions_role(r))
+                {
+                    return Err(ValidationError::InvalidCompensation(role.name.to_string()));
+                }
+                body.validate(roles)?;
+                compensation.validate(roles)
+            }
+            Protocol::Timeout {
+                role,
+                body,
+                on_timeout,
+                ..
+            } => {
+                if !roles.contains(role) {
+                    return Err(ValidationError::UndefinedRole(role.name.to_string()));
+                }
+                // Only the roles of the body are told that the time ran out
+                if let Some(other) = roles
+                    .iter()
+                    .find(|r| *r != role && !body.mentions_role(r) && on_timeout.mentions_role(r))
+                {
+                    return Err(ValidationError::InvalidTimeout(other.name.to_string()));
+                }
+                body.validate(roles)?;
+                on_timeout.validate(roles)
+            }
+            Protocol::Spawn {
+                roles: child_roles,
+                body,
+                continuation,
+                ..
+            } => {
+                if let Some(role) = child_roles.iter().find(|r| !roles.contains(r)) {
+                    return Err(ValidationError::UndefinedRole(role.name.to_string()));
+                }
+                // The child only knows the roles it was started with
+                body.validate(child_roles)?;
+                continuation.validate(roles)
+            }
+            Protocol::Await {
+                roles: child_roles,
+                continuation,
+                ..
+            } => {
+                if let Some(role) = child_roles.iter().find(|r| !roles.contains(r)) {
+                    return Err(ValidationError::UndefinedRole(role.name.to_string()));
+                }
+                continuation.validate(roles)
+            }
             Protocol::Var(_) | Protocol::End => Ok(()),
         }
     }
 }
+
+/// Size measures of a protocol, from [`Protocol::shape`]
+#[derive(Debug, Default)]
+pub(crate) struct Shape {
+    /// Deepest nesting of structures; the top level is 0
+    pub depth: usize,
+    /// The choice or race with the most branches, and how many it has
+    pub widest: Option<(String, usize)>,
+}
@@ -111,3 +111,13 @@ impl Role {
         self.array_size.is_some()
     }
 }
+
+/// Formats as written in a choreography, e.g. `Client` or `Worker[0]`
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{}[{}]", self.name, index),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
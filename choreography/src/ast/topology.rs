@@ -0,0 +1,231 @@
+//! Combinators building whole choreographies of common shapes
+//!
+//! A ring passes a message from each role to the next and from the last back
+//! to the first; a pipeline passes one from each stage to the next and stops
+//! at the last. Either shape runs once by default, and
+//! [`Termination`] repeats it a fixed number of times, forever, or until its
+//! first role decides to stop.
+//!
+//! ```ignore
+//! use quote::format_ident;
+//! use rumpsteak_choreography::ast::topology::{self, Termination};
+//! use rumpsteak_choreography::{MessageType, Role};
+//!
+//! let roles = ["A", "B", "C"].map(|name| Role::new(format_ident!("{}", name)));
+//! let token = MessageType { name: format_ident!("Token"), type_annotation: None, payload: None };
+//! let done = MessageType { name: format_ident!("Done"), type_annotation: None, payload: None };
+//! let ring = topology::ring(roles, token)
+//!     .with_termination(Termination::Until(done))
+//!     .build(format_ident!("Ring"))?;
+//! ```
+
+use super::*;
+use proc_macro2::Ident;
+use quote::format_ident;
+use std::collections::{BTreeMap, HashMap};
+
+/// Errors building a topology
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TopologyError {
+    #[error("A {shape} needs at least 2 roles, got {count}")]
+    TooFewRoles { shape: &'static str, count: usize },
+
+    #[error("Role {0} appears more than once")]
+    DuplicateRole(String),
+
+    #[error("A {shape} of {roles} roles has {expected} hops, got {count} messages")]
+    HopCount {
+        shape: &'static str,
+        roles: usize,
+        expected: usize,
+        count: usize,
+    },
+
+    #[error("Stop message {0} is also sent on a hop, so receivers cannot tell them apart")]
+    AmbiguousStop(String),
+}
+
+/// How many times a topology passes its messages along
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Termination {
+    /// Pass the messages along once
+    #[default]
+    Once,
+    /// Repeat a fixed number of times
+    Rounds(usize),
+    /// Repeat without end, as the `ring_choice` example does
+    Forever,
+    /// Repeat until the first role chooses to send this message along
+    /// instead, with the label `Stop`; each round starts with the label
+    /// `Continue`
+    Until(MessageType),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Ring,
+    Pipeline,
+}
+
+impl Shape {
+    fn name(self) -> &'static str {
+        match self {
+            Shape::Ring => "ring",
+            Shape::Pipeline => "pipeline",
+        }
+    }
+}
+
+/// A ring or pipeline of roles, built with [`ring`] or [`pipeline`]
+#[derive(Debug, Clone)]
+pub struct Topology {
+    shape: Shape,
+    roles: Vec<Role>,
+    /// Message of each hop, the hop from `roles[i]` being at `i`
+    hops: Vec<MessageType>,
+    termination: Termination,
+}
+
+/// A ring passing `msg` from each role to the next, and from the last role
+/// back to the first
+pub fn ring(roles: impl IntoIterator<Item = Role>, msg: MessageType) -> Topology {
+    let roles: Vec<Role> = roles.into_iter().collect();
+    Topology {
+        shape: Shape::Ring,
+        hops: vec![msg; roles.len()],
+        roles,
+        termination: Termination::Once,
+    }
+}
+
+/// A pipeline passing `msgs[i]` from `roles[i]` to `roles[i + 1]`
+pub fn pipeline(
+    roles: impl IntoIterator<Item = Role>,
+    msgs: impl IntoIterator<Item = MessageType>,
+) -> Topology {
+    Topology {
+        shape: Shape::Pipeline,
+        roles: roles.into_iter().collect(),
+        hops: msgs.into_iter().collect(),
+        termination: Termination::Once,
+    }
+}
+
+impl Topology {
+    /// Send `msg` on the hop from the role at `hop` instead; hops past the
+    /// last are ignored
+    pub fn with_hop(mut self, hop: usize, msg: MessageType) -> Self {
+        if let Some(slot) = self.hops.get_mut(hop) {
+            *slot = msg;
+        }
+        self
+    }
+
+    pub fn with_termination(mut self, termination: Termination) -> Self {
+        self.termination = termination;
+        self
+    }
+
+    pub fn roles(&self) -> &[Role] {
+        &self.roles
+    }
+
+    /// The global protocol of the topology
+    pub fn protocol(&self) -> Result<Protocol, TopologyError> {
+        self.check()?;
+        let round = format_ident!("Round");
+        let protocol = match &self.termination {
+            Termination::Once => self.pass(&self.hops, Protocol::End),
+            Termination::Rounds(count) => Protocol::Loop {
+                condition: Some(Condition::Count(*count)),
+                body: Box::new(self.pass(&self.hops, Protocol::End)),
+            },
+            Termination::Forever => Protocol::Rec {
+                label: round.clone(),
+                body: Box::new(self.pass(&self.hops, Protocol::Var(round))),
+            },
+            Termination::Until(stop) => {
+                let stops = vec![stop.clone(); self.hops.len()];
+                Protocol::Rec {
+                    label: round.clone(),
+                    body: Box::new(Protocol::Choice {
+                        role: self.roles[0].clone(),
+                        branches: vec![
+                            branch("Continue", self.pass(&self.hops, Protocol::Var(round))),
+                            branch("Stop", self.pass(&stops, Protocol::End)),
+                        ],
+                    }),
+                }
+            }
+        };
+        Ok(protocol)
+    }
+
+    /// The topology as a choreography called `name`
+    pub fn build(self, name: Ident) -> Result<Choreography, TopologyError> {
+        let protocol = self.protocol()?;
+        Ok(Choreography {
+            name,
+            roles: self.roles,
+            protocol,
+            attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
+        })
+    }
+
+    fn check(&self) -> Result<(), TopologyError> {
+        let shape = self.shape.name();
+        let count = self.roles.len();
+        if count < 2 {
+            return Err(TopologyError::TooFewRoles { shape, count });
+        }
+        for (i, role) in self.roles.iter().enumerate() {
+            if self.roles[..i].contains(role) {
+                return Err(TopologyError::DuplicateRole(role.to_string()));
+            }
+        }
+        let expected = match self.shape {
+            Shape::Ring => count,
+            Shape::Pipeline => count - 1,
+        };
+        if self.hops.len() != expected {
+            return Err(TopologyError::HopCount {
+                shape,
+                roles: count,
+                expected,
+                count: self.hops.len(),
+            });
+        }
+        if let Termination::Until(stop) = &self.termination {
+            if self.hops.iter().any(|msg| msg.name == stop.name) {
+                return Err(TopologyError::AmbiguousStop(stop.name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send each of `msgs` on its hop in turn, then carry on with `then`
+    fn pass(&self, msgs: &[MessageType], then: Protocol) -> Protocol {
+        msgs.iter()
+            .enumerate()
+            .rev()
+            .fold(then, |continuation, (i, message)| Protocol::Send {
+                from: self.roles[i].clone(),
+                to: self.roles[(i + 1) % self.roles.len()].clone(),
+                message: message.clone(),
+                continuation: Box::new(continuation),
+            })
+    }
+}
+
+fn branch(label: &str, protocol: Protocol) -> Branch {
+    Branch {
+        label: format_ident!("{}", label),
+        guard: None,
+        weight: None,
+        protocol,
+    }
+}
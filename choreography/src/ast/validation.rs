@@ -17,4 +17,112 @@ pub enum ValidationError {
 
     #[error("Role {0} is not used in protocol")]
     UnusedRole(String),
+
+    #[error("Race arm {0} must start with a send")]
+    InvalidRace(String),
+
+    #[error("Role {0} takes part in a compensation but not in its try body")]
+    InvalidCompensation(String),
+
+    #[error("Role {0} takes part in the else branch of a timeout but not in its body")]
+    InvalidTimeout(String),
+
+    #[error("Choreography has {count} roles, more than the limit of {limit}")]
+    TooManyRoles { count: usize, limit: usize },
+
+    #[error("{choice} has {count} branches, more than the limit of {limit}")]
+    TooManyBranches {
+        choice: String,
+        count: usize,
+        limit: usize,
+    },
+
+    #[error("Protocol nests {depth} levels deep, more than the limit of {limit}")]
+    TooDeep { depth: usize, limit: usize },
+}
+
+/// Limits on the size of a choreography
+///
+/// Machine-generated choreographies can be large enough to produce
+/// megabytes of generated code, or deep enough to overflow the stack of
+/// the passes that recurse over them. Validation rejects them up front
+/// instead. The defaults are far beyond what a hand-written protocol needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// Roles, counting each instance of a role array whose size is known
+    pub max_roles: usize,
+    /// Branches of a single choice or race
+    pub max_branches: usize,
+    /// Structures nested in one another: each choice or race branch,
+    /// loop, `rec`, parallel arm, try, timeout, finally and spawned child
+    /// is one level
+    pub max_depth: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_roles: 256,
+            max_branches: 256,
+            max_depth: 64,
+        }
+    }
+}
+
+impl ProtocolLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// No limits at all
+    pub fn unlimited() -> Self {
+        Self {
+            max_roles: usize::MAX,
+            max_branches: usize::MAX,
+            max_depth: usize::MAX,
+        }
+    }
+
+    pub fn with_max_roles(mut self, max: usize) -> Self {
+        self.max_roles = max;
+        self
+    }
+
+    pub fn with_max_branches(mut self, max: usize) -> Self {
+        self.max_branches = max;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max: usize) -> Self {
+        self.max_depth = max;
+        self
+    }
+}
+
+impl ValidationError {
+    /// Stable machine-readable code for this error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::UndefinedRole(_) => "V001",
+            ValidationError::UnboundVariable(_) => "V002",
+            ValidationError::InvalidChoice(_) => "V003",
+            ValidationError::Deadlock => "V004",
+            ValidationError::UnusedRole(_) => "V005",
+            ValidationError::InvalidRace(_) => "V006",
+            ValidationError::InvalidCompensation(_) => "V007",
+            ValidationError::InvalidTimeout(_) => "V008",
+            ValidationError::TooManyRoles { .. } => "V009",
+            ValidationError::TooManyBranches { .. } => "V010",
+            ValidationError::TooDeep { .. } => "V011",
+        }
+    }
+
+    /// Convert into a structured diagnostic for editors and tools
+    pub fn to_diagnostic(&self) -> crate::compiler::Diagnostic {
+        crate::compiler::Diagnostic::new(
+            self.code(),
+            crate::compiler::Severity::Error,
+            self.to_string(),
+        )
+    }
 }
@@ -1,18 +1,185 @@
 // Static analysis for choreographic protocols
 
-use crate::ast::{Choreography, Protocol, Role};
-use std::collections::{HashMap, HashSet};
+use crate::ast::{Branch, Choreography, Condition, LocalType, Protocol, Role};
+use crate::compiler::deadlock::find_deadlock;
+use crate::compiler::diagnostic::{Diagnostic, Severity};
+use crate::compiler::expand::expand_roles;
+use crate::compiler::parser::ErrorSpan;
+use crate::compiler::projection::{project, ProjectionError};
+use crate::compiler::provenance::{walk_with_paths, Provenance};
+use crate::effects::guard::{BinOp, Guard, GuardContext, GuardValue};
+use proc_macro2::Ident;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::OnceLock;
 
-/// Analysis results for a choreography
+/// Machine-readable diagnostic codes emitted by the analyzer
+///
+/// Codes are stable across releases so tooling can filter or suppress them.
+pub mod codes {
+    /// Projected roles can reach a state where some of them wait forever
+    pub const DEADLOCK: &str = "A001";
+    /// Some path through the protocol cannot make progress
+    pub const NO_PROGRESS: &str = "A002";
+    /// A declared role never sends, receives, or chooses
+    pub const UNUSED_ROLE: &str = "A003";
+    /// A choice sends its first message to different roles in different branches
+    pub const ASYMMETRIC_CHOICE: &str = "A004";
+    /// Statements that can never execute
+    pub const UNREACHABLE_CODE: &str = "A005";
+    /// A cleanup block could block after an abort
+    pub const BLOCKING_CLEANUP: &str = "A006";
+    /// Two role or message names differ only by case
+    pub const SIMILAR_NAMES: &str = "A007";
+    /// A field tagged `@sensitive` is sent to a role not marked `@trusted`
+    pub const SENSITIVE_DATA: &str = "A008";
+    /// A role cannot tell which arm of a race won, or a race has one initiator
+    pub const RACE: &str = "A009";
+    /// A role that takes part in the protocol does nothing on some path through it
+    pub const STARVED_ROLE: &str = "A010";
+    /// A guarded choice can fail to take any branch, or has a branch it never takes
+    pub const GUARD: &str = "A011";
+    /// A candidate of a `route` cannot receive the routed message
+    pub const ROUTE: &str = "A012";
+    /// A role cannot be projected, so deadlock freedom cannot be checked
+    pub const UNPROJECTABLE: &str = "A013";
+}
+
+/// Outcome of one named check
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckResult {
+    /// Pass name, e.g. `deadlock-freedom`
+    pub name: String,
+    /// False when the pass reported an error
+    pub passed: bool,
+}
+
+/// Analysis report for a choreography
+///
+/// Holds the outcome of every check, the diagnostics they produced, and
+/// per-role statistics. Render it with `Display` for humans or
+/// [`AnalysisReport::to_json`] for tools.
 #[derive(Debug)]
-pub struct AnalysisResult {
+pub struct AnalysisReport {
+    /// Name of the analyzed choreography
+    pub choreography: String,
     pub is_deadlock_free: bool,
     pub has_progress: bool,
+    /// Pass/fail result of each check, in the order they ran
+    pub checks: Vec<CheckResult>,
+    /// All findings, including failed checks and converted warnings
+    pub diagnostics: Vec<Diagnostic>,
     pub role_participation: HashMap<Role, ParticipationInfo>,
     pub warnings: Vec<AnalysisWarning>,
     pub communication_graph: CommunicationGraph,
 }
 
+impl AnalysisReport {
+    /// True when no diagnostic has [`Severity::Error`]
+    pub fn is_ok(&self) -> bool {
+        !self.has_errors()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+
+    /// Diagnostics at exactly the given severity
+    pub fn diagnostics_with(&self, severity: Severity) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(move |d| d.severity == severity)
+    }
+
+    /// Look up a check by name
+    pub fn check(&self, name: &str) -> Option<&CheckResult> {
+        self.checks.iter().find(|c| c.name == name)
+    }
+
+    /// Serialize the report as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Participation {
+            sends: usize,
+            receives: usize,
+            choices: usize,
+            is_active: bool,
+        }
+
+        #[derive(Serialize)]
+        struct Edge<'a> {
+            from: String,
+            to: String,
+            message: &'a str,
+        }
+
+        #[derive(Serialize)]
+        struct Json<'a> {
+            choreography: &'a str,
+            ok: bool,
+            checks: &'a [CheckResult],
+            diagnostics: &'a [Diagnostic],
+            roles: BTreeMap<String, Participation>,
+            edges: Vec<Edge<'a>>,
+        }
+
+        let roles = self
+            .role_participation
+            .iter()
+            .map(|(role, info)| {
+                (
+                    role.name.to_string(),
+                    Participation {
+                        sends: info.sends,
+                        receives: info.receives,
+                        choices: info.choices,
+                        is_active: info.is_active,
+                    },
+                )
+            })
+            .collect();
+        let edges = self
+            .communication_graph
+            .edges
+            .iter()
+            .map(|(from, to, message)| Edge {
+                from: from.name.to_string(),
+                to: to.name.to_string(),
+                message,
+            })
+            .collect();
+
+        serde_json::to_string_pretty(&Json {
+            choreography: &self.choreography,
+            ok: self.is_ok(),
+            checks: &self.checks,
+            diagnostics: &self.diagnostics,
+            roles,
+            edges,
+        })
+        .expect("analysis report is always serializable")
+    }
+}
+
+impl fmt::Display for AnalysisReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "analysis of `{}`", self.choreography)?;
+        for check in &self.checks {
+            let status = if check.passed { "ok" } else { "FAILED" };
+            writeln!(f, "  {} ... {}", check.name, status)?;
+        }
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        let errors = self.diagnostics_with(Severity::Error).count();
+        let warnings = self.diagnostics_with(Severity::Warning).count();
+        write!(f, "{} error(s), {} warning(s)", errors, warnings)
+    }
+}
+
 /// Information about a role's participation
 #[derive(Debug)]
 pub struct ParticipationInfo {
@@ -30,6 +197,66 @@ pub enum AnalysisWarning {
     NoProgress(String),
     AsymmetricChoice(Role),
     UnreachableCode(String),
+    BlockingCleanup(String),
+    SimilarRoleNames(Role, Role),
+    SimilarMessageNames(String, String),
+}
+
+impl AnalysisWarning {
+    /// Diagnostic code for this warning
+    pub fn code(&self) -> &'static str {
+        match self {
+            AnalysisWarning::UnusedRole(_) => codes::UNUSED_ROLE,
+            AnalysisWarning::PotentialDeadlock(_) => codes::DEADLOCK,
+            AnalysisWarning::NoProgress(_) => codes::NO_PROGRESS,
+            AnalysisWarning::AsymmetricChoice(_) => codes::ASYMMETRIC_CHOICE,
+            AnalysisWarning::UnreachableCode(_) => codes::UNREACHABLE_CODE,
+            AnalysisWarning::BlockingCleanup(_) => codes::BLOCKING_CLEANUP,
+            AnalysisWarning::SimilarRoleNames(..) | AnalysisWarning::SimilarMessageNames(..) => {
+                codes::SIMILAR_NAMES
+            }
+        }
+    }
+
+    /// Convert into a warning-level diagnostic
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.code(), Severity::Warning, self.to_string())
+    }
+}
+
+impl fmt::Display for AnalysisWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisWarning::UnusedRole(role) => {
+                write!(f, "role `{}` never sends, receives, or chooses", role.name)
+            }
+            AnalysisWarning::PotentialDeadlock(detail) => {
+                write!(f, "potential deadlock: {}", detail)
+            }
+            AnalysisWarning::NoProgress(detail) => write!(f, "no progress: {}", detail),
+            AnalysisWarning::AsymmetricChoice(role) => write!(
+                f,
+                "choice by `{}` starts with messages to different roles",
+                role.name
+            ),
+            AnalysisWarning::UnreachableCode(detail) => {
+                write!(f, "unreachable code: {}", detail)
+            }
+            AnalysisWarning::BlockingCleanup(detail) => {
+                write!(f, "cleanup may block: {}", detail)
+            }
+            AnalysisWarning::SimilarRoleNames(a, b) => write!(
+                f,
+                "roles `{}` and `{}` differ only by case; use `alias` if they are the same role",
+                a.name, b.name
+            ),
+            AnalysisWarning::SimilarMessageNames(a, b) => write!(
+                f,
+                "messages `{}` and `{}` differ only by case; use `alias` if they are the same message",
+                a, b
+            ),
+        }
+    }
 }
 
 /// Communication graph for visualization
@@ -39,15 +266,1338 @@ pub struct CommunicationGraph {
     pub edges: Vec<(Role, Role, String)>, // (from, to, message)
 }
 
-/// Analyze a choreography for correctness properties
-pub fn analyze(choreography: &Choreography) -> AnalysisResult {
-    let mut analyzer = Analyzer::new(choreography);
-    analyzer.analyze()
+/// Analyze a choreography with the default set of passes
+pub fn analyze(choreography: &Choreography) -> AnalysisReport {
+    Analyzer::default().analyze(choreography)
+}
+
+/// Analyze a choreography with the default set of passes, pointing each
+/// diagnostic about a statement at its source
+///
+/// Takes the [`Provenance`] from
+/// [`parse_choreography_with_provenance`](crate::compiler::parser::parse_choreography_with_provenance).
+pub fn analyze_with_provenance(
+    choreography: &Choreography,
+    provenance: &Provenance,
+) -> AnalysisReport {
+    Analyzer::default().analyze_with_provenance(choreography, provenance)
 }
 
-struct Analyzer<'a> {
+/// A single check run by the [`Analyzer`]
+///
+/// Passes read the choreography, and the projected local types if they need
+/// them, from the [`AnalysisContext`] and record what they find in
+/// [`Findings`]. A pass fails when it reports an error-level diagnostic.
+pub trait AnalysisPass: Send + Sync {
+    /// Name reported in [`CheckResult::name`]
+    fn name(&self) -> &str;
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings);
+}
+
+/// Read-only input shared by every pass
+pub struct AnalysisContext<'a> {
     choreography: &'a Choreography,
+    provenance: Option<&'a Provenance>,
+    role_participation: HashMap<Role, ParticipationInfo>,
+    communication_graph: CommunicationGraph,
+    local_types: OnceLock<HashMap<Role, Result<LocalType, ProjectionError>>>,
+}
+
+impl<'a> AnalysisContext<'a> {
+    pub fn new(choreography: &'a Choreography) -> Self {
+        let mut collector = StatsCollector::new(choreography);
+        collector.collect(&choreography.protocol);
+
+        AnalysisContext {
+            choreography,
+            provenance: None,
+            role_participation: collector.participation_info(),
+            communication_graph: collector.comm_graph,
+            local_types: OnceLock::new(),
+        }
+    }
+
+    /// Locate the diagnostics reported from [`for_each_node`](Self::for_each_node)
+    /// with the source spans in `provenance`
+    pub fn with_provenance(mut self, provenance: &'a Provenance) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    pub fn choreography(&self) -> &'a Choreography {
+        self.choreography
+    }
+
+    /// Source span of the node at `path`, if the context has provenance
+    pub fn span(&self, path: &[usize]) -> Option<&'a ErrorSpan> {
+        self.provenance.and_then(|provenance| provenance.span(path))
+    }
+
+    /// Visit every node of the protocol
+    ///
+    /// Diagnostics `f` reports without a span of their own get the span of
+    /// the node being visited.
+    pub fn for_each_node(
+        &self,
+        findings: &mut Findings,
+        mut f: impl FnMut(&Protocol, &mut Findings),
+    ) {
+        walk_with_paths(self.protocol(), &mut |path, protocol| {
+            findings.location = self.span(path).cloned();
+            f(protocol, findings);
+        });
+        findings.location = None;
+    }
+
+    pub fn protocol(&self) -> &'a Protocol {
+        &self.choreography.protocol
+    }
+
+    pub fn participation(&self) -> &HashMap<Role, ParticipationInfo> {
+        &self.role_participation
+    }
+
+    pub fn communication_graph(&self) -> &CommunicationGraph {
+        &self.communication_graph
+    }
+
+    /// Projection of every role, computed on first use
+    pub fn local_types(&self) -> &HashMap<Role, Result<LocalType, ProjectionError>> {
+        self.local_types.get_or_init(|| {
+            self.choreography
+                .roles
+                .iter()
+                .map(|role| (role.clone(), project(self.choreography, role)))
+                .collect()
+        })
+    }
+
+    /// Projection of a single role, if it succeeded
+    pub fn local_type(&self, role: &Role) -> Option<&LocalType> {
+        self.local_types().get(role).and_then(|r| r.as_ref().ok())
+    }
+}
+
+/// Sink for the diagnostics produced by one pass
+#[derive(Debug, Default)]
+pub struct Findings {
+    diagnostics: Vec<Diagnostic>,
     warnings: Vec<AnalysisWarning>,
+    /// Span of the node being visited, given to diagnostics without one
+    location: Option<ErrorSpan>,
+}
+
+impl Findings {
+    pub fn report(&mut self, mut diagnostic: Diagnostic) {
+        if diagnostic.span.is_none() {
+            diagnostic.span = self.location.clone();
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    pub fn error(&mut self, code: impl Into<String>, message: impl Into<String>) {
+        self.report(Diagnostic::new(code, Severity::Error, message));
+    }
+
+    /// Record a built-in warning, keeping the typed form for
+    /// [`AnalysisReport::warnings`]
+    pub fn warn(&mut self, warning: AnalysisWarning) {
+        self.report(warning.to_diagnostic());
+        self.warnings.push(warning);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+}
+
+/// Runs a configurable list of passes and merges their output
+///
+/// `Analyzer::default()` runs the built-in checks. Use [`Analyzer::builder`]
+/// to pick passes or add custom ones:
+///
+/// ```
+/// use rumpsteak_choreography::compiler::analysis::{Analyzer, CustomPass, DeadlockCheck};
+///
+/// let analyzer = Analyzer::builder()
+///     .with(DeadlockCheck)
+///     .with(CustomPass::new("max-roles", |ctx, findings| {
+///         if ctx.choreography().roles.len() > 8 {
+///             findings.error("X001", "too many roles");
+///         }
+///     }))
+///     .build();
+/// assert_eq!(analyzer.pass_names().count(), 2);
+/// ```
+pub struct Analyzer {
+    passes: Vec<Box<dyn AnalysisPass>>,
+}
+
+impl Analyzer {
+    /// Start from an empty pipeline
+    pub fn builder() -> AnalyzerBuilder {
+        AnalyzerBuilder { passes: Vec::new() }
+    }
+
+    /// Names of the configured passes, in run order
+    pub fn pass_names(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|p| p.name())
+    }
+
+    pub fn analyze(&self, choreography: &Choreography) -> AnalysisReport {
+        self.run(AnalysisContext::new(choreography))
+    }
+
+    /// Analyze, pointing each diagnostic about a statement at its source
+    pub fn analyze_with_provenance(
+        &self,
+        choreography: &Choreography,
+        provenance: &Provenance,
+    ) -> AnalysisReport {
+        self.run(AnalysisContext::new(choreography).with_provenance(provenance))
+    }
+
+    fn run(&self, ctx: AnalysisContext<'_>) -> AnalysisReport {
+        let choreography = ctx.choreography();
+        let mut checks = Vec::new();
+        let mut diagnostics = Vec::new();
+        let mut warnings = Vec::new();
+        for pass in &self.passes {
+            let mut findings = Findings::default();
+            pass.run(&ctx, &mut findings);
+            checks.push(CheckResult {
+                name: pass.name().to_string(),
+                passed: !findings.has_errors(),
+            });
+            diagnostics.extend(findings.diagnostics);
+            warnings.extend(findings.warnings);
+        }
+
+        let passed = |name: &str| {
+            checks
+                .iter()
+                .find(|c: &&CheckResult| c.name == name)
+                .map_or(true, |c| c.passed)
+        };
+        let is_deadlock_free = passed(DeadlockCheck.name());
+        let has_progress = passed(ProgressCheck.name());
+
+        AnalysisReport {
+            choreography: choreography.name.to_string(),
+            is_deadlock_free,
+            has_progress,
+            checks,
+            diagnostics,
+            role_participation: ctx.role_participation,
+            warnings,
+            communication_graph: ctx.communication_graph,
+        }
+    }
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Analyzer::builder().with_defaults().build()
+    }
+}
+
+/// Builder for [`Analyzer`]
+pub struct AnalyzerBuilder {
+    passes: Vec<Box<dyn AnalysisPass>>,
+}
+
+impl AnalyzerBuilder {
+    /// Append a pass
+    pub fn with(mut self, pass: impl AnalysisPass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Append all built-in passes
+    pub fn with_defaults(self) -> Self {
+        self.with(DeadlockCheck)
+            .with(ProgressCheck)
+            .with(UnusedRoleCheck)
+            .with(ChoiceSymmetryCheck)
+            .with(CleanupCheck)
+            .with(NamingCheck)
+            .with(SensitiveDataCheck)
+            .with(RaceCheck)
+            .with(LivenessCheck)
+            .with(GuardCheck)
+            .with(RouteCheck)
+    }
+
+    pub fn build(self) -> Analyzer {
+        Analyzer {
+            passes: self.passes,
+        }
+    }
+}
+
+/// Pass built from a closure
+pub struct CustomPass<F> {
+    name: String,
+    run: F,
+}
+
+impl<F> CustomPass<F>
+where
+    F: Fn(&AnalysisContext<'_>, &mut Findings) + Send + Sync,
+{
+    pub fn new(name: impl Into<String>, run: F) -> Self {
+        CustomPass {
+            name: name.into(),
+            run,
+        }
+    }
+}
+
+impl<F> AnalysisPass for CustomPass<F>
+where
+    F: Fn(&AnalysisContext<'_>, &mut Findings) + Send + Sync,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        (self.run)(ctx, findings)
+    }
+}
+
+/// Fails when the projected roles can reach a state where some of them
+/// wait forever
+///
+/// Runs the local types against each other through every choice, see
+/// [`find_deadlock`]. The error names the cycle of waiting roles, or the
+/// role whose message never comes, and the steps that lead there. A role
+/// that fails to project gets an error of its own, and the check fails
+/// without running the rest.
+///
+/// Role families are checked member by member once expanded with
+/// [`expand_roles`]. A family whose size is symbolic cannot be, so the
+/// check is skipped with a note instead.
+pub struct DeadlockCheck;
+
+impl AnalysisPass for DeadlockCheck {
+    fn name(&self) -> &str {
+        "deadlock-freedom"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        let choreography = ctx.choreography();
+        let has_families = choreography
+            .roles
+            .iter()
+            .any(|role| role.array_size.is_some() || role.index.is_some());
+        if !has_families {
+            let mut local_types = Vec::new();
+            for role in &choreography.roles {
+                match &ctx.local_types()[role] {
+                    Ok(local_type) => local_types.push((role.clone(), local_type.clone())),
+                    Err(e) => report_unprojectable(role, e, findings),
+                }
+            }
+            if !findings.has_errors() {
+                report_deadlock(&local_types, findings);
+            }
+            return;
+        }
+
+        let expanded = match expand_roles(choreography, &HashMap::new()) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                findings.report(Diagnostic::new(
+                    codes::DEADLOCK,
+                    Severity::Info,
+                    format!(
+                        "deadlock freedom not checked for role families: {}; \
+                         check the choreography expanded with expand_roles",
+                        e
+                    ),
+                ));
+                return;
+            }
+        };
+        let mut local_types = Vec::new();
+        for role in &expanded.roles {
+            match project(&expanded, role) {
+                Ok(local_type) => local_types.push((role.clone(), local_type)),
+                Err(e) => report_unprojectable(role, &e, findings),
+            }
+        }
+        if !findings.has_errors() {
+            report_deadlock(&local_types, findings);
+        }
+    }
+}
+
+fn report_unprojectable(role: &Role, error: &ProjectionError, findings: &mut Findings) {
+    findings.error(
+        codes::UNPROJECTABLE,
+        format!(
+            "deadlock freedom not checked: role {} cannot be projected: {}",
+            role.name, error
+        ),
+    );
+}
+
+fn report_deadlock(local_types: &[(Role, LocalType)], findings: &mut Findings) {
+    let local_types: Vec<_> = local_types.iter().map(|(r, t)| (r.clone(), t)).collect();
+    if let Some(deadlock) = find_deadlock(&local_types) {
+        findings.error(codes::DEADLOCK, deadlock.to_string());
+    }
+}
+
+/// Fails when a loop or recursion never communicates
+pub struct ProgressCheck;
+
+impl AnalysisPass for ProgressCheck {
+    fn name(&self) -> &str {
+        "progress"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        // Check that the protocol eventually terminates or makes progress
+        if !check_protocol_progress(ctx.protocol()) {
+            findings.error(codes::NO_PROGRESS, "a loop or recursion never communicates");
+        }
+    }
+}
+
+/// Warns about declared roles that never take part
+pub struct UnusedRoleCheck;
+
+impl AnalysisPass for UnusedRoleCheck {
+    fn name(&self) -> &str {
+        "unused-roles"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        for role in &ctx.choreography().roles {
+            if let Some(info) = ctx.participation().get(role) {
+                if !info.is_active {
+                    findings.warn(AnalysisWarning::UnusedRole(role.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Warns when a choice's branches start by sending to different roles
+pub struct ChoiceSymmetryCheck;
+
+impl AnalysisPass for ChoiceSymmetryCheck {
+    fn name(&self) -> &str {
+        "choice-symmetry"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        ctx.for_each_node(findings, |protocol, findings| {
+            if let Protocol::Choice { role, branches } = protocol {
+                let recipients: HashSet<_> = branches
+                    .iter()
+                    .filter_map(|branch| {
+                        if let Protocol::Send { to, .. } = &branch.protocol {
+                            Some(to.clone())
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+
+                if recipients.len() > 1 {
+                    findings.warn(AnalysisWarning::AsymmetricChoice(role.clone()));
+                }
+            }
+        });
+    }
+}
+
+/// Warns when a cleanup block could block after an abort
+///
+/// Cleanups run after an abort, when peers may no longer be making
+/// decisions, so they must be straight-line: no choices, no unbounded
+/// loops, and no recursion.
+pub struct CleanupCheck;
+
+impl AnalysisPass for CleanupCheck {
+    fn name(&self) -> &str {
+        "cleanup"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        ctx.for_each_node(findings, |protocol, findings| {
+            if let Protocol::Finally { cleanup, .. } = protocol {
+                check_cleanup(cleanup, findings);
+            }
+        });
+    }
+}
+
+fn check_cleanup(cleanup: &Protocol, findings: &mut Findings) {
+    match cleanup {
+        Protocol::Send { continuation, .. } | Protocol::Broadcast { continuation, .. } => {
+            check_cleanup(continuation, findings)
+        }
+        Protocol::Choice { role, .. } => {
+            findings.warn(AnalysisWarning::BlockingCleanup(format!(
+                "cleanup waits on a choice by {}",
+                role.name
+            )));
+        }
+        Protocol::Race { .. } => {
+            findings.warn(AnalysisWarning::BlockingCleanup(
+                "cleanup waits on a race".to_string(),
+            ));
+        }
+        Protocol::Loop {
+            condition: Some(Condition::Count(_)),
+            body,
+        } => check_cleanup(body, findings),
+        Protocol::Loop { .. } => {
+            findings.warn(AnalysisWarning::BlockingCleanup(
+                "cleanup contains a loop without a fixed iteration count".to_string(),
+            ));
+        }
+        Protocol::Parallel { protocols } => {
+            for p in protocols {
+                check_cleanup(p, findings);
+            }
+        }
+        Protocol::Rec { label, .. } | Protocol::Var(label) => {
+            findings.warn(AnalysisWarning::BlockingCleanup(format!(
+                "cleanup uses recursion on {}",
+                label
+            )));
+        }
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => {
+            check_cleanup(body, findings);
+            check_cleanup(cleanup, findings);
+        }
+        Protocol::Spawn { continuation, .. } => check_cleanup(continuation, findings),
+        Protocol::Await { handle, .. } => {
+            findings.warn(AnalysisWarning::BlockingCleanup(format!(
+                "cleanup waits on child session {}",
+                handle
+            )));
+        }
+        Protocol::End => {}
+    }
+}
+
+/// Warns about role or message names that differ only by case
+///
+/// These are usually typos that otherwise surface as confusing projection
+/// results.
+pub struct NamingCheck;
+
+impl AnalysisPass for NamingCheck {
+    fn name(&self) -> &str {
+        "similar-names"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        let roles = &ctx.choreography().roles;
+        for (i, a) in roles.iter().enumerate() {
+            for b in &roles[i + 1..] {
+                let (a_name, b_name) = (a.name.to_string(), b.name.to_string());
+                if a_name != b_name && a_name.eq_ignore_ascii_case(&b_name) {
+                    findings.warn(AnalysisWarning::SimilarRoleNames(a.clone(), b.clone()));
+                }
+            }
+        }
+
+        let mut messages: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        walk_with_paths(ctx.protocol(), &mut |_, protocol| {
+            if let Protocol::Send { message, .. } | Protocol::Broadcast { message, .. } = protocol {
+                let name = message.name.to_string();
+                messages
+                    .entry(name.to_lowercase())
+                    .or_default()
+                    .insert(name);
+            }
+        });
+        for spellings in messages.values() {
+            let mut spellings = spellings.iter();
+            if let Some(first) = spellings.next() {
+                for other in spellings {
+                    findings.warn(AnalysisWarning::SimilarMessageNames(
+                        first.clone(),
+                        other.clone(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Fails when a field tagged `@sensitive` is sent to a role that is not
+/// marked `@trusted`
+///
+/// Gives privacy review a backstop: a new recipient of personal data has to
+/// be declared trusted in the choreography, where reviewers will see it.
+pub struct SensitiveDataCheck;
+
+impl AnalysisPass for SensitiveDataCheck {
+    fn name(&self) -> &str {
+        "sensitive-data"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        let choreography = ctx.choreography();
+        let mut reported = BTreeSet::new();
+        ctx.for_each_node(findings, |protocol, findings| {
+            let (from, recipients, message) = match protocol {
+                Protocol::Send {
+                    from, to, message, ..
+                } => (from, std::slice::from_ref(to), message),
+                Protocol::Broadcast {
+                    from,
+                    to_all,
+                    message,
+                    ..
+                } => (from, to_all.as_slice(), message),
+                _ => return,
+            };
+            let fields = message.sensitive_fields();
+            if fields.is_empty() {
+                return;
+            }
+            for to in recipients {
+                if choreography.is_trusted(to) {
+                    continue;
+                }
+                let key = (message.name.to_string(), to.name.to_string());
+                if reported.insert(key) {
+                    findings.error(
+                        codes::SENSITIVE_DATA,
+                        format!(
+                            "{} sends sensitive field(s) {} of `{}` to `{}`, which is not @trusted",
+                            from.name,
+                            fields.join(", "),
+                            message.name,
+                            to.name
+                        ),
+                    );
+                }
+            }
+        });
+    }
+}
+
+/// Checks that every race resolves the same way for all its participants
+///
+/// A role that sends or receives the first message of some arm has to see
+/// the first message of every arm, or it may keep waiting on an arm that
+/// already lost. A race whose arms are all started by one role is reported
+/// as a warning, since that role is simply making a choice.
+pub struct RaceCheck;
+
+impl AnalysisPass for RaceCheck {
+    fn name(&self) -> &str {
+        "race"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        ctx.for_each_node(findings, |protocol, findings| {
+            let Protocol::Race { branches } = protocol else {
+                return;
+            };
+            let heads: Vec<(&Branch, Option<(&Role, &Role)>)> = branches
+                .iter()
+                .map(|branch| match &branch.protocol {
+                    Protocol::Send { from, to, .. } => (branch, Some((from, to))),
+                    _ => (branch, None),
+                })
+                .collect();
+
+            let mut participants: Vec<&Role> = Vec::new();
+            for (from, to) in heads.iter().filter_map(|(_, head)| *head) {
+                for role in [from, to] {
+                    if !participants.contains(&role) {
+                        participants.push(role);
+                    }
+                }
+            }
+            for role in participants {
+                let Some((missed, _)) = heads.iter().find(
+                    |(_, head)| !matches!(head, Some((from, to)) if *from == role || *to == role),
+                ) else {
+                    continue;
+                };
+                findings.error(
+                    codes::RACE,
+                    format!(
+                        "`{}` takes part in a race but cannot tell when arm `{}` starts",
+                        role.name, missed.label
+                    ),
+                );
+            }
+
+            let mut initiators = heads
+                .iter()
+                .filter_map(|(_, head)| head.map(|(from, _)| from));
+            if let Some(first) = initiators.next() {
+                if initiators.all(|from| from == first) {
+                    findings.report(Diagnostic::new(
+                        codes::RACE,
+                        Severity::Warning,
+                        format!(
+                            "every arm of a race is started by `{}`; use a choice instead",
+                            first.name
+                        ),
+                    ));
+                }
+            }
+        });
+    }
+}
+
+/// Fails when a role can be left out of a whole execution
+///
+/// Every role that takes part in the protocol somewhere must send, receive,
+/// or choose on every path through it. Roles that never take part are
+/// reported by [`UnusedRoleCheck`] instead. See [`find_starved_roles`].
+pub struct LivenessCheck;
+
+impl AnalysisPass for LivenessCheck {
+    fn name(&self) -> &str {
+        "liveness"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        for starvation in find_starved_roles(ctx.choreography()) {
+            findings.error(codes::STARVED_ROLE, starvation.to_string());
+        }
+    }
+}
+
+/// Warns about guarded choices that can get stuck or never take a branch
+///
+/// The chooser takes the first branch whose guard holds, so a choice whose
+/// branches are all guarded fails at runtime unless their guards cover
+/// every case, and a branch whose guard cannot hold once the earlier ones
+/// have failed is dead. Comparisons of a variable with a literal are
+/// reasoned about exactly; other terms are only matched against their own
+/// negation, and guards too large to expand are skipped.
+pub struct GuardCheck;
+
+impl AnalysisPass for GuardCheck {
+    fn name(&self) -> &str {
+        "guards"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        ctx.for_each_node(findings, |protocol, findings| {
+            let Protocol::Choice { role, branches } = protocol else {
+                return;
+            };
+            if branches.iter().all(|b| b.guard.is_none()) {
+                return;
+            }
+            // The parser rejects guards that do not parse
+            let Some(guards) = branches
+                .iter()
+                .map(|b| match &b.guard {
+                    Some(tokens) => Guard::parse(&tokens.to_string()).ok(),
+                    None => Some(Guard::Value(GuardValue::Bool(true))),
+                })
+                .collect::<Option<Vec<_>>>()
+            else {
+                return;
+            };
+
+            let mut none_held = Guard::Value(GuardValue::Bool(true));
+            for (i, (branch, guard)) in branches.iter().zip(&guards).enumerate() {
+                if guard_satisfiable(guard) == Some(false) {
+                    findings.report(Diagnostic::new(
+                        codes::GUARD,
+                        Severity::Warning,
+                        format!(
+                            "the guard of branch `{}` in the choice by `{}` can never hold",
+                            branch.label, role.name
+                        ),
+                    ));
+                } else if guard_satisfiable(&guard_and(none_held.clone(), guard.clone()))
+                    == Some(false)
+                {
+                    let earlier: Vec<String> = branches[..i]
+                        .iter()
+                        .map(|b| format!("`{}`", b.label))
+                        .collect();
+                    findings.report(Diagnostic::new(
+                        codes::GUARD,
+                        Severity::Warning,
+                        format!(
+                            "branch `{}` in the choice by `{}` is never taken, since {} \
+                             is taken whenever its guard holds",
+                            branch.label,
+                            role.name,
+                            earlier.join(" or ")
+                        ),
+                    ));
+                }
+                none_held = guard_and(none_held, Guard::Not(Box::new(guard.clone())));
+            }
+
+            if branches.iter().all(|b| b.guard.is_some())
+                && guard_satisfiable(&none_held) == Some(true)
+            {
+                let labels: Vec<String> =
+                    branches.iter().map(|b| format!("`{}`", b.label)).collect();
+                findings.report(Diagnostic::new(
+                    codes::GUARD,
+                    Severity::Warning,
+                    format!(
+                        "the choice by `{}` fails when none of the guards of {} hold; \
+                         add an unguarded branch or a complementary guard",
+                        role.name,
+                        labels.join(", ")
+                    ),
+                ));
+            }
+        });
+    }
+}
+
+/// Fails when a candidate of a `route` cannot handle the routed message
+///
+/// The sender picks the receiver from the payload at runtime, so every
+/// candidate must project and receive the message from the sender when
+/// it is picked.
+pub struct RouteCheck;
+
+impl AnalysisPass for RouteCheck {
+    fn name(&self) -> &str {
+        "routes"
+    }
+
+    fn run(&self, ctx: &AnalysisContext<'_>, findings: &mut Findings) {
+        for route in &ctx.choreography().routes {
+            let (from, message) = (&route.from.name, &route.message);
+            for candidate in route.candidates.iter().map(|role| &role.name) {
+                let Some((_, local)) = ctx
+                    .local_types()
+                    .iter()
+                    .find(|(role, _)| role.name == *candidate)
+                else {
+                    findings.error(
+                        codes::ROUTE,
+                        format!(
+                            "`{}` routes {} to `{}`, which is not a role",
+                            from, message, candidate
+                        ),
+                    );
+                    continue;
+                };
+                match local {
+                    Err(e) => findings.error(
+                        codes::ROUTE,
+                        format!(
+                            "`{}` may be routed {} by `{}` but cannot be projected: {}",
+                            candidate, message, from, e
+                        ),
+                    ),
+                    Ok(local) if !receives_from(local, &route.from, message) => findings.error(
+                        codes::ROUTE,
+                        format!(
+                            "`{}` may be routed {} by `{}` but never receives it",
+                            candidate, message, from
+                        ),
+                    ),
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Whether the local type receives `message` from `from` anywhere
+fn receives_from(local: &LocalType, from: &Role, message: &Ident) -> bool {
+    match local {
+        LocalType::Receive {
+            from: sender,
+            message: received,
+            continuation,
+        } => {
+            (sender.name == from.name && received.name == *message)
+                || receives_from(continuation, from, message)
+        }
+        LocalType::Send { continuation, .. } => receives_from(continuation, from, message),
+        LocalType::Select { branches, .. }
+        | LocalType::Branch { branches, .. }
+        | LocalType::LocalChoice { branches }
+        | LocalType::Race { branches } => branches
+            .iter()
+            .any(|(_, branch)| receives_from(branch, from, message)),
+        LocalType::Loop { body, .. } | LocalType::Rec { body, .. } => {
+            receives_from(body, from, message)
+        }
+        LocalType::Finally {
+            body,
+            cleanup: other,
+        }
+        | LocalType::TryCatch {
+            body,
+            compensation: other,
+            ..
+        }
+        | LocalType::Timeout {
+            body,
+            on_timeout: other,
+            ..
+        } => receives_from(body, from, message) || receives_from(other, from, message),
+        LocalType::Var(_) | LocalType::End => false,
+    }
+}
+
+/// Most conjunctions a guard may expand to before it is skipped
+const MAX_GUARD_TERMS: usize = 64;
+
+/// One condition of a guard in disjunctive normal form
+#[derive(Debug, Clone, PartialEq)]
+enum GuardAtom {
+    /// `var op value`, where a bare variable `flag` is `flag == true`
+    Compare {
+        var: String,
+        op: BinOp,
+        value: GuardValue,
+    },
+    /// Any other term, known only by its text and whether it must hold
+    Opaque { term: String, holds: bool },
+}
+
+fn guard_and(left: Guard, right: Guard) -> Guard {
+    Guard::Binary {
+        op: BinOp::And,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+/// Whether some variable values make the guard hold, or `None` if the
+/// guard is too large to tell
+fn guard_satisfiable(guard: &Guard) -> Option<bool> {
+    let terms = guard_dnf(guard, true)?;
+    Some(terms.iter().any(|atoms| atoms_satisfiable(atoms)))
+}
+
+/// The conjunctions whose disjunction is `guard`, or its negation when
+/// `positive` is false
+fn guard_dnf(guard: &Guard, positive: bool) -> Option<Vec<Vec<GuardAtom>>> {
+    // Subterms without variables are evaluated outright
+    if let Ok(holds) = guard.holds(&GuardContext::new()) {
+        return Some(if holds == positive {
+            vec![Vec::new()]
+        } else {
+            Vec::new()
+        });
+    }
+    let opaque = || {
+        // `a <= b` is kept as `!(a > b)`, so that it meets its negation
+        let atom = match guard {
+            Guard::Binary { op, left, right }
+                if matches!(op, BinOp::Ne | BinOp::Le | BinOp::Ge) =>
+            {
+                GuardAtom::Opaque {
+                    term: Guard::Binary {
+                        op: negate_comparison(*op).expect("a comparison"),
+                        left: left.clone(),
+                        right: right.clone(),
+                    }
+                    .to_string(),
+                    holds: !positive,
+                }
+            }
+            _ => GuardAtom::Opaque {
+                term: guard.to_string(),
+                holds: positive,
+            },
+        };
+        Some(vec![vec![atom]])
+    };
+    match guard {
+        Guard::Not(inner) => guard_dnf(inner, !positive),
+        Guard::Var(var) => Some(vec![vec![GuardAtom::Compare {
+            var: var.clone(),
+            op: BinOp::Eq,
+            value: GuardValue::Bool(positive),
+        }]]),
+        Guard::Binary { op, left, right } if matches!(op, BinOp::And | BinOp::Or) => {
+            let left = guard_dnf(left, positive)?;
+            let right = guard_dnf(right, positive)?;
+            let terms: Vec<Vec<GuardAtom>> = if (*op == BinOp::Or) == positive {
+                left.into_iter().chain(right).collect()
+            } else {
+                if left.len() * right.len() > MAX_GUARD_TERMS {
+                    return None;
+                }
+                left.iter()
+                    .flat_map(|l| right.iter().map(move |r| [l.clone(), r.clone()].concat()))
+                    .collect()
+            };
+            (terms.len() <= MAX_GUARD_TERMS).then_some(terms)
+        }
+        Guard::Binary { op, left, right } => {
+            if negate_comparison(*op).is_none() {
+                return opaque();
+            }
+            let literal = |side: &Guard| side.eval(&GuardContext::new()).ok();
+            let (var, op, value) = match (left.as_ref(), right.as_ref()) {
+                (Guard::Var(var), other) => (var, Some(*op), literal(other)),
+                (other, Guard::Var(var)) => (var, flip_comparison(*op), literal(other)),
+                _ => return opaque(),
+            };
+            let (Some(value), Some(op)) = (value, op) else {
+                return opaque();
+            };
+            let op = if positive {
+                Some(op)
+            } else {
+                negate_comparison(op)
+            };
+            match op {
+                Some(op) => Some(vec![vec![GuardAtom::Compare {
+                    var: var.clone(),
+                    op,
+                    value,
+                }]]),
+                None => opaque(),
+            }
+        }
+        _ => opaque(),
+    }
+}
+
+/// `op` with its operands swapped, for comparisons
+fn flip_comparison(op: BinOp) -> Option<BinOp> {
+    Some(match op {
+        BinOp::Eq | BinOp::Ne => op,
+        BinOp::Lt => BinOp::Gt,
+        BinOp::Le => BinOp::Ge,
+        BinOp::Gt => BinOp::Lt,
+        BinOp::Ge => BinOp::Le,
+        _ => return None,
+    })
+}
+
+/// The comparison that holds exactly when `op` does not
+fn negate_comparison(op: BinOp) -> Option<BinOp> {
+    Some(match op {
+        BinOp::Eq => BinOp::Ne,
+        BinOp::Ne => BinOp::Eq,
+        BinOp::Lt => BinOp::Ge,
+        BinOp::Le => BinOp::Gt,
+        BinOp::Gt => BinOp::Le,
+        BinOp::Ge => BinOp::Lt,
+        _ => return None,
+    })
+}
+
+/// Whether every atom of a conjunction can hold at once
+///
+/// Errs towards satisfiable when a variable is compared with values of
+/// different types, or with strings by order.
+fn atoms_satisfiable(atoms: &[GuardAtom]) -> bool {
+    let mut by_var: BTreeMap<&str, Vec<(BinOp, &GuardValue)>> = BTreeMap::new();
+    for atom in atoms {
+        match atom {
+            GuardAtom::Compare { var, op, value } => {
+                by_var.entry(var).or_default().push((*op, value))
+            }
+            GuardAtom::Opaque { term, holds } => {
+                let negated = GuardAtom::Opaque {
+                    term: term.clone(),
+                    holds: !holds,
+                };
+                if atoms.contains(&negated) {
+                    return false;
+                }
+            }
+        }
+    }
+    by_var
+        .values()
+        .all(|comparisons| comparisons_satisfiable(comparisons))
+}
+
+/// Whether one variable can meet all of `comparisons`
+fn comparisons_satisfiable(comparisons: &[(BinOp, &GuardValue)]) -> bool {
+    let meets = |candidate: &GuardValue| {
+        comparisons.iter().all(|(op, value)| {
+            Guard::Binary {
+                op: *op,
+                left: Box::new(Guard::Value(candidate.clone())),
+                right: Box::new(Guard::Value((*value).clone())),
+            }
+            .holds(&GuardContext::new())
+            .unwrap_or(true)
+        })
+    };
+
+    // An equality leaves one candidate, as does a bool compared at all
+    if let Some((_, value)) = comparisons.iter().find(|(op, _)| *op == BinOp::Eq) {
+        return meets(value);
+    }
+    if comparisons
+        .iter()
+        .any(|(_, value)| matches!(value, GuardValue::Bool(_)))
+    {
+        return [GuardValue::Bool(true), GuardValue::Bool(false)]
+            .iter()
+            .any(meets);
+    }
+
+    let numbers: Option<Vec<(BinOp, f64)>> = comparisons
+        .iter()
+        .map(|(op, value)| match value {
+            GuardValue::Int(i) => Some((*op, *i as f64)),
+            GuardValue::Float(f) => Some((*op, *f)),
+            _ => None,
+        })
+        .collect();
+    let Some(numbers) = numbers else {
+        return true;
+    };
+    let integral = comparisons
+        .iter()
+        .all(|(_, value)| matches!(value, GuardValue::Int(_)));
+
+    // Bounds as (value, inclusive); integers only take inclusive ones
+    let mut lower = (f64::NEG_INFINITY, false);
+    let mut upper = (f64::INFINITY, false);
+    let mut excluded = Vec::new();
+    for (op, value) in numbers {
+        let (bound, is_lower) = match op {
+            BinOp::Gt if integral => ((value + 1.0, true), true),
+            BinOp::Lt if integral => ((value - 1.0, true), false),
+            BinOp::Ge => ((value, true), true),
+            BinOp::Gt => ((value, false), true),
+            BinOp::Le => ((value, true), false),
+            BinOp::Lt => ((value, false), false),
+            _ => {
+                excluded.push(value);
+                continue;
+            }
+        };
+        if is_lower {
+            if bound.0 > lower.0 || (bound.0 == lower.0 && !bound.1) {
+                lower = bound;
+            }
+        } else if bound.0 < upper.0 || (bound.0 == upper.0 && !bound.1) {
+            upper = bound;
+        }
+    }
+    if lower.0 > upper.0 {
+        return false;
+    }
+    if lower.0 == upper.0 {
+        return lower.1 && upper.1 && !excluded.contains(&lower.0);
+    }
+    if integral && upper.0 - lower.0 < excluded.len() as f64 {
+        // Few enough integers in range to try each one
+        let first = lower.0 as i64;
+        let last = upper.0 as i64;
+        return (first..=last).any(|i| !excluded.contains(&(i as f64)));
+    }
+    true
+}
+
+/// Most execution paths explored before the rest are ignored
+const MAX_PATHS: usize = 1024;
+
+/// Most paths spelled out in a starvation message
+const SHOWN_PATHS: usize = 3;
+
+/// A role that does nothing on some paths through the protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Starvation {
+    pub role: Role,
+    /// The decisions leading to each path, e.g. "`Client` chooses `sell`"
+    pub paths: Vec<Vec<String>>,
+}
+
+impl fmt::Display for Starvation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paths: Vec<String> = self
+            .paths
+            .iter()
+            .take(SHOWN_PATHS)
+            .map(|decisions| decisions.join(" and then "))
+            .collect();
+        write!(
+            f,
+            "`{}` takes no part if {}",
+            self.role.name,
+            paths.join(", or if ")
+        )?;
+        if self.paths.len() > SHOWN_PATHS {
+            write!(f, ", or on {} more paths", self.paths.len() - SHOWN_PATHS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Roles that take part in the protocol but do nothing on some path through
+/// it, with the choice branches and race arms that lead there
+///
+/// Loops are assumed to run their body at least once. Only the first
+/// thousand or so paths are explored.
+pub fn find_starved_roles(choreography: &Choreography) -> Vec<Starvation> {
+    let paths = execution_paths(&choreography.protocol);
+    choreography
+        .roles
+        .iter()
+        .filter(|role| paths.iter().any(|path| path.roles.contains(*role)))
+        .filter_map(|role| {
+            let starved: Vec<Vec<String>> = paths
+                .iter()
+                .filter(|path| !path.roles.contains(role))
+                .map(|path| path.decisions.clone())
+                .collect();
+            (!starved.is_empty()).then(|| Starvation {
+                role: role.clone(),
+                paths: starved,
+            })
+        })
+        .collect()
+}
+
+/// One way through the protocol: the decisions made and who acted
+#[derive(Clone, Default)]
+struct ExecutionPath {
+    decisions: Vec<String>,
+    roles: HashSet<Role>,
+}
+
+fn execution_paths(protocol: &Protocol) -> Vec<ExecutionPath> {
+    let acting = |mut paths: Vec<ExecutionPath>, roles: Vec<&Role>| {
+        for path in &mut paths {
+            path.roles.extend(roles.iter().map(|role| (*role).clone()));
+        }
+        paths
+    };
+    let deciding = |branches: &[Branch], role: Option<&Role>| -> Vec<ExecutionPath> {
+        branches
+            .iter()
+            .flat_map(|branch| {
+                let decision = match role {
+                    Some(role) => format!("`{}` chooses `{}`", role.name, branch.label),
+                    None => format!("arm `{}` of a race wins", branch.label),
+                };
+                execution_paths(&branch.protocol)
+                    .into_iter()
+                    .map(move |mut path| {
+                        path.decisions.insert(0, decision.clone());
+                        path
+                    })
+            })
+            .take(MAX_PATHS)
+            .collect()
+    };
+
+    match protocol {
+        Protocol::Send {
+            from,
+            to,
+            continuation,
+            ..
+        } => acting(execution_paths(continuation), vec![from, to]),
+        Protocol::Broadcast {
+            from,
+            to_all,
+            continuation,
+            ..
+        } => acting(
+            execution_paths(continuation),
+            std::iter::once(from).chain(to_all).collect(),
+        ),
+        Protocol::Choice { role, branches } => acting(deciding(branches, Some(role)), vec![role]),
+        Protocol::Race { branches } => deciding(branches, None),
+        Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => execution_paths(body),
+        Protocol::Parallel { protocols } => protocols
+            .iter()
+            .map(execution_paths)
+            .fold(vec![ExecutionPath::default()], |paths, next| {
+                combine_paths(&paths, &next)
+            }),
+        Protocol::Finally { body, cleanup } => {
+            combine_paths(&execution_paths(body), &execution_paths(cleanup))
+        }
+        Protocol::TryCatch {
+            body, compensation, ..
+        } => {
+            let raised = execution_paths(compensation).into_iter().map(|mut path| {
+                path.decisions
+                    .insert(0, "the `try` body raises an error".to_string());
+                path
+            });
+            let mut paths = execution_paths(body);
+            paths.extend(raised);
+            paths.truncate(MAX_PATHS);
+            paths
+        }
+        Protocol::Timeout {
+            role,
+            duration,
+            body,
+            on_timeout,
+        } => {
+            let decision = format!("{} gives up after {:?}", role.name, duration);
+            let timed_out = execution_paths(on_timeout).into_iter().map(|mut path| {
+                path.decisions.insert(0, decision.clone());
+                path
+            });
+            let mut paths = execution_paths(body);
+            paths.extend(timed_out);
+            paths.truncate(MAX_PATHS);
+            paths
+        }
+        Protocol::Spawn {
+            roles,
+            continuation,
+            ..
+        }
+        | Protocol::Await {
+            roles,
+            continuation,
+            ..
+        } => acting(execution_paths(continuation), roles.iter().collect()),
+        Protocol::Var(_) | Protocol::End => vec![ExecutionPath::default()],
+    }
+}
+
+/// Every path of `first` followed by every path of `second`
+fn combine_paths(first: &[ExecutionPath], second: &[ExecutionPath]) -> Vec<ExecutionPath> {
+    first
+        .iter()
+        .flat_map(|a| {
+            second.iter().map(move |b| ExecutionPath {
+                decisions: a.decisions.iter().chain(&b.decisions).cloned().collect(),
+                roles: a.roles.union(&b.roles).cloned().collect(),
+            })
+        })
+        .take(MAX_PATHS)
+        .collect()
+}
+
+/// Per-role counters and the communication graph, gathered in one walk
+struct StatsCollector {
     role_stats: HashMap<Role, RoleStats>,
     comm_graph: CommunicationGraph,
 }
@@ -59,16 +1609,14 @@ struct RoleStats {
     choices: usize,
 }
 
-impl<'a> Analyzer<'a> {
-    fn new(choreography: &'a Choreography) -> Self {
+impl StatsCollector {
+    fn new(choreography: &Choreography) -> Self {
         let mut role_stats = HashMap::new();
         for role in &choreography.roles {
             role_stats.insert(role.clone(), RoleStats::default());
         }
 
-        Analyzer {
-            choreography,
-            warnings: Vec::new(),
+        StatsCollector {
             role_stats,
             comm_graph: CommunicationGraph {
                 nodes: choreography.roles.clone(),
@@ -77,39 +1625,7 @@ impl<'a> Analyzer<'a> {
         }
     }
 
-    fn analyze(&mut self) -> AnalysisResult {
-        // Collect statistics
-        self.analyze_protocol(&self.choreography.protocol);
-
-        // Check for deadlocks
-        let is_deadlock_free = self.check_deadlock_freedom();
-
-        // Check for progress
-        let has_progress = self.check_progress();
-
-        // Check role participation
-        let role_participation = self.compute_participation_info();
-
-        // Check for unused roles
-        for role in &self.choreography.roles {
-            if let Some(info) = role_participation.get(role) {
-                if !info.is_active {
-                    self.warnings
-                        .push(AnalysisWarning::UnusedRole(role.clone()));
-                }
-            }
-        }
-
-        AnalysisResult {
-            is_deadlock_free,
-            has_progress,
-            role_participation,
-            warnings: self.warnings.clone(),
-            communication_graph: self.comm_graph.clone(),
-        }
-    }
-
-    fn analyze_protocol(&mut self, protocol: &Protocol) {
+    fn collect(&mut self, protocol: &Protocol) {
         match protocol {
             Protocol::Send {
                 from,
@@ -126,7 +1642,7 @@ impl<'a> Analyzer<'a> {
                 self.comm_graph
                     .edges
                     .push((from.clone(), to.clone(), message.name.to_string()));
-                self.analyze_protocol(continuation);
+                self.collect(continuation);
             }
 
             Protocol::Broadcast {
@@ -134,6 +1650,7 @@ impl<'a> Analyzer<'a> {
                 to_all,
                 message,
                 continuation,
+                ..
             } => {
                 if let Some(stats) = self.role_stats.get_mut(from) {
                     stats.sends += to_all.len();
@@ -148,141 +1665,63 @@ impl<'a> Analyzer<'a> {
                         format!("{} (broadcast)", message.name),
                     ));
                 }
-                self.analyze_protocol(continuation);
+                self.collect(continuation);
             }
 
             Protocol::Choice { role, branches } => {
                 if let Some(stats) = self.role_stats.get_mut(role) {
                     stats.choices += 1;
                 }
-
-                // Check for asymmetric choices
-                let recipients: HashSet<_> = branches
-                    .iter()
-                    .filter_map(|branch| {
-                        if let Protocol::Send { to, .. } = &branch.protocol {
-                            Some(to.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-
-                if recipients.len() > 1 {
-                    self.warnings
-                        .push(AnalysisWarning::AsymmetricChoice(role.clone()));
+                for branch in branches {
+                    self.collect(&branch.protocol);
                 }
+            }
 
+            Protocol::Race { branches } => {
                 for branch in branches {
-                    self.analyze_protocol(&branch.protocol);
+                    self.collect(&branch.protocol);
                 }
             }
 
-            Protocol::Loop { body, .. } => {
-                self.analyze_protocol(body);
+            Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => {
+                self.collect(body);
             }
 
             Protocol::Parallel { protocols } => {
                 for p in protocols {
-                    self.analyze_protocol(p);
+                    self.collect(p);
                 }
             }
 
-            Protocol::Rec { body, .. } => {
-                self.analyze_protocol(body);
+            Protocol::Finally { body, cleanup }
+            | Protocol::TryCatch {
+                body,
+                compensation: cleanup,
+                ..
             }
-
-            Protocol::Var(_) | Protocol::End => {}
-        }
-    }
-
-    fn check_deadlock_freedom(&self) -> bool {
-        // Simple check: ensure no circular waiting patterns
-        // More sophisticated analysis would use session type techniques
-
-        // Build dependency graph
-        let mut dependencies: HashMap<Role, HashSet<Role>> = HashMap::new();
-        for role in &self.choreography.roles {
-            dependencies.insert(role.clone(), HashSet::new());
-        }
-
-        // Analyze protocol for dependencies
-        Self::extract_dependencies(&self.choreography.protocol, &mut dependencies);
-
-        // Check for cycles using DFS
-        !has_cycle(&dependencies)
-    }
-
-    fn extract_dependencies(protocol: &Protocol, deps: &mut HashMap<Role, HashSet<Role>>) {
-        match protocol {
-            Protocol::Send {
-                from,
-                to,
-                continuation,
+            | Protocol::Timeout {
+                body,
+                on_timeout: cleanup,
                 ..
             } => {
-                if let Some(to_deps) = deps.get_mut(to) {
-                    to_deps.insert(from.clone());
-                }
-                Self::extract_dependencies(continuation, deps);
-            }
-            Protocol::Choice { branches, .. } => {
-                for branch in branches {
-                    Self::extract_dependencies(&branch.protocol, deps);
-                }
-            }
-            Protocol::Loop { body, .. } => {
-                Self::extract_dependencies(body, deps);
-            }
-            Protocol::Parallel { protocols } => {
-                // Parallel branches don't create dependencies between them
-                for p in protocols {
-                    Self::extract_dependencies(p, deps);
-                }
-            }
-            Protocol::Rec { body, .. } => {
-                Self::extract_dependencies(body, deps);
+                self.collect(body);
+                self.collect(cleanup);
             }
-            Protocol::Broadcast { continuation, .. } => {
-                Self::extract_dependencies(continuation, deps);
+
+            Protocol::Spawn {
+                body, continuation, ..
+            } => {
+                self.collect(body);
+                self.collect(continuation);
             }
-            Protocol::Var(_) | Protocol::End => {}
-        }
-    }
 
-    fn check_progress(&self) -> bool {
-        // Check that the protocol eventually terminates or makes progress
-        Self::check_protocol_progress(&self.choreography.protocol)
-    }
+            Protocol::Await { continuation, .. } => self.collect(continuation),
 
-    fn check_protocol_progress(protocol: &Protocol) -> bool {
-        match protocol {
-            Protocol::End => true,
-            Protocol::Send { continuation, .. } => {
-                // Send is progress
-                Self::check_protocol_progress(continuation)
-            }
-            Protocol::Choice { branches, .. } => {
-                // All branches must have progress
-                branches
-                    .iter()
-                    .all(|b| Self::check_protocol_progress(&b.protocol))
-            }
-            Protocol::Loop { body, .. } => {
-                // Check that loop body has communication (progress)
-                has_communication(body)
-            }
-            Protocol::Parallel { protocols } => protocols.iter().all(Self::check_protocol_progress),
-            Protocol::Rec { body, .. } => {
-                // Recursive protocols must have communication
-                has_communication(body)
-            }
-            Protocol::Var(_) => true, // Assume recursive calls are okay
-            Protocol::Broadcast { continuation, .. } => Self::check_protocol_progress(continuation),
+            Protocol::Var(_) | Protocol::End => {}
         }
     }
 
-    fn compute_participation_info(&self) -> HashMap<Role, ParticipationInfo> {
+    fn participation_info(&self) -> HashMap<Role, ParticipationInfo> {
         let mut result = HashMap::new();
 
         for (role, stats) in &self.role_stats {
@@ -303,53 +1742,72 @@ impl<'a> Analyzer<'a> {
 
 // Helper functions
 
-fn has_cycle(graph: &HashMap<Role, HashSet<Role>>) -> bool {
-    let mut visited = HashSet::new();
-    let mut rec_stack = HashSet::new();
-
-    for node in graph.keys() {
-        if !visited.contains(node) && dfs_cycle(node, graph, &mut visited, &mut rec_stack) {
-            return true;
+fn check_protocol_progress(protocol: &Protocol) -> bool {
+    match protocol {
+        Protocol::End => true,
+        Protocol::Send { continuation, .. } => {
+            // Send is progress
+            check_protocol_progress(continuation)
         }
-    }
-
-    false
-}
-
-fn dfs_cycle(
-    node: &Role,
-    graph: &HashMap<Role, HashSet<Role>>,
-    visited: &mut HashSet<Role>,
-    rec_stack: &mut HashSet<Role>,
-) -> bool {
-    visited.insert(node.clone());
-    rec_stack.insert(node.clone());
-
-    if let Some(neighbors) = graph.get(node) {
-        for neighbor in neighbors {
-            if !visited.contains(neighbor) {
-                if dfs_cycle(neighbor, graph, visited, rec_stack) {
-                    return true;
-                }
-            } else if rec_stack.contains(neighbor) {
-                return true;
-            }
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+            // All branches must have progress
+            branches
+                .iter()
+                .all(|b| check_protocol_progress(&b.protocol))
+        }
+        Protocol::Loop { body, .. } => {
+            // Check that loop body has communication (progress)
+            has_communication(body)
+        }
+        Protocol::Parallel { protocols } => protocols.iter().all(check_protocol_progress),
+        Protocol::Rec { body, .. } => {
+            // Recursive protocols must have communication
+            has_communication(body)
         }
+        Protocol::Var(_) => true, // Assume recursive calls are okay
+        Protocol::Broadcast { continuation, .. } => check_protocol_progress(continuation),
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => check_protocol_progress(body) && check_protocol_progress(cleanup),
+        Protocol::Spawn {
+            body, continuation, ..
+        } => check_protocol_progress(body) && check_protocol_progress(continuation),
+        Protocol::Await { continuation, .. } => check_protocol_progress(continuation),
     }
-
-    rec_stack.remove(node);
-    false
 }
 
 fn has_communication(protocol: &Protocol) -> bool {
     match protocol {
         Protocol::Send { .. } | Protocol::Broadcast { .. } => true,
-        Protocol::Choice { branches, .. } => {
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
             branches.iter().any(|b| has_communication(&b.protocol))
         }
         Protocol::Loop { body, .. } => has_communication(body),
         Protocol::Parallel { protocols } => protocols.iter().any(has_communication),
         Protocol::Rec { body, .. } => has_communication(body),
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => has_communication(body) || has_communication(cleanup),
+        Protocol::Spawn {
+            body, continuation, ..
+        } => has_communication(body) || has_communication(continuation),
+        Protocol::Await { continuation, .. } => has_communication(continuation),
         Protocol::Var(_) | Protocol::End => false,
     }
 }
@@ -0,0 +1,412 @@
+// On-disk cache for projection and code generation outputs
+//
+// Build scripts and tools that compile many choreographies can keep
+// generated code between runs. Entries are content addressed: the
+// directory name is a fingerprint of the choreography, so an edited
+// protocol never reads stale output and unchanged protocols skip
+// projection and codegen entirely.
+
+use crate::ast::{Choreography, Condition, MessageType, Protocol, Role};
+use crate::compiler::codegen::generate_session_type;
+use crate::compiler::effects_codegen::generate_effects_protocol;
+use crate::compiler::projection::{project, ProjectionError};
+use proc_macro2::TokenStream;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Errors that can occur while filling the cache
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    #[error("cache I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("projection failed: {0}")]
+    Projection(#[from] ProjectionError),
+}
+
+/// Content hash of a choreography
+///
+/// Covers the name, roles, attributes, and protocol, plus the version of
+/// this crate so upgrading the compiler invalidates old entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint(u128);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// Compute the fingerprint of a choreography
+///
+/// Uses 128-bit FNV-1a over a canonical encoding of the AST, which is
+/// stable across runs and toolchains.
+pub fn fingerprint(choreography: &Choreography) -> Fingerprint {
+    let mut canonical = format!("rumpsteak-choreography {}\n", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(canonical, "name {}", choreography.name);
+
+    let mut attrs: Vec<_> = choreography.attrs.iter().collect();
+    attrs.sort();
+    for (key, value) in attrs {
+        let _ = writeln!(canonical, "attr {}={}", key, value);
+    }
+    for r in &choreography.returns {
+        let _ = writeln!(canonical, "returns {} {}", r.role.name, r.message);
+    }
+    for route in &choreography.routes {
+        let _ = write!(canonical, "route {} {}", route.from.name, route.message);
+        for candidate in &route.candidates {
+            let _ = write!(canonical, " {}", candidate.name);
+        }
+        canonical.push('\n');
+    }
+    for (name, id) in &choreography.wire_ids {
+        let _ = writeln!(canonical, "id {}={}", name, id);
+    }
+    for role in &choreography.trusted {
+        let _ = writeln!(canonical, "trusted {}", role.name);
+    }
+    for role in &choreography.roles {
+        canonical.push_str("role ");
+        encode_role(role, &mut canonical);
+        canonical.push('\n');
+    }
+    encode_protocol(&choreography.protocol, &mut canonical);
+
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    let hash = canonical.bytes().fold(OFFSET, |hash, byte| {
+        (hash ^ u128::from(byte)).wrapping_mul(PRIME)
+    });
+    Fingerprint(hash)
+}
+
+/// Cache of generated code rooted at a directory
+///
+/// Entries live at `<dir>/<fingerprint>/<entry>.rs`. Writes go through a
+/// temporary file and a rename, so concurrent builds never see a partial
+/// entry. Unreadable entries are treated as misses and regenerated.
+#[derive(Debug)]
+pub struct ProjectionCache {
+    dir: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// Hit and miss counts since the cache was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl ProjectionCache {
+    /// Open the cache, creating the directory if needed
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(ProjectionCache {
+            dir,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Session type of one role, projecting only on a miss
+    pub fn session_type(
+        &self,
+        choreography: &Choreography,
+        role: &Role,
+    ) -> Result<TokenStream, CacheError> {
+        let entry = format!("{}.session", role_key(role));
+        self.get_or_insert_with(fingerprint(choreography), &entry, || {
+            let local_type = project(choreography, role)?;
+            Ok(generate_session_type(
+                role,
+                &local_type,
+                &choreography.name.to_string(),
+            ))
+        })
+    }
+
+    /// Effect-based protocol code for the whole choreography
+    pub fn effects_protocol(&self, choreography: &Choreography) -> Result<TokenStream, CacheError> {
+        self.get_or_insert_with(fingerprint(choreography), "protocol.effects", || {
+            Ok(generate_effects_protocol(choreography))
+        })
+    }
+
+    /// Look up an entry, or generate and store it
+    pub fn get_or_insert_with(
+        &self,
+        fingerprint: Fingerprint,
+        entry: &str,
+        generate: impl FnOnce() -> Result<TokenStream, CacheError>,
+    ) -> Result<TokenStream, CacheError> {
+        if let Some(tokens) = self.get(fingerprint, entry) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(tokens);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let tokens = generate()?;
+        self.put(fingerprint, entry, &tokens)?;
+        Ok(tokens)
+    }
+
+    /// Read an entry, if present and well-formed
+    pub fn get(&self, fingerprint: Fingerprint, entry: &str) -> Option<TokenStream> {
+        let source = fs::read_to_string(self.entry_path(fingerprint, entry)).ok()?;
+        source.parse().ok()
+    }
+
+    /// Store an entry, replacing any previous contents
+    pub fn put(
+        &self,
+        fingerprint: Fingerprint,
+        entry: &str,
+        tokens: &TokenStream,
+    ) -> io::Result<()> {
+        let path = self.entry_path(fingerprint, entry);
+        fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, tokens.to_string())?;
+        fs::rename(&tmp, &path)
+    }
+
+    /// Remove the entries of every choreography not in `keep`
+    ///
+    /// Returns the number of fingerprints removed.
+    pub fn retain(&self, keep: &[Fingerprint]) -> io::Result<usize> {
+        let keep: Vec<String> = keep.iter().map(ToString::to_string).collect();
+        let mut removed = 0;
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            if dir_entry.file_type()?.is_dir() && !keep.contains(&name) {
+                fs::remove_dir_all(dir_entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn entry_path(&self, fingerprint: Fingerprint, entry: &str) -> PathBuf {
+        self.dir
+            .join(fingerprint.to_string())
+            .join(format!("{}.rs", entry))
+    }
+}
+
+fn role_key(role: &Role) -> String {
+    match role.index {
+        Some(index) => format!("{}_{}", role.name, index),
+        None => role.name.to_string(),
+    }
+}
+
+fn encode_role(role: &Role, out: &mut String) {
+    out.push_str(&role.name.to_string());
+    if let Some(index) = role.index {
+        let _ = write!(out, "[{}]", index);
+    }
+    if let Some(param) = &role.param {
+        let _ = write!(out, "[{}]", param);
+    }
+    if let Some(size) = &role.array_size {
+        let _ = write!(out, "#{}", size);
+    }
+}
+
+fn encode_message(message: &MessageType, out: &mut String) {
+    out.push_str(&message.name.to_string());
+    if let Some(ty) = &message.type_annotation {
+        let _ = write!(out, "<{}>", ty);
+    }
+    if let Some(payload) = &message.payload {
+        let _ = write!(out, "({})", payload);
+    }
+}
+
+fn encode_protocol(protocol: &Protocol, out: &mut String) {
+    match protocol {
+        Protocol::Send {
+            from,
+            to,
+            message,
+            continuation,
+        } => {
+            out.push_str("(send ");
+            encode_role(from, out);
+            out.push(' ');
+            encode_role(to, out);
+            out.push(' ');
+            encode_message(message, out);
+            out.push(' ');
+            encode_protocol(continuation, out);
+            out.push(')');
+        }
+        Protocol::Broadcast {
+            from,
+            to_all,
+            message,
+            abort,
+            continuation,
+        } => {
+            out.push_str(if *abort { "(abort " } else { "(broadcast " });
+            encode_role(from, out);
+            for to in to_all {
+                out.push(' ');
+                encode_role(to, out);
+            }
+            out.push(' ');
+            encode_message(message, out);
+            out.push(' ');
+            encode_protocol(continuation, out);
+            out.push(')');
+        }
+        Protocol::Choice { role, branches } => {
+            out.push_str("(choice ");
+            encode_role(role, out);
+            for branch in branches {
+                let _ = write!(out, " ({}", branch.label);
+                if let Some(guard) = &branch.guard {
+                    let _ = write!(out, " when {}", guard);
+                }
+                out.push(' ');
+                encode_protocol(&branch.protocol, out);
+                out.push(')');
+            }
+            out.push(')');
+        }
+        Protocol::Race { branches } => {
+            out.push_str("(race");
+            for branch in branches {
+                let _ = write!(out, " ({} ", branch.label);
+                encode_protocol(&branch.protocol, out);
+                out.push(')');
+            }
+            out.push(')');
+        }
+        Protocol::Loop { condition, body } => {
+            out.push_str("(loop ");
+            match condition {
+                Some(Condition::RoleDecides(role)) => {
+                    out.push_str("decides ");
+                    encode_role(role, out);
+                }
+                Some(Condition::Count(n)) => {
+                    let _ = write!(out, "count {}", n);
+                }
+                Some(Condition::Custom(tokens)) => {
+                    let _ = write!(out, "custom {}", tokens);
+                }
+                None => out.push('_'),
+            }
+            out.push(' ');
+            encode_protocol(body, out);
+            out.push(')');
+        }
+        Protocol::Parallel { protocols } => {
+            out.push_str("(parallel");
+            for p in protocols {
+                out.push(' ');
+                encode_protocol(p, out);
+            }
+            out.push(')');
+        }
+        Protocol::Rec { label, body } => {
+            let _ = write!(out, "(rec {} ", label);
+            encode_protocol(body, out);
+            out.push(')');
+        }
+        Protocol::Var(label) => {
+            let _ = write!(out, "(var {})", label);
+        }
+        Protocol::Finally { body, cleanup } => {
+            out.push_str("(finally ");
+            encode_protocol(body, out);
+            out.push(' ');
+            encode_protocol(cleanup, out);
+            out.push(')');
+        }
+        Protocol::TryCatch {
+            body,
+            handler_roles,
+            compensation,
+        } => {
+            out.push_str("(try");
+            for role in handler_roles {
+                out.push(' ');
+                encode_role(role, out);
+            }
+            out.push(' ');
+            encode_protocol(body, out);
+            out.push(' ');
+            encode_protocol(compensation, out);
+            out.push(')');
+        }
+        Protocol::Timeout {
+            role,
+            duration,
+            body,
+            on_timeout,
+        } => {
+            out.push_str("(timeout ");
+            encode_role(role, out);
+            let _ = write!(out, " {} ", duration.as_nanos());
+            encode_protocol(body, out);
+            out.push(' ');
+            encode_protocol(on_timeout, out);
+            out.push(')');
+        }
+        Protocol::Spawn {
+            handle,
+            name,
+            roles,
+            body,
+            continuation,
+        } => {
+            let _ = write!(out, "(spawn {} {}", handle, name);
+            for role in roles {
+                out.push(' ');
+                encode_role(role, out);
+            }
+            out.push(' ');
+            encode_protocol(body, out);
+            out.push(' ');
+            encode_protocol(continuation, out);
+            out.push(')');
+        }
+        Protocol::Await {
+            handle,
+            roles,
+            continuation,
+        } => {
+            let _ = write!(out, "(await {}", handle);
+            for role in roles {
+                out.push(' ');
+                encode_role(role, out);
+            }
+            out.push(' ');
+            encode_protocol(continuation, out);
+            out.push(')');
+        }
+        Protocol::End => out.push_str("end"),
+    }
+}
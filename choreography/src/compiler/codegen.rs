@@ -76,6 +76,14 @@ fn generate_type_expr(local_type: &LocalType) -> TokenStream {
             }
         }
 
+        LocalType::Race { branches } => {
+            let choice_type = generate_choice_enum(branches, false);
+
+            quote! {
+                Race<#choice_type>
+            }
+        }
+
         LocalType::Loop { condition, body } => {
             let body_expr = generate_type_expr(body);
 
@@ -133,6 +141,19 @@ fn generate_type_expr(local_type: &LocalType) -> TokenStream {
             quote! { #label }
         }
 
+        LocalType::Finally { body, cleanup } => {
+            // Session types have no exceptional exit, so the normal path is
+            // the body followed by the cleanup
+            let sequenced = (**body).clone().then((**cleanup).clone());
+            generate_type_expr(&sequenced)
+        }
+
+        LocalType::TryCatch { body, .. } | LocalType::Timeout { body, .. } => {
+            // The compensation or the else of a timeout only runs once the
+            // session was aborted, so the session type follows the body
+            generate_type_expr(body)
+        }
+
         LocalType::End => {
             quote! { End }
         }
@@ -171,22 +192,44 @@ fn generate_choice_enum(branches: &[(Ident, LocalType)], _is_select: bool) -> To
 }
 
 /// Generate complete Rumpsteak code from a choreography
+///
+/// Session types are emitted in the order of `roles`, whatever the order of
+/// `local_types`, so the output does not depend on how projections were
+/// collected. They are generated on the calling thread, since local types
+/// hold `proc_macro2` identifiers that cannot be sent to other threads.
 pub fn generate_choreography_code(
     name: &str,
     roles: &[Role],
     local_types: &[(Role, LocalType)],
 ) -> TokenStream {
+    let module = format_ident!("{}", super::namespace::snake_case(name));
     let role_struct_defs = generate_role_structs(roles);
-    let session_type_defs = local_types
-        .iter()
+
+    let mut ordered: Vec<&(Role, LocalType)> = local_types.iter().collect();
+    ordered.sort_by_key(|(role, _)| roles.iter().position(|r| r == role).unwrap_or(roles.len()));
+    let session_type_defs = ordered
+        .into_iter()
         .map(|(role, local_type)| generate_session_type(role, local_type, name));
 
     quote! {
-        #role_struct_defs
-        #(#session_type_defs)*
+        pub mod #module {
+            use super::*;
+
+            #role_struct_defs
+            #(#session_type_defs)*
+        }
     }
 }
 
+/// Format generated code as rustfmt-style source
+///
+/// Doc attributes come out as `///` comments, so provenance links stay
+/// readable in the formatted output.
+pub fn pretty_print(tokens: &TokenStream) -> syn::Result<String> {
+    let file: syn::File = syn::parse2(tokens.clone())?;
+    Ok(prettyplease::unparse(&file))
+}
+
 /// Generate role struct definitions
 fn generate_role_structs(roles: &[Role]) -> TokenStream {
     let _n = roles.len();
@@ -344,7 +387,12 @@ pub fn generate_helpers(_name: &str, messages: &[MessageType]) -> TokenStream {
 
     let message_structs = messages.iter().map(|msg| {
         let name = &msg.name;
-        if let Some(payload) = &msg.payload {
+        if let Some(fields) = msg.fields() {
+            // Tags only matter to the effects protocol
+            let names = fields.iter().map(|field| &field.name);
+            let types = fields.iter().map(|field| &field.ty);
+            quote! { struct #name { #(#names: #types),* } }
+        } else if let Some(payload) = &msg.payload {
             quote! { struct #name #payload; }
         } else {
             quote! { struct #name; }
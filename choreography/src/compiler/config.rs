@@ -0,0 +1,161 @@
+// Compile-time configuration for choreography sources
+//
+// A `#[cfg(...)] { ... }` block in the DSL is kept or dropped depending on
+// the configuration the parser is given, so one protocol source can serve
+// several deployment variants. The same configuration supplies values for
+// `const` declarations, and the limits protocols are validated against.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ast::ProtocolLimits;
+
+/// Features and cfg values visible to `#[cfg(...)]` blocks, values for
+/// `const` declarations, and limits on the size of protocols
+///
+/// Feature names are normalized like Cargo does for `CARGO_FEATURE_*`:
+/// case and the difference between `-` and `_` are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileConfig {
+    features: BTreeSet<String>,
+    flags: BTreeSet<String>,
+    values: BTreeMap<String, String>,
+    consts: BTreeMap<String, usize>,
+    limits: ProtocolLimits,
+}
+
+impl CompileConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read features and cfg values from the Cargo build script environment
+    ///
+    /// `CARGO_FEATURE_AUDIT` enables `feature = "audit"`, and
+    /// `CARGO_CFG_TARGET_OS=linux` sets `target_os = "linux"`. Cfg keys
+    /// without a value, such as `CARGO_CFG_UNIX`, become flags. Outside a
+    /// build script the result is empty.
+    ///
+    /// `RUMPSTEAK_CONST_N=8` sets the constant `N`; values that are not a
+    /// valid `usize` are ignored.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        for (key, value) in std::env::vars() {
+            if let Some(name) = key.strip_prefix("RUMPSTEAK_CONST_") {
+                if let Ok(value) = value.parse() {
+                    config.consts.insert(name.to_string(), value);
+                }
+            } else if let Some(feature) = key.strip_prefix("CARGO_FEATURE_") {
+                config.features.insert(normalize_feature(feature));
+            } else if let Some(cfg) = key.strip_prefix("CARGO_CFG_") {
+                let cfg = cfg.to_lowercase();
+                if value.is_empty() {
+                    config.flags.insert(cfg);
+                } else {
+                    config.values.insert(cfg, value);
+                }
+            }
+        }
+        config
+    }
+
+    /// Enable a feature
+    pub fn with_feature(mut self, feature: impl AsRef<str>) -> Self {
+        self.features.insert(normalize_feature(feature.as_ref()));
+        self
+    }
+
+    /// Set a bare cfg flag, matched by `#[cfg(name)]`
+    pub fn with_flag(mut self, flag: impl Into<String>) -> Self {
+        self.flags.insert(flag.into());
+        self
+    }
+
+    /// Set a cfg value, matched by `#[cfg(key = "value")]`
+    pub fn with_value(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.values.insert(key.into(), value.into());
+        self
+    }
+
+    /// Set a constant, overriding the default in its `const` declaration
+    pub fn with_const(mut self, name: impl Into<String>, value: usize) -> Self {
+        self.consts.insert(name.into(), value);
+        self
+    }
+
+    /// Validate protocols against `limits` rather than the defaults
+    pub fn with_limits(mut self, limits: ProtocolLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.contains(&normalize_feature(feature))
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(flag)
+    }
+
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn const_value(&self, name: &str) -> Option<usize> {
+        self.consts.get(name).copied()
+    }
+
+    pub fn limits(&self) -> &ProtocolLimits {
+        &self.limits
+    }
+}
+
+/// Condition of a `#[cfg(...)]` block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfgPredicate {
+    /// `name`
+    Flag(String),
+    /// `key = "value"`; `feature = "x"` checks the enabled features
+    KeyValue(String, String),
+    /// `not(p)`
+    Not(Box<CfgPredicate>),
+    /// `all(p, q, ...)`, true when empty
+    All(Vec<CfgPredicate>),
+    /// `any(p, q, ...)`, false when empty
+    Any(Vec<CfgPredicate>),
+}
+
+impl CfgPredicate {
+    pub fn evaluate(&self, config: &CompileConfig) -> bool {
+        match self {
+            CfgPredicate::Flag(name) => config.has_flag(name),
+            CfgPredicate::KeyValue(key, value) if key == "feature" => config.has_feature(value),
+            CfgPredicate::KeyValue(key, value) => config.value(key) == Some(value.as_str()),
+            CfgPredicate::Not(inner) => !inner.evaluate(config),
+            CfgPredicate::All(predicates) => predicates.iter().all(|p| p.evaluate(config)),
+            CfgPredicate::Any(predicates) => predicates.iter().any(|p| p.evaluate(config)),
+        }
+    }
+}
+
+impl std::fmt::Display for CfgPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |predicates: &[CfgPredicate]| {
+            predicates
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            CfgPredicate::Flag(name) => write!(f, "{}", name),
+            CfgPredicate::KeyValue(key, value) => write!(f, "{} = \"{}\"", key, value),
+            CfgPredicate::Not(inner) => write!(f, "not({})", inner),
+            CfgPredicate::All(predicates) => write!(f, "all({})", list(predicates)),
+            CfgPredicate::Any(predicates) => write!(f, "any({})", list(predicates)),
+        }
+    }
+}
+
+fn normalize_feature(feature: &str) -> String {
+    feature.to_lowercase().replace('-', "_")
+}
@@ -0,0 +1,466 @@
+// Deadlock detection over projected local types
+//
+// A choreography cannot deadlock by itself, but the local types projection
+// builds from it can: parallel branches are sequenced per role, branches a
+// role is not told about are merged, and a role may lose the part of its
+// type that follows a loop. This module runs the projections against each
+// other the way the runtime would. Sends are queued per pair of roles,
+// receives and branches block until their message or label is at the head
+// of the queue, and every choice is tried. A state in which some role has
+// not finished and no role can move is a deadlock.
+//
+// The waits of the stuck roles form the dependency graph between roles. A
+// cycle in it means roles wait on each other; otherwise the chain of waits
+// ends at a role that has finished. Loops run the same bounded number of
+// times for every role, recursion is unfolded until states repeat, and a
+// path that reaches a race is not followed further, since which arm wins
+// depends on timing.
+
+use crate::ast::{Condition, LocalType, Role};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// Iterations explored for loops without a smaller fixed count
+const LOOP_ITERATIONS: usize = 2;
+
+/// States explored before the search gives up and reports nothing
+const MAX_STATES: usize = 20_000;
+
+/// Pending messages between two roles beyond which a path is not followed
+const MAX_QUEUE: usize = 8;
+
+/// A reachable state in which some roles wait forever
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deadlock {
+    /// Every role that has not finished, with what it waits for
+    pub waits: Vec<Wait>,
+    /// Roles waiting on each other, each on the next and the last on the
+    /// first; empty when the waits end at a role that has finished
+    pub cycle: Vec<Role>,
+    /// Shortest sequence of steps from the start to the stuck state
+    pub trace: Vec<String>,
+}
+
+/// One edge of the dependency graph at a stuck state
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wait {
+    pub role: Role,
+    /// The role the message or label has to come from
+    pub on: Role,
+    /// Name of the expected message, or the labels of a branch
+    pub expects: String,
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cycle.is_empty() {
+            let waits: Vec<_> = self
+                .waits
+                .iter()
+                .map(|w| format!("`{}` waits for {} from `{}`", w.role, w.expects, w.on))
+                .collect();
+            write!(f, "{}, which never comes", waits.join(", "))?;
+        } else {
+            let mut cycle: Vec<_> = self.cycle.iter().map(|r| format!("`{}`", r)).collect();
+            cycle.push(cycle[0].clone());
+            write!(
+                f,
+                "roles wait on each other in a cycle: {}",
+                cycle.join(" -> ")
+            )?;
+        }
+        if self.trace.is_empty() {
+            write!(f, " (at the start)")
+        } else {
+            write!(f, " (after {})", self.trace.join("; "))
+        }
+    }
+}
+
+/// Look for a reachable state in which the given roles wait forever
+///
+/// Returns `None` when every explored path lets each role finish, or when
+/// the search gives up after a bounded number of states.
+pub fn find_deadlock(local_types: &[(Role, &LocalType)]) -> Option<Deadlock> {
+    let roles: Vec<&Role> = local_types.iter().map(|(role, _)| role).collect();
+    let start = State {
+        processes: local_types
+            .iter()
+            .map(|(_, local_type)| Process::start(local_type))
+            .collect(),
+        queues: BTreeMap::new(),
+    };
+
+    // Breadth-first, so the first stuck state has the shortest trace
+    let mut seen: HashMap<State<'_>, usize> = HashMap::new();
+    let mut parents: Vec<Option<(usize, String)>> = Vec::new();
+    let mut states = vec![start.clone()];
+    seen.insert(start, 0);
+    parents.push(None);
+    let mut next = 0;
+
+    while next < states.len() && states.len() < MAX_STATES {
+        let state = states[next].clone();
+        let id = next;
+        next += 1;
+
+        let mut moves = Vec::new();
+        let mut racing = false;
+        for i in 0..state.processes.len() {
+            racing |= state.steps(i, &roles, &mut moves);
+        }
+        if moves.is_empty() && !racing && !state.finished() {
+            return Some(state.deadlock(&roles, trace(&parents, id)));
+        }
+        for (step, successor) in moves {
+            if successor.queues.values().any(|q| q.len() > MAX_QUEUE) {
+                continue;
+            }
+            if !seen.contains_key(&successor) {
+                seen.insert(successor.clone(), states.len());
+                states.push(successor);
+                parents.push(Some((id, step)));
+            }
+        }
+    }
+    None
+}
+
+fn trace(parents: &[Option<(usize, String)>], mut id: usize) -> Vec<String> {
+    let mut steps = Vec::new();
+    while let Some((parent, step)) = &parents[id] {
+        steps.push(step.clone());
+        id = *parent;
+    }
+    steps.reverse();
+    steps
+}
+
+/// A local type node, compared and hashed by address
+#[derive(Debug, Clone, Copy)]
+struct Node<'a>(&'a LocalType);
+
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Node<'_> {}
+
+impl Hash for Node<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self.0, state)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Item {
+    Message(String),
+    Label(String),
+}
+
+/// Where a role resumes once the node it is in reaches `End`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Frame<'a> {
+    /// A loop with this many iterations left after the current one
+    Loop { node: Node<'a>, remaining: usize },
+    /// A recursion point that `Var` jumps back to
+    Rec(Node<'a>),
+    /// Cleanup to run after the body of a `Finally`
+    Cleanup(Node<'a>),
+}
+
+/// Position of one role, `at` is `None` once it has finished
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Process<'a> {
+    at: Option<Node<'a>>,
+    frames: Vec<Frame<'a>>,
+}
+
+impl<'a> Process<'a> {
+    fn start(local_type: &'a LocalType) -> Self {
+        Process {
+            at: Some(Node(local_type)),
+            frames: Vec::new(),
+        }
+        .settle()
+    }
+
+    fn then(&self, next: &'a LocalType) -> Self {
+        Process {
+            at: Some(Node(next)),
+            frames: self.frames.clone(),
+        }
+        .settle()
+    }
+
+    /// Unfold loops, recursion, and cleanups until the role is at a
+    /// communication, a choice, or has finished
+    fn settle(mut self) -> Self {
+        // Bounds degenerate recursion such as `rec X { X }`
+        for _ in 0..64 {
+            let Some(Node(node)) = self.at else {
+                return self;
+            };
+            match node {
+                LocalType::End => {
+                    self.at = None;
+                    while let Some(frame) = self.frames.pop() {
+                        match frame {
+                            Frame::Loop { node, remaining } if remaining > 0 => {
+                                let LocalType::Loop { body, .. } = node.0 else {
+                                    unreachable!("loop frames hold loops")
+                                };
+                                self.frames.push(Frame::Loop {
+                                    node,
+                                    remaining: remaining - 1,
+                                });
+                                self.at = Some(Node(body));
+                                break;
+                            }
+                            Frame::Cleanup(cleanup) => {
+                                self.at = Some(cleanup);
+                                break;
+                            }
+                            Frame::Loop { .. } | Frame::Rec(_) => {}
+                        }
+                    }
+                }
+                LocalType::Loop { condition, body } => {
+                    let iterations = match condition {
+                        Some(Condition::Count(n)) => (*n).min(LOOP_ITERATIONS),
+                        _ => LOOP_ITERATIONS,
+                    };
+                    if iterations == 0 {
+                        self.at = Some(Node(&LocalType::End));
+                    } else {
+                        self.frames.push(Frame::Loop {
+                            node: Node(node),
+                            remaining: iterations - 1,
+                        });
+                        self.at = Some(Node(body));
+                    }
+                }
+                LocalType::Rec { body, .. } => {
+                    self.frames.push(Frame::Rec(Node(node)));
+                    self.at = Some(Node(body));
+                }
+                LocalType::Var(label) => {
+                    let target = self.frames.iter().rposition(|frame| {
+                        matches!(frame, Frame::Rec(Node(LocalType::Rec { label: l, .. })) if l == label)
+                    });
+                    match target {
+                        Some(i) => {
+                            self.frames.truncate(i + 1);
+                            let Frame::Rec(Node(LocalType::Rec { body, .. })) = &self.frames[i]
+                            else {
+                                unreachable!("matched above")
+                            };
+                            self.at = Some(Node(body));
+                        }
+                        // A free variable ends the role, as projection treats it
+                        None => self.at = Some(Node(&LocalType::End)),
+                    }
+                }
+                LocalType::Finally { body, cleanup } => {
+                    self.frames.push(Frame::Cleanup(Node(cleanup)));
+                    self.at = Some(Node(body));
+                }
+                // Only runs without errors or expired timers are explored
+                LocalType::TryCatch { body, .. } | LocalType::Timeout { body, .. } => {
+                    self.at = Some(Node(body))
+                }
+                _ => return self,
+            }
+        }
+        self.at = None;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State<'a> {
+    processes: Vec<Process<'a>>,
+    queues: BTreeMap<(usize, usize), VecDeque<Item>>,
+}
+
+impl<'a> State<'a> {
+    fn finished(&self) -> bool {
+        self.processes.iter().all(|p| p.at.is_none())
+    }
+
+    fn front(&self, from: usize, to: usize) -> Option<&Item> {
+        self.queues.get(&(from, to)).and_then(|q| q.front())
+    }
+
+    fn moved(&self, i: usize, process: Process<'a>) -> Self {
+        let mut state = self.clone();
+        state.processes[i] = process;
+        state
+    }
+
+    fn sent(mut self, from: usize, to: usize, item: Item) -> Self {
+        self.queues.entry((from, to)).or_default().push_back(item);
+        self
+    }
+
+    fn received(mut self, from: usize, to: usize) -> Self {
+        if let Some(queue) = self.queues.get_mut(&(from, to)) {
+            queue.pop_front();
+            if queue.is_empty() {
+                self.queues.remove(&(from, to));
+            }
+        }
+        self
+    }
+
+    /// Push every move role `i` can make onto `moves`, returning true when
+    /// the role is at a race and the path should not be judged
+    fn steps(&self, i: usize, roles: &[&Role], moves: &mut Vec<(String, State<'a>)>) -> bool {
+        let Some(Node(node)) = self.processes[i].at else {
+            return false;
+        };
+        let process = &self.processes[i];
+        let me = roles[i];
+        let index = |role: &Role| roles.iter().position(|r| *r == role);
+        match node {
+            LocalType::Send {
+                to,
+                message,
+                continuation,
+            } => {
+                let step = format!("{} -> {}: {}", me, to, message.name);
+                let state = self.moved(i, process.then(continuation));
+                let state = match index(to) {
+                    Some(j) => state.sent(i, j, Item::Message(message.name.to_string())),
+                    None => state,
+                };
+                moves.push((step, state));
+            }
+            LocalType::Receive {
+                from,
+                message,
+                continuation,
+            } => {
+                let Some(j) = index(from) else {
+                    return false;
+                };
+                if self.front(j, i) == Some(&Item::Message(message.name.to_string())) {
+                    let step = format!("{} receives {} from {}", me, message.name, from);
+                    let state = self.moved(i, process.then(continuation)).received(j, i);
+                    moves.push((step, state));
+                }
+            }
+            LocalType::Select { to, branches } => {
+                for (label, branch) in branches {
+                    let step = format!("{} chooses {} and tells {}", me, label, to);
+                    let state = self.moved(i, process.then(branch));
+                    let state = match index(to) {
+                        Some(j) => state.sent(i, j, Item::Label(label.to_string())),
+                        None => state,
+                    };
+                    moves.push((step, state));
+                }
+            }
+            LocalType::Branch { from, branches } => {
+                let Some(j) = index(from) else {
+                    return false;
+                };
+                // The chooser's label stands for the first message of the
+                // branch, which the branch still lists as a receive
+                let chosen = match self.front(j, i) {
+                    Some(Item::Label(label)) => branches.iter().find(|(l, _)| l == label).map(
+                        |(label, branch)| match branch {
+                            LocalType::Receive {
+                                from: sender,
+                                continuation,
+                                ..
+                            } if sender == from => (label, &**continuation),
+                            _ => (label, branch),
+                        },
+                    ),
+                    Some(Item::Message(name)) => {
+                        branches.iter().find_map(|(label, branch)| match branch {
+                            LocalType::Receive {
+                                from: sender,
+                                message,
+                                continuation,
+                            } if sender == from && message.name == name => {
+                                Some((label, &**continuation))
+                            }
+                            _ => None,
+                        })
+                    }
+                    None => None,
+                };
+                if let Some((label, branch)) = chosen {
+                    let step = format!("{} learns {} from {}", me, label, from);
+                    let state = self.moved(i, process.then(branch)).received(j, i);
+                    moves.push((step, state));
+                }
+            }
+            LocalType::LocalChoice { branches } => {
+                for (label, branch) in branches {
+                    let step = format!("{} chooses {}", me, label);
+                    moves.push((step, self.moved(i, process.then(branch))));
+                }
+            }
+            LocalType::Race { .. } => return true,
+            // `settle` unfolds everything else
+            _ => {}
+        }
+        false
+    }
+
+    fn deadlock(&self, roles: &[&Role], trace: Vec<String>) -> Deadlock {
+        let mut waits = Vec::new();
+        let mut on: Vec<Option<usize>> = vec![None; roles.len()];
+        for (i, process) in self.processes.iter().enumerate() {
+            let Some(Node(node)) = process.at else {
+                continue;
+            };
+            let (from, expects) = match node {
+                LocalType::Receive { from, message, .. } => (from, format!("`{}`", message.name)),
+                LocalType::Branch { from, branches } => {
+                    let labels: Vec<_> = branches.iter().map(|(l, _)| format!("`{}`", l)).collect();
+                    (from, format!("one of {}", labels.join(", ")))
+                }
+                _ => continue,
+            };
+            on[i] = roles.iter().position(|r| *r == from);
+            waits.push(Wait {
+                role: roles[i].clone(),
+                on: from.clone(),
+                expects,
+            });
+        }
+
+        // Every stuck role waits on exactly one other, so following the
+        // waits from each role either repeats a role or reaches a role that
+        // waits on nothing
+        let mut cycle = Vec::new();
+        for start in 0..roles.len() {
+            let mut path = vec![start];
+            let mut current = start;
+            while let Some(next) = on[current] {
+                if let Some(pos) = path.iter().position(|&r| r == next) {
+                    cycle = path[pos..].iter().map(|&r| roles[r].clone()).collect();
+                    break;
+                }
+                path.push(next);
+                current = next;
+            }
+            if !cycle.is_empty() {
+                break;
+            }
+        }
+
+        Deadlock {
+            waits,
+            cycle,
+            trace,
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Diagnostics shared by the parser and the analyzer
+
+use crate::compiler::parser::ErrorSpan;
+use serde::Serialize;
+use std::fmt;
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A single finding from parsing or analysis
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    /// Stable machine-readable code, e.g. `A001` or `P002`
+    pub code: String,
+    pub severity: Severity,
+    pub message: String,
+    /// Source location, when the finding can be tied to one
+    pub span: Option<ErrorSpan>,
+    /// Suggested fixes, most likely first
+    pub fixits: Vec<FixIt>,
+}
+
+impl Diagnostic {
+    pub fn new(code: impl Into<String>, severity: Severity, message: impl Into<String>) -> Self {
+        Diagnostic {
+            code: code.into(),
+            severity,
+            message: message.into(),
+            span: None,
+            fixits: Vec::new(),
+        }
+    }
+
+    /// Attach a source location
+    pub fn with_span(mut self, span: ErrorSpan) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Attach a suggested fix
+    pub fn with_fixit(mut self, fixit: FixIt) -> Self {
+        self.fixits.push(fixit);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = format!("{}[{}]: {}", self.severity, self.code, self.message);
+        match &self.span {
+            Some(span) => write!(f, "{}", span.format_error(&header).trim_start())?,
+            None => write!(f, "{}", header)?,
+        }
+        for fixit in &self.fixits {
+            write!(f, "\n  help: {}", fixit.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// A machine-applicable fix for a diagnostic
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FixIt {
+    /// Human-readable description, e.g. "did you mean `Bob`?"
+    pub message: String,
+    pub edits: Vec<TextEdit>,
+}
+
+/// Replace the text covered by `span` with `replacement`
+///
+/// An empty span (start equal to end) is an insertion.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextEdit {
+    pub span: ErrorSpan,
+    pub replacement: String,
+}
+
+impl FixIt {
+    pub fn new(message: impl Into<String>, edits: Vec<TextEdit>) -> Self {
+        FixIt {
+            message: message.into(),
+            edits,
+        }
+    }
+
+    /// Apply the edits to the source the diagnostic was produced from
+    ///
+    /// Returns `None` if an edit points outside `source`.
+    pub fn apply(&self, source: &str) -> Option<String> {
+        let mut ranges = self
+            .edits
+            .iter()
+            .map(|edit| {
+                let start = offset_of(source, edit.span.line, edit.span.column)?;
+                let end = offset_of(source, edit.span.line_end, edit.span.column_end)?;
+                Some((start, end.max(start), edit.replacement.as_str()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // Apply back to front so earlier offsets stay valid
+        ranges.sort_by_key(|(start, _, _)| std::cmp::Reverse(*start));
+        let mut output = source.to_string();
+        for (start, end, replacement) in ranges {
+            output.replace_range(start..end, replacement);
+        }
+        Some(output)
+    }
+}
+
+/// Byte offset of a 1-based line and character column
+fn offset_of(source: &str, line: usize, column: usize) -> Option<usize> {
+    let line_start = if line <= 1 {
+        0
+    } else {
+        source
+            .match_indices('\n')
+            .nth(line - 2)
+            .map(|(i, _)| i + 1)?
+    };
+    let rest = &source[line_start..];
+    let line_text = rest.split('\n').next().unwrap_or("");
+    match line_text.char_indices().nth(column.saturating_sub(1)) {
+        Some((i, _)) => Some(line_start + i),
+        None if column.saturating_sub(1) == line_text.chars().count() => {
+            Some(line_start + line_text.len())
+        }
+        None => None,
+    }
+}
+
+/// Edit distance between two names, counting an adjacent swap as one edit
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// The candidate closest to `name`, if it is a plausible typo
+pub(crate) fn closest_match<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(&name.to_lowercase(), &c.to_lowercase()), c))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, c)| (*distance, *c))
+        .map(|(_, c)| c)
+}
@@ -3,42 +3,100 @@
 // This module generates protocol implementations that build
 // effect programs using a free algebra approach.
 
-use crate::ast::{Choreography, Condition, MessageType, Protocol, Role};
-use proc_macro2::TokenStream;
+use crate::ast::{Choreography, Condition, MessageType, PayloadField, Protocol, Role, WireFormat};
+use crate::compiler::namespace::snake_case;
+use crate::compiler::projection::timeout_peers;
+use crate::compiler::provenance::{role_steps, walk_with_paths, Provenance, SourceMap};
+use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// Generate effect-based protocol implementation
+///
+/// Everything is emitted inside a module named after the choreography (see
+/// [`module_name`](super::namespace::module_name)) so several protocols can
+/// share a crate.
+///
+/// Roles are generated one after another on the calling thread. The AST
+/// holds `proc_macro2` identifiers and token streams, which cannot cross
+/// threads, so parallel generation would mean reparsing the source on every
+/// worker; tools with many protocols can run [`check_library`] or one
+/// generation per protocol in parallel instead.
+///
+/// [`check_library`]: super::library::check_library
 pub fn generate_effects_protocol(choreography: &Choreography) -> TokenStream {
-    let protocol_name = &choreography.name;
+    generate_effects(choreography, None)
+}
+
+/// Generate the effect-based protocol with doc comments pointing at the DSL
+///
+/// Each role function lists the source lines of the steps it performs, and
+/// each message struct names the line where the message is first used. The
+/// module also gets a `SOURCE_MAP` constant holding the [`SourceMap`] as
+/// JSON.
+pub fn generate_effects_protocol_with_provenance(
+    choreography: &Choreography,
+    provenance: &Provenance,
+) -> TokenStream {
+    generate_effects(choreography, Some(provenance))
+}
+
+/// Generate the effect-based protocol as formatted Rust source
+///
+/// With a [`Provenance`] the output carries the same source links as
+/// [`generate_effects_protocol_with_provenance`].
+pub fn render_effects_protocol(
+    choreography: &Choreography,
+    provenance: Option<&Provenance>,
+) -> syn::Result<String> {
+    super::codegen::pretty_print(&generate_effects(choreography, provenance))
+}
+
+fn generate_effects(choreography: &Choreography, provenance: Option<&Provenance>) -> TokenStream {
+    let module = super::namespace::module_name(choreography);
     let roles = generate_role_enum(&choreography.roles);
-    let messages = generate_message_types(&choreography.protocol);
-    let role_functions = generate_role_functions(choreography);
-    let endpoint_type = generate_endpoint_type(protocol_name);
+    let messages = generate_message_types(
+        &choreography.protocol,
+        provenance,
+        choreography.wire_format(),
+    );
+    let message_enum = generate_message_enum(&choreography.protocol, &choreography.wire_ids);
+    let role_functions = generate_role_functions(choreography, provenance);
+    let source_map = provenance.map(|p| {
+        let json = SourceMap::build(choreography, p).to_json();
+        quote! {
+            /// Generated items mapped to the DSL statements they implement,
+            /// as JSON; load with `SourceMap::from_json`
+            pub const SOURCE_MAP: &str = #json;
+        }
+    });
+    let endpoint_types = generate_endpoint_types(choreography);
+    let runtime = generate_runtime(choreography);
 
     quote! {
-        use rumpsteak_choreography::{
-            ChoreoHandler, Result, Label, Program, Effect,
-            interpret, InterpretResult, ProgramMessage
-        };
-        use serde::{Serialize, Deserialize};
+        pub mod #module {
+            use super::*;
+            use rumpsteak_choreography::{
+                ChoreoHandler, ChoreographyError, Result, Label, Program, Effect,
+                interpret, InterpretResult, InterpreterState,
+                RumpsteakEndpoint, RumpsteakHandler, SimpleChannel
+            };
+            use serde::{Serialize, Deserialize};
 
-        // Common message trait for this choreography
-        #[derive(Clone, Debug, Serialize, Deserialize)]
-        pub enum Message {
-            // Generated message variants would go here
-            Default,
-        }
+            #message_enum
+
+            #roles
 
-        impl ProgramMessage for Message {}
+            #endpoint_types
 
-        #roles
+            #messages
 
-        #endpoint_type
+            #role_functions
 
-        #messages
+            #runtime
 
-        #role_functions
+            #source_map
+        }
     }
 }
 
@@ -55,54 +113,651 @@ fn generate_role_enum(roles: &[Role]) -> TokenStream {
     }
 }
 
-fn generate_endpoint_type(protocol_name: &proc_macro2::Ident) -> TokenStream {
+/// Roles each role exchanges messages or labels with, by name
+///
+/// Handler roles of a `try` block are peers of each other, since any of them
+/// may tell the rest that the block failed, and the timer of a `timeout`
+/// block is a peer of every other role in it.
+fn peers(choreography: &Choreography) -> BTreeMap<String, BTreeSet<String>> {
+    let mut peers: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (from, to) in super::grpc::collect_edges(choreography).into_keys() {
+        peers.entry(from.clone()).or_default().insert(to.clone());
+        peers.entry(to).or_default().insert(from);
+    }
+    let mut link = |a: &Role, b: &Role| {
+        if a.name != b.name {
+            peers
+                .entry(a.name.to_string())
+                .or_default()
+                .insert(b.name.to_string());
+        }
+    };
+    walk_with_paths(&choreography.protocol, &mut |_, node| match node {
+        Protocol::TryCatch { handler_roles, .. } => {
+            for role in handler_roles {
+                for other in handler_roles {
+                    link(role, other);
+                }
+            }
+        }
+        // The timer aborts everyone else in the block when it fires
+        Protocol::Timeout {
+            role,
+            body,
+            on_timeout,
+            ..
+        } => {
+            for other in timeout_peers(role, body, on_timeout) {
+                link(role, &other);
+                link(&other, role);
+            }
+        }
+        _ => {}
+    });
+    peers
+}
+
+/// One endpoint type per role, plus one that wires them all together
+///
+/// A role's endpoint holds a channel for every peer and can only be built
+/// with all of them, so a missing peer is a compile error. The
+/// choreography's endpoint connects every role to its peers in memory.
+fn generate_endpoint_types(choreography: &Choreography) -> TokenStream {
+    let protocol_name = &choreography.name;
     let ep_name = format_ident!("{}Endpoint", protocol_name);
+    let peers = peers(choreography);
+    let mut seen = BTreeSet::new();
+    let roles: Vec<String> = choreography
+        .roles
+        .iter()
+        .map(|role| role.name.to_string())
+        .filter(|name| seen.insert(name.clone()))
+        .collect();
+    let no_peers = BTreeSet::new();
+    let peers_of = |role: &str| peers.get(role).unwrap_or(&no_peers);
+    let field = |name: &str| format_ident!("{}", snake_case(name));
+
+    let role_endpoints = roles.iter().map(|role| {
+        let role_ep = format_ident!("{}Endpoint", role);
+        let role_ident = format_ident!("{}", role);
+        let peer_idents: Vec<_> = peers_of(role)
+            .iter()
+            .map(|p| format_ident!("{}", p))
+            .collect();
+        let fields: Vec<_> = peers_of(role).iter().map(|p| field(p)).collect();
+        let doc = format!(" Channels of `{}` to each of its peers", role);
+        // A role that talks to no one has nothing to wire up
+        let (derive, param, wire) = if fields.is_empty() {
+            (
+                quote! { #[derive(Default)] },
+                format_ident!("_endpoint"),
+                quote! { RumpsteakEndpoint::new(Role::#role_ident) },
+            )
+        } else {
+            (
+                quote! {},
+                format_ident!("endpoint"),
+                quote! {
+                    let mut wired = RumpsteakEndpoint::new(Role::#role_ident);
+                    #(wired.register_channel(Role::#peer_idents, endpoint.#fields);)*
+                    wired
+                },
+            )
+        };
+        quote! {
+            #[doc = #doc]
+            #derive
+            pub struct #role_ep {
+                #(#fields: SimpleChannel,)*
+            }
+
+            impl #role_ep {
+                /// The roles this role needs a channel to
+                pub const PEERS: &'static [Role] = &[#(Role::#peer_idents),*];
+
+                pub fn new(#(#fields: SimpleChannel),*) -> Self {
+                    Self { #(#fields),* }
+                }
+
+                #(
+                    pub fn #fields(&mut self) -> &mut SimpleChannel {
+                        &mut self.#fields
+                    }
+                )*
+            }
+
+            impl From<#role_ep> for RumpsteakEndpoint<Role> {
+                fn from(#param: #role_ep) -> Self {
+                    #wire
+                }
+            }
+        }
+    });
+
+    let mut pairs = Vec::new();
+    let mut ends: BTreeMap<(&str, &str), proc_macro2::Ident> = BTreeMap::new();
+    for role in &roles {
+        for peer in peers_of(role) {
+            if ends.contains_key(&(role.as_str(), peer.as_str())) {
+                continue;
+            }
+            let here = format_ident!("{}_to_{}", snake_case(role), snake_case(peer));
+            let there = format_ident!("{}_to_{}", snake_case(peer), snake_case(role));
+            pairs.push(quote! { let (#here, #there) = SimpleChannel::pair(); });
+            ends.insert((role, peer), here);
+            ends.insert((peer, role), there);
+        }
+    }
+    let role_fields: Vec<_> = roles.iter().map(|role| field(role)).collect();
+    let role_eps = roles.iter().map(|role| format_ident!("{}Endpoint", role));
+    let constructors = roles.iter().map(|role| {
+        let role_ep = format_ident!("{}Endpoint", role);
+        let args = peers_of(role)
+            .iter()
+            .map(|peer| &ends[&(role.as_str(), peer.as_str())]);
+        quote! { #role_ep::new(#(#args),*) }
+    });
 
     quote! {
+        #(#role_endpoints)*
+
+        /// The endpoints of every role, connected to each other in memory
         pub struct #ep_name {
-            // Protocol-specific endpoint state
+            #(pub #role_fields: #role_eps,)*
         }
 
-        impl rumpsteak::effects::Endpoint for #ep_name {}
+        impl #ep_name {
+            pub fn connect() -> Self {
+                #(#pairs)*
+                Self {
+                    #(#role_fields: #constructors,)*
+                }
+            }
+        }
     }
 }
 
-fn generate_message_types(protocol: &Protocol) -> TokenStream {
-    let mut message_types = HashSet::new();
+/// A `run_all` that runs every role in this process, with the handler
+/// each role uses picked by a `RuntimeConfig`
+///
+/// The config has a type parameter per role, so each role can run on its
+/// own middleware stack. It starts with a `RumpsteakHandler` for every role,
+/// limited to the choreography's `@max_size(...)` if it has one.
+fn generate_runtime(choreography: &Choreography) -> TokenStream {
+    let ep_name = format_ident!("{}Endpoint", choreography.name);
+    let mut seen = BTreeSet::new();
+    let roles: Vec<&Role> = choreography
+        .roles
+        .iter()
+        .filter(|role| seen.insert(role.name.to_string()))
+        .collect();
+    let fields: Vec<_> = roles
+        .iter()
+        .map(|role| format_ident!("{}", snake_case(&role.name.to_string())))
+        .collect();
+    let params: Vec<_> = roles
+        .iter()
+        .map(|role| format_ident!("H{}", role.name))
+        .collect();
+    let run_fns = roles
+        .iter()
+        .map(|role| format_ident!("run_{}", role.name.to_string().to_lowercase()));
+    let outputs = roles
+        .iter()
+        .map(|role| match choreography.returned_by(role) {
+            Some(returned) => {
+                quote! { Result<#returned> }
+            }
+            None => quote! { Result<InterpretResult<Message>> },
+        });
+    let defaults: Vec<_> = roles
+        .iter()
+        .map(|_| quote! { RumpsteakHandler<Role, Message> })
+        .collect();
+    let mut handler = quote! { RumpsteakHandler::new() };
+    let limit = choreography.max_message_size().map(|size| {
+        handler.extend(quote! { .with_max_message_size(MAX_MESSAGE_SIZE) });
+        quote! {
+            /// Largest encoded message in bytes, from `@max_size(...)`
+            pub const MAX_MESSAGE_SIZE: usize = #size;
+        }
+    });
+    let labels = branch_labels(&choreography.protocol);
+    let (label_names, label_ids): (Vec<_>, Vec<_>) = choreography
+        .wire_ids
+        .iter()
+        .filter(|(name, _)| labels.contains(&name.to_string()))
+        .map(|(name, id)| (name.to_string(), *id))
+        .unzip();
+    let label_ids = (!label_names.is_empty()).then(|| {
+        handler.extend(quote! { .with_label_ids(LABEL_IDS) });
+        quote! {
+            /// Ids branch labels are sent as, from `id Label = n`
+            pub const LABEL_IDS: rumpsteak_choreography::WireIds =
+                rumpsteak_choreography::WireIds(&[#((#label_names, #label_ids)),*]);
+        }
+    });
+
+    let setters = roles.iter().enumerate().map(|(i, role)| {
+        let setter = format_ident!("with_{}", fields[i]);
+        let doc = format!(" Run `{}` on `handler`", role.name);
+        let result_params = params.iter().enumerate().map(|(j, param)| {
+            if i == j {
+                quote! { H }
+            } else {
+                quote! { #param }
+            }
+        });
+        let moved = fields.iter().enumerate().map(|(j, field)| {
+            if i == j {
+                quote! { #field: handler }
+            } else {
+                quote! { #field: self.#field }
+            }
+        });
+        quote! {
+            #[doc = #doc]
+            pub fn #setter<H>(self, handler: H) -> RuntimeConfig<#(#result_params),*> {
+                RuntimeConfig { #(#moved),* }
+            }
+        }
+    });
+
+    quote! {
+        #limit
+
+        #label_ids
+
+        /// The handler each role runs on under `run_all`
+        pub struct RuntimeConfig<#(#params),*> {
+            #(pub #fields: #params,)*
+        }
+
+        impl RuntimeConfig<#(#defaults),*> {
+            /// Every role on its own `RumpsteakHandler`
+            pub fn new() -> Self {
+                Self {
+                    #(#fields: #handler,)*
+                }
+            }
+        }
 
-    // Collect unique message types from protocol
+        impl Default for RuntimeConfig<#(#defaults),*> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<#(#params),*> RuntimeConfig<#(#params),*> {
+            #(#setters)*
+        }
+
+        /// What each role's run function returned under `run_all`
+        pub struct RunAllResults {
+            #(pub #fields: #outputs,)*
+        }
+
+        /// Run every role in this process, connected in memory, each on its
+        /// handler from `config`
+        ///
+        /// The roles run concurrently on the calling task; the call returns
+        /// once all of them have finished.
+        pub async fn run_all<#(#params),*>(config: RuntimeConfig<#(#params),*>) -> RunAllResults
+        where
+            #(#params: ChoreoHandler<Role = Role, Endpoint = RumpsteakEndpoint<Role>>,)*
+        {
+            let RuntimeConfig { #(mut #fields),* } = config;
+            let endpoints = #ep_name::connect();
+            let (#(#fields,)*) = futures::join!(
+                #(#run_fns(&mut #fields, endpoints.#fields)),*
+            );
+            RunAllResults { #(#fields),* }
+        }
+    }
+}
+
+/// Labels of the choices and races in `protocol`
+fn branch_labels(protocol: &Protocol) -> BTreeSet<String> {
+    let mut labels = BTreeSet::new();
+    walk_with_paths(protocol, &mut |_, node| {
+        if let Protocol::Choice { branches, .. } | Protocol::Race { branches } = node {
+            labels.extend(branches.iter().map(|branch| branch.label.to_string()));
+        }
+    });
+    labels
+}
+
+/// The message type programs of this choreography carry, with a variant
+/// per message
+///
+/// Role functions that return a message take it out of the received
+/// values with `TryFrom`. When the choreography gives its messages wire
+/// ids, variants are encoded by id instead of by position.
+fn generate_message_enum(protocol: &Protocol, wire_ids: &BTreeMap<Ident, u32>) -> TokenStream {
+    let mut message_types = BTreeMap::new();
+    collect_message_types(protocol, &mut message_types);
+    let names: Vec<_> = message_types.values().map(|m| &m.name).collect();
+
+    let ids: Vec<u32> = message_types
+        .keys()
+        .filter_map(|name| wire_ids.iter().find(|(n, _)| *n == name).map(|(_, id)| *id))
+        .collect();
+    let (derives, codec) = if wire_ids.is_empty() || ids.len() != names.len() {
+        (quote! { Clone, Debug, Serialize, Deserialize }, quote! {})
+    } else {
+        let keys: Vec<_> = message_types.keys().collect();
+        (
+            quote! { Clone, Debug },
+            quote! {
+                /// Ids messages are sent as, from `id Message = n`
+                pub const MESSAGE_IDS: rumpsteak_choreography::WireIds =
+                    rumpsteak_choreography::WireIds(&[#((#keys, #ids)),*]);
+
+                impl Serialize for Message {
+                    fn serialize<S: serde::Serializer>(
+                        &self,
+                        serializer: S,
+                    ) -> std::result::Result<S::Ok, S::Error> {
+                        match self {
+                            #(Message::#names(message) => serializer
+                                .serialize_newtype_variant("Message", #ids, #keys, message),)*
+                        }
+                    }
+                }
+
+                impl<'de> Deserialize<'de> for Message {
+                    fn deserialize<D: serde::Deserializer<'de>>(
+                        deserializer: D,
+                    ) -> std::result::Result<Self, D::Error> {
+                        struct MessageVisitor;
+
+                        impl<'de> serde::de::Visitor<'de> for MessageVisitor {
+                            type Value = Message;
+
+                            fn expecting(
+                                &self,
+                                f: &mut std::fmt::Formatter<'_>,
+                            ) -> std::fmt::Result {
+                                f.write_str("a message of this choreography")
+                            }
+
+                            fn visit_enum<A: serde::de::EnumAccess<'de>>(
+                                self,
+                                data: A,
+                            ) -> std::result::Result<Message, A::Error> {
+                                use serde::de::{Error, VariantAccess};
+                                let (id, variant) = data.variant_seed(MESSAGE_IDS)?;
+                                match id {
+                                    #(#ids => variant.newtype_variant().map(Message::#names),)*
+                                    other => Err(A::Error::custom(format!(
+                                        "unknown message id {}",
+                                        other
+                                    ))),
+                                }
+                            }
+                        }
+
+                        deserializer.deserialize_enum(
+                            "Message",
+                            &[#(#keys),*],
+                            MessageVisitor,
+                        )
+                    }
+                }
+            },
+        )
+    };
+
+    quote! {
+        #[derive(#derives)]
+        pub enum Message {
+            #(#names(#names),)*
+        }
+
+        #codec
+
+        #(
+            impl From<#names> for Message {
+                fn from(message: #names) -> Self {
+                    Message::#names(message)
+                }
+            }
+
+            impl TryFrom<Message> for #names {
+                type Error = Message;
+
+                #[allow(unreachable_patterns)]
+                fn try_from(message: Message) -> std::result::Result<Self, Message> {
+                    match message {
+                        Message::#names(message) => Ok(message),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+
+        /// The message whose type `std::any::type_name` calls `msg_type`,
+        /// with a default payload, as stub peers send it in dry runs
+        pub fn default_message(msg_type: &str) -> Option<Message> {
+            let defaults: &[(&str, fn() -> Message)] = &[
+                #((std::any::type_name::<#names>(), || Message::#names(#names::default()))),*
+            ];
+            defaults
+                .iter()
+                .find(|(name, _)| *name == msg_type)
+                .map(|(_, default)| default())
+        }
+    }
+}
+
+fn generate_message_types(
+    protocol: &Protocol,
+    provenance: Option<&Provenance>,
+    wire: WireFormat,
+) -> TokenStream {
+    let mut message_types = BTreeMap::new();
+
+    // Collect unique message types from protocol, sorted by name so the
+    // output is the same on every build
     collect_message_types(protocol, &mut message_types);
 
     let message_structs: Vec<_> = message_types
-        .into_iter()
+        .values()
         .map(|msg_type| {
             let type_name = &msg_type.name;
+            let origin = provenance
+                .and_then(|p| message_origin(protocol, msg_type, p))
+                .map(|line| {
+                    let doc = format!(" First used at {}", line);
+                    quote! { #[doc = #doc] }
+                });
+
+            if wire == WireFormat::Protobuf {
+                return generate_protobuf_message(msg_type, origin);
+            }
+            if let Some(fields) = msg_type.fields() {
+                return generate_field_struct(type_name, &fields, origin);
+            }
             let content_type = if let Some(ref payload) = msg_type.payload {
                 payload.clone()
             } else {
                 infer_content_type(&msg_type.name.to_string())
             };
-
             quote! {
+                #origin
                 #[derive(Clone, Debug, Serialize, Deserialize)]
                 pub struct #type_name(pub #content_type);
             }
         })
         .collect();
 
+    let redacted: Vec<_> = message_types
+        .values()
+        .flat_map(|msg_type| {
+            let message = msg_type.name.to_string();
+            msg_type
+                .sensitive_fields()
+                .into_iter()
+                .map(move |field| quote! { .with_field(#message, #field) })
+        })
+        .collect();
+    let redaction = (!redacted.is_empty()).then(|| {
+        quote! {
+            /// Fields tagged `@sensitive`, for `Trace::with_redaction`
+            pub fn redaction() -> rumpsteak_choreography::Redaction {
+                rumpsteak_choreography::Redaction::new() #(#redacted)*
+            }
+        }
+    });
+
     quote! {
         #(#message_structs)*
+
+        #redaction
+    }
+}
+
+/// A message whose payload is a prost type, under `@wire(protobuf)`
+///
+/// The payload is encoded with `prost::Message` and handed to the handler
+/// as bytes, so peers in other languages can decode it from the `.proto`
+/// the prost type was generated from. Messages without a payload carry
+/// nothing.
+fn generate_protobuf_message(msg_type: &MessageType, origin: Option<TokenStream>) -> TokenStream {
+    let type_name = &msg_type.name;
+    if msg_type.fields().is_some() {
+        let error = format!(
+            "message `{}` lists payload fields, but with @wire(protobuf) its payload must be a single prost type",
+            type_name
+        );
+        return quote! { compile_error!(#error); };
+    }
+    let Some(payload) = &msg_type.payload else {
+        return quote! {
+            #origin
+            #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+            pub struct #type_name;
+        };
+    };
+    quote! {
+        #origin
+        #[derive(Clone, Debug, Default)]
+        pub struct #type_name(pub #payload);
+
+        impl Serialize for #type_name {
+            fn serialize<S: serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                prost::Message::encode_to_vec(&self.0).serialize(serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for #type_name {
+            fn deserialize<D: serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                let bytes = <Vec<u8>>::deserialize(deserializer)?;
+                <#payload as prost::Message>::decode(bytes.as_slice())
+                    .map(#type_name)
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
+/// A message with named payload fields
+///
+/// Tags become doc attributes on the fields and a `FIELD_TAGS` constant.
+/// Fields tagged `@sensitive` print as `<redacted>` in `Debug` output.
+fn generate_field_struct(
+    type_name: &proc_macro2::Ident,
+    fields: &[PayloadField],
+    origin: Option<TokenStream>,
+) -> TokenStream {
+    let declarations = fields.iter().map(|field| {
+        let name = &field.name;
+        let ty = &field.ty;
+        let tags = field.tags.iter().map(|tag| {
+            let doc = format!(" Tagged `@{}` in the choreography", tag);
+            quote! { #[doc = #doc] }
+        });
+        quote! {
+            #(#tags)*
+            pub #name: #ty
+        }
+    });
+    let field_tags: Vec<_> = fields
+        .iter()
+        .flat_map(|field| {
+            let name = field.name.to_string();
+            field.tags.iter().map(move |tag| {
+                let tag = tag.to_string();
+                quote! { (#name, #tag) }
+            })
+        })
+        .collect();
+    let tag_table = (!field_tags.is_empty()).then(|| {
+        quote! {
+            impl #type_name {
+                /// Field tags from the choreography, as `(field, tag)`
+                pub const FIELD_TAGS: &'static [(&'static str, &'static str)] = &[#(#field_tags),*];
+            }
+        }
+    });
+
+    let sensitive = fields.iter().any(PayloadField::is_sensitive);
+    let derives = if sensitive {
+        quote! { #[derive(Clone, Serialize, Deserialize)] }
+    } else {
+        quote! { #[derive(Clone, Debug, Serialize, Deserialize)] }
+    };
+    let debug = sensitive.then(|| {
+        let struct_name = type_name.to_string();
+        let entries = fields.iter().map(|field| {
+            let name = &field.name;
+            let label = name.to_string();
+            if field.is_sensitive() {
+                quote! { .field(#label, &"<redacted>") }
+            } else {
+                quote! { .field(#label, &self.#name) }
+            }
+        });
+        quote! {
+            impl std::fmt::Debug for #type_name {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    f.debug_struct(#struct_name) #(#entries)* .finish()
+                }
+            }
+        }
+    });
+
+    quote! {
+        #origin
+        #derives
+        pub struct #type_name {
+            #(#declarations),*
+        }
+
+        #tag_table
+
+        #debug
     }
 }
 
-fn collect_message_types(protocol: &Protocol, message_types: &mut HashSet<MessageType>) {
+pub(crate) fn collect_message_types<'a>(
+    protocol: &'a Protocol,
+    message_types: &mut BTreeMap<String, &'a MessageType>,
+) {
     match protocol {
         Protocol::Send {
             message,
             continuation,
             ..
         } => {
-            message_types.insert(message.clone());
+            message_types
+                .entry(message.name.to_string())
+                .or_insert(message);
             collect_message_types(continuation, message_types);
         }
         Protocol::Broadcast {
@@ -110,10 +765,12 @@ fn collect_message_types(protocol: &Protocol, message_types: &mut HashSet<Messag
             continuation,
             ..
         } => {
-            message_types.insert(message.clone());
+            message_types
+                .entry(message.name.to_string())
+                .or_insert(message);
             collect_message_types(continuation, message_types);
         }
-        Protocol::Choice { branches, .. } => {
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
             for branch in branches {
                 collect_message_types(&branch.protocol, message_types);
             }
@@ -129,11 +786,43 @@ fn collect_message_types(protocol: &Protocol, message_types: &mut HashSet<Messag
         Protocol::Rec { body, .. } => {
             collect_message_types(body, message_types);
         }
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => {
+            collect_message_types(body, message_types);
+            collect_message_types(cleanup, message_types);
+        }
+        Protocol::Spawn {
+            body, continuation, ..
+        } => {
+            // Children share the message types of the choreography
+            collect_message_types(body, message_types);
+            collect_message_types(continuation, message_types);
+        }
+        Protocol::Await { continuation, .. } => {
+            collect_message_types(continuation, message_types);
+        }
         Protocol::Var(_) | Protocol::End => {}
     }
 }
 
-fn generate_role_functions(choreography: &Choreography) -> TokenStream {
+/// Role functions in declaration order
+///
+/// Roles are generated one after another: proc_macro2 tokens are neither
+/// `Send` nor usable off the compiler thread inside a proc macro, so the
+/// per-role work cannot be spread across threads.
+fn generate_role_functions(
+    choreography: &Choreography,
+    provenance: Option<&Provenance>,
+) -> TokenStream {
     choreography
         .roles
         .iter()
@@ -141,30 +830,142 @@ fn generate_role_functions(choreography: &Choreography) -> TokenStream {
             let role_name_str = role.name.to_string().to_lowercase();
             let program_fn_name = format_ident!("{}_program", role_name_str);
             let run_fn_name = format_ident!("run_{}", role_name_str);
-            let protocol_name = &choreography.name;
-            let endpoint_type = format_ident!("{}Endpoint", protocol_name);
+            let dry_run_fn_name = format_ident!("dry_run_{}", role_name_str);
+            let role_label = role.name.to_string();
+            let endpoint_type = format_ident!("{}Endpoint", role.name);
 
-            let body = generate_role_body(&choreography.protocol, role);
+            let mut body = generate_role_body(&choreography.protocol, role);
+            if let Some(policy) = choreography.unknown_labels() {
+                let unknown = match policy {
+                    "reject" => quote! { Reject },
+                    "skip" => quote! { Skip },
+                    label => quote! { Fallback(rumpsteak_choreography::Label(#label)) },
+                };
+                body = quote! {
+                    let program = { #body };
+                    program.on_unknown_labels(rumpsteak_choreography::UnknownLabel::#unknown)
+                };
+            }
+            let run_fn = match choreography.returned_by(role) {
+                Some(returned) => {
+                    let missing = format!(
+                        "{} finished without receiving {}",
+                        role.name, returned
+                    );
+                    quote! {
+                        /// Run the choreographic program for this role and return the
+                        /// last message of the declared type it received
+                        ///
+                        /// The endpoint's channels are closed once the program ends.
+                        pub async fn #run_fn_name<H>(
+                            handler: &mut H,
+                            endpoint: #endpoint_type,
+                        ) -> Result<#returned>
+                        where
+                            H: ChoreoHandler<Role = Role, Endpoint = RumpsteakEndpoint<Role>>,
+                        {
+                            let mut endpoint = RumpsteakEndpoint::from(endpoint);
+                            let program = #program_fn_name();
+                            let result = interpret(handler, &mut endpoint, program).await?;
+                            match result.final_state {
+                                InterpreterState::Completed => {}
+                                InterpreterState::Failed(reason) => {
+                                    return Err(result
+                                        .error
+                                        .unwrap_or(ChoreographyError::ProtocolViolation(reason)))
+                                }
+                                InterpreterState::Timeout => {
+                                    return Err(result
+                                        .error
+                                        .unwrap_or(ChoreographyError::Timeout(std::time::Duration::ZERO)))
+                                }
+                                InterpreterState::Cancelled => return Err(ChoreographyError::Cancelled),
+                            }
+                            result
+                                .received_values
+                                .into_iter()
+                                .rev()
+                                .find_map(|message| #returned::try_from(message).ok())
+                                .ok_or_else(|| ChoreographyError::ProtocolViolation(#missing.to_string()))
+                        }
+                    }
+                }
+                None => quote! {
+                    /// Run the choreographic program for this role using a handler
+                    ///
+                    /// The endpoint's channels are closed once the program ends.
+                    pub async fn #run_fn_name<H>(
+                        handler: &mut H,
+                        endpoint: #endpoint_type,
+                    ) -> Result<InterpretResult<Message>>
+                    where
+                        H: ChoreoHandler<Role = Role, Endpoint = RumpsteakEndpoint<Role>>,
+                    {
+                        let mut endpoint = RumpsteakEndpoint::from(endpoint);
+                        let program = #program_fn_name();
+                        interpret(handler, &mut endpoint, program).await
+                    }
+                },
+            };
+            let steps = provenance.map(|p| {
+                let lines = role_steps(&choreography.protocol, role)
+                    .into_iter()
+                    .filter_map(|path| p.describe(&path))
+                    .map(|line| format!(" - {}", line));
+                quote! {
+                    ///
+                    /// Steps from the choreography source:
+                    #(#[doc = #lines])*
+                }
+            });
 
             quote! {
                 /// Generate the choreographic program for this role
+                #steps
                 pub fn #program_fn_name() -> Program<Role, Message> {
                     #body
                 }
 
-                /// Run the choreographic program for this role using a handler
-                pub async fn #run_fn_name<H: ChoreoHandler<Role = Role, Endpoint = #endpoint_type>>(
-                    handler: &mut H,
-                    endpoint: &mut #endpoint_type,
-                ) -> Result<InterpretResult<Message>> {
-                    let program = #program_fn_name();
-                    interpret(handler, endpoint, program).await
+                #run_fn
+
+                /// Run the program for this role against stub peers and print
+                /// each step it takes
+                ///
+                /// Peers send default payloads and every choice takes its first
+                /// branch, so no transport is needed. Fails as the program would,
+                /// or once it takes more steps than a dry run allows; the steps
+                /// taken are printed either way.
+                pub async fn #dry_run_fn_name() -> Result<Vec<rumpsteak_choreography::DryRunStep<Role>>> {
+                    let mut dry_run = rumpsteak_choreography::DryRun::new(default_message);
+                    let result = dry_run.run(#program_fn_name()).await;
+                    for step in dry_run.steps() {
+                        println!("{}: {}", #role_label, step);
+                    }
+                    result?;
+                    Ok(dry_run.into_steps())
                 }
             }
         })
         .collect()
 }
 
+/// Where a message is first sent, as recorded in the provenance
+fn message_origin(
+    protocol: &Protocol,
+    message: &MessageType,
+    provenance: &Provenance,
+) -> Option<String> {
+    let mut origin = None;
+    walk_with_paths(protocol, &mut |path, node| {
+        if let Protocol::Send { message: m, .. } | Protocol::Broadcast { message: m, .. } = node {
+            if origin.is_none() && m.name == message.name {
+                origin = provenance.describe(path);
+            }
+        }
+    });
+    origin
+}
+
 fn generate_role_body(protocol: &Protocol, role: &Role) -> TokenStream {
     generate_program_builder(protocol, role)
 }
@@ -219,6 +1020,20 @@ fn generate_program_effects(protocol: &Protocol, role: &Role) -> TokenStream {
                 continuation_effects
             }
         }
+        Protocol::Race { branches } => {
+            // Programs have no selective receive, so a race runs as a choice
+            // by the role starting the first arm, the one that wins collisions
+            match branches.first().map(|b| &b.protocol) {
+                Some(Protocol::Send { from, .. }) => generate_program_effects(
+                    &Protocol::Choice {
+                        role: from.clone(),
+                        branches: branches.clone(),
+                    },
+                    role,
+                ),
+                _ => quote! {},
+            }
+        }
         Protocol::Choice {
             role: choice_role,
             branches,
@@ -246,34 +1061,28 @@ fn generate_program_effects(protocol: &Protocol, role: &Role) -> TokenStream {
                 let has_guards = branches.iter().any(|b| b.guard.is_some());
                 
                 if has_guards {
-                    // Generate guard evaluation logic
-                    let guard_checks: Vec<TokenStream> = branches
+                    // Guards are decided at runtime on the interpreter's
+                    // GuardContext, in the order the branches are listed
+                    let guarded: Vec<TokenStream> = branches
                         .iter()
                         .map(|branch| {
                             let label_str = branch.label.to_string();
-                            if let Some(ref guard) = branch.guard {
-                                quote! {
-                                    if #guard {
-                                        Label(#label_str)
+                            let branch_effects = generate_program_effects(&branch.protocol, role);
+                            let guard = match &branch.guard {
+                                Some(guard) => {
+                                    let source = guard.to_string();
+                                    quote! {
+                                        Some(rumpsteak_choreography::Guard::parse(#source)
+                                            .expect("guard checked when the choreography was parsed"))
                                     }
                                 }
-                            } else {
-                                quote! {
-                                    // No guard - default fallback
-                                    { Label(#label_str) }
-                                }
-                            }
+                                None => quote! { None },
+                            };
+                            quote! { (Label(#label_str), #guard, Program::new()#branch_effects) }
                         })
                         .collect();
-                    
-                    // Generate a choice selection expression using guards
-                    let first_label = branches.first().map(|b| b.label.to_string()).unwrap_or_default();
                     quote! {
-                        .choose(Role::#choice_role_name, {
-                            // Evaluate guards to determine which branch to choose
-                            #(#guard_checks else)* Label(#first_label)
-                        })
-                        .branch(Role::#choice_role_name, vec![#(#branch_programs),*])
+                        .choose_when(Role::#choice_role_name, vec![#(#guarded),*])
                     }
                 } else if let Some(first_branch) = branches.first() {
                     // No guards - default to first branch or allow runtime decision
@@ -290,8 +1099,7 @@ fn generate_program_effects(protocol: &Protocol, role: &Role) -> TokenStream {
                 // This role is offering/waiting for choice
                 // It will receive the label and execute the matching branch
                 quote! {
-                    .offer(Role::#choice_role_name)
-                    .branch(Role::#choice_role_name, vec![#(#branch_programs),*])
+                    .offer_branches(Role::#choice_role_name, vec![#(#branch_programs),*])
                 }
             }
         }
@@ -362,15 +1170,20 @@ fn generate_program_effects(protocol: &Protocol, role: &Role) -> TokenStream {
                 #(#parallel_effects)*
             }
         }
-        Protocol::Rec { label: _, body } => {
-            // For simplicity, treat recursion as a simple body
-            generate_program_effects(body, role)
+        Protocol::Rec { label, body } => {
+            // The interpreter reruns the body each time it reaches a jump
+            let label = label.to_string();
+            let body_effects = generate_program_effects(body, role);
+            quote! {
+                .rec(#label, Program::new()#body_effects)
+            }
         }
         Protocol::Broadcast {
             from,
             to_all,
             message,
             continuation,
+            ..
         } => {
             let continuation_effects = generate_program_effects(continuation, role);
             let message_type = &message.name;
@@ -406,24 +1219,119 @@ fn generate_program_effects(protocol: &Protocol, role: &Role) -> TokenStream {
                 }
             }
         }
-        Protocol::Var(_label) => {
-            // Variable reference for recursion - refers back to a Rec label
-            // This creates a recursive call/loop back to the labeled protocol point
+        Protocol::Finally { body, cleanup } => {
+            // The interpreter runs the cleanup program on every exit path
+            let body_effects = generate_program_effects(body, role);
+            let cleanup_effects = generate_program_effects(cleanup, role);
+
+            quote! {
+                .with_finally(Program::new()#body_effects, Program::new()#cleanup_effects)
+            }
+        }
+        Protocol::TryCatch {
+            body,
+            handler_roles,
+            compensation,
+        } => {
+            let body_effects = generate_program_effects(body, role);
+            if !handler_roles.contains(role) {
+                return body_effects;
+            }
+            // A failure here is announced to the other handler roles
+            let notify = handler_roles.iter().filter(|r| *r != role).map(|r| &r.name);
+            let compensation_effects = generate_program_effects(compensation, role);
+
+            quote! {
+                .try_catch(
+                    Program::new()#body_effects,
+                    vec![#(Role::#notify),*],
+                    Program::new()#compensation_effects,
+                )
+            }
+        }
+        Protocol::Timeout {
+            role: timer,
+            duration,
+            body,
+            on_timeout,
+        } => {
+            let body_effects = generate_program_effects(body, role);
+            let fallback_effects = generate_program_effects(on_timeout, role);
+            if timer == role {
+                // The timer tells everyone else in the block before its fallback
+                let notify = timeout_peers(timer, body, on_timeout)
+                    .into_iter()
+                    .map(|r| r.name);
+                let timer = &timer.name;
+                let millis = duration.as_millis() as u64;
+                quote! {
+                    .with_timeout_else(
+                        Role::#timer,
+                        std::time::Duration::from_millis(#millis),
+                        Program::new()#body_effects,
+                        Program::new()#(.choose(Role::#notify, Label::ABORT))*#fallback_effects,
+                    )
+                }
+            } else if protocol.mentions_role(role) {
+                quote! {
+                    .try_catch(
+                        Program::new()#body_effects,
+                        vec![],
+                        Program::new()#fallback_effects,
+                    )
+                }
+            } else {
+                quote! {}
+            }
+        }
+        Protocol::Spawn {
+            handle,
+            roles,
+            body,
+            continuation,
+            ..
+        } => {
+            let continuation_effects = generate_program_effects(continuation, role);
+            if roles.contains(role) {
+                // The child runs on its own session through the interpreter's
+                // session context
+                let handle = handle.to_string();
+                let child_effects = generate_program_effects(body, role);
+                quote! {
+                    .spawn(#handle, Program::new()#child_effects.end())
+                    #continuation_effects
+                }
+            } else {
+                continuation_effects
+            }
+        }
+        Protocol::Await {
+            handle,
+            roles,
+            continuation,
+        } => {
+            let continuation_effects = generate_program_effects(continuation, role);
+            if roles.contains(role) {
+                let handle = handle.to_string();
+                quote! {
+                    .await_child(#handle)
+                    #continuation_effects
+                }
+            } else {
+                continuation_effects
+            }
+        }
+        Protocol::Var(label) => {
+            // Back to the start of the enclosing rec with this label
+            let label = label.to_string();
             quote! {
-                // Recursion to recursive label
-                // This represents a jump back to the Rec point
-                // In a runtime implementation, this would:
-                // 1. Reset state to the Rec entry point
-                // 2. Continue execution from the beginning of the Rec body
-                // 3. Maintain any accumulated state/messages
-                // For code generation, this is typically handled by the containing Rec block
-                // which wraps the body in an actual loop construct
+                .jump(#label)
             }
         }
     }
 }
 
-fn infer_content_type(message_type: &str) -> TokenStream {
+pub(crate) fn infer_content_type(message_type: &str) -> TokenStream {
     // Simple heuristic - can be improved
     match message_type {
         s if s.contains("Request") => quote! { String },
@@ -450,15 +1358,396 @@ mod tests {
             ],
             protocol: Protocol::End,
             attrs: std::collections::HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: std::collections::BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         let code = generate_effects_protocol(&choreography);
         let code_str = code.to_string();
 
+        assert!(code_str.starts_with("pub mod"));
         assert!(code_str.contains("enum Role"));
         assert!(code_str.contains("Client"));
         assert!(code_str.contains("Server"));
         assert!(code_str.contains("run_client"));
         assert!(code_str.contains("run_server"));
     }
+
+    #[test]
+    fn test_message_types_are_sorted() {
+        let client = Role::new(format_ident!("Client"));
+        let server = Role::new(format_ident!("Server"));
+        let send = |from: &Role, to: &Role, name: &str, continuation| Protocol::Send {
+            from: from.clone(),
+            to: to.clone(),
+            message: MessageType {
+                name: format_ident!("{}", name),
+                type_annotation: None,
+                payload: None,
+            },
+            continuation: Box::new(continuation),
+        };
+        let protocol = send(
+            &client,
+            &server,
+            "Zulu",
+            send(
+                &server,
+                &client,
+                "Alpha",
+                send(&client, &server, "Mike", Protocol::End),
+            ),
+        );
+
+        let code = generate_message_types(&protocol, None, WireFormat::Bincode).to_string();
+        let alpha = code.find("struct Alpha").unwrap();
+        let mike = code.find("struct Mike").unwrap();
+        let zulu = code.find("struct Zulu").unwrap();
+        assert!(alpha < mike && mike < zulu);
+        assert_eq!(
+            code,
+            generate_message_types(&protocol, None, WireFormat::Bincode).to_string()
+        );
+    }
+
+    #[test]
+    fn test_sensitive_fields_are_redacted() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Checkout {
+    roles: Shopper, Shop
+
+    Shopper -> Shop: Order(card: String @sensitive, amount: u64)
+}
+"#,
+        )
+        .unwrap();
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("pub card: String"));
+        assert!(code.contains("impl std::fmt::Debug for Order"));
+        assert!(code.contains(r#".field("card", &"<redacted>")"#));
+        assert!(code.contains(r#"("card", "sensitive")"#));
+        assert!(code.contains(r#".with_field("Order", "card")"#));
+    }
+
+    #[test]
+    fn test_protobuf_wire_encodes_prost_payloads() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+@wire(protobuf)
+choreography Checkout {
+    roles: Shopper, Shop
+
+    Shopper -> Shop: Order(proto::Order)
+    Shop -> Shopper: Done
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(choreography.wire_format(), WireFormat::Protobuf);
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("pub struct Order(pub proto::Order);"));
+        assert!(code.contains("prost::Message::encode_to_vec(&self.0).serialize(serializer)"));
+        assert!(code.contains("<proto::Order as prost::Message>::decode(bytes.as_slice())"));
+        assert!(code.contains("pub struct Done;"));
+        assert!(!code.contains("bincode"));
+
+        let fields = crate::compiler::parser::parse_choreography_str(
+            "@wire(protobuf) choreography C { roles: A, B A -> B: Order(id: u64) }",
+        )
+        .unwrap();
+        let code = generate_effects_protocol(&fields).to_string();
+        assert!(code.contains("compile_error !"));
+    }
+
+    #[test]
+    fn test_max_size_limits_default_handlers() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            "@max_size(64KB) choreography Upload { roles: A, B A -> B: Chunk }",
+        )
+        .unwrap();
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("pub const MAX_MESSAGE_SIZE: usize = 65536usize;"));
+        assert!(
+            code.contains("a: RumpsteakHandler::new().with_max_message_size(MAX_MESSAGE_SIZE),")
+        );
+
+        let unlimited = crate::compiler::parser::parse_choreography_str(
+            "choreography Upload { roles: A, B A -> B: Chunk }",
+        )
+        .unwrap();
+        let code = render_effects_protocol(&unlimited, None).unwrap();
+        assert!(!code.contains("MAX_MESSAGE_SIZE"));
+    }
+
+    #[test]
+    fn test_wire_ids_encode_messages_and_labels() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            "choreography Quote { roles: A, B id Request = 4 id Accept = 2 id Reject = 9 \
+             A -> B: Request choice B { Accept: { B -> A: Accept } Reject: { B -> A: Request } } }",
+        )
+        .unwrap();
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("#[derive(Clone, Debug)]\n    pub enum Message"));
+        assert!(code.contains(r#"&[("Accept", 2u32), ("Request", 4u32)],"#));
+        assert!(code.contains(r#".serialize_newtype_variant("Message", 4u32, "Request", message)"#));
+        assert!(code.contains(r#"&[("Accept", 2u32), ("Reject", 9u32)],"#));
+        assert!(code.contains("a: RumpsteakHandler::new().with_label_ids(LABEL_IDS),"));
+
+        let positional = crate::compiler::parser::parse_choreography_str(
+            "choreography Quote { roles: A, B A -> B: Request }",
+        )
+        .unwrap();
+        let code = render_effects_protocol(&positional, None).unwrap();
+        assert!(code.contains("#[derive(Clone, Debug, Serialize, Deserialize)]"));
+        assert!(!code.contains("WireIds"));
+    }
+
+    #[test]
+    fn test_recursion_becomes_rec_and_jump() {
+        let client = Role::new(format_ident!("Client"));
+        let server = Role::new(format_ident!("Server"));
+        let choreography = Choreography {
+            name: format_ident!("Polling"),
+            roles: vec![client.clone(), server.clone()],
+            protocol: Protocol::Rec {
+                label: format_ident!("Poll"),
+                body: Box::new(Protocol::Send {
+                    from: client,
+                    to: server,
+                    message: MessageType {
+                        name: format_ident!("Ping"),
+                        type_annotation: None,
+                        payload: None,
+                    },
+                    continuation: Box::new(Protocol::Var(format_ident!("Poll"))),
+                }),
+            },
+            attrs: std::collections::HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: std::collections::BTreeMap::new(),
+            trusted: Vec::new(),
+        };
+
+        let code = generate_effects_protocol(&choreography).to_string();
+        assert!(code.contains(". rec (\"Poll\" , Program :: new () . send (Role :: Server"));
+        assert!(code.contains(". jump (\"Poll\")"));
+    }
+
+    #[test]
+    fn test_role_endpoints_need_every_peer() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Relay {
+    roles: Client, Proxy, Server, Monitor
+
+    Client -> Proxy: Request
+    Proxy -> Server: Forward
+    choice Server {
+        ok: { Server -> Proxy: Reply }
+        busy: { Server -> Proxy: Busy }
+    }
+}
+"#,
+        )
+        .unwrap();
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("pub fn new(client: SimpleChannel, server: SimpleChannel) -> Self"));
+        assert!(code.contains("pub const PEERS: &'static [Role] = &[Role::Client, Role::Server];"));
+        assert!(code.contains("wired.register_channel(Role::Proxy, endpoint.proxy);"));
+        // The monitor takes no part, so its endpoint needs nothing
+        assert!(code.contains("#[derive(Default)]\n    pub struct MonitorEndpoint {}"));
+        assert!(code.contains("let (client_to_proxy, proxy_to_client) = SimpleChannel::pair();"));
+        assert!(code.contains("proxy: ProxyEndpoint::new(proxy_to_client, proxy_to_server),"));
+        assert!(code.contains("endpoint: ServerEndpoint,"));
+    }
+
+    #[test]
+    fn test_returns_give_run_functions_a_typed_result() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Purchase {
+    roles: Buyer, Seller
+    returns Quote at Buyer
+
+    Buyer -> Seller: Request
+    Seller -> Buyer: Quote(u64)
+}
+"#,
+        )
+        .unwrap();
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("Quote(Quote),"));
+        assert!(code.contains("impl TryFrom<Message> for Quote"));
+        assert!(code.contains(") -> Result<Quote>"));
+        assert!(code.contains(".find_map(|message| Quote::try_from(message).ok())"));
+        assert!(code.contains("\"Buyer finished without receiving Quote\""));
+        // Roles without a declared output still get the whole result
+        assert!(code.contains(") -> Result<InterpretResult<Message>>"));
+    }
+
+    #[test]
+    fn test_try_notifies_other_handler_roles() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Booking {
+    roles: Client, Hotel
+
+    try {
+        Client -> Hotel: Reserve
+    } catch {
+        Client -> Hotel: Release
+    }
+}
+"#,
+        )
+        .unwrap();
+
+        let code = generate_effects_protocol(&choreography).to_string();
+        assert!(code.contains(". try_catch (Program :: new () . send (Role :: Hotel"));
+        assert!(code.contains("vec ! [Role :: Hotel]"));
+        assert!(code.contains("vec ! [Role :: Client]"));
+    }
+
+    #[test]
+    fn test_guards_are_evaluated_by_the_interpreter() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Shop {
+    roles: Client, Server
+
+    choice Client {
+        buy when (balance > price): {
+            Client -> Server: Purchase
+        }
+        cancel: {
+            Client -> Server: Cancel
+        }
+    }
+}
+"#,
+        )
+        .unwrap();
+
+        let code = generate_effects_protocol(&choreography).to_string();
+        assert!(code.contains(". choose_when (Role :: Client"));
+        assert!(code.contains("Guard :: parse (\"balance > price\")"));
+        assert!(code.contains("(Label (\"cancel\") , None , Program :: new ()"));
+    }
+
+    #[test]
+    fn test_timeout_wraps_the_timer_in_with_timeout() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Quote {
+    roles: Client, Server
+
+    timeout(500ms) at Client {
+        Client -> Server: Request
+        Server -> Client: Response
+    } else {
+        Client -> Server: Give
+    }
+}
+"#,
+        )
+        .unwrap();
+
+        let code = generate_effects_protocol(&choreography).to_string();
+        assert!(code.contains(". with_timeout_else (Role :: Client"));
+        assert!(code.contains("std :: time :: Duration :: from_millis (500u64)"));
+        assert!(code.contains(". choose (Role :: Server , Label :: ABORT)"));
+        // The server follows the timer's abort like a compensation
+        assert!(code.contains(". try_catch (Program :: new () . recv"));
+    }
+
+    #[test]
+    fn test_run_all_takes_a_handler_per_role() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Purchase {
+    roles: Buyer, Seller
+    returns Quote at Buyer
+
+    Buyer -> Seller: Request
+    Seller -> Buyer: Quote(u64)
+}
+"#,
+        )
+        .unwrap();
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("pub struct RuntimeConfig<HBuyer, HSeller>"));
+        assert!(code.contains(
+            "for RuntimeConfig<RumpsteakHandler<Role, Message>, RumpsteakHandler<Role, Message>>"
+        ));
+        assert!(
+            code.contains("pub fn with_seller<H>(self, handler: H) -> RuntimeConfig<HBuyer, H>")
+        );
+        assert!(code.contains("pub buyer: Result<Quote>,"));
+        assert!(code.contains("pub seller: Result<InterpretResult<Message>>,"));
+        assert!(code.contains("let endpoints = PurchaseEndpoint::connect();"));
+        assert!(code.contains("run_buyer(& mut buyer, endpoints.buyer)"));
+    }
+
+    #[test]
+    fn test_unknown_label_policy_applies_to_every_program() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+@unknown_labels(Other)
+choreography Pricing {
+    roles: Buyer, Seller
+
+    choice Seller {
+        Accept: {
+            Seller -> Buyer: Deal
+        }
+        Other: {
+            Seller -> Buyer: Later
+        }
+    }
+}
+"#,
+        )
+        .unwrap();
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert_eq!(
+            code.matches("rumpsteak_choreography::Label(\"Other\")")
+                .count(),
+            2
+        );
+        assert!(code.contains(".on_unknown_labels("));
+    }
+
+    #[test]
+    fn test_dry_run_per_role() {
+        let choreography = crate::compiler::parser::parse_choreography_str(
+            r#"
+choreography Purchase {
+    roles: Buyer, Seller
+
+    Buyer -> Seller: Request
+    Seller -> Buyer: Quote(u64)
+}
+"#,
+        )
+        .unwrap();
+
+        let code = render_effects_protocol(&choreography, None).unwrap();
+        assert!(code.contains("pub fn default_message(msg_type: &str) -> Option<Message>"));
+        assert!(
+            code.contains("(std::any::type_name::<Quote>(), || Message::Quote(Quote::default()))")
+        );
+        assert!(code.contains("pub async fn dry_run_buyer() -> Result<"));
+        assert!(code.contains("pub async fn dry_run_seller() -> Result<"));
+        assert!(code.contains("dry_run.run(seller_program()).await"));
+    }
 }
@@ -0,0 +1,565 @@
+// Expansion of parameterized roles into concrete ones
+//
+// `roles: Master, Worker[N]` declares a family of workers whose size is only
+// known once `N` is. Given values for the sizes, this pass replaces each
+// family by one role per index and rewrites the protocol to match, so the
+// result can be projected and code generated like any other choreography.
+
+use crate::ast::{Branch, Choreography, Condition, Protocol, Return, Role};
+use crate::compiler::provenance::walk_with_paths;
+use quote::format_ident;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+/// Errors that can occur while expanding role families
+#[derive(Debug, thiserror::Error)]
+pub enum ExpansionError {
+    #[error("role family {role}[{size}] has no size; bind '{size}' to expand it")]
+    UnboundSize { role: String, size: String },
+
+    #[error("loop count '{0}' has no value; bind it to expand the loop")]
+    UnboundCount(String),
+
+    #[error("{role}[{index}] is out of range for a family of {size}")]
+    IndexOutOfRange {
+        role: String,
+        index: usize,
+        size: usize,
+    },
+
+    #[error("index '{index}' of {role} is not bound by an enclosing loop")]
+    UnboundIndex { role: String, index: String },
+
+    #[error("role family {0} is used without an index")]
+    MissingIndex(String),
+
+    #[error(
+        "loop over '{count}' could be indexed by any of {indices}; give the families different sizes or bind them at parse time"
+    )]
+    AmbiguousIndex { count: String, indices: String },
+
+    #[error(
+        "loop over '{0}' cannot be unrolled, since its body ends in a loop, parallel block, or recursion"
+    )]
+    CannotUnroll(String),
+}
+
+/// Instantiate every role family with the sizes in `bindings`
+///
+/// `Worker[N]` with `N = 3` becomes the roles `Worker0`, `Worker1`, and
+/// `Worker2`, in the place the family was declared. References such as
+/// `Worker[1]` are renamed to the matching role, and a broadcast reaches
+/// every instance. A counted loop whose body uses an index variable, as left
+/// by a `foreach` over a symbolic range, is unrolled once per index; counted
+/// loops without one keep their body and get a fixed count.
+///
+/// A family declared with a literal or constant size needs no binding.
+/// Choreographies without families are returned unchanged.
+pub fn expand_roles(
+    choreography: &Choreography,
+    bindings: &HashMap<String, usize>,
+) -> Result<Choreography, ExpansionError> {
+    let mut families = HashMap::new();
+    let mut roles = Vec::new();
+    for role in &choreography.roles {
+        let size = match (&role.array_size, role.index) {
+            (Some(size), _) => {
+                let size = size.to_string();
+                value(&size, bindings).ok_or_else(|| ExpansionError::UnboundSize {
+                    role: role.name.to_string(),
+                    size,
+                })?
+            }
+            // `Worker[3]` declares three workers
+            (None, Some(size)) => size,
+            (None, None) => {
+                roles.push(role.clone());
+                continue;
+            }
+        };
+        roles.extend((0..size).map(|index| instance(&role.name.to_string(), index)));
+        families.insert(role.name.to_string(), (role.clone(), size));
+    }
+    if families.is_empty() {
+        return Ok(choreography.clone());
+    }
+
+    let expander = Expander {
+        families,
+        bindings,
+        roles: &roles,
+    };
+    let protocol = expander.protocol(&choreography.protocol, &Indices::new())?;
+    let returns = choreography
+        .returns
+        .iter()
+        .flat_map(|r| {
+            expander.instances(&r.role).into_iter().map(|role| Return {
+                role,
+                message: r.message.clone(),
+            })
+        })
+        .collect();
+    let trusted = choreography
+        .trusted
+        .iter()
+        .flat_map(|role| expander.instances(role))
+        .collect();
+
+    Ok(Choreography {
+        name: choreography.name.clone(),
+        roles,
+        protocol,
+        attrs: choreography.attrs.clone(),
+        returns,
+        routes: choreography.routes.clone(),
+        wire_ids: choreography.wire_ids.clone(),
+        trusted,
+    })
+}
+
+/// The role standing for `index` of `family`
+fn instance(family: &str, index: usize) -> Role {
+    Role::new(format_ident!("{}{}", family, index))
+}
+
+/// Value of a size or bound: an integer or a bound name
+fn value(text: &str, bindings: &HashMap<String, usize>) -> Option<usize> {
+    let text = text.trim();
+    text.parse().ok().or_else(|| bindings.get(text).copied())
+}
+
+/// Index of a reference to a member of `family`, which the parser names
+/// `Family_index` for `Family[index]`
+pub(crate) fn family_index<'a>(name: &'a str, family: &str) -> Option<&'a str> {
+    name.strip_prefix(family)?.strip_prefix('_')
+}
+
+/// Index each loop variable stands for in the current unrolling
+type Indices = HashMap<String, usize>;
+
+/// Rewrites the protocol of one choreography
+struct Expander<'a> {
+    /// Family and size, by family name
+    families: HashMap<String, (Role, usize)>,
+    bindings: &'a HashMap<String, usize>,
+    /// Expanded role list
+    roles: &'a [Role],
+}
+
+impl Expander<'_> {
+    /// Family and index of a reference
+    fn split(&self, role: &Role) -> Option<(String, String)> {
+        let name = role.name.to_string();
+        if self.roles.iter().any(|r| r.name == name) {
+            return None;
+        }
+        self.families.keys().find_map(|family| {
+            let index = family_index(&name, family)?;
+            Some((family.clone(), index.to_string()))
+        })
+    }
+
+    /// Concrete role for a reference
+    fn role(&self, role: &Role, indices: &Indices) -> Result<Role, ExpansionError> {
+        let name = role.name.to_string();
+        if self.families.contains_key(&name) {
+            return Err(ExpansionError::MissingIndex(name));
+        }
+        let Some((family, index)) = self.split(role) else {
+            return Ok(role.clone());
+        };
+        let size = self.families[&family].1;
+        let index = match index.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => *indices.get(&index).ok_or(ExpansionError::UnboundIndex {
+                role: family.clone(),
+                index,
+            })?,
+        };
+        if index >= size {
+            return Err(ExpansionError::IndexOutOfRange {
+                role: family,
+                index,
+                size,
+            });
+        }
+        Ok(instance(&family, index))
+    }
+
+    fn roles(&self, roles: &[Role], indices: &Indices) -> Result<Vec<Role>, ExpansionError> {
+        roles.iter().map(|role| self.role(role, indices)).collect()
+    }
+
+    /// The instances of `role` if it names a family, or `role` itself
+    fn instances(&self, role: &Role) -> Vec<Role> {
+        let name = role.name.to_string();
+        match self.families.get(&name) {
+            Some((_, size)) => (0..*size).map(|index| instance(&name, index)).collect(),
+            None => vec![role.clone()],
+        }
+    }
+
+    fn protocol(&self, protocol: &Protocol, indices: &Indices) -> Result<Protocol, ExpansionError> {
+        let next = |p: &Protocol| self.protocol(p, indices).map(Box::new);
+        let branches = |branches: &[Branch]| -> Result<Vec<Branch>, ExpansionError> {
+            branches
+                .iter()
+                .map(|b| {
+                    Ok(Branch {
+                        protocol: self.protocol(&b.protocol, indices)?,
+                        ..b.clone()
+                    })
+                })
+                .collect()
+        };
+        Ok(match protocol {
+            Protocol::Send {
+                from,
+                to,
+                message,
+                continuation,
+            } => Protocol::Send {
+                from: self.role(from, indices)?,
+                to: self.role(to, indices)?,
+                message: message.clone(),
+                continuation: next(continuation)?,
+            },
+            Protocol::Broadcast {
+                from,
+                to_all,
+                message,
+                abort,
+                continuation,
+            } => {
+                let from = self.role(from, indices)?;
+                let mut receivers = Vec::new();
+                for role in to_all {
+                    match self.families.get(&role.name.to_string()) {
+                        // A broadcast reaches every instance but the sender
+                        Some((_, size)) => receivers.extend(
+                            (0..*size)
+                                .map(|index| instance(&role.name.to_string(), index))
+                                .filter(|r| *r != from),
+                        ),
+                        None => receivers.push(self.role(role, indices)?),
+                    }
+                }
+                Protocol::Broadcast {
+                    from,
+                    to_all: receivers,
+                    message: message.clone(),
+                    abort: *abort,
+                    continuation: next(continuation)?,
+                }
+            }
+            Protocol::Choice { role, branches: b } => Protocol::Choice {
+                role: self.role(role, indices)?,
+                branches: branches(b)?,
+            },
+            Protocol::Race { branches: b } => Protocol::Race {
+                branches: branches(b)?,
+            },
+            Protocol::Loop { condition, body } => self.expand_loop(condition, body, indices)?,
+            Protocol::Parallel { protocols } => Protocol::Parallel {
+                protocols: protocols
+                    .iter()
+                    .map(|p| self.protocol(p, indices))
+                    .collect::<Result<_, _>>()?,
+            },
+            Protocol::Rec { label, body } => Protocol::Rec {
+                label: label.clone(),
+                body: next(body)?,
+            },
+            Protocol::Finally { body, cleanup } => Protocol::Finally {
+                body: next(body)?,
+                cleanup: next(cleanup)?,
+            },
+            Protocol::TryCatch {
+                body, compensation, ..
+            } => {
+                let body = self.protocol(body, indices)?;
+                // Handler roles are the roles of the body, as when parsed
+                let handler_roles = self
+                    .roles
+                    .iter()
+                    .filter(|role| body.mentions_role(role))
+                    .cloned()
+                    .collect();
+                Protocol::TryCatch {
+                    body: Box::new(body),
+                    handler_roles,
+                    compensation: next(compensation)?,
+                }
+            }
+            Protocol::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => Protocol::Timeout {
+                role: self.role(role, indices)?,
+                duration: *duration,
+                body: next(body)?,
+                on_timeout: next(on_timeout)?,
+            },
+            Protocol::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                continuation,
+            } => Protocol::Spawn {
+                handle: handle.clone(),
+                name: name.clone(),
+                roles: self.roles(roles, indices)?,
+                body: next(body)?,
+                continuation: next(continuation)?,
+            },
+            Protocol::Await {
+                handle,
+                roles,
+                continuation,
+            } => Protocol::Await {
+                handle: handle.clone(),
+                roles: self.roles(roles, indices)?,
+                continuation: next(continuation)?,
+            },
+            Protocol::Var(_) | Protocol::End => protocol.clone(),
+        })
+    }
+
+    /// Unroll a counted loop over an index, or fix the count of one without
+    fn expand_loop(
+        &self,
+        condition: &Option<Condition>,
+        body: &Protocol,
+        indices: &Indices,
+    ) -> Result<Protocol, ExpansionError> {
+        let (count, range) = match condition {
+            Some(Condition::Count(n)) => (n.to_string(), Some(0..*n)),
+            Some(Condition::Custom(count)) => {
+                let count = count.to_string();
+                let range = self.range(&count);
+                (count, range)
+            }
+            Some(Condition::RoleDecides(role)) => {
+                return Ok(Protocol::Loop {
+                    condition: Some(Condition::RoleDecides(self.role(role, indices)?)),
+                    body: Box::new(self.protocol(body, indices)?),
+                })
+            }
+            None => {
+                return Ok(Protocol::Loop {
+                    condition: None,
+                    body: Box::new(self.protocol(body, indices)?),
+                })
+            }
+        };
+
+        let Some(var) = self.loop_index(&count, body, indices)? else {
+            return Ok(Protocol::Loop {
+                condition: match range {
+                    Some(range) => Some(Condition::Count(range.len())),
+                    None => condition.clone(),
+                },
+                body: Box::new(self.protocol(body, indices)?),
+            });
+        };
+        let range = range.ok_or_else(|| ExpansionError::UnboundCount(count.clone()))?;
+
+        // Each iteration runs after the previous one, so the copies are
+        // chained from the last
+        let mut unrolled = Protocol::End;
+        for index in range.rev() {
+            let mut inner = indices.clone();
+            inner.insert(var.clone(), index);
+            let iteration = self.protocol(body, &inner)?;
+            unrolled = then(iteration, unrolled)
+                .ok_or_else(|| ExpansionError::CannotUnroll(count.clone()))?;
+        }
+        Ok(unrolled)
+    }
+
+    /// Indices a counted loop runs over
+    ///
+    /// A `foreach` over a symbolic range is parsed to a loop counting
+    /// `end - start`, or `end` when it starts at zero.
+    fn range(&self, count: &str) -> Option<Range<usize>> {
+        let (end, start) = count.split_once(" - ").unwrap_or((count, "0"));
+        Some(value(start, self.bindings)?..value(end, self.bindings)?)
+    }
+
+    /// The index variable a counted loop binds, if its body uses one
+    ///
+    /// The loop's variable is the one index its body uses that no enclosing
+    /// loop binds. When there are several, as in nested loops, it is the one
+    /// indexing a family whose declared size is the loop's bound.
+    fn loop_index(
+        &self,
+        count: &str,
+        body: &Protocol,
+        indices: &Indices,
+    ) -> Result<Option<String>, ExpansionError> {
+        let mut free: BTreeSet<(String, String)> = BTreeSet::new();
+        collect_references(body, &mut |role| {
+            if let Some((family, index)) = self.split(role) {
+                if index.parse::<usize>().is_err() && !indices.contains_key(&index) {
+                    free.insert((family, index));
+                }
+            }
+        });
+        let vars: BTreeSet<&String> = free.iter().map(|(_, index)| index).collect();
+        if vars.len() <= 1 {
+            return Ok(vars.into_iter().next().cloned());
+        }
+
+        let end = count.split_once(" - ").map_or(count, |(end, _)| end);
+        let sized: BTreeSet<&String> = free
+            .iter()
+            .filter(|(family, _)| {
+                self.families[family]
+                    .0
+                    .array_size
+                    .as_ref()
+                    .is_some_and(|size| size.to_string() == end)
+            })
+            .map(|(_, index)| index)
+            .collect();
+        if sized.len() == 1 {
+            return Ok(sized.into_iter().next().cloned());
+        }
+        Err(ExpansionError::AmbiguousIndex {
+            count: count.to_string(),
+            indices: vars
+                .into_iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+    }
+}
+
+/// Every role a protocol refers to by name, wherever it appears
+pub(crate) fn collect_references(protocol: &Protocol, f: &mut dyn FnMut(&Role)) {
+    walk_with_paths(protocol, &mut |_, node| match node {
+        Protocol::Send { from, to, .. } => {
+            f(from);
+            f(to);
+        }
+        Protocol::Broadcast { from, .. } => f(from),
+        Protocol::Choice { role, .. } | Protocol::Timeout { role, .. } => f(role),
+        Protocol::Loop {
+            condition: Some(Condition::RoleDecides(role)),
+            ..
+        } => f(role),
+        Protocol::Spawn { roles, .. } | Protocol::Await { roles, .. } => {
+            roles.iter().for_each(&mut *f)
+        }
+        _ => {}
+    });
+}
+
+/// `first` followed by `next`, where the end of `first` can be found
+///
+/// Choices, races, and the two sides of a `try` or `timeout` each go on
+/// to `next`. Loops, parallel blocks, and recursion have no end to continue
+/// from.
+fn then(first: Protocol, next: Protocol) -> Option<Protocol> {
+    let after = |p: Box<Protocol>| then(*p, next.clone()).map(Box::new);
+    let branches = |branches: Vec<Branch>| {
+        branches
+            .into_iter()
+            .map(|b| {
+                Some(Branch {
+                    protocol: then(b.protocol, next.clone())?,
+                    ..b
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+    Some(match first {
+        Protocol::End => next,
+        Protocol::Send {
+            from,
+            to,
+            message,
+            continuation,
+        } => Protocol::Send {
+            from,
+            to,
+            message,
+            continuation: after(continuation)?,
+        },
+        Protocol::Broadcast {
+            from,
+            to_all,
+            message,
+            abort,
+            continuation,
+        } => Protocol::Broadcast {
+            from,
+            to_all,
+            message,
+            abort,
+            continuation: after(continuation)?,
+        },
+        Protocol::Choice { role, branches: b } => Protocol::Choice {
+            role,
+            branches: branches(b)?,
+        },
+        Protocol::Race { branches: b } => Protocol::Race {
+            branches: branches(b)?,
+        },
+        Protocol::Finally { body, cleanup } => Protocol::Finally {
+            body,
+            cleanup: after(cleanup)?,
+        },
+        Protocol::TryCatch {
+            body,
+            handler_roles,
+            compensation,
+        } => Protocol::TryCatch {
+            body: after(body)?,
+            handler_roles,
+            compensation: after(compensation)?,
+        },
+        Protocol::Timeout {
+            role,
+            duration,
+            body,
+            on_timeout,
+        } => Protocol::Timeout {
+            role,
+            duration,
+            body: after(body)?,
+            on_timeout: after(on_timeout)?,
+        },
+        Protocol::Spawn {
+            handle,
+            name,
+            roles,
+            body,
+            continuation,
+        } => Protocol::Spawn {
+            handle,
+            name,
+            roles,
+            body,
+            continuation: after(continuation)?,
+        },
+        Protocol::Await {
+            handle,
+            roles,
+            continuation,
+        } => Protocol::Await {
+            handle,
+            roles,
+            continuation: after(continuation)?,
+        },
+        Protocol::Loop { .. }
+        | Protocol::Parallel { .. }
+        | Protocol::Rec { .. }
+        | Protocol::Var(_) => return None,
+    })
+}
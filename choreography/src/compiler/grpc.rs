@@ -0,0 +1,279 @@
+// gRPC service definitions for a choreography
+//
+// Every directed edge `A -> B` becomes a bidirectional streaming method
+// `AToB` on the service of the receiving role `B`. The sender opens the call
+// and writes the messages and branch labels of the edge to its request
+// stream; the response stream carries nothing and ends when the call does.
+// The `.proto` lets participants written in other languages join, and the
+// tonic glue connects projected Rust roles through `GrpcHandler`.
+
+use crate::ast::{Choreography, Protocol};
+use crate::compiler::namespace::{module_name, snake_case};
+use crate::compiler::provenance::walk_with_paths;
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// What travels on each directed edge, keyed by (sender, receiver)
+pub(crate) type Edges = BTreeMap<(String, String), BTreeSet<String>>;
+
+pub(crate) fn collect_edges(choreography: &Choreography) -> Edges {
+    let mut edges = Edges::new();
+    walk_with_paths(&choreography.protocol, &mut |_, node| match node {
+        Protocol::Send {
+            from, to, message, ..
+        } => {
+            edges
+                .entry((from.name.to_string(), to.name.to_string()))
+                .or_default()
+                .insert(message.name.to_string());
+        }
+        Protocol::Broadcast {
+            from,
+            to_all,
+            message,
+            ..
+        } => {
+            for to in to_all {
+                edges
+                    .entry((from.name.to_string(), to.name.to_string()))
+                    .or_default()
+                    .insert(message.name.to_string());
+            }
+        }
+        Protocol::Choice { role, branches } => {
+            // The chooser's label goes to every role that takes part
+            for other in &choreography.roles {
+                if other.name != role.name
+                    && branches.iter().any(|b| b.protocol.mentions_role(other))
+                {
+                    let carried = edges
+                        .entry((role.name.to_string(), other.name.to_string()))
+                        .or_default();
+                    for branch in branches {
+                        carried.insert(format!("label {}", branch.label));
+                    }
+                }
+            }
+        }
+        _ => {}
+    });
+    edges
+}
+
+fn method_name(from: &str, to: &str) -> String {
+    format!("{}To{}", from, to)
+}
+
+/// The `.proto` file for a choreography
+///
+/// The package is the choreography's module name, with one service per role
+/// that receives anything.
+pub fn generate_grpc_proto(choreography: &Choreography) -> String {
+    let edges = collect_edges(choreography);
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "// Generated from choreography {}. Do not edit.",
+        choreography.name
+    );
+    out.push_str("syntax = \"proto3\";\n\n");
+    let _ = writeln!(out, "package {};\n", module_name(choreography));
+    out.push_str("// A message: its type name and JSON payload\n");
+    out.push_str("message Frame {\n  string name = 1;\n  bytes payload = 2;\n}\n\n");
+    out.push_str("message Envelope {\n  oneof body {\n");
+    out.push_str("    string label = 1;\n    Frame message = 2;\n  }\n}\n");
+
+    let mut seen = BTreeSet::new();
+    for role in &choreography.roles {
+        let receiver = role.name.to_string();
+        let incoming: Vec<_> = edges
+            .iter()
+            .filter(|((_, to), _)| *to == receiver)
+            .collect();
+        if incoming.is_empty() || !seen.insert(receiver.clone()) {
+            continue;
+        }
+        let _ = writeln!(out, "\nservice {} {{", receiver);
+        for ((from, to), carried) in incoming {
+            let carried: Vec<_> = carried.iter().map(String::as_str).collect();
+            let _ = writeln!(out, "  // {}", carried.join(", "));
+            let _ = writeln!(
+                out,
+                "  rpc {}(stream Envelope) returns (stream Envelope);",
+                method_name(from, to)
+            );
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+/// tonic glue between the generated `.proto` and `GrpcHandler`
+///
+/// Expects the prost types in a sibling module `proto`, e.g.
+/// `mod proto { tonic::include_proto!("ping_pong"); }`, and the `Role` enum
+/// of the effects code generated for the same choreography. For each
+/// receiving role it generates a `<Role>Service` that passes incoming calls
+/// to a `GrpcAcceptor`, and for each sending role an async `connect_<role>`
+/// function that connects to its peers and opens its outgoing calls. It
+/// returns an error if a peer cannot be reached; a call that fails later is
+/// reported to the endpoint's `GrpcFailures`.
+pub fn generate_grpc_glue(choreography: &Choreography) -> TokenStream {
+    let edges = collect_edges(choreography);
+    let module = module_name(choreography);
+    let glue = format_ident!("{}_grpc", module);
+
+    let mut receivers: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut senders: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (from, to) in edges.keys() {
+        receivers.entry(to).or_default().push(from);
+        senders.entry(from).or_default().push(to);
+    }
+
+    let services = receivers.iter().map(|(to, froms)| {
+        let service = format_ident!("{}Service", to);
+        let server = format_ident!("{}_server", snake_case(to));
+        let server_trait = format_ident!("{}", to);
+        let methods = froms.iter().map(|from| {
+            let method = format_ident!("{}", snake_case(&method_name(from, to)));
+            let stream = format_ident!("{}Stream", method_name(from, to));
+            let peer = format_ident!("{}", from);
+            quote! {
+                type #stream = Responses;
+
+                async fn #method(
+                    &self,
+                    request: tonic::Request<tonic::Streaming<proto::Envelope>>,
+                ) -> std::result::Result<tonic::Response<Responses>, tonic::Status> {
+                    let requests = request.into_inner().map(|item| {
+                        item.map_err(|status| ChoreographyError::Transport(status.to_string()))
+                            .and_then(from_proto)
+                    });
+                    let ended = self.acceptor.accept(Role::#peer, requests);
+                    Ok(tonic::Response::new(Box::pin(
+                        futures::stream::once(ended).filter_map(|()| async { None::<Response> }),
+                    )))
+                }
+            }
+        });
+        quote! {
+            /// Passes the calls of peers to the endpoint's acceptor
+            #[derive(Clone)]
+            pub struct #service {
+                acceptor: GrpcAcceptor<Role>,
+            }
+
+            impl #service {
+                pub fn new(acceptor: GrpcAcceptor<Role>) -> Self {
+                    Self { acceptor }
+                }
+            }
+
+            #[tonic::async_trait]
+            impl proto::#server::#server_trait for #service {
+                #(#methods)*
+            }
+        }
+    });
+
+    let connects = senders.iter().map(|(from, tos)| {
+        let connect = format_ident!("connect_{}", snake_case(from));
+        let calls = tos.iter().map(|to| {
+            let client_mod = format_ident!("{}_client", snake_case(to));
+            let client = format_ident!("{}Client", to);
+            let method = format_ident!("{}", snake_case(&method_name(from, to)));
+            let peer = format_ident!("{}", to);
+            quote! {
+                let channel = address_of(Role::#peer).connect().await.map_err(|e| {
+                    ChoreographyError::Transport(format!(
+                        "gRPC connection to {:?} failed: {}",
+                        Role::#peer,
+                        e
+                    ))
+                })?;
+                let mut client = proto::#client_mod::#client::new(channel);
+                let requests = endpoint.outgoing(Role::#peer).map(into_proto);
+                let failures = endpoint.failures();
+                rumpsteak_choreography::spawn(async move {
+                    if let Err(status) = drain(client.#method(requests)).await {
+                        failures.report(Role::#peer, call_failed(Role::#peer, status));
+                    }
+                });
+            }
+        });
+        quote! {
+            /// Connect to every peer this role sends to and open the calls
+            /// of its edges
+            pub async fn #connect(
+                endpoint: &mut GrpcEndpoint<Role>,
+                address_of: impl Fn(Role) -> tonic::transport::Endpoint,
+            ) -> Result<()> {
+                #(#calls)*
+                Ok(())
+            }
+        }
+    });
+
+    quote! {
+        pub mod #glue {
+            use super::proto;
+            use super::#module::Role;
+            use futures::StreamExt;
+            use rumpsteak_choreography::{
+                ChoreographyError, GrpcAcceptor, GrpcEndpoint, GrpcEnvelope, Result,
+            };
+
+            type Response = std::result::Result<proto::Envelope, tonic::Status>;
+            type Responses = std::pin::Pin<Box<dyn futures::Stream<Item = Response> + Send>>;
+
+            /// Wait for an outgoing call to end, failing if it does not end
+            /// cleanly
+            async fn drain(
+                call: impl std::future::Future<
+                    Output = std::result::Result<
+                        tonic::Response<tonic::Streaming<proto::Envelope>>,
+                        tonic::Status,
+                    >,
+                >,
+            ) -> std::result::Result<(), tonic::Status> {
+                let mut responses = call.await?.into_inner();
+                while responses.message().await?.is_some() {}
+                Ok(())
+            }
+
+            fn call_failed(peer: Role, status: tonic::Status) -> ChoreographyError {
+                ChoreographyError::Transport(format!("gRPC call to {:?} failed: {}", peer, status))
+            }
+
+            fn into_proto(envelope: GrpcEnvelope) -> proto::Envelope {
+                let body = match envelope {
+                    GrpcEnvelope::Label(label) => proto::envelope::Body::Label(label),
+                    GrpcEnvelope::Message { name, payload } => {
+                        proto::envelope::Body::Message(proto::Frame { name, payload })
+                    }
+                };
+                proto::Envelope { body: Some(body) }
+            }
+
+            fn from_proto(envelope: proto::Envelope) -> Result<GrpcEnvelope> {
+                match envelope.body {
+                    Some(proto::envelope::Body::Label(label)) => Ok(GrpcEnvelope::Label(label)),
+                    Some(proto::envelope::Body::Message(frame)) => Ok(GrpcEnvelope::Message {
+                        name: frame.name,
+                        payload: frame.payload,
+                    }),
+                    None => Err(ChoreographyError::ProtocolViolation(
+                        "gRPC envelope without a body".to_string(),
+                    )),
+                }
+            }
+
+            #(#services)*
+
+            #(#connects)*
+        }
+    }
+}
@@ -0,0 +1,322 @@
+// Hash-consed local types shared between roles
+//
+// Symmetric roles such as `Worker[0]` .. `Worker[63]` project to identical
+// local types, and most projections end in the same suffixes. Holding one
+// `LocalType` tree per role repeats those subtrees, and comparing two roles
+// walks both trees. The interner stores every distinct subtree once and
+// hands out ids, so equal types share memory and compare in constant time.
+
+use crate::ast::{Choreography, Condition, LocalType, MessageType, Role};
+use crate::compiler::projection::{project, ProjectionError};
+use proc_macro2::Ident;
+use std::collections::HashMap;
+
+/// Handle to an interned local type
+///
+/// Two ids from the same interner are equal exactly when the types they
+/// stand for are equal, including message payload annotations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LocalTypeId(u32);
+
+/// One node of an interned local type, with children replaced by ids
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SharedNode {
+    Send {
+        to: Role,
+        message: MessageType,
+        continuation: LocalTypeId,
+    },
+    Receive {
+        from: Role,
+        message: MessageType,
+        continuation: LocalTypeId,
+    },
+    Select {
+        to: Role,
+        branches: Vec<(Ident, LocalTypeId)>,
+    },
+    Branch {
+        from: Role,
+        branches: Vec<(Ident, LocalTypeId)>,
+    },
+    LocalChoice {
+        branches: Vec<(Ident, LocalTypeId)>,
+    },
+    Race {
+        branches: Vec<(Ident, LocalTypeId)>,
+    },
+    Loop {
+        condition: Option<Condition>,
+        body: LocalTypeId,
+    },
+    Rec {
+        label: Ident,
+        body: LocalTypeId,
+    },
+    Var(Ident),
+    Finally {
+        body: LocalTypeId,
+        cleanup: LocalTypeId,
+    },
+    TryCatch {
+        body: LocalTypeId,
+        notify: Vec<Role>,
+        compensation: LocalTypeId,
+    },
+    Timeout {
+        duration: std::time::Duration,
+        body: LocalTypeId,
+        notify: Vec<Role>,
+        on_timeout: LocalTypeId,
+    },
+    End,
+}
+
+/// Store of distinct local type subtrees
+#[derive(Debug, Default)]
+pub struct LocalTypeInterner {
+    nodes: Vec<SharedNode>,
+    ids: HashMap<SharedNode, LocalTypeId>,
+}
+
+impl LocalTypeInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Intern `local_type` and every subtree of it
+    pub fn intern(&mut self, local_type: &LocalType) -> LocalTypeId {
+        let node = match local_type {
+            LocalType::Send {
+                to,
+                message,
+                continuation,
+            } => SharedNode::Send {
+                to: to.clone(),
+                message: message.clone(),
+                continuation: self.intern(continuation),
+            },
+            LocalType::Receive {
+                from,
+                message,
+                continuation,
+            } => SharedNode::Receive {
+                from: from.clone(),
+                message: message.clone(),
+                continuation: self.intern(continuation),
+            },
+            LocalType::Select { to, branches } => SharedNode::Select {
+                to: to.clone(),
+                branches: self.intern_branches(branches),
+            },
+            LocalType::Branch { from, branches } => SharedNode::Branch {
+                from: from.clone(),
+                branches: self.intern_branches(branches),
+            },
+            LocalType::LocalChoice { branches } => SharedNode::LocalChoice {
+                branches: self.intern_branches(branches),
+            },
+            LocalType::Race { branches } => SharedNode::Race {
+                branches: self.intern_branches(branches),
+            },
+            LocalType::Loop { condition, body } => SharedNode::Loop {
+                condition: condition.clone(),
+                body: self.intern(body),
+            },
+            LocalType::Rec { label, body } => SharedNode::Rec {
+                label: label.clone(),
+                body: self.intern(body),
+            },
+            LocalType::Var(label) => SharedNode::Var(label.clone()),
+            LocalType::Finally { body, cleanup } => SharedNode::Finally {
+                body: self.intern(body),
+                cleanup: self.intern(cleanup),
+            },
+            LocalType::TryCatch {
+                body,
+                notify,
+                compensation,
+            } => SharedNode::TryCatch {
+                body: self.intern(body),
+                notify: notify.clone(),
+                compensation: self.intern(compensation),
+            },
+            LocalType::Timeout {
+                duration,
+                body,
+                notify,
+                on_timeout,
+            } => SharedNode::Timeout {
+                duration: *duration,
+                body: self.intern(body),
+                notify: notify.clone(),
+                on_timeout: self.intern(on_timeout),
+            },
+            LocalType::End => SharedNode::End,
+        };
+        self.insert(node)
+    }
+
+    fn intern_branches(&mut self, branches: &[(Ident, LocalType)]) -> Vec<(Ident, LocalTypeId)> {
+        branches
+            .iter()
+            .map(|(label, ty)| (label.clone(), self.intern(ty)))
+            .collect()
+    }
+
+    fn insert(&mut self, node: SharedNode) -> LocalTypeId {
+        if let Some(&id) = self.ids.get(&node) {
+            return id;
+        }
+        let id = LocalTypeId(self.nodes.len() as u32);
+        self.nodes.push(node.clone());
+        self.ids.insert(node, id);
+        id
+    }
+
+    /// The node behind `id`
+    ///
+    /// # Panics
+    ///
+    /// If `id` comes from a different interner.
+    pub fn node(&self, id: LocalTypeId) -> &SharedNode {
+        &self.nodes[id.0 as usize]
+    }
+
+    /// Rebuild the full tree behind `id`
+    pub fn resolve(&self, id: LocalTypeId) -> LocalType {
+        let branches = |branches: &[(Ident, LocalTypeId)]| {
+            branches
+                .iter()
+                .map(|(label, id)| (label.clone(), self.resolve(*id)))
+                .collect()
+        };
+        match self.node(id) {
+            SharedNode::Send {
+                to,
+                message,
+                continuation,
+            } => LocalType::Send {
+                to: to.clone(),
+                message: message.clone(),
+                continuation: Box::new(self.resolve(*continuation)),
+            },
+            SharedNode::Receive {
+                from,
+                message,
+                continuation,
+            } => LocalType::Receive {
+                from: from.clone(),
+                message: message.clone(),
+                continuation: Box::new(self.resolve(*continuation)),
+            },
+            SharedNode::Select { to, branches: b } => LocalType::Select {
+                to: to.clone(),
+                branches: branches(b),
+            },
+            SharedNode::Branch { from, branches: b } => LocalType::Branch {
+                from: from.clone(),
+                branches: branches(b),
+            },
+            SharedNode::LocalChoice { branches: b } => LocalType::LocalChoice {
+                branches: branches(b),
+            },
+            SharedNode::Race { branches: b } => LocalType::Race {
+                branches: branches(b),
+            },
+            SharedNode::Loop { condition, body } => LocalType::Loop {
+                condition: condition.clone(),
+                body: Box::new(self.resolve(*body)),
+            },
+            SharedNode::Rec { label, body } => LocalType::Rec {
+                label: label.clone(),
+                body: Box::new(self.resolve(*body)),
+            },
+            SharedNode::Var(label) => LocalType::Var(label.clone()),
+            SharedNode::Finally { body, cleanup } => LocalType::Finally {
+                body: Box::new(self.resolve(*body)),
+                cleanup: Box::new(self.resolve(*cleanup)),
+            },
+            SharedNode::TryCatch {
+                body,
+                notify,
+                compensation,
+            } => LocalType::TryCatch {
+                body: Box::new(self.resolve(*body)),
+                notify: notify.clone(),
+                compensation: Box::new(self.resolve(*compensation)),
+            },
+            SharedNode::Timeout {
+                duration,
+                body,
+                notify,
+                on_timeout,
+            } => LocalType::Timeout {
+                duration: *duration,
+                body: Box::new(self.resolve(*body)),
+                notify: notify.clone(),
+                on_timeout: Box::new(self.resolve(*on_timeout)),
+            },
+            SharedNode::End => LocalType::End,
+        }
+    }
+
+    /// Number of distinct nodes stored
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// Projections of every role of a choreography, sharing equal subtrees
+#[derive(Debug)]
+pub struct SharedProjections {
+    interner: LocalTypeInterner,
+    roots: Vec<(Role, LocalTypeId)>,
+}
+
+impl SharedProjections {
+    /// The local type of `role`, if it is a role of the choreography
+    pub fn get(&self, role: &Role) -> Option<LocalTypeId> {
+        self.roots
+            .iter()
+            .find(|(r, _)| r == role)
+            .map(|(_, id)| *id)
+    }
+
+    /// Rebuild the full local type of `role`
+    pub fn local_type(&self, role: &Role) -> Option<LocalType> {
+        self.get(role).map(|id| self.interner.resolve(id))
+    }
+
+    /// Whether two roles have the same local type, in constant time
+    pub fn same_type(&self, a: &Role, b: &Role) -> bool {
+        matches!((self.get(a), self.get(b)), (Some(a), Some(b)) if a == b)
+    }
+
+    /// Every role with its local type, in declaration order
+    pub fn roles(&self) -> &[(Role, LocalTypeId)] {
+        &self.roots
+    }
+
+    pub fn interner(&self) -> &LocalTypeInterner {
+        &self.interner
+    }
+}
+
+/// Project every role of `choreography` into one shared store
+///
+/// Each projection is interned as soon as it is built, so at most one full
+/// tree is alive at a time.
+pub fn project_shared(choreography: &Choreography) -> Result<SharedProjections, ProjectionError> {
+    let mut interner = LocalTypeInterner::new();
+    let mut roots = Vec::with_capacity(choreography.roles.len());
+    for role in &choreography.roles {
+        let local_type = project(choreography, role)?;
+        roots.push((role.clone(), interner.intern(&local_type)));
+    }
+    Ok(SharedProjections { interner, roots })
+}
@@ -0,0 +1,147 @@
+// Checking protocol libraries in parallel
+//
+// Build scripts and tools often check a whole directory of choreographies
+// at once. Each source is independent, so parsing, validation, and
+// analysis run on a rayon thread per source and the diagnostics are merged
+// in input order afterwards. The AST holds `proc_macro2` identifiers,
+// which cannot cross threads, so each source is parsed on the thread that
+// checks it and only diagnostics come back.
+
+use crate::compiler::analysis::analyze_with_provenance;
+use crate::compiler::config::CompileConfig;
+use crate::compiler::diagnostic::{Diagnostic, Severity};
+use crate::compiler::parser::parse_choreography_with_provenance;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// One choreography source of a library
+#[derive(Debug, Clone)]
+pub struct LibrarySource {
+    /// Where the source came from, e.g. its file path
+    pub origin: String,
+    pub text: String,
+}
+
+impl LibrarySource {
+    pub fn new(origin: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            origin: origin.into(),
+            text: text.into(),
+        }
+    }
+
+    /// Read a source file, using its path as the origin
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self::new(
+            path.display().to_string(),
+            std::fs::read_to_string(path)?,
+        ))
+    }
+}
+
+/// Findings for one source
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryEntry {
+    pub origin: String,
+    /// Name of the choreography, if the source parsed
+    pub choreography: Option<String>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Merged findings for a library, in the order the sources were given
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryReport {
+    pub entries: Vec<LibraryEntry>,
+}
+
+impl LibraryReport {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics()
+            .any(|(_, d)| d.severity == Severity::Error)
+    }
+
+    /// Every diagnostic with the origin of its source
+    pub fn diagnostics(&self) -> impl Iterator<Item = (&str, &Diagnostic)> {
+        self.entries.iter().flat_map(|entry| {
+            entry
+                .diagnostics
+                .iter()
+                .map(move |d| (entry.origin.as_str(), d))
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("library report is always serializable")
+    }
+}
+
+impl fmt::Display for LibraryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (origin, diagnostic) in self.diagnostics() {
+            writeln!(f, "{}: {}", origin, diagnostic)?;
+        }
+        let errors = self
+            .diagnostics()
+            .filter(|(_, d)| d.severity == Severity::Error)
+            .count();
+        let warnings = self
+            .diagnostics()
+            .filter(|(_, d)| d.severity == Severity::Warning)
+            .count();
+        write!(
+            f,
+            "{} source(s), {} error(s), {} warning(s)",
+            self.entries.len(),
+            errors,
+            warnings
+        )
+    }
+}
+
+/// Parse, validate, and analyze every source, in parallel where threads
+/// are available
+///
+/// A source that fails to parse or validate reports that error and is not
+/// analyzed. On wasm the sources are checked one after another.
+pub fn check_library(sources: &[LibrarySource], config: &CompileConfig) -> LibraryReport {
+    #[cfg(not(target_arch = "wasm32"))]
+    let entries = {
+        use rayon::prelude::*;
+        sources
+            .par_iter()
+            .map(|source| check_source(source, config))
+            .collect()
+    };
+    #[cfg(target_arch = "wasm32")]
+    let entries = sources
+        .iter()
+        .map(|source| check_source(source, config))
+        .collect();
+    LibraryReport { entries }
+}
+
+fn check_source(source: &LibrarySource, config: &CompileConfig) -> LibraryEntry {
+    let mut entry = LibraryEntry {
+        origin: source.origin.clone(),
+        choreography: None,
+        diagnostics: Vec::new(),
+    };
+    let (choreography, provenance) = match parse_choreography_with_provenance(&source.text, config)
+    {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            entry.diagnostics.push(e.to_diagnostic());
+            return entry;
+        }
+    };
+    entry.choreography = Some(choreography.name.to_string());
+    if let Err(e) = choreography.validate_with_limits(config.limits()) {
+        entry.diagnostics.push(e.to_diagnostic());
+        return entry;
+    }
+    entry.diagnostics = analyze_with_provenance(&choreography, &provenance).diagnostics;
+    entry
+}
@@ -4,20 +4,71 @@
 //! specifications into executable code.
 
 pub mod analysis;
+pub mod cache;
 pub mod codegen;
+pub mod config;
+pub mod deadlock;
+pub mod diagnostic;
 pub mod effects_codegen;
+pub mod expand;
+pub mod grpc;
+pub mod interning;
+pub mod library;
+pub mod namespace;
+pub mod optimize;
 pub mod parser;
 pub mod projection;
+pub mod provenance;
+pub mod query;
+pub mod simulation;
+pub mod testkit;
+pub mod timeline;
+pub mod timings;
+pub mod vectors;
 
 // Re-export compiler pipeline components explicitly
 pub use analysis::{
-    analyze, generate_dot_graph, AnalysisResult, AnalysisWarning, CommunicationGraph,
-    ParticipationInfo,
+    analyze, analyze_with_provenance, find_starved_roles, generate_dot_graph, AnalysisContext,
+    AnalysisPass, AnalysisReport, AnalysisWarning, Analyzer, AnalyzerBuilder, CheckResult,
+    ChoiceSymmetryCheck, CleanupCheck, CommunicationGraph, CustomPass, DeadlockCheck, Findings,
+    GuardCheck, LivenessCheck, NamingCheck, ParticipationInfo, ProgressCheck, RaceCheck,
+    RouteCheck, SensitiveDataCheck, Starvation, UnusedRoleCheck,
 };
+pub use cache::{fingerprint, CacheError, CacheStats, Fingerprint, ProjectionCache};
 pub use codegen::{
     generate_choreography_code, generate_helpers, generate_role_implementations,
-    generate_session_type,
+    generate_session_type, pretty_print,
 };
-pub use effects_codegen::generate_effects_protocol;
-pub use parser::{choreography_macro, parse_choreography, parse_choreography_file, parse_dsl};
-pub use projection::{project, ProjectionError};
+pub use config::{CfgPredicate, CompileConfig};
+pub use deadlock::{find_deadlock, Deadlock};
+pub use diagnostic::{Diagnostic, FixIt, Severity, TextEdit};
+pub use effects_codegen::{
+    generate_effects_protocol, generate_effects_protocol_with_provenance, render_effects_protocol,
+};
+pub use expand::{expand_roles, ExpansionError};
+pub use grpc::{generate_grpc_glue, generate_grpc_proto};
+pub use interning::{
+    project_shared, LocalTypeId, LocalTypeInterner, SharedNode, SharedProjections,
+};
+pub use library::{check_library, LibraryEntry, LibraryReport, LibrarySource};
+pub use namespace::{check_name_collisions, generate_reexports, module_name, NameCollision};
+pub use optimize::{min_sync, optimize, Optimization, OptimizeError, RemovedInteraction};
+pub use parser::{
+    choreography_macro, parse_choreography, parse_choreography_file,
+    parse_choreography_str_with_config, parse_choreography_with_provenance,
+    parse_choreography_with_resolver, parse_dsl, ModuleResolver,
+};
+pub use projection::{project, project_family, FamilyProjection, ProjectionError};
+pub use provenance::{
+    role_steps, walk_with_paths, NodePath, Provenance, SourceMap, SourceMapEntry,
+};
+pub use query::{Interaction, ProtocolQuery, Step};
+pub use simulation::{
+    simulate, Latency, LatencyModel, LatencySummary, SimulationConfig, SimulationReport,
+};
+pub use testkit::{
+    generate_test_kit, KitMessage, KitStep, RoleSequences, TestKit, TestKitError, MAX_TRACES,
+};
+pub use timeline::{timeline, Lane, Timeline, TimelineAction, TimelineEntry};
+pub use timings::{compile_with_timings, CompileError, CompileTimings, NodeCounts};
+pub use vectors::{generate_reference_vectors, ReferenceVector, ReferenceVectors, VectorError};
@@ -0,0 +1,214 @@
+// Per-choreography namespaces for generated code
+//
+// Generated items such as `Role`, `Message`, and the message structs use
+// short names, so two choreographies compiled into the same module would
+// clash. Code generation wraps each choreography in its own module and this
+// file provides the naming, the collision checks, and re-export helpers.
+
+use crate::ast::{Choreography, MessageType, Protocol};
+use proc_macro2::{Ident, TokenStream};
+use quote::{format_ident, quote};
+use std::collections::HashMap;
+
+/// Names that generated code defines in every choreography module
+const RESERVED: &[&str] = &[
+    "Role",
+    "Message",
+    "Label",
+    "Roles",
+    "RuntimeConfig",
+    "RunAllResults",
+];
+
+/// A clash between generated item names
+#[derive(Debug, thiserror::Error)]
+pub enum NameCollision {
+    #[error(
+        "choreographies '{first}' and '{second}' both generate module '{module}'; rename one of them"
+    )]
+    Module {
+        module: String,
+        first: String,
+        second: String,
+    },
+
+    #[error("message '{message}' in choreography '{choreography}' has the same name as a role")]
+    MessageShadowsRole {
+        choreography: String,
+        message: String,
+    },
+
+    #[error(
+        "message '{message}' in choreography '{choreography}' uses a name reserved for generated code"
+    )]
+    ReservedName {
+        choreography: String,
+        message: String,
+    },
+
+    #[error(
+        "message '{message}' in choreography '{choreography}' is used with different payload types"
+    )]
+    ConflictingPayload {
+        choreography: String,
+        message: String,
+    },
+}
+
+/// Module that holds the generated items of a choreography
+///
+/// `PingPong` becomes `ping_pong`, `HTTPProxy` becomes `http_proxy`.
+pub fn module_name(choreography: &Choreography) -> Ident {
+    format_ident!("{}", snake_case(&choreography.name.to_string()))
+}
+
+/// Check that the choreographies can be generated side by side
+///
+/// Catches message names that would clash with roles or generated items
+/// inside one module, and choreographies whose module names collide.
+pub fn check_name_collisions(choreographies: &[&Choreography]) -> Result<(), NameCollision> {
+    let mut modules: HashMap<String, String> = HashMap::new();
+
+    for choreography in choreographies {
+        let name = choreography.name.to_string();
+        let module = module_name(choreography).to_string();
+        if let Some(first) = modules.insert(module.clone(), name.clone()) {
+            return Err(NameCollision::Module {
+                module,
+                first,
+                second: name,
+            });
+        }
+
+        let mut messages = Vec::new();
+        visit_messages(&choreography.protocol, &mut |m| messages.push(m.clone()));
+
+        let endpoint = format!("{}Endpoint", name);
+        let mut payloads: HashMap<String, String> = HashMap::new();
+        for message in messages {
+            let message_name = message.name.to_string();
+            if choreography.roles.iter().any(|r| r.name == message_name) {
+                return Err(NameCollision::MessageShadowsRole {
+                    choreography: name,
+                    message: message_name,
+                });
+            }
+            let role_endpoint = choreography
+                .roles
+                .iter()
+                .any(|r| format!("{}Endpoint", r.name) == message_name);
+            if RESERVED.contains(&message_name.as_str())
+                || message_name == endpoint
+                || role_endpoint
+            {
+                return Err(NameCollision::ReservedName {
+                    choreography: name,
+                    message: message_name,
+                });
+            }
+
+            let payload = message
+                .payload
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default();
+            if let Some(seen) = payloads.insert(message_name.clone(), payload.clone()) {
+                if seen != payload {
+                    return Err(NameCollision::ConflictingPayload {
+                        choreography: name,
+                        message: message_name,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-export the generic items of a choreography module under prefixed names
+///
+/// For `PingPong` this emits
+/// `pub use ping_pong::{Role as PingPongRole, Message as PingPongMessage};`,
+/// so several choreographies can be used from one scope without globbing
+/// their modules.
+pub fn generate_reexports(choreography: &Choreography) -> TokenStream {
+    let module = module_name(choreography);
+    let name = &choreography.name;
+    let role = format_ident!("{}Role", name);
+    let message = format_ident!("{}Message", name);
+
+    quote! {
+        pub use #module::{Role as #role, Message as #message};
+    }
+}
+
+fn visit_messages(protocol: &Protocol, f: &mut dyn FnMut(&MessageType)) {
+    match protocol {
+        Protocol::Send {
+            message,
+            continuation,
+            ..
+        }
+        | Protocol::Broadcast {
+            message,
+            continuation,
+            ..
+        } => {
+            f(message);
+            visit_messages(continuation, f);
+        }
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+            for branch in branches {
+                visit_messages(&branch.protocol, f);
+            }
+        }
+        Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => visit_messages(body, f),
+        Protocol::Parallel { protocols } => {
+            for p in protocols {
+                visit_messages(p, f);
+            }
+        }
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => {
+            visit_messages(body, f);
+            visit_messages(cleanup, f);
+        }
+        Protocol::Spawn {
+            body, continuation, ..
+        } => {
+            visit_messages(body, f);
+            visit_messages(continuation, f);
+        }
+        Protocol::Await { continuation, .. } => visit_messages(continuation, f),
+        Protocol::Var(_) | Protocol::End => {}
+    }
+}
+
+pub(crate) fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_uppercase() && next_is_lower)
+            {
+                out.push('_');
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
@@ -0,0 +1,291 @@
+// Protocol optimizations enabled through choreography annotations
+
+use crate::ast::{Branch, Choreography, Protocol, Role};
+use crate::compiler::analysis::{analyze, AnalysisReport};
+use std::fmt;
+
+/// Errors that can occur while optimizing a choreography
+#[derive(Debug, thiserror::Error)]
+pub enum OptimizeError {
+    #[error("optimization '{optimization}' broke the '{check}' check")]
+    SafetyRegression { optimization: String, check: String },
+}
+
+/// An interaction dropped by an optimization
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemovedInteraction {
+    pub from: Role,
+    pub to: Role,
+    pub message: String,
+    /// The later message that makes this one redundant
+    pub subsumed_by: String,
+}
+
+impl fmt::Display for RemovedInteraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "- {} -> {}: {}  (subsumed by {})",
+            self.from.name, self.to.name, self.message, self.subsumed_by
+        )
+    }
+}
+
+/// Result of running the optimizations requested by a choreography
+#[derive(Debug)]
+pub struct Optimization {
+    /// The rewritten choreography
+    pub choreography: Choreography,
+    /// Interactions that were removed, in protocol order
+    pub removed: Vec<RemovedInteraction>,
+    /// Safety checks re-run on the rewritten protocol; `None` when nothing changed
+    pub report: Option<AnalysisReport>,
+}
+
+impl Optimization {
+    /// Removed interactions as a diff, one line each
+    pub fn diff(&self) -> String {
+        self.removed.iter().map(|r| format!("{}\n", r)).collect()
+    }
+}
+
+/// Apply the optimizations named in `@optimize(...)`
+///
+/// Other `@optimize` hints (`inline`, `buffer_size=...`) are left for code
+/// generation. Without the annotation the choreography is returned unchanged.
+pub fn optimize(choreography: &Choreography) -> Result<Optimization, OptimizeError> {
+    let hints = choreography.attrs.get("optimize");
+    if hints.is_some_and(|h| h.split(',').any(|hint| hint.trim() == "min_sync")) {
+        return min_sync(choreography);
+    }
+
+    Ok(Optimization {
+        choreography: choreography.clone(),
+        removed: Vec::new(),
+        report: None,
+    })
+}
+
+/// Remove synchronization messages made redundant by later messages
+///
+/// A payload-free message from `A` to `B` only tells `B` that `A` reached
+/// that point. When the next interaction involving `B` is another message
+/// from `A`, FIFO delivery already gives `B` that guarantee, so the first
+/// message can go. Only straight-line sequences are rewritten; a choice,
+/// loop, or recursion between the two messages keeps the original.
+///
+/// The rewritten protocol is analyzed again, and the optimization is
+/// rejected if any check that passed before now fails.
+pub fn min_sync(choreography: &Choreography) -> Result<Optimization, OptimizeError> {
+    let mut removed = Vec::new();
+    let protocol = strip_redundant_sync(&choreography.protocol, &mut removed);
+
+    if removed.is_empty() {
+        return Ok(Optimization {
+            choreography: choreography.clone(),
+            removed,
+            report: None,
+        });
+    }
+
+    let optimized = Choreography {
+        protocol,
+        ..choreography.clone()
+    };
+
+    let before = analyze(choreography);
+    let after = analyze(&optimized);
+    for check in &before.checks {
+        if check.passed && after.check(&check.name).is_some_and(|c| !c.passed) {
+            return Err(OptimizeError::SafetyRegression {
+                optimization: "min_sync".to_string(),
+                check: check.name.clone(),
+            });
+        }
+    }
+
+    Ok(Optimization {
+        choreography: optimized,
+        removed,
+        report: Some(after),
+    })
+}
+
+fn strip_redundant_sync(protocol: &Protocol, removed: &mut Vec<RemovedInteraction>) -> Protocol {
+    match protocol {
+        Protocol::Send {
+            from,
+            to,
+            message,
+            continuation,
+        } => {
+            let is_sync = message.payload.is_none() && message.type_annotation.is_none();
+            if is_sync {
+                if let Some(later) = next_message_from(continuation, from, to) {
+                    removed.push(RemovedInteraction {
+                        from: from.clone(),
+                        to: to.clone(),
+                        message: message.name.to_string(),
+                        subsumed_by: later,
+                    });
+                    return strip_redundant_sync(continuation, removed);
+                }
+            }
+            Protocol::Send {
+                from: from.clone(),
+                to: to.clone(),
+                message: message.clone(),
+                continuation: Box::new(strip_redundant_sync(continuation, removed)),
+            }
+        }
+        Protocol::Broadcast {
+            from,
+            to_all,
+            message,
+            abort,
+            continuation,
+        } => Protocol::Broadcast {
+            from: from.clone(),
+            to_all: to_all.clone(),
+            message: message.clone(),
+            abort: *abort,
+            continuation: Box::new(strip_redundant_sync(continuation, removed)),
+        },
+        Protocol::Choice { role, branches } => Protocol::Choice {
+            role: role.clone(),
+            branches: branches
+                .iter()
+                .map(|b| Branch {
+                    label: b.label.clone(),
+                    guard: b.guard.clone(),
+                    weight: b.weight,
+                    protocol: strip_redundant_sync(&b.protocol, removed),
+                })
+                .collect(),
+        },
+        Protocol::Race { branches } => Protocol::Race {
+            // The first message of an arm is what starts it, so it stays
+            branches: branches
+                .iter()
+                .map(|b| Branch {
+                    protocol: match &b.protocol {
+                        Protocol::Send {
+                            from,
+                            to,
+                            message,
+                            continuation,
+                        } => Protocol::Send {
+                            from: from.clone(),
+                            to: to.clone(),
+                            message: message.clone(),
+                            continuation: Box::new(strip_redundant_sync(continuation, removed)),
+                        },
+                        other => strip_redundant_sync(other, removed),
+                    },
+                    ..b.clone()
+                })
+                .collect(),
+        },
+        Protocol::Loop { condition, body } => Protocol::Loop {
+            condition: condition.clone(),
+            body: Box::new(strip_redundant_sync(body, removed)),
+        },
+        Protocol::Parallel { protocols } => Protocol::Parallel {
+            protocols: protocols
+                .iter()
+                .map(|p| strip_redundant_sync(p, removed))
+                .collect(),
+        },
+        Protocol::Rec { label, body } => Protocol::Rec {
+            label: label.clone(),
+            body: Box::new(strip_redundant_sync(body, removed)),
+        },
+        Protocol::Finally { body, cleanup } => Protocol::Finally {
+            body: Box::new(strip_redundant_sync(body, removed)),
+            cleanup: Box::new(strip_redundant_sync(cleanup, removed)),
+        },
+        Protocol::TryCatch {
+            body,
+            handler_roles,
+            compensation,
+        } => Protocol::TryCatch {
+            body: Box::new(strip_redundant_sync(body, removed)),
+            handler_roles: handler_roles.clone(),
+            compensation: Box::new(strip_redundant_sync(compensation, removed)),
+        },
+        Protocol::Timeout {
+            role,
+            duration,
+            body,
+            on_timeout,
+        } => Protocol::Timeout {
+            role: role.clone(),
+            duration: *duration,
+            body: Box::new(strip_redundant_sync(body, removed)),
+            on_timeout: Box::new(strip_redundant_sync(on_timeout, removed)),
+        },
+        Protocol::Spawn {
+            handle,
+            name,
+            roles,
+            body,
+            continuation,
+        } => Protocol::Spawn {
+            handle: handle.clone(),
+            name: name.clone(),
+            roles: roles.clone(),
+            body: Box::new(strip_redundant_sync(body, removed)),
+            continuation: Box::new(strip_redundant_sync(continuation, removed)),
+        },
+        Protocol::Await {
+            handle,
+            roles,
+            continuation,
+        } => Protocol::Await {
+            handle: handle.clone(),
+            roles: roles.clone(),
+            continuation: Box::new(strip_redundant_sync(continuation, removed)),
+        },
+        Protocol::Var(_) | Protocol::End => protocol.clone(),
+    }
+}
+
+/// Describe the next straight-line interaction involving `to`, if it is a
+/// message from `from`
+fn next_message_from(protocol: &Protocol, from: &Role, to: &Role) -> Option<String> {
+    match protocol {
+        Protocol::Send {
+            from: sender,
+            to: receiver,
+            message,
+            continuation,
+        } => {
+            if receiver == to && sender == from {
+                Some(format!(
+                    "{} -> {}: {}",
+                    sender.name, receiver.name, message.name
+                ))
+            } else if receiver == to || sender == to {
+                None
+            } else {
+                next_message_from(continuation, from, to)
+            }
+        }
+        Protocol::Broadcast {
+            from: sender,
+            to_all,
+            message,
+            continuation,
+            ..
+        } => {
+            if sender == from && to_all.contains(to) {
+                Some(format!("{} ->*: {}", sender.name, message.name))
+            } else if sender == to || to_all.contains(to) {
+                None
+            } else {
+                next_message_from(continuation, from, to)
+            }
+        }
+        _ => None,
+    }
+}
@@ -2,12 +2,26 @@
 //
 // Full implementation using Pest grammar for parsing choreographic DSL
 
-use crate::ast::{Branch, Choreography, Condition, MessageType, Protocol, Role};
+use crate::ast::choreography::{
+    parse_size, Return, Route, WireFormat, MAX_SIZE, TRUSTED, UNKNOWN_LABELS, WIRE,
+};
+use crate::ast::message::{has_tags, payload_fields};
+use crate::ast::{
+    Branch, Choreography, Condition, MessageType, Protocol, ProtocolLimits, Role, SENSITIVE,
+};
+use crate::compiler::config::{CfgPredicate, CompileConfig};
+use crate::compiler::diagnostic::{closest_match, Diagnostic, FixIt, Severity, TextEdit};
+use crate::compiler::expand::expand_roles;
+use crate::compiler::projection::project;
+use crate::compiler::provenance::{walk_with_paths, NodePath, Provenance};
+use crate::effects::Guard;
 use pest::Parser;
 use pest_derive::Parser;
-use proc_macro2::{Ident, Span, TokenStream};
-use quote::format_ident;
-use std::collections::{HashMap, HashSet};
+use proc_macro2::{Ident, Literal, Span, TokenStream};
+use quote::{format_ident, ToTokens};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use syn::Result;
 use thiserror::Error;
 
@@ -16,7 +30,7 @@ use thiserror::Error;
 struct ChoreographyParser;
 
 /// Span information for error reporting
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct ErrorSpan {
     pub line: usize,
     pub column: usize,
@@ -92,7 +106,12 @@ pub enum ParseError {
     Syntax { span: ErrorSpan, message: String },
 
     #[error("{}", .span.format_error(&format!("Undefined role '{}'", .role)))]
-    UndefinedRole { role: String, span: ErrorSpan },
+    UndefinedRole {
+        role: String,
+        span: ErrorSpan,
+        /// Suggested fixes: the closest declared role, or declaring this one
+        fixits: Vec<FixIt>,
+    },
 
     #[error("{}", .span.format_error(&format!("Duplicate role declaration '{}'", .role)))]
     DuplicateRole { role: String, span: ErrorSpan },
@@ -111,6 +130,158 @@ pub enum ParseError {
 
     #[error("{}", .span.format_error(&format!("Duplicate protocol definition '{}'", .protocol)))]
     DuplicateProtocol { protocol: String, span: ErrorSpan },
+
+    #[error("{}", .span.format_error(&format!("Constant '{}' has no value: give it a default or set it in the CompileConfig", .name)))]
+    UnresolvedConst { name: String, span: ErrorSpan },
+
+    #[error("{}", .span.format_error(&format!("Cannot import '{}': {}", .path, .message)))]
+    Import {
+        path: String,
+        message: String,
+        span: ErrorSpan,
+    },
+
+    #[error("{}", .span.format_error(&format!("Import cycle: {}", .cycle)))]
+    ImportCycle { cycle: String, span: ErrorSpan },
+}
+
+impl ParseError {
+    /// Stable machine-readable code for this error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::Pest(_) => "P001",
+            ParseError::Syntax { .. } => "P002",
+            ParseError::UndefinedRole { .. } => "P003",
+            ParseError::DuplicateRole { .. } => "P004",
+            ParseError::EmptyChoreography => "P005",
+            ParseError::InvalidMessage { .. } => "P006",
+            ParseError::InvalidCondition { .. } => "P007",
+            ParseError::UndefinedProtocol { .. } => "P008",
+            ParseError::DuplicateProtocol { .. } => "P009",
+            ParseError::UnresolvedConst { .. } => "P010",
+            ParseError::Import { .. } => "P011",
+            ParseError::ImportCycle { .. } => "P012",
+        }
+    }
+
+    /// Source location of the error, if known
+    pub fn span(&self) -> Option<&ErrorSpan> {
+        match self {
+            ParseError::Pest(_) | ParseError::EmptyChoreography => None,
+            ParseError::Syntax { span, .. }
+            | ParseError::UndefinedRole { span, .. }
+            | ParseError::DuplicateRole { span, .. }
+            | ParseError::InvalidMessage { span, .. }
+            | ParseError::InvalidCondition { span, .. }
+            | ParseError::UndefinedProtocol { span, .. }
+            | ParseError::DuplicateProtocol { span, .. }
+            | ParseError::UnresolvedConst { span, .. }
+            | ParseError::Import { span, .. }
+            | ParseError::ImportCycle { span, .. } => Some(span),
+        }
+    }
+
+    /// Convert into a structured diagnostic for editors and tools
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let message = match self {
+            ParseError::Pest(err) => err.variant.message().to_string(),
+            ParseError::Syntax { message, .. } => format!("Syntax error: {}", message),
+            ParseError::UndefinedRole { role, .. } => format!("Undefined role '{}'", role),
+            ParseError::DuplicateRole { role, .. } => {
+                format!("Duplicate role declaration '{}'", role)
+            }
+            ParseError::EmptyChoreography => "Empty choreography: no statements found".to_string(),
+            ParseError::InvalidMessage { message, .. } => {
+                format!("Invalid message format: {}", message)
+            }
+            ParseError::InvalidCondition { message, .. } => {
+                format!("Invalid condition: {}", message)
+            }
+            ParseError::UndefinedProtocol { protocol, .. } => {
+                format!("Undefined protocol '{}'", protocol)
+            }
+            ParseError::DuplicateProtocol { protocol, .. } => {
+                format!("Duplicate protocol definition '{}'", protocol)
+            }
+            ParseError::UnresolvedConst { name, .. } => {
+                format!("Constant '{}' has no value", name)
+            }
+            ParseError::Import { path, message, .. } => {
+                format!("Cannot import '{}': {}", path, message)
+            }
+            ParseError::ImportCycle { cycle, .. } => format!("Import cycle: {}", cycle),
+        };
+
+        let mut diagnostic = Diagnostic::new(self.code(), Severity::Error, message);
+        diagnostic.span = self.span().cloned();
+        if let ParseError::UndefinedRole { fixits, .. } = self {
+            diagnostic.fixits = fixits.clone();
+        }
+        diagnostic
+    }
+}
+
+/// Suggest fixes for an undefined role: the closest declared role by edit
+/// distance, and adding the role to the `roles:` list
+fn add_role_fixits(error: ParseError, input: &str) -> ParseError {
+    let ParseError::UndefinedRole {
+        role,
+        span,
+        mut fixits,
+    } = error
+    else {
+        return error;
+    };
+
+    let role_list = ChoreographyParser::parse(Rule::choreography, input)
+        .ok()
+        .and_then(|mut pairs| pairs.next())
+        .and_then(|choreo| {
+            choreo
+                .into_inner()
+                .find(|p| p.as_rule() == Rule::roles_decl)
+        })
+        .and_then(|decl| decl.into_inner().find(|p| p.as_rule() == Rule::role_list));
+
+    if let Some(role_list) = role_list {
+        let declared: Vec<&str> = role_list
+            .clone()
+            .into_inner()
+            .filter_map(|decl| decl.into_inner().next())
+            .map(|ident| ident.as_str())
+            .collect();
+
+        if let Some(closest) = closest_match(&role, declared.iter().copied()) {
+            let mut replace = span.clone();
+            replace.column_end = replace.column + role.chars().count();
+            replace.line_end = replace.line;
+            fixits.push(FixIt::new(
+                format!("did you mean `{}`?", closest),
+                vec![TextEdit {
+                    span: replace,
+                    replacement: closest.to_string(),
+                }],
+            ));
+        }
+
+        // Spans of the list and its declarations run into the following
+        // whitespace, so insert after the last token of the last declaration
+        let last = role_list
+            .into_inner()
+            .last()
+            .and_then(|decl| decl.into_inner().last());
+        if let Some(end) = last.map(|token| token.as_span().end_pos()) {
+            fixits.push(FixIt::new(
+                format!("add `{}` to the roles list", role),
+                vec![TextEdit {
+                    span: ErrorSpan::from_pest_span(end.span(&end), input),
+                    replacement: format!(", {}", role),
+                }],
+            ));
+        }
+    }
+
+    ParseError::UndefinedRole { role, span, fixits }
 }
 
 /// Format Pest errors nicely
@@ -162,7 +333,10 @@ fn parse_annotation(
                                         _ => {}
                                     }
                                 }
-                                if !arg_val.is_empty() {
+                                if arg_key.is_empty() {
+                                    // Bare value such as @weight(0.9)
+                                    values.push(arg_val);
+                                } else if !arg_val.is_empty() {
                                     values.push(format!("{}={}", arg_key, arg_val));
                                 } else if !arg_key.is_empty() {
                                     values.push(arg_key);
@@ -186,35 +360,297 @@ fn parse_annotation(
 }
 
 /// Parse a choreographic protocol from a string
+///
+/// `#[cfg(...)]` blocks are resolved against an empty [`CompileConfig`], so
+/// only blocks whose condition holds without any features are kept.
 pub fn parse_choreography_str(input: &str) -> std::result::Result<Choreography, ParseError> {
+    parse_choreography_str_with_config(input, &CompileConfig::default())
+}
+
+/// Parse a choreographic protocol, resolving `#[cfg(...)]` blocks against `config`
+pub fn parse_choreography_str_with_config(
+    input: &str,
+    config: &CompileConfig,
+) -> std::result::Result<Choreography, ParseError> {
+    parse_choreography_with_provenance(input, config).map(|(choreography, _)| choreography)
+}
+
+/// Parse a choreographic protocol and record the source span of every
+/// protocol node
+///
+/// The [`Provenance`] lets generated code and runtime errors point back at
+/// the DSL statement that defined a step. Imports are resolved against the
+/// current directory.
+pub fn parse_choreography_with_provenance(
+    input: &str,
+    config: &CompileConfig,
+) -> std::result::Result<(Choreography, Provenance), ParseError> {
+    parse_choreography_with_resolver(input, config, &ModuleResolver::default())
+}
+
+/// Parse a choreographic protocol, loading its imports through `resolver`
+pub fn parse_choreography_with_resolver(
+    input: &str,
+    config: &CompileConfig,
+    resolver: &ModuleResolver,
+) -> std::result::Result<(Choreography, Provenance), ParseError> {
+    parse_choreography_inner(input, config, resolver)
+        .map(|(choreography, provenance, _)| (choreography, provenance))
+        .map_err(|e| add_role_fixits(e, input))
+}
+
+/// Finds the files named by `import` declarations
+///
+/// Paths are relative to the directory of the importing file, or to the
+/// root for input that does not come from a file. The resolver tracks the
+/// chain of files being imported, so a file that imports itself, directly
+/// or through others, is rejected.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleResolver {
+    root: PathBuf,
+    /// Files being parsed, outermost first
+    chain: Vec<PathBuf>,
+}
+
+impl ModuleResolver {
+    /// Resolve the imports of top-level input against `root`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ModuleResolver {
+            root: root.into(),
+            chain: Vec::new(),
+        }
+    }
+
+    /// Resolver for the imports of the file at `path`
+    fn within(&self, path: &Path) -> Self {
+        let mut chain = self.chain.clone();
+        chain.push(path.to_path_buf());
+        ModuleResolver {
+            root: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            chain,
+        }
+    }
+
+    /// Parse the file `path` names and return the protocols it defines
+    fn import(
+        &self,
+        path: &str,
+        config: &CompileConfig,
+        span: ErrorSpan,
+    ) -> std::result::Result<ProtocolDefs, ParseError> {
+        let error = |message: String| ParseError::Import {
+            path: path.to_string(),
+            message,
+            span: span.clone(),
+        };
+        let file = self
+            .root
+            .join(path)
+            .canonicalize()
+            .map_err(|e| error(e.to_string()))?;
+        if let Some(start) = self.chain.iter().position(|f| *f == file) {
+            let cycle: Vec<String> = self.chain[start..]
+                .iter()
+                .chain([&file])
+                .map(|f| f.display().to_string())
+                .collect();
+            return Err(ParseError::ImportCycle {
+                cycle: cycle.join(" -> "),
+                span,
+            });
+        }
+        let source = std::fs::read_to_string(&file).map_err(|e| error(e.to_string()))?;
+        let (_, _, protocols) = parse_choreography_inner(&source, config, &self.within(&file))?;
+        // A file's own imports are not passed on to its importers
+        Ok(protocols
+            .into_iter()
+            .filter(|(name, _)| !name.contains('.'))
+            .collect())
+    }
+}
+
+/// Reject input whose blocks nest far deeper than the depth limit allows,
+/// before the recursive parser can overflow the stack on it
+///
+/// A level of protocol nesting takes at most two blocks, as a choice branch
+/// does, and the choreography and a protocol definition add one each.
+/// Validation checks the exact depth once the protocol is built.
+fn check_nesting(input: &str, limits: &ProtocolLimits) -> std::result::Result<(), ParseError> {
+    let allowed = limits.max_depth.saturating_mul(2).saturating_add(2);
+    let mut depth = 0usize;
+    for (offset, c) in input.char_indices() {
+        match c {
+            '{' if depth == allowed => {
+                let span = pest::Span::new(input, offset, offset + 1).expect("offset is in input");
+                return Err(ParseError::Syntax {
+                    span: ErrorSpan::from_pest_span(span, input),
+                    message: format!(
+                        "blocks nest more than {} deep, beyond the nesting limit of {}",
+                        allowed, limits.max_depth
+                    ),
+                });
+            }
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A `protocol` definition
+#[derive(Debug, Clone)]
+struct ProtocolDef {
+    /// The roles the body is written over, which every call must bind; the
+    /// choreography's own roles when the definition declares none
+    params: Option<Vec<String>>,
+    body: Vec<Statement>,
+}
+
+/// Protocol definitions by name; imported ones are named `Module.Protocol`
+type ProtocolDefs = HashMap<String, ProtocolDef>;
+
+fn parse_choreography_inner(
+    input: &str,
+    config: &CompileConfig,
+    resolver: &ModuleResolver,
+) -> std::result::Result<(Choreography, Provenance, ProtocolDefs), ParseError> {
+    check_nesting(input, config.limits())?;
     let pairs = ChoreographyParser::parse(Rule::choreography, input).map_err(Box::new)?;
 
     let mut name = format_ident!("Unnamed");
     let mut roles = Vec::new();
     let mut declared_roles = HashSet::new();
-    let mut protocol_defs: HashMap<String, Vec<Statement>> = HashMap::new();
+    let mut protocol_defs: ProtocolDefs = HashMap::new();
+    let mut modules = HashSet::new();
     let mut statements = Vec::new();
+    let mut cleanup_statements = None;
     let mut attrs: HashMap<String, String> = HashMap::new();
+    let mut aliases = Aliases::default();
+    let mut consts: HashMap<String, usize> = HashMap::new();
+    let mut trusted = Vec::new();
+    let mut returns: Vec<(String, String, ErrorSpan)> = Vec::new();
+    let mut topics = Subscribers::new();
+    let mut unknown_labels: Option<(String, ErrorSpan)> = None;
+    let mut wire_ids: Vec<(String, u32, ErrorSpan)> = Vec::new();
+    let mut roles_span = None;
 
     for pair in pairs {
         if pair.as_rule() == Rule::choreography {
             for inner in pair.into_inner() {
                 match inner.as_rule() {
+                    Rule::import_decl => {
+                        let span = ErrorSpan::from_pest_span(inner.as_span(), input);
+                        let mut import_inner = inner.into_inner();
+                        let path = import_inner.next().unwrap().as_str().trim_matches('"');
+                        let module = import_inner.next().unwrap().as_str();
+                        if !modules.insert(module) {
+                            return Err(ParseError::Syntax {
+                                span,
+                                message: format!("duplicate import '{}'", module),
+                            });
+                        }
+                        for (name, def) in resolver.import(path, config, span)? {
+                            protocol_defs.insert(format!("{}.{}", module, name), def);
+                        }
+                    }
                     Rule::annotation => {
                         // Parse annotation and add to attrs
+                        let span = inner.as_span();
                         let (key, value) = parse_annotation(inner)?;
+                        if key == WIRE && WireFormat::from_name(&value).is_none() {
+                            return Err(ParseError::Syntax {
+                                span: ErrorSpan::from_pest_span(span, input),
+                                message: format!(
+                                    "unknown wire format '{}', expected bincode or protobuf",
+                                    value
+                                ),
+                            });
+                        }
+                        if key == MAX_SIZE && parse_size(&value).is_none() {
+                            return Err(ParseError::Syntax {
+                                span: ErrorSpan::from_pest_span(span, input),
+                                message: format!(
+                                    "invalid message size '{}', expected bytes such as 4096 or 1MB",
+                                    value
+                                ),
+                            });
+                        }
+                        if key == UNKNOWN_LABELS {
+                            unknown_labels =
+                                Some((value.clone(), ErrorSpan::from_pest_span(span, input)));
+                        }
+                        if key == TRUSTED {
+                            if value != "true" {
+                                trusted.extend(value.split(',').map(|r| r.trim().to_string()));
+                            }
+                            continue;
+                        }
                         attrs.insert(key, value);
                     }
                     Rule::ident => {
                         name = format_ident!("{}", inner.as_str());
                     }
+                    Rule::const_decl => {
+                        let span = inner.as_span();
+                        let mut const_inner = inner.into_inner();
+                        let const_name = const_inner.next().unwrap().as_str();
+                        let const_type = const_inner.next().unwrap().as_str();
+                        let default = const_inner.next().map(|p| p.as_str());
+
+                        if const_type != "usize" {
+                            return Err(ParseError::Syntax {
+                                span: ErrorSpan::from_pest_span(span, input),
+                                message: format!(
+                                    "constant '{}' has type '{}', only usize is supported",
+                                    const_name, const_type
+                                ),
+                            });
+                        }
+                        if consts.contains_key(const_name) {
+                            return Err(ParseError::Syntax {
+                                span: ErrorSpan::from_pest_span(span, input),
+                                message: format!("duplicate constant '{}'", const_name),
+                            });
+                        }
+
+                        let value = config
+                            .const_value(const_name)
+                            .or_else(|| default.and_then(|d| d.parse().ok()))
+                            .ok_or_else(|| ParseError::UnresolvedConst {
+                                name: const_name.to_string(),
+                                span: ErrorSpan::from_pest_span(span, input),
+                            })?;
+                        consts.insert(const_name.to_string(), value);
+                    }
                     Rule::roles_decl => {
+                        roles_span = Some(ErrorSpan::from_pest_span(inner.as_span(), input));
                         for role_pair in inner.into_inner() {
                             if let Rule::role_list = role_pair.as_rule() {
                                 for role_decl in role_pair.into_inner() {
                                     if let Rule::role_decl = role_decl.as_rule() {
-                                        let mut inner_role = role_decl.into_inner();
+                                        let mut inner_role = role_decl.into_inner().peekable();
+                                        let mut role_annotations = Vec::new();
+                                        while let Some(annotation) = inner_role
+                                            .next_if(|pair| pair.as_rule() == Rule::annotation)
+                                        {
+                                            role_annotations.push(annotation);
+                                        }
                                         let role_ident = inner_role.next().unwrap();
+                                        for annotation in role_annotations {
+                                            let span = annotation.as_span();
+                                            let (key, _) = parse_annotation(annotation)?;
+                                            if key != TRUSTED {
+                                                return Err(ParseError::Syntax {
+                                                    span: ErrorSpan::from_pest_span(span, input),
+                                                    message: format!(
+                                                        "unknown role annotation '@{}', only @{} is supported",
+                                                        key, TRUSTED
+                                                    ),
+                                                });
+                                            }
+                                            trusted.push(role_ident.as_str().to_string());
+                                        }
                                         let role_name = role_ident.as_str();
                                         let span = role_ident.as_span();
 
@@ -228,7 +664,13 @@ pub fn parse_choreography_str(input: &str) -> std::result::Result<Choreography,
                                                     .trim_end_matches(']');
 
                                                 // Try to parse as integer for concrete index
-                                                if let Ok(idx) = param_str.parse::<usize>() {
+                                                if let Some(size) = consts.get(param_str) {
+                                                    Role::parameterized(
+                                                        format_ident!("{}", role_name),
+                                                        Literal::usize_unsuffixed(*size)
+                                                            .into_token_stream(),
+                                                    )
+                                                } else if let Ok(idx) = param_str.parse::<usize>() {
                                                     Role::indexed(
                                                         format_ident!("{}", role_name),
                                                         idx,
@@ -273,6 +715,110 @@ pub fn parse_choreography_str(input: &str) -> std::result::Result<Choreography,
                             }
                         }
                     }
+                    Rule::alias_decl => {
+                        let mut alias_inner = inner.into_inner();
+                        let alias_pair = alias_inner.next().unwrap();
+                        let target = alias_inner.next().unwrap().as_str();
+                        let alias = alias_pair.as_str();
+
+                        if declared_roles.contains(target) {
+                            if !declared_roles.insert(alias.to_string()) {
+                                return Err(ParseError::DuplicateRole {
+                                    role: alias.to_string(),
+                                    span: ErrorSpan::from_pest_span(alias_pair.as_span(), input),
+                                });
+                            }
+                            let target = aliases.roles.get(target).map_or(target, String::as_str);
+                            aliases.roles.insert(alias.to_string(), target.to_string());
+                        } else {
+                            let target =
+                                aliases.messages.get(target).map_or(target, String::as_str);
+                            aliases
+                                .messages
+                                .insert(alias.to_string(), target.to_string());
+                        }
+                    }
+                    Rule::returns_decl => {
+                        let span = ErrorSpan::from_pest_span(inner.as_span(), input);
+                        let mut returns_inner = inner.into_inner();
+                        let message = returns_inner.next().unwrap().as_str();
+                        let role_pair = returns_inner.next().unwrap();
+                        let role = aliases
+                            .roles
+                            .get(role_pair.as_str())
+                            .map_or(role_pair.as_str(), String::as_str);
+
+                        if !declared_roles.contains(role) {
+                            return Err(ParseError::UndefinedRole {
+                                role: role.to_string(),
+                                span: ErrorSpan::from_pest_span(role_pair.as_span(), input),
+                                fixits: Vec::new(),
+                            });
+                        }
+                        if let Some((_, earlier, _)) = returns.iter().find(|(r, _, _)| r == role) {
+                            return Err(ParseError::Syntax {
+                                span,
+                                message: format!("role '{}' already returns {}", role, earlier),
+                            });
+                        }
+                        let message = aliases
+                            .messages
+                            .get(message)
+                            .map_or(message, String::as_str);
+                        returns.push((role.to_string(), message.to_string(), span));
+                    }
+                    Rule::topic_decl => {
+                        let span = ErrorSpan::from_pest_span(inner.as_span(), input);
+                        let mut topic_inner = inner.into_inner();
+                        let topic = topic_inner.next().unwrap().as_str();
+                        if topics.contains_key(topic) {
+                            return Err(ParseError::Syntax {
+                                span,
+                                message: format!("topic '{}' is already declared", topic),
+                            });
+                        }
+                        let mut subscribers = BTreeSet::new();
+                        for role_pair in topic_inner {
+                            let role = aliases
+                                .roles
+                                .get(role_pair.as_str())
+                                .map_or(role_pair.as_str(), String::as_str);
+                            if !declared_roles.contains(role) {
+                                return Err(ParseError::UndefinedRole {
+                                    role: role.to_string(),
+                                    span: ErrorSpan::from_pest_span(role_pair.as_span(), input),
+                                    fixits: Vec::new(),
+                                });
+                            }
+                            subscribers.insert(role.to_string());
+                        }
+                        topics.insert(topic.to_string(), (subscribers, None));
+                    }
+                    Rule::id_decl => {
+                        let span = ErrorSpan::from_pest_span(inner.as_span(), input);
+                        let mut id_inner = inner.into_inner();
+                        let name = id_inner.next().unwrap().as_str();
+                        let id_pair = id_inner.next().unwrap();
+                        let id =
+                            id_pair
+                                .as_str()
+                                .parse::<u32>()
+                                .map_err(|_| ParseError::Syntax {
+                                    span: ErrorSpan::from_pest_span(id_pair.as_span(), input),
+                                    message: format!(
+                                        "wire id {} does not fit in 32 bits",
+                                        id_pair.as_str()
+                                    ),
+                                })?;
+                        let name = aliases.messages.get(name).map_or(name, String::as_str);
+                        if let Some((_, earlier, _)) = wire_ids.iter().find(|(n, _, _)| n == name) {
+                            return Err(ParseError::Syntax {
+                                span,
+                                message: format!("'{}' already has wire id {}", name, earlier),
+                            });
+                        }
+                        wire_ids.push((name.to_string(), id, span));
+                    }
                     Rule::protocol_defs => {
                         for protocol_def in inner.into_inner() {
                             if let Rule::protocol_def = protocol_def.as_rule() {
@@ -288,14 +834,35 @@ pub fn parse_choreography_str(input: &str) -> std::result::Result<Choreography,
                                     });
                                 }
 
-                                let body_pair = def_inner.next().unwrap();
-                                let body = parse_protocol_body(
-                                    body_pair,
-                                    &declared_roles,
-                                    input,
-                                    &protocol_defs,
-                                )?;
-                                protocol_defs.insert(proto_name.to_string(), body);
+                                let mut params = None;
+                                let mut body_pair = def_inner.next().unwrap();
+                                if body_pair.as_rule() == Rule::protocol_params {
+                                    let mut names = Vec::new();
+                                    for param in body_pair.into_inner() {
+                                        if names.iter().any(|name| name == param.as_str()) {
+                                            return Err(ParseError::DuplicateRole {
+                                                role: param.as_str().to_string(),
+                                                span: ErrorSpan::from_pest_span(
+                                                    param.as_span(),
+                                                    input,
+                                                ),
+                                            });
+                                        }
+                                        names.push(param.as_str().to_string());
+                                    }
+                                    params = Some(names);
+                                    body_pair = def_inner.next().unwrap();
+                                }
+
+                                // A definition over roles of its own sees only those
+                                let scope: HashSet<String> = match &params {
+                                    Some(names) => names.iter().cloned().collect(),
+                                    None => declared_roles.clone(),
+                                };
+                                let body =
+                                    parse_protocol_body(body_pair, &scope, input, &protocol_defs)?;
+                                protocol_defs
+                                    .insert(proto_name.to_string(), ProtocolDef { params, body });
                             }
                         }
                     }
@@ -303,6 +870,15 @@ pub fn parse_choreography_str(input: &str) -> std::result::Result<Choreography,
                         statements =
                             parse_protocol_body(inner, &declared_roles, input, &protocol_defs)?;
                     }
+                    Rule::finally_block => {
+                        let body_pair = inner.into_inner().next().unwrap();
+                        cleanup_statements = Some(parse_protocol_body(
+                            body_pair,
+                            &declared_roles,
+                            input,
+                            &protocol_defs,
+                        )?);
+                    }
                     Rule::EOI => {}
                     _ => {}
                 }
@@ -314,14 +890,220 @@ pub fn parse_choreography_str(input: &str) -> std::result::Result<Choreography,
         return Err(ParseError::EmptyChoreography);
     }
 
-    let protocol = convert_statements_to_protocol(&statements, &roles);
+    let unrolled = Cell::new(false);
+    let statements = expand_foreach(statements, &consts, &declared_roles, &unrolled);
+    let statements = aliases.resolve(resolve_config(statements, config, &consts), &declared_roles);
+    let cleanup_statements = cleanup_statements.map(|cleanup| {
+        let cleanup = expand_foreach(cleanup, &consts, &declared_roles, &unrolled);
+        aliases.resolve(resolve_config(cleanup, config, &consts), &declared_roles)
+    });
+    let mut publishers = HashMap::new();
+    topic_publishers(&statements, &mut publishers);
+    topic_publishers(cleanup_statements.iter().flatten(), &mut publishers);
+    let mut subscribers = topics.clone();
+    let statements = expand_topics(statements, &mut subscribers, None, &publishers, &roles)?;
+    let mut routes = Vec::new();
+    let statements = expand_routes(statements, &mut routes);
+    let statements = expand_aborts(statements, &Err(ABORT_OUTSIDE_CHOICE), &roles)?;
+    let statements = notify_peers(statements, &roles);
+    let statements = link_spawns(statements, &mut HashMap::new(), &roles)?;
+    let mut protocol = convert_statements_to_protocol(&statements, &roles);
+    let mut provenance = Provenance::new();
+    if let Some(cleanup) = cleanup_statements {
+        // The body may stop anywhere, so cleanup sees the declared subscribers
+        let cleanup = expand_topics(
+            cleanup,
+            &mut topics,
+            Some("a finally block"),
+            &publishers,
+            &roles,
+        )?;
+        let cleanup = expand_routes(cleanup, &mut routes);
+        let cleanup = expand_aborts(cleanup, &Err(ABORT_OUTSIDE_CHOICE), &roles)?;
+        let cleanup = notify_peers(cleanup, &roles);
+        let cleanup = link_spawns(cleanup, &mut HashMap::new(), &roles)?;
+        protocol = Protocol::Finally {
+            body: Box::new(protocol),
+            cleanup: Box::new(convert_statements_to_protocol(&cleanup, &roles)),
+        };
+        record_spans(&inline_calls(&statements), vec![0], &mut provenance);
+        record_spans(&inline_calls(&cleanup), vec![1], &mut provenance);
+    } else {
+        record_spans(&inline_calls(&statements), Vec::new(), &mut provenance);
+    }
 
-    Ok(Choreography {
+    if !returns.is_empty() {
+        for (role, message, span) in &returns {
+            if !receives(&protocol, role, message) {
+                return Err(ParseError::Syntax {
+                    span: span.clone(),
+                    message: format!(
+                        "role '{}' never receives {}, so it cannot return it",
+                        role, message
+                    ),
+                });
+            }
+        }
+    }
+
+    if !wire_ids.is_empty() {
+        check_wire_ids(&protocol, &wire_ids)?;
+    }
+
+    if let Some((policy, span)) = unknown_labels {
+        if policy != "reject" && policy != "skip" && !offers_label(&protocol, &policy) {
+            return Err(ParseError::Syntax {
+                span,
+                message: format!(
+                    "unknown label policy '{}', expected reject, skip, or the label of a branch",
+                    policy
+                ),
+            });
+        }
+    }
+
+    // Declarations name roles as written; use the declared role where
+    // there is one
+    let role_named = |name: &str| {
+        roles
+            .iter()
+            .find(|role| role.name == name)
+            .cloned()
+            .unwrap_or_else(|| Role::new(format_ident!("{}", name)))
+    };
+    let returns = returns
+        .iter()
+        .map(|(role, message, _)| Return {
+            role: role_named(role),
+            message: format_ident!("{}", message),
+        })
+        .collect();
+    let routes = routes
+        .iter()
+        .map(
+            |(from, message, candidates): &(Ident, Ident, Vec<Ident>)| Route {
+                from: role_named(&from.to_string()),
+                message: message.clone(),
+                candidates: candidates
+                    .iter()
+                    .map(|candidate| role_named(&candidate.to_string()))
+                    .collect(),
+            },
+        )
+        .collect();
+    let wire_ids = wire_ids
+        .iter()
+        .map(|(name, id, _)| (format_ident!("{}", name), *id))
+        .collect();
+    let trusted = trusted.iter().map(|name| role_named(name)).collect();
+
+    let mut choreography = Choreography {
         name,
         roles,
         protocol,
         attrs,
-    })
+        returns,
+        routes,
+        wire_ids,
+        trusted,
+    };
+    if unrolled.get() {
+        // An unrolled `foreach` names concrete members of its families, so
+        // they become declared roles as under `expand_roles`
+        choreography = expand_roles(&choreography, &consts).map_err(|e| ParseError::Syntax {
+            span: roles_span
+                .clone()
+                .expect("a choreography with roles declares them"),
+            message: e.to_string(),
+        })?;
+    }
+    Ok((choreography, provenance, protocol_defs))
+}
+
+/// Whether `role` receives `message` on some path through the protocol
+fn receives(protocol: &Protocol, role: &str, message: &str) -> bool {
+    let mut found = false;
+    walk_with_paths(protocol, &mut |_, node| match node {
+        Protocol::Send { to, message: m, .. } => {
+            found |= to.name == role && m.name == message;
+        }
+        Protocol::Broadcast {
+            to_all, message: m, ..
+        } => {
+            found |= m.name == message && to_all.iter().any(|to| to.name == role);
+        }
+        _ => {}
+    });
+    found
+}
+
+/// Check the `id Name = n` declarations against the protocol
+///
+/// Each must name a message or a branch label, no two messages and no two
+/// labels may share an id, and once anything has an id every message and
+/// label needs one, so none is left to travel by name or position.
+fn check_wire_ids(
+    protocol: &Protocol,
+    ids: &[(String, u32, ErrorSpan)],
+) -> std::result::Result<(), ParseError> {
+    let mut messages = BTreeSet::new();
+    let mut labels = BTreeSet::new();
+    walk_with_paths(protocol, &mut |_, node| match node {
+        Protocol::Send { message, .. } | Protocol::Broadcast { message, .. } => {
+            messages.insert(message.name.to_string());
+        }
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+            labels.extend(branches.iter().map(|branch| branch.label.to_string()));
+        }
+        _ => {}
+    });
+
+    for (i, (name, id, span)) in ids.iter().enumerate() {
+        let is_message = messages.contains(name);
+        let is_label = labels.contains(name);
+        if !is_message && !is_label {
+            return Err(ParseError::Syntax {
+                span: span.clone(),
+                message: format!("'{}' is neither a message nor a branch label", name),
+            });
+        }
+        let clash = ids[..i].iter().find(|(other, other_id, _)| {
+            other_id == id
+                && (is_message && messages.contains(other) || is_label && labels.contains(other))
+        });
+        if let Some((other, _, _)) = clash {
+            return Err(ParseError::Syntax {
+                span: span.clone(),
+                message: format!("wire id {} is already given to '{}'", id, other),
+            });
+        }
+    }
+
+    if let Some(missing) = messages
+        .iter()
+        .chain(&labels)
+        .find(|name| !ids.iter().any(|(n, _, _)| n == *name))
+    {
+        return Err(ParseError::Syntax {
+            span: ids[0].2.clone(),
+            message: format!(
+                "'{}' has no wire id; once one message or label has an id, all of them need one",
+                missing
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Whether some choice in the protocol has a branch labelled `label`
+fn offers_label(protocol: &Protocol, label: &str) -> bool {
+    let mut found = false;
+    walk_with_paths(protocol, &mut |_, node| {
+        if let Protocol::Choice { branches, .. } = node {
+            found |= branches.iter().any(|branch| branch.label == label);
+        }
+    });
+    found
 }
 
 /// Parse protocol body into statements
@@ -329,11 +1111,17 @@ fn parse_protocol_body(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Vec<Statement>, ParseError> {
-    let mut statements = Vec::new();
+    let mut statements: Vec<Statement> = Vec::new();
 
     for statement_pair in pair.into_inner() {
+        if let Some(Statement::Abort { span, .. }) = statements.last().map(unspanned) {
+            return Err(ParseError::Syntax {
+                span: span.clone(),
+                message: "Statements after an abort never run".to_string(),
+            });
+        }
         let statement = parse_statement(statement_pair, declared_roles, input, protocol_defs)?;
         statements.push(statement);
     }
@@ -346,7 +1134,7 @@ fn parse_statement(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
     // Handle annotated statements
     if let Rule::annotated_stmt = pair.as_rule() {
@@ -356,7 +1144,12 @@ fn parse_statement(
         while stmt_pair.as_rule() == Rule::annotation {
             stmt_pair = inner.next().unwrap();
         }
-        return parse_statement_inner(stmt_pair, declared_roles, input, protocol_defs);
+        let span = ErrorSpan::from_pest_span(stmt_pair.as_span(), input);
+        let statement = parse_statement_inner(stmt_pair, declared_roles, input, protocol_defs)?;
+        return Ok(Statement::Spanned {
+            span,
+            statement: Box::new(statement),
+        });
     }
 
     parse_statement_inner(pair, declared_roles, input, protocol_defs)
@@ -367,16 +1160,33 @@ fn parse_statement_inner(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
     match pair.as_rule() {
         Rule::send_stmt => parse_send_stmt(pair, declared_roles, input),
         Rule::broadcast_stmt => parse_broadcast_stmt(pair, declared_roles, input),
         Rule::choice_stmt => parse_choice_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::if_stmt => parse_if_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::abort_stmt => parse_abort_stmt(pair, declared_roles, input),
+        Rule::publish_stmt => parse_publish_stmt(pair, declared_roles, input),
+        Rule::route_stmt => parse_route_stmt(pair, declared_roles, input),
+        Rule::subscribe_stmt => parse_subscription_stmt(pair, true, declared_roles, input),
+        Rule::unsubscribe_stmt => parse_subscription_stmt(pair, false, declared_roles, input),
         Rule::loop_stmt => parse_loop_stmt(pair, declared_roles, input, protocol_defs),
         Rule::parallel_stmt => parse_parallel_stmt(pair, declared_roles, input, protocol_defs),
         Rule::rec_stmt => parse_rec_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::try_stmt => parse_try_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::timeout_stmt => parse_timeout_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::race_stmt => parse_race_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::foreach_stmt => parse_foreach_stmt(pair, declared_roles, input, protocol_defs),
         Rule::call_stmt => parse_call_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::spawn_stmt => parse_spawn_stmt(pair, declared_roles, input, protocol_defs),
+        Rule::await_stmt => Ok(Statement::Await {
+            span: ErrorSpan::from_pest_span(pair.as_span(), input),
+            handle: format_ident!("{}", pair.into_inner().next().unwrap().as_str()),
+            roles: Vec::new(),
+        }),
+        Rule::cfg_block => parse_cfg_block(pair, declared_roles, input, protocol_defs),
         _ => {
             let span = pair.as_span();
             Err(ParseError::Syntax {
@@ -404,6 +1214,7 @@ fn parse_role_ref(
         return Err(ParseError::UndefinedRole {
             role: role_name.to_string(),
             span: ErrorSpan::from_pest_span(span, input),
+            fixits: Vec::new(),
         });
     }
 
@@ -444,7 +1255,8 @@ fn parse_send_stmt(
     Ok(Statement::Send { from, to, message })
 }
 
-/// Parse broadcast statement: A ->* : Message(payload)
+/// Parse broadcast statement: A ->* : Message(payload), optionally with
+/// roles left out: A ->* \ {B, C}: Message
 fn parse_broadcast_stmt(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
@@ -455,9 +1267,30 @@ fn parse_broadcast_stmt(
     let from_pair = inner.next().unwrap();
     let from = parse_role_ref(from_pair, declared_roles, input)?;
 
-    let message = parse_message(inner.next().unwrap(), input)?;
+    let mut except = Vec::new();
+    let mut next = inner.next().unwrap();
+    if next.as_rule() == Rule::broadcast_except {
+        for role_pair in next.into_inner() {
+            if !declared_roles.contains(role_pair.as_str()) {
+                return Err(ParseError::UndefinedRole {
+                    role: role_pair.as_str().to_string(),
+                    span: ErrorSpan::from_pest_span(role_pair.as_span(), input),
+                    fixits: Vec::new(),
+                });
+            }
+            except.push(format_ident!("{}", role_pair.as_str()));
+        }
+        next = inner.next().unwrap();
+    }
 
-    Ok(Statement::Broadcast { from, message })
+    let message = parse_message(next, input)?;
+
+    Ok(Statement::Broadcast {
+        from,
+        except,
+        message,
+        abort: false,
+    })
 }
 
 /// Parse choice statement
@@ -465,7 +1298,7 @@ fn parse_choice_stmt(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
     let mut inner = pair.into_inner();
 
@@ -476,6 +1309,7 @@ fn parse_choice_stmt(
         return Err(ParseError::UndefinedRole {
             role: role_str.to_string(),
             span: ErrorSpan::from_pest_span(role_span, input),
+            fixits: Vec::new(),
         });
     }
     let role = format_ident!("{}", role_str);
@@ -483,7 +1317,25 @@ fn parse_choice_stmt(
     let mut branches = Vec::new();
     for branch_pair in inner {
         if let Rule::choice_branch = branch_pair.as_rule() {
-            let mut branch_inner = branch_pair.into_inner();
+            let mut branch_inner = branch_pair.into_inner().peekable();
+
+            let mut weight = None;
+            while let Some(annotation) =
+                branch_inner.next_if(|pair| pair.as_rule() == Rule::annotation)
+            {
+                let span = ErrorSpan::from_pest_span(annotation.as_span(), input);
+                let (key, value) = parse_annotation(annotation)?;
+                if key == "weight" {
+                    weight = Some(parse_weight(&value).ok_or_else(|| ParseError::Syntax {
+                        span,
+                        message: format!(
+                            "Invalid branch weight '{}': expected a non-negative number",
+                            value
+                        ),
+                    })?);
+                }
+            }
+
             let label = format_ident!("{}", branch_inner.next().unwrap().as_str());
 
             // Check for optional guard
@@ -494,12 +1346,15 @@ fn parse_choice_stmt(
                 let guard_span = next_item.as_span();
                 let mut guard_inner = next_item.into_inner();
                 let guard_expr = guard_inner.next().unwrap().as_str();
-                guard = Some(syn::parse_str::<TokenStream>(guard_expr).map_err(|e| {
-                    ParseError::Syntax {
-                        span: ErrorSpan::from_pest_span(guard_span, input),
-                        message: format!("Invalid guard expression: {}", e),
-                    }
-                })?);
+                let invalid = |message: String| ParseError::Syntax {
+                    span: ErrorSpan::from_pest_span(guard_span, input),
+                    message: format!("Invalid guard expression: {}", message),
+                };
+                let tokens = syn::parse_str::<TokenStream>(guard_expr)
+                    .map_err(|e| invalid(e.to_string()))?;
+                // Checked here so that generated programs can parse it again
+                Guard::parse(&tokens.to_string()).map_err(|e| invalid(e.to_string()))?;
+                guard = Some(tokens);
                 // Body comes after guard
                 parse_protocol_body(
                     branch_inner.next().unwrap(),
@@ -515,6 +1370,8 @@ fn parse_choice_stmt(
             branches.push(ChoiceBranch {
                 label,
                 guard,
+                weight,
+                notify_peers: false,
                 statements: body,
             });
         }
@@ -523,12 +1380,187 @@ fn parse_choice_stmt(
     Ok(Statement::Choice { role, branches })
 }
 
+/// Parse `if (cond) at A { ... } else { ... }` into a choice by `A` between
+/// `Then` and `Else`, guarded by the condition and its negation
+fn parse_if_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let condition_pair = inner.next().unwrap();
+    let condition =
+        syn::parse_str::<TokenStream>(condition_pair.as_str()).map_err(|e| ParseError::Syntax {
+            span: ErrorSpan::from_pest_span(condition_pair.as_span(), input),
+            message: format!("Invalid condition: {}", e),
+        })?;
+    Guard::parse(&condition.to_string()).map_err(|e| ParseError::Syntax {
+        span: ErrorSpan::from_pest_span(condition_pair.as_span(), input),
+        message: format!("Invalid condition: {}", e),
+    })?;
+
+    let role_pair = inner.next().unwrap();
+    if !declared_roles.contains(role_pair.as_str()) {
+        return Err(ParseError::UndefinedRole {
+            role: role_pair.as_str().to_string(),
+            span: ErrorSpan::from_pest_span(role_pair.as_span(), input),
+            fixits: Vec::new(),
+        });
+    }
+    let role = format_ident!("{}", role_pair.as_str());
+
+    let then_body =
+        parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+    let else_body = match inner.next() {
+        Some(body) => parse_protocol_body(body, declared_roles, input, protocol_defs)?,
+        None => Vec::new(),
+    };
+
+    let negated = quote::quote! { !(#condition) };
+    let branch = |label: &str, guard: TokenStream, statements| ChoiceBranch {
+        label: format_ident!("{}", label),
+        guard: Some(guard),
+        weight: None,
+        notify_peers: true,
+        statements,
+    };
+    Ok(Statement::Choice {
+        role,
+        branches: vec![
+            branch("Then", condition, then_body),
+            branch("Else", negated, else_body),
+        ],
+    })
+}
+
+/// Parse abort statement: abort at A: Reason
+///
+/// Nothing may follow an abort in its block. Who is told is worked out
+/// later by `expand_aborts`.
+fn parse_abort_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+) -> std::result::Result<Statement, ParseError> {
+    let span = ErrorSpan::from_pest_span(pair.as_span(), input);
+    let mut inner = pair.into_inner();
+
+    let role = parse_role_ref(inner.next().unwrap(), declared_roles, input)?;
+    let message = parse_message(inner.next().unwrap(), input)?;
+
+    Ok(Statement::Abort {
+        role,
+        message,
+        span,
+    })
+}
+
+/// Parse publish statement: A -> topic(prices): Update
+fn parse_publish_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+) -> std::result::Result<Statement, ParseError> {
+    let span = ErrorSpan::from_pest_span(pair.as_span(), input);
+    let mut inner = pair.into_inner();
+
+    let from = parse_role_ref(inner.next().unwrap(), declared_roles, input)?;
+    let topic = format_ident!("{}", inner.next().unwrap().as_str());
+    let message = parse_message(inner.next().unwrap(), input)?;
+
+    Ok(Statement::Publish {
+        from,
+        topic,
+        message,
+        span,
+    })
+}
+
+/// Parse `A -> route(B, C): Message`
+fn parse_route_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let from = parse_role_ref(inner.next().unwrap(), declared_roles, input)?;
+    let mut candidates = Vec::new();
+    let mut message = None;
+    for item in inner {
+        if item.as_rule() == Rule::message {
+            message = Some(parse_message(item, input)?);
+            continue;
+        }
+        let span = ErrorSpan::from_pest_span(item.as_span(), input);
+        let candidate = parse_role_ref(item, declared_roles, input)?;
+        if candidate == from {
+            return Err(ParseError::Syntax {
+                span,
+                message: format!("role '{}' cannot route a message to itself", from),
+            });
+        }
+        if candidates.contains(&candidate) {
+            return Err(ParseError::Syntax {
+                span,
+                message: format!("role '{}' is already a candidate", candidate),
+            });
+        }
+        candidates.push(candidate);
+    }
+
+    Ok(Statement::Route {
+        from,
+        candidates,
+        message: message.unwrap(),
+    })
+}
+
+/// Parse `subscribe B to prices`, or `unsubscribe B from prices` if
+/// `subscribed` is false
+fn parse_subscription_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    subscribed: bool,
+    declared_roles: &HashSet<String>,
+    input: &str,
+) -> std::result::Result<Statement, ParseError> {
+    let span = ErrorSpan::from_pest_span(pair.as_span(), input);
+    let mut inner = pair.into_inner();
+
+    let role_pair = inner.next().unwrap();
+    if !declared_roles.contains(role_pair.as_str()) {
+        return Err(ParseError::UndefinedRole {
+            role: role_pair.as_str().to_string(),
+            span: ErrorSpan::from_pest_span(role_pair.as_span(), input),
+            fixits: Vec::new(),
+        });
+    }
+    let topic = format_ident!("{}", inner.next().unwrap().as_str());
+
+    Ok(Statement::Subscription {
+        role: format_ident!("{}", role_pair.as_str()),
+        topic,
+        subscribed,
+        span,
+    })
+}
+
+/// Parse the argument of `@weight(...)`
+fn parse_weight(value: &str) -> Option<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|weight| weight.is_finite() && *weight >= 0.0)
+}
+
 /// Parse loop statement
 fn parse_loop_stmt(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
     let inner = pair.into_inner();
 
@@ -566,6 +1598,7 @@ fn parse_loop_stmt(
                     return Err(ParseError::UndefinedRole {
                         role: role_str.to_string(),
                         span: ErrorSpan::from_pest_span(role_span, input),
+                        fixits: Vec::new(),
                     });
                 }
                 condition = Some(Condition::RoleDecides(Role::new(format_ident!(
@@ -602,7 +1635,7 @@ fn parse_parallel_stmt(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
     let mut branches = Vec::new();
 
@@ -621,53 +1654,364 @@ fn parse_parallel_stmt(
     Ok(Statement::Parallel { branches })
 }
 
-/// Parse recursive statement
-fn parse_rec_stmt(
+/// Parse race statement: race { A -> B: X ... | B -> A: Y ... }
+///
+/// Every arm must start with a send, and the first messages of the arms
+/// must differ since they name the arms.
+fn parse_race_stmt(
     pair: pest::iterators::Pair<Rule>,
     declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
-    let mut inner = pair.into_inner();
+    let mut arms = Vec::new();
+    let mut labels = HashSet::new();
 
-    let label = format_ident!("{}", inner.next().unwrap().as_str());
-    let body = parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
-
-    Ok(Statement::Rec { label, body })
+    for arm_pair in pair.into_inner() {
+        let span = ErrorSpan::from_pest_span(arm_pair.as_span(), input);
+        let body_pair = arm_pair.into_inner().next().unwrap();
+        let body = parse_protocol_body(body_pair, declared_roles, input, protocol_defs)?;
+        let label = match body.first().map(unspanned) {
+            Some(Statement::Send { message, .. }) => message.name.to_string(),
+            _ => {
+                return Err(ParseError::Syntax {
+                    span,
+                    message: "Race arm must start with a send".to_string(),
+                })
+            }
+        };
+        if !labels.insert(label.clone()) {
+            return Err(ParseError::Syntax {
+                span,
+                message: format!(
+                    "Race arms must start with different messages, {} repeats",
+                    label
+                ),
+            });
+        }
+        arms.push(body);
+    }
+
+    Ok(Statement::Race { arms })
+}
+
+/// Parse recursive statement
+fn parse_rec_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let label = format_ident!("{}", inner.next().unwrap().as_str());
+    let body = parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+
+    Ok(Statement::Rec { label, body })
+}
+
+/// Parse `try { ... } catch { ... }`
+fn parse_try_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let body = parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+    let compensation =
+        parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+
+    Ok(Statement::TryCatch { body, compensation })
+}
+
+/// Parse `timeout(500ms) at A { ... } else { ... }`
+///
+/// Durations are whole milliseconds (`ms`), seconds (`s`), or minutes
+/// (`m`). A missing else body leaves nothing to do once the time runs out.
+fn parse_timeout_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let duration_pair = inner.next().unwrap();
+    let text = duration_pair.as_str();
+    let digits = text.trim_end_matches(char::is_alphabetic);
+    let duration = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| match &text[digits.len()..] {
+            "ms" => Some(std::time::Duration::from_millis(amount)),
+            "s" => Some(std::time::Duration::from_secs(amount)),
+            "m" => amount.checked_mul(60).map(std::time::Duration::from_secs),
+            _ => None,
+        })
+        .ok_or_else(|| ParseError::Syntax {
+            span: ErrorSpan::from_pest_span(duration_pair.as_span(), input),
+            message: format!("Invalid duration: {}", text),
+        })?;
+
+    let role_pair = inner.next().unwrap();
+    if !declared_roles.contains(role_pair.as_str()) {
+        return Err(ParseError::UndefinedRole {
+            role: role_pair.as_str().to_string(),
+            span: ErrorSpan::from_pest_span(role_pair.as_span(), input),
+            fixits: Vec::new(),
+        });
+    }
+    let role = format_ident!("{}", role_pair.as_str());
+
+    let body = parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+    let on_timeout = match inner.next() {
+        Some(body) => parse_protocol_body(body, declared_roles, input, protocol_defs)?,
+        None => Vec::new(),
+    };
+
+    Ok(Statement::Timeout {
+        role,
+        duration,
+        body,
+        on_timeout,
+    })
+}
+
+/// Parse `foreach i in 0..N { ... }`
+fn parse_foreach_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let var = format_ident!("{}", inner.next().unwrap().as_str());
+    let start = inner.next().unwrap().as_str().to_string();
+    let end = inner.next().unwrap().as_str().to_string();
+    let body = parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+
+    Ok(Statement::ForEach {
+        var,
+        start,
+        end,
+        body,
+    })
+}
+
+/// Parse conditional block
+///
+/// The body is checked like any other statement even when the condition
+/// ends up false, so a variant that is rarely built cannot rot unnoticed.
+fn parse_cfg_block(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let predicate = parse_cfg_predicate(inner.next().unwrap());
+    let body = parse_protocol_body(inner.next().unwrap(), declared_roles, input, protocol_defs)?;
+
+    Ok(Statement::Cfg { predicate, body })
+}
+
+fn parse_cfg_predicate(pair: pest::iterators::Pair<Rule>) -> CfgPredicate {
+    match pair.as_rule() {
+        Rule::cfg_not => CfgPredicate::Not(Box::new(parse_cfg_predicate(
+            pair.into_inner().next().unwrap(),
+        ))),
+        Rule::cfg_all => CfgPredicate::All(pair.into_inner().map(parse_cfg_predicate).collect()),
+        Rule::cfg_any => CfgPredicate::Any(pair.into_inner().map(parse_cfg_predicate).collect()),
+        Rule::cfg_key_value => {
+            let mut inner = pair.into_inner();
+            let key = inner.next().unwrap().as_str().to_string();
+            let value = inner.next().unwrap().as_str().trim_matches('"').to_string();
+            CfgPredicate::KeyValue(key, value)
+        }
+        _ => CfgPredicate::Flag(pair.as_str().to_string()),
+    }
 }
 
 /// Parse protocol call statement
 fn parse_call_stmt(
     pair: pest::iterators::Pair<Rule>,
-    _declared_roles: &HashSet<String>,
+    declared_roles: &HashSet<String>,
     input: &str,
-    protocol_defs: &HashMap<String, Vec<Statement>>,
+    protocol_defs: &ProtocolDefs,
 ) -> std::result::Result<Statement, ParseError> {
-    let mut inner = pair.into_inner();
+    let mut inner = pair.into_inner().peekable();
     let proto_name_pair = inner.next().unwrap();
-    let proto_name = proto_name_pair.as_str();
-    let span = proto_name_pair.as_span();
+    let mut name = format_ident!("{}", proto_name_pair.as_str());
+    let mut proto_name = proto_name_pair.as_str().to_string();
+    let mut span = proto_name_pair.as_span();
+
+    // `Module.Protocol` names a protocol of an imported file
+    if let Some(member) = inner.next_if(|pair| pair.as_rule() == Rule::ident) {
+        name = format_ident!("{}_{}", proto_name, member.as_str());
+        proto_name = format!("{}.{}", proto_name, member.as_str());
+        span = span.start_pos().span(&member.as_span().end_pos());
+    }
 
     // Look up the protocol definition
-    let proto_statements =
-        protocol_defs
-            .get(proto_name)
-            .ok_or_else(|| ParseError::UndefinedProtocol {
-                protocol: proto_name.to_string(),
+    let def = protocol_defs
+        .get(&proto_name)
+        .ok_or_else(|| ParseError::UndefinedProtocol {
+            protocol: proto_name.clone(),
+            span: ErrorSpan::from_pest_span(span, input),
+        })?;
+
+    // `with { Coordinator = Leader }` or `(Coordinator = Leader)` plays the
+    // protocol's roles with the caller's, like an alias scoped to the call
+    let mut mapping = Aliases::default();
+    if let Some(bindings) = inner.next() {
+        for binding in bindings.into_inner() {
+            let binding_span = ErrorSpan::from_pest_span(binding.as_span(), input);
+            let mut binding = binding.into_inner();
+            let role = binding.next().unwrap();
+            let target = binding.next().unwrap();
+            if !declared_roles.contains(target.as_str()) {
+                return Err(ParseError::UndefinedRole {
+                    role: target.as_str().to_string(),
+                    span: ErrorSpan::from_pest_span(target.as_span(), input),
+                    fixits: Vec::new(),
+                });
+            }
+            let error = |message: String| ParseError::Syntax {
+                span: binding_span.clone(),
+                message,
+            };
+            if let Some(params) = &def.params {
+                if !params.iter().any(|param| param == role.as_str()) {
+                    return Err(error(format!(
+                        "{} has no role {}, its roles are {}",
+                        proto_name,
+                        role.as_str(),
+                        params.join(", ")
+                    )));
+                }
+                if let Some((other, _)) = mapping
+                    .roles
+                    .iter()
+                    .find(|(_, bound)| *bound == target.as_str())
+                {
+                    return Err(error(format!(
+                        "roles {} and {} of {} are both bound to {}",
+                        other,
+                        role.as_str(),
+                        proto_name,
+                        target.as_str()
+                    )));
+                }
+            }
+            if mapping
+                .roles
+                .insert(role.as_str().to_string(), target.as_str().to_string())
+                .is_some()
+            {
+                return Err(error(format!(
+                    "role {} of {} is bound more than once",
+                    role.as_str(),
+                    proto_name
+                )));
+            }
+        }
+    }
+
+    if let Some(params) = &def.params {
+        let unbound: Vec<&str> = params
+            .iter()
+            .filter(|param| !mapping.roles.contains_key(*param))
+            .map(String::as_str)
+            .collect();
+        if !unbound.is_empty() {
+            return Err(ParseError::Syntax {
                 span: ErrorSpan::from_pest_span(span, input),
-            })?;
+                message: format!(
+                    "call to {} does not bind {}",
+                    proto_name,
+                    unbound.join(", ")
+                ),
+            });
+        }
+    }
 
     // Return a Call statement that will be inlined later
     Ok(Statement::Call {
+        name,
+        statements: mapping.resolve(def.body.clone(), declared_roles),
+    })
+}
+
+/// Parse spawn statement: spawn Name(A, B) as handle
+fn parse_spawn_stmt(
+    pair: pest::iterators::Pair<Rule>,
+    declared_roles: &HashSet<String>,
+    input: &str,
+    protocol_defs: &ProtocolDefs,
+) -> std::result::Result<Statement, ParseError> {
+    let span = ErrorSpan::from_pest_span(pair.as_span(), input);
+    let mut inner = pair.into_inner().collect::<Vec<_>>();
+    let handle = format_ident!("{}", inner.pop().unwrap().as_str());
+    let mut inner = inner.into_iter();
+
+    let proto_name_pair = inner.next().unwrap();
+    let proto_name = proto_name_pair.as_str();
+    let def = protocol_defs
+        .get(proto_name)
+        .ok_or_else(|| ParseError::UndefinedProtocol {
+            protocol: proto_name.to_string(),
+            span: ErrorSpan::from_pest_span(proto_name_pair.as_span(), input),
+        })?;
+
+    let mut roles = Vec::new();
+    for role_pair in inner {
+        let role = parse_role_ref(role_pair, declared_roles, input)?;
+        if roles.contains(&role) {
+            return Err(ParseError::Syntax {
+                span,
+                message: format!("Role {} is passed to spawn more than once", role),
+            });
+        }
+        roles.push(role);
+    }
+
+    // A protocol over roles of its own plays them with the listed roles, in order
+    let mut mapping = Aliases::default();
+    if let Some(params) = &def.params {
+        if params.len() != roles.len() {
+            return Err(ParseError::Syntax {
+                span,
+                message: format!(
+                    "{} takes {} roles ({}), but {} are passed",
+                    proto_name,
+                    params.len(),
+                    params.join(", "),
+                    roles.len()
+                ),
+            });
+        }
+        for (param, role) in params.iter().zip(&roles) {
+            mapping.roles.insert(param.clone(), role.to_string());
+        }
+    }
+
+    Ok(Statement::Spawn {
+        handle,
         name: format_ident!("{}", proto_name),
-        statements: proto_statements.clone(),
+        roles,
+        body: mapping.resolve(def.body.clone(), declared_roles),
+        span,
     })
 }
 
 /// Parse message specification
 fn parse_message(
     pair: pest::iterators::Pair<Rule>,
-    _input: &str,
+    input: &str,
 ) -> std::result::Result<MessageSpec, ParseError> {
     let _span = pair.as_span();
     let mut inner = pair.into_inner();
@@ -691,145 +2035,1947 @@ fn parse_message(
                 let payload_str = part.as_str();
                 let payload_str = payload_str.trim_matches('(').trim_matches(')');
                 payload = syn::parse_str::<TokenStream>(payload_str).ok();
+                if let Some(tokens) = &payload {
+                    if has_tags(tokens) && payload_fields(tokens).is_none() {
+                        return Err(ParseError::InvalidMessage {
+                            message: format!(
+                                "tags such as @{} are only allowed on named fields, e.g. `{}(card: String @{})`",
+                                SENSITIVE, name, SENSITIVE
+                            ),
+                            span: ErrorSpan::from_pest_span(part.as_span(), input),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(MessageSpec {
+        name,
+        type_annotation,
+        payload,
+    })
+}
+
+/// Choreography statement types
+#[derive(Debug, Clone)]
+enum Statement {
+    Send {
+        from: Ident,
+        to: Ident,
+        message: MessageSpec,
+    },
+    Broadcast {
+        from: Ident,
+        /// Roles left out of the broadcast
+        except: Vec<Ident>,
+        message: MessageSpec,
+        /// Whether an abort expanded to this broadcast
+        abort: bool,
+    },
+    Choice {
+        role: Ident,
+        branches: Vec<ChoiceBranch>,
+    },
+    Loop {
+        condition: Option<Condition>,
+        body: Vec<Statement>,
+    },
+    Parallel {
+        branches: Vec<Vec<Statement>>,
+    },
+    /// Arms in priority order, each starting with a send
+    Race {
+        arms: Vec<Vec<Statement>>,
+    },
+    Rec {
+        label: Ident,
+        body: Vec<Statement>,
+    },
+    /// `try { body } catch { compensation }`
+    TryCatch {
+        body: Vec<Statement>,
+        compensation: Vec<Statement>,
+    },
+    /// `timeout(duration) at role { body } else { on_timeout }`
+    Timeout {
+        role: Ident,
+        duration: std::time::Duration,
+        body: Vec<Statement>,
+        on_timeout: Vec<Statement>,
+    },
+    Call {
+        #[allow(dead_code)]
+        name: Ident,
+        statements: Vec<Statement>,
+    },
+    Cfg {
+        predicate: CfgPredicate,
+        body: Vec<Statement>,
+    },
+    /// `foreach var in start..end`; bounds are integers or constant names
+    ForEach {
+        var: Ident,
+        start: String,
+        end: String,
+        body: Vec<Statement>,
+    },
+    /// Early exit by `role`; becomes a broadcast of `message` to the roles
+    /// still waiting, see `expand_aborts`
+    Abort {
+        role: Ident,
+        message: MessageSpec,
+        span: ErrorSpan,
+    },
+    /// `from -> topic(name): Message`; becomes a broadcast to the roles
+    /// subscribed at that point, see `expand_topics`
+    Publish {
+        from: Ident,
+        topic: Ident,
+        message: MessageSpec,
+        span: ErrorSpan,
+    },
+    /// `from -> route(candidates): Message`; becomes a choice by `from`
+    /// with a branch per candidate, see `expand_routes`
+    Route {
+        from: Ident,
+        candidates: Vec<Ident>,
+        message: MessageSpec,
+    },
+    /// `subscribe role to topic`, or `unsubscribe role from topic` if
+    /// `subscribed` is false; becomes a message to each of the topic's
+    /// publishers, see `expand_topics`
+    Subscription {
+        role: Ident,
+        topic: Ident,
+        subscribed: bool,
+        span: ErrorSpan,
+    },
+    /// `spawn Name(A, B) as handle`: `body` runs as a child session
+    /// between `roles`
+    Spawn {
+        handle: Ident,
+        name: Ident,
+        roles: Vec<Ident>,
+        body: Vec<Statement>,
+        span: ErrorSpan,
+    },
+    /// `await handle`; `roles` are filled in from the spawn by `link_spawns`
+    Await {
+        handle: Ident,
+        roles: Vec<Ident>,
+        span: ErrorSpan,
+    },
+    /// A statement together with where it was written
+    Spanned {
+        span: ErrorSpan,
+        statement: Box<Statement>,
+    },
+}
+
+/// Choice branch in choreography
+#[derive(Debug, Clone)]
+struct ChoiceBranch {
+    label: Ident,
+    guard: Option<TokenStream>,
+    weight: Option<f64>,
+    /// From `if`/`else`: peers are told the label where they need it
+    notify_peers: bool,
+    statements: Vec<Statement>,
+}
+
+/// Role and message aliases declared with `alias Name = Target`
+#[derive(Debug, Default)]
+struct Aliases {
+    roles: HashMap<String, String>,
+    messages: HashMap<String, String>,
+}
+
+impl Aliases {
+    /// Replace every alias in the statements with its target
+    fn resolve(
+        &self,
+        statements: Vec<Statement>,
+        declared_roles: &HashSet<String>,
+    ) -> Vec<Statement> {
+        if self.roles.is_empty() && self.messages.is_empty() {
+            return statements;
+        }
+        statements
+            .into_iter()
+            .map(|statement| self.resolve_statement(statement, declared_roles))
+            .collect()
+    }
+
+    fn resolve_statement(
+        &self,
+        statement: Statement,
+        declared_roles: &HashSet<String>,
+    ) -> Statement {
+        match statement {
+            Statement::Send { from, to, message } => Statement::Send {
+                from: self.role(&from, declared_roles),
+                to: self.role(&to, declared_roles),
+                message: self.message(message),
+            },
+            Statement::Broadcast {
+                from,
+                except,
+                message,
+                abort,
+            } => Statement::Broadcast {
+                from: self.role(&from, declared_roles),
+                except: except
+                    .iter()
+                    .map(|role| self.role(role, declared_roles))
+                    .collect(),
+                message: self.message(message),
+                abort,
+            },
+            Statement::Choice { role, branches } => Statement::Choice {
+                role: self.role(&role, declared_roles),
+                branches: branches
+                    .into_iter()
+                    .map(|branch| ChoiceBranch {
+                        statements: self.resolve(branch.statements, declared_roles),
+                        ..branch
+                    })
+                    .collect(),
+            },
+            Statement::Loop { condition, body } => Statement::Loop {
+                condition: match condition {
+                    Some(Condition::RoleDecides(role)) => Some(Condition::RoleDecides(Role::new(
+                        self.role(&role.name, declared_roles),
+                    ))),
+                    other => other,
+                },
+                body: self.resolve(body, declared_roles),
+            },
+            Statement::Parallel { branches } => Statement::Parallel {
+                branches: branches
+                    .into_iter()
+                    .map(|branch| self.resolve(branch, declared_roles))
+                    .collect(),
+            },
+            Statement::Race { arms } => Statement::Race {
+                arms: arms
+                    .into_iter()
+                    .map(|arm| self.resolve(arm, declared_roles))
+                    .collect(),
+            },
+            Statement::Rec { label, body } => Statement::Rec {
+                label,
+                body: self.resolve(body, declared_roles),
+            },
+            Statement::TryCatch { body, compensation } => Statement::TryCatch {
+                body: self.resolve(body, declared_roles),
+                compensation: self.resolve(compensation, declared_roles),
+            },
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => Statement::Timeout {
+                role: self.role(&role, declared_roles),
+                duration,
+                body: self.resolve(body, declared_roles),
+                on_timeout: self.resolve(on_timeout, declared_roles),
+            },
+            Statement::Call { name, statements } => Statement::Call {
+                name,
+                statements: self.resolve(statements, declared_roles),
+            },
+            Statement::Cfg { predicate, body } => Statement::Cfg {
+                predicate,
+                body: self.resolve(body, declared_roles),
+            },
+            Statement::ForEach {
+                var,
+                start,
+                end,
+                body,
+            } => Statement::ForEach {
+                var,
+                start,
+                end,
+                body: self.resolve(body, declared_roles),
+            },
+            Statement::Abort {
+                role,
+                message,
+                span,
+            } => Statement::Abort {
+                role: self.role(&role, declared_roles),
+                message: self.message(message),
+                span,
+            },
+            Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                span,
+            } => Statement::Spawn {
+                handle,
+                name,
+                roles: roles
+                    .iter()
+                    .map(|role| self.role(role, declared_roles))
+                    .collect(),
+                body: self.resolve(body, declared_roles),
+                span,
+            },
+            Statement::Publish {
+                from,
+                topic,
+                message,
+                span,
+            } => Statement::Publish {
+                from: self.role(&from, declared_roles),
+                topic,
+                message: self.message(message),
+                span,
+            },
+            Statement::Route {
+                from,
+                candidates,
+                message,
+            } => Statement::Route {
+                from: self.role(&from, declared_roles),
+                candidates: candidates
+                    .iter()
+                    .map(|role| self.role(role, declared_roles))
+                    .collect(),
+                message: self.message(message),
+            },
+            Statement::Subscription {
+                role,
+                topic,
+                subscribed,
+                span,
+            } => Statement::Subscription {
+                role: self.role(&role, declared_roles),
+                topic,
+                subscribed,
+                span,
+            },
+            // Roles are filled in later from the spawn
+            await_stmt @ Statement::Await { .. } => await_stmt,
+            Statement::Spanned { span, statement } => Statement::Spanned {
+                span,
+                statement: Box::new(self.resolve_statement(*statement, declared_roles)),
+            },
+        }
+    }
+
+    fn role(&self, ident: &Ident, declared_roles: &HashSet<String>) -> Ident {
+        let name = ident.to_string();
+        if let Some(target) = self.roles.get(&name) {
+            return format_ident!("{}", target);
+        }
+
+        // Indexed references are flattened to `Role_index` by parse_role_ref
+        if !declared_roles.contains(&name) {
+            for (alias, target) in &self.roles {
+                if let Some(index) = name.strip_prefix(&format!("{}_", alias)) {
+                    return format_ident!("{}_{}", target, index);
+                }
+            }
+        }
+
+        ident.clone()
+    }
+
+    fn message(&self, message: MessageSpec) -> MessageSpec {
+        match self.messages.get(&message.name.to_string()) {
+            Some(target) => MessageSpec {
+                name: format_ident!("{}", target),
+                ..message
+            },
+            None => message,
+        }
+    }
+}
+
+/// Message specification with optional payload
+#[derive(Debug, Clone)]
+struct MessageSpec {
+    name: Ident,
+    type_annotation: Option<TokenStream>,
+    payload: Option<TokenStream>,
+}
+
+/// Convert statements to protocol AST
+fn convert_statements_to_protocol(statements: &[Statement], roles: &[Role]) -> Protocol {
+    if statements.is_empty() {
+        return Protocol::End;
+    }
+
+    // First, inline all Call statements
+    let inlined = inline_calls(statements);
+
+    let mut current = Protocol::End;
+
+    // Build protocol from back to front
+    for statement in inlined.iter().rev() {
+        current = convert_statement(statement, current, roles);
+    }
+
+    current
+}
+
+/// Convert one statement, given the protocol that follows it
+fn convert_statement(statement: &Statement, current: Protocol, roles: &[Role]) -> Protocol {
+    match statement {
+        Statement::Send { from, to, message } => Protocol::Send {
+            from: Role::new(from.clone()),
+            to: Role::new(to.clone()),
+            message: MessageType {
+                name: message.name.clone(),
+                type_annotation: message.type_annotation.clone(),
+                payload: message.payload.clone(),
+            },
+            continuation: Box::new(current),
+        },
+        Statement::Broadcast {
+            from,
+            except,
+            message,
+            abort,
+        } => {
+            // Resolve to all roles except the sender and any left out
+            let from_role = Role::new(from.clone());
+            let to_all = roles
+                .iter()
+                .filter(|r| r.name != *from && !except.contains(&r.name))
+                .cloned()
+                .collect();
+
+            Protocol::Broadcast {
+                from: from_role,
+                to_all,
+                message: MessageType {
+                    name: message.name.clone(),
+                    type_annotation: message.type_annotation.clone(),
+                    payload: message.payload.clone(),
+                },
+                abort: *abort,
+                continuation: Box::new(current),
+            }
+        }
+        Statement::Choice { role, branches } => Protocol::Choice {
+            role: Role::new(role.clone()),
+            branches: branches
+                .iter()
+                .map(|b| Branch {
+                    label: b.label.clone(),
+                    guard: b.guard.clone(),
+                    weight: b.weight,
+                    protocol: convert_statements_to_protocol(&b.statements, roles),
+                })
+                .collect(),
+        },
+        Statement::Loop { condition, body } => Protocol::Loop {
+            condition: condition.clone(),
+            body: Box::new(convert_statements_to_protocol(body, roles)),
+        },
+        Statement::Parallel { branches } => Protocol::Parallel {
+            protocols: branches
+                .iter()
+                .map(|b| convert_statements_to_protocol(b, roles))
+                .collect(),
+        },
+        Statement::Race { arms } => Protocol::Race {
+            branches: arms
+                .iter()
+                .map(|arm| {
+                    let protocol = convert_statements_to_protocol(arm, roles);
+                    // Arms are named after the message that starts them
+                    let label = match &protocol {
+                        Protocol::Send { message, .. } => message.name.clone(),
+                        _ => format_ident!("Race"),
+                    };
+                    Branch {
+                        label,
+                        guard: None,
+                        weight: None,
+                        protocol,
+                    }
+                })
+                .collect(),
+        },
+        Statement::Rec { label, body } => Protocol::Rec {
+            label: label.clone(),
+            body: Box::new(convert_statements_to_protocol(body, roles)),
+        },
+        Statement::TryCatch { body, compensation } => {
+            let body = convert_statements_to_protocol(body, roles);
+            // Only the roles in the body can notice that it failed
+            let handler_roles = roles
+                .iter()
+                .filter(|role| body.mentions_role(role))
+                .cloned()
+                .collect();
+            Protocol::TryCatch {
+                body: Box::new(body),
+                handler_roles,
+                compensation: Box::new(convert_statements_to_protocol(compensation, roles)),
+            }
+        }
+        Statement::Timeout {
+            role,
+            duration,
+            body,
+            on_timeout,
+        } => Protocol::Timeout {
+            role: Role::new(role.clone()),
+            duration: *duration,
+            body: Box::new(convert_statements_to_protocol(body, roles)),
+            on_timeout: Box::new(convert_statements_to_protocol(on_timeout, roles)),
+        },
+        Statement::Spawn {
+            handle,
+            name,
+            roles: names,
+            body,
+            ..
+        } => {
+            let child = child_roles(names, roles);
+            Protocol::Spawn {
+                handle: handle.clone(),
+                name: name.clone(),
+                body: Box::new(convert_statements_to_protocol(body, &child)),
+                roles: child,
+                continuation: Box::new(current),
+            }
+        }
+        Statement::Await {
+            handle,
+            roles: names,
+            ..
+        } => Protocol::Await {
+            handle: handle.clone(),
+            roles: child_roles(names, roles),
+            continuation: Box::new(current),
+        },
+        Statement::Spanned { statement, .. } => convert_statement(statement, current, roles),
+        Statement::Call { .. }
+        | Statement::Cfg { .. }
+        | Statement::ForEach { .. }
+        | Statement::Abort { .. }
+        | Statement::Publish { .. }
+        | Statement::Subscription { .. }
+        | Statement::Route { .. } => {
+            // This should not happen after inlining, cfg resolution, and
+            // foreach, topic, route and abort expansion
+            current
+        }
+    }
+}
+
+/// The roles of a child session, as declared in the parent
+fn child_roles(names: &[Ident], roles: &[Role]) -> Vec<Role> {
+    names
+        .iter()
+        .map(|name| {
+            roles
+                .iter()
+                .find(|role| role.name == *name)
+                .cloned()
+                .unwrap_or_else(|| Role::new(name.clone()))
+        })
+        .collect()
+}
+
+/// Record the span of every statement under the protocol path it converts to
+///
+/// Mirrors `convert_statements_to_protocol`: sends, broadcasts, spawns,
+/// and awaits continue the sequence, while choices, loops, parallel blocks,
+/// recursion, and `try` and `timeout` blocks end it.
+fn record_spans(statements: &[Statement], mut path: NodePath, provenance: &mut Provenance) {
+    for statement in statements {
+        let statement = match statement {
+            Statement::Spanned { span, statement } => {
+                provenance.insert(path.clone(), span.clone());
+                &**statement
+            }
+            other => other,
+        };
+
+        let nested = |i: usize, body: &[Statement], provenance: &mut Provenance| {
+            let mut child = path.clone();
+            child.push(i);
+            record_spans(body, child, provenance);
+        };
+        match statement {
+            Statement::Send { .. } | Statement::Broadcast { .. } | Statement::Await { .. } => {
+                path.push(0)
+            }
+            Statement::Spawn { body, .. } => {
+                nested(1, body, provenance);
+                path.push(0);
+            }
+            Statement::Choice { branches, .. } => {
+                for (i, branch) in branches.iter().enumerate() {
+                    nested(i, &branch.statements, provenance);
+                }
+                return;
+            }
+            Statement::Loop { body, .. } | Statement::Rec { body, .. } => {
+                nested(0, body, provenance);
+                return;
+            }
+            Statement::Parallel { branches: arms } | Statement::Race { arms } => {
+                for (i, arm) in arms.iter().enumerate() {
+                    nested(i, arm, provenance);
+                }
+                return;
+            }
+            Statement::TryCatch {
+                body,
+                compensation: cleanup,
+            }
+            | Statement::Timeout {
+                body,
+                on_timeout: cleanup,
+                ..
+            } => {
+                nested(0, body, provenance);
+                nested(1, cleanup, provenance);
+                return;
+            }
+            Statement::Call { .. }
+            | Statement::Cfg { .. }
+            | Statement::ForEach { .. }
+            | Statement::Abort { .. }
+            | Statement::Publish { .. }
+            | Statement::Subscription { .. }
+            | Statement::Route { .. }
+            | Statement::Spanned { .. } => {}
+        }
+    }
+}
+
+/// Apply compile-time configuration to parsed statements
+///
+/// Splices in the bodies of `#[cfg(...)]` blocks that hold under `config`,
+/// drops the rest, and replaces constants used as loop counts with their
+/// values.
+fn resolve_config(
+    statements: Vec<Statement>,
+    config: &CompileConfig,
+    consts: &HashMap<String, usize>,
+) -> Vec<Statement> {
+    let mut result = Vec::new();
+
+    for statement in statements {
+        match statement {
+            Statement::Cfg { predicate, body } => {
+                if predicate.evaluate(config) {
+                    result.extend(resolve_config(body, config, consts));
+                }
+            }
+            Statement::Spanned { span, statement } => {
+                for resolved in resolve_config(vec![*statement], config, consts) {
+                    result.push(Statement::Spanned {
+                        span: span.clone(),
+                        statement: Box::new(resolved),
+                    });
+                }
+            }
+            Statement::Choice { role, branches } => result.push(Statement::Choice {
+                role,
+                branches: branches
+                    .into_iter()
+                    .map(|branch| ChoiceBranch {
+                        statements: resolve_config(branch.statements, config, consts),
+                        ..branch
+                    })
+                    .collect(),
+            }),
+            Statement::Loop { condition, body } => result.push(Statement::Loop {
+                condition: match condition {
+                    Some(Condition::Custom(count)) => match consts.get(&count.to_string()) {
+                        Some(value) => Some(Condition::Count(*value)),
+                        None => Some(Condition::Custom(count)),
+                    },
+                    other => other,
+                },
+                body: resolve_config(body, config, consts),
+            }),
+            Statement::Parallel { branches } => result.push(Statement::Parallel {
+                branches: branches
+                    .into_iter()
+                    .map(|branch| resolve_config(branch, config, consts))
+                    .collect(),
+            }),
+            Statement::Race { arms } => result.push(Statement::Race {
+                arms: arms
+                    .into_iter()
+                    .map(|arm| resolve_config(arm, config, consts))
+                    .collect(),
+            }),
+            Statement::Rec { label, body } => result.push(Statement::Rec {
+                label,
+                body: resolve_config(body, config, consts),
+            }),
+            Statement::TryCatch { body, compensation } => result.push(Statement::TryCatch {
+                body: resolve_config(body, config, consts),
+                compensation: resolve_config(compensation, config, consts),
+            }),
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => result.push(Statement::Timeout {
+                role,
+                duration,
+                body: resolve_config(body, config, consts),
+                on_timeout: resolve_config(on_timeout, config, consts),
+            }),
+            Statement::Call { name, statements } => result.push(Statement::Call {
+                name,
+                statements: resolve_config(statements, config, consts),
+            }),
+            Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                span,
+            } => result.push(Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body: resolve_config(body, config, consts),
+                span,
+            }),
+            other => result.push(other),
+        }
+    }
+
+    result
+}
+
+/// Subscribers of each declared topic at a point of the protocol, with the
+/// subscription change that last set them
+type Subscribers = BTreeMap<String, (BTreeSet<String>, Option<ErrorSpan>)>;
+
+/// Roles that publish to each topic used anywhere in the statements
+fn topic_publishers<'a>(
+    statements: impl IntoIterator<Item = &'a Statement>,
+    publishers: &mut HashMap<String, Vec<Ident>>,
+) {
+    for statement in statements {
+        match statement {
+            Statement::Publish { from, topic, .. } => {
+                let roles = publishers.entry(topic.to_string()).or_default();
+                if !roles.contains(from) {
+                    roles.push(from.clone());
+                }
+            }
+            Statement::Subscription { topic, .. } => {
+                publishers.entry(topic.to_string()).or_default();
+            }
+            Statement::Choice { branches, .. } => {
+                for branch in branches {
+                    topic_publishers(&branch.statements, publishers);
+                }
+            }
+            Statement::Parallel { branches: arms } | Statement::Race { arms } => {
+                for arm in arms {
+                    topic_publishers(arm, publishers);
+                }
+            }
+            Statement::Loop { body, .. }
+            | Statement::Rec { body, .. }
+            | Statement::Spawn { body, .. }
+            | Statement::Call {
+                statements: body, ..
+            } => topic_publishers(body, publishers),
+            Statement::TryCatch {
+                body,
+                compensation: other,
+            }
+            | Statement::Timeout {
+                body,
+                on_timeout: other,
+                ..
+            } => {
+                topic_publishers(body, publishers);
+                topic_publishers(other, publishers);
+            }
+            Statement::Spanned { statement, .. } => topic_publishers([&**statement], publishers),
+            _ => {}
+        }
+    }
+}
+
+/// Message named `prefix` and the topic in pascal case, such as
+/// `SubscribePriceFeed` for `price_feed`
+fn topic_message(prefix: &str, topic: &Ident) -> MessageSpec {
+    let mut name = prefix.to_string();
+    for word in topic.to_string().split('_') {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            name.extend(first.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    MessageSpec {
+        name: format_ident!("{}", name),
+        type_annotation: None,
+        payload: None,
+    }
+}
+
+/// Turn publications into broadcasts and subscription changes into messages
+///
+/// Each `A -> topic(prices): Update` becomes a broadcast of `Update` from
+/// `A` to the roles subscribed to `prices` at that point, or nothing if no
+/// other role is. Each `subscribe B to prices` becomes a `SubscribePrices`
+/// message from `B` to every other role publishing to `prices`, and each
+/// `unsubscribe` an `UnsubscribePrices`, so projection and analysis see
+/// subscription changes like any other event.
+///
+/// The subscribers are fixed when the protocol is written, so they must be
+/// the same whichever way the protocol goes: the branches of a choice or
+/// race end with the same subscribers, and `fixed` gives the reason when
+/// they cannot change at all, as inside a loop, where each iteration must
+/// start with the same subscribers.
+fn expand_topics(
+    statements: Vec<Statement>,
+    subscribers: &mut Subscribers,
+    fixed: Option<&'static str>,
+    publishers: &HashMap<String, Vec<Ident>>,
+    roles: &[Role],
+) -> std::result::Result<Vec<Statement>, ParseError> {
+    let expand =
+        |body: Vec<Statement>, subscribers: &mut Subscribers, fixed: Option<&'static str>| {
+            expand_topics(body, subscribers, fixed, publishers, roles)
+        };
+    // Bodies that must leave the subscribers as they found them
+    let unchanged = |body: Vec<Statement>, subscribers: &Subscribers, reason: &'static str| {
+        expand(body, &mut subscribers.clone(), fixed.or(Some(reason)))
+    };
+    // Without topic statements there is nothing to expand
+    if publishers.is_empty() {
+        return Ok(statements);
+    }
+    let mut result = Vec::new();
+
+    for statement in statements {
+        match statement {
+            event @ (Statement::Publish { .. } | Statement::Subscription { .. }) => {
+                topic_event(event, subscribers, fixed, publishers, roles, &mut result)?
+            }
+            Statement::Spanned { span, statement } => {
+                for expanded in expand(vec![*statement], subscribers, fixed)? {
+                    result.push(Statement::Spanned {
+                        span: span.clone(),
+                        statement: Box::new(expanded),
+                    });
+                }
+            }
+            Statement::Choice { role, branches } => {
+                let mut ends = Vec::new();
+                let mut expanded = Vec::new();
+                for branch in branches {
+                    let mut end = subscribers.clone();
+                    let statements = expand(branch.statements, &mut end, fixed)?;
+                    expanded.push(ChoiceBranch {
+                        statements,
+                        ..branch
+                    });
+                    ends.push(end);
+                }
+                *subscribers = agree(ends, subscribers)?;
+                result.push(Statement::Choice {
+                    role,
+                    branches: expanded,
+                });
+            }
+            Statement::Race { arms } => {
+                let mut ends = Vec::new();
+                let mut expanded = Vec::new();
+                for arm in arms {
+                    let mut end = subscribers.clone();
+                    expanded.push(expand(arm, &mut end, fixed)?);
+                    ends.push(end);
+                }
+                *subscribers = agree(ends, subscribers)?;
+                result.push(Statement::Race { arms: expanded });
+            }
+            Statement::Loop { condition, body } => result.push(Statement::Loop {
+                condition,
+                body: unchanged(body, subscribers, "a loop")?,
+            }),
+            Statement::Rec { label, body } => result.push(Statement::Rec {
+                label,
+                body: unchanged(body, subscribers, "a rec")?,
+            }),
+            Statement::Parallel { branches } => result.push(Statement::Parallel {
+                branches: branches
+                    .into_iter()
+                    .map(|branch| unchanged(branch, subscribers, "a parallel branch"))
+                    .collect::<std::result::Result<_, _>>()?,
+            }),
+            Statement::TryCatch { body, compensation } => result.push(Statement::TryCatch {
+                body: unchanged(body, subscribers, "a try body")?,
+                compensation: unchanged(compensation, subscribers, "a catch body")?,
+            }),
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => result.push(Statement::Timeout {
+                role,
+                duration,
+                body: unchanged(body, subscribers, "a timeout")?,
+                on_timeout: unchanged(on_timeout, subscribers, "a timeout")?,
+            }),
+            Statement::Spawn {
+                handle,
+                name,
+                roles: names,
+                body,
+                span,
+            } => result.push(Statement::Spawn {
+                handle,
+                name,
+                roles: names,
+                body: unchanged(body, subscribers, "a spawned session")?,
+                span,
+            }),
+            Statement::Call { name, statements } => result.push(Statement::Call {
+                name,
+                statements: expand(statements, subscribers, fixed)?,
+            }),
+            other => result.push(other),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Expand one publication or subscription change into `result`
+fn topic_event(
+    event: Statement,
+    subscribers: &mut Subscribers,
+    fixed: Option<&'static str>,
+    publishers: &HashMap<String, Vec<Ident>>,
+    roles: &[Role],
+    result: &mut Vec<Statement>,
+) -> std::result::Result<(), ParseError> {
+    let undeclared = |topic: &Ident, span: &ErrorSpan| ParseError::Syntax {
+        span: span.clone(),
+        message: format!("undeclared topic '{}'", topic),
+    };
+    match event {
+        Statement::Publish {
+            from,
+            topic,
+            message,
+            span,
+        } => {
+            let (current, _) = subscribers
+                .get(&topic.to_string())
+                .ok_or_else(|| undeclared(&topic, &span))?;
+            if current.iter().any(|role| from != role.as_str()) {
+                result.push(Statement::Broadcast {
+                    except: roles
+                        .iter()
+                        .filter(|role| !current.contains(&role.name.to_string()))
+                        .map(|role| role.name.clone())
+                        .collect(),
+                    from,
+                    message,
+                    abort: false,
+                });
+            }
+        }
+        Statement::Subscription {
+            role,
+            topic,
+            subscribed,
+            span,
+        } => {
+            let (current, changed) = subscribers
+                .get_mut(&topic.to_string())
+                .ok_or_else(|| undeclared(&topic, &span))?;
+            let error = |message: String| ParseError::Syntax {
+                span: span.clone(),
+                message,
+            };
+            if let Some(reason) = fixed {
+                return Err(error(format!(
+                    "subscriptions to '{}' cannot change inside {}",
+                    topic, reason
+                )));
+            }
+            let name = role.to_string();
+            if subscribed && !current.insert(name.clone()) {
+                return Err(error(format!(
+                    "role '{}' is already subscribed to '{}'",
+                    role, topic
+                )));
+            }
+            if !subscribed && !current.remove(&name) {
+                return Err(error(format!(
+                    "role '{}' is not subscribed to '{}'",
+                    role, topic
+                )));
+            }
+            *changed = Some(span.clone());
+            let prefix = if subscribed {
+                "Subscribe"
+            } else {
+                "Unsubscribe"
+            };
+            for publisher in publishers.get(&topic.to_string()).into_iter().flatten() {
+                if *publisher != role {
+                    result.push(Statement::Send {
+                        from: role.clone(),
+                        to: publisher.clone(),
+                        message: topic_message(prefix, &topic),
+                    });
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The subscribers every alternative ends with, which must be the same
+fn agree(
+    ends: Vec<Subscribers>,
+    start: &Subscribers,
+) -> std::result::Result<Subscribers, ParseError> {
+    let mut ends = ends.into_iter();
+    let Some(first) = ends.next() else {
+        return Ok(start.clone());
+    };
+    for end in ends {
+        for (topic, (current, changed)) in &end {
+            let (expected, changed_first) = &first[topic];
+            if current != expected {
+                let span = changed
+                    .as_ref()
+                    .or(changed_first.as_ref())
+                    .cloned()
+                    .expect("subscribers differ only after a change");
+                return Err(ParseError::Syntax {
+                    span,
+                    message: format!(
+                        "branches end with different subscribers to '{}'; every branch must subscribe and unsubscribe the same roles",
+                        topic
+                    ),
+                });
+            }
+        }
+    }
+    Ok(first)
+}
+
+/// Sender, message, and candidates of a route
+type RouteSpec = (Ident, Ident, Vec<Ident>);
+
+/// Turn each route into a choice by its sender between the candidates
+///
+/// `A -> route(B, C): Order` becomes a choice by `A` with the labels `B`
+/// and `C`, where each branch sends `Order` to its candidate and goes on
+/// with the rest of the block, so that every candidate projects to a
+/// branch on where the message went. The branches tell the roles whose
+/// part differs, as for `if`. Each route is added to `routes` once, as its
+/// sender, message, and candidates.
+fn expand_routes(statements: Vec<Statement>, routes: &mut Vec<RouteSpec>) -> Vec<Statement> {
+    let mut result = Vec::new();
+    let mut statements = statements.into_iter();
+
+    while let Some(statement) = statements.next() {
+        let (span, statement) = match statement {
+            Statement::Spanned { span, statement } => (Some(span), *statement),
+            other => (None, other),
+        };
+        let expanded = match statement {
+            Statement::Route {
+                from,
+                candidates,
+                message,
+            } => {
+                let route = (from.clone(), message.name.clone(), candidates.clone());
+                if !routes.contains(&route) {
+                    routes.push(route);
+                }
+                let rest = expand_routes(statements.by_ref().collect(), routes);
+                Statement::Choice {
+                    branches: candidates
+                        .into_iter()
+                        .map(|candidate| {
+                            let mut statements = vec![Statement::Send {
+                                from: from.clone(),
+                                to: candidate.clone(),
+                                message: message.clone(),
+                            }];
+                            statements.extend(rest.iter().cloned());
+                            ChoiceBranch {
+                                label: candidate,
+                                guard: None,
+                                weight: None,
+                                notify_peers: true,
+                                statements,
+                            }
+                        })
+                        .collect(),
+                    role: from,
+                }
             }
-            _ => {}
-        }
+            Statement::Choice { role, branches } => Statement::Choice {
+                role,
+                branches: branches
+                    .into_iter()
+                    .map(|branch| ChoiceBranch {
+                        statements: expand_routes(branch.statements, routes),
+                        ..branch
+                    })
+                    .collect(),
+            },
+            Statement::Loop { condition, body } => Statement::Loop {
+                condition,
+                body: expand_routes(body, routes),
+            },
+            Statement::Rec { label, body } => Statement::Rec {
+                label,
+                body: expand_routes(body, routes),
+            },
+            Statement::Parallel { branches } => Statement::Parallel {
+                branches: branches
+                    .into_iter()
+                    .map(|branch| expand_routes(branch, routes))
+                    .collect(),
+            },
+            Statement::Race { arms } => Statement::Race {
+                arms: arms
+                    .into_iter()
+                    .map(|arm| expand_routes(arm, routes))
+                    .collect(),
+            },
+            Statement::TryCatch { body, compensation } => Statement::TryCatch {
+                body: expand_routes(body, routes),
+                compensation: expand_routes(compensation, routes),
+            },
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => Statement::Timeout {
+                role,
+                duration,
+                body: expand_routes(body, routes),
+                on_timeout: expand_routes(on_timeout, routes),
+            },
+            Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                span,
+            } => Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body: expand_routes(body, routes),
+                span,
+            },
+            Statement::Call { name, statements } => Statement::Call {
+                name,
+                statements: expand_routes(statements, routes),
+            },
+            other => other,
+        };
+        result.push(match span {
+            Some(span) => Statement::Spanned {
+                span,
+                statement: Box::new(expanded),
+            },
+            None => expanded,
+        });
     }
 
-    Ok(MessageSpec {
-        name,
-        type_annotation,
-        payload,
-    })
+    result
 }
 
-/// Choreography statement types
-#[derive(Debug, Clone)]
-enum Statement {
-    Send {
-        from: Ident,
-        to: Ident,
-        message: MessageSpec,
-    },
-    Broadcast {
-        from: Ident,
-        message: MessageSpec,
-    },
-    Choice {
-        role: Ident,
-        branches: Vec<ChoiceBranch>,
-    },
-    Loop {
-        condition: Option<Condition>,
-        body: Vec<Statement>,
-    },
-    Parallel {
-        branches: Vec<Vec<Statement>>,
-    },
-    Rec {
-        label: Ident,
-        body: Vec<Statement>,
-    },
-    Call {
-        #[allow(dead_code)]
-        name: Ident,
-        statements: Vec<Statement>,
-    },
-}
+/// Roles an abort tells, or why there cannot be an abort here
+type AbortScope = std::result::Result<HashSet<Ident>, &'static str>;
 
-/// Choice branch in choreography
-#[derive(Debug, Clone)]
-struct ChoiceBranch {
-    label: Ident,
-    guard: Option<TokenStream>,
+const ABORT_OUTSIDE_CHOICE: &str =
+    "An abort must be inside a choice, if, or race, or there is nothing to leave early";
+
+/// Turn every `abort at A: Reason` into a broadcast of `Reason` from `A` to
+/// the roles still waiting on it
+///
+/// Choices, `if`s, and races are where a protocol can end early, and
+/// nothing follows them, so the roles still waiting are those taking part
+/// in the other branches of the innermost one around the abort. `scope`
+/// holds their names, or the error to report when an abort cannot leave
+/// its surroundings: a loop would just start its next iteration, and
+/// parallel branches would carry on.
+fn expand_aborts(
     statements: Vec<Statement>,
+    scope: &AbortScope,
+    roles: &[Role],
+) -> std::result::Result<Vec<Statement>, ParseError> {
+    // Each alternative hands its abort the roles of the other alternatives
+    let alternatives = |bodies: &[&Vec<Statement>]| -> Vec<AbortScope> {
+        // Inside a loop or parallel branch, a choice does not make an abort legal
+        if matches!(scope, Err(error) if *error != ABORT_OUTSIDE_CHOICE) {
+            return vec![scope.clone(); bodies.len()];
+        }
+        let mentioned: Vec<HashSet<Ident>> = bodies
+            .iter()
+            .map(|body| {
+                let protocol = convert_statements_to_protocol(body, roles);
+                roles
+                    .iter()
+                    .filter(|role| protocol.mentions_role(role))
+                    .map(|role| role.name.clone())
+                    .collect()
+            })
+            .collect();
+        (0..bodies.len())
+            .map(|i| {
+                Ok(mentioned
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .flat_map(|(_, names)| names.iter().cloned())
+                    .collect())
+            })
+            .collect()
+    };
+    let within =
+        |body: Vec<Statement>, error: &'static str| expand_aborts(body, &Err(error), roles);
+
+    statements
+        .into_iter()
+        .map(|statement| {
+            Ok(match statement {
+                Statement::Abort {
+                    role,
+                    message,
+                    span,
+                } => {
+                    let remaining = scope.as_ref().map_err(|message| ParseError::Syntax {
+                        span,
+                        message: message.to_string(),
+                    })?;
+                    Statement::Broadcast {
+                        except: roles
+                            .iter()
+                            .map(|r| r.name.clone())
+                            .filter(|name| !remaining.contains(name))
+                            .collect(),
+                        from: role,
+                        message,
+                        abort: true,
+                    }
+                }
+                Statement::Choice { role, branches } => {
+                    let bodies: Vec<_> = branches.iter().map(|b| &b.statements).collect();
+                    let scopes = alternatives(&bodies);
+                    Statement::Choice {
+                        role,
+                        branches: branches
+                            .into_iter()
+                            .zip(&scopes)
+                            .map(|(branch, scope)| {
+                                Ok(ChoiceBranch {
+                                    statements: expand_aborts(branch.statements, scope, roles)?,
+                                    ..branch
+                                })
+                            })
+                            .collect::<std::result::Result<_, ParseError>>()?,
+                    }
+                }
+                Statement::Race { arms } => {
+                    let scopes = alternatives(&arms.iter().collect::<Vec<_>>());
+                    Statement::Race {
+                        arms: arms
+                            .into_iter()
+                            .zip(&scopes)
+                            .map(|(arm, scope)| expand_aborts(arm, scope, roles))
+                            .collect::<std::result::Result<_, _>>()?,
+                    }
+                }
+                Statement::Loop { condition, body } => Statement::Loop {
+                    condition,
+                    body: within(
+                        body,
+                        "An abort cannot leave a loop; use rec and stop recursing instead",
+                    )?,
+                },
+                Statement::Parallel { branches } => Statement::Parallel {
+                    branches: branches
+                        .into_iter()
+                        .map(|branch| within(branch, "An abort cannot stop parallel branches"))
+                        .collect::<std::result::Result<_, _>>()?,
+                },
+                Statement::Rec { label, body } => Statement::Rec {
+                    label,
+                    body: expand_aborts(body, scope, roles)?,
+                },
+                Statement::TryCatch { body, compensation } => Statement::TryCatch {
+                    body: expand_aborts(body, scope, roles)?,
+                    compensation: expand_aborts(compensation, scope, roles)?,
+                },
+                Statement::Timeout {
+                    role,
+                    duration,
+                    body,
+                    on_timeout,
+                } => Statement::Timeout {
+                    role,
+                    duration,
+                    body: expand_aborts(body, scope, roles)?,
+                    on_timeout: expand_aborts(on_timeout, scope, roles)?,
+                },
+                Statement::Call { name, statements } => Statement::Call {
+                    name,
+                    statements: expand_aborts(statements, scope, roles)?,
+                },
+                // An abort in a child ends the child, among its own roles
+                Statement::Spawn {
+                    handle,
+                    name,
+                    roles: names,
+                    body,
+                    span,
+                } => Statement::Spawn {
+                    body: expand_aborts(
+                        body,
+                        &Err(ABORT_OUTSIDE_CHOICE),
+                        &child_roles(&names, roles),
+                    )?,
+                    handle,
+                    name,
+                    roles: names,
+                    span,
+                },
+                Statement::Spanned { span, statement } => Statement::Spanned {
+                    span,
+                    statement: Box::new(expand_aborts(vec![*statement], scope, roles)?.remove(0)),
+                },
+                other => other,
+            })
+        })
+        .collect()
 }
 
-/// Message specification with optional payload
-#[derive(Debug, Clone)]
-struct MessageSpec {
-    name: Ident,
-    type_annotation: Option<TokenStream>,
-    payload: Option<TokenStream>,
+/// Tell peers which way an `if` went where they need to know
+///
+/// A peer needs to know when its projection differs between the two
+/// branches. If both branches already start with a message from the
+/// deciding role to the same peer, that peer learns the label from it.
+/// Every other peer that needs to know is sent the label itself, `Then` or
+/// `Else`, each in a nested single-branch choice so that it projects to a
+/// `Branch` and the branches merge for it.
+fn notify_peers(statements: Vec<Statement>, roles: &[Role]) -> Vec<Statement> {
+    let notify = |body: Vec<Statement>| notify_peers(body, roles);
+
+    statements
+        .into_iter()
+        .map(|statement| match statement {
+            Statement::Choice { role, branches } => {
+                let branches: Vec<ChoiceBranch> = branches
+                    .into_iter()
+                    .map(|branch| ChoiceBranch {
+                        statements: notify(branch.statements),
+                        ..branch
+                    })
+                    .collect();
+                if branches.iter().any(|branch| branch.notify_peers) {
+                    notify_branches(role, branches, roles)
+                } else {
+                    Statement::Choice { role, branches }
+                }
+            }
+            Statement::Loop { condition, body } => Statement::Loop {
+                condition,
+                body: notify(body),
+            },
+            Statement::Parallel { branches } => Statement::Parallel {
+                branches: branches.into_iter().map(notify).collect(),
+            },
+            Statement::Race { arms } => Statement::Race {
+                arms: arms.into_iter().map(notify).collect(),
+            },
+            Statement::Rec { label, body } => Statement::Rec {
+                label,
+                body: notify(body),
+            },
+            Statement::TryCatch { body, compensation } => Statement::TryCatch {
+                body: notify(body),
+                compensation: notify(compensation),
+            },
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => Statement::Timeout {
+                role,
+                duration,
+                body: notify(body),
+                on_timeout: notify(on_timeout),
+            },
+            Statement::Call { name, statements } => Statement::Call {
+                name,
+                statements: notify(statements),
+            },
+            Statement::Spawn {
+                handle,
+                name,
+                roles: names,
+                body,
+                span,
+            } => Statement::Spawn {
+                body: notify_peers(body, &child_roles(&names, roles)),
+                handle,
+                name,
+                roles: names,
+                span,
+            },
+            Statement::Spanned { span, statement } => Statement::Spanned {
+                span,
+                statement: Box::new(notify(vec![*statement]).remove(0)),
+            },
+            other => other,
+        })
+        .collect()
 }
 
-/// Convert statements to protocol AST
-fn convert_statements_to_protocol(statements: &[Statement], roles: &[Role]) -> Protocol {
-    if statements.is_empty() {
-        return Protocol::End;
+fn notify_branches(role: Ident, branches: Vec<ChoiceBranch>, roles: &[Role]) -> Statement {
+    let views: Vec<_> = branches
+        .iter()
+        .map(|branch| Choreography {
+            name: role.clone(),
+            roles: roles.to_vec(),
+            protocol: convert_statements_to_protocol(&branch.statements, roles),
+            attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
+        })
+        .collect();
+    let mut peers: Vec<&Role> = roles
+        .iter()
+        .filter(|peer| peer.name != role)
+        .filter(|peer| {
+            let projections: Vec<_> = views.iter().map(|c| project(c, peer).ok()).collect();
+            projections.iter().any(Option::is_none) || projections.windows(2).any(|w| w[0] != w[1])
+        })
+        .collect();
+
+    // A first message from the decider to the same peer in every branch
+    // already carries the label; an abort's broadcast counts as one
+    let leads_to =
+        |branch: &ChoiceBranch, peer: &Ident| match branch.statements.first().map(unspanned) {
+            Some(Statement::Send { from, to, .. }) => *from == role && to == peer,
+            Some(Statement::Broadcast { from, except, .. }) => {
+                *from == role && from != peer && !except.contains(peer)
+            }
+            _ => false,
+        };
+    let lead = roles
+        .iter()
+        .map(|peer| &peer.name)
+        .find(|peer| branches.iter().all(|b| leads_to(b, peer)))
+        .cloned();
+    if let Some(lead) = &lead {
+        peers.retain(|peer| peer.name != *lead);
     }
 
-    // First, inline all Call statements
-    let inlined = inline_calls(statements);
+    let branches = branches
+        .into_iter()
+        .map(|branch| {
+            let mut statements = branch.statements;
+            let first = lead.as_ref().map(|_| statements.remove(0));
+            for (i, peer) in peers.iter().enumerate().rev() {
+                statements.insert(
+                    0,
+                    Statement::Send {
+                        from: role.clone(),
+                        to: peer.name.clone(),
+                        message: MessageSpec {
+                            name: branch.label.clone(),
+                            type_annotation: None,
+                            payload: None,
+                        },
+                    },
+                );
+                if i > 0 || first.is_some() {
+                    statements = vec![Statement::Choice {
+                        role: role.clone(),
+                        branches: vec![ChoiceBranch {
+                            label: branch.label.clone(),
+                            guard: None,
+                            weight: None,
+                            notify_peers: false,
+                            statements,
+                        }],
+                    }];
+                }
+            }
+            statements.splice(0..0, first);
+            ChoiceBranch {
+                notify_peers: false,
+                statements,
+                ..branch
+            }
+        })
+        .collect();
 
-    let mut current = Protocol::End;
+    Statement::Choice { role, branches }
+}
 
-    // Build protocol from back to front
-    for statement in inlined.iter().rev() {
-        current = match statement {
-            Statement::Send { from, to, message } => Protocol::Send {
-                from: Role::new(from.clone()),
-                to: Role::new(to.clone()),
-                message: MessageType {
-                    name: message.name.clone(),
-                    type_annotation: message.type_annotation.clone(),
-                    payload: message.payload.clone(),
-                },
-                continuation: Box::new(current),
-            },
-            Statement::Broadcast { from, message } => {
-                // Resolve to all roles except the sender
-                let from_role = Role::new(from.clone());
-                let to_all = roles
+/// Check `spawn` and `await` statements against each other
+///
+/// An await names a child spawned before it in the same session and gets
+/// the child's roles from the spawn, and a handle cannot be spawned again
+/// while its child is still to be awaited. The branches of a choice or race
+/// see the children spawned before it. Loop, recursion, and parallel bodies
+/// may run more than once or side by side, so they only see their own.
+/// A child may only use the roles passed to it.
+fn link_spawns(
+    statements: Vec<Statement>,
+    spawned: &mut HashMap<Ident, Vec<Ident>>,
+    roles: &[Role],
+) -> std::result::Result<Vec<Statement>, ParseError> {
+    let own = |body: Vec<Statement>| link_spawns(body, &mut HashMap::new(), roles);
+    let mut result = Vec::with_capacity(statements.len());
+
+    for statement in statements {
+        result.push(match statement {
+            Statement::Spawn {
+                handle,
+                name,
+                roles: names,
+                body,
+                span,
+            } => {
+                let child = child_roles(&names, roles);
+                let protocol = convert_statements_to_protocol(&body, &child);
+                if let Some(role) = roles
                     .iter()
-                    .filter(|r| r.name != *from)
-                    .cloned()
-                    .collect();
-                
-                Protocol::Broadcast {
-                    from: from_role,
-                    to_all,
-                    message: MessageType {
-                        name: message.name.clone(),
-                        type_annotation: message.type_annotation.clone(),
-                        payload: message.payload.clone(),
-                    },
-                    continuation: Box::new(current),
+                    .find(|role| !names.contains(&role.name) && protocol.mentions_role(role))
+                {
+                    return Err(ParseError::Syntax {
+                        span,
+                        message: format!(
+                            "{} uses role {}, which is not passed to spawn",
+                            name, role.name
+                        ),
+                    });
+                }
+                if spawned.insert(handle.clone(), names.clone()).is_some() {
+                    return Err(ParseError::Syntax {
+                        span,
+                        message: format!("{} is spawned again before it is awaited", handle),
+                    });
+                }
+                Statement::Spawn {
+                    body: link_spawns(body, &mut HashMap::new(), &child)?,
+                    handle,
+                    name,
+                    roles: names,
+                    span,
                 }
             }
-            Statement::Choice { role, branches } => Protocol::Choice {
-                role: Role::new(role.clone()),
+            Statement::Await { handle, span, .. } => match spawned.remove(&handle) {
+                Some(roles) => Statement::Await {
+                    handle,
+                    roles,
+                    span,
+                },
+                None => {
+                    return Err(ParseError::Syntax {
+                        span,
+                        message: format!("await {} has no spawn before it", handle),
+                    })
+                }
+            },
+            Statement::Choice { role, branches } => Statement::Choice {
+                role,
                 branches: branches
-                    .iter()
-                    .map(|b| Branch {
-                        label: b.label.clone(),
-                        guard: b.guard.clone(),
-                        protocol: convert_statements_to_protocol(&b.statements, roles),
+                    .into_iter()
+                    .map(|branch| {
+                        Ok(ChoiceBranch {
+                            statements: link_spawns(
+                                branch.statements,
+                                &mut spawned.clone(),
+                                roles,
+                            )?,
+                            ..branch
+                        })
                     })
-                    .collect(),
+                    .collect::<std::result::Result<_, ParseError>>()?,
             },
-            Statement::Loop { condition, body } => Protocol::Loop {
-                condition: condition.clone(),
-                body: Box::new(convert_statements_to_protocol(body, roles)),
+            Statement::Race { arms } => Statement::Race {
+                arms: arms
+                    .into_iter()
+                    .map(|arm| link_spawns(arm, &mut spawned.clone(), roles))
+                    .collect::<std::result::Result<_, _>>()?,
             },
-            Statement::Parallel { branches } => Protocol::Parallel {
-                protocols: branches
-                    .iter()
-                    .map(|b| convert_statements_to_protocol(b, roles))
-                    .collect(),
+            Statement::Loop { condition, body } => Statement::Loop {
+                condition,
+                body: own(body)?,
             },
-            Statement::Rec { label, body } => Protocol::Rec {
-                label: label.clone(),
-                body: Box::new(convert_statements_to_protocol(body, roles)),
+            Statement::Parallel { branches } => Statement::Parallel {
+                branches: branches
+                    .into_iter()
+                    .map(own)
+                    .collect::<std::result::Result<_, _>>()?,
+            },
+            Statement::Rec { label, body } => Statement::Rec {
+                label,
+                body: own(body)?,
+            },
+            // The compensation may start before the body's children exist
+            Statement::TryCatch { body, compensation } => Statement::TryCatch {
+                body: own(body)?,
+                compensation: own(compensation)?,
             },
-            Statement::Call { .. } => {
-                // This should not happen after inlining
-                current
+            // Likewise the else branch may start before the body's children
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => Statement::Timeout {
+                role,
+                duration,
+                body: own(body)?,
+                on_timeout: own(on_timeout)?,
+            },
+            // A call is spliced in place, so it shares the caller's children
+            Statement::Call { name, statements } => Statement::Call {
+                name,
+                statements: link_spawns(statements, spawned, roles)?,
+            },
+            Statement::Spanned { span, statement } => Statement::Spanned {
+                span,
+                statement: Box::new(link_spawns(vec![*statement], spawned, roles)?.remove(0)),
+            },
+            other => other,
+        });
+    }
+
+    Ok(result)
+}
+
+fn unspanned(statement: &Statement) -> &Statement {
+    match statement {
+        Statement::Spanned { statement, .. } => unspanned(statement),
+        other => other,
+    }
+}
+
+/// Expand `foreach` comprehensions
+///
+/// A range whose bounds are integers or known constants is unrolled, with
+/// the variable replaced by each index in the role references of the body:
+/// `foreach i in 0..3 { Master -> Worker[i]: Task }` becomes three sends,
+/// and `unrolled` is set so the families are expanded once parsed.
+/// Otherwise the body is kept once, with symbolic role indices, inside a
+/// loop counting the range, the same shape as `loop (count: N)`.
+fn expand_foreach(
+    statements: Vec<Statement>,
+    consts: &HashMap<String, usize>,
+    declared_roles: &HashSet<String>,
+    unrolled: &Cell<bool>,
+) -> Vec<Statement> {
+    let expand = |body: Vec<Statement>| expand_foreach(body, consts, declared_roles, unrolled);
+    let mut result = Vec::new();
+
+    for statement in statements {
+        match statement {
+            Statement::ForEach {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let body = expand(body);
+                let bound = |b: &str| b.parse::<usize>().ok().or_else(|| consts.get(b).copied());
+                match (bound(&start), bound(&end)) {
+                    (Some(start), Some(end)) => {
+                        unrolled.set(true);
+                        for index in start..end {
+                            result.extend(
+                                body.iter()
+                                    .map(|s| substitute_index(s, &var, index, declared_roles)),
+                            );
+                        }
+                    }
+                    _ => {
+                        let count = if start == "0" {
+                            end
+                        } else {
+                            format!("{} - {}", end, start)
+                        };
+                        result.push(Statement::Loop {
+                            condition: syn::parse_str(&count).ok().map(Condition::Custom),
+                            body,
+                        });
+                    }
+                }
             }
-        };
+            Statement::Spanned { span, statement } => {
+                for statement in expand(vec![*statement]) {
+                    // Unrolled statements keep the spans of the body
+                    if let Statement::Spanned { .. } = statement {
+                        result.push(statement);
+                    } else {
+                        result.push(Statement::Spanned {
+                            span: span.clone(),
+                            statement: Box::new(statement),
+                        });
+                    }
+                }
+            }
+            Statement::Choice { role, branches } => result.push(Statement::Choice {
+                role,
+                branches: branches
+                    .into_iter()
+                    .map(|branch| ChoiceBranch {
+                        statements: expand(branch.statements),
+                        ..branch
+                    })
+                    .collect(),
+            }),
+            Statement::Loop { condition, body } => result.push(Statement::Loop {
+                condition,
+                body: expand(body),
+            }),
+            Statement::Parallel { branches } => result.push(Statement::Parallel {
+                branches: branches.into_iter().map(expand).collect(),
+            }),
+            Statement::Race { arms } => result.push(Statement::Race {
+                arms: arms.into_iter().map(expand).collect(),
+            }),
+            Statement::Rec { label, body } => result.push(Statement::Rec {
+                label,
+                body: expand(body),
+            }),
+            Statement::TryCatch { body, compensation } => result.push(Statement::TryCatch {
+                body: expand(body),
+                compensation: expand(compensation),
+            }),
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => result.push(Statement::Timeout {
+                role,
+                duration,
+                body: expand(body),
+                on_timeout: expand(on_timeout),
+            }),
+            Statement::Call { name, statements } => result.push(Statement::Call {
+                name,
+                statements: expand(statements),
+            }),
+            Statement::Cfg { predicate, body } => result.push(Statement::Cfg {
+                predicate,
+                body: expand(body),
+            }),
+            Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                span,
+            } => result.push(Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body: expand(body),
+                span,
+            }),
+            other => result.push(other),
+        }
     }
 
-    current
+    result
+}
+
+/// Replace `var` by `index` in the role references of `statement`
+///
+/// Role references are parsed to identifiers such as `Worker_i`, so
+/// `Worker[i]` becomes `Worker_0` like a literal `Worker[0]` would.
+fn substitute_index(
+    statement: &Statement,
+    var: &Ident,
+    index: usize,
+    declared_roles: &HashSet<String>,
+) -> Statement {
+    let role = |ident: &Ident| {
+        let suffix = format!("_{}", var);
+        match ident.to_string().strip_suffix(&suffix) {
+            Some(base) if declared_roles.contains(base) => format_ident!("{}_{}", base, index),
+            _ => ident.clone(),
+        }
+    };
+    let all = |statements: &[Statement]| -> Vec<Statement> {
+        statements
+            .iter()
+            .map(|s| substitute_index(s, var, index, declared_roles))
+            .collect()
+    };
+    match statement {
+        Statement::Send { from, to, message } => Statement::Send {
+            from: role(from),
+            to: role(to),
+            message: message.clone(),
+        },
+        Statement::Broadcast {
+            from,
+            except,
+            message,
+            abort,
+        } => Statement::Broadcast {
+            from: role(from),
+            except: except.clone(),
+            message: message.clone(),
+            abort: *abort,
+        },
+        Statement::Choice {
+            role: chooser,
+            branches,
+        } => Statement::Choice {
+            role: role(chooser),
+            branches: branches
+                .iter()
+                .map(|branch| ChoiceBranch {
+                    statements: all(&branch.statements),
+                    ..branch.clone()
+                })
+                .collect(),
+        },
+        Statement::Loop { condition, body } => Statement::Loop {
+            condition: match condition {
+                Some(Condition::RoleDecides(decider)) => {
+                    Some(Condition::RoleDecides(Role::new(role(&decider.name))))
+                }
+                other => other.clone(),
+            },
+            body: all(body),
+        },
+        Statement::Parallel { branches } => Statement::Parallel {
+            branches: branches.iter().map(|b| all(b)).collect(),
+        },
+        Statement::Race { arms } => Statement::Race {
+            arms: arms.iter().map(|arm| all(arm)).collect(),
+        },
+        Statement::Rec { label, body } => Statement::Rec {
+            label: label.clone(),
+            body: all(body),
+        },
+        Statement::TryCatch { body, compensation } => Statement::TryCatch {
+            body: all(body),
+            compensation: all(compensation),
+        },
+        Statement::Timeout {
+            role: timer,
+            duration,
+            body,
+            on_timeout,
+        } => Statement::Timeout {
+            role: role(timer),
+            duration: *duration,
+            body: all(body),
+            on_timeout: all(on_timeout),
+        },
+        Statement::Call { name, statements } => Statement::Call {
+            name: name.clone(),
+            statements: all(statements),
+        },
+        Statement::Cfg { predicate, body } => Statement::Cfg {
+            predicate: predicate.clone(),
+            body: all(body),
+        },
+        Statement::ForEach {
+            var: inner,
+            start,
+            end,
+            body,
+        } => Statement::ForEach {
+            var: inner.clone(),
+            start: start.clone(),
+            end: end.clone(),
+            // An inner loop over the same name shadows this one
+            body: if inner == var {
+                body.clone()
+            } else {
+                all(body)
+            },
+        },
+        Statement::Abort {
+            role: aborter,
+            message,
+            span,
+        } => Statement::Abort {
+            role: role(aborter),
+            message: message.clone(),
+            span: span.clone(),
+        },
+        Statement::Spawn {
+            handle,
+            name,
+            roles,
+            body,
+            span,
+        } => Statement::Spawn {
+            handle: handle.clone(),
+            name: name.clone(),
+            roles: roles.iter().map(role).collect(),
+            body: all(body),
+            span: span.clone(),
+        },
+        Statement::Publish {
+            from,
+            topic,
+            message,
+            span,
+        } => Statement::Publish {
+            from: role(from),
+            topic: topic.clone(),
+            message: message.clone(),
+            span: span.clone(),
+        },
+        Statement::Route {
+            from,
+            candidates,
+            message,
+        } => Statement::Route {
+            from: role(from),
+            candidates: candidates.iter().map(role).collect(),
+            message: message.clone(),
+        },
+        Statement::Subscription { .. } | Statement::Await { .. } => statement.clone(),
+        Statement::Spanned { span, statement } => Statement::Spanned {
+            span: span.clone(),
+            statement: Box::new(substitute_index(statement, var, index, declared_roles)),
+        },
+    }
 }
 
 /// Inline all Call statements by replacing them with their definitions
@@ -842,6 +3988,19 @@ fn inline_calls(statements: &[Statement]) -> Vec<Statement> {
                 // Recursively inline the called protocol's statements
                 result.extend(inline_calls(statements));
             }
+            Statement::Spanned { span, statement } => {
+                if let Statement::Call { statements, .. } = &**statement {
+                    // The spliced statements carry spans from the definition
+                    result.extend(inline_calls(statements));
+                } else {
+                    for inlined in inline_calls(std::slice::from_ref(statement)) {
+                        result.push(Statement::Spanned {
+                            span: span.clone(),
+                            statement: Box::new(inlined),
+                        });
+                    }
+                }
+            }
             Statement::Choice { role, branches } => {
                 // Inline calls within choice branches
                 let new_branches = branches
@@ -849,6 +4008,8 @@ fn inline_calls(statements: &[Statement]) -> Vec<Statement> {
                     .map(|b| ChoiceBranch {
                         label: b.label.clone(),
                         guard: b.guard.clone(),
+                        weight: b.weight,
+                        notify_peers: b.notify_peers,
                         statements: inline_calls(&b.statements),
                     })
                     .collect();
@@ -871,6 +4032,11 @@ fn inline_calls(statements: &[Statement]) -> Vec<Statement> {
                     branches: new_branches,
                 });
             }
+            Statement::Race { arms } => {
+                result.push(Statement::Race {
+                    arms: arms.iter().map(|arm| inline_calls(arm)).collect(),
+                });
+            }
             Statement::Rec { label, body } => {
                 // Inline calls within recursive body
                 result.push(Statement::Rec {
@@ -878,6 +4044,40 @@ fn inline_calls(statements: &[Statement]) -> Vec<Statement> {
                     body: inline_calls(body),
                 });
             }
+            Statement::TryCatch { body, compensation } => {
+                result.push(Statement::TryCatch {
+                    body: inline_calls(body),
+                    compensation: inline_calls(compensation),
+                });
+            }
+            Statement::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => {
+                result.push(Statement::Timeout {
+                    role: role.clone(),
+                    duration: *duration,
+                    body: inline_calls(body),
+                    on_timeout: inline_calls(on_timeout),
+                });
+            }
+            Statement::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                span,
+            } => {
+                result.push(Statement::Spawn {
+                    handle: handle.clone(),
+                    name: name.clone(),
+                    roles: roles.clone(),
+                    body: inline_calls(body),
+                    span: span.clone(),
+                });
+            }
             _ => {
                 // Other statements remain unchanged
                 result.push(statement.clone());
@@ -917,10 +4117,14 @@ pub fn parse_choreography(input: TokenStream) -> Result<Choreography> {
         roles,
         protocol,
         attrs: Default::default(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: Default::default(),
+        trusted: Vec::new(),
     })
 }
 
-/// Parse a choreography from a file
+/// Parse a choreography from a file, resolving imports relative to it
 pub fn parse_choreography_file(
     path: &std::path::Path,
 ) -> std::result::Result<Choreography, ParseError> {
@@ -935,7 +4139,12 @@ pub fn parse_choreography_file(
         message: e.to_string(),
     })?;
 
-    parse_choreography_str(&content)
+    let resolver = match path.canonicalize() {
+        Ok(path) => ModuleResolver::default().within(&path),
+        Err(_) => ModuleResolver::default(),
+    };
+    parse_choreography_with_resolver(&content, &CompileConfig::default(), &resolver)
+        .map(|(choreography, _)| choreography)
 }
 
 /// Parse choreography DSL
@@ -956,6 +4165,17 @@ pub fn choreography_macro(input: TokenStream) -> TokenStream {
         return syn::Error::new(Span::call_site(), e.to_string()).to_compile_error();
     }
 
+    // Generated items must not clash with each other
+    if let Err(e) = super::namespace::check_name_collisions(&[&choreography]) {
+        return syn::Error::new(Span::call_site(), e.to_string()).to_compile_error();
+    }
+
+    // Apply optimizations requested through @optimize
+    let choreography = match super::optimize::optimize(&choreography) {
+        Ok(optimization) => optimization.choreography,
+        Err(e) => return syn::Error::new(Span::call_site(), e.to_string()).to_compile_error(),
+    };
+
     // Project to local types
     let mut local_types = Vec::new();
     for role in &choreography.roles {
@@ -1021,6 +4241,34 @@ choreography Negotiation {
         assert_eq!(choreo.name.to_string(), "Negotiation");
     }
 
+    #[test]
+    fn test_parse_tagged_payload_fields() {
+        let input = r#"
+choreography Checkout {
+    roles: Shopper, Shop
+
+    Shopper -> Shop: Order(card: String @sensitive, items: HashMap<String, u32>)
+}
+"#;
+
+        let choreo = parse_choreography_str(input).unwrap();
+        let Protocol::Send { message, .. } = &choreo.protocol else {
+            panic!("expected a send");
+        };
+        let fields = message.fields().unwrap();
+        assert_eq!(fields.len(), 2);
+        assert!(fields[0].is_sensitive());
+        assert_eq!(fields[1].ty.to_string(), "HashMap < String , u32 >");
+        assert_eq!(message.sensitive_fields(), vec!["card"]);
+
+        let tuple = input.replace(
+            "card: String @sensitive, items: HashMap<String, u32>",
+            "String @sensitive",
+        );
+        let err = parse_choreography_str(&tuple).unwrap_err();
+        assert!(matches!(err, ParseError::InvalidMessage { .. }));
+    }
+
     #[test]
     fn test_parse_undefined_role() {
         let input = r#"
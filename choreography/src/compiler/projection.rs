@@ -1,6 +1,15 @@
 // Projection from global choreographies to local session types
 
-use crate::ast::{Branch, Choreography, LocalType, MessageType, Protocol, Role};
+use crate::ast::{Branch, Choreography, Condition, LocalType, MessageType, Protocol, Role};
+use crate::compiler::expand::{collect_references, family_index};
+use crate::compiler::provenance::walk_with_paths;
+use proc_macro2::Ident;
+use quote::format_ident;
+use smallvec::SmallVec;
+
+/// Branch or arm projections of one choice or parallel block; most have
+/// only a few, so they stay off the heap
+type Projections = SmallVec<[LocalType; 4]>;
 
 /// Project a choreography to a local session type for a specific role
 pub fn project(choreography: &Choreography, role: &Role) -> Result<LocalType, ProjectionError> {
@@ -8,6 +17,311 @@ pub fn project(choreography: &Choreography, role: &Role) -> Result<LocalType, Pr
     context.project_protocol(&choreography.protocol)
 }
 
+/// Local type shared by every member of a role family
+///
+/// Projecting `Worker[N]` once leaves the member's own index open as
+/// `index`, so one program serves whichever worker it is started as. A
+/// member only exchanges messages with roles outside its family, which
+/// makes the local type the same at every index.
+#[derive(Debug, Clone)]
+pub struct FamilyProjection {
+    /// The family, as declared
+    pub family: Role,
+    /// Name of the open index, taken from the protocol's loops
+    pub index: Ident,
+    /// Local type of the member at `index`
+    pub local_type: LocalType,
+}
+
+impl FamilyProjection {
+    /// Role and local type of the member at `index`, with the role named as
+    /// by [`expand_roles`](crate::compiler::expand::expand_roles)
+    pub fn instantiate(&self, index: usize) -> (Role, LocalType) {
+        (
+            Role::new(format_ident!("{}{}", self.family.name, index)),
+            self.local_type.clone(),
+        )
+    }
+}
+
+/// Project a choreography once for any member of a role family
+///
+/// `foreach i in 0..N { Master -> Worker[i]: Task }` projects for the
+/// member to the body with `Worker[i]` as itself, since exactly one
+/// iteration is its own. Protocols where what a member does depends on
+/// which member it is, such as a message to `Worker[0]` or a loop over
+/// part of the family, are rejected; expand those with `expand_roles`.
+pub fn project_family(
+    choreography: &Choreography,
+    family: &Role,
+) -> Result<FamilyProjection, ProjectionError> {
+    let name = family.name.to_string();
+    let size = match (&family.array_size, family.index) {
+        (Some(size), _) => size.to_string(),
+        (None, Some(size)) => size.to_string(),
+        (None, None) => return Err(ProjectionError::UndeclaredFamily(name)),
+    };
+    let member = Role::new(format_ident!("{}_self", name));
+    let roles: Vec<Role> = choreography
+        .roles
+        .iter()
+        .map(|role| {
+            if role.name == name {
+                member.clone()
+            } else {
+                role.clone()
+            }
+        })
+        .collect();
+    let mut opener = Opener {
+        family: &name,
+        size,
+        member: &member,
+        roles: &roles,
+        index: None,
+    };
+    let protocol = opener.open(&choreography.protocol, None)?;
+    let index = opener.index.unwrap_or_else(|| "i".to_string());
+    let opened = Choreography {
+        name: choreography.name.clone(),
+        roles,
+        protocol,
+        attrs: choreography.attrs.clone(),
+        returns: choreography.returns.clone(),
+        routes: choreography.routes.clone(),
+        wire_ids: choreography.wire_ids.clone(),
+        trusted: choreography.trusted.clone(),
+    };
+    Ok(FamilyProjection {
+        family: family.clone(),
+        index: format_ident!("{}", index),
+        local_type: project(&opened, &member)?,
+    })
+}
+
+/// Rewrites a protocol so that one member of a family is a plain role
+struct Opener<'a> {
+    family: &'a str,
+    /// Declared size of the family
+    size: String,
+    member: &'a Role,
+    roles: &'a [Role],
+    /// Loop variable first used for the member
+    index: Option<String>,
+}
+
+impl Opener<'_> {
+    fn dependent(&self, reason: String) -> ProjectionError {
+        ProjectionError::IndexDependent {
+            role: self.family.to_string(),
+            reason,
+        }
+    }
+
+    /// The member for `Family[var]`, any other role unchanged
+    fn role(&self, role: &Role, var: Option<&str>) -> Result<Role, ProjectionError> {
+        let name = role.name.to_string();
+        if name == self.family {
+            return Err(self.dependent(format!("{} is used without an index", name)));
+        }
+        match family_index(&name, self.family) {
+            Some(index) if Some(index) == var => Ok(self.member.clone()),
+            Some(index) => Err(self.dependent(format!(
+                "{}[{}] may or may not be the projected member",
+                self.family, index
+            ))),
+            None => Ok(role.clone()),
+        }
+    }
+
+    fn open(
+        &mut self,
+        protocol: &Protocol,
+        var: Option<&str>,
+    ) -> Result<Protocol, ProjectionError> {
+        Ok(match protocol {
+            Protocol::Send {
+                from,
+                to,
+                message,
+                continuation,
+            } => Protocol::Send {
+                from: self.role(from, var)?,
+                to: self.role(to, var)?,
+                message: message.clone(),
+                continuation: Box::new(self.open(continuation, var)?),
+            },
+            Protocol::Broadcast {
+                from,
+                to_all,
+                message,
+                abort,
+                continuation,
+            } => {
+                let from = self.role(from, var)?;
+                let mut receivers = Vec::new();
+                for role in to_all {
+                    if role.name != self.family {
+                        receivers.push(self.role(role, var)?);
+                    } else if from == *self.member {
+                        return Err(
+                            self.dependent("a member broadcasts to the rest of the family".into())
+                        );
+                    } else {
+                        receivers.push(self.member.clone());
+                    }
+                }
+                Protocol::Broadcast {
+                    from,
+                    to_all: receivers,
+                    message: message.clone(),
+                    abort: *abort,
+                    continuation: Box::new(self.open(continuation, var)?),
+                }
+            }
+            Protocol::Choice { role, branches } => Protocol::Choice {
+                role: self.role(role, var)?,
+                branches: self.branches(branches, var)?,
+            },
+            Protocol::Race { branches } => Protocol::Race {
+                branches: self.branches(branches, var)?,
+            },
+            Protocol::Loop { condition, body } => {
+                let mut free = Vec::new();
+                collect_references(body, &mut |role| {
+                    let name = role.name.to_string();
+                    if let Some(index) = family_index(&name, self.family) {
+                        if index.parse::<usize>().is_err()
+                            && Some(index) != var
+                            && !free.iter().any(|v| v == index)
+                        {
+                            free.push(index.to_string());
+                        }
+                    }
+                });
+                match (free.as_slice(), var) {
+                    ([], _) => Protocol::Loop {
+                        condition: match condition {
+                            Some(Condition::RoleDecides(role)) => {
+                                Some(Condition::RoleDecides(self.role(role, var)?))
+                            }
+                            other => other.clone(),
+                        },
+                        body: Box::new(self.open(body, var)?),
+                    },
+                    // Exactly one iteration of a loop over the whole family
+                    // is the member's own
+                    ([index], None) if self.covers(condition) => {
+                        self.index.get_or_insert_with(|| index.clone());
+                        self.open(body, Some(index))?
+                    }
+                    ([index, ..], _) => {
+                        return Err(self.dependent(format!(
+                            "the loop using {}[{}] does not run once for every member",
+                            self.family, index
+                        )))
+                    }
+                }
+            }
+            Protocol::Parallel { protocols } => Protocol::Parallel {
+                protocols: protocols
+                    .iter()
+                    .map(|p| self.open(p, var))
+                    .collect::<Result<_, _>>()?,
+            },
+            Protocol::Rec { label, body } => Protocol::Rec {
+                label: label.clone(),
+                body: Box::new(self.open(body, var)?),
+            },
+            Protocol::Finally { body, cleanup } => Protocol::Finally {
+                body: Box::new(self.open(body, var)?),
+                cleanup: Box::new(self.open(cleanup, var)?),
+            },
+            Protocol::TryCatch {
+                body, compensation, ..
+            } => {
+                let body = self.open(body, var)?;
+                let handler_roles = self
+                    .roles
+                    .iter()
+                    .filter(|role| body.mentions_role(role))
+                    .cloned()
+                    .collect();
+                Protocol::TryCatch {
+                    body: Box::new(body),
+                    handler_roles,
+                    compensation: Box::new(self.open(compensation, var)?),
+                }
+            }
+            Protocol::Timeout {
+                role,
+                duration,
+                body,
+                on_timeout,
+            } => Protocol::Timeout {
+                role: self.role(role, var)?,
+                duration: *duration,
+                body: Box::new(self.open(body, var)?),
+                on_timeout: Box::new(self.open(on_timeout, var)?),
+            },
+            Protocol::Spawn {
+                handle,
+                name,
+                roles,
+                body,
+                continuation,
+            } => Protocol::Spawn {
+                handle: handle.clone(),
+                name: name.clone(),
+                roles: roles
+                    .iter()
+                    .map(|role| self.role(role, var))
+                    .collect::<Result<_, _>>()?,
+                body: Box::new(self.open(body, var)?),
+                continuation: Box::new(self.open(continuation, var)?),
+            },
+            Protocol::Await {
+                handle,
+                roles,
+                continuation,
+            } => Protocol::Await {
+                handle: handle.clone(),
+                roles: roles
+                    .iter()
+                    .map(|role| self.role(role, var))
+                    .collect::<Result<_, _>>()?,
+                continuation: Box::new(self.open(continuation, var)?),
+            },
+            Protocol::Var(_) | Protocol::End => protocol.clone(),
+        })
+    }
+
+    fn branches(
+        &mut self,
+        branches: &[Branch],
+        var: Option<&str>,
+    ) -> Result<Vec<Branch>, ProjectionError> {
+        branches
+            .iter()
+            .map(|b| {
+                Ok(Branch {
+                    protocol: self.open(&b.protocol, var)?,
+                    ..b.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Whether a loop runs once per member: `foreach i in 0..N` for `Worker[N]`
+    fn covers(&self, condition: &Option<Condition>) -> bool {
+        match condition {
+            Some(Condition::Custom(count)) => count.to_string() == self.size,
+            Some(Condition::Count(n)) => n.to_string() == self.size,
+            _ => false,
+        }
+    }
+}
+
 /// Errors that can occur during projection
 #[derive(Debug, thiserror::Error)]
 pub enum ProjectionError {
@@ -22,6 +336,25 @@ pub enum ProjectionError {
 
     #[error("Recursive variable {0} not in scope")]
     UnboundVariable(String),
+
+    #[error(
+        "Role {role} is not told which branch was chosen, but behaves differently in branches {} and {}",
+        labels.0,
+        labels.1
+    )]
+    UnmergeableBranches {
+        role: String,
+        labels: (String, String),
+    },
+
+    #[error("Role {role} takes part in a race but cannot tell when arm {label} starts")]
+    UnobservedRaceArm { role: String, label: String },
+
+    #[error("{0} is not a role family")]
+    UndeclaredFamily(String),
+
+    #[error("Members of {role} cannot share one projection: {reason}")]
+    IndexDependent { role: String, reason: String },
 }
 
 /// Context for projection algorithm
@@ -31,11 +364,15 @@ pub enum ProjectionError {
 /// - `rec_env: HashMap<String, LocalType>` for memoizing recursive projections
 struct ProjectionContext<'a> {
     role: &'a Role,
+    /// Sender and message of every abort in the choreography
+    aborts: Vec<(Role, Ident)>,
 }
 
 impl<'a> ProjectionContext<'a> {
-    fn new(_choreography: &'a Choreography, role: &'a Role) -> Self {
-        ProjectionContext { role }
+    fn new(choreography: &'a Choreography, role: &'a Role) -> Self {
+        let mut aborts = Vec::new();
+        collect_aborts(&choreography.protocol, &mut aborts);
+        ProjectionContext { role, aborts }
     }
 
     fn project_protocol(&mut self, protocol: &Protocol) -> Result<LocalType, ProjectionError> {
@@ -52,6 +389,7 @@ impl<'a> ProjectionContext<'a> {
                 to_all,
                 message,
                 continuation,
+                ..
             } => self.project_broadcast(from, to_all, message, continuation),
 
             Protocol::Choice {
@@ -59,6 +397,8 @@ impl<'a> ProjectionContext<'a> {
                 branches,
             } => self.project_choice(choice_role, branches),
 
+            Protocol::Race { branches } => self.project_race(branches),
+
             Protocol::Loop { condition, body } => self.project_loop(condition.as_ref(), body),
 
             Protocol::Parallel { protocols } => self.project_parallel(protocols),
@@ -67,6 +407,27 @@ impl<'a> ProjectionContext<'a> {
 
             Protocol::Var(label) => self.project_var(label),
 
+            Protocol::Finally { body, cleanup } => self.project_finally(body, cleanup),
+
+            Protocol::TryCatch {
+                body,
+                handler_roles,
+                compensation,
+            } => self.project_try_catch(body, handler_roles, compensation),
+
+            Protocol::Timeout {
+                role: timer,
+                duration,
+                body,
+                on_timeout,
+            } => self.project_timeout(timer, *duration, body, on_timeout),
+
+            // A child runs as a session of its own, with its own local
+            // types, so the parent's local type goes straight on
+            Protocol::Spawn { continuation, .. } | Protocol::Await { continuation, .. } => {
+                self.project_protocol(continuation)
+            }
+
             Protocol::End => Ok(LocalType::End),
         }
     }
@@ -178,7 +539,7 @@ impl<'a> ProjectionContext<'a> {
 
             if first_sends && !branches.is_empty() {
                 // Communicated choice - project as Select
-                let mut local_branches = Vec::new();
+                let mut local_branches = Vec::with_capacity(branches.len());
 
                 for branch in branches {
                     // Skip the initial send (it's implied by the choice)
@@ -205,7 +566,7 @@ impl<'a> ProjectionContext<'a> {
                 })
             } else {
                 // Local choice (no communication) - project as LocalChoice
-                let mut local_branches = Vec::new();
+                let mut local_branches = Vec::with_capacity(branches.len());
 
                 for branch in branches {
                     let local_type = self.project_protocol(&branch.protocol)?;
@@ -234,7 +595,7 @@ impl<'a> ProjectionContext<'a> {
             if receives_choice {
                 // We receive the choice - project as Branch
                 let sender = sender.expect("sender must be Some when receives_choice is true");
-                let mut local_branches = Vec::new();
+                let mut local_branches = Vec::with_capacity(branches.len());
 
                 for branch in branches {
                     let local_type = self.project_protocol(&branch.protocol)?;
@@ -252,6 +613,40 @@ impl<'a> ProjectionContext<'a> {
         }
     }
 
+    /// Project a race onto the local type for this role
+    ///
+    /// # Projection Rules
+    /// - If the role sends or receives the first message of every arm:
+    ///   Project to `Race`, each arm starting with that `Send` or `Receive`
+    /// - If it takes part in no first message: Merge the arms, as for a
+    ///   choice the role is not told about
+    /// - Otherwise the role could miss which arm won, which is an error
+    fn project_race(&mut self, branches: &[Branch]) -> Result<LocalType, ProjectionError> {
+        let observes = |branch: &Branch| match &branch.protocol {
+            Protocol::Send { from, to, .. } => self.role == from || self.role == to,
+            _ => false,
+        };
+
+        if !branches.iter().any(observes) {
+            return self.merge_choice_continuations(branches);
+        }
+        if let Some(unobserved) = branches.iter().find(|b| !observes(b)) {
+            return Err(ProjectionError::UnobservedRaceArm {
+                role: self.role.name.to_string(),
+                label: unobserved.label.to_string(),
+            });
+        }
+
+        let mut local_branches = Vec::with_capacity(branches.len());
+        for branch in branches {
+            let local_type = self.project_protocol(&branch.protocol)?;
+            local_branches.push((branch.label.clone(), local_type));
+        }
+        Ok(LocalType::Race {
+            branches: local_branches,
+        })
+    }
+
     /// Project a loop operation onto the local type for this role
     ///
     /// # Projection Rules
@@ -295,7 +690,7 @@ impl<'a> ProjectionContext<'a> {
     /// to the same recipient simultaneously) and provides better error messages.
     fn project_parallel(&mut self, protocols: &[Protocol]) -> Result<LocalType, ProjectionError> {
         // Project all parallel branches for this role
-        let mut projections = Vec::new();
+        let mut projections = Projections::new();
         for protocol in protocols {
             if protocol.mentions_role(self.role) {
                 projections.push(self.project_protocol(protocol)?);
@@ -335,10 +730,10 @@ impl<'a> ProjectionContext<'a> {
     /// The actual execution order is non-deterministic (depends on runtime).
     fn merge_parallel_projections(
         &mut self,
-        projections: Vec<LocalType>,
+        projections: Projections,
     ) -> Result<LocalType, ProjectionError> {
         // Remove End projections as they don't contribute
-        let non_end: Vec<_> = projections
+        let non_end: Projections = projections
             .into_iter()
             .filter(|p| p != &LocalType::End)
             .collect();
@@ -371,34 +766,34 @@ impl<'a> ProjectionContext<'a> {
     /// that cannot be safely interleaved.
     fn check_parallel_conflicts(&self, projections: &[LocalType]) -> Result<(), ProjectionError> {
         // Check for conflicting sends
-        let mut send_targets = Vec::new();
-        let mut recv_sources = Vec::new();
+        let mut send_targets: SmallVec<[&Role; 4]> = SmallVec::new();
+        let mut recv_sources: SmallVec<[&Role; 4]> = SmallVec::new();
 
         for proj in projections {
             match proj {
                 LocalType::Send { to, .. } => {
-                    if send_targets.contains(to) {
+                    if send_targets.contains(&to) {
                         return Err(ProjectionError::InconsistentParallel);
                     }
-                    send_targets.push(to.clone());
+                    send_targets.push(to);
                 }
                 LocalType::Receive { from, .. } => {
-                    if recv_sources.contains(from) {
+                    if recv_sources.contains(&from) {
                         return Err(ProjectionError::InconsistentParallel);
                     }
-                    recv_sources.push(from.clone());
+                    recv_sources.push(from);
                 }
                 LocalType::Select { to, .. } => {
-                    if send_targets.contains(to) {
+                    if send_targets.contains(&to) {
                         return Err(ProjectionError::InconsistentParallel);
                     }
-                    send_targets.push(to.clone());
+                    send_targets.push(to);
                 }
                 LocalType::Branch { from, .. } => {
-                    if recv_sources.contains(from) {
+                    if recv_sources.contains(&from) {
                         return Err(ProjectionError::InconsistentParallel);
                     }
-                    recv_sources.push(from.clone());
+                    recv_sources.push(from);
                 }
                 _ => {
                     // Other types are compatible with parallel composition
@@ -478,38 +873,345 @@ impl<'a> ProjectionContext<'a> {
         Ok(LocalType::Var(label.clone()))
     }
 
+    /// Project a protocol with a cleanup section onto the local type for this role
+    ///
+    /// # Projection Rules
+    /// - If the role takes no part in the cleanup: Project to `body↓role`
+    /// - Otherwise: Project to `Finally(body↓role, cleanup↓role)` so the
+    ///   cleanup is kept distinct from the normal continuation
+    fn project_finally(
+        &mut self,
+        body: &Protocol,
+        cleanup: &Protocol,
+    ) -> Result<LocalType, ProjectionError> {
+        let body_projection = self.project_protocol(body)?;
+        let cleanup_projection = self.project_protocol(cleanup)?;
+
+        if cleanup_projection == LocalType::End {
+            Ok(body_projection)
+        } else {
+            Ok(LocalType::Finally {
+                body: Box::new(body_projection),
+                cleanup: Box::new(cleanup_projection),
+            })
+        }
+    }
+
+    /// Project a protocol with a compensation onto the local type for this role
+    ///
+    /// # Projection Rules
+    /// - If the role is not a handler role: Project to `body↓role`
+    /// - Otherwise: Project to `TryCatch(body↓role, compensation↓role)`,
+    ///   notifying the other handler roles, even if the compensation is
+    ///   empty, so the role still tells its peers when it fails
+    fn project_try_catch(
+        &mut self,
+        body: &Protocol,
+        handler_roles: &[Role],
+        compensation: &Protocol,
+    ) -> Result<LocalType, ProjectionError> {
+        let body_projection = self.project_protocol(body)?;
+        if !handler_roles.contains(self.role) {
+            return Ok(body_projection);
+        }
+        let compensation_projection = self.project_protocol(compensation)?;
+
+        Ok(LocalType::TryCatch {
+            body: Box::new(body_projection),
+            notify: handler_roles
+                .iter()
+                .filter(|r| *r != self.role)
+                .cloned()
+                .collect(),
+            compensation: Box::new(compensation_projection),
+        })
+    }
+
+    /// Project a timeout block onto the local type for this role
+    ///
+    /// # Projection Rules
+    /// - If `role == timer`: Project to `Timeout(body↓role, on_timeout↓role)`,
+    ///   notifying every other role in the block when the timer fires
+    /// - If the role takes part in the block: Project to
+    ///   `TryCatch(body↓role, on_timeout↓role)`, so the timer's abort moves
+    ///   it to the else branch
+    /// - Otherwise: Project to `End`
+    fn project_timeout(
+        &mut self,
+        timer: &Role,
+        duration: std::time::Duration,
+        body: &Protocol,
+        on_timeout: &Protocol,
+    ) -> Result<LocalType, ProjectionError> {
+        let involved = body.mentions_role(self.role) || on_timeout.mentions_role(self.role);
+        if self.role != timer && !involved {
+            return Ok(LocalType::End);
+        }
+        let body_projection = Box::new(self.project_protocol(body)?);
+        let on_timeout_projection = Box::new(self.project_protocol(on_timeout)?);
+
+        if self.role == timer {
+            Ok(LocalType::Timeout {
+                duration,
+                body: body_projection,
+                notify: timeout_peers(timer, body, on_timeout),
+                on_timeout: on_timeout_projection,
+            })
+        } else {
+            Ok(LocalType::TryCatch {
+                body: body_projection,
+                notify: Vec::new(),
+                compensation: on_timeout_projection,
+            })
+        }
+    }
+
+    /// Project a choice this role neither makes nor is told about
+    ///
+    /// The role cannot tell the branches apart, so their projections are
+    /// combined with the merge operator. This fails if the role would have
+    /// to act differently depending on a choice it never learns.
     fn merge_choice_continuations(
         &mut self,
         branches: &[Branch],
     ) -> Result<LocalType, ProjectionError> {
-        // Project each branch and find where we rejoin
-        let mut projections = Vec::new();
+        let mut merged: Option<LocalType> = None;
+        let mut projected: Vec<(&Branch, LocalType)> = Vec::new();
 
         for branch in branches {
-            projections.push(self.project_protocol(&branch.protocol)?);
+            let projection = self.project_protocol(&branch.protocol)?;
+            merged = Some(match merged {
+                None => projection.clone(),
+                Some(merged) => merge(&merged, &projection, &self.aborts).ok_or_else(|| {
+                    // Name an earlier branch this one conflicts with on its
+                    // own, or the last one merged if it only conflicts with
+                    // several of them together
+                    let (earlier, _) = projected
+                        .iter()
+                        .find(|(_, earlier)| merge(earlier, &projection, &self.aborts).is_none())
+                        .or(projected.last())
+                        .expect("an earlier branch was merged");
+                    ProjectionError::UnmergeableBranches {
+                        role: self.role.name.to_string(),
+                        labels: (earlier.label.to_string(), branch.label.to_string()),
+                    }
+                })?,
+            });
+            projected.push((branch, projection));
         }
 
-        // Check if all projections are identical (common case)
-        if projections.windows(2).all(|w| w[0] == w[1]) {
-            Ok(projections
-                .into_iter()
-                .next()
-                .expect("projections must be non-empty when windows returned true"))
-        } else {
-            // Different projections per branch
-            // Find common suffix (merge point) if one exists
-            self.find_merge_point(projections)
+        Ok(merged.unwrap_or(LocalType::End))
+    }
+}
+
+/// Roles the timer of a timeout block tells when it fires
+///
+/// Every role other than the timer that appears in the body or the else
+/// branch, in order of first appearance.
+pub(crate) fn timeout_peers(timer: &Role, body: &Protocol, on_timeout: &Protocol) -> Vec<Role> {
+    let mut peers: Vec<Role> = Vec::new();
+    let mut add = |role: &Role| {
+        if role != timer && !peers.contains(role) {
+            peers.push(role.clone());
         }
+    };
+    for part in [body, on_timeout] {
+        walk_with_paths(part, &mut |_, node| match node {
+            Protocol::Send { from, to, .. } => {
+                add(from);
+                add(to);
+            }
+            Protocol::Broadcast { from, to_all, .. } => {
+                add(from);
+                to_all.iter().for_each(&mut add);
+            }
+            Protocol::Choice { role, .. } | Protocol::Timeout { role, .. } => add(role),
+            Protocol::TryCatch { handler_roles, .. } => handler_roles.iter().for_each(&mut add),
+            Protocol::Spawn { roles, .. } | Protocol::Await { roles, .. } => {
+                roles.iter().for_each(&mut add)
+            }
+            _ => {}
+        });
     }
+    peers
+}
 
-    fn find_merge_point(&self, projections: Vec<LocalType>) -> Result<LocalType, ProjectionError> {
-        // Look for a common continuation across all branches
-        // Use the first non-End projection as representative
-        // Advanced: find least common continuation across all branches
-        Ok(projections
-            .into_iter()
-            .find(|p| p != &LocalType::End)
-            .unwrap_or(LocalType::End))
+/// Merge the projections of two branches for a role not involved in the choice
+///
+/// # Merge Rules
+/// - Equal types merge to themselves
+/// - `Branch` types from the same role merge label-wise: labels in only one
+///   of them are kept, continuations under a shared label are merged
+/// - `Receive` types of the same message from the same role merge their
+///   continuations, as do `Loop` and `Rec` types with the same header
+/// - `Receive` types of different messages from the same role, or a
+///   `Receive` and a `Branch` from it, become a `Branch` with a label per
+///   message, since the message tells the branches apart
+/// - The `Receive` of an abort, one of `aborts` that ends the role, merges
+///   with a `Receive` from another role, or a `Race` of them, into a `Race`
+///   with the abort as its last arm, so the role takes it wherever it waits
+/// - Anything else, notably differing `Send` or `Select` types, cannot be
+///   merged, since the role would need to know which branch was taken
+fn merge(first: &LocalType, second: &LocalType, aborts: &[(Role, Ident)]) -> Option<LocalType> {
+    if first == second {
+        return Some(first.clone());
+    }
+    if let Some(raced) =
+        race_with_abort(first, second, aborts).or_else(|| race_with_abort(second, first, aborts))
+    {
+        return Some(raced);
+    }
+    match (first, second) {
+        (
+            LocalType::Branch {
+                from: from1,
+                branches: br1,
+            },
+            LocalType::Branch {
+                from: from2,
+                branches: br2,
+            },
+        ) if from1 == from2 => {
+            let mut branches = br1.clone();
+            for (label, ty) in br2 {
+                match branches.iter_mut().find(|(l, _)| l == label) {
+                    Some((_, existing)) => *existing = merge(existing, ty, aborts)?,
+                    None => branches.push((label.clone(), ty.clone())),
+                }
+            }
+            Some(LocalType::Branch {
+                from: from1.clone(),
+                branches,
+            })
+        }
+        (
+            LocalType::Receive {
+                from: from1,
+                message: msg1,
+                continuation: cont1,
+            },
+            LocalType::Receive {
+                from: from2,
+                message: msg2,
+                continuation: cont2,
+            },
+        ) if from1 == from2 && msg1.name == msg2.name => Some(LocalType::Receive {
+            from: from1.clone(),
+            message: msg1.clone(),
+            continuation: Box::new(merge(cont1, cont2, aborts)?),
+        }),
+        (LocalType::Receive { from, .. }, LocalType::Receive { from: other, .. })
+        | (LocalType::Receive { from, .. }, LocalType::Branch { from: other, .. })
+        | (LocalType::Branch { from, .. }, LocalType::Receive { from: other, .. })
+            if from == other =>
+        {
+            merge(&by_message(first), &by_message(second), aborts)
+        }
+        (
+            LocalType::Loop {
+                condition: c1,
+                body: b1,
+            },
+            LocalType::Loop {
+                condition: c2,
+                body: b2,
+            },
+        ) if same_condition(c1, c2) => Some(LocalType::Loop {
+            condition: c1.clone(),
+            body: Box::new(merge(b1, b2, aborts)?),
+        }),
+        (
+            LocalType::Rec {
+                label: l1,
+                body: b1,
+            },
+            LocalType::Rec {
+                label: l2,
+                body: b2,
+            },
+        ) if l1 == l2 => Some(LocalType::Rec {
+            label: l1.clone(),
+            body: Box::new(merge(b1, b2, aborts)?),
+        }),
+        _ => None,
+    }
+}
+
+/// `waiting` with `abort` as one more arm to wait on, if `abort` is the
+/// message of one of `aborts` and `waiting` waits on other roles
+///
+/// The aborting branch sends nothing else to the role, so whichever message
+/// comes first tells it which branch was taken.
+fn race_with_abort(
+    abort: &LocalType,
+    waiting: &LocalType,
+    aborts: &[(Role, Ident)],
+) -> Option<LocalType> {
+    let LocalType::Receive {
+        from,
+        message,
+        continuation,
+    } = abort
+    else {
+        return None;
+    };
+    let is_abort = aborts
+        .iter()
+        .any(|(sender, name)| sender == from && *name == message.name);
+    if !is_abort || **continuation != LocalType::End {
+        return None;
+    }
+    let from_another =
+        |arm: &LocalType| matches!(arm, LocalType::Receive { from: sender, .. } if sender != from);
+    let mut arms = match waiting {
+        LocalType::Receive { message: first, .. } if from_another(waiting) => {
+            vec![(first.name.clone(), waiting.clone())]
+        }
+        LocalType::Race { branches } if branches.iter().all(|(_, arm)| from_another(arm)) => {
+            branches.clone()
+        }
+        _ => return None,
+    };
+    if arms.iter().any(|(label, _)| *label == message.name) {
+        return None;
+    }
+    arms.push((message.name.clone(), abort.clone()));
+    Some(LocalType::Race { branches: arms })
+}
+
+/// Add the sender and message of every abort in `protocol` to `aborts`
+fn collect_aborts(protocol: &Protocol, aborts: &mut Vec<(Role, Ident)>) {
+    walk_with_paths(protocol, &mut |_, protocol| {
+        if let Protocol::Broadcast {
+            from,
+            message,
+            abort: true,
+            ..
+        } = protocol
+        {
+            aborts.push((from.clone(), message.name.clone()));
+        }
+    });
+}
+
+/// A `Receive` as a `Branch` labelled by its message
+fn by_message(local_type: &LocalType) -> LocalType {
+    match local_type {
+        LocalType::Receive { from, message, .. } => LocalType::Branch {
+            from: from.clone(),
+            branches: vec![(message.name.clone(), local_type.clone())],
+        },
+        other => other.clone(),
+    }
+}
+
+/// Compare loop conditions structurally
+fn same_condition(c1: &Option<Condition>, c2: &Option<Condition>) -> bool {
+    match (c1, c2) {
+        (None, None) => true,
+        (Some(Condition::Count(n1)), Some(Condition::Count(n2))) => n1 == n2,
+        (Some(Condition::RoleDecides(r1)), Some(Condition::RoleDecides(r2))) => r1 == r2,
+        _ => false,
     }
 }
 
@@ -580,7 +1282,8 @@ impl PartialEq for LocalType {
             (
                 LocalType::LocalChoice { branches: br1 },
                 LocalType::LocalChoice { branches: br2 },
-            ) => {
+            )
+            | (LocalType::Race { branches: br1 }, LocalType::Race { branches: br2 }) => {
                 br1.len() == br2.len()
                     && br1
                         .iter()
@@ -596,22 +1299,7 @@ impl PartialEq for LocalType {
                     condition: c2,
                     body: b2,
                 },
-            ) => {
-                // For conditions, we compare structurally
-                let cond_eq = match (c1, c2) {
-                    (None, None) => true,
-                    (
-                        Some(crate::ast::protocol::Condition::Count(n1)),
-                        Some(crate::ast::protocol::Condition::Count(n2)),
-                    ) => n1 == n2,
-                    (
-                        Some(crate::ast::protocol::Condition::RoleDecides(r1)),
-                        Some(crate::ast::protocol::Condition::RoleDecides(r2)),
-                    ) => r1 == r2,
-                    _ => false,
-                };
-                cond_eq && b1 == b2
-            }
+            ) => same_condition(c1, c2) && b1 == b2,
             (
                 LocalType::Rec {
                     label: l1,
@@ -622,6 +1310,42 @@ impl PartialEq for LocalType {
                     body: b2,
                 },
             ) => l1 == l2 && b1 == b2,
+            (
+                LocalType::Finally {
+                    body: b1,
+                    cleanup: c1,
+                },
+                LocalType::Finally {
+                    body: b2,
+                    cleanup: c2,
+                },
+            ) => b1 == b2 && c1 == c2,
+            (
+                LocalType::TryCatch {
+                    body: b1,
+                    notify: n1,
+                    compensation: c1,
+                },
+                LocalType::TryCatch {
+                    body: b2,
+                    notify: n2,
+                    compensation: c2,
+                },
+            ) => b1 == b2 && n1 == n2 && c1 == c2,
+            (
+                LocalType::Timeout {
+                    duration: d1,
+                    body: b1,
+                    notify: n1,
+                    on_timeout: o1,
+                },
+                LocalType::Timeout {
+                    duration: d2,
+                    body: b2,
+                    notify: n2,
+                    on_timeout: o2,
+                },
+            ) => d1 == d2 && b1 == b2 && n1 == n2 && o1 == o2,
             _ => false,
         }
     }
@@ -0,0 +1,325 @@
+// Links from protocol nodes and generated code back to the DSL source
+
+use crate::ast::{Choreography, Protocol, Role};
+use crate::compiler::parser::ErrorSpan;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Position of a node in a protocol tree
+///
+/// Each element picks a child: the continuation of a send or broadcast is
+/// child 0, branch `i` of a choice or parallel block is child `i`, the body
+/// of a loop or recursion is child 0, and the body and cleanup of a
+/// `finally` protocol, or the body and compensation of a `try`, are
+/// children 0 and 1. The root is the empty path.
+pub type NodePath = Vec<usize>;
+
+/// Source span of every protocol node produced by the parser
+///
+/// Nodes spliced in through `call` point at the protocol definition.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Provenance {
+    spans: BTreeMap<NodePath, ErrorSpan>,
+}
+
+impl Provenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: NodePath, span: ErrorSpan) {
+        self.spans.insert(path, span);
+    }
+
+    /// Span of the statement that produced the node at `path`
+    pub fn span(&self, path: &[usize]) -> Option<&ErrorSpan> {
+        self.spans.get(path)
+    }
+
+    /// All recorded nodes, in path order
+    pub fn iter(&self) -> impl Iterator<Item = (&NodePath, &ErrorSpan)> {
+        self.spans.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// One-line description of where a node came from, e.g.
+    /// ``line 5: `Client -> Server: Request` ``
+    pub fn describe(&self, path: &[usize]) -> Option<String> {
+        self.span(path)
+            .map(|span| format!("line {}: `{}`", span.line, span.snippet.trim()))
+    }
+}
+
+/// Visit every node of a protocol together with its path
+pub fn walk_with_paths(protocol: &Protocol, f: &mut dyn FnMut(&NodePath, &Protocol)) {
+    fn go(protocol: &Protocol, path: &mut NodePath, f: &mut dyn FnMut(&NodePath, &Protocol)) {
+        f(path, protocol);
+        let mut child = |i: usize, p: &Protocol, path: &mut NodePath| {
+            path.push(i);
+            go(p, path, f);
+            path.pop();
+        };
+        match protocol {
+            Protocol::Send { continuation, .. } | Protocol::Broadcast { continuation, .. } => {
+                child(0, continuation, path)
+            }
+            Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+                for (i, branch) in branches.iter().enumerate() {
+                    child(i, &branch.protocol, path);
+                }
+            }
+            Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => child(0, body, path),
+            Protocol::Parallel { protocols } => {
+                for (i, p) in protocols.iter().enumerate() {
+                    child(i, p, path);
+                }
+            }
+            Protocol::Finally { body, cleanup }
+            | Protocol::TryCatch {
+                body,
+                compensation: cleanup,
+                ..
+            }
+            | Protocol::Timeout {
+                body,
+                on_timeout: cleanup,
+                ..
+            } => {
+                child(0, body, path);
+                child(1, cleanup, path);
+            }
+            Protocol::Spawn {
+                body, continuation, ..
+            } => {
+                child(0, continuation, path);
+                child(1, body, path);
+            }
+            Protocol::Await { continuation, .. } => child(0, continuation, path),
+            Protocol::Var(_) | Protocol::End => {}
+        }
+    }
+    go(protocol, &mut Vec::new(), f);
+}
+
+/// Paths of the interactions a role takes part in, in protocol order
+///
+/// Covers sends and receives, broadcasts, and choices the role makes.
+pub fn role_steps(protocol: &Protocol, role: &Role) -> Vec<NodePath> {
+    let mut steps = Vec::new();
+    walk_with_paths(protocol, &mut |path, node| {
+        let involved = match node {
+            Protocol::Send { from, to, .. } => from == role || to == role,
+            Protocol::Broadcast { from, to_all, .. } => from == role || to_all.contains(role),
+            Protocol::Choice { role: chooser, .. } => chooser == role,
+            _ => false,
+        };
+        if involved {
+            steps.push(path.clone());
+        }
+    });
+    steps
+}
+
+/// Machine-readable map from generated items to DSL spans
+///
+/// Built from a choreography and its [`Provenance`]. Serialized as JSON for
+/// editor tooling, and embedded in generated code as `SOURCE_MAP` so a
+/// handler or monitor that sees an unexpected message can report the
+/// statement that defined the step it was waiting for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    pub choreography: String,
+    pub entries: Vec<SourceMapEntry>,
+}
+
+/// One generated item, or one step of a role program, and where it came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceMapEntry {
+    /// Generated item, e.g. `client_program` or a message struct name
+    pub item: String,
+    /// Role whose program contains the step, for role program entries
+    pub role: Option<String>,
+    /// Index of the step in [`role_steps`] order, for role program entries
+    pub step: Option<usize>,
+    /// The interaction, e.g. `Client -> Server: Query`
+    pub interaction: String,
+    pub path: NodePath,
+    pub span: ErrorSpan,
+}
+
+impl SourceMap {
+    pub fn build(choreography: &Choreography, provenance: &Provenance) -> Self {
+        let protocol = &choreography.protocol;
+        let mut entries = Vec::new();
+
+        for role in &choreography.roles {
+            let item = format!("{}_program", role.name.to_string().to_lowercase());
+            for (step, path) in role_steps(protocol, role).into_iter().enumerate() {
+                let (Some(span), Some(node)) = (provenance.span(&path), node_at(protocol, &path))
+                else {
+                    continue;
+                };
+                entries.push(SourceMapEntry {
+                    item: item.clone(),
+                    role: Some(role.name.to_string()),
+                    step: Some(step),
+                    interaction: describe_node(node),
+                    path,
+                    span: span.clone(),
+                });
+            }
+        }
+
+        let mut messages = Vec::new();
+        walk_with_paths(protocol, &mut |path, node| {
+            if let Protocol::Send { message, .. } | Protocol::Broadcast { message, .. } = node {
+                let name = message.name.to_string();
+                if !messages.iter().any(|(m, _, _)| m == &name) {
+                    if let Some(span) = provenance.span(path) {
+                        messages.push((name, path.clone(), span.clone()));
+                    }
+                }
+            }
+        });
+        for (name, path, span) in messages {
+            let interaction = node_at(protocol, &path)
+                .map(describe_node)
+                .unwrap_or_default();
+            entries.push(SourceMapEntry {
+                item: name,
+                role: None,
+                step: None,
+                interaction,
+                path,
+                span,
+            });
+        }
+
+        SourceMap {
+            choreography: choreography.name.to_string(),
+            entries,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Entries for a generated item
+    pub fn item<'a>(&'a self, item: &'a str) -> impl Iterator<Item = &'a SourceMapEntry> + 'a {
+        self.entries.iter().filter(move |e| e.item == item)
+    }
+
+    /// The entry for step `step` of a role's program
+    pub fn step(&self, role: &str, step: usize) -> Option<&SourceMapEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.role.as_deref() == Some(role) && e.step == Some(step))
+    }
+
+    /// The first statement that sends `message` from `from` to `to`
+    pub fn find_interaction(&self, from: &str, to: &str, message: &str) -> Option<&SourceMapEntry> {
+        let send = format!("{} -> {}: {}", from, to, message);
+        let broadcast = format!("{} ->*: {}", from, message);
+        self.entries.iter().find(|e| {
+            e.role.as_deref() == Some(to) && (e.interaction == send || e.interaction == broadcast)
+        })
+    }
+
+    /// Format a runtime error against the DSL statement of an entry
+    pub fn explain(&self, entry: &SourceMapEntry, message: &str) -> String {
+        entry.span.format_error(&format!(
+            "{} (in choreography `{}`)",
+            message, self.choreography
+        ))
+    }
+}
+
+fn node_at<'a>(protocol: &'a Protocol, path: &[usize]) -> Option<&'a Protocol> {
+    let Some((&first, rest)) = path.split_first() else {
+        return Some(protocol);
+    };
+    let child = match protocol {
+        Protocol::Send { continuation, .. } | Protocol::Broadcast { continuation, .. } => {
+            (first == 0).then_some(&**continuation)
+        }
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+            branches.get(first).map(|b| &b.protocol)
+        }
+        Protocol::Loop { body, .. } | Protocol::Rec { body, .. } => (first == 0).then_some(&**body),
+        Protocol::Parallel { protocols } => protocols.get(first),
+        Protocol::Finally { body, cleanup }
+        | Protocol::TryCatch {
+            body,
+            compensation: cleanup,
+            ..
+        }
+        | Protocol::Timeout {
+            body,
+            on_timeout: cleanup,
+            ..
+        } => match first {
+            0 => Some(&**body),
+            1 => Some(&**cleanup),
+            _ => None,
+        },
+        Protocol::Spawn {
+            body, continuation, ..
+        } => match first {
+            0 => Some(&**continuation),
+            1 => Some(&**body),
+            _ => None,
+        },
+        Protocol::Await { continuation, .. } => (first == 0).then_some(&**continuation),
+        Protocol::Var(_) | Protocol::End => None,
+    };
+    node_at(child?, rest)
+}
+
+fn describe_node(node: &Protocol) -> String {
+    match node {
+        Protocol::Send {
+            from, to, message, ..
+        } => format!("{} -> {}: {}", from.name, to.name, message.name),
+        Protocol::Broadcast { from, message, .. } => format!("{} ->*: {}", from.name, message.name),
+        Protocol::Choice { role, branches } => {
+            let labels: Vec<String> = branches.iter().map(|b| b.label.to_string()).collect();
+            format!("choice {} {{ {} }}", role.name, labels.join(" | "))
+        }
+        Protocol::Race { branches } => {
+            let labels: Vec<String> = branches.iter().map(|b| b.label.to_string()).collect();
+            format!("race {{ {} }}", labels.join(" | "))
+        }
+        Protocol::Loop { .. } => "loop".to_string(),
+        Protocol::Parallel { .. } => "parallel".to_string(),
+        Protocol::Rec { label, .. } => format!("rec {}", label),
+        Protocol::Var(label) => format!("continue {}", label),
+        Protocol::Finally { .. } => "finally".to_string(),
+        Protocol::TryCatch { .. } => "try".to_string(),
+        Protocol::Timeout { role, duration, .. } => {
+            format!("timeout({:?}) at {}", duration, role.name)
+        }
+        Protocol::Spawn {
+            handle,
+            name,
+            roles,
+            ..
+        } => {
+            let roles: Vec<String> = roles.iter().map(|r| r.name.to_string()).collect();
+            format!("spawn {}({}) as {}", name, roles.join(", "), handle)
+        }
+        Protocol::Await { handle, .. } => format!("await {}", handle),
+        Protocol::End => "end".to_string(),
+    }
+}
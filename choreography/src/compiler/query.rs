@@ -0,0 +1,360 @@
+// Graph queries over choreographies
+//
+// Tooling and custom lints keep asking the same questions of a protocol:
+// which messages pass between two roles, how a branch can be reached, and
+// who is affected once a message is sent. Answering them by walking the
+// AST means re-implementing control flow each time. The query API builds a
+// control-flow graph of the global protocol once and answers those
+// questions over it.
+//
+// Every message (one node per recipient of a broadcast) and every branch
+// of a choice is a node. Loops and `rec` blocks add back edges. Parallel
+// arms start from the same point and do not follow each other.
+
+use crate::ast::{Choreography, Protocol};
+use crate::compiler::provenance::NodePath;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// One message of the protocol
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Interaction {
+    pub from: String,
+    pub to: String,
+    pub message: String,
+    /// Position of the send in the protocol tree
+    pub path: NodePath,
+}
+
+/// A step along a path through the protocol
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Step {
+    Message(Interaction),
+    /// `role` picks the branch `label`; `path` is the branch body
+    Branch {
+        role: String,
+        label: String,
+        path: NodePath,
+    },
+}
+
+impl Step {
+    /// Message name or branch label
+    pub fn label(&self) -> &str {
+        match self {
+            Step::Message(interaction) => &interaction.message,
+            Step::Branch { label, .. } => label,
+        }
+    }
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Message(i) => write!(f, "{} -> {}: {}", i.from, i.to, i.message),
+            Step::Branch { role, label, .. } => write!(f, "{} chooses {}", role, label),
+        }
+    }
+}
+
+impl Choreography {
+    /// Query the protocol's control-flow graph
+    pub fn query(&self) -> ProtocolQuery {
+        let mut graph = ProtocolQuery {
+            nodes: vec![Node {
+                step: None,
+                successors: Vec::new(),
+            }],
+        };
+        graph.build(
+            &self.protocol,
+            &mut NodePath::new(),
+            vec![START],
+            &mut HashMap::new(),
+        );
+        graph
+    }
+}
+
+const START: usize = 0;
+
+struct Node {
+    /// `None` for the start node and loop heads
+    step: Option<Step>,
+    successors: Vec<usize>,
+}
+
+/// Control-flow graph of a choreography's global protocol
+///
+/// Built by [`Choreography::query`]. Roles are named as written in the
+/// choreography, e.g. `Worker[0]`.
+pub struct ProtocolQuery {
+    nodes: Vec<Node>,
+}
+
+impl ProtocolQuery {
+    /// Every message, in protocol order
+    pub fn interactions(&self) -> Vec<&Interaction> {
+        self.nodes
+            .iter()
+            .filter_map(|node| match &node.step {
+                Some(Step::Message(interaction)) => Some(interaction),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Messages sent from `a` to `b` or from `b` to `a`, in protocol order
+    pub fn messages_between(&self, a: &str, b: &str) -> Vec<&Interaction> {
+        self.interactions()
+            .into_iter()
+            .filter(|i| (i.from == a && i.to == b) || (i.from == b && i.to == a))
+            .collect()
+    }
+
+    /// Every way to get from the start of the protocol to a branch or
+    /// message named `label`
+    ///
+    /// Each path lists the messages and branch choices on the way, ending
+    /// with the step that matched. Loops are followed at most once per
+    /// path. The number of paths grows with the number of choices before
+    /// the target.
+    pub fn paths_reaching(&self, label: &str) -> Vec<Vec<Step>> {
+        let mut paths = Vec::new();
+        let mut seen = HashSet::new();
+        let mut on_path = vec![false; self.nodes.len()];
+        let mut steps = Vec::new();
+        self.paths_from(
+            START,
+            label,
+            &mut on_path,
+            &mut steps,
+            &mut paths,
+            &mut seen,
+        );
+        paths
+    }
+
+    fn paths_from(
+        &self,
+        node: usize,
+        label: &str,
+        on_path: &mut [bool],
+        steps: &mut Vec<Step>,
+        paths: &mut Vec<Vec<Step>>,
+        seen: &mut HashSet<Vec<Step>>,
+    ) {
+        on_path[node] = true;
+        let step = self.nodes[node].step.clone();
+        let pushed = step.is_some();
+        if let Some(step) = step {
+            let matched = step.label() == label;
+            steps.push(step);
+            if matched && seen.insert(steps.clone()) {
+                paths.push(steps.clone());
+            }
+        }
+        for &next in &self.nodes[node].successors {
+            if !on_path[next] {
+                self.paths_from(next, label, on_path, steps, paths, seen);
+            }
+        }
+        if pushed {
+            steps.pop();
+        }
+        on_path[node] = false;
+    }
+
+    /// Roles that can be affected by `message` once it is sent
+    ///
+    /// The receiver is affected, and so is every role that later receives a
+    /// message from an affected role, including in later loop iterations.
+    pub fn roles_downstream_of(&self, message: &str) -> BTreeSet<String> {
+        let mut affected: Vec<BTreeSet<String>> = vec![BTreeSet::new(); self.nodes.len()];
+        let mut work = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(Step::Message(interaction)) = &node.step {
+                if interaction.message == message {
+                    affected[i].insert(interaction.to.clone());
+                    work.push(i);
+                }
+            }
+        }
+
+        while let Some(node) = work.pop() {
+            for &next in &self.nodes[node].successors {
+                let mut roles = affected[node].clone();
+                if let Some(Step::Message(interaction)) = &self.nodes[next].step {
+                    if roles.contains(&interaction.from) {
+                        roles.insert(interaction.to.clone());
+                    }
+                }
+                let before = affected[next].len();
+                affected[next].extend(roles);
+                if affected[next].len() > before {
+                    work.push(next);
+                }
+            }
+        }
+        affected.into_iter().flatten().collect()
+    }
+
+    fn add(&mut self, step: Option<Step>, predecessors: &[usize]) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            step,
+            successors: Vec::new(),
+        });
+        for &p in predecessors {
+            self.nodes[p].successors.push(node);
+        }
+        node
+    }
+
+    fn child(
+        &mut self,
+        path: &mut NodePath,
+        index: usize,
+        protocol: &Protocol,
+        predecessors: Vec<usize>,
+        recs: &mut HashMap<String, usize>,
+    ) -> Vec<usize> {
+        path.push(index);
+        let exits = self.build(protocol, path, predecessors, recs);
+        path.pop();
+        exits
+    }
+
+    /// Add the nodes of `protocol` after `predecessors` and return the nodes
+    /// control leaves it from
+    fn build(
+        &mut self,
+        protocol: &Protocol,
+        path: &mut NodePath,
+        predecessors: Vec<usize>,
+        recs: &mut HashMap<String, usize>,
+    ) -> Vec<usize> {
+        match protocol {
+            Protocol::Send {
+                from,
+                to,
+                message,
+                continuation,
+            } => {
+                let node = self.add(
+                    Some(Step::Message(Interaction {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        message: message.name.to_string(),
+                        path: path.clone(),
+                    })),
+                    &predecessors,
+                );
+                self.child(path, 0, continuation, vec![node], recs)
+            }
+            Protocol::Broadcast {
+                from,
+                to_all,
+                message,
+                continuation,
+                ..
+            } => {
+                let mut last = predecessors;
+                for to in to_all {
+                    let node = self.add(
+                        Some(Step::Message(Interaction {
+                            from: from.to_string(),
+                            to: to.to_string(),
+                            message: message.name.to_string(),
+                            path: path.clone(),
+                        })),
+                        &last,
+                    );
+                    last = vec![node];
+                }
+                self.child(path, 0, continuation, last, recs)
+            }
+            Protocol::Choice { role, branches } => {
+                let mut exits = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    let mut branch_path = path.clone();
+                    branch_path.push(i);
+                    let node = self.add(
+                        Some(Step::Branch {
+                            role: role.to_string(),
+                            label: branch.label.to_string(),
+                            path: branch_path,
+                        }),
+                        &predecessors,
+                    );
+                    exits.extend(self.child(path, i, &branch.protocol, vec![node], recs));
+                }
+                exits
+            }
+            Protocol::Race { branches } => {
+                // Each arm starts with its own message, so there is no
+                // separate branch step
+                let mut exits = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    exits.extend(self.child(path, i, &branch.protocol, predecessors.clone(), recs));
+                }
+                exits
+            }
+            Protocol::Loop { body, .. } => {
+                let head = self.add(None, &predecessors);
+                for exit in self.child(path, 0, body, vec![head], recs) {
+                    self.nodes[exit].successors.push(head);
+                }
+                vec![head]
+            }
+            Protocol::Rec { label, body } => {
+                let head = self.add(None, &predecessors);
+                let outer = recs.insert(label.to_string(), head);
+                let exits = self.child(path, 0, body, vec![head], recs);
+                match outer {
+                    Some(outer) => recs.insert(label.to_string(), outer),
+                    None => recs.remove(&label.to_string()),
+                };
+                exits
+            }
+            Protocol::Var(label) => {
+                if let Some(&head) = recs.get(&label.to_string()) {
+                    for p in predecessors {
+                        self.nodes[p].successors.push(head);
+                    }
+                }
+                Vec::new()
+            }
+            Protocol::Parallel { protocols } => {
+                let mut exits = Vec::new();
+                for (i, arm) in protocols.iter().enumerate() {
+                    exits.extend(self.child(path, i, arm, predecessors.clone(), recs));
+                }
+                exits
+            }
+            Protocol::Finally { body, cleanup } => {
+                let exits = self.child(path, 0, body, predecessors, recs);
+                self.child(path, 1, cleanup, exits, recs)
+            }
+            // The compensation may start anywhere in the body; it is
+            // treated as an alternative to it, as is the else branch of a
+            // timeout
+            Protocol::TryCatch {
+                body, compensation, ..
+            }
+            | Protocol::Timeout {
+                body,
+                on_timeout: compensation,
+                ..
+            } => {
+                let mut exits = self.child(path, 0, body, predecessors.clone(), recs);
+                exits.extend(self.child(path, 1, compensation, predecessors, recs));
+                exits
+            }
+            // Children are sessions of their own and are queried separately
+            Protocol::Spawn { continuation, .. } | Protocol::Await { continuation, .. } => {
+                self.child(path, 0, continuation, predecessors, recs)
+            }
+            Protocol::End => predecessors,
+        }
+    }
+}
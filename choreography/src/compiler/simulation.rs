@@ -0,0 +1,651 @@
+// Monte Carlo simulation of choreographies
+//
+// Capacity planning needs numbers before there is an implementation: how
+// many messages a session sends, how many sequential hops it waits on, and
+// how long it takes at the tail. The simulator walks the global protocol
+// many times, picking branches by their `@weight(...)` annotations and
+// delaying each message by the latency model. Each role keeps its own
+// clock: a send leaves at the sender's time and moves the receiver's clock
+// to the arrival time if that is later. Latencies are drawn per edge from
+// constant, uniform, or log-normal distributions, and bandwidth limits make
+// messages on an edge queue behind each other, so deadlines and receive
+// timeouts can be checked against realistic network conditions before any
+// code runs. Runs are seeded, so the same configuration always produces the
+// same report.
+
+use crate::ast::{Branch, Choreography, Condition, Protocol, Role};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+/// Distribution of one message's network delay
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Constant(Duration),
+    /// Uniform between `min` and `max`
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Log-normal around `median`; `sigma` is the standard deviation of the
+    /// underlying normal distribution, so larger values give longer tails
+    LogNormal {
+        median: Duration,
+        sigma: f64,
+    },
+}
+
+impl From<Duration> for Latency {
+    fn from(latency: Duration) -> Self {
+        Latency::Constant(latency)
+    }
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut SplitMix64) -> Duration {
+        match *self {
+            Latency::Constant(latency) => latency,
+            Latency::Uniform { min, max } => {
+                let (min, max) = (min.min(max), min.max(max));
+                min + (max - min).mul_f64(rng.next_f64())
+            }
+            Latency::LogNormal { median, sigma } => {
+                median.mul_f64((sigma.max(0.0) * rng.next_normal()).exp().min(1e6))
+            }
+        }
+    }
+}
+
+/// Network conditions, per sender and receiver
+///
+/// Each edge has a latency distribution and an optional bandwidth limit.
+/// On a limited edge a message occupies the link for its size divided by
+/// the bandwidth, and later messages on that edge queue behind it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyModel {
+    default: Latency,
+    edges: BTreeMap<(String, String), Latency>,
+    bandwidth: BTreeMap<(String, String), u64>,
+    default_bandwidth: Option<u64>,
+    message_sizes: BTreeMap<String, u64>,
+    default_message_size: u64,
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        Self::constant(Duration::from_millis(1))
+    }
+}
+
+impl LatencyModel {
+    /// Every message takes `latency`
+    pub fn constant(latency: Duration) -> Self {
+        Self::new(Latency::Constant(latency))
+    }
+
+    /// Every message's delay is drawn from `latency`
+    pub fn new(latency: Latency) -> Self {
+        Self {
+            default: latency,
+            edges: BTreeMap::new(),
+            bandwidth: BTreeMap::new(),
+            default_bandwidth: None,
+            message_sizes: BTreeMap::new(),
+            default_message_size: 256,
+        }
+    }
+
+    /// Messages from `from` to `to` are delayed by `latency`
+    ///
+    /// Roles are named as written in the choreography, e.g. `Worker[0]`.
+    pub fn with_edge(
+        mut self,
+        from: impl Into<String>,
+        to: impl Into<String>,
+        latency: impl Into<Latency>,
+    ) -> Self {
+        self.edges.insert((from.into(), to.into()), latency.into());
+        self
+    }
+
+    /// Limit the edge from `from` to `to` to `bytes_per_sec`
+    pub fn with_bandwidth(
+        mut self,
+        from: impl Into<String>,
+        to: impl Into<String>,
+        bytes_per_sec: u64,
+    ) -> Self {
+        self.bandwidth
+            .insert((from.into(), to.into()), bytes_per_sec.max(1));
+        self
+    }
+
+    /// Limit every edge without its own limit to `bytes_per_sec`
+    pub fn with_default_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.default_bandwidth = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Size of the message named `message`, for bandwidth limits
+    pub fn with_message_size(mut self, message: impl Into<String>, bytes: u64) -> Self {
+        self.message_sizes.insert(message.into(), bytes);
+        self
+    }
+
+    /// Size of messages without their own size; 256 bytes unless set
+    pub fn with_default_message_size(mut self, bytes: u64) -> Self {
+        self.default_message_size = bytes;
+        self
+    }
+
+    fn latency(&self, edge: &(String, String)) -> &Latency {
+        self.edges.get(edge).unwrap_or(&self.default)
+    }
+
+    /// Time `message` occupies the edge, if the edge is limited
+    fn transmission(&self, edge: &(String, String), message: &str) -> Option<Duration> {
+        let bandwidth = self
+            .bandwidth
+            .get(edge)
+            .copied()
+            .or(self.default_bandwidth)?;
+        let size = self
+            .message_sizes
+            .get(message)
+            .copied()
+            .unwrap_or(self.default_message_size);
+        Some(Duration::from_secs_f64(size as f64 / bandwidth as f64))
+    }
+}
+
+/// Settings for [`simulate`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    /// Number of protocol runs
+    pub runs: usize,
+    /// Seed for branch selection and latency sampling
+    pub seed: u64,
+    pub latency: LatencyModel,
+    /// Iterations of loops without a fixed count
+    pub loop_iterations: usize,
+    /// Times a run may re-enter a `rec` block before it is cut short
+    pub max_recursion: usize,
+    /// Time a whole session is allowed to take
+    pub deadline: Option<Duration>,
+    /// Time a role is allowed to wait for a single message
+    pub receive_timeout: Option<Duration>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            runs: 10_000,
+            seed: 0,
+            latency: LatencyModel::default(),
+            loop_iterations: 1,
+            max_recursion: 100,
+            deadline: None,
+            receive_timeout: None,
+        }
+    }
+}
+
+impl SimulationConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_runs(mut self, runs: usize) -> Self {
+        self.runs = runs.max(1);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_latency(mut self, latency: LatencyModel) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_loop_iterations(mut self, iterations: usize) -> Self {
+        self.loop_iterations = iterations;
+        self
+    }
+
+    pub fn with_max_recursion(mut self, depth: usize) -> Self {
+        self.max_recursion = depth;
+        self
+    }
+
+    /// Count runs whose completion time exceeds `deadline`
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Count messages a role waits on for longer than `timeout`
+    ///
+    /// The wait starts once the receiver has finished everything before the
+    /// receive, which is when a receive with a timeout would start its timer.
+    pub fn with_receive_timeout(mut self, timeout: Duration) -> Self {
+        self.receive_timeout = Some(timeout);
+        self
+    }
+}
+
+/// Distribution of session completion times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencySummary {
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Estimates over all simulated runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationReport {
+    pub runs: usize,
+    /// Messages per session, on average
+    pub mean_messages: f64,
+    /// Most messages any run sent
+    pub max_messages: usize,
+    /// Messages per session on each sender and receiver pair, on average
+    pub edges: BTreeMap<(String, String), f64>,
+    /// Half the longest causal chain of messages, on average
+    pub mean_round_trips: f64,
+    /// Time until the last role finishes
+    pub latency: LatencySummary,
+    /// Times each `Role.label` branch was taken per session, on average
+    pub branches: BTreeMap<String, f64>,
+    /// Runs cut short by the recursion limit
+    pub truncated: usize,
+    /// Fraction of runs that finished after the configured deadline
+    pub deadline_misses: f64,
+    /// Receives per session that waited longer than the configured timeout,
+    /// on average
+    pub receive_timeouts: f64,
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "runs: {}", self.runs)?;
+        writeln!(
+            f,
+            "messages: {:.2} mean, {} max",
+            self.mean_messages, self.max_messages
+        )?;
+        for ((from, to), count) in &self.edges {
+            writeln!(f, "  {} -> {}: {:.2}", from, to, count)?;
+        }
+        writeln!(f, "round trips: {:.2}", self.mean_round_trips)?;
+        let l = &self.latency;
+        writeln!(
+            f,
+            "latency: mean {:?}, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            l.mean, l.p50, l.p95, l.p99, l.max
+        )?;
+        for (branch, count) in &self.branches {
+            writeln!(f, "  {}: {:.3}", branch, count)?;
+        }
+        if self.deadline_misses > 0.0 {
+            writeln!(f, "deadline misses: {:.2}%", self.deadline_misses * 100.0)?;
+        }
+        if self.receive_timeouts > 0.0 {
+            writeln!(f, "receive timeouts: {:.3}", self.receive_timeouts)?;
+        }
+        if self.truncated > 0 {
+            writeln!(f, "truncated runs: {}", self.truncated)?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimate the cost of a choreography by running it many times
+///
+/// Branches without a weight share whatever probability the weighted
+/// branches of the same choice leave over, or count as weight 1 when no
+/// branch has one. Weights are relative, so `@weight(9)` and `@weight(1)`
+/// mean the same as `@weight(0.9)` and `@weight(0.1)`. Parallel arms start
+/// at the same time. Edge bandwidth is shared by every message on the edge
+/// within a run, including messages from parallel arms.
+pub fn simulate(choreography: &Choreography, config: &SimulationConfig) -> SimulationReport {
+    let runs = config.runs.max(1);
+    let mut rng = SplitMix64(config.seed);
+    let mut totals = Totals::default();
+
+    for _ in 0..runs {
+        let mut run = Run {
+            config,
+            rng: &mut rng,
+            clocks: HashMap::new(),
+            hops: HashMap::new(),
+            links: HashMap::new(),
+            receive_timeouts: 0,
+            recs: HashMap::new(),
+            children: HashMap::new(),
+            recursion: 0,
+            truncated: false,
+            messages: 0,
+            edges: BTreeMap::new(),
+            branches: BTreeMap::new(),
+        };
+        run.walk(&choreography.protocol);
+
+        totals.messages += run.messages;
+        totals.max_messages = totals.max_messages.max(run.messages);
+        totals.hops += run.hops.values().copied().max().unwrap_or(0);
+        totals.truncated += usize::from(run.truncated);
+        totals.receive_timeouts += run.receive_timeouts;
+        for (edge, count) in run.edges {
+            *totals.edges.entry(edge).or_default() += count;
+        }
+        for (branch, count) in run.branches {
+            *totals.branches.entry(branch).or_default() += count;
+        }
+        let duration = run.clocks.values().copied().max().unwrap_or_default();
+        if config.deadline.is_some_and(|deadline| duration > deadline) {
+            totals.deadline_misses += 1;
+        }
+        totals.durations.push(duration);
+    }
+
+    let mean = |total: usize| total as f64 / runs as f64;
+    SimulationReport {
+        runs,
+        mean_messages: mean(totals.messages),
+        max_messages: totals.max_messages,
+        edges: totals
+            .edges
+            .into_iter()
+            .map(|(edge, count)| (edge, mean(count)))
+            .collect(),
+        mean_round_trips: mean(totals.hops) / 2.0,
+        latency: summarize(totals.durations),
+        branches: totals
+            .branches
+            .into_iter()
+            .map(|(branch, count)| (branch, mean(count)))
+            .collect(),
+        truncated: totals.truncated,
+        deadline_misses: mean(totals.deadline_misses),
+        receive_timeouts: mean(totals.receive_timeouts),
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    messages: usize,
+    max_messages: usize,
+    hops: usize,
+    truncated: usize,
+    deadline_misses: usize,
+    receive_timeouts: usize,
+    edges: BTreeMap<(String, String), usize>,
+    branches: BTreeMap<String, usize>,
+    durations: Vec<Duration>,
+}
+
+fn summarize(mut durations: Vec<Duration>) -> LatencySummary {
+    if durations.is_empty() {
+        return LatencySummary::default();
+    }
+    durations.sort();
+    let at = |q: f64| durations[((durations.len() - 1) as f64 * q).round() as usize];
+    LatencySummary {
+        mean: durations.iter().sum::<Duration>() / durations.len() as u32,
+        p50: at(0.5),
+        p95: at(0.95),
+        p99: at(0.99),
+        max: durations[durations.len() - 1],
+    }
+}
+
+/// State of one simulated run
+struct Run<'a, 'r> {
+    config: &'a SimulationConfig,
+    rng: &'r mut SplitMix64,
+    /// Time at which each role has finished everything so far
+    clocks: HashMap<String, Duration>,
+    /// Length of the longest message chain that led to each role
+    hops: HashMap<String, usize>,
+    /// Time at which each bandwidth-limited edge is free again
+    links: HashMap<(String, String), Duration>,
+    receive_timeouts: usize,
+    /// Bodies of the enclosing `rec` blocks
+    recs: HashMap<String, &'a Protocol>,
+    /// Clocks at the end of each spawned child not awaited yet
+    children: HashMap<String, HashMap<String, Duration>>,
+    recursion: usize,
+    truncated: bool,
+    messages: usize,
+    edges: BTreeMap<(String, String), usize>,
+    branches: BTreeMap<String, usize>,
+}
+
+impl<'a> Run<'a, '_> {
+    fn walk(&mut self, protocol: &'a Protocol) {
+        match protocol {
+            Protocol::Send {
+                from,
+                to,
+                message,
+                continuation,
+            } => {
+                self.message(from, to, &message.name.to_string());
+                self.walk(continuation);
+            }
+            Protocol::Broadcast {
+                from,
+                to_all,
+                message,
+                continuation,
+                ..
+            } => {
+                let message = message.name.to_string();
+                for to in to_all {
+                    self.message(from, to, &message);
+                }
+                self.walk(continuation);
+            }
+            Protocol::Choice { role, branches } => {
+                if branches.is_empty() {
+                    return;
+                }
+                let branch = &branches[self.pick(branches)];
+                *self
+                    .branches
+                    .entry(format!("{}.{}", role, branch.label))
+                    .or_default() += 1;
+                self.walk(&branch.protocol);
+            }
+            Protocol::Race { branches } => {
+                if branches.is_empty() {
+                    return;
+                }
+                let branch = &branches[self.pick(branches)];
+                *self
+                    .branches
+                    .entry(format!("race.{}", branch.label))
+                    .or_default() += 1;
+                self.walk(&branch.protocol);
+            }
+            Protocol::Loop { condition, body } => {
+                let iterations = match condition {
+                    Some(Condition::Count(n)) => *n,
+                    _ => self.config.loop_iterations,
+                };
+                for _ in 0..iterations {
+                    self.walk(body);
+                }
+            }
+            Protocol::Parallel { protocols } => {
+                let start = (self.clocks.clone(), self.hops.clone());
+                let mut clocks = start.0.clone();
+                let mut hops = start.1.clone();
+                for arm in protocols {
+                    self.clocks = start.0.clone();
+                    self.hops = start.1.clone();
+                    self.walk(arm);
+                    merge_max(&mut clocks, &self.clocks);
+                    merge_max(&mut hops, &self.hops);
+                }
+                self.clocks = clocks;
+                self.hops = hops;
+            }
+            Protocol::Rec { label, body } => {
+                let previous = self.recs.insert(label.to_string(), body);
+                self.walk(body);
+                match previous {
+                    Some(outer) => self.recs.insert(label.to_string(), outer),
+                    None => self.recs.remove(&label.to_string()),
+                };
+            }
+            Protocol::Var(label) => {
+                let Some(body) = self.recs.get(&label.to_string()).copied() else {
+                    return;
+                };
+                if self.recursion >= self.config.max_recursion {
+                    self.truncated = true;
+                    return;
+                }
+                self.recursion += 1;
+                self.walk(body);
+            }
+            Protocol::Finally { body, cleanup } => {
+                self.walk(body);
+                self.walk(cleanup);
+            }
+            // Runs follow the protocol without errors or delays, so neither
+            // the compensation nor the else branch of a timeout runs
+            Protocol::TryCatch { body, .. } | Protocol::Timeout { body, .. } => self.walk(body),
+            Protocol::Spawn {
+                handle,
+                body,
+                continuation,
+                ..
+            } => {
+                // The child runs alongside the parent from this point, and
+                // only holds the parent up where it is awaited
+                let start = self.clocks.clone();
+                self.walk(body);
+                let child = std::mem::replace(&mut self.clocks, start);
+                self.children.insert(handle.to_string(), child);
+                self.walk(continuation);
+            }
+            Protocol::Await {
+                handle,
+                continuation,
+                ..
+            } => {
+                if let Some(child) = self.children.remove(&handle.to_string()) {
+                    merge_max(&mut self.clocks, &child);
+                }
+                self.walk(continuation);
+            }
+            Protocol::End => {}
+        }
+    }
+
+    fn message(&mut self, from: &Role, to: &Role, message: &str) {
+        let edge = (from.to_string(), to.to_string());
+        let model = &self.config.latency;
+        let mut sent = self.clocks.get(&edge.0).copied().unwrap_or_default();
+        if let Some(transmission) = model.transmission(&edge, message) {
+            let link = self.links.entry(edge.clone()).or_default();
+            sent = sent.max(*link) + transmission;
+            *link = sent;
+        }
+        let arrival = sent + model.latency(&edge).sample(self.rng);
+
+        let clock = self.clocks.entry(edge.1.clone()).or_default();
+        if self
+            .config
+            .receive_timeout
+            .is_some_and(|timeout| arrival > *clock + timeout)
+        {
+            self.receive_timeouts += 1;
+        }
+        *clock = (*clock).max(arrival);
+        let (from, to) = edge;
+
+        let hops = self.hops.get(&from).copied().unwrap_or(0) + 1;
+        let entry = self.hops.entry(to.clone()).or_default();
+        *entry = (*entry).max(hops);
+
+        self.messages += 1;
+        *self.edges.entry((from, to)).or_default() += 1;
+    }
+
+    /// Index of the branch to take, drawn by weight
+    fn pick(&mut self, branches: &[Branch]) -> usize {
+        let weights = branch_weights(branches);
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return (self.rng.next_f64() * branches.len() as f64) as usize % branches.len();
+        }
+        let mut target = self.rng.next_f64() * total;
+        for (i, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                return i;
+            }
+            target -= weight;
+        }
+        weights.iter().rposition(|w| *w > 0.0).unwrap_or(0)
+    }
+}
+
+/// Weight of every branch, filling in the unweighted ones
+fn branch_weights(branches: &[Branch]) -> Vec<f64> {
+    let given: f64 = branches.iter().filter_map(|b| b.weight).sum();
+    let unweighted = branches.iter().filter(|b| b.weight.is_none()).count();
+    let fill = if unweighted == 0 {
+        0.0
+    } else if unweighted == branches.len() {
+        1.0
+    } else {
+        (1.0 - given).max(0.0) / unweighted as f64
+    };
+    branches.iter().map(|b| b.weight.unwrap_or(fill)).collect()
+}
+
+fn merge_max<T: Ord + Copy>(into: &mut HashMap<String, T>, from: &HashMap<String, T>) {
+    for (role, value) in from {
+        let entry = into.entry(role.clone()).or_insert(*value);
+        *entry = (*entry).max(*value);
+    }
+}
+
+/// Small deterministic generator, so reports do not depend on `rand`
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `0..n`, for `n` above zero
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Standard normal, by the Box-Muller transform
+    fn next_normal(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
@@ -0,0 +1,577 @@
+// Wire-compatibility test kits for implementations in other languages
+//
+// A kit describes one choreography as the Rust reference puts it on the
+// wire: every message with a sample encoding, and for every role the
+// sequences of sends, receives, and branch labels its generated program
+// can go through. A team implementing one role elsewhere checks its
+// encoder against the samples, and runs the kit's driver, which plays
+// every other role over WebSockets and reports the first step where the
+// implementation strays from its sequences.
+//
+// Sequences follow the generated programs rather than the local types:
+// a choice sends its label to every other role before the branch runs,
+// a loop without a count runs once, a race is a choice by the role
+// starting its first arm, and parallel arms run in order. Failure paths of
+// `try` and `timeout` blocks are left out, as are spawned children, which
+// run on sessions of their own.
+
+use crate::ast::{Branch, Choreography, Condition, Protocol, Role, WireFormat};
+use crate::compiler::namespace::snake_case;
+use crate::compiler::vectors::{message_samples, Sample};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use thiserror::Error;
+
+/// Most traces kept for one role; sequences past it are dropped
+pub const MAX_TRACES: usize = 256;
+
+/// Times a `rec` body is entered again from one of its `continue`s
+const REPEATS: usize = 1;
+
+/// Errors while generating, writing, or reading a test kit
+#[derive(Debug, Error)]
+pub enum TestKitError {
+    #[error("{0} is not a role of the choreography")]
+    UnknownRole(String),
+
+    #[error("test kit I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed test kit file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Conformance kit for implementing one role of a choreography
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestKit {
+    /// Name of the choreography
+    pub choreography: String,
+    /// The role implemented outside the reference
+    pub role: String,
+    /// Every role, in declaration order
+    pub roles: Vec<String>,
+    /// `bincode`, or `protobuf` under `@wire(protobuf)`
+    pub wire: String,
+    /// Messages in the order of the generated `Message` enum
+    #[serde(skip)]
+    pub messages: Vec<KitMessage>,
+    /// Sequences of every role, keyed by role name
+    #[serde(skip)]
+    pub sequences: BTreeMap<String, RoleSequences>,
+}
+
+/// A message type with the bytes the reference sends for it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KitMessage {
+    pub name: String,
+    /// Variant index in the generated `Message` enum
+    pub index: u32,
+    /// Payload as written in the choreography, or the type inferred for it
+    pub payload: String,
+    /// The default payload the reference sends, as JSON
+    pub sample: Value,
+    /// Hex of the bincode-encoded `Message` carrying `sample`; `None` when
+    /// the payload type is not one the kit can encode
+    pub sample_hex: Option<String>,
+}
+
+/// Every sequence of steps a role can go through
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoleSequences {
+    /// Whether sequences were dropped, for repeating past `REPEATS` or
+    /// going over `MAX_TRACES`
+    pub truncated: bool,
+    pub traces: Vec<Vec<KitStep>>,
+}
+
+/// One step of a role, from its own point of view
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum KitStep {
+    Send { peer: String, message: String },
+    Recv { peer: String, message: String },
+    Choose { peer: String, label: String },
+    Offer { peer: String, label: String },
+}
+
+impl KitStep {
+    pub fn peer(&self) -> &str {
+        match self {
+            KitStep::Send { peer, .. }
+            | KitStep::Recv { peer, .. }
+            | KitStep::Choose { peer, .. }
+            | KitStep::Offer { peer, .. } => peer,
+        }
+    }
+
+    /// Whether the step waits for something from its peer
+    pub fn is_inbound(&self) -> bool {
+        matches!(self, KitStep::Recv { .. } | KitStep::Offer { .. })
+    }
+}
+
+impl std::fmt::Display for KitStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KitStep::Send { peer, message } => write!(f, "-> {}: {}", peer, message),
+            KitStep::Recv { peer, message } => write!(f, "<- {}: {}", peer, message),
+            KitStep::Choose { peer, label } => write!(f, "-> {}: label {}", peer, label),
+            KitStep::Offer { peer, label } => write!(f, "<- {}: label {}", peer, label),
+        }
+    }
+}
+
+/// Build the test kit for implementing `role` of a choreography
+pub fn generate_test_kit(
+    choreography: &Choreography,
+    role: &Role,
+) -> Result<TestKit, TestKitError> {
+    if !choreography.roles.contains(role) {
+        return Err(TestKitError::UnknownRole(role.name.to_string()));
+    }
+    let wire = choreography.wire_format();
+    let sequences = choreography
+        .roles
+        .iter()
+        .map(|r| (r.name.to_string(), role_sequences(choreography, r)))
+        .collect();
+
+    Ok(TestKit {
+        choreography: choreography.name.to_string(),
+        role: role.name.to_string(),
+        roles: choreography
+            .roles
+            .iter()
+            .map(|r| r.name.to_string())
+            .collect(),
+        wire: match wire {
+            WireFormat::Bincode => "bincode",
+            WireFormat::Protobuf => "protobuf",
+        }
+        .to_string(),
+        messages: kit_messages(&choreography.protocol, wire),
+        sequences,
+    })
+}
+
+impl TestKit {
+    /// Sequences of a role
+    pub fn sequences(&self, role: &str) -> Option<&RoleSequences> {
+        self.sequences.get(role)
+    }
+
+    pub fn message(&self, name: &str) -> Option<&KitMessage> {
+        self.messages.iter().find(|m| m.name == name)
+    }
+
+    /// Roles the implementation exchanges anything with, which are the
+    /// ones it opens a WebSocket to
+    pub fn peers(&self) -> Vec<String> {
+        let mut peers: Vec<String> = Vec::new();
+        for step in self
+            .sequences(&self.role)
+            .into_iter()
+            .flat_map(|s| s.traces.iter().flatten())
+        {
+            if !peers.iter().any(|p| p == step.peer()) {
+                peers.push(step.peer().to_string());
+            }
+        }
+        peers.sort_by_key(|p| self.roles.iter().position(|r| r == p));
+        peers
+    }
+
+    /// The kit's files, as paths relative to its directory with their
+    /// contents
+    pub fn files(&self) -> Result<Vec<(String, String)>, TestKitError> {
+        let mut files = vec![
+            ("README.md".to_string(), self.readme()),
+            ("kit.json".to_string(), serde_json::to_string_pretty(self)?),
+            (
+                "messages.json".to_string(),
+                serde_json::to_string_pretty(&self.messages)?,
+            ),
+        ];
+        for (role, sequences) in &self.sequences {
+            files.push((
+                format!("sequences/{}.json", role),
+                serde_json::to_string_pretty(sequences)?,
+            ));
+        }
+        files.push(("driver/Cargo.toml".to_string(), self.driver_manifest()));
+        files.push(("driver/src/main.rs".to_string(), self.driver_main()));
+        Ok(files)
+    }
+
+    /// Write the kit's files under `dir`, creating it if needed
+    pub fn write_to(&self, dir: impl AsRef<Path>) -> Result<(), TestKitError> {
+        let dir = dir.as_ref();
+        for (path, contents) in self.files()? {
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+
+    /// Load a kit written by [`TestKit::write_to`]
+    ///
+    /// `messages.json` may have been edited, e.g. to fill in samples the
+    /// kit could not encode.
+    pub fn read_from(dir: impl AsRef<Path>) -> Result<TestKit, TestKitError> {
+        let dir = dir.as_ref();
+        let read = |path: &str| std::fs::read_to_string(dir.join(path));
+        let mut kit: TestKit = serde_json::from_str(&read("kit.json")?)?;
+        kit.messages = serde_json::from_str(&read("messages.json")?)?;
+        for role in &kit.roles {
+            let sequences = serde_json::from_str(&read(&format!("sequences/{}.json", role))?)?;
+            kit.sequences.insert(role.clone(), sequences);
+        }
+        Ok(kit)
+    }
+
+    fn readme(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# Test kit for role {} of {}\n",
+            self.role, self.choreography
+        );
+        let _ = writeln!(
+            out,
+            "Generated from the choreography. It lets an implementation of \
+             `{}` in any language check itself against the Rust reference.\n",
+            self.role
+        );
+        let _ = writeln!(out, "## Files\n");
+        let _ = writeln!(
+            out,
+            "- `messages.json`: every message, its variant index, and the bytes \
+             the reference sends for its default payload"
+        );
+        let _ = writeln!(
+            out,
+            "- `sequences/<Role>.json`: every sequence of steps a role can go \
+             through; `truncated` is set when some were left out"
+        );
+        let _ = writeln!(
+            out,
+            "- `driver/`: a program that plays every other role against your \
+             implementation\n"
+        );
+        let _ = writeln!(out, "## Wire format\n");
+        let _ = writeln!(
+            out,
+            "Each WebSocket binary frame holds one bincode-encoded frame \
+             (little endian, fixed-width integers):\n"
+        );
+        let _ = writeln!(
+            out,
+            "- message: `u32` 0, `u64` length, then the encoded message\n\
+             - branch label: `u32` 1, `u64` length, then the label in UTF-8\n"
+        );
+        if self.wire == "protobuf" {
+            let _ = writeln!(
+                out,
+                "A message is a `u32` variant index followed by its payload, \
+                 encoded with protobuf. The kit has no samples for them.\n"
+            );
+        } else {
+            let _ = writeln!(
+                out,
+                "A message is a `u32` variant index followed by its bincode \
+                 payload: strings and sequences are a `u64` length then their \
+                 items, `Option` a `u8` tag, struct fields in order.\n"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "A role that makes a choice sends the label to every other role \
+             before running the branch.\n"
+        );
+        let _ = writeln!(out, "## Running the driver\n");
+        let _ = writeln!(
+            out,
+            "```sh\ncargo run --manifest-path driver/Cargo.toml -- 127.0.0.1:9000\n```\n"
+        );
+        let peers = self.peers();
+        let _ = writeln!(
+            out,
+            "Then open one WebSocket per peer at `ws://127.0.0.1:9000/<Peer>`, \
+             for {}. The driver checks the kind and order of every message and \
+             label, and exits with an error at the first step that matches no \
+             sequence.",
+            if peers.is_empty() {
+                "no peers".to_string()
+            } else {
+                peers.join(", ")
+            }
+        );
+        out
+    }
+
+    fn driver_manifest(&self) -> String {
+        let name = format!("{}-testkit-driver", snake_case(&self.choreography));
+        format!(
+            "[package]\n\
+             name = \"{}\"\n\
+             version = \"0.1.0\"\n\
+             edition = \"2021\"\n\
+             publish = false\n\
+             \n\
+             [dependencies]\n\
+             rumpsteak-choreography = \"{}\"\n\
+             tokio = {{ version = \"1\", features = [\"macros\", \"rt-multi-thread\", \"net\"] }}\n\
+             \n\
+             # Keep the driver out of any enclosing workspace\n\
+             [workspace]\n",
+            name.replace('_', "-"),
+            env!("CARGO_PKG_VERSION")
+        )
+    }
+
+    fn driver_main(&self) -> String {
+        format!(
+            r#"// Driver for the {choreography} test kit
+//
+// Plays every role except {role} and waits for the implementation under test
+// to connect one WebSocket per peer at ws://<address>/<Peer>.
+
+use rumpsteak_choreography::compiler::TestKit;
+use rumpsteak_choreography::effects::KitDriver;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {{
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9000".to_string());
+    let kit = TestKit::read_from(concat!(env!("CARGO_MANIFEST_DIR"), "/.."))?;
+    let listener = tokio::net::TcpListener::bind(&address).await?;
+    println!("waiting for {role} to connect to ws://{{}}/<Peer>", address);
+
+    let report = KitDriver::new(kit).run(listener).await?;
+    for (role, steps) in &report.steps {{
+        println!("{{}}: {{}} steps as expected", role, steps);
+    }}
+    Ok(())
+}}
+"#,
+            choreography = self.choreography,
+            role = self.role,
+        )
+    }
+}
+
+fn kit_messages(protocol: &Protocol, wire: WireFormat) -> Vec<KitMessage> {
+    message_samples(protocol)
+        .into_iter()
+        .enumerate()
+        .map(|(index, message)| {
+            let index = index as u32;
+            KitMessage {
+                sample: message.sample.as_ref().map_or(Value::Null, Sample::value),
+                sample_hex: message
+                    .sample
+                    .filter(|_| wire == WireFormat::Bincode)
+                    .map(|sample| hex::encode(sample.message_bincode(index))),
+                name: message.name,
+                index,
+                payload: message.payload,
+            }
+        })
+        .collect()
+}
+
+fn role_sequences(choreography: &Choreography, role: &Role) -> RoleSequences {
+    let mut walker = Walker {
+        role,
+        roles: &choreography.roles,
+        recs: Vec::new(),
+        truncated: false,
+    };
+    let traces = walker.walk(&choreography.protocol);
+    RoleSequences {
+        truncated: walker.truncated,
+        traces,
+    }
+}
+
+type Traces = Vec<Vec<KitStep>>;
+
+/// Enumerates the steps one role's generated program takes
+struct Walker<'a> {
+    role: &'a Role,
+    roles: &'a [Role],
+    /// Enclosing `rec` blocks with the times each was re-entered
+    recs: Vec<(String, &'a Protocol, usize)>,
+    truncated: bool,
+}
+
+impl<'a> Walker<'a> {
+    fn walk(&mut self, protocol: &'a Protocol) -> Traces {
+        match protocol {
+            Protocol::Send {
+                from,
+                to,
+                message,
+                continuation,
+            } => {
+                let message = message.name.to_string();
+                let step = if from == self.role {
+                    Some(KitStep::Send {
+                        peer: to.name.to_string(),
+                        message,
+                    })
+                } else if to == self.role {
+                    Some(KitStep::Recv {
+                        peer: from.name.to_string(),
+                        message,
+                    })
+                } else {
+                    None
+                };
+                let rest = self.walk(continuation);
+                prepend(step.into_iter().collect(), rest)
+            }
+            Protocol::Broadcast {
+                from,
+                to_all,
+                message,
+                continuation,
+                ..
+            } => {
+                let message = message.name.to_string();
+                let steps = if from == self.role {
+                    to_all
+                        .iter()
+                        .map(|to| KitStep::Send {
+                            peer: to.name.to_string(),
+                            message: message.clone(),
+                        })
+                        .collect()
+                } else if to_all.contains(self.role) {
+                    vec![KitStep::Recv {
+                        peer: from.name.to_string(),
+                        message,
+                    }]
+                } else {
+                    Vec::new()
+                };
+                let rest = self.walk(continuation);
+                prepend(steps, rest)
+            }
+            Protocol::Choice { role, branches } => self.choice(role, branches),
+            Protocol::Race { branches } => match branches.first().map(|b| &b.protocol) {
+                Some(Protocol::Send { from, .. }) => self.choice(from, branches),
+                _ => vec![Vec::new()],
+            },
+            Protocol::Loop { condition, body } => {
+                let times = match condition {
+                    Some(Condition::Count(n)) => *n,
+                    _ => 1,
+                };
+                let once = self.walk(body);
+                let mut traces = vec![Vec::new()];
+                for _ in 0..times {
+                    traces = self.then(traces, once.clone());
+                }
+                traces
+            }
+            Protocol::Parallel { protocols } => {
+                let mut traces = vec![Vec::new()];
+                for p in protocols {
+                    let next = self.walk(p);
+                    traces = self.then(traces, next);
+                }
+                traces
+            }
+            Protocol::Rec { label, body } => {
+                self.recs.push((label.to_string(), body, 0));
+                let traces = self.walk(body);
+                self.recs.pop();
+                traces
+            }
+            Protocol::Var(label) => {
+                let label = label.to_string();
+                let Some(index) = self.recs.iter().rposition(|(l, _, _)| *l == label) else {
+                    return vec![Vec::new()];
+                };
+                if self.recs[index].2 >= REPEATS {
+                    self.truncated = true;
+                    return Vec::new();
+                }
+                self.recs[index].2 += 1;
+                let body = self.recs[index].1;
+                let traces = self.walk(body);
+                self.recs[index].2 -= 1;
+                traces
+            }
+            Protocol::Finally { body, cleanup } => {
+                let body = self.walk(body);
+                let cleanup = self.walk(cleanup);
+                self.then(body, cleanup)
+            }
+            Protocol::TryCatch { body, .. } | Protocol::Timeout { body, .. } => self.walk(body),
+            Protocol::Spawn { continuation, .. } | Protocol::Await { continuation, .. } => {
+                self.walk(continuation)
+            }
+            Protocol::End => vec![Vec::new()],
+        }
+    }
+
+    /// The chooser sends its label to every other role, which all offer
+    fn choice(&mut self, chooser: &Role, branches: &'a [Branch]) -> Traces {
+        let mut traces = Vec::new();
+        for branch in branches {
+            let label = branch.label.to_string();
+            let steps = if chooser == self.role {
+                self.roles
+                    .iter()
+                    .filter(|r| *r != chooser)
+                    .map(|r| KitStep::Choose {
+                        peer: r.name.to_string(),
+                        label: label.clone(),
+                    })
+                    .collect()
+            } else {
+                vec![KitStep::Offer {
+                    peer: chooser.name.to_string(),
+                    label,
+                }]
+            };
+            let rest = self.walk(&branch.protocol);
+            traces.extend(prepend(steps, rest));
+        }
+        self.cap(traces)
+    }
+
+    /// Every trace of `first` followed by every trace of `second`
+    fn then(&mut self, first: Traces, second: Traces) -> Traces {
+        let mut traces = Vec::new();
+        for a in &first {
+            for b in &second {
+                traces.push(a.iter().chain(b).cloned().collect());
+            }
+        }
+        self.cap(traces)
+    }
+
+    fn cap(&mut self, mut traces: Traces) -> Traces {
+        if traces.len() > MAX_TRACES {
+            traces.truncate(MAX_TRACES);
+            self.truncated = true;
+        }
+        traces
+    }
+}
+
+fn prepend(steps: Vec<KitStep>, rest: Traces) -> Traces {
+    rest.into_iter()
+        .map(|trace| steps.iter().cloned().chain(trace).collect())
+        .collect()
+}
@@ -0,0 +1,357 @@
+// Per-role interaction timelines for choreographic protocols
+
+use crate::ast::{Choreography, Condition, Protocol, Role};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Ordered interactions of every role in a choreography
+///
+/// Each interaction gets a phase number from a depth-first walk of the
+/// global protocol, so entries at the same phase across lanes belong to
+/// the same global step. Branches, loops, and parallel arms are walked in
+/// order and recorded in each entry's `scope`.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    /// Number of phases in the protocol
+    pub phases: usize,
+    /// One lane per declared role, in declaration order
+    pub lanes: Vec<Lane>,
+}
+
+/// Interactions of a single role
+#[derive(Debug, Clone)]
+pub struct Lane {
+    pub role: Role,
+    pub entries: Vec<TimelineEntry>,
+}
+
+/// One interaction of a role
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEntry {
+    pub phase: usize,
+    pub action: TimelineAction,
+    /// Enclosing constructs, outermost first, e.g. `loop/branch Accept`
+    pub scope: String,
+}
+
+/// What a role does in a phase
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimelineAction {
+    Send { to: Role, message: String },
+    Receive { from: Role, message: String },
+    Choose { branches: Vec<String> },
+}
+
+impl std::fmt::Display for TimelineAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimelineAction::Send { to, message } => write!(f, "-> {}: {}", to.name, message),
+            TimelineAction::Receive { from, message } => {
+                write!(f, "<- {}: {}", from.name, message)
+            }
+            TimelineAction::Choose { branches } => write!(f, "choose {}", branches.join("|")),
+        }
+    }
+}
+
+/// Build the timeline of every role in a choreography
+pub fn timeline(choreography: &Choreography) -> Timeline {
+    let mut builder = TimelineBuilder {
+        phase: 0,
+        scope: Vec::new(),
+        lanes: choreography
+            .roles
+            .iter()
+            .map(|role| Lane {
+                role: role.clone(),
+                entries: Vec::new(),
+            })
+            .collect(),
+    };
+    builder.walk(&choreography.protocol);
+
+    Timeline {
+        phases: builder.phase,
+        lanes: builder.lanes,
+    }
+}
+
+impl Timeline {
+    pub fn lane(&self, role: &Role) -> Option<&Lane> {
+        self.lanes.iter().find(|lane| &lane.role == role)
+    }
+
+    /// Distinct roles this role exchanges messages with
+    ///
+    /// A large set points at a role that couples many others together.
+    pub fn peers(&self, role: &Role) -> BTreeSet<String> {
+        self.lane(role)
+            .map(|lane| {
+                lane.entries
+                    .iter()
+                    .filter_map(|entry| match &entry.action {
+                        TimelineAction::Send { to: peer, .. }
+                        | TimelineAction::Receive { from: peer, .. } => Some(peer.name.to_string()),
+                        TimelineAction::Choose { .. } => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Phases in which the role does nothing
+    pub fn idle_phases(&self, role: &Role) -> Vec<usize> {
+        let active: BTreeSet<usize> = self
+            .lane(role)
+            .map(|lane| lane.entries.iter().map(|e| e.phase).collect())
+            .unwrap_or_default();
+        (0..self.phases).filter(|p| !active.contains(p)).collect()
+    }
+
+    /// Render as a plain-text table with one row per phase and one column per role
+    pub fn to_table(&self) -> String {
+        let mut header = vec!["phase".to_string()];
+        header.extend(self.lanes.iter().map(|lane| lane.role.name.to_string()));
+        header.push("scope".to_string());
+
+        let mut rows = vec![header];
+        for phase in 0..self.phases {
+            let mut row = vec![phase.to_string()];
+            let mut scope = String::new();
+            for lane in &self.lanes {
+                let cell: Vec<String> = lane
+                    .entries
+                    .iter()
+                    .filter(|e| e.phase == phase)
+                    .map(|e| {
+                        scope.clone_from(&e.scope);
+                        e.action.to_string()
+                    })
+                    .collect();
+                row.push(cell.join(", "));
+            }
+            row.push(scope);
+            rows.push(row);
+        }
+
+        let columns = rows[0].len();
+        let widths: Vec<usize> = (0..columns)
+            .map(|c| rows.iter().map(|r| r[c].len()).max().unwrap_or(0))
+            .collect();
+
+        let mut out = String::new();
+        for (i, row) in rows.iter().enumerate() {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            out.push_str(cells.join(" | ").trim_end());
+            out.push('\n');
+            if i == 0 {
+                let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+                out.push_str(&rule.join("-+-"));
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Render as a swimlane DOT graph with one cluster per role
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph Timeline {\n");
+        dot.push_str("  rankdir=TB;\n");
+        dot.push_str("  node [shape=box];\n");
+
+        for lane in &self.lanes {
+            let name = &lane.role.name;
+            let _ = writeln!(dot, "  subgraph cluster_{} {{", name);
+            let _ = writeln!(dot, "    label=\"{}\";", name);
+            for (i, entry) in lane.entries.iter().enumerate() {
+                let _ = writeln!(
+                    dot,
+                    "    {}_{} [label=\"{}: {}\"];",
+                    name, i, entry.phase, entry.action
+                );
+            }
+            for i in 1..lane.entries.len() {
+                let _ = writeln!(
+                    dot,
+                    "    {}_{} -> {}_{} [style=dotted];",
+                    name,
+                    i - 1,
+                    name,
+                    i
+                );
+            }
+            dot.push_str("  }\n");
+        }
+
+        // Connect each send to the matching receive in the same phase
+        for lane in &self.lanes {
+            for (i, entry) in lane.entries.iter().enumerate() {
+                let TimelineAction::Send { to, message } = &entry.action else {
+                    continue;
+                };
+                let Some(target) = self.lane(to) else {
+                    continue;
+                };
+                let matching = target.entries.iter().position(|e| {
+                    e.phase == entry.phase
+                        && matches!(&e.action, TimelineAction::Receive { from, .. } if from == &lane.role)
+                });
+                if let Some(j) = matching {
+                    let _ = writeln!(
+                        dot,
+                        "  {}_{} -> {}_{} [label=\"{}\"];",
+                        lane.role.name, i, to.name, j, message
+                    );
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+struct TimelineBuilder {
+    phase: usize,
+    scope: Vec<String>,
+    lanes: Vec<Lane>,
+}
+
+impl TimelineBuilder {
+    fn record(&mut self, role: &Role, action: TimelineAction) {
+        let scope = self.scope.join("/");
+        if let Some(lane) = self.lanes.iter_mut().find(|lane| &lane.role == role) {
+            lane.entries.push(TimelineEntry {
+                phase: self.phase,
+                action,
+                scope,
+            });
+        }
+    }
+
+    fn scoped(&mut self, scope: String, protocol: &Protocol) {
+        self.scope.push(scope);
+        self.walk(protocol);
+        self.scope.pop();
+    }
+
+    fn walk(&mut self, protocol: &Protocol) {
+        match protocol {
+            Protocol::Send {
+                from,
+                to,
+                message,
+                continuation,
+            } => {
+                let message = message.name.to_string();
+                self.record(
+                    from,
+                    TimelineAction::Send {
+                        to: to.clone(),
+                        message: message.clone(),
+                    },
+                );
+                self.record(
+                    to,
+                    TimelineAction::Receive {
+                        from: from.clone(),
+                        message,
+                    },
+                );
+                self.phase += 1;
+                self.walk(continuation);
+            }
+            Protocol::Broadcast {
+                from,
+                to_all,
+                message,
+                continuation,
+                ..
+            } => {
+                let message = message.name.to_string();
+                for to in to_all {
+                    self.record(
+                        from,
+                        TimelineAction::Send {
+                            to: to.clone(),
+                            message: message.clone(),
+                        },
+                    );
+                    self.record(
+                        to,
+                        TimelineAction::Receive {
+                            from: from.clone(),
+                            message: message.clone(),
+                        },
+                    );
+                }
+                self.phase += 1;
+                self.walk(continuation);
+            }
+            Protocol::Choice { role, branches } => {
+                self.record(
+                    role,
+                    TimelineAction::Choose {
+                        branches: branches.iter().map(|b| b.label.to_string()).collect(),
+                    },
+                );
+                self.phase += 1;
+                for branch in branches {
+                    self.scoped(format!("branch {}", branch.label), &branch.protocol);
+                }
+            }
+            Protocol::Race { branches } => {
+                for branch in branches {
+                    self.scoped(format!("race {}", branch.label), &branch.protocol);
+                }
+            }
+            Protocol::Loop { condition, body } => {
+                let scope = match condition {
+                    Some(Condition::Count(n)) => format!("loop x{}", n),
+                    Some(Condition::RoleDecides(role)) => format!("loop by {}", role.name),
+                    _ => "loop".to_string(),
+                };
+                self.scoped(scope, body);
+            }
+            Protocol::Parallel { protocols } => {
+                for (i, p) in protocols.iter().enumerate() {
+                    self.scoped(format!("parallel {}", i), p);
+                }
+            }
+            Protocol::Rec { label, body } => {
+                self.scoped(format!("rec {}", label), body);
+            }
+            Protocol::Finally { body, cleanup } => {
+                self.walk(body);
+                self.scoped("finally".to_string(), cleanup);
+            }
+            Protocol::TryCatch {
+                body, compensation, ..
+            } => {
+                self.walk(body);
+                self.scoped("catch".to_string(), compensation);
+            }
+            Protocol::Timeout {
+                body, on_timeout, ..
+            } => {
+                self.walk(body);
+                self.scoped("timeout".to_string(), on_timeout);
+            }
+            Protocol::Spawn {
+                handle,
+                body,
+                continuation,
+                ..
+            } => {
+                self.scoped(format!("spawn {}", handle), body);
+                self.walk(continuation);
+            }
+            Protocol::Await { continuation, .. } => self.walk(continuation),
+            Protocol::Var(_) | Protocol::End => {}
+        }
+    }
+}
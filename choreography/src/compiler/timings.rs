@@ -0,0 +1,181 @@
+// Compile-time measurements for large choreographies
+//
+// When a protocol with hundreds of interactions compiles slowly, the first
+// question is which phase is responsible. `compile_with_timings` runs the
+// same pipeline as code generation, phase by phase, and reports how long
+// each one took together with the size of the AST, so users can point at
+// the bottleneck before filing a performance bug.
+
+use crate::ast::{Choreography, Protocol, ValidationError};
+use crate::compiler::analysis::analyze;
+use crate::compiler::codegen::generate_choreography_code;
+use crate::compiler::config::CompileConfig;
+use crate::compiler::effects_codegen::generate_effects_protocol;
+use crate::compiler::parser::{parse_choreography_str_with_config, ParseError};
+use crate::compiler::projection::{project, ProjectionError};
+use crate::compiler::provenance::walk_with_paths;
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Errors that stop a timed compilation
+#[derive(Debug, thiserror::Error)]
+pub enum CompileError {
+    #[error("parse failed: {0}")]
+    Parse(#[from] ParseError),
+
+    #[error("validation failed: {0}")]
+    Validation(#[from] ValidationError),
+
+    #[error("projection failed: {0}")]
+    Projection(#[from] ProjectionError),
+}
+
+/// Number of nodes of each kind in the global protocol
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct NodeCounts {
+    pub total: usize,
+    /// Sends and broadcasts
+    pub messages: usize,
+    pub choices: usize,
+    pub branches: usize,
+    /// `loop` and `rec` blocks
+    pub loops: usize,
+    pub parallels: usize,
+}
+
+impl NodeCounts {
+    pub fn of(protocol: &Protocol) -> Self {
+        let mut counts = Self::default();
+        walk_with_paths(protocol, &mut |_, node| {
+            counts.total += 1;
+            match node {
+                Protocol::Send { .. } | Protocol::Broadcast { .. } => counts.messages += 1,
+                Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+                    counts.choices += 1;
+                    counts.branches += branches.len();
+                }
+                Protocol::Loop { .. } | Protocol::Rec { .. } => counts.loops += 1,
+                Protocol::Parallel { .. } => counts.parallels += 1,
+                _ => {}
+            }
+        });
+        counts
+    }
+}
+
+/// How long each compilation phase took
+#[derive(Debug, Clone, Serialize)]
+pub struct CompileTimings {
+    pub choreography: String,
+    pub parse: Duration,
+    pub validation: Duration,
+    /// Projection time of each role, in declaration order
+    pub projection: Vec<(String, Duration)>,
+    pub analysis: Duration,
+    /// Session types and the effects protocol
+    pub codegen: Duration,
+    pub nodes: NodeCounts,
+}
+
+impl CompileTimings {
+    /// Time spent projecting all roles
+    pub fn projection_total(&self) -> Duration {
+        self.projection.iter().map(|(_, time)| *time).sum()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.parse + self.validation + self.projection_total() + self.analysis + self.codegen
+    }
+
+    /// The role whose projection took longest
+    pub fn slowest_projection(&self) -> Option<(&str, Duration)> {
+        self.projection
+            .iter()
+            .max_by_key(|(_, time)| *time)
+            .map(|(role, time)| (role.as_str(), *time))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("timings serialize to JSON")
+    }
+}
+
+impl fmt::Display for CompileTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = &self.nodes;
+        writeln!(f, "choreography: {}", self.choreography)?;
+        writeln!(
+            f,
+            "nodes: {} ({} messages, {} choices, {} branches, {} loops, {} parallel)",
+            n.total, n.messages, n.choices, n.branches, n.loops, n.parallels
+        )?;
+        writeln!(f, "parse: {:?}", self.parse)?;
+        writeln!(f, "validation: {:?}", self.validation)?;
+        writeln!(f, "projection: {:?}", self.projection_total())?;
+        for (role, time) in &self.projection {
+            writeln!(f, "  {}: {:?}", role, time)?;
+        }
+        writeln!(f, "analysis: {:?}", self.analysis)?;
+        writeln!(f, "codegen: {:?}", self.codegen)?;
+        writeln!(f, "total: {:?}", self.total())
+    }
+}
+
+/// Compile a choreography source and measure every phase
+///
+/// Runs parsing, validation, projection of each role, the built-in
+/// analysis passes, and generation of session types and the effects
+/// protocol. Analysis findings do not stop the run. Generated code is
+/// discarded.
+pub fn compile_with_timings(
+    input: &str,
+    config: &CompileConfig,
+) -> Result<CompileTimings, CompileError> {
+    let (choreography, parse) = timed(|| parse_choreography_str_with_config(input, config));
+    let choreography = choreography?;
+    let mut timings = measure(&choreography, config)?;
+    timings.parse = parse;
+    Ok(timings)
+}
+
+fn measure(
+    choreography: &Choreography,
+    config: &CompileConfig,
+) -> Result<CompileTimings, CompileError> {
+    let (valid, validation) = timed(|| choreography.validate_with_limits(config.limits()));
+    valid?;
+
+    let mut projection = Vec::new();
+    let mut local_types = Vec::new();
+    for role in &choreography.roles {
+        let (local_type, time) = timed(|| project(choreography, role));
+        projection.push((role.to_string(), time));
+        local_types.push((role.clone(), local_type?));
+    }
+
+    let (_, analysis) = timed(|| analyze(choreography));
+    let name = choreography.name.to_string();
+    let (_, codegen) = timed(|| {
+        (
+            generate_choreography_code(&name, &choreography.roles, &local_types),
+            generate_effects_protocol(choreography),
+        )
+    });
+
+    Ok(CompileTimings {
+        choreography: name,
+        parse: Duration::ZERO,
+        validation,
+        projection,
+        analysis,
+        codegen,
+        nodes: NodeCounts::of(&choreography.protocol),
+    })
+}
+
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let value = f();
+    (value, start.elapsed())
+}
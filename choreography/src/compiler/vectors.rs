@@ -0,0 +1,422 @@
+// Reference vectors: canonical encodings of the messages of a choreography
+//
+// Every message type gets one vector: the generated `Message` enum holding
+// the message with its default payload, as bincode, JSON, and CBOR bytes.
+// The exporter works from the choreography alone, so the vectors can be
+// shipped to implementations in other languages as golden files. Rust code
+// checks its own serde types against the same files with
+// `ReferenceVectors::verify`, which decodes every vector into the type and
+// encodes it again.
+//
+// Payloads are built for primitives, strings, collections, `Option`, tuples,
+// and field lists of them. Messages with other payload types are listed as
+// skipped; vectors for them can be added from real values with
+// `ReferenceVectors::with_vector`.
+
+use crate::ast::message::split_top_level;
+use crate::ast::{Choreography, MessageType, Protocol, WireFormat};
+use crate::compiler::effects_codegen::{collect_message_types, infer_content_type};
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Errors while building or checking reference vectors
+#[derive(Debug, Error)]
+pub enum VectorError {
+    #[error("cannot encode {message} as {format}: {reason}")]
+    Encode {
+        message: String,
+        format: &'static str,
+        reason: String,
+    },
+
+    #[error("{format} vector of {message} does not decode: {reason}")]
+    Decode {
+        message: String,
+        format: &'static str,
+        reason: String,
+    },
+
+    #[error("{format} vector of {message} is {expected}, but the type encodes it as {found}")]
+    Mismatch {
+        message: String,
+        format: &'static str,
+        expected: String,
+        found: String,
+    },
+
+    #[error("malformed reference vectors: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Canonical encodings of the messages of one choreography
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReferenceVectors {
+    pub choreography: String,
+    pub vectors: Vec<ReferenceVector>,
+    /// Messages the exporter could not build a payload for
+    #[serde(default)]
+    pub skipped: Vec<String>,
+}
+
+/// One value in every supported encoding
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReferenceVector {
+    /// The message the value carries
+    pub message: String,
+    pub description: String,
+    /// Hex of the bincode encoding (little endian, fixed-width integers)
+    pub bincode: String,
+    /// The JSON encoding, as serde_json writes it
+    pub json: String,
+    /// Hex of the CBOR encoding, as ciborium writes it
+    pub cbor: String,
+}
+
+/// Build the reference vectors of a choreography
+///
+/// Under `@wire(protobuf)` payloads are prost types encoded by protobuf, so
+/// every message is skipped.
+pub fn generate_reference_vectors(choreography: &Choreography) -> ReferenceVectors {
+    let mut vectors = ReferenceVectors::new(choreography.name.to_string());
+    for (index, message) in message_samples(&choreography.protocol)
+        .into_iter()
+        .enumerate()
+    {
+        match message.sample {
+            Some(sample) if choreography.wire_format() == WireFormat::Bincode => {
+                let description = format!(
+                    "Message::{} with the default payload ({})",
+                    message.name, message.payload
+                );
+                vectors.vectors.push(ReferenceVector {
+                    bincode: hex::encode(sample.message_bincode(index as u32)),
+                    json: sample.message_json(&message.name),
+                    cbor: hex::encode(sample.message_cbor(&message.name)),
+                    message: message.name,
+                    description,
+                });
+            }
+            _ => vectors.skipped.push(message.name),
+        }
+    }
+    vectors
+}
+
+impl ReferenceVectors {
+    pub fn new(choreography: impl Into<String>) -> Self {
+        Self {
+            choreography: choreography.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Add a vector for a value of the message type, e.g. a generated
+    /// `Message` holding a payload the exporter cannot build
+    pub fn with_vector<M: Serialize>(
+        mut self,
+        message: impl Into<String>,
+        description: impl Into<String>,
+        value: &M,
+    ) -> Result<Self, VectorError> {
+        let message = message.into();
+        let (bincode, json, cbor) = encode_all(&message, value)?;
+        self.skipped.retain(|m| *m != message);
+        self.vectors.push(ReferenceVector {
+            message,
+            description: description.into(),
+            bincode: hex::encode(bincode),
+            json,
+            cbor: hex::encode(cbor),
+        });
+        Ok(self)
+    }
+
+    /// Vectors of one message
+    pub fn vectors_for<'a>(
+        &'a self,
+        message: &'a str,
+    ) -> impl Iterator<Item = &'a ReferenceVector> + 'a {
+        self.vectors.iter().filter(move |v| v.message == message)
+    }
+
+    /// Check that `M` decodes every vector and encodes the value to the
+    /// same bytes in every format
+    pub fn verify<M: Serialize + DeserializeOwned>(&self) -> Result<(), VectorError> {
+        for vector in &self.vectors {
+            vector.verify::<M>()?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+}
+
+impl ReferenceVector {
+    /// Check one vector against `M`
+    pub fn verify<M: Serialize + DeserializeOwned>(&self) -> Result<(), VectorError> {
+        let decode = |format: &'static str, reason: String| VectorError::Decode {
+            message: self.message.clone(),
+            format,
+            reason,
+        };
+        let bincode = hex::decode(&self.bincode).map_err(|e| decode("bincode", e.to_string()))?;
+        let cbor = hex::decode(&self.cbor).map_err(|e| decode("cbor", e.to_string()))?;
+
+        let from_bincode: M =
+            bincode::deserialize(&bincode).map_err(|e| decode("bincode", e.to_string()))?;
+        let from_json: M =
+            serde_json::from_str(&self.json).map_err(|e| decode("json", e.to_string()))?;
+        let from_cbor: M =
+            ciborium::from_reader(cbor.as_slice()).map_err(|e| decode("cbor", e.to_string()))?;
+
+        // Each format must give back its own bytes, and all three must
+        // hold the same value
+        let mismatch = |format: &'static str, expected: String, found: String| {
+            Err(VectorError::Mismatch {
+                message: self.message.clone(),
+                format,
+                expected,
+                found,
+            })
+        };
+        for value in [&from_bincode, &from_json, &from_cbor] {
+            let (b, j, c) = encode_all(&self.message, value)?;
+            if b != bincode {
+                return mismatch("bincode", self.bincode.clone(), hex::encode(b));
+            }
+            if j != self.json {
+                return mismatch("json", self.json.clone(), j);
+            }
+            if c != cbor {
+                return mismatch("cbor", self.cbor.clone(), hex::encode(c));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_all<M: Serialize>(
+    message: &str,
+    value: &M,
+) -> Result<(Vec<u8>, String, Vec<u8>), VectorError> {
+    let encode = |format: &'static str, reason: String| VectorError::Encode {
+        message: message.to_string(),
+        format,
+        reason,
+    };
+    let bincode = bincode::serialize(value).map_err(|e| encode("bincode", e.to_string()))?;
+    let json = serde_json::to_string(value).map_err(|e| encode("json", e.to_string()))?;
+    let mut cbor = Vec::new();
+    ciborium::into_writer(value, &mut cbor).map_err(|e| encode("cbor", e.to_string()))?;
+    Ok((bincode, json, cbor))
+}
+
+/// A message type in the order of the generated `Message` enum
+pub(crate) struct MessageSample {
+    pub(crate) name: String,
+    /// Payload as written in the choreography, or the type inferred for it
+    pub(crate) payload: String,
+    /// The default payload, if its type is one the exporter knows
+    pub(crate) sample: Option<Sample>,
+}
+
+pub(crate) fn message_samples(protocol: &Protocol) -> Vec<MessageSample> {
+    let mut message_types = BTreeMap::new();
+    collect_message_types(protocol, &mut message_types);
+    message_types.values().map(|m| message_sample(m)).collect()
+}
+
+fn message_sample(message: &MessageType) -> MessageSample {
+    let name = message.name.to_string();
+    if let Some(fields) = message.fields() {
+        let sample = fields
+            .iter()
+            .map(|f| Some((f.name.to_string(), Sample::default_of(&f.ty)?)))
+            .collect::<Option<Vec<_>>>()
+            .map(Sample::Struct);
+        let payload = fields
+            .iter()
+            .map(|f| format!("{}: {}", f.name, f.ty))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return MessageSample {
+            name,
+            payload,
+            sample,
+        };
+    }
+    // Other payloads are the single field of a tuple struct, which serde
+    // encodes as the field alone
+    let ty = message
+        .payload
+        .clone()
+        .unwrap_or_else(|| infer_content_type(&name));
+    MessageSample {
+        sample: Sample::default_of(&ty),
+        payload: ty.to_string(),
+        name,
+    }
+}
+
+/// The default value of a payload type, in the shape serde sees it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Sample {
+    Bool,
+    /// An integer of this many bytes
+    Int(usize),
+    /// A float of this many bytes
+    Float(usize),
+    Char,
+    Str,
+    Seq,
+    Map,
+    None,
+    Unit,
+    Tuple(Vec<Sample>),
+    Struct(Vec<(String, Sample)>),
+}
+
+impl Sample {
+    /// `Default::default()` of a payload type, if the type is known
+    pub(crate) fn default_of(ty: &TokenStream) -> Option<Sample> {
+        let tokens: Vec<TokenTree> = ty.clone().into_iter().collect();
+        if let [TokenTree::Group(group)] = tokens.as_slice() {
+            if group.delimiter() == Delimiter::Parenthesis {
+                let parts = split_top_level(&group.stream());
+                let trailing_comma = parts.len() > 1 && parts.last().is_some_and(|p| p.is_empty());
+                let items = parts
+                    .into_iter()
+                    .filter(|p| !p.is_empty())
+                    .map(|p| Sample::default_of(&p.into_iter().collect()))
+                    .collect::<Option<Vec<_>>>()?;
+                return Some(match items.len() {
+                    0 => Sample::Unit,
+                    // `(T)` is just `T`
+                    1 if !trailing_comma => items.into_iter().next()?,
+                    _ => Sample::Tuple(items),
+                });
+            }
+        }
+        // The last path segment before any generics names the type
+        let name = tokens
+            .iter()
+            .take_while(|t| !matches!(t, TokenTree::Punct(p) if p.as_char() == '<'))
+            .filter_map(|t| match t {
+                TokenTree::Ident(ident) => Some(ident.to_string()),
+                _ => None,
+            })
+            .last()?;
+        Some(match name.as_str() {
+            "bool" => Sample::Bool,
+            "u8" | "i8" => Sample::Int(1),
+            "u16" | "i16" => Sample::Int(2),
+            "u32" | "i32" => Sample::Int(4),
+            "u64" | "i64" | "usize" | "isize" => Sample::Int(8),
+            "u128" | "i128" => Sample::Int(16),
+            "f32" => Sample::Float(4),
+            "f64" => Sample::Float(8),
+            "char" => Sample::Char,
+            "String" => Sample::Str,
+            "Vec" | "VecDeque" | "HashSet" | "BTreeSet" => Sample::Seq,
+            "HashMap" | "BTreeMap" => Sample::Map,
+            "Option" => Sample::None,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn bincode(&self) -> Vec<u8> {
+        match self {
+            // `None` is a zero tag
+            Sample::Bool | Sample::Char | Sample::None => vec![0],
+            Sample::Int(width) | Sample::Float(width) => vec![0; *width],
+            // A zero `u64` length
+            Sample::Str | Sample::Seq | Sample::Map => vec![0; 8],
+            Sample::Unit => Vec::new(),
+            Sample::Tuple(items) => items.iter().flat_map(Sample::bincode).collect(),
+            Sample::Struct(fields) => fields.iter().flat_map(|(_, s)| s.bincode()).collect(),
+        }
+    }
+
+    pub(crate) fn json(&self) -> String {
+        match self {
+            Sample::Bool => "false".to_string(),
+            Sample::Int(_) => "0".to_string(),
+            Sample::Float(_) => "0.0".to_string(),
+            Sample::Char => "\"\\u0000\"".to_string(),
+            Sample::Str => "\"\"".to_string(),
+            Sample::Seq => "[]".to_string(),
+            Sample::Map => "{}".to_string(),
+            Sample::None | Sample::Unit => "null".to_string(),
+            Sample::Tuple(items) => format!(
+                "[{}]",
+                items.iter().map(Sample::json).collect::<Vec<_>>().join(",")
+            ),
+            Sample::Struct(fields) => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|(name, s)| format!("{}:{}", json!(name), s.json()))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+
+    /// The value as a JSON tree, for display
+    pub(crate) fn value(&self) -> Value {
+        serde_json::from_str(&self.json()).unwrap_or(Value::Null)
+    }
+
+    fn cbor_value(&self) -> ciborium::Value {
+        use ciborium::Value as Cbor;
+        match self {
+            Sample::Bool => Cbor::Bool(false),
+            Sample::Int(_) => Cbor::Integer(0.into()),
+            Sample::Float(_) => Cbor::Float(0.0),
+            Sample::Char => Cbor::Text("\u{0}".to_string()),
+            Sample::Str => Cbor::Text(String::new()),
+            Sample::Seq => Cbor::Array(Vec::new()),
+            Sample::Map => Cbor::Map(Vec::new()),
+            Sample::None | Sample::Unit => Cbor::Null,
+            Sample::Tuple(items) => Cbor::Array(items.iter().map(Sample::cbor_value).collect()),
+            Sample::Struct(fields) => Cbor::Map(
+                fields
+                    .iter()
+                    .map(|(name, s)| (Cbor::Text(name.clone()), s.cbor_value()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Bincode of the `Message` variant at `index` holding this payload
+    pub(crate) fn message_bincode(&self, index: u32) -> Vec<u8> {
+        let mut bytes = index.to_le_bytes().to_vec();
+        bytes.extend(self.bincode());
+        bytes
+    }
+
+    /// JSON of the `Message` variant `name` holding this payload
+    pub(crate) fn message_json(&self, name: &str) -> String {
+        format!("{{{}:{}}}", json!(name), self.json())
+    }
+
+    /// CBOR of the `Message` variant `name` holding this payload
+    pub(crate) fn message_cbor(&self, name: &str) -> Vec<u8> {
+        let value = ciborium::Value::Map(vec![(
+            ciborium::Value::Text(name.to_string()),
+            self.cbor_value(),
+        )]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).expect("writing to a Vec cannot fail");
+        bytes
+    }
+}
@@ -3,8 +3,12 @@
 // This module provides a data representation of choreographic programs
 // that can be analyzed, transformed, and interpreted separately from execution.
 
-use crate::effects::{Label, RoleId};
-use std::collections::HashSet;
+use crate::effects::compute::{Computation, LoopCondition, Router};
+use crate::effects::guard::Guard;
+use crate::effects::handlers::session::short_type_name;
+use crate::effects::{ChoreographyError, Label, RoleId};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
 use std::time::Duration;
 
 /// A choreographic effect that can be performed by a role
@@ -19,6 +23,22 @@ pub enum Effect<R: RoleId, M> {
     /// Make an internal choice and broadcast the label
     Choose { at: R, label: Label },
 
+    /// Choose the first label whose guard holds and broadcast it
+    ///
+    /// A label without a guard always qualifies. The continuations follow
+    /// in a Branch effect, as after Choose.
+    ChooseWhen {
+        at: R,
+        guards: Vec<(Label, Option<Guard>)>,
+    },
+
+    /// Choose the label of the candidate `router` picks from `msg`, then
+    /// send `msg` to it
+    ///
+    /// A Branch effect may follow, as after Choose, when what comes next
+    /// depends on the candidate.
+    Route { at: R, msg: M, router: Router<R, M> },
+
     /// Wait for an external choice from another role
     Offer { from: R },
 
@@ -28,6 +48,8 @@ pub enum Effect<R: RoleId, M> {
     Branch {
         choosing_role: R,
         branches: Vec<(Label, Program<R, M>)>,
+        /// What to do with a label none of the branches lists
+        unknown: UnknownLabel,
     },
 
     /// Loop that executes body a fixed number of times or until a condition
@@ -37,20 +59,94 @@ pub enum Effect<R: RoleId, M> {
         body: Box<Program<R, M>>,
     },
 
+    /// Loop that runs body for as long as `condition` holds
+    LoopWhile {
+        condition: LoopCondition<M>,
+        body: Box<Program<R, M>>,
+    },
+
+    /// Recursion point that `Jump` effects in body go back to
+    Rec {
+        label: &'static str,
+        body: Box<Program<R, M>>,
+    },
+
+    /// Go back to the start of the enclosing `Rec` with this label
+    Jump { label: &'static str },
+
     /// Execute a sub-program with a timeout
+    ///
+    /// If the time runs out, `on_timeout` runs in place of the rest of the
+    /// body; without one the timeout is an error.
     Timeout {
         at: R,
         dur: Duration,
         body: Box<Program<R, M>>,
+        on_timeout: Option<Box<Program<R, M>>>,
     },
 
     /// Execute multiple programs in parallel
     Parallel { programs: Vec<Program<R, M>> },
 
+    /// Execute a body, then always run the cleanup program on every exit path
+    Finally {
+        body: Box<Program<R, M>>,
+        cleanup: Box<Program<R, M>>,
+    },
+
+    /// Execute a body, and run the compensation instead of failing if it
+    /// raises an error; `notify` are the peers told about a local error
+    TryCatch {
+        body: Box<Program<R, M>>,
+        notify: Vec<R>,
+        compensation: Box<Program<R, M>>,
+    },
+
+    /// Compute a value locally from the most recently received message
+    Compute { computation: Computation<M> },
+
+    /// Keep the most recently received or computed value under `name`
+    Bind { name: &'static str },
+
+    /// Send the message `computation` builds from the value bound to `name`
+    SendWith {
+        to: R,
+        name: &'static str,
+        computation: Computation<M>,
+    },
+
+    /// Start `program` as an independent child session and carry on
+    Spawn {
+        handle: &'static str,
+        program: Box<Program<R, M>>,
+    },
+
+    /// Wait for the child session started as `handle` to finish
+    Await { handle: &'static str },
+
+    /// Tell `notify` the session is cancelled and stop the program
+    Cancel { notify: Vec<R> },
+
     /// End of program
     End,
 }
 
+/// What an offer does with a label none of its branches lists
+///
+/// A peer built from a newer choreography may choose a branch an older one
+/// does not know. System labels that end the session are handled before the
+/// policy applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownLabel {
+    /// Fail the program with a protocol violation
+    #[default]
+    Reject,
+    /// Log a warning and wait for the next label from the same role
+    Skip,
+    /// Log a warning and run the branch with this label instead
+    Fallback(Label),
+}
+
 /// A choreographic program as a sequence of effects
 #[derive(Debug, Clone, PartialEq)]
 pub struct Program<R: RoleId, M> {
@@ -80,12 +176,45 @@ impl<R: RoleId, M> Program<R, M> {
         self
     }
 
+    /// Add a receive whose value is kept under `name`
+    ///
+    /// Later [`send_with`](Self::send_with) effects can build their
+    /// messages from it.
+    pub fn recv_into<T: 'static>(self, from: R, name: &'static str) -> Self {
+        self.recv::<T>(from).bind(name)
+    }
+
+    /// Keep the most recently received or computed value under `name`
+    ///
+    /// Binding a name again replaces its value.
+    pub fn bind(mut self, name: &'static str) -> Self {
+        self.effects.push(Effect::Bind { name });
+        self
+    }
+
+    /// Add a send of the message `computation` builds from the value bound
+    /// to `name`
+    pub fn send_with(mut self, to: R, name: &'static str, computation: Computation<M>) -> Self {
+        self.effects.push(Effect::SendWith {
+            to,
+            name,
+            computation,
+        });
+        self
+    }
+
     /// Add a choice effect
     pub fn choose(mut self, at: R, label: Label) -> Self {
         self.effects.push(Effect::Choose { at, label });
         self
     }
 
+    /// Add a route of `msg` from `at` to the candidate `router` picks
+    pub fn route(mut self, at: R, msg: M, router: Router<R, M>) -> Self {
+        self.effects.push(Effect::Route { at, msg, router });
+        self
+    }
+
     /// Add an offer effect
     pub fn offer(mut self, from: R) -> Self {
         self.effects.push(Effect::Offer { from });
@@ -98,6 +227,27 @@ impl<R: RoleId, M> Program<R, M> {
             at,
             dur,
             body: Box::new(body),
+            on_timeout: None,
+        });
+        self
+    }
+
+    /// Add a timeout effect that runs `on_timeout` if the time runs out
+    ///
+    /// The program carries on after either the body or `on_timeout`
+    /// completes.
+    pub fn with_timeout_else(
+        mut self,
+        at: R,
+        dur: Duration,
+        body: Program<R, M>,
+        on_timeout: Program<R, M>,
+    ) -> Self {
+        self.effects.push(Effect::Timeout {
+            at,
+            dur,
+            body: Box::new(body),
+            on_timeout: Some(Box::new(on_timeout)),
         });
         self
     }
@@ -108,15 +258,186 @@ impl<R: RoleId, M> Program<R, M> {
         self
     }
 
+    /// Add a body whose cleanup program runs even if the body fails or times out
+    pub fn with_finally(mut self, body: Program<R, M>, cleanup: Program<R, M>) -> Self {
+        self.effects.push(Effect::Finally {
+            body: Box::new(body),
+            cleanup: Box::new(cleanup),
+        });
+        self
+    }
+
+    /// Add a body whose compensation runs if the body raises an error
+    ///
+    /// If the body fails here, each of `notify` is sent `sys.abort` before
+    /// the compensation runs. If it fails because a peer aborted, the
+    /// compensation runs without telling anyone. Timeouts and cancellation
+    /// are not caught. The program carries on after a compensation that
+    /// completes.
+    pub fn try_catch(
+        mut self,
+        body: Program<R, M>,
+        notify: Vec<R>,
+        compensation: Program<R, M>,
+    ) -> Self {
+        self.effects.push(Effect::TryCatch {
+            body: Box::new(body),
+            notify,
+            compensation: Box::new(compensation),
+        });
+        self
+    }
+
+    /// Add a local computation over the most recently received message
+    ///
+    /// The result is added to the received values, where later
+    /// computations and the caller can see it.
+    pub fn compute(mut self, computation: Computation<M>) -> Self {
+        self.effects.push(Effect::Compute { computation });
+        self
+    }
+
+    /// Add a child session running `program`, awaited later through
+    /// `handle`
+    ///
+    /// Needs a [`SessionContext`](crate::effects::SessionContext) to start
+    /// the child, see
+    /// [`interpret_in_session`](crate::effects::interpret_in_session).
+    pub fn spawn(mut self, handle: &'static str, program: Program<R, M>) -> Self {
+        self.effects.push(Effect::Spawn {
+            handle,
+            program: Box::new(program),
+        });
+        self
+    }
+
+    /// Add a wait for the child session started as `handle`
+    ///
+    /// The values the child received are added to this program's.
+    pub fn await_child(mut self, handle: &'static str) -> Self {
+        self.effects.push(Effect::Await { handle });
+        self
+    }
+
     /// Add a branch effect with multiple labeled continuations
     pub fn branch(mut self, choosing_role: R, branches: Vec<(Label, Program<R, M>)>) -> Self {
         self.effects.push(Effect::Branch {
             choosing_role,
             branches,
+            unknown: UnknownLabel::Reject,
         });
         self
     }
 
+    /// Choose at `at` the first branch whose guard holds, then run it
+    ///
+    /// Guards are evaluated in order against the interpreter's
+    /// [`GuardContext`](crate::effects::GuardContext); a branch without one
+    /// always qualifies. If none does, the program fails with
+    /// [`ChoreographyError::GuardUnsatisfied`].
+    pub fn choose_when(
+        mut self,
+        at: R,
+        branches: Vec<(Label, Option<Guard>, Program<R, M>)>,
+    ) -> Self {
+        let (guards, branches) = branches
+            .into_iter()
+            .map(|(label, guard, program)| ((label, guard), (label, program)))
+            .unzip();
+        self.effects.push(Effect::ChooseWhen { at, guards });
+        self.effects.push(Effect::Branch {
+            choosing_role: at,
+            branches,
+            unknown: UnknownLabel::Reject,
+        });
+        self
+    }
+
+    /// Add an offer from `from` followed by the continuation for each label
+    ///
+    /// Shorthand for `.offer(from).branch(from, branches)`. The interpreter
+    /// runs the continuation of whichever label the handler returns. Any
+    /// other label fails the program, unless it is a system label that
+    /// ends the session.
+    pub fn offer_branches(self, from: R, branches: Vec<(Label, Program<R, M>)>) -> Self {
+        self.offer(from).branch(from, branches)
+    }
+
+    /// Like [`offer_branches`](Self::offer_branches), with `unknown`
+    /// deciding what happens to a label none of the branches lists
+    pub fn offer_branches_or(
+        mut self,
+        from: R,
+        branches: Vec<(Label, Program<R, M>)>,
+        unknown: UnknownLabel,
+    ) -> Self {
+        self.effects.push(Effect::Offer { from });
+        self.effects.push(Effect::Branch {
+            choosing_role: from,
+            branches,
+            unknown,
+        });
+        self
+    }
+
+    /// Apply `unknown` to every branch in the program, nested ones included
+    ///
+    /// A fallback only applies to the branches that list its label; the
+    /// others keep their policy.
+    pub fn on_unknown_labels(mut self, unknown: UnknownLabel) -> Self {
+        self.set_unknown_labels(unknown);
+        self
+    }
+
+    fn set_unknown_labels(&mut self, policy: UnknownLabel) {
+        for effect in &mut self.effects {
+            match effect {
+                Effect::Branch {
+                    branches, unknown, ..
+                } => {
+                    let listed = match policy {
+                        UnknownLabel::Fallback(label) => branches.iter().any(|(l, _)| *l == label),
+                        _ => true,
+                    };
+                    if listed {
+                        *unknown = policy;
+                    }
+                    for (_, prog) in branches {
+                        prog.set_unknown_labels(policy);
+                    }
+                }
+                Effect::Loop { body, .. }
+                | Effect::LoopWhile { body, .. }
+                | Effect::Rec { body, .. }
+                | Effect::Spawn { program: body, .. } => body.set_unknown_labels(policy),
+                Effect::Timeout {
+                    body, on_timeout, ..
+                } => {
+                    body.set_unknown_labels(policy);
+                    if let Some(on_timeout) = on_timeout {
+                        on_timeout.set_unknown_labels(policy);
+                    }
+                }
+                Effect::Parallel { programs } => {
+                    for prog in programs {
+                        prog.set_unknown_labels(policy);
+                    }
+                }
+                Effect::Finally {
+                    body,
+                    cleanup: compensation,
+                }
+                | Effect::TryCatch {
+                    body, compensation, ..
+                } => {
+                    body.set_unknown_labels(policy);
+                    compensation.set_unknown_labels(policy);
+                }
+                _ => {}
+            }
+        }
+    }
+
     /// Add a loop effect
     pub fn loop_n(mut self, iterations: usize, body: Program<R, M>) -> Self {
         self.effects.push(Effect::Loop {
@@ -135,6 +456,59 @@ impl<R: RoleId, M> Program<R, M> {
         self
     }
 
+    /// Run `body` `n` times
+    ///
+    /// Same as [`loop_n`](Self::loop_n), mirroring a protocol loop with an
+    /// iteration count.
+    pub fn repeat(self, n: usize, body: Program<R, M>) -> Self {
+        self.loop_n(n, body)
+    }
+
+    /// Run `body` for as long as `condition` holds
+    ///
+    /// The condition is checked before every iteration, so the body may
+    /// not run at all.
+    pub fn loop_while(mut self, condition: LoopCondition<M>, body: Program<R, M>) -> Self {
+        self.effects.push(Effect::LoopWhile {
+            condition,
+            body: Box::new(body),
+        });
+        self
+    }
+
+    /// Add a recursion point: `body` runs again each time it reaches a
+    /// [`jump`](Self::jump) to `label`
+    ///
+    /// Mirrors `rec` blocks of a protocol. The body ends the recursion by
+    /// finishing without a jump, e.g. in a branch that does not take one.
+    pub fn rec(mut self, label: &'static str, body: Program<R, M>) -> Self {
+        self.effects.push(Effect::Rec {
+            label,
+            body: Box::new(body),
+        });
+        self
+    }
+
+    /// Go back to the start of the enclosing [`rec`](Self::rec) with
+    /// `label`
+    ///
+    /// Effects after the jump in the same body do not run.
+    pub fn jump(mut self, label: &'static str) -> Self {
+        self.effects.push(Effect::Jump { label });
+        self
+    }
+
+    /// Cancel the session: send `sys.cancel` to each of `notify`, let the
+    /// handler tear down its transport state, and stop
+    ///
+    /// Nothing after the cancel runs except the cleanup of enclosing
+    /// [`with_finally`](Self::with_finally) effects, and the program ends
+    /// as [`InterpreterState::Cancelled`].
+    pub fn cancel(mut self, notify: Vec<R>) -> Self {
+        self.effects.push(Effect::Cancel { notify });
+        self
+    }
+
     /// Mark the end of the program
     pub fn end(mut self) -> Self {
         self.effects.push(Effect::End);
@@ -181,14 +555,18 @@ impl<R: RoleId, M> Program<R, M> {
     fn collect_roles(&self, roles: &mut HashSet<R>) {
         for effect in &self.effects {
             match effect {
-                Effect::Send { to, .. } => {
+                Effect::Send { to, .. } | Effect::SendWith { to, .. } => {
                     roles.insert(*to);
                 }
                 Effect::Recv { from, .. } => {
                     roles.insert(*from);
                 }
-                Effect::Choose { at, .. } => {
+                Effect::Choose { at, .. } | Effect::ChooseWhen { at, .. } => {
+                    roles.insert(*at);
+                }
+                Effect::Route { at, router, .. } => {
                     roles.insert(*at);
+                    roles.extend(router.candidates.iter().map(|(_, role)| *role));
                 }
                 Effect::Offer { from } => {
                     roles.insert(*from);
@@ -196,25 +574,55 @@ impl<R: RoleId, M> Program<R, M> {
                 Effect::Branch {
                     choosing_role,
                     branches,
+                    ..
                 } => {
                     roles.insert(*choosing_role);
                     for (_, prog) in branches {
                         prog.collect_roles(roles);
                     }
                 }
-                Effect::Loop { body, .. } => {
+                Effect::Loop { body, .. }
+                | Effect::LoopWhile { body, .. }
+                | Effect::Rec { body, .. } => {
                     body.collect_roles(roles);
                 }
-                Effect::Timeout { at, body, .. } => {
+                Effect::Timeout {
+                    at,
+                    body,
+                    on_timeout,
+                    ..
+                } => {
                     roles.insert(*at);
                     body.collect_roles(roles);
+                    if let Some(on_timeout) = on_timeout {
+                        on_timeout.collect_roles(roles);
+                    }
                 }
                 Effect::Parallel { programs } => {
                     for prog in programs {
                         prog.collect_roles(roles);
                     }
                 }
-                Effect::End => {}
+                Effect::Finally { body, cleanup } => {
+                    body.collect_roles(roles);
+                    cleanup.collect_roles(roles);
+                }
+                Effect::TryCatch {
+                    body,
+                    notify,
+                    compensation,
+                } => {
+                    body.collect_roles(roles);
+                    roles.extend(notify.iter().copied());
+                    compensation.collect_roles(roles);
+                }
+                Effect::Spawn { program, .. } => program.collect_roles(roles),
+                Effect::Cancel { notify } => roles.extend(notify.iter().copied()),
+                Effect::Compute { .. }
+                | Effect::Bind { .. }
+                | Effect::Await { .. }
+                | Effect::Jump { .. }
+                | Effect::End => {}
             }
         }
     }
@@ -224,15 +632,20 @@ impl<R: RoleId, M> Program<R, M> {
         self.effects
             .iter()
             .map(|e| match e {
-                Effect::Send { .. } => 1,
+                Effect::Send { .. } | Effect::SendWith { .. } | Effect::Route { .. } => 1,
                 Effect::Branch { branches, .. } => branches
                     .iter()
                     .map(|(_, p)| p.send_count())
                     .max()
                     .unwrap_or(0),
-                Effect::Loop { body, .. } => body.send_count(),
+                Effect::Loop { body, .. }
+                | Effect::LoopWhile { body, .. }
+                | Effect::Rec { body, .. } => body.send_count(),
                 Effect::Timeout { body, .. } => body.send_count(),
                 Effect::Parallel { programs } => programs.iter().map(|p| p.send_count()).sum(),
+                Effect::Finally { body, cleanup } => body.send_count() + cleanup.send_count(),
+                Effect::TryCatch { body, .. } => body.send_count(),
+                Effect::Spawn { program, .. } => program.send_count(),
                 _ => 0,
             })
             .sum()
@@ -249,9 +662,14 @@ impl<R: RoleId, M> Program<R, M> {
                     .map(|(_, p)| p.recv_count())
                     .max()
                     .unwrap_or(0),
-                Effect::Loop { body, .. } => body.recv_count(),
+                Effect::Loop { body, .. }
+                | Effect::LoopWhile { body, .. }
+                | Effect::Rec { body, .. } => body.recv_count(),
                 Effect::Timeout { body, .. } => body.recv_count(),
                 Effect::Parallel { programs } => programs.iter().map(|p| p.recv_count()).sum(),
+                Effect::Finally { body, cleanup } => body.recv_count() + cleanup.recv_count(),
+                Effect::TryCatch { body, .. } => body.recv_count(),
+                Effect::Spawn { program, .. } => program.recv_count(),
                 _ => 0,
             })
             .sum()
@@ -272,26 +690,80 @@ impl<R: RoleId, M> Program<R, M> {
     }
 
     /// Validate that the program is well-formed
+    ///
+    /// Every jump must be inside a `rec` with its label.
     pub fn validate(&self) -> Result<(), ProgramError> {
+        self.validate_in(&mut Vec::new())
+    }
+
+    fn validate_in(&self, recs: &mut Vec<&'static str>) -> Result<(), ProgramError> {
         for effect in &self.effects {
             match effect {
-                Effect::Branch { branches, .. } => {
+                Effect::Branch {
+                    branches, unknown, ..
+                } => {
                     if branches.is_empty() {
                         return Err(ProgramError::InvalidStructure(
                             "Branch must have at least one branch".to_string(),
                         ));
                     }
+                    if let UnknownLabel::Fallback(label) = unknown {
+                        if !branches.iter().any(|(l, _)| l == label) {
+                            return Err(ProgramError::InvalidStructure(format!(
+                                "fallback label {} is not one of the branches",
+                                label.0
+                            )));
+                        }
+                    }
                     for (_, prog) in branches {
-                        prog.validate()?;
+                        prog.validate_in(recs)?;
+                    }
+                }
+                Effect::Loop { body, .. } | Effect::LoopWhile { body, .. } => {
+                    body.validate_in(recs)?
+                }
+                Effect::Rec { label, body } => {
+                    recs.push(label);
+                    let result = body.validate_in(recs);
+                    recs.pop();
+                    result?
+                }
+                Effect::Route { router, .. } if router.candidates.is_empty() => {
+                    return Err(ProgramError::InvalidStructure(format!(
+                        "router {} has no candidates",
+                        router.name
+                    )));
+                }
+                Effect::Jump { label } if !recs.contains(label) => {
+                    return Err(ProgramError::InvalidStructure(format!(
+                        "jump to {} outside of a rec with that label",
+                        label
+                    )));
+                }
+                Effect::Timeout {
+                    body, on_timeout, ..
+                } => {
+                    body.validate_in(recs)?;
+                    if let Some(on_timeout) = on_timeout {
+                        on_timeout.validate_in(recs)?;
                     }
                 }
-                Effect::Loop { body, .. } => body.validate()?,
-                Effect::Timeout { body, .. } => body.validate()?,
                 Effect::Parallel { programs } => {
                     for prog in programs {
-                        prog.validate()?;
+                        prog.validate_in(recs)?;
                     }
                 }
+                Effect::Finally {
+                    body,
+                    cleanup: compensation,
+                }
+                | Effect::TryCatch {
+                    body, compensation, ..
+                } => {
+                    body.validate_in(recs)?;
+                    compensation.validate_in(recs)?;
+                }
+                Effect::Spawn { program, .. } => program.validate_in(recs)?,
                 _ => {}
             }
         }
@@ -299,6 +771,279 @@ impl<R: RoleId, M> Program<R, M> {
     }
 }
 
+impl<R: RoleId, M: std::fmt::Debug> Program<R, M> {
+    /// Render the effect tree as a DOT control-flow graph
+    ///
+    /// Each effect is a node. Branch and parallel arms are labelled edges,
+    /// and loops have a dashed back edge from the end of their body.
+    pub fn to_dot(&self) -> String {
+        let mut builder = DotBuilder {
+            dot: String::from("digraph Program {\n"),
+            next: 0,
+            recs: Vec::new(),
+        };
+        builder.dot.push_str("  rankdir=TB;\n");
+        builder.dot.push_str("  node [shape=box];\n");
+        builder.dot.push_str("  start [shape=point];\n");
+        builder.program(self, vec![("start".to_string(), None)]);
+        builder.dot.push_str("}\n");
+        builder.dot
+    }
+
+    fn fmt_tree(&self, f: &mut std::fmt::Formatter<'_>, depth: usize) -> std::fmt::Result {
+        for effect in &self.effects {
+            effect.fmt_tree(f, depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// One line per effect, with nested programs indented below their effect
+impl<R: RoleId, M: std::fmt::Debug> std::fmt::Display for Program<R, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_tree(f, 0)
+    }
+}
+
+impl<R: RoleId, M: std::fmt::Debug> Effect<R, M> {
+    /// The effect itself, without nested programs
+    pub(crate) fn head(&self) -> String {
+        match self {
+            Effect::Send { to, msg } => format!("send {:?} to {:?}", msg, to),
+            Effect::Recv { from, msg_type } => {
+                format!("recv {} from {:?}", short_type_name(msg_type), from)
+            }
+            Effect::Choose { at, label } => format!("choose {} at {:?}", label.0, at),
+            Effect::ChooseWhen { at, guards } => {
+                let labels: Vec<&str> = guards.iter().map(|(label, _)| label.0).collect();
+                format!("choose one of {} at {:?}", labels.join(", "), at)
+            }
+            Effect::Route { msg, router, .. } => {
+                let candidates: Vec<String> = router
+                    .candidates
+                    .iter()
+                    .map(|(_, role)| format!("{:?}", role))
+                    .collect();
+                format!(
+                    "route {:?} to one of {} by {}",
+                    msg,
+                    candidates.join(", "),
+                    router.name
+                )
+            }
+            Effect::Offer { from } => format!("offer from {:?}", from),
+            Effect::Branch { choosing_role, .. } => format!("branch on {:?}", choosing_role),
+            Effect::Loop {
+                iterations: Some(n),
+                ..
+            } => format!("loop x{}", n),
+            Effect::Loop {
+                iterations: None, ..
+            } => "loop".to_string(),
+            Effect::LoopWhile { condition, .. } => format!("loop while {}", condition.name),
+            Effect::Rec { label, .. } => format!("rec {}", label),
+            Effect::Jump { label } => format!("jump {}", label),
+            Effect::Timeout { at, dur, .. } => format!("timeout {:?} at {:?}", dur, at),
+            Effect::Parallel { .. } => "parallel".to_string(),
+            Effect::Finally { .. } => "finally".to_string(),
+            Effect::TryCatch { notify, .. } if notify.is_empty() => "try".to_string(),
+            Effect::TryCatch { notify, .. } => format!("try, notifying {:?}", notify),
+            Effect::Compute { computation } if computation.pure => {
+                format!("compute {} (pure)", computation.name)
+            }
+            Effect::Compute { computation } => format!("compute {}", computation.name),
+            Effect::Bind { name } => format!("bind {}", name),
+            Effect::SendWith {
+                to,
+                name,
+                computation,
+            } => format!("send {}({}) to {:?}", computation.name, name, to),
+            Effect::Spawn { handle, .. } => format!("spawn {}", handle),
+            Effect::Await { handle } => format!("await {}", handle),
+            Effect::Cancel { notify } if notify.is_empty() => "cancel".to_string(),
+            Effect::Cancel { notify } => format!("cancel, notifying {:?}", notify),
+            Effect::End => "end".to_string(),
+        }
+    }
+
+    fn fmt_tree(&self, f: &mut std::fmt::Formatter<'_>, depth: usize) -> std::fmt::Result {
+        let indent = "  ".repeat(depth);
+        writeln!(f, "{}{}", indent, self.head())?;
+        let mut arm = |name: &str, program: &Program<R, M>| -> std::fmt::Result {
+            writeln!(f, "{}  {}:", indent, name)?;
+            program.fmt_tree(f, depth + 2)
+        };
+        match self {
+            Effect::Branch { branches, .. } => {
+                for (label, program) in branches {
+                    arm(label.0, program)?;
+                }
+            }
+            Effect::Parallel { programs } => {
+                for (i, program) in programs.iter().enumerate() {
+                    arm(&format!("[{}]", i), program)?;
+                }
+            }
+            Effect::Finally { body, cleanup } => {
+                arm("body", body)?;
+                arm("cleanup", cleanup)?;
+            }
+            Effect::TryCatch {
+                body, compensation, ..
+            } => {
+                arm("body", body)?;
+                arm("catch", compensation)?;
+            }
+            Effect::Timeout {
+                body,
+                on_timeout: Some(on_timeout),
+                ..
+            } => {
+                arm("body", body)?;
+                arm("else", on_timeout)?;
+            }
+            Effect::Loop { body, .. }
+            | Effect::LoopWhile { body, .. }
+            | Effect::Rec { body, .. }
+            | Effect::Timeout { body, .. }
+            | Effect::Spawn { program: body, .. } => {
+                body.fmt_tree(f, depth + 1)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<R: RoleId, M: std::fmt::Debug> std::fmt::Display for Effect<R, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_tree(f, 0)
+    }
+}
+
+/// Edges still waiting for their target node: source and optional label
+type Pending = Vec<(String, Option<String>)>;
+
+struct DotBuilder {
+    dot: String,
+    next: usize,
+    /// Nodes of the enclosing `rec` effects, by label
+    recs: Vec<(&'static str, String)>,
+}
+
+impl DotBuilder {
+    fn node(&mut self, label: &str, incoming: Pending) -> String {
+        let id = format!("e{}", self.next);
+        self.next += 1;
+        let _ = writeln!(self.dot, "  {} [label=\"{}\"];", id, escape(label));
+        for (from, edge_label) in incoming {
+            self.edge(&from, &id, edge_label.as_deref(), None);
+        }
+        id
+    }
+
+    fn edge(&mut self, from: &str, to: &str, label: Option<&str>, style: Option<&str>) {
+        let mut attrs = Vec::new();
+        if let Some(label) = label {
+            attrs.push(format!("label=\"{}\"", escape(label)));
+        }
+        if let Some(style) = style {
+            attrs.push(format!("style={}", style));
+        }
+        if attrs.is_empty() {
+            let _ = writeln!(self.dot, "  {} -> {};", from, to);
+        } else {
+            let _ = writeln!(self.dot, "  {} -> {} [{}];", from, to, attrs.join(", "));
+        }
+    }
+
+    fn program<R: RoleId, M: std::fmt::Debug>(
+        &mut self,
+        program: &Program<R, M>,
+        mut pending: Pending,
+    ) -> Pending {
+        for effect in &program.effects {
+            pending = self.effect(effect, pending);
+        }
+        pending
+    }
+
+    fn effect<R: RoleId, M: std::fmt::Debug>(
+        &mut self,
+        effect: &Effect<R, M>,
+        incoming: Pending,
+    ) -> Pending {
+        let id = self.node(&effect.head(), incoming);
+        let from = |label: &str| vec![(id.clone(), Some(label.to_string()))];
+        match effect {
+            Effect::Branch { branches, .. } => {
+                let mut exits = Vec::new();
+                for (label, program) in branches {
+                    exits.extend(self.program(program, from(label.0)));
+                }
+                exits
+            }
+            Effect::Parallel { programs } => {
+                let mut exits = Vec::new();
+                for (i, program) in programs.iter().enumerate() {
+                    exits.extend(self.program(program, from(&format!("[{}]", i))));
+                }
+                exits
+            }
+            Effect::Loop { body, .. } | Effect::LoopWhile { body, .. } => {
+                for (exit, _) in self.program(body, from("body")) {
+                    self.edge(&exit, &id, Some("repeat"), Some("dashed"));
+                }
+                from("done")
+            }
+            Effect::Rec { label, body } => {
+                self.recs.push((label, id.clone()));
+                let exits = self.program(body, from("body"));
+                self.recs.pop();
+                exits
+            }
+            Effect::Jump { label } => {
+                if let Some((_, rec)) = self.recs.iter().rev().find(|(l, _)| l == label) {
+                    let rec = rec.clone();
+                    self.edge(&id, &rec, None, Some("dashed"));
+                }
+                // Nothing after a jump runs
+                Vec::new()
+            }
+            Effect::Timeout {
+                body, on_timeout, ..
+            } => {
+                let mut exits = self.program(body, from("body"));
+                if let Some(on_timeout) = on_timeout {
+                    exits.extend(self.program(on_timeout, from("timeout")));
+                }
+                exits
+            }
+            Effect::Finally { body, cleanup } => {
+                let after_body = self.program(body, from("body"));
+                self.program(cleanup, after_body)
+            }
+            Effect::TryCatch {
+                body, compensation, ..
+            } => {
+                let mut exits = self.program(body, from("body"));
+                exits.extend(self.program(compensation, from("catch")));
+                exits
+            }
+            Effect::Spawn { program, .. } => {
+                // The child runs on its own; the parent carries on at once
+                self.program(program, from("child"));
+                vec![(id, None)]
+            }
+            _ => vec![(id, None)],
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 /// Errors that can occur during program construction or analysis
 #[derive(Debug, Clone, PartialEq)]
 pub enum ProgramError {
@@ -310,6 +1055,9 @@ pub enum ProgramError {
 
     /// Program contains unreachable effects
     UnreachableCode,
+
+    /// Program does not follow the session type it was checked against
+    SessionMismatch { expected: String, found: String },
 }
 
 impl std::fmt::Display for ProgramError {
@@ -320,6 +1068,13 @@ impl std::fmt::Display for ProgramError {
                 write!(f, "Unbalanced send/receive operations")
             }
             ProgramError::UnreachableCode => write!(f, "Program contains unreachable code"),
+            ProgramError::SessionMismatch { expected, found } => {
+                write!(
+                    f,
+                    "Program diverges from session type: expected {}, found {}",
+                    expected, found
+                )
+            }
         }
     }
 }
@@ -332,8 +1087,15 @@ pub struct InterpretResult<M> {
     /// Messages received during execution
     pub received_values: Vec<M>,
 
+    /// Values kept with `bind` or `recv_into`, by name
+    pub bindings: HashMap<&'static str, M>,
+
     /// Final state of the interpreter
     pub final_state: InterpreterState,
+
+    /// The error that failed or timed out the program, with the step that
+    /// raised it
+    pub error: Option<ChoreographyError>,
 }
 
 /// State of the program interpreter
@@ -347,6 +1109,9 @@ pub enum InterpreterState {
 
     /// Program failed with an error
     Failed(String),
+
+    /// Program was stopped by a cancel effect or its cancellation token
+    Cancelled,
 }
 
 /// Type alias for any message type that can be used in programs
@@ -0,0 +1,161 @@
+// Signed choreography manifests
+//
+// A deployment approves a protocol by signing a manifest of the compiled
+// choreography: its fingerprint plus descriptive metadata. Participants
+// exchange their signed manifests before the protocol starts and refuse to
+// run unless every peer holds an approved manifest for the same fingerprint.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::ast::Choreography;
+use crate::compiler::cache::fingerprint;
+use crate::effects::identity::{
+    IdentityError, IdentityResult, PublicKey, RoleCertificate, RoleIdentity,
+};
+use crate::effects::{ChoreoHandler, ChoreographyError, Result};
+
+/// Description of a compiled choreography
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChoreographyManifest {
+    pub name: String,
+    /// [`Fingerprint`](crate::compiler::Fingerprint) of the choreography
+    pub fingerprint: String,
+    pub roles: Vec<String>,
+    /// The choreography's `version` attribute, if any
+    pub version: Option<String>,
+    /// Version of the compiler that produced the fingerprint
+    pub compiler_version: String,
+    /// Free-form provenance such as the source revision or approver
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ChoreographyManifest {
+    pub fn new(choreography: &Choreography) -> Self {
+        Self {
+            name: choreography.name.to_string(),
+            fingerprint: fingerprint(choreography).to_string(),
+            roles: choreography
+                .roles
+                .iter()
+                .map(|role| role.name.to_string())
+                .collect(),
+            version: choreography.attrs.get("version").cloned(),
+            compiler_version: env!("CARGO_PKG_VERSION").to_string(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Whether this manifest describes `choreography`
+    pub fn matches(&self, choreography: &Choreography) -> bool {
+        self.fingerprint == fingerprint(choreography).to_string()
+    }
+
+    /// Sign the manifest as `signer`
+    pub fn sign(self, signer: &RoleIdentity) -> IdentityResult<SignedChoreography> {
+        let signature = signer.sign(&manifest_bytes(&self)?);
+        Ok(SignedChoreography {
+            manifest: self,
+            signer: signer.certificate().clone(),
+            signature,
+        })
+    }
+}
+
+/// A manifest with the signature of the identity that approved it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedChoreography {
+    pub manifest: ChoreographyManifest,
+    /// Certificate of the approving identity
+    pub signer: RoleCertificate,
+    #[serde(with = "crate::effects::identity::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedChoreography {
+    /// Check that an identity certified by `authority` signed the manifest
+    pub fn verify(&self, authority: &PublicKey) -> IdentityResult<()> {
+        RoleIdentity::verify(
+            &self.signer,
+            &self.signer.role,
+            authority,
+            &manifest_bytes(&self.manifest)?,
+            &self.signature,
+        )
+    }
+
+    /// Check the signature and that the manifest describes `choreography`
+    pub fn verify_for(
+        &self,
+        choreography: &Choreography,
+        authority: &PublicKey,
+    ) -> IdentityResult<()> {
+        self.verify(authority)?;
+        let expected = fingerprint(choreography).to_string();
+        if self.manifest.fingerprint != expected {
+            return Err(IdentityError::FingerprintMismatch {
+                expected,
+                found: self.manifest.fingerprint.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Exchange signed manifests with `peers` before running a protocol
+///
+/// Sends `local` to every peer, then receives each peer's manifest and
+/// checks that it is signed by an identity `authority` certified and that
+/// its fingerprint equals the local one. Fails with
+/// [`ChoreographyError::UnapprovedProtocol`] naming the first peer that
+/// does not pass. The exchange is not part of the choreography, so run it
+/// on an endpoint that does not track a session type.
+pub async fn handshake<H: ChoreoHandler>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    peers: &[H::Role],
+    local: &SignedChoreography,
+    authority: &PublicKey,
+) -> Result<()> {
+    for peer in peers {
+        handler.send(endpoint, *peer, local).await?;
+    }
+    for peer in peers {
+        let remote: SignedChoreography = handler.recv(endpoint, *peer).await?;
+        let checked = remote.verify(authority).and_then(|()| {
+            if remote.manifest.fingerprint == local.manifest.fingerprint {
+                Ok(())
+            } else {
+                Err(IdentityError::FingerprintMismatch {
+                    expected: local.manifest.fingerprint.clone(),
+                    found: remote.manifest.fingerprint.clone(),
+                })
+            }
+        });
+        if let Err(e) = checked {
+            tracing::warn!(?peer, error = %e, "peer failed choreography approval");
+            return Err(ChoreographyError::UnapprovedProtocol {
+                role: format!("{:?}", peer),
+                reason: e.to_string(),
+            });
+        }
+        tracing::debug!(
+            ?peer,
+            fingerprint = %remote.manifest.fingerprint,
+            "peer runs approved choreography"
+        );
+    }
+    Ok(())
+}
+
+fn manifest_bytes(manifest: &ChoreographyManifest) -> IdentityResult<Vec<u8>> {
+    serde_json::to_vec(manifest).map_err(|e| IdentityError::Malformed {
+        what: "manifest",
+        reason: e.to_string(),
+    })
+}
@@ -0,0 +1,349 @@
+// Bridging sessions of different choreographies
+//
+// A monolithic protocol is easier to split up if the pieces can keep
+// talking while they are pulled apart. A bridge plays one role in a
+// session of each choreography and forwards designated messages from one
+// session into the other. Routes are declared in a `BridgeConfig`, which
+// maps the sending role and message name on one side to the receiving role
+// and message name on the other.
+//
+// Each side runs the bridge role's ordinary program under a
+// `BridgeHandler`. When that program receives a routed message, the handler
+// passes it across. When the program on the other side sends the message
+// the route names, the placeholder it holds is replaced by the forwarded
+// one. Messages cross as JSON, so the two choreographies need no shared
+// types, only matching fields.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+
+/// One forwarded message: who it comes from on one side, and who it goes to
+/// on the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeRoute<From, To> {
+    pub from: From,
+    pub message: String,
+    pub to: To,
+    /// Name of the message on the receiving side
+    pub as_message: String,
+}
+
+/// Routes between a session of choreography `A` and one of `B`
+///
+/// Roles appear as they do in each choreography. A route for a message
+/// that the bridge role never receives or sends is never used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "A: Serialize, B: Serialize",
+    deserialize = "A: Deserialize<'de>, B: Deserialize<'de>"
+))]
+pub struct BridgeConfig<A, B> {
+    /// Messages received in the `A` session and sent on in the `B` session
+    #[serde(default)]
+    pub forward: Vec<BridgeRoute<A, B>>,
+    /// Messages received in the `B` session and sent on in the `A` session
+    #[serde(default)]
+    pub backward: Vec<BridgeRoute<B, A>>,
+}
+
+impl<A, B> Default for BridgeConfig<A, B> {
+    fn default() -> Self {
+        Self {
+            forward: Vec::new(),
+            backward: Vec::new(),
+        }
+    }
+}
+
+impl<A: RoleId, B: RoleId> BridgeConfig<A, B> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forward `message` from `from` in `A` to `to` in `B` as `as_message`
+    pub fn forward(mut self, from: A, message: &str, to: B, as_message: &str) -> Self {
+        self.forward.push(BridgeRoute {
+            from,
+            message: message.to_string(),
+            to,
+            as_message: as_message.to_string(),
+        });
+        self
+    }
+
+    /// Forward `message` from `from` in `B` to `to` in `A` as `as_message`
+    pub fn backward(mut self, from: B, message: &str, to: A, as_message: &str) -> Self {
+        self.backward.push(BridgeRoute {
+            from,
+            message: message.to_string(),
+            to,
+            as_message: as_message.to_string(),
+        });
+        self
+    }
+
+    /// Wrap the bridge role's handler in each session
+    ///
+    /// `MA` and `MB` are the message types of the two choreographies. Run
+    /// the two handlers concurrently, each owned by the task that
+    /// interprets its side, so that a side that finishes early closes its
+    /// routes instead of leaving the other waiting.
+    pub fn split<HA, HB, MA, MB>(
+        self,
+        a: HA,
+        b: HB,
+    ) -> (BridgeHandler<HA, MA>, BridgeHandler<HB, MB>)
+    where
+        HA: ChoreoHandler<Role = A>,
+        HB: ChoreoHandler<Role = B>,
+    {
+        let mut side_a = BridgeHandler::new(a);
+        let mut side_b = BridgeHandler::new(b);
+        for route in self.forward {
+            let (tx, rx) = unbounded();
+            side_a.outbound.push(Outbound {
+                from: route.from,
+                message: route.message,
+                as_message: route.as_message.clone(),
+                tx,
+            });
+            side_b.inbound.push(Inbound {
+                to: route.to,
+                message: route.as_message,
+                rx,
+            });
+        }
+        for route in self.backward {
+            let (tx, rx) = unbounded();
+            side_b.outbound.push(Outbound {
+                from: route.from,
+                message: route.message,
+                as_message: route.as_message.clone(),
+                tx,
+            });
+            side_a.inbound.push(Inbound {
+                to: route.to,
+                message: route.as_message,
+                rx,
+            });
+        }
+        (side_a, side_b)
+    }
+}
+
+impl<A, B> BridgeConfig<A, B>
+where
+    A: Serialize + DeserializeOwned,
+    B: Serialize + DeserializeOwned,
+{
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+}
+
+/// A route whose messages this side receives
+struct Outbound<R> {
+    from: R,
+    message: String,
+    as_message: String,
+    tx: UnboundedSender<Value>,
+}
+
+/// A route whose messages this side sends
+struct Inbound<R> {
+    to: R,
+    message: String,
+    rx: UnboundedReceiver<Value>,
+}
+
+/// The bridge role's handler in one of the two sessions
+///
+/// Created by [`BridgeConfig::split`]. Everything that is not routed goes
+/// straight to the wrapped handler.
+pub struct BridgeHandler<H: ChoreoHandler, M> {
+    inner: H,
+    outbound: Vec<Outbound<H::Role>>,
+    inbound: Vec<Inbound<H::Role>>,
+    forwarded: usize,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<H: ChoreoHandler, M> BridgeHandler<H, M> {
+    fn new(inner: H) -> Self {
+        Self {
+            inner,
+            outbound: Vec::new(),
+            inbound: Vec::new(),
+            forwarded: 0,
+            _message: PhantomData,
+        }
+    }
+
+    /// Messages this side has passed to the other so far
+    pub fn forwarded(&self) -> usize {
+        self.forwarded
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+/// The name a message is routed by
+///
+/// Enum variants are named by their variant, other messages by their type.
+pub(crate) fn message_name<T>(value: &Value) -> String {
+    let variant = match value {
+        Value::String(name) => Some(name.as_str()),
+        Value::Object(fields) if fields.len() == 1 => fields.keys().next().map(String::as_str),
+        _ => None,
+    };
+    match variant {
+        Some(name) if name.starts_with(|c: char| c.is_ascii_uppercase()) => name.to_string(),
+        _ => {
+            let name = std::any::type_name::<T>();
+            let base = name.split('<').next().unwrap_or(name);
+            base.rsplit("::").next().unwrap_or(base).to_string()
+        }
+    }
+}
+
+/// Give a forwarded variant its name on the receiving side
+fn rename(value: Value, from: &str, to: &str) -> Value {
+    match value {
+        Value::String(name) if name == from => Value::String(to.to_string()),
+        Value::Object(mut fields) if fields.len() == 1 && fields.contains_key(from) => {
+            if let Some(inner) = fields.remove(from) {
+                fields.insert(to.to_string(), inner);
+            }
+            Value::Object(fields)
+        }
+        other => other,
+    }
+}
+
+fn to_json<T: Serialize>(msg: &T) -> Result<Value> {
+    serde_json::to_value(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(value: Value) -> Result<T> {
+    serde_json::from_value(value).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+}
+
+#[async_trait]
+impl<H, M> ChoreoHandler for BridgeHandler<H, M>
+where
+    H: ChoreoHandler,
+    M: Serialize + DeserializeOwned + Send + Sync,
+{
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<T: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &T,
+    ) -> Result<()> {
+        if !self.inbound.iter().any(|route| route.to == to) {
+            return self.inner.send(ep, to, msg).await;
+        }
+        let name = message_name::<T>(&to_json(msg)?);
+        let Some(route) = self
+            .inbound
+            .iter_mut()
+            .find(|route| route.to == to && route.message == name)
+        else {
+            return self.inner.send(ep, to, msg).await;
+        };
+        // The program only holds a placeholder; send what was forwarded
+        let value = route.rx.next().await.ok_or_else(|| {
+            ChoreographyError::Transport(format!(
+                "bridged session ended before {} reached {:?}",
+                name, to
+            ))
+        })?;
+        let forwarded: M = from_json(value)?;
+        tracing::debug!(?to, %name, "bridge delivering forwarded message");
+        self.inner.send(ep, to, &forwarded).await
+    }
+
+    async fn recv<T: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<T> {
+        if !self.outbound.iter().any(|route| route.from == from) {
+            return self.inner.recv(ep, from).await;
+        }
+        let msg: M = self.inner.recv(ep, from).await?;
+        let value = to_json(&msg)?;
+        let name = message_name::<M>(&value);
+        if let Some(route) = self
+            .outbound
+            .iter()
+            .find(|route| route.from == from && route.message == name)
+        {
+            let renamed = rename(value.clone(), &route.message, &route.as_message);
+            route.tx.unbounded_send(renamed).map_err(|_| {
+                ChoreographyError::Transport(format!(
+                    "bridged session ended before {} from {:?} was forwarded",
+                    name, from
+                ))
+            })?;
+            self.forwarded += 1;
+            tracing::debug!(?from, %name, as_message = %route.as_message, "bridge forwarding");
+        }
+        from_json(value)
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
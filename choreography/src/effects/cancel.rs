@@ -0,0 +1,85 @@
+// Cooperative cancellation of running sessions
+//
+// A caller that no longer wants a session's result holds on to a
+// `CancellationToken` and passes a clone to `interpret_with_cancel`. Firing
+// the token stops the program at whatever effect it is waiting on, lets
+// the handler tear down its transport state through `on_cancel`, and ends
+// the interpretation as `Cancelled`. The token is plain futures code, so it
+// works the same on wasm.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    /// Tasks waiting in `cancelled`
+    waiters: Mutex<Vec<Waker>>,
+}
+
+/// Asks running sessions to stop
+///
+/// Clones share the same state, so firing any clone cancels every session
+/// interpreted with one of them. Cancelling twice has no further effect.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    shared: Arc<Shared>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        if self.shared.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let waiters = std::mem::take(
+            &mut *self
+                .shared
+                .waiters
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the token has been cancelled
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + '_ {
+        futures::future::poll_fn(move |cx| {
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+            let mut waiters = self
+                .shared
+                .waiters
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            // Checked again under the lock, which `cancel` takes to wake us
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl std::fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
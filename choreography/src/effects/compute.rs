@@ -0,0 +1,285 @@
+// Local computations inside effect programs
+//
+// A `compute` effect turns the most recently received message into a new
+// value without talking to any other role, e.g. validating a payload or
+// deriving a reply. Computations marked pure only depend on their input,
+// so the interpreter remembers their results for the rest of the session
+// and, given a `ComputeCache`, across sessions too. Loops that validate
+// the same payload again then skip the work.
+//
+// A `Router` computes where a message goes instead, picking one of a fixed
+// set of candidate roles from the message itself.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::effects::Label;
+
+type ComputeFn<M> = Arc<dyn Fn(&M) -> M + Send + Sync>;
+
+type ConditionFn<M> = Arc<dyn Fn(usize, Option<&M>) -> bool + Send + Sync>;
+
+type RouteFn<R, M> = Arc<dyn Fn(&M) -> R + Send + Sync>;
+
+/// Cached outputs by computation name and serialized input
+type Entries = HashMap<(String, Vec<u8>), Vec<u8>>;
+
+/// A named local computation from one message to another
+pub struct Computation<M> {
+    pub name: &'static str,
+    /// The result depends only on the input, so it may be memoized
+    pub pure: bool,
+    f: ComputeFn<M>,
+    cache: Option<Arc<dyn ComputeCache>>,
+}
+
+impl<M> Computation<M> {
+    /// A computation that runs every time, e.g. because it reads a clock
+    pub fn new(name: &'static str, f: impl Fn(&M) -> M + Send + Sync + 'static) -> Self {
+        Self {
+            name,
+            pure: false,
+            f: Arc::new(f),
+            cache: None,
+        }
+    }
+
+    /// A computation whose result depends only on its input
+    pub fn pure(name: &'static str, f: impl Fn(&M) -> M + Send + Sync + 'static) -> Self {
+        Self {
+            pure: true,
+            ..Self::new(name, f)
+        }
+    }
+
+    /// Share results with other sessions through `cache`
+    ///
+    /// Only used for pure computations. Entries are keyed on the
+    /// computation's name and the serialized input, so names must be
+    /// unique among computations sharing a cache.
+    pub fn with_cache(mut self, cache: Arc<dyn ComputeCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&Arc<dyn ComputeCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Run the computation without any memoization
+    pub fn apply(&self, input: &M) -> M {
+        (self.f)(input)
+    }
+}
+
+impl<M> Clone for Computation<M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            pure: self.pure,
+            f: self.f.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<M> std::fmt::Debug for Computation<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Computation")
+            .field("name", &self.name)
+            .field("pure", &self.pure)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Equal when both wrap the same function under the same name
+impl<M> PartialEq for Computation<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.pure == other.pure && Arc::ptr_eq(&self.f, &other.f)
+    }
+}
+
+/// A named test deciding whether a `loop_while` runs another iteration
+///
+/// It sees how many iterations have run so far and the most recently
+/// received value, if any.
+pub struct LoopCondition<M> {
+    pub name: &'static str,
+    f: ConditionFn<M>,
+}
+
+impl<M> LoopCondition<M> {
+    pub fn new(
+        name: &'static str,
+        f: impl Fn(usize, Option<&M>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            f: Arc::new(f),
+        }
+    }
+
+    pub fn holds(&self, iteration: usize, last: Option<&M>) -> bool {
+        (self.f)(iteration, last)
+    }
+}
+
+impl<M> Clone for LoopCondition<M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            f: self.f.clone(),
+        }
+    }
+}
+
+impl<M> std::fmt::Debug for LoopCondition<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoopCondition")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Equal when both wrap the same function under the same name
+impl<M> PartialEq for LoopCondition<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Arc::ptr_eq(&self.f, &other.f)
+    }
+}
+
+/// A named choice of the role a message goes to, made from its payload
+///
+/// Each candidate comes with the label that tells the other roles which
+/// candidate was picked, as a choice would.
+pub struct Router<R, M> {
+    pub name: &'static str,
+    pub candidates: Vec<(Label, R)>,
+    f: RouteFn<R, M>,
+}
+
+impl<R, M> Router<R, M> {
+    pub fn new(
+        name: &'static str,
+        candidates: Vec<(Label, R)>,
+        f: impl Fn(&M) -> R + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            candidates,
+            f: Arc::new(f),
+        }
+    }
+
+    /// The role `msg` goes to, which need not be a candidate
+    pub fn route(&self, msg: &M) -> R {
+        (self.f)(msg)
+    }
+}
+
+impl<R: PartialEq, M> Router<R, M> {
+    /// The label of `role`, if it is a candidate
+    pub fn label_of(&self, role: &R) -> Option<Label> {
+        self.candidates
+            .iter()
+            .find(|(_, candidate)| candidate == role)
+            .map(|(label, _)| *label)
+    }
+}
+
+impl<R: Clone, M> Clone for Router<R, M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            candidates: self.candidates.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+impl<R: std::fmt::Debug, M> std::fmt::Debug for Router<R, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field("name", &self.name)
+            .field("candidates", &self.candidates)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Equal when both wrap the same function under the same name and candidates
+impl<R: PartialEq, M> PartialEq for Router<R, M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.candidates == other.candidates
+            && Arc::ptr_eq(&self.f, &other.f)
+    }
+}
+
+/// Storage for pure computation results shared between sessions
+///
+/// Inputs and outputs are serialized messages, so a backend can live out
+/// of process.
+pub trait ComputeCache: Send + Sync {
+    fn get(&self, computation: &str, input: &[u8]) -> Option<Vec<u8>>;
+
+    fn put(&self, computation: &str, input: &[u8], output: Vec<u8>);
+}
+
+/// Process-local [`ComputeCache`]
+#[derive(Default)]
+pub struct InMemoryComputeCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl InMemoryComputeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl ComputeCache for InMemoryComputeCache {
+    fn get(&self, computation: &str, input: &[u8]) -> Option<Vec<u8>> {
+        let output = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&(computation.to_string(), input.to_vec()))
+            .cloned();
+        let counter = if output.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        output
+    }
+
+    fn put(&self, computation: &str, input: &[u8], output: Vec<u8>) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert((computation.to_string(), input.to_vec()), output);
+    }
+}
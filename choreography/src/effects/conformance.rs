@@ -0,0 +1,284 @@
+// Checking programs against session types
+//
+// A handwritten `Program` and the choreography it implements can drift
+// apart. Walking the program's effects through a `SessionCursor` finds the
+// first effect the projected session type does not allow, without running
+// the program or opening any channels.
+//
+// The check runs when it is called, not during compilation. A `Program` is
+// built at runtime from boxed messages and closures, so it cannot be
+// evaluated in a const context, and the proc-macro crate cannot depend on
+// the choreography compiler to project a session type of its own. Calling
+// the check from a unit test next to the program makes drift fail
+// `cargo test` instead.
+
+use crate::effects::algebra::{Effect, Program, ProgramError, ProgramMessage};
+use crate::effects::handlers::session::{short_type_name, SessionCursor, SessionType};
+use crate::effects::{ChoreographyError, RoleId};
+
+impl<R: RoleId, M: ProgramMessage> Program<R, M> {
+    /// Check that every run of this program follows `session`
+    ///
+    /// Sent messages are named by their `Debug` head, so an enum variant
+    /// `Ping(..)` matches a session step for message `Ping`. After an
+    /// `offer`, every branch is checked against the session type, together
+    /// with the effects that follow the branch. A loop without an iteration
+    /// count, each `loop_while` iteration, and every jump must bring the
+    /// session back to where the loop or `rec` started. The compensation of
+    /// a `try_catch` and the `else` of a timeout are not checked, since
+    /// they only run once the session was aborted. The whole session type
+    /// must be used up when the program ends.
+    ///
+    /// This is a runtime check; there is no compile-time form of it, see
+    /// the module notes.
+    pub fn check_against(&self, session: &SessionType<R>) -> Result<(), ProgramError> {
+        let mut cursor = SessionCursor::new(session.clone());
+        if check(&self.effects, &mut cursor, &[])? == Flow::Continue && !cursor.is_complete() {
+            return Err(ProgramError::SessionMismatch {
+                expected: cursor.current().to_string(),
+                found: "end of program".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Flow {
+    /// Later effects still run
+    Continue,
+    /// Everything after this point was checked already, or never runs
+    Done,
+}
+
+/// Where each enclosing `rec` started, innermost last
+type Recs<'a, R> = &'a [(&'static str, SessionType<R>)];
+
+fn check<R: RoleId, M: ProgramMessage>(
+    effects: &[Effect<R, M>],
+    cursor: &mut SessionCursor<R>,
+    recs: Recs<'_, R>,
+) -> Result<Flow, ProgramError> {
+    let mut i = 0;
+    while i < effects.len() {
+        match &effects[i] {
+            Effect::Send { to, msg } => cursor
+                .send(*to, debug_name(msg).as_str())
+                .map_err(mismatch)?,
+            Effect::SendWith {
+                to, computation, ..
+            } => {
+                // The message is only built at runtime, so any message to
+                // the right peer fits
+                let name = match cursor.current() {
+                    SessionType::Send { message, .. } => message.clone(),
+                    _ => computation.name.to_string(),
+                };
+                cursor.send(*to, &name).map_err(mismatch)?
+            }
+            Effect::Recv { from, msg_type } => cursor
+                .receive(*from, short_type_name(msg_type))
+                .map_err(mismatch)?,
+            Effect::Choose { at, label } => {
+                cursor.select(*at, label.0).map_err(mismatch)?;
+                if let Some(Effect::Branch { branches, .. }) = effects.get(i + 1) {
+                    if let Some((_, branch)) = branches.iter().find(|(l, _)| l == label) {
+                        if check(&branch.effects, cursor, recs)? == Flow::Done {
+                            return Ok(Flow::Done);
+                        }
+                    }
+                    i += 1;
+                }
+            }
+            Effect::ChooseWhen { at, guards } => {
+                // Any label whose guard may hold can be chosen
+                let Some(Effect::Branch { branches, .. }) = effects.get(i + 1) else {
+                    return Err(ProgramError::InvalidStructure(
+                        "a guarded choice must be followed by a branch".to_string(),
+                    ));
+                };
+                let rest = &effects[i + 2..];
+                for (label, _) in guards {
+                    let mut arm = cursor.clone();
+                    arm.select(*at, label.0).map_err(mismatch)?;
+                    if let Some((_, branch)) = branches.iter().find(|(l, _)| l == label) {
+                        check_sequence(&branch.effects, rest, &mut arm, recs)?;
+                    }
+                }
+                return Ok(Flow::Done);
+            }
+            Effect::Route { at, msg, router } => {
+                // Any candidate may be picked, and a branch may follow
+                let branches = match effects.get(i + 1) {
+                    Some(Effect::Branch { branches, .. }) => Some(branches),
+                    _ => None,
+                };
+                let rest = &effects[i + 1 + usize::from(branches.is_some())..];
+                for (label, to) in &router.candidates {
+                    let mut arm = cursor.clone();
+                    arm.select(*at, label.0).map_err(mismatch)?;
+                    arm.send(*to, debug_name(msg).as_str()).map_err(mismatch)?;
+                    let branch = branches
+                        .and_then(|branches| branches.iter().find(|(l, _)| l == label))
+                        .map_or(&[][..], |(_, branch)| &branch.effects[..]);
+                    check_sequence(branch, rest, &mut arm, recs)?;
+                }
+                return Ok(Flow::Done);
+            }
+            Effect::Offer { from } => {
+                cursor.expect_branch(*from).map_err(mismatch)?;
+                let Some(Effect::Branch { branches, .. }) = effects.get(i + 1) else {
+                    return Err(ProgramError::InvalidStructure(
+                        "offer must be followed by a branch".to_string(),
+                    ));
+                };
+                let rest = &effects[i + 2..];
+                for (label, branch) in branches {
+                    let mut arm = cursor.clone();
+                    arm.branch(*from, label.0).map_err(mismatch)?;
+                    check_sequence(&branch.effects, rest, &mut arm, recs)?;
+                }
+                return Ok(Flow::Done);
+            }
+            Effect::Branch { branches, .. } => {
+                // Not part of a choice this role takes part in: any arm may run
+                let rest = &effects[i + 1..];
+                for (_, branch) in branches {
+                    check_sequence(&branch.effects, rest, &mut cursor.clone(), recs)?;
+                }
+                return Ok(Flow::Done);
+            }
+            Effect::Loop {
+                iterations: Some(n),
+                body,
+            } => {
+                for _ in 0..*n {
+                    if check(&body.effects, cursor, recs)? == Flow::Done {
+                        return Ok(Flow::Done);
+                    }
+                }
+            }
+            Effect::Loop {
+                iterations: None,
+                body,
+            } => {
+                let entry = cursor.current().clone();
+                if check(&body.effects, cursor, recs)? == Flow::Done {
+                    return Ok(Flow::Done);
+                }
+                if *cursor.current() != entry {
+                    return Err(ProgramError::SessionMismatch {
+                        expected: entry.to_string(),
+                        found: format!("loop body ending at {}", cursor.current()),
+                    });
+                }
+                return Ok(Flow::Done);
+            }
+            Effect::LoopWhile { body, .. } => {
+                // The body may run any number of times, including none
+                let entry = cursor.current().clone();
+                let mut iteration = cursor.clone();
+                if check(&body.effects, &mut iteration, recs)? == Flow::Continue
+                    && *iteration.current() != entry
+                {
+                    return Err(ProgramError::SessionMismatch {
+                        expected: entry.to_string(),
+                        found: format!("loop body ending at {}", iteration.current()),
+                    });
+                }
+            }
+            Effect::Rec { label, body } => {
+                let mut inner = recs.to_vec();
+                inner.push((*label, cursor.current().clone()));
+                if check(&body.effects, cursor, &inner)? == Flow::Done {
+                    return Ok(Flow::Done);
+                }
+            }
+            Effect::Jump { label } => {
+                let Some((_, entry)) = recs.iter().rev().find(|(l, _)| l == label) else {
+                    return Err(ProgramError::InvalidStructure(format!(
+                        "jump to {} outside of a rec with that label",
+                        label
+                    )));
+                };
+                if cursor.current() != entry {
+                    return Err(ProgramError::SessionMismatch {
+                        expected: entry.to_string(),
+                        found: format!("jump to {} at {}", label, cursor.current()),
+                    });
+                }
+                return Ok(Flow::Done);
+            }
+            Effect::Timeout { body, .. } => {
+                if check(&body.effects, cursor, recs)? == Flow::Done {
+                    return Ok(Flow::Done);
+                }
+            }
+            Effect::Parallel { programs } => {
+                for program in programs {
+                    if check(&program.effects, cursor, recs)? == Flow::Done {
+                        return Ok(Flow::Done);
+                    }
+                }
+            }
+            Effect::Finally { body, cleanup } => {
+                if check(&body.effects, cursor, recs)? == Flow::Done {
+                    return Ok(Flow::Done);
+                }
+                if check(&cleanup.effects, cursor, recs)? == Flow::Done {
+                    return Ok(Flow::Done);
+                }
+            }
+            // The compensation runs after an abort, which ends the session
+            Effect::TryCatch { body, .. } => {
+                if check(&body.effects, cursor, recs)? == Flow::Done {
+                    return Ok(Flow::Done);
+                }
+            }
+            // The rest of the session is given up
+            Effect::Cancel { .. } => return Ok(Flow::Done),
+            // Child sessions follow session types of their own
+            Effect::Spawn { .. } | Effect::Await { .. } => {}
+            Effect::Compute { .. } | Effect::Bind { .. } | Effect::End => {}
+        }
+        i += 1;
+    }
+    Ok(Flow::Continue)
+}
+
+/// Check `first` followed by `rest`, requiring the session to be used up
+fn check_sequence<R: RoleId, M: ProgramMessage>(
+    first: &[Effect<R, M>],
+    rest: &[Effect<R, M>],
+    cursor: &mut SessionCursor<R>,
+    recs: Recs<'_, R>,
+) -> Result<(), ProgramError> {
+    if check(first, cursor, recs)? == Flow::Done || check(rest, cursor, recs)? == Flow::Done {
+        return Ok(());
+    }
+    if !cursor.is_complete() {
+        return Err(ProgramError::SessionMismatch {
+            expected: cursor.current().to_string(),
+            found: "end of program".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn debug_name<M: std::fmt::Debug>(msg: &M) -> String {
+    let debug = format!("{:?}", msg);
+    debug
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn mismatch(error: ChoreographyError) -> ProgramError {
+    match error {
+        ChoreographyError::SessionMismatch { expected, found } => {
+            ProgramError::SessionMismatch { expected, found }
+        }
+        other => ProgramError::InvalidStructure(other.to_string()),
+    }
+}
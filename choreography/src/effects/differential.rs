@@ -0,0 +1,565 @@
+// Differential testing between handlers
+//
+// Unit tests of a transport check the operations it was written for, not
+// the interleavings a real protocol produces. A differential run executes
+// the same programs over two handler setups, for example in-memory channels
+// and TCP, and compares what every role observed. Programs fix their own
+// choices, so both runs take the same decisions and any difference comes
+// from the transports: reordered or duplicated messages, lost choices, or
+// a role failing on one side only.
+//
+// Traces are compared per role. The interleaving of different roles'
+// effects depends on scheduling and is not part of the comparison.
+//
+// Messages travel in an envelope naming the role whose program sent them.
+// When a setup hands a role the handler or endpoint built for another, its
+// messages arrive on that other role's links, and the receiver's check of
+// the envelope turns a hang or a confusing trace into an error naming both
+// roles.
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::effects::algebra::{Program, ProgramMessage};
+use crate::effects::handlers::session::short_type_name;
+use crate::effects::{interpret, ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+
+/// One effect as observed by a role
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceEvent<R> {
+    /// A message was sent, with its JSON form
+    Send {
+        to: R,
+        message: serde_json::Value,
+    },
+    /// A message of the named type was received
+    Recv {
+        from: R,
+        msg_type: String,
+    },
+    Choose {
+        at: R,
+        label: String,
+    },
+    /// A branch label was received
+    Offer {
+        from: R,
+        label: String,
+    },
+}
+
+impl<R: fmt::Debug> fmt::Display for TraceEvent<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEvent::Send { to, message } => write!(f, "send {} to {:?}", message, to),
+            TraceEvent::Recv { from, msg_type } => write!(f, "recv {} from {:?}", msg_type, from),
+            TraceEvent::Choose { at, label } => write!(f, "choose {} at {:?}", label, at),
+            TraceEvent::Offer { from, label } => write!(f, "offer {} from {:?}", label, from),
+        }
+    }
+}
+
+/// Handler wrapper that records every completed effect
+///
+/// Failed operations are not recorded; the failure shows up in the role's
+/// outcome instead.
+pub struct TraceRecorder<H: ChoreoHandler> {
+    inner: H,
+    events: Arc<Mutex<Vec<TraceEvent<H::Role>>>>,
+}
+
+impl<H: ChoreoHandler> TraceRecorder<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn events(&self) -> Vec<TraceEvent<H::Role>> {
+        self.lock().clone()
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    fn record(&self, event: TraceEvent<H::Role>) {
+        self.lock().push(event);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<TraceEvent<H::Role>>> {
+        self.events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for TraceRecorder<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let message = serde_json::to_value(msg)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        self.inner.send(ep, to, msg).await?;
+        self.record(TraceEvent::Send { to, message });
+        Ok(())
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let msg = self.inner.recv(ep, from).await?;
+        self.record(TraceEvent::Recv {
+            from,
+            msg_type: short_type_name(std::any::type_name::<M>()).to_string(),
+        });
+        Ok(msg)
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.inner.choose(ep, who, label).await?;
+        self.record(TraceEvent::Choose {
+            at: who,
+            label: label.0.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        let label = self.inner.offer(ep, from).await?;
+        self.record(TraceEvent::Offer {
+            from,
+            label: label.0.to_string(),
+        });
+        Ok(label)
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
+
+/// A message as a differential run sends it
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    /// `Debug` form of the role whose program sent the message
+    sender: String,
+    message: M,
+}
+
+/// Handler wrapper that stamps sends with the role it runs and checks the
+/// stamp of every receive against the role it was expected from
+struct SenderCheck<H: ChoreoHandler> {
+    inner: H,
+    role: H::Role,
+    enabled: bool,
+    impersonations: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for SenderCheck<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        if !self.enabled {
+            return self.inner.send(ep, to, msg).await;
+        }
+        let envelope = Envelope {
+            sender: format!("{:?}", self.role),
+            message: msg,
+        };
+        self.inner.send(ep, to, &envelope).await
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        if !self.enabled {
+            return self.inner.recv(ep, from).await;
+        }
+        let envelope: Envelope<M> = self.inner.recv(ep, from).await?;
+        let expected = format!("{:?}", from);
+        if envelope.sender != expected {
+            let problem = format!(
+                "{:?} received a message from {} on its link from {}: the handler or \
+                 endpoint built for {} was given to {}",
+                self.role, envelope.sender, expected, expected, envelope.sender
+            );
+            self.impersonations
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(problem.clone());
+            return Err(ChoreographyError::ProtocolViolation(problem));
+        }
+        Ok(envelope.message)
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
+
+/// What one role observed during a run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoleTrace<R> {
+    pub role: R,
+    pub events: Vec<TraceEvent<R>>,
+    /// Received values in the order the program received them
+    pub received: Vec<serde_json::Value>,
+    /// The error the role's program ended with, if any
+    pub error: Option<String>,
+}
+
+/// What every role observed during a run, in the order roles were added
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionTrace<R> {
+    pub roles: Vec<RoleTrace<R>>,
+}
+
+impl<R: RoleId> SessionTrace<R> {
+    pub fn role(&self, role: R) -> Option<&RoleTrace<R>> {
+        self.roles.iter().find(|trace| trace.role == role)
+    }
+
+    /// First difference in each role's effects, received values, and
+    /// outcome
+    ///
+    /// Error messages are not compared, only whether a role failed, since
+    /// different transports describe the same failure differently.
+    pub fn diff(&self, other: &SessionTrace<R>) -> Vec<Divergence<R>> {
+        let mut divergences = Vec::new();
+        for left in &self.roles {
+            let Some(right) = other.role(left.role) else {
+                divergences.push(Divergence::MissingRole { role: left.role });
+                continue;
+            };
+            if let Some((index, left_event, right_event)) =
+                first_difference(&left.events, &right.events)
+            {
+                divergences.push(Divergence::Event {
+                    role: left.role,
+                    index,
+                    left: left_event.cloned(),
+                    right: right_event.cloned(),
+                });
+            }
+            if let Some((index, left_value, right_value)) =
+                first_difference(&left.received, &right.received)
+            {
+                divergences.push(Divergence::Received {
+                    role: left.role,
+                    index,
+                    left: left_value.cloned(),
+                    right: right_value.cloned(),
+                });
+            }
+            if left.error.is_some() != right.error.is_some() {
+                divergences.push(Divergence::Outcome {
+                    role: left.role,
+                    left: left.error.clone(),
+                    right: right.error.clone(),
+                });
+            }
+        }
+        for right in &other.roles {
+            if self.role(right.role).is_none() {
+                divergences.push(Divergence::MissingRole { role: right.role });
+            }
+        }
+        divergences
+    }
+}
+
+fn first_difference<'a, T: PartialEq>(
+    left: &'a [T],
+    right: &'a [T],
+) -> Option<(usize, Option<&'a T>, Option<&'a T>)> {
+    (0..left.len().max(right.len()))
+        .map(|i| (i, left.get(i), right.get(i)))
+        .find(|(_, l, r)| l != r)
+}
+
+/// A difference between two runs of the same programs
+///
+/// `left` and `right` name the two setups passed to
+/// [`Differential::compare`]; `None` means that side ended first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence<R> {
+    Event {
+        role: R,
+        index: usize,
+        left: Option<TraceEvent<R>>,
+        right: Option<TraceEvent<R>>,
+    },
+    Received {
+        role: R,
+        index: usize,
+        left: Option<serde_json::Value>,
+        right: Option<serde_json::Value>,
+    },
+    /// The role failed in one run and completed in the other
+    Outcome {
+        role: R,
+        left: Option<String>,
+        right: Option<String>,
+    },
+    /// Only one run has a trace for the role
+    MissingRole { role: R },
+}
+
+impl<R: fmt::Debug> fmt::Display for Divergence<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn side<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "nothing".to_string(), T::to_string)
+        }
+        match self {
+            Divergence::Event {
+                role,
+                index,
+                left,
+                right,
+            } => write!(
+                f,
+                "{:?} effect {}: {} vs {}",
+                role,
+                index,
+                side(left),
+                side(right)
+            ),
+            Divergence::Received {
+                role,
+                index,
+                left,
+                right,
+            } => write!(
+                f,
+                "{:?} received value {}: {} vs {}",
+                role,
+                index,
+                side(left),
+                side(right)
+            ),
+            Divergence::Outcome { role, left, right } => {
+                let outcome = |error: &Option<String>| match error {
+                    Some(error) => format!("failed ({})", error),
+                    None => "completed".to_string(),
+                };
+                write!(f, "{:?} {} vs {}", role, outcome(left), outcome(right))
+            }
+            Divergence::MissingRole { role } => write!(f, "{:?} ran on one side only", role),
+        }
+    }
+}
+
+/// Runs the same programs over different handlers and compares the traces
+pub struct Differential<R: RoleId, M> {
+    programs: Vec<(R, Program<R, M>)>,
+    check_senders: bool,
+}
+
+impl<R, M> Differential<R, M>
+where
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            programs: Vec::new(),
+            check_senders: true,
+        }
+    }
+
+    /// Run `program` as `role`
+    pub fn role(mut self, role: R, program: Program<R, M>) -> Self {
+        self.programs.push((role, program));
+        self
+    }
+
+    /// Send messages as they are, without the envelope naming their sender
+    ///
+    /// Needed when a handler checks messages by type name, such as a
+    /// `RumpsteakHandler` following a session type, since enveloped messages
+    /// are all named `Envelope`.
+    pub fn without_sender_checks(mut self) -> Self {
+        self.check_senders = false;
+        self
+    }
+
+    pub fn roles(&self) -> Vec<R> {
+        self.programs.iter().map(|(role, _)| *role).collect()
+    }
+
+    /// Run every role's program concurrently over the handlers `setup`
+    /// returns
+    ///
+    /// `setup` receives the roles in the order they were added and must
+    /// return one connected handler and endpoint for each, in that order.
+    /// A role that receives a message sent by another role's program over
+    /// the link of a third fails the run, since the setup wired the wrong
+    /// handler or endpoint to one of them.
+    pub async fn run<H, F>(&self, setup: F) -> Result<SessionTrace<R>>
+    where
+        H: ChoreoHandler<Role = R> + Send,
+        F: FnOnce(&[R]) -> Vec<(H, H::Endpoint)>,
+    {
+        let roles = self.roles();
+        let handlers = setup(&roles);
+        if handlers.len() != roles.len() {
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "differential setup returned {} handlers for {} roles",
+                handlers.len(),
+                roles.len()
+            )));
+        }
+
+        let impersonations = Arc::new(Mutex::new(Vec::new()));
+        let runs =
+            self.programs
+                .iter()
+                .zip(handlers)
+                .map(|((role, program), (handler, mut endpoint))| {
+                    let impersonations = impersonations.clone();
+                    async move {
+                        let mut recorder = TraceRecorder::new(SenderCheck {
+                            inner: handler,
+                            role: *role,
+                            enabled: self.check_senders,
+                            impersonations,
+                        });
+                        let result = interpret(&mut recorder, &mut endpoint, program.clone()).await;
+                        let (received, error) = match result {
+                            Ok(result) => (
+                                result
+                                    .received_values
+                                    .iter()
+                                    .map(|value| serde_json::to_value(value).unwrap_or_default())
+                                    .collect(),
+                                None,
+                            ),
+                            Err(e) => (Vec::new(), Some(e.to_string())),
+                        };
+                        RoleTrace {
+                            role: *role,
+                            events: recorder.events(),
+                            received,
+                            error,
+                        }
+                    }
+                });
+        let roles = join_all(runs).await;
+        let impersonations = impersonations
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(problem) = impersonations.first() {
+            return Err(ChoreographyError::ProtocolViolation(problem.clone()));
+        }
+        Ok(SessionTrace { roles })
+    }
+
+    /// Run over two setups and return where their traces differ
+    ///
+    /// The runs happen one after the other, so both setups may share
+    /// resources such as ports.
+    pub async fn compare<A, B, FA, FB>(&self, left: FA, right: FB) -> Result<Vec<Divergence<R>>>
+    where
+        A: ChoreoHandler<Role = R> + Send,
+        B: ChoreoHandler<Role = R> + Send,
+        FA: FnOnce(&[R]) -> Vec<(A, A::Endpoint)>,
+        FB: FnOnce(&[R]) -> Vec<(B, B::Endpoint)>,
+    {
+        let left = self.run(left).await?;
+        let right = self.run(right).await?;
+        let divergences = left.diff(&right);
+        for divergence in &divergences {
+            tracing::warn!(%divergence, "handlers diverged");
+        }
+        Ok(divergences)
+    }
+}
+
+impl<R, M> Default for Differential<R, M>
+where
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
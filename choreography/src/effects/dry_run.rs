@@ -0,0 +1,375 @@
+// Dry runs of role programs
+//
+// Runs one role's program with nobody on the other end, to check that its
+// control flow is sane before any transport is wired up. Peers are stubbed:
+// every receive gets a default payload of the expected message, and the
+// role takes the first branch of every choice, whether it makes the choice
+// or is offered it. Each send, receive, choice and offer is kept as a step.
+//
+// Taking the first branch can go round a recursive protocol forever, so a
+// run stops with `BudgetExceeded` once it has taken too many steps.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::effects::algebra::{
+    Effect, InterpretResult, InterpreterState, Program, ProgramMessage, UnknownLabel,
+};
+use crate::effects::conformance::debug_name;
+use crate::effects::handlers::session::short_type_name;
+use crate::effects::interpreter::interpret_with_hooks;
+use crate::effects::{
+    ChoreoHandler, ChoreographyError, EffectContext, InterpreterHooks, Label, Result, RoleId,
+};
+
+/// Default number of steps a dry run may take before it is stopped
+pub const DEFAULT_MAX_STEPS: usize = 1_000;
+
+/// The label stub peers offer; every branch falls back to its first
+const STUB_LABEL: Label = Label("dry-run");
+
+/// One communication a role performed in a dry run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DryRunStep<R> {
+    Send { to: R, message: String },
+    Recv { from: R, message: String },
+    Choose { label: Label },
+    Offer { from: R, label: Label },
+}
+
+impl<R: fmt::Debug> fmt::Display for DryRunStep<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DryRunStep::Send { to, message } => write!(f, "send {} to {:?}", message, to),
+            DryRunStep::Recv { from, message } => write!(f, "receive {} from {:?}", message, from),
+            DryRunStep::Choose { label } => write!(f, "choose {}", label.0),
+            DryRunStep::Offer { from, label } => write!(f, "offer from {:?}: {}", from, label.0),
+        }
+    }
+}
+
+/// Builds the default payload for a message type, by `type_name`
+type PayloadFn<M> = Box<dyn Fn(&str) -> Option<M> + Send + Sync>;
+
+/// State the stub handler and the step recorder share
+struct Shared<R, M> {
+    steps: Vec<DryRunStep<R>>,
+    /// Payloads for the receives that have started, oldest first
+    payloads: VecDeque<(&'static str, Option<M>)>,
+}
+
+/// Runs a role's program against stub peers
+pub struct DryRun<R: RoleId, M> {
+    payload: PayloadFn<M>,
+    max_steps: usize,
+    steps: Vec<DryRunStep<R>>,
+}
+
+impl<R: RoleId, M> DryRun<R, M>
+where
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    /// Stub peers with the messages `payload` builds from the expected
+    /// type's `std::any::type_name`
+    pub fn new(payload: impl Fn(&str) -> Option<M> + Send + Sync + 'static) -> Self {
+        Self {
+            payload: Box::new(payload),
+            max_steps: DEFAULT_MAX_STEPS,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Stop a run once it has taken `max_steps` steps
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Run `program` against stub peers
+    ///
+    /// A program that does not complete is an error. Steps from an earlier
+    /// run are discarded, and the steps taken before a failure are kept,
+    /// so [`steps`](Self::steps) shows how far the program got.
+    pub async fn run(&mut self, program: Program<R, M>) -> Result<InterpretResult<M>> {
+        let shared = Arc::new(Mutex::new(Shared {
+            steps: Vec::new(),
+            payloads: VecDeque::new(),
+        }));
+        let mut stub = Stub {
+            shared: shared.clone(),
+            max_steps: self.max_steps,
+        };
+        let mut recorder = Recorder {
+            shared: shared.clone(),
+            payload: &self.payload,
+            offered: None,
+        };
+        let result =
+            interpret_with_hooks(&mut stub, &mut (), first_branches(program), &mut recorder).await;
+        self.steps = std::mem::take(&mut lock(&shared).steps);
+        let result = result?;
+        match &result.final_state {
+            InterpreterState::Completed => Ok(result),
+            InterpreterState::Failed(reason) => Err(result
+                .error
+                .unwrap_or_else(|| ChoreographyError::ProtocolViolation(reason.clone()))),
+            InterpreterState::Timeout => Err(result
+                .error
+                .unwrap_or(ChoreographyError::Timeout(Duration::ZERO))),
+            InterpreterState::Cancelled => Err(ChoreographyError::Cancelled),
+        }
+    }
+
+    /// Steps of the last run, in the order they were taken
+    pub fn steps(&self) -> &[DryRunStep<R>] {
+        &self.steps
+    }
+
+    pub fn into_steps(self) -> Vec<DryRunStep<R>> {
+        self.steps
+    }
+}
+
+fn lock<R, M>(shared: &Mutex<Shared<R, M>>) -> std::sync::MutexGuard<'_, Shared<R, M>> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Make every choice take its first branch: guarded choices choose their
+/// first label, and offered choices fall back to theirs
+fn first_branches<R: RoleId, M>(mut program: Program<R, M>) -> Program<R, M> {
+    program.effects = program
+        .effects
+        .into_iter()
+        .map(|effect| match effect {
+            Effect::ChooseWhen { at, guards } => match guards.first() {
+                Some((label, _)) => Effect::Choose { at, label: *label },
+                None => Effect::ChooseWhen { at, guards },
+            },
+            Effect::Branch {
+                choosing_role,
+                branches,
+                unknown,
+            } => Effect::Branch {
+                choosing_role,
+                unknown: branches
+                    .first()
+                    .map_or(unknown, |(label, _)| UnknownLabel::Fallback(*label)),
+                branches: branches
+                    .into_iter()
+                    .map(|(label, body)| (label, first_branches(body)))
+                    .collect(),
+            },
+            Effect::Loop { iterations, body } => Effect::Loop {
+                iterations,
+                body: Box::new(first_branches(*body)),
+            },
+            Effect::LoopWhile { condition, body } => Effect::LoopWhile {
+                condition,
+                body: Box::new(first_branches(*body)),
+            },
+            Effect::Rec { label, body } => Effect::Rec {
+                label,
+                body: Box::new(first_branches(*body)),
+            },
+            Effect::Timeout {
+                at,
+                dur,
+                body,
+                on_timeout,
+            } => Effect::Timeout {
+                at,
+                dur,
+                body: Box::new(first_branches(*body)),
+                on_timeout: on_timeout.map(|program| Box::new(first_branches(*program))),
+            },
+            Effect::Parallel { programs } => Effect::Parallel {
+                programs: programs.into_iter().map(first_branches).collect(),
+            },
+            Effect::Finally { body, cleanup } => Effect::Finally {
+                body: Box::new(first_branches(*body)),
+                cleanup: Box::new(first_branches(*cleanup)),
+            },
+            Effect::TryCatch {
+                body,
+                notify,
+                compensation,
+            } => Effect::TryCatch {
+                body: Box::new(first_branches(*body)),
+                notify,
+                compensation: Box::new(first_branches(*compensation)),
+            },
+            Effect::Spawn { handle, program } => Effect::Spawn {
+                handle,
+                program: Box::new(first_branches(*program)),
+            },
+            other => other,
+        })
+        .collect();
+    program
+}
+
+/// Hooks that record the steps and queue a payload for each receive
+struct Recorder<'a, R, M> {
+    shared: Arc<Mutex<Shared<R, M>>>,
+    payload: &'a PayloadFn<M>,
+    /// Step of the offer whose branch has not been picked yet
+    offered: Option<usize>,
+}
+
+#[async_trait]
+impl<R: RoleId, M: ProgramMessage> InterpreterHooks<R, M> for Recorder<'_, R, M> {
+    async fn on_effect_start(&mut self, effect: &Effect<R, M>, _ctx: &EffectContext) {
+        let mut shared = lock(&self.shared);
+        match effect {
+            Effect::Send { to, msg } => shared.steps.push(DryRunStep::Send {
+                to: *to,
+                message: debug_name(msg),
+            }),
+            Effect::SendWith {
+                to, computation, ..
+            } => shared.steps.push(DryRunStep::Send {
+                to: *to,
+                message: computation.name.to_string(),
+            }),
+            Effect::Recv { from, msg_type } => {
+                shared
+                    .payloads
+                    .push_back((msg_type, (self.payload)(msg_type)));
+                shared.steps.push(DryRunStep::Recv {
+                    from: *from,
+                    message: short_type_name(msg_type).to_string(),
+                });
+            }
+            Effect::Choose { label, .. } => {
+                shared.steps.push(DryRunStep::Choose { label: *label });
+            }
+            Effect::Route { msg, router, .. } => {
+                let to = router.route(msg);
+                if let Some(label) = router.label_of(&to) {
+                    shared.steps.push(DryRunStep::Choose { label });
+                    shared.steps.push(DryRunStep::Send {
+                        to,
+                        message: debug_name(msg),
+                    });
+                }
+            }
+            Effect::Offer { from } => {
+                self.offered = Some(shared.steps.len());
+                shared.steps.push(DryRunStep::Offer {
+                    from: *from,
+                    label: STUB_LABEL,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    async fn on_branch_taken(&mut self, label: Label, _ctx: &EffectContext) {
+        if let Some(index) = self.offered.take() {
+            if let Some(DryRunStep::Offer { label: offered, .. }) =
+                lock(&self.shared).steps.get_mut(index)
+            {
+                *offered = label;
+            }
+        }
+    }
+}
+
+/// Handler that plays every peer of the role being dry run
+struct Stub<R, M> {
+    shared: Arc<Mutex<Shared<R, M>>>,
+    max_steps: usize,
+}
+
+impl<R, M> Stub<R, M> {
+    /// Fail if the step just recorded is one too many, and drop it as it
+    /// is not taken
+    fn step(&self) -> Result<()> {
+        let mut shared = lock(&self.shared);
+        let used = shared.steps.len();
+        if used > self.max_steps {
+            shared.steps.pop();
+            return Err(ChoreographyError::BudgetExceeded {
+                resource: "dry-run steps",
+                limit: self.max_steps as u64,
+                used: used as u64,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R: RoleId, M: Serialize + Send> ChoreoHandler for Stub<R, M> {
+    type Role = R;
+    type Endpoint = ();
+
+    async fn send<T: Serialize + Send + Sync>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        _to: Self::Role,
+        _msg: &T,
+    ) -> Result<()> {
+        self.step()
+    }
+
+    async fn recv<T: DeserializeOwned + Send>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<T> {
+        self.step()?;
+        let next = lock(&self.shared).payloads.pop_front();
+        let payload = match next {
+            Some((_, Some(payload))) => payload,
+            Some((msg_type, None)) => {
+                return Err(ChoreographyError::ProtocolViolation(format!(
+                    "no default payload for {} from {:?}",
+                    msg_type, from
+                )))
+            }
+            None => {
+                return Err(ChoreographyError::ProtocolViolation(format!(
+                    "receive from {:?} outside a receive effect",
+                    from
+                )))
+            }
+        };
+        let bytes = bincode::serialize(&payload)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        bincode::deserialize(&bytes).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    async fn choose(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        _who: Self::Role,
+        _label: Label,
+    ) -> Result<()> {
+        self.step()
+    }
+
+    async fn offer(&mut self, _ep: &mut Self::Endpoint, _from: Self::Role) -> Result<Label> {
+        self.step()?;
+        Ok(STUB_LABEL)
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        _at: Self::Role,
+        _dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        body.await
+    }
+}
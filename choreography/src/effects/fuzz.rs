@@ -0,0 +1,750 @@
+// Property-based fuzzing of choreographies
+//
+// Hand-written tests only cover the protocols someone thought of. The
+// fuzzer generates random choreographies out of choices, loops, parallel
+// blocks and recursion, projects every role, and runs the projections
+// against each other over `InMemoryHandler`s, picking which role runs next
+// at random after every step. A role that cannot be projected, one that
+// receives something it did not expect, and roles left waiting on each
+// other are all failures. A failing choreography is shrunk to the smallest
+// one that still fails the same way. Everything is seeded, so a failure
+// replays exactly.
+//
+// Generated choreographies keep to what projection needs to be sound:
+// every branch of a choice starts with the chooser sending to the same
+// role, and then telling each other role of the block which branch it
+// took; parallel blocks split their roles between the arms; and recursion
+// only jumps back from a branch other than the first of the choice that
+// starts it, so every run can leave. A choice's label is the name of the
+// first message of its branch, as rumpsteak's `Select` sends its message
+// as the label. Runs therefore send labels as messages, and a role
+// offering a choice takes the first message of its branch as the label.
+
+use futures::task::noop_waker;
+use proc_macro2::Ident;
+use quote::format_ident;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::ast::{Branch, Choreography, Condition, MessageType, Protocol, Role};
+use crate::compiler::projection::project;
+use crate::compiler::simulation::SplitMix64;
+use crate::effects::handlers::{InMemoryHandler, SessionType};
+use crate::effects::ChoreoHandler;
+
+/// Settings for [`fuzz_choreography`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzConfig {
+    /// Number of choreographies to generate
+    pub cases: usize,
+    /// Seed for generation and scheduling
+    pub seed: u64,
+    /// Most roles a choreography has; at least 2
+    pub max_roles: usize,
+    /// Deepest nesting of choices, loops, parallel blocks and recursion
+    pub max_depth: usize,
+    /// Most statements a choreography has, not counting the messages that
+    /// tell roles which branch was taken
+    pub max_size: usize,
+    /// Schedules each choreography is run under
+    pub schedules: usize,
+    /// Steps a run may take before it counts as running forever
+    pub max_steps: usize,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        Self {
+            cases: 100,
+            seed: 0,
+            max_roles: 4,
+            max_depth: 3,
+            max_size: 16,
+            schedules: 4,
+            max_steps: 10_000,
+        }
+    }
+}
+
+impl FuzzConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cases(mut self, cases: usize) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_max_roles(mut self, roles: usize) -> Self {
+        self.max_roles = roles.max(2);
+        self
+    }
+
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn with_max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    pub fn with_schedules(mut self, schedules: usize) -> Self {
+        self.schedules = schedules.max(1);
+        self
+    }
+
+    pub fn with_max_steps(mut self, steps: usize) -> Self {
+        self.max_steps = steps;
+        self
+    }
+
+    /// Seeds of the schedules each choreography is run under
+    fn schedule_seeds(&self) -> Vec<u64> {
+        let mut rng = SplitMix64(self.seed);
+        (0..self.schedules.max(1)).map(|_| rng.next_u64()).collect()
+    }
+}
+
+/// Why a fuzzed run failed
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FuzzFailure {
+    #[error("{role} cannot be projected: {error}")]
+    Projection { role: String, error: String },
+
+    #[error("The projection of {0} has no session type to run")]
+    Unsupported(String),
+
+    #[error("{role} failed: {error}")]
+    Violation { role: String, error: String },
+
+    #[error("Deadlock: {} wait forever", .0.join(", "))]
+    Deadlock(Vec<String>),
+
+    #[error("Still running after {0} steps")]
+    StepLimit(usize),
+}
+
+/// A choreography that fails, shrunk as far as it still fails the same way
+#[derive(Debug, Clone)]
+pub struct FuzzCounterexample {
+    /// The failing choreography as it was generated or given
+    pub original: Choreography,
+    pub shrunk: Choreography,
+    /// Seed of a schedule that fails the shrunk choreography
+    pub schedule: u64,
+    pub failure: FuzzFailure,
+}
+
+impl fmt::Display for FuzzCounterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} under schedule {}, shrunk to {:#?}",
+            self.failure, self.schedule, self.shrunk.protocol
+        )
+    }
+}
+
+/// Totals of a fuzzing session that found no failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FuzzReport {
+    pub cases: usize,
+    pub runs: usize,
+    pub steps: usize,
+}
+
+/// Generate `config.cases` choreographies and run each under
+/// `config.schedules` schedules, stopping at the first failure
+#[allow(clippy::result_large_err)]
+pub fn fuzz_choreography(config: &FuzzConfig) -> Result<FuzzReport, FuzzCounterexample> {
+    let mut rng = SplitMix64(config.seed);
+    let mut report = FuzzReport::default();
+    for _ in 0..config.cases {
+        let case = config.clone().with_seed(rng.next_u64());
+        let choreography = arbitrary_choreography(case.seed, &case);
+        report.steps += check_choreography(&choreography, &case)?;
+        report.cases += 1;
+        report.runs += case.schedules.max(1);
+    }
+    Ok(report)
+}
+
+/// Run `choreography` under `config.schedules` schedules, returning the
+/// steps taken over all of them, or the shrunk failure
+#[allow(clippy::result_large_err)]
+pub fn check_choreography(
+    choreography: &Choreography,
+    config: &FuzzConfig,
+) -> Result<usize, FuzzCounterexample> {
+    let mut steps = 0;
+    for seed in config.schedule_seeds() {
+        match run_shuffled(choreography, seed, config.max_steps) {
+            Ok(taken) => steps += taken,
+            Err(failure) => return Err(shrink(choreography, seed, failure, config)),
+        }
+    }
+    Ok(steps)
+}
+
+/// Project every role of `choreography` and run them against each other,
+/// picking the role that takes the next step with a generator seeded by
+/// `seed`; returns the number of steps taken
+///
+/// Roles choose branches at random, from the same seed.
+pub fn run_shuffled(
+    choreography: &Choreography,
+    seed: u64,
+    max_steps: usize,
+) -> Result<usize, FuzzFailure> {
+    let names: Vec<String> = choreography.roles.iter().map(Role::to_string).collect();
+    let slot = |role: &Role| {
+        choreography
+            .roles
+            .iter()
+            .position(|declared| declared.name == role.name)
+            .map(Slot)
+    };
+    let mut sessions = Vec::with_capacity(names.len());
+    for (role, name) in choreography.roles.iter().zip(&names) {
+        let local = project(choreography, role).map_err(|e| FuzzFailure::Projection {
+            role: name.clone(),
+            error: e.to_string(),
+        })?;
+        let session = SessionType::from_local_type(&local, &slot)
+            .ok_or_else(|| FuzzFailure::Unsupported(name.clone()))?;
+        sessions.push(session);
+    }
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let steps = Cell::new(0);
+    let mut runs: Vec<Option<Run<'_>>> = sessions
+        .into_iter()
+        .enumerate()
+        .map(|(i, session)| {
+            let handler =
+                InMemoryHandler::with_channels(Slot(i), channels.clone(), choices.clone());
+            let rng = SplitMix64(seed ^ (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let run: Run<'_> = Box::pin(play(handler, session, rng, i, &names, &steps));
+            Some(run)
+        })
+        .collect();
+
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut rng = SplitMix64(seed);
+    loop {
+        let mut running: Vec<usize> = (0..runs.len()).filter(|i| runs[*i].is_some()).collect();
+        if running.is_empty() {
+            return Ok(steps.get());
+        }
+        if steps.get() > max_steps {
+            return Err(FuzzFailure::StepLimit(max_steps));
+        }
+        for i in (1..running.len()).rev() {
+            running.swap(i, rng.below(i + 1));
+        }
+
+        // Every role takes at most one step per poll, and sends complete at
+        // once, so a round in which nothing happens would repeat forever
+        let before = steps.get();
+        let mut finished = false;
+        for i in running {
+            let Some(run) = runs[i].as_mut() else {
+                continue;
+            };
+            if let Poll::Ready(result) = run.as_mut().poll(&mut cx) {
+                result?;
+                runs[i] = None;
+                finished = true;
+            }
+        }
+        if !finished && steps.get() == before {
+            let waiting = (0..runs.len())
+                .filter(|i| runs[*i].is_some())
+                .map(|i| names[i].clone())
+                .collect();
+            return Err(FuzzFailure::Deadlock(waiting));
+        }
+    }
+}
+
+/// One role's run, polled by the scheduler
+type Run<'a> = Pin<Box<dyn Future<Output = Result<(), FuzzFailure>> + 'a>>;
+
+/// Role of a run, by its place in the choreography's roles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Slot(usize);
+
+/// A fuzzed message, which carries only its name
+#[derive(Serialize, Deserialize)]
+struct Fuzzed(String);
+
+/// Run one role's session type to its end
+async fn play(
+    mut handler: InMemoryHandler<Slot>,
+    session: SessionType<Slot>,
+    mut rng: SplitMix64,
+    role: usize,
+    names: &[String],
+    steps: &Cell<usize>,
+) -> Result<(), FuzzFailure> {
+    let violation = |error: String| FuzzFailure::Violation {
+        role: names[role].clone(),
+        error,
+    };
+    let mut recs = HashMap::new();
+    let mut current = session;
+    loop {
+        current = match current {
+            SessionType::End => return Ok(()),
+            SessionType::Send {
+                to,
+                message,
+                continuation,
+            } => {
+                handler
+                    .send(&mut (), to, &Fuzzed(message))
+                    .await
+                    .map_err(|e| violation(e.to_string()))?;
+                *continuation
+            }
+            SessionType::Receive {
+                from,
+                message,
+                continuation,
+            } => {
+                let Fuzzed(received) = handler
+                    .recv(&mut (), from)
+                    .await
+                    .map_err(|e| violation(e.to_string()))?;
+                if received != message {
+                    return Err(violation(format!(
+                        "expected {} from {}, got {}",
+                        message, names[from.0], received
+                    )));
+                }
+                *continuation
+            }
+            SessionType::Select { to, mut branches } => {
+                if branches.is_empty() {
+                    return Err(violation("chooses between no branches".into()));
+                }
+                let (label, branch) = branches.swap_remove(rng.below(branches.len()));
+                handler
+                    .send(&mut (), to, &Fuzzed(label))
+                    .await
+                    .map_err(|e| violation(e.to_string()))?;
+                branch
+            }
+            SessionType::Branch { from, branches } => {
+                let Fuzzed(label) = handler
+                    .recv(&mut (), from)
+                    .await
+                    .map_err(|e| violation(e.to_string()))?;
+                let branch = branches
+                    .into_iter()
+                    .find(|(name, _)| *name == label)
+                    .map(|(_, branch)| branch)
+                    .ok_or_else(|| {
+                        violation(format!(
+                            "{} from {} is not one of its branches",
+                            label, names[from.0]
+                        ))
+                    })?;
+                // The label was the first message of the branch
+                match branch {
+                    SessionType::Receive {
+                        from: sender,
+                        message,
+                        continuation,
+                    } if sender == from && message == label => *continuation,
+                    branch => branch,
+                }
+            }
+            SessionType::Rec { label, body } => {
+                recs.insert(
+                    label.clone(),
+                    SessionType::Rec {
+                        label,
+                        body: body.clone(),
+                    },
+                );
+                current = *body;
+                continue;
+            }
+            SessionType::Var(label) => {
+                current = recs
+                    .get(&label)
+                    .cloned()
+                    .ok_or_else(|| violation(format!("jumps to unbound {}", label)))?;
+                continue;
+            }
+        };
+        steps.set(steps.get() + 1);
+        // Let the scheduler pick who runs next
+        futures::pending!();
+    }
+}
+
+/// A random choreography, built by the rules at the top of this module
+pub fn arbitrary_choreography(seed: u64, config: &FuzzConfig) -> Choreography {
+    let mut rng = SplitMix64(seed);
+    let count = 2 + rng.below(config.max_roles.max(2) - 1);
+    let roles: Vec<Role> = (0..count)
+        .map(|i| Role::new(format_ident!("R{}", i)))
+        .collect();
+    let mut generator = Generator {
+        rng,
+        messages: 0,
+        recs: 0,
+        budget: config.max_size,
+    };
+    // Start with a message, so no choreography is empty
+    let protocol = generator.send(&roles, config.max_depth, &[]);
+    with_protocol(
+        &Choreography {
+            name: format_ident!("Fuzzed"),
+            roles,
+            protocol: Protocol::End,
+            attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
+        },
+        protocol,
+    )
+}
+
+struct Generator {
+    rng: SplitMix64,
+    messages: usize,
+    recs: usize,
+    /// Statements left to generate
+    budget: usize,
+}
+
+impl Generator {
+    /// A protocol among `roles`, which may jump back to any of `jumps`
+    /// where it ends
+    fn protocol(&mut self, roles: &[Role], depth: usize, jumps: &[Ident]) -> Protocol {
+        if self.budget == 0 {
+            return self.end(jumps);
+        }
+        self.budget -= 1;
+        let kinds = if depth == 0 { 3 } else { 8 };
+        match self.rng.below(kinds) {
+            0 => self.end(jumps),
+            1..=3 => self.send(roles, depth, jumps),
+            4 => self.choice(roles, depth - 1, jumps, None),
+            5 => Protocol::Loop {
+                condition: Some(Condition::Count(1 + self.rng.below(3))),
+                body: Box::new(self.protocol(roles, depth - 1, &[])),
+            },
+            6 if roles.len() >= 4 => self.parallel(roles, depth - 1),
+            _ => self.rec(roles, depth - 1, jumps),
+        }
+    }
+
+    fn end(&mut self, jumps: &[Ident]) -> Protocol {
+        if !jumps.is_empty() && self.rng.below(2) == 0 {
+            Protocol::Var(jumps[self.rng.below(jumps.len())].clone())
+        } else {
+            Protocol::End
+        }
+    }
+
+    fn message(&mut self) -> MessageType {
+        self.messages += 1;
+        MessageType {
+            name: format_ident!("M{}", self.messages),
+            type_annotation: None,
+            payload: None,
+        }
+    }
+
+    /// Index of a role, and of another one
+    fn pair(&mut self, roles: &[Role]) -> (usize, usize) {
+        let from = self.rng.below(roles.len());
+        let to = (from + 1 + self.rng.below(roles.len() - 1)) % roles.len();
+        (from, to)
+    }
+
+    fn send(&mut self, roles: &[Role], depth: usize, jumps: &[Ident]) -> Protocol {
+        let (from, to) = self.pair(roles);
+        let message = self.message();
+        Protocol::Send {
+            from: roles[from].clone(),
+            to: roles[to].clone(),
+            message,
+            continuation: Box::new(self.protocol(roles, depth, jumps)),
+        }
+    }
+
+    /// A choice whose first branch may not jump back to `leaves`
+    fn choice(
+        &mut self,
+        roles: &[Role],
+        depth: usize,
+        jumps: &[Ident],
+        leaves: Option<&Ident>,
+    ) -> Protocol {
+        let (chooser, receiver) = self.pair(roles);
+        let mut branches = Vec::new();
+        for i in 0..2 + self.rng.below(2) {
+            let label = self.message();
+            let told: Vec<_> = (0..roles.len())
+                .filter(|r| *r != chooser && *r != receiver)
+                .map(|r| (r, self.message()))
+                .collect();
+            let jumps: Vec<Ident> = jumps
+                .iter()
+                .filter(|jump| i > 0 || Some(*jump) != leaves)
+                .cloned()
+                .collect();
+            let body = self.protocol(roles, depth, &jumps);
+            let body = told
+                .into_iter()
+                .rev()
+                .fold(body, |continuation, (r, message)| Protocol::Send {
+                    from: roles[chooser].clone(),
+                    to: roles[r].clone(),
+                    message,
+                    continuation: Box::new(continuation),
+                });
+            branches.push(Branch {
+                label: label.name.clone(),
+                guard: None,
+                weight: None,
+                protocol: Protocol::Send {
+                    from: roles[chooser].clone(),
+                    to: roles[receiver].clone(),
+                    message: label,
+                    continuation: Box::new(body),
+                },
+            });
+        }
+        Protocol::Choice {
+            role: roles[chooser].clone(),
+            branches,
+        }
+    }
+
+    fn parallel(&mut self, roles: &[Role], depth: usize) -> Protocol {
+        let mut roles = roles.to_vec();
+        for i in (1..roles.len()).rev() {
+            roles.swap(i, self.rng.below(i + 1));
+        }
+        let (left, right) = roles.split_at(2 + self.rng.below(roles.len() - 3));
+        Protocol::Parallel {
+            protocols: vec![
+                self.protocol(left, depth, &[]),
+                self.protocol(right, depth, &[]),
+            ],
+        }
+    }
+
+    fn rec(&mut self, roles: &[Role], depth: usize, jumps: &[Ident]) -> Protocol {
+        self.recs += 1;
+        let label = format_ident!("T{}", self.recs);
+        let mut jumps = jumps.to_vec();
+        jumps.push(label.clone());
+        Protocol::Rec {
+            body: Box::new(self.choice(roles, depth, &jumps, Some(&label))),
+            label,
+        }
+    }
+}
+
+/// `choreography` with `protocol`, keeping only the roles it mentions
+fn with_protocol(choreography: &Choreography, protocol: Protocol) -> Choreography {
+    Choreography {
+        name: choreography.name.clone(),
+        roles: choreography
+            .roles
+            .iter()
+            .filter(|role| protocol.mentions_role(role))
+            .cloned()
+            .collect(),
+        protocol,
+        attrs: choreography.attrs.clone(),
+        returns: choreography.returns.clone(),
+        routes: choreography.routes.clone(),
+        wire_ids: choreography.wire_ids.clone(),
+        trusted: choreography.trusted.clone(),
+    }
+}
+
+/// Shrink a choreography that fails under the schedule `seed`, keeping
+/// each smaller version that fails the same way under any schedule
+fn shrink(
+    choreography: &Choreography,
+    seed: u64,
+    failure: FuzzFailure,
+    config: &FuzzConfig,
+) -> FuzzCounterexample {
+    let seeds = config.schedule_seeds();
+    let fails = |candidate: &Choreography| {
+        seeds.iter().find_map(
+            |seed| match run_shuffled(candidate, *seed, config.max_steps) {
+                Err(found)
+                    if std::mem::discriminant(&found) == std::mem::discriminant(&failure) =>
+                {
+                    Some((*seed, found))
+                }
+                _ => None,
+            },
+        )
+    };
+
+    let mut shrunk = choreography.clone();
+    let mut found = (seed, failure.clone());
+    // Every candidate is smaller than the protocol it came from
+    'shrinking: loop {
+        for candidate in smaller(&shrunk.protocol) {
+            if !closed(&candidate, &mut Vec::new()) {
+                continue;
+            }
+            let candidate = with_protocol(&shrunk, candidate);
+            if let Some(failing) = fails(&candidate) {
+                shrunk = candidate;
+                found = failing;
+                continue 'shrinking;
+            }
+        }
+        break;
+    }
+
+    FuzzCounterexample {
+        original: choreography.clone(),
+        shrunk,
+        schedule: found.0,
+        failure: found.1,
+    }
+}
+
+/// Protocols one step smaller than `protocol`
+fn smaller(protocol: &Protocol) -> Vec<Protocol> {
+    let mut candidates = Vec::new();
+    if !matches!(protocol, Protocol::End) {
+        candidates.push(Protocol::End);
+    }
+    match protocol {
+        Protocol::Send {
+            from,
+            to,
+            message,
+            continuation,
+        } => {
+            candidates.push((**continuation).clone());
+            candidates.extend(smaller(continuation).into_iter().map(|continuation| {
+                Protocol::Send {
+                    from: from.clone(),
+                    to: to.clone(),
+                    message: message.clone(),
+                    continuation: Box::new(continuation),
+                }
+            }));
+        }
+        Protocol::Choice { role, branches } => {
+            candidates.extend(branches.iter().map(|branch| branch.protocol.clone()));
+            if branches.len() > 1 {
+                for i in 0..branches.len() {
+                    let mut fewer = branches.clone();
+                    fewer.remove(i);
+                    candidates.push(Protocol::Choice {
+                        role: role.clone(),
+                        branches: fewer,
+                    });
+                }
+            }
+            // A branch must still start with the chooser's message
+            for (i, branch) in branches.iter().enumerate() {
+                for protocol in smaller(&branch.protocol) {
+                    if matches!(&protocol, Protocol::Send { from, .. } if from == role) {
+                        let mut branches = branches.clone();
+                        branches[i].protocol = protocol;
+                        candidates.push(Protocol::Choice {
+                            role: role.clone(),
+                            branches,
+                        });
+                    }
+                }
+            }
+        }
+        Protocol::Loop { condition, body } => {
+            candidates.push((**body).clone());
+            if matches!(condition, Some(Condition::Count(n)) if *n > 1) {
+                candidates.push(Protocol::Loop {
+                    condition: Some(Condition::Count(1)),
+                    body: body.clone(),
+                });
+            }
+            candidates.extend(smaller(body).into_iter().map(|body| Protocol::Loop {
+                condition: condition.clone(),
+                body: Box::new(body),
+            }));
+        }
+        Protocol::Parallel { protocols } => {
+            candidates.extend(protocols.iter().cloned());
+            for i in 0..protocols.len() {
+                if protocols.len() > 1 {
+                    let mut fewer = protocols.clone();
+                    fewer.remove(i);
+                    candidates.push(Protocol::Parallel { protocols: fewer });
+                }
+                for arm in smaller(&protocols[i]) {
+                    let mut protocols = protocols.clone();
+                    protocols[i] = arm;
+                    candidates.push(Protocol::Parallel { protocols });
+                }
+            }
+        }
+        Protocol::Rec { label, body } => {
+            candidates.push((**body).clone());
+            candidates.extend(smaller(body).into_iter().map(|body| Protocol::Rec {
+                label: label.clone(),
+                body: Box::new(body),
+            }));
+        }
+        _ => {}
+    }
+    candidates
+}
+
+/// Whether every jump in `protocol` is to an enclosing `rec`
+fn closed(protocol: &Protocol, recs: &mut Vec<Ident>) -> bool {
+    match protocol {
+        Protocol::Var(label) => recs.contains(label),
+        Protocol::Rec { label, body } => {
+            recs.push(label.clone());
+            let closed = closed(body, recs);
+            recs.pop();
+            closed
+        }
+        Protocol::Send { continuation, .. } | Protocol::Broadcast { continuation, .. } => {
+            closed(continuation, recs)
+        }
+        Protocol::Choice { branches, .. } | Protocol::Race { branches } => {
+            branches.iter().all(|branch| closed(&branch.protocol, recs))
+        }
+        Protocol::Loop { body, .. } => closed(body, recs),
+        Protocol::Parallel { protocols } => protocols.iter().all(|arm| closed(arm, recs)),
+        _ => true,
+    }
+}
@@ -0,0 +1,535 @@
+// Guards on choice branches
+//
+// A guard such as `balance > price` decides whether the choosing role may
+// take a branch. Guards are parsed once into an expression tree and
+// evaluated by the interpreter against the variables of a `GuardContext`,
+// so the same program can choose differently for different callers.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// A typed value a guard variable holds or a guard evaluates to
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuardValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl GuardValue {
+    fn type_name(&self) -> &'static str {
+        match self {
+            GuardValue::Bool(_) => "bool",
+            GuardValue::Int(_) => "int",
+            GuardValue::Float(_) => "float",
+            GuardValue::Str(_) => "string",
+        }
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            GuardValue::Int(i) => Some(*i as f64),
+            GuardValue::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GuardValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardValue::Bool(b) => write!(f, "{}", b),
+            GuardValue::Int(i) => write!(f, "{}", i),
+            GuardValue::Float(x) => write!(f, "{:?}", x),
+            GuardValue::Str(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+impl From<bool> for GuardValue {
+    fn from(value: bool) -> Self {
+        GuardValue::Bool(value)
+    }
+}
+
+impl From<i64> for GuardValue {
+    fn from(value: i64) -> Self {
+        GuardValue::Int(value)
+    }
+}
+
+impl From<i32> for GuardValue {
+    fn from(value: i32) -> Self {
+        GuardValue::Int(value.into())
+    }
+}
+
+impl From<u32> for GuardValue {
+    fn from(value: u32) -> Self {
+        GuardValue::Int(value.into())
+    }
+}
+
+impl From<f64> for GuardValue {
+    fn from(value: f64) -> Self {
+        GuardValue::Float(value)
+    }
+}
+
+impl From<&str> for GuardValue {
+    fn from(value: &str) -> Self {
+        GuardValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for GuardValue {
+    fn from(value: String) -> Self {
+        GuardValue::Str(value)
+    }
+}
+
+/// The variables guards are evaluated against
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GuardContext {
+    vars: HashMap<String, GuardValue>,
+}
+
+impl GuardContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `name` to `value`
+    pub fn with_var(mut self, name: impl Into<String>, value: impl Into<GuardValue>) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    /// Set `name` to `value`, replacing any value it had
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<GuardValue>) {
+        self.vars.insert(name.into(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&GuardValue> {
+        self.vars.get(name)
+    }
+}
+
+/// Binary operators, from the loosest binding to the tightest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Or => "||",
+            BinOp::And => "&&",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 3,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 5,
+        }
+    }
+}
+
+/// A guard expression
+///
+/// Guards use a small subset of Rust expression syntax: variables,
+/// `true`/`false`, integer, float and string literals, `!` and unary `-`,
+/// arithmetic, comparisons, `&&` and `||`, and parentheses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Guard {
+    Value(GuardValue),
+    Var(String),
+    Not(Box<Guard>),
+    Neg(Box<Guard>),
+    Binary {
+        op: BinOp,
+        left: Box<Guard>,
+        right: Box<Guard>,
+    },
+}
+
+/// Errors from parsing or evaluating a guard
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum GuardError {
+    #[error("invalid guard at offset {offset}: {message}")]
+    Syntax { offset: usize, message: String },
+
+    #[error("guard variable {0} is not set")]
+    Unbound(String),
+
+    #[error("cannot apply {op} to {left} and {right}")]
+    Type {
+        op: &'static str,
+        left: &'static str,
+        right: &'static str,
+    },
+
+    #[error("guard evaluates to {0}, not a bool")]
+    NotBool(&'static str),
+
+    #[error("division by zero")]
+    DivisionByZero,
+}
+
+impl Guard {
+    /// Parse a guard from its source text
+    pub fn parse(source: &str) -> Result<Guard, GuardError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.len(),
+        };
+        let guard = parser.expr(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(guard),
+            Some((offset, token)) => Err(GuardError::Syntax {
+                offset: *offset,
+                message: format!("unexpected {}", token),
+            }),
+        }
+    }
+
+    /// Whether the guard holds for the variables of `context`
+    pub fn holds(&self, context: &GuardContext) -> Result<bool, GuardError> {
+        match self.eval(context)? {
+            GuardValue::Bool(b) => Ok(b),
+            other => Err(GuardError::NotBool(other.type_name())),
+        }
+    }
+
+    /// Evaluate the guard against the variables of `context`
+    pub fn eval(&self, context: &GuardContext) -> Result<GuardValue, GuardError> {
+        match self {
+            Guard::Value(value) => Ok(value.clone()),
+            Guard::Var(name) => context
+                .get(name)
+                .cloned()
+                .ok_or_else(|| GuardError::Unbound(name.clone())),
+            Guard::Not(inner) => match inner.eval(context)? {
+                GuardValue::Bool(b) => Ok(GuardValue::Bool(!b)),
+                other => Err(GuardError::NotBool(other.type_name())),
+            },
+            Guard::Neg(inner) => match inner.eval(context)? {
+                GuardValue::Int(i) => Ok(GuardValue::Int(-i)),
+                GuardValue::Float(f) => Ok(GuardValue::Float(-f)),
+                other => Err(GuardError::Type {
+                    op: "-",
+                    left: other.type_name(),
+                    right: other.type_name(),
+                }),
+            },
+            Guard::Binary { op, left, right } => {
+                // `&&` and `||` only evaluate their right side when needed
+                if matches!(op, BinOp::And | BinOp::Or) {
+                    let left = left.holds(context)?;
+                    if left == (*op == BinOp::Or) {
+                        return Ok(GuardValue::Bool(left));
+                    }
+                    return right.holds(context).map(GuardValue::Bool);
+                }
+                binary(*op, left.eval(context)?, right.eval(context)?)
+            }
+        }
+    }
+}
+
+fn binary(op: BinOp, left: GuardValue, right: GuardValue) -> Result<GuardValue, GuardError> {
+    use std::cmp::Ordering;
+
+    let mismatch = || GuardError::Type {
+        op: op.symbol(),
+        left: left.type_name(),
+        right: right.type_name(),
+    };
+    let ordering = match (&left, &right) {
+        (GuardValue::Int(a), GuardValue::Int(b)) => Some(a.cmp(b)),
+        (GuardValue::Str(a), GuardValue::Str(b)) => Some(a.cmp(b)),
+        (GuardValue::Bool(a), GuardValue::Bool(b)) => Some(a.cmp(b)),
+        _ => match (left.as_float(), right.as_float()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+    };
+
+    match op {
+        BinOp::Eq | BinOp::Ne => {
+            let equal = ordering.ok_or_else(mismatch)? == Ordering::Equal;
+            Ok(GuardValue::Bool(equal == (op == BinOp::Eq)))
+        }
+        BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            if matches!(left, GuardValue::Bool(_)) {
+                return Err(mismatch());
+            }
+            let ordering = ordering.ok_or_else(mismatch)?;
+            Ok(GuardValue::Bool(match op {
+                BinOp::Lt => ordering == Ordering::Less,
+                BinOp::Le => ordering != Ordering::Greater,
+                BinOp::Gt => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }))
+        }
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => {
+            if let (GuardValue::Int(a), GuardValue::Int(b)) = (&left, &right) {
+                let (a, b) = (*a, *b);
+                if matches!(op, BinOp::Div | BinOp::Rem) && b == 0 {
+                    return Err(GuardError::DivisionByZero);
+                }
+                return Ok(GuardValue::Int(match op {
+                    BinOp::Add => a.wrapping_add(b),
+                    BinOp::Sub => a.wrapping_sub(b),
+                    BinOp::Mul => a.wrapping_mul(b),
+                    BinOp::Div => a / b,
+                    _ => a % b,
+                }));
+            }
+            let (a, b) = match (left.as_float(), right.as_float()) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Err(mismatch()),
+            };
+            Ok(GuardValue::Float(match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                _ => a % b,
+            }))
+        }
+        BinOp::And | BinOp::Or => unreachable!("short-circuited by Guard::eval"),
+    }
+}
+
+impl fmt::Display for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Guard::Value(value) => write!(f, "{}", value),
+            Guard::Var(name) => write!(f, "{}", name),
+            Guard::Not(inner) => write!(f, "!({})", inner),
+            Guard::Neg(inner) => write!(f, "-({})", inner),
+            Guard::Binary { op, left, right } => {
+                write!(f, "({} {} {})", left, op.symbol(), right)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Value(GuardValue),
+    Op(BinOp),
+    Not,
+    Minus,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Value(value) => write!(f, "`{}`", value),
+            Token::Op(op) => write!(f, "`{}`", op.symbol()),
+            Token::Not => write!(f, "`!`"),
+            Token::Minus => write!(f, "`-`"),
+            Token::Open => write!(f, "`(`"),
+            Token::Close => write!(f, "`)`"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, GuardError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        let two = source.get(i..i + 2);
+        let token = match c {
+            b' ' | b'\t' | b'\n' | b'\r' => {
+                i += 1;
+                continue;
+            }
+            b'(' => Token::Open,
+            b')' => Token::Close,
+            b'+' => Token::Op(BinOp::Add),
+            b'-' => Token::Minus,
+            b'*' => Token::Op(BinOp::Mul),
+            b'/' => Token::Op(BinOp::Div),
+            b'%' => Token::Op(BinOp::Rem),
+            _ if two == Some("&&") => Token::Op(BinOp::And),
+            _ if two == Some("||") => Token::Op(BinOp::Or),
+            _ if two == Some("==") => Token::Op(BinOp::Eq),
+            _ if two == Some("!=") => Token::Op(BinOp::Ne),
+            _ if two == Some("<=") => Token::Op(BinOp::Le),
+            _ if two == Some(">=") => Token::Op(BinOp::Ge),
+            b'!' => Token::Not,
+            b'<' => Token::Op(BinOp::Lt),
+            b'>' => Token::Op(BinOp::Gt),
+            b'"' => {
+                let end = source[i + 1..]
+                    .find('"')
+                    .ok_or_else(|| GuardError::Syntax {
+                        offset: i,
+                        message: "unterminated string".to_string(),
+                    })?;
+                let text = source[i + 1..i + 1 + end].to_string();
+                i += end + 2;
+                tokens.push((start, Token::Value(GuardValue::Str(text))));
+                continue;
+            }
+            c if c.is_ascii_digit() => {
+                while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let float =
+                    i + 1 < bytes.len() && bytes[i] == b'.' && bytes[i + 1].is_ascii_digit();
+                if float {
+                    i += 1;
+                    while i < bytes.len() && bytes[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                let text = source[start..i].replace('_', "");
+                let value = if float {
+                    text.parse().map(GuardValue::Float).ok()
+                } else {
+                    text.parse().map(GuardValue::Int).ok()
+                };
+                let value = value.ok_or_else(|| GuardError::Syntax {
+                    offset: start,
+                    message: format!("invalid number {}", text),
+                })?;
+                tokens.push((start, Token::Value(value)));
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let token = match &source[start..i] {
+                    "true" => Token::Value(GuardValue::Bool(true)),
+                    "false" => Token::Value(GuardValue::Bool(false)),
+                    name => Token::Ident(name.to_string()),
+                };
+                tokens.push((start, token));
+                continue;
+            }
+            _ => {
+                return Err(GuardError::Syntax {
+                    offset: i,
+                    message: format!("unexpected character {:?}", source[i..].chars().next()),
+                })
+            }
+        };
+        i += match token {
+            Token::Op(BinOp::And | BinOp::Or | BinOp::Eq | BinOp::Ne | BinOp::Le | BinOp::Ge) => 2,
+            _ => 1,
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Offset reported for a guard that ends too early
+    end: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<BinOp> {
+        match self.tokens.get(self.pos) {
+            Some((_, Token::Op(op))) => Some(*op),
+            Some((_, Token::Minus)) => Some(BinOp::Sub),
+            _ => None,
+        }
+    }
+
+    /// Operators binding tighter than `min` and their operands
+    fn expr(&mut self, min: u8) -> Result<Guard, GuardError> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.peek_op() {
+            if op.precedence() <= min {
+                break;
+            }
+            self.pos += 1;
+            let right = self.expr(op.precedence())?;
+            left = Guard::Binary {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Guard, GuardError> {
+        let Some((offset, token)) = self.tokens.get(self.pos).cloned() else {
+            return Err(GuardError::Syntax {
+                offset: self.end,
+                message: "expected an operand".to_string(),
+            });
+        };
+        self.pos += 1;
+        match token {
+            Token::Not => Ok(Guard::Not(Box::new(self.unary()?))),
+            Token::Minus => Ok(Guard::Neg(Box::new(self.unary()?))),
+            Token::Value(value) => Ok(Guard::Value(value)),
+            Token::Ident(name) => Ok(Guard::Var(name)),
+            Token::Open => {
+                let inner = self.expr(0)?;
+                match self.tokens.get(self.pos) {
+                    Some((_, Token::Close)) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err(GuardError::Syntax {
+                        offset,
+                        message: "unclosed parenthesis".to_string(),
+                    }),
+                }
+            }
+            other => Err(GuardError::Syntax {
+                offset,
+                message: format!("expected an operand, found {}", other),
+            }),
+        }
+    }
+}
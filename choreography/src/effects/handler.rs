@@ -28,6 +28,7 @@
 //! }
 //! ```
 
+use crate::effects::interpreter::ErrorContext;
 use async_trait::async_trait;
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
@@ -45,9 +46,54 @@ impl<T: Copy + Eq + std::hash::Hash + Debug + Send + Sync> RoleId for T {}
 ///
 /// Used to distinguish between different paths in choice protocols.
 /// The label is typically a static string matching a protocol branch name.
+///
+/// Labels starting with `sys.` are reserved for the runtime. Protocols never
+/// list them as branches: the interpreter skips keep-alive labels while
+/// waiting for a choice and ends the session on `sys.abort` and
+/// `sys.cancel`, and session monitors record them.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 pub struct Label(pub &'static str);
 
+impl Label {
+    /// Prefix of the labels reserved for the runtime
+    pub const SYSTEM_PREFIX: &'static str = "sys.";
+    /// The sender gave up on the session
+    pub const ABORT: Label = Label("sys.abort");
+    /// The sender's caller no longer wants the session's result
+    pub const CANCEL: Label = Label("sys.cancel");
+    /// The sender is still alive but has nothing to say yet
+    pub const HEARTBEAT: Label = Label("sys.heartbeat");
+    /// The sender reconnected and carries on where it left off
+    pub const RESUME: Label = Label("sys.resume");
+
+    /// Every label the runtime handles itself
+    pub const SYSTEM: [Label; 4] = [Self::ABORT, Self::CANCEL, Self::HEARTBEAT, Self::RESUME];
+
+    /// The runtime label named `name`, if there is one
+    ///
+    /// Handlers that read labels off the wire use this to avoid leaking a
+    /// new string for each heartbeat.
+    pub fn system(name: &str) -> Option<Label> {
+        Self::SYSTEM.into_iter().find(|label| label.0 == name)
+    }
+
+    /// Whether the label is in the reserved `sys.` namespace
+    pub fn is_system(&self) -> bool {
+        self.0.starts_with(Self::SYSTEM_PREFIX)
+    }
+
+    /// Whether receiving the label ends the session
+    pub fn ends_session(&self) -> bool {
+        *self == Self::ABORT || *self == Self::CANCEL
+    }
+
+    /// Whether the label only shows the sender is alive and is otherwise
+    /// ignored
+    pub fn is_keep_alive(&self) -> bool {
+        *self == Self::HEARTBEAT || *self == Self::RESUME
+    }
+}
+
 /// Session endpoint trait
 ///
 /// Represents the runtime-specific connection state (e.g., Rumpsteak channel bundle).
@@ -56,7 +102,7 @@ pub trait Endpoint: Send {}
 impl<T: Send> Endpoint for T {}
 
 /// Errors that can occur during choreographic execution
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum ChoreographyError {
     /// Transport-layer error (network, channel failure, etc.)
     #[error("Transport error: {0}")]
@@ -77,6 +123,125 @@ pub enum ChoreographyError {
     /// Referenced role not found in the choreography
     #[error("Role {0:?} not found in this choreography")]
     UnknownRole(String),
+
+    /// An operation did not match the endpoint's session type
+    #[error("Session type mismatch: expected {expected}, found {found}")]
+    SessionMismatch { expected: String, found: String },
+
+    /// A session used more of a resource than its budget allows
+    #[error("Budget exceeded: {resource} {used} over limit {limit}")]
+    BudgetExceeded {
+        resource: &'static str,
+        limit: u64,
+        used: u64,
+    },
+
+    /// An encoded message to or from `role` is larger than the handler
+    /// accepts
+    #[error("Message exchanged with {role} is {size} bytes, over the limit of {limit}")]
+    MessageTooLarge {
+        role: String,
+        size: usize,
+        limit: usize,
+    },
+
+    /// A peer has fallen too far behind the messages sent to it
+    #[error("Role {role} is {lag} messages behind, over the limit of {limit}")]
+    SlowConsumer {
+        role: String,
+        lag: usize,
+        limit: usize,
+    },
+
+    /// A tenant's session addressed a role the tenant does not own
+    #[error("Tenant {tenant} may not address role {role}")]
+    TenantViolation { tenant: String, role: String },
+
+    /// A peer's certificate does not prove the role it claims to play
+    #[error("Authentication of {role} failed: {reason}")]
+    AuthenticationFailed { role: String, reason: String },
+
+    /// A message's authentication tag did not verify
+    #[error("Integrity violation from {role}: {reason}")]
+    IntegrityViolation { role: String, reason: String },
+
+    /// A peer is not running an approved version of the choreography
+    #[error("Role {role} is not running an approved protocol: {reason}")]
+    UnapprovedProtocol { role: String, reason: String },
+
+    /// A peer runs a different build of the choreography, or shares no
+    /// codec with this role
+    #[error("Role {role} does not match: expected {expected}, found {found}")]
+    VersionMismatch {
+        role: String,
+        expected: String,
+        found: String,
+    },
+
+    /// A peer ended the session with `sys.abort` or `sys.cancel`
+    #[error("Session ended by {role} with {label}")]
+    Aborted { role: String, label: &'static str },
+
+    /// The session was cancelled before it finished
+    #[error("Session was cancelled")]
+    Cancelled,
+
+    /// Declared roles did not all become ready before a startup barrier
+    /// timed out
+    #[error("Roles not ready after {waited:?}: {}", .missing.join(", "))]
+    NotReady {
+        missing: Vec<String>,
+        waited: Duration,
+    },
+
+    /// A session was saved elsewhere since it was loaded
+    #[error("Session {session} is at version {found}, not version {expected}")]
+    StaleSession {
+        session: String,
+        expected: u64,
+        found: u64,
+    },
+
+    /// A role had to choose, but none of the branches' guards held
+    #[error("No guard holds for {role}'s choice among {}", .labels.join(", "))]
+    GuardUnsatisfied { role: String, labels: Vec<String> },
+
+    /// An error raised by a step of a program, with where the step was
+    #[error("{context}: {source}")]
+    InContext {
+        context: Box<ErrorContext>,
+        source: Box<ChoreographyError>,
+    },
+}
+
+impl ChoreographyError {
+    /// The error without the context the interpreter added to it
+    pub fn root_cause(&self) -> &ChoreographyError {
+        match self {
+            ChoreographyError::InContext { source, .. } => source.root_cause(),
+            other => other,
+        }
+    }
+
+    /// Where the error happened, if it was raised by a running program
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            ChoreographyError::InContext { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Attach `context`, unless the error already has one from a step
+    /// nested deeper
+    pub(crate) fn in_context(self, context: ErrorContext) -> Self {
+        match self {
+            ChoreographyError::InContext { .. } => self,
+            source => ChoreographyError::InContext {
+                context: Box::new(context),
+                source: Box::new(source),
+            },
+        }
+    }
 }
 
 /// Result type for choreography operations
@@ -181,6 +346,27 @@ pub trait ChoreoHandler: Send {
     where
         F: std::future::Future<Output = Result<T>> + Send;
 
+    /// Tear down transport state after the session was cancelled
+    ///
+    /// Called by the interpreter once a `cancel` effect has run or the
+    /// session's [`CancellationToken`](crate::effects::CancellationToken)
+    /// fired. Nothing else is done with the endpoint afterwards. Handlers
+    /// that wrap another handler must pass the call on. The default does
+    /// nothing.
+    async fn on_cancel(&mut self, _ep: &mut Self::Endpoint) -> Result<()> {
+        Ok(())
+    }
+
+    /// Deliver anything the handler has held back
+    ///
+    /// Called by the interpreter once a program has finished without being
+    /// cancelled, so that messages a handler buffers are not left behind.
+    /// Handlers that wrap another handler must pass the call on. The default
+    /// does nothing.
+    async fn flush(&mut self, _ep: &mut Self::Endpoint) -> Result<()> {
+        Ok(())
+    }
+
     /// Broadcast a message to multiple recipients
     ///
     /// Default implementation sends sequentially. Override for optimized broadcasting.
@@ -231,7 +417,9 @@ pub trait ChoreoHandlerExt: ChoreoHandler {
 /// A no-op handler for testing pure choreographic logic
 ///
 /// This handler performs no actual communication, making it useful for
-/// testing protocol logic without network overhead.
+/// testing protocol logic without network overhead. Sends are accepted
+/// without any checks; use `ValidationHandler` to have them serialized and
+/// logged, or `RumpsteakHandler` to deliver them.
 pub struct NoOpHandler<R: RoleId> {
     _phantom: std::marker::PhantomData<R>,
 }
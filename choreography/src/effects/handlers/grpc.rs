@@ -0,0 +1,403 @@
+// gRPC effect handler
+//
+// Each directed edge of a choreography becomes a bidirectional streaming
+// method of the generated `.proto` (see `compiler::grpc`), served by the
+// receiving role. The sender opens the call and writes envelopes to its
+// request stream, so every edge is an ordered stream of its own. Payloads
+// are JSON, which participants written in other languages can decode
+// without the Rust types.
+//
+// The handler does not depend on a gRPC library. The generated tonic glue
+// converts between its prost types and `GrpcEnvelope`, hands the request
+// stream of each outgoing call to the endpoint, and passes the streams of
+// incoming calls to a `GrpcAcceptor`. Calls that fail are reported back
+// through `GrpcFailures`, so the next send on the edge returns the error.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::{Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::phases;
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId, WireIds};
+
+/// One item of an edge's stream, mirroring the `Envelope` message of the
+/// generated `.proto`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrpcEnvelope {
+    /// A message with its type name and JSON payload
+    Message { name: String, payload: Vec<u8> },
+    /// A branch label picked by the sender
+    Label(String),
+}
+
+type Inbox = UnboundedReceiver<Result<GrpcEnvelope>>;
+
+/// Incoming streams by peer, filled in as peers call in
+struct Incoming<R> {
+    senders: HashMap<R, UnboundedSender<Result<GrpcEnvelope>>>,
+    /// Inboxes not yet taken by the endpoint
+    inboxes: HashMap<R, Inbox>,
+}
+
+impl<R: RoleId> Incoming<R> {
+    fn slot(&mut self, peer: R) -> &UnboundedSender<Result<GrpcEnvelope>> {
+        let inboxes = &mut self.inboxes;
+        self.senders.entry(peer).or_insert_with(|| {
+            let (tx, rx) = unbounded();
+            inboxes.insert(peer, rx);
+            tx
+        })
+    }
+}
+
+/// Streams of one role to and from its peers
+pub struct GrpcEndpoint<R: RoleId> {
+    role: R,
+    outgoing: HashMap<R, UnboundedSender<GrpcEnvelope>>,
+    incoming: Arc<Mutex<Incoming<R>>>,
+    inboxes: HashMap<R, Inbox>,
+    /// Why the outgoing call to each peer failed, if it has
+    failures: Arc<Mutex<HashMap<R, ChoreographyError>>>,
+}
+
+impl<R: RoleId> GrpcEndpoint<R> {
+    pub fn new(role: R) -> Self {
+        Self {
+            role,
+            outgoing: HashMap::new(),
+            incoming: Arc::new(Mutex::new(Incoming {
+                senders: HashMap::new(),
+                inboxes: HashMap::new(),
+            })),
+            inboxes: HashMap::new(),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn local_role(&self) -> R {
+        self.role
+    }
+
+    /// The request stream for the call on the edge to `peer`
+    ///
+    /// Everything sent to `peer` is written to it. Replaces any earlier
+    /// stream to `peer`, which then ends, and forgets its failure.
+    pub fn outgoing(&mut self, peer: R) -> impl Stream<Item = GrpcEnvelope> + Send + 'static {
+        let (tx, rx) = unbounded();
+        self.outgoing.insert(peer, tx);
+        lock(&self.failures).remove(&peer);
+        rx
+    }
+
+    /// Handle through which a server passes on the calls of peers
+    pub fn acceptor(&self) -> GrpcAcceptor<R> {
+        GrpcAcceptor {
+            incoming: Arc::clone(&self.incoming),
+        }
+    }
+
+    /// Handle through which a client reports that an outgoing call failed
+    pub fn failures(&self) -> GrpcFailures<R> {
+        GrpcFailures {
+            failures: Arc::clone(&self.failures),
+        }
+    }
+
+    /// Roles this endpoint has an outgoing stream to
+    pub fn peers(&self) -> impl Iterator<Item = &R> {
+        self.outgoing.keys()
+    }
+
+    /// End the outgoing stream to `peer`, returning whether there was one
+    pub fn disconnect(&mut self, peer: &R) -> bool {
+        self.outgoing.remove(peer).is_some()
+    }
+
+    fn send_envelope(&mut self, to: R, envelope: GrpcEnvelope) -> Result<()> {
+        if let Some(failure) = lock(&self.failures).get(&to) {
+            return Err(failure.clone());
+        }
+        let stream = self.outgoing.get(&to).ok_or_else(|| {
+            ChoreographyError::Transport(format!("No gRPC stream open to role: {:?}", to))
+        })?;
+        stream
+            .unbounded_send(envelope)
+            .map_err(|_| ChoreographyError::Transport(format!("gRPC stream to {:?} ended", to)))
+    }
+
+    async fn recv_envelope(&mut self, from: R) -> Result<GrpcEnvelope> {
+        if !self.inboxes.contains_key(&from) {
+            let mut incoming = self
+                .incoming
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            incoming.slot(from);
+            if let Some(inbox) = incoming.inboxes.remove(&from) {
+                self.inboxes.insert(from, inbox);
+            }
+        }
+        let inbox = self.inboxes.get_mut(&from).ok_or_else(|| {
+            ChoreographyError::Transport(format!("No gRPC stream from role: {:?}", from))
+        })?;
+        match inbox.next().await {
+            Some(item) => item,
+            None => {
+                // A new call from the peer gets a fresh inbox
+                self.inboxes.remove(&from);
+                Err(ChoreographyError::Transport(format!(
+                    "gRPC stream from {:?} ended",
+                    from
+                )))
+            }
+        }
+    }
+}
+
+/// Passes the request streams of incoming calls to a [`GrpcEndpoint`]
+///
+/// Clones share the endpoint, so one can be moved into each generated
+/// service.
+#[derive(Clone)]
+pub struct GrpcAcceptor<R: RoleId> {
+    incoming: Arc<Mutex<Incoming<R>>>,
+}
+
+impl<R: RoleId + 'static> GrpcAcceptor<R> {
+    /// Read the request stream of `peer`'s call on the runtime
+    ///
+    /// Resolves once the stream has ended, which is when the call's
+    /// response stream should end too.
+    pub fn accept<S>(&self, peer: R, stream: S) -> impl std::future::Future<Output = ()>
+    where
+        S: Stream<Item = Result<GrpcEnvelope>> + Send + 'static,
+    {
+        let incoming = Arc::clone(&self.incoming);
+        let tx = incoming
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .slot(peer)
+            .clone();
+        let (done, ended) = oneshot::channel();
+        tracing::debug!(?peer, "gRPC call accepted");
+        crate::runtime::spawn(async move {
+            futures::pin_mut!(stream);
+            while let Some(item) = stream.next().await {
+                if tx.unbounded_send(item).is_err() {
+                    break;
+                }
+            }
+            // Close the inbox unless another call has replaced this one
+            let mut incoming = incoming
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if incoming
+                .senders
+                .get(&peer)
+                .is_some_and(|current| current.same_receiver(&tx))
+            {
+                incoming.senders.remove(&peer);
+            }
+            drop(incoming);
+            let _ = done.send(());
+        });
+        async move {
+            let _ = ended.await;
+        }
+    }
+}
+
+/// Records the failures of a [`GrpcEndpoint`]'s outgoing calls
+///
+/// Once the call to a peer has failed, sending to that peer returns the
+/// reported error instead of writing to a stream nobody reads.
+#[derive(Clone)]
+pub struct GrpcFailures<R: RoleId> {
+    failures: Arc<Mutex<HashMap<R, ChoreographyError>>>,
+}
+
+impl<R: RoleId> GrpcFailures<R> {
+    /// Record that the call to `peer` failed with `error`
+    pub fn report(&self, peer: R, error: ChoreographyError) {
+        tracing::warn!(?peer, %error, "gRPC call failed");
+        lock(&self.failures).insert(peer, error);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Handler that exchanges messages and labels over gRPC streams
+///
+/// Streams live on the [`GrpcEndpoint`]. When a role chooses a branch
+/// itself, the label is sent on every outgoing stream; choosing on behalf
+/// of another role sends it only to that role. System labels are handled
+/// as in the WebSocket handler.
+pub struct GrpcHandler<R> {
+    label_ids: WireIds,
+    _phantom: PhantomData<R>,
+}
+
+impl<R> GrpcHandler<R> {
+    pub fn new() -> Self {
+        Self {
+            label_ids: WireIds::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Send labels as their ids in `ids` rather than their names
+    pub fn with_label_ids(mut self, ids: WireIds) -> Self {
+        self.label_ids = ids;
+        self
+    }
+}
+
+impl<R> Default for GrpcHandler<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The name a message is sent under: its type name without the path
+fn message_name<M>() -> String {
+    let name = std::any::type_name::<M>();
+    let base = name.split('<').next().unwrap_or(name);
+    base.rsplit("::").next().unwrap_or(base).to_string()
+}
+
+#[async_trait]
+impl<R: RoleId + 'static> ChoreoHandler for GrpcHandler<R> {
+    type Role = R;
+    type Endpoint = GrpcEndpoint<R>;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload = phases::serializing(|| serde_json::to_vec(msg))
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let name = message_name::<M>();
+        tracing::debug!(?to, %name, size = payload.len(), "gRPC send");
+        ep.send_envelope(to, GrpcEnvelope::Message { name, payload })
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        // System labels may arrive where a message is expected
+        loop {
+            match ep.recv_envelope(from).await? {
+                GrpcEnvelope::Message { name, payload } => {
+                    tracing::debug!(?from, %name, size = payload.len(), "gRPC recv");
+                    return phases::deserializing(|| serde_json::from_slice(&payload))
+                        .map_err(|e| ChoreographyError::Serialization(e.to_string()));
+                }
+                GrpcEnvelope::Label(label) => match Label::system(&label) {
+                    Some(label) if label.is_keep_alive() => continue,
+                    Some(label) => {
+                        return Err(ChoreographyError::Aborted {
+                            role: format!("{:?}", from),
+                            label: label.0,
+                        })
+                    }
+                    None => {
+                        return Err(ChoreographyError::ProtocolViolation(format!(
+                            "expected a message from {:?}, got branch label {}",
+                            from, label
+                        )))
+                    }
+                },
+            }
+        }
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        let recipients: Vec<R> = if who == ep.role {
+            ep.outgoing.keys().copied().collect()
+        } else {
+            vec![who]
+        };
+        tracing::debug!(?recipients, ?label, "gRPC choose");
+        let wire = self.label_ids.encode(label);
+        for peer in recipients {
+            ep.send_envelope(peer, GrpcEnvelope::Label(wire.clone()))?;
+        }
+        Ok(())
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        match ep.recv_envelope(from).await? {
+            GrpcEnvelope::Label(label) => {
+                tracing::debug!(?from, %label, "gRPC offer");
+                Ok(self.label_ids.decode(label))
+            }
+            GrpcEnvelope::Message { name, .. } => {
+                Err(ChoreographyError::ProtocolViolation(format!(
+                    "expected a branch label from {:?}, got message {}",
+                    from, name
+                )))
+            }
+        }
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        _at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            match tokio::time::timeout(dur, body).await {
+                Ok(result) => result,
+                Err(_) => Err(ChoreographyError::Timeout(dur)),
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            use futures::future::{select, Either};
+            use futures::pin_mut;
+            use wasm_timer::Delay;
+
+            let timeout = Delay::new(dur);
+            pin_mut!(body);
+            pin_mut!(timeout);
+
+            match select(body, timeout).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(ChoreographyError::Timeout(dur)),
+            }
+        }
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        // Ends the request streams of the outgoing calls
+        tracing::debug!(peers = ep.outgoing.len(), "gRPC cancel");
+        ep.outgoing.clear();
+        ep.inboxes.clear();
+        Ok(())
+    }
+}
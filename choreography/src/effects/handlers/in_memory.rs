@@ -11,7 +11,8 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::collections::HashMap;
 use std::time::Duration;
 
-use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+use super::{check_message_size, phases};
+use crate::effects::{ChoreoHandler, ChoreoHandlerExt, ChoreographyError, Label, Result, RoleId};
 
 type MessageChannelPair = (UnboundedSender<Vec<u8>>, UnboundedReceiver<Vec<u8>>);
 type ChoiceChannelPair = (UnboundedSender<Label>, UnboundedReceiver<Label>);
@@ -23,6 +24,8 @@ pub struct InMemoryHandler<R: RoleId> {
     channels: std::sync::Arc<std::sync::Mutex<HashMap<(R, R), MessageChannelPair>>>,
     // Choice channel for broadcasting/receiving choice labels
     choice_channels: std::sync::Arc<std::sync::Mutex<HashMap<(R, R), ChoiceChannelPair>>>,
+    // Largest encoded message sent or received, if limited
+    max_message_size: Option<usize>,
 }
 
 impl<R: RoleId> InMemoryHandler<R> {
@@ -31,6 +34,7 @@ impl<R: RoleId> InMemoryHandler<R> {
             role,
             channels: std::sync::Arc::new(std::sync::Mutex::new(HashMap::new())),
             choice_channels: std::sync::Arc::new(std::sync::Mutex::new(HashMap::new())),
+            max_message_size: None,
         }
     }
 
@@ -44,9 +48,17 @@ impl<R: RoleId> InMemoryHandler<R> {
             role,
             channels,
             choice_channels,
+            max_message_size: None,
         }
     }
 
+    /// Refuse to send or receive messages that encode to more than `limit`
+    /// bytes, with [`ChoreographyError::MessageTooLarge`]
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
     /// Get or create a channel pair for communication between two roles
     fn get_or_create_channel(&self, from: R, to: R) -> UnboundedSender<Vec<u8>> {
         let mut channels = self
@@ -60,13 +72,17 @@ impl<R: RoleId> InMemoryHandler<R> {
             .clone()
     }
 
-    /// Get receiver for a channel pair
-    fn get_receiver(&self, from: R, to: R) -> Option<UnboundedReceiver<Vec<u8>>> {
+    /// Take the receiver of a channel pair, creating the pair if needed
+    ///
+    /// The sender stays in place, so messages sent while the receiver is
+    /// out still reach it.
+    fn take_receiver(&self, from: R, to: R) -> UnboundedReceiver<Vec<u8>> {
         let mut channels = self
             .channels
             .lock()
             .unwrap_or_else(|poisoned| poisoned.into_inner());
-        channels.remove(&(from, to)).map(|(_, rx)| rx)
+        let (_, receiver) = channels.entry((from, to)).or_insert_with(unbounded);
+        std::mem::replace(receiver, unbounded().1)
     }
 
     /// Get or create a choice channel pair for broadcasting choices
@@ -105,8 +121,9 @@ impl<R: RoleId + 'static> ChoreoHandler for InMemoryHandler<R> {
         msg: &M,
     ) -> Result<()> {
         // Serialize message
-        let bytes =
-            bincode::serialize(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let bytes = phases::serializing(|| bincode::serialize(msg))
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        check_message_size(to, bytes.len(), self.max_message_size)?;
 
         // Get or create channel for (self.role, to) and send bytes
         let sender = self.get_or_create_channel(self.role, to);
@@ -129,9 +146,7 @@ impl<R: RoleId + 'static> ChoreoHandler for InMemoryHandler<R> {
         tracing::trace!(?from, "InMemoryHandler: recv start");
 
         // Get the receiver for messages from 'from' to 'self.role'
-        let mut receiver = self.get_receiver(from, self.role).ok_or_else(|| {
-            ChoreographyError::Transport(format!("No channel from {:?} to {:?}", from, self.role))
-        })?;
+        let mut receiver = self.take_receiver(from, self.role);
 
         // Wait for message
         let bytes = receiver.next().await.ok_or_else(|| {
@@ -150,7 +165,8 @@ impl<R: RoleId + 'static> ChoreoHandler for InMemoryHandler<R> {
         }
 
         // Deserialize message
-        let msg = bincode::deserialize(&bytes)
+        check_message_size(from, bytes.len(), self.max_message_size)?;
+        let msg = phases::deserializing(|| bincode::deserialize(&bytes))
             .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
 
         tracing::trace!(?from, "InMemoryHandler: recv success");
@@ -243,3 +259,15 @@ impl<R: RoleId + 'static> ChoreoHandler for InMemoryHandler<R> {
         }
     }
 }
+
+// Channels are created on first use, so there is nothing to set up
+#[async_trait]
+impl<R: RoleId + 'static> ChoreoHandlerExt for InMemoryHandler<R> {
+    async fn setup(&mut self, _role: Self::Role) -> Result<Self::Endpoint> {
+        Ok(())
+    }
+
+    async fn teardown(&mut self, _ep: Self::Endpoint) -> Result<()> {
+        Ok(())
+    }
+}
@@ -3,15 +3,64 @@
 // This module contains concrete implementations of the ChoreoHandler trait
 // for different execution environments:
 //
+// - grpc: gRPC streams per choreography edge, for non-Rust participants
 // - in_memory: WASM-compatible handler using futures channels for testing
+// - phases: Serialization and deserialization timing shared by the handlers
 // - recording: Captures effects for verification
 // - rumpsteak: Session-typed Rumpsteak integration (WASM-compatible via SimpleChannel)
+// - session: Runtime session types checked by the rumpsteak handler
+// - tls: Mutual TLS over any byte stream, checking peers' roles (`tls` feature)
+// - validation: Checks outgoing effects without a transport
+// - websocket: WebSocket transport for browser (WASM) and native peers
 
+// Handlers are used through the re-exports below; only the rumpsteak and
+// session modules have public items of their own, such as `message_name`
+#[doc(hidden)]
+pub mod grpc;
+#[doc(hidden)]
 pub mod in_memory;
+pub(crate) mod phases;
+#[doc(hidden)]
 pub mod recording;
 pub mod rumpsteak;
+pub mod session;
+#[cfg(all(not(target_arch = "wasm32"), feature = "tls"))]
+#[doc(hidden)]
+pub mod tls;
+#[doc(hidden)]
+pub mod validation;
+#[doc(hidden)]
+pub mod websocket;
 
 // Re-export handler types for convenience
+pub use grpc::{GrpcAcceptor, GrpcEndpoint, GrpcEnvelope, GrpcFailures, GrpcHandler};
 pub use in_memory::InMemoryHandler;
 pub use recording::{RecordedEvent, RecordingHandler};
 pub use rumpsteak::{HasRoute, RumpsteakEndpoint, RumpsteakHandler, SimpleChannel};
+pub use session::{SessionCursor, SessionType};
+#[cfg(all(not(target_arch = "wasm32"), feature = "tls"))]
+pub use tls::{TlsConfig, TlsEndpoint, TlsHandler};
+pub use validation::{ValidatedEffect, ValidationHandler};
+pub use websocket::{WebSocketEndpoint, WebSocketHandler};
+
+use crate::effects::{ChoreographyError, Result};
+
+/// Fail with [`ChoreographyError::MessageTooLarge`] if an encoded message
+/// exchanged with `peer` is over `limit`
+pub(crate) fn check_message_size<R: std::fmt::Debug>(
+    peer: R,
+    size: usize,
+    limit: Option<usize>,
+) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => {
+            tracing::warn!(?peer, size, limit, "message over the size limit");
+            Err(ChoreographyError::MessageTooLarge {
+                role: format!("{:?}", peer),
+                size,
+                limit,
+            })
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,90 @@
+// Serialization and deserialization timing for handlers
+//
+// Handlers encode and decode messages through `serializing` and
+// `deserializing`, which run the codec in a `serialize` or `deserialize`
+// tracing span, so flame graphs built from spans separate codec time from
+// the rest of a step. While a `Measured` future is being polled, the time
+// spent in them is also added to its `Phases`; the Metrics middleware uses
+// this to tell codec time from transport time.
+//
+// Codecs run synchronously inside a poll, so the phases being measured are
+// kept in a thread-local stack that each `Measured` future pushes onto for
+// the length of its poll. Nothing is timed when no operation is measured.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Time an operation spent encoding and decoding messages; `None` if it
+/// did not
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Phases {
+    pub serialize: Option<Duration>,
+    pub deserialize: Option<Duration>,
+}
+
+thread_local! {
+    static MEASURING: RefCell<Vec<Arc<Mutex<Phases>>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `encode` as the serialization of a message
+pub(crate) fn serializing<T>(encode: impl FnOnce() -> T) -> T {
+    let _span = tracing::trace_span!("serialize").entered();
+    timed(encode, |phases| &mut phases.serialize)
+}
+
+/// Run `decode` as the deserialization of a message
+pub(crate) fn deserializing<T>(decode: impl FnOnce() -> T) -> T {
+    let _span = tracing::trace_span!("deserialize").entered();
+    timed(decode, |phases| &mut phases.deserialize)
+}
+
+fn timed<T>(f: impl FnOnce() -> T, phase: fn(&mut Phases) -> &mut Option<Duration>) -> T {
+    if MEASURING.with(|measuring| measuring.borrow().is_empty()) {
+        return f();
+    }
+    let started = Instant::now();
+    let value = f();
+    let elapsed = started.elapsed();
+    MEASURING.with(|measuring| {
+        for phases in measuring.borrow().iter() {
+            let mut phases = phases
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let total = phase(&mut phases);
+            *total = Some(total.unwrap_or_default() + elapsed);
+        }
+    });
+    value
+}
+
+/// `future`, adding the time it spends in codecs to `phases`
+pub(crate) fn measure<F: Future + Unpin>(future: F, phases: Arc<Mutex<Phases>>) -> Measured<F> {
+    Measured { future, phases }
+}
+
+pub(crate) struct Measured<F> {
+    future: F,
+    phases: Arc<Mutex<Phases>>,
+}
+
+impl<F: Future + Unpin> Future for Measured<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.get_mut();
+        MEASURING.with(|measuring| measuring.borrow_mut().push(this.phases.clone()));
+        // Popped by the guard even if the poll panics
+        struct Pop;
+        impl Drop for Pop {
+            fn drop(&mut self) {
+                MEASURING.with(|measuring| measuring.borrow_mut().pop());
+            }
+        }
+        let _pop = Pop;
+        Pin::new(&mut this.future).poll(cx)
+    }
+}
@@ -8,15 +8,22 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+use super::session::{message_name, SessionCursor, SessionType};
+use super::{check_message_size, phases};
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId, WireIds};
 use rumpsteak_aura::{Message, Role, Route};
 
 /// Simple bidirectional channel for basic message passing
 ///
-/// This is a simplified channel type for Phase 1 implementation.
-/// Phase 2 will integrate with full Rumpsteak session types.
+/// Carries serialized messages only. Protocol order is enforced separately
+/// by the endpoint's [`SessionType`], if one is set.
+///
+/// Each end counts the messages queued in either direction, so a sender can
+/// tell how far behind its peer is.
 ///
 /// Note: This does not implement Clone. Channels should be unique per endpoint
 /// and managed via the take/put pattern in SessionChannelBundle.
@@ -25,6 +32,11 @@ pub struct SimpleChannel {
     sender: mpsc::UnboundedSender<Vec<u8>>,
     /// Receiver for incoming messages
     receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Messages sent that the peer has not received, shared with its
+    /// `incoming`
+    outgoing: Arc<AtomicUsize>,
+    /// Messages the peer sent that have not been received here
+    incoming: Arc<AtomicUsize>,
 }
 
 impl SimpleChannel {
@@ -32,15 +44,21 @@ impl SimpleChannel {
     pub fn pair() -> (Self, Self) {
         let (tx1, rx1) = mpsc::unbounded();
         let (tx2, rx2) = mpsc::unbounded();
+        let one_to_two = Arc::new(AtomicUsize::new(0));
+        let two_to_one = Arc::new(AtomicUsize::new(0));
 
         (
             SimpleChannel {
                 sender: tx1,
                 receiver: rx2,
+                outgoing: one_to_two.clone(),
+                incoming: two_to_one.clone(),
             },
             SimpleChannel {
                 sender: tx2,
                 receiver: rx1,
+                outgoing: two_to_one,
+                incoming: one_to_two,
             },
         )
     }
@@ -50,15 +68,31 @@ impl SimpleChannel {
         self.sender
             .send(msg)
             .await
-            .map_err(|e| format!("Send failed: {}", e))
+            .map_err(|e| format!("Send failed: {}", e))?;
+        self.outgoing.fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
     /// Receive a message
     pub async fn recv(&mut self) -> std::result::Result<Vec<u8>, String> {
-        self.receiver
+        let msg = self
+            .receiver
             .next()
             .await
-            .ok_or_else(|| "Channel closed".to_string())
+            .ok_or_else(|| "Channel closed".to_string())?;
+        self.incoming.fetch_sub(1, Ordering::Relaxed);
+        Ok(msg)
+    }
+
+    /// Messages and labels sent on this channel that the peer has not
+    /// received yet
+    pub fn lag(&self) -> usize {
+        self.outgoing.load(Ordering::Relaxed)
+    }
+
+    /// Messages and labels waiting to be received on this channel
+    pub fn backlog(&self) -> usize {
+        self.incoming.load(Ordering::Relaxed)
     }
 }
 
@@ -67,13 +101,9 @@ impl SimpleChannel {
 /// This wraps a session-typed channel (Send<>, Receive<>, etc.) and tracks
 /// its current state for proper progression through the protocol.
 ///
-/// Phase 2 Note: Full Rumpsteak session type integration would require:
-/// - Storing heterogeneous session types (Send<A,M1,S1>, Receive<B,M2,S2>, etc.)
-/// - Progressing types through operations (Send<R,M,S> -> S)
-/// - Managing lifetime parameters
-/// - Type-safe branch selection for choices
-///
-/// Current implementation uses SimpleChannel as a baseline.
+/// Rumpsteak's own session type values borrow their role, so they cannot be
+/// stored here. Progression through the protocol is tracked by
+/// [`SessionCursor`] on the channel bundle instead.
 pub struct SessionState {
     /// The underlying channel (can be SimpleChannel or Rumpsteak session type)
     channel: Box<dyn Any + Send + Sync>,
@@ -216,7 +246,8 @@ impl ChannelBox {
 /// Each role maps to a channel with its current session state.
 ///
 /// Phase 2 Enhancement: Now tracks session state metadata for each channel,
-/// enabling visibility into session progression and state.
+/// enabling visibility into session progression and state. When a session
+/// type is set, every operation must match it and advances it.
 pub struct SessionChannelBundle<RoleKey>
 where
     RoleKey: Eq + std::hash::Hash + Clone,
@@ -225,6 +256,8 @@ where
     channels: HashMap<RoleKey, ChannelBox>,
     /// Map from role to session metadata
     session_metadata: HashMap<RoleKey, SessionMetadata>,
+    /// Session type of the local role, if operations are checked
+    session: Option<SessionCursor<RoleKey>>,
 }
 
 impl<RoleKey> SessionChannelBundle<RoleKey>
@@ -236,6 +269,28 @@ where
         Self {
             channels: HashMap::new(),
             session_metadata: HashMap::new(),
+            session: None,
+        }
+    }
+
+    /// Check operations on this bundle against a session type
+    pub fn set_session(&mut self, session: SessionCursor<RoleKey>) {
+        self.session = Some(session);
+    }
+
+    /// Progress through the session type, if one is set
+    pub fn session(&self) -> Option<&SessionCursor<RoleKey>> {
+        self.session.as_ref()
+    }
+
+    /// Apply a session step, succeeding trivially when no session type is set
+    fn step_session(
+        &mut self,
+        step: impl FnOnce(&mut SessionCursor<RoleKey>) -> Result<()>,
+    ) -> Result<()> {
+        match &mut self.session {
+            Some(session) => step(session),
+            None => Ok(()),
         }
     }
 
@@ -277,6 +332,24 @@ where
         self.channels.insert(role, ChannelBox::new(channel));
     }
 
+    /// Borrow a role's channel to inspect it
+    ///
+    /// Returns `None` if no channel is registered or it is not a `T`.
+    pub fn channel<T: Any>(&self, role: &RoleKey) -> Option<&T> {
+        self.channels
+            .get(role)
+            .and_then(|channel| channel.inner.downcast_ref())
+    }
+
+    /// Borrow a role's channel without removing it from the bundle
+    ///
+    /// Returns `None` if no channel is registered or it is not a `T`.
+    pub fn channel_mut<T: Any>(&mut self, role: &RoleKey) -> Option<&mut T> {
+        self.channels
+            .get_mut(role)
+            .and_then(|channel| channel.inner.downcast_mut())
+    }
+
     /// Get metadata for a role's session
     pub fn get_metadata(&self, role: &RoleKey) -> Option<&SessionMetadata> {
         self.session_metadata.get(role)
@@ -355,6 +428,25 @@ where
         }
     }
 
+    /// Create an endpoint whose operations are checked against `session`
+    ///
+    /// Each send, receive, choice, and offer must match the next step of the
+    /// session type, or the handler fails with
+    /// [`ChoreographyError::SessionMismatch`] before touching the channel.
+    pub fn with_session_type(local_role: R, session: SessionType<R>) -> Self
+    where
+        R: std::fmt::Debug,
+    {
+        let mut endpoint = Self::new(local_role);
+        endpoint.channels.set_session(SessionCursor::new(session));
+        endpoint
+    }
+
+    /// Progress through the session type, if one is set
+    pub fn session(&self) -> Option<&SessionCursor<R>> {
+        self.channels.session()
+    }
+
     /// Register a session-typed channel with a peer role
     ///
     /// # Example
@@ -381,11 +473,29 @@ where
         self.channels.put_channel(peer, channel);
     }
 
+    /// Borrow a peer's channel in place
+    ///
+    /// Unlike `take_channel`, the channel stays registered if the operation
+    /// using it is dropped part way, as when a timeout expires during a
+    /// receive.
+    pub fn channel_mut<T: Any>(&mut self, peer: &R) -> Option<&mut T> {
+        self.channels.channel_mut(peer)
+    }
+
     /// Check if a channel is registered for a peer
     pub fn has_channel(&self, peer: &R) -> bool {
         self.channels.has_channel(peer)
     }
 
+    /// How many messages and labels sent to `peer` it has not received yet
+    ///
+    /// `None` if the peer has no [`SimpleChannel`] registered.
+    pub fn lag(&self, peer: &R) -> Option<usize> {
+        self.channels
+            .channel::<SimpleChannel>(peer)
+            .map(SimpleChannel::lag)
+    }
+
     /// Remove a channel for a peer
     pub fn close_channel(&mut self, peer: &R) -> bool {
         tracing::debug!("Closing channel");
@@ -464,16 +574,67 @@ where
 }
 
 /// Handler that interprets effects using Rumpsteak's session-typed channels
+///
+/// Every send is delivered over the channel registered for the peer and
+/// fails if there is none. Endpoints built with
+/// [`RumpsteakEndpoint::with_session_type`] also check each operation
+/// against the role's session type and advance it. To run a role without peers, use
+/// `ValidationHandler`, which checks sends but never delivers them.
+///
+/// Messages are unbounded in size unless limited with
+/// [`with_max_message_size`](Self::with_max_message_size), and a peer may
+/// fall arbitrarily far behind unless limited with
+/// [`with_max_lag`](Self::with_max_lag).
 pub struct RumpsteakHandler<R, M> {
+    max_message_size: Option<usize>,
+    lag_warning: Option<usize>,
+    max_lag: Option<usize>,
+    label_ids: WireIds,
     _phantom: PhantomData<(R, M)>,
 }
 
 impl<R, M> RumpsteakHandler<R, M> {
     pub fn new() -> Self {
         Self {
+            max_message_size: None,
+            lag_warning: None,
+            max_lag: None,
+            label_ids: WireIds::default(),
             _phantom: PhantomData,
         }
     }
+
+    /// Log a warning for each message sent to a peer that is `threshold` or
+    /// more messages behind
+    pub fn with_lag_warning(mut self, threshold: usize) -> Self {
+        self.lag_warning = Some(threshold);
+        self
+    }
+
+    /// Refuse to send messages to a peer that is `limit` or more messages
+    /// behind, with [`ChoreographyError::SlowConsumer`]
+    ///
+    /// Labels are still sent, so the role can choose a branch that
+    /// throttles or aborts.
+    pub fn with_max_lag(mut self, limit: usize) -> Self {
+        self.max_lag = Some(limit);
+        self
+    }
+
+    /// Refuse to send or receive messages that encode to more than `limit`
+    /// bytes, with [`ChoreographyError::MessageTooLarge`]
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// Send labels as their ids in `ids` rather than their names
+    ///
+    /// Generated code passes the ids declared with `id Name = n`.
+    pub fn with_label_ids(mut self, ids: WireIds) -> Self {
+        self.label_ids = ids;
+        self
+    }
 }
 
 impl<R, M> Default for RumpsteakHandler<R, M> {
@@ -489,6 +650,25 @@ pub trait HasRoute<R: RoleId>: Route<R> {
     fn get_route_mut(&mut self) -> &mut Self::RouteType;
 }
 
+/// Channel to wait on for a message or label from `peer`
+fn waiting_channel<'a, R>(
+    ep: &'a mut RumpsteakEndpoint<R>,
+    peer: &R,
+) -> Result<&'a mut SimpleChannel>
+where
+    R: Role + Eq + std::hash::Hash + Clone + std::fmt::Debug,
+{
+    if !ep.has_channel(peer) {
+        return Err(ChoreographyError::Transport(format!(
+            "No channel registered for role: {:?}",
+            peer
+        )));
+    }
+    ep.channel_mut(peer).ok_or_else(|| {
+        ChoreographyError::Transport("Failed to downcast channel - wrong channel type".to_string())
+    })
+}
+
 #[async_trait]
 impl<R, M> ChoreoHandler for RumpsteakHandler<R, M>
 where
@@ -505,9 +685,24 @@ where
         msg: &Msg,
     ) -> Result<()> {
         // Serialize the message
-        let serialized = bincode::serialize(msg)
+        let serialized = phases::serializing(|| bincode::serialize(msg))
             .map_err(|e| ChoreographyError::Transport(format!("Serialization failed: {}", e)))?;
         tracing::debug!(?to, size = serialized.len(), "Sending message");
+        check_message_size(to, serialized.len(), self.max_message_size)?;
+        if let Some(lag) = ep.lag(&to) {
+            if self.lag_warning.is_some_and(|threshold| lag >= threshold) {
+                tracing::warn!(?to, lag, "peer is falling behind");
+            }
+            if let Some(limit) = self.max_lag.filter(|limit| lag >= *limit) {
+                return Err(ChoreographyError::SlowConsumer {
+                    role: format!("{:?}", to),
+                    lag,
+                    limit,
+                });
+            }
+        }
+        ep.channels
+            .step_session(|session| session.send(to, message_name::<Msg>()))?;
 
         // Take the channel for this peer
         let channel_box = ep.take_channel(&to).ok_or_else(|| {
@@ -540,33 +735,23 @@ where
         from: Self::Role,
     ) -> Result<Msg> {
         tracing::debug!(?from, "Receiving message");
+        ep.channels
+            .step_session(|session| session.receive(from, message_name::<Msg>()))?;
 
-        // Take the channel for this peer
-        let channel_box = ep.take_channel(&from).ok_or_else(|| {
-            ChoreographyError::Transport(format!("No channel registered for role: {:?}", from))
-        })?;
-
-        // Downcast to SimpleChannel
-        let mut channel = *channel_box.downcast::<SimpleChannel>().map_err(|_| {
-            ChoreographyError::Transport(
-                "Failed to downcast channel - wrong channel type".to_string(),
-            )
-        })?;
-
-        // Receive the serialized message
-        let serialized = channel
+        // Wait on the channel in place, so a receive dropped by a timeout
+        // leaves it registered
+        let serialized = waiting_channel(ep, &from)?
             .recv()
             .await
             .map_err(|e| ChoreographyError::Transport(format!("Receive failed: {}", e)))?;
 
         tracing::debug!(?from, size = serialized.len(), "Received message");
+        check_message_size(from, serialized.len(), self.max_message_size)?;
 
         // Deserialize the message
-        let msg: Msg = bincode::deserialize(&serialized)
+        let msg: Msg = phases::deserializing(|| bincode::deserialize(&serialized))
             .map_err(|e| ChoreographyError::Transport(format!("Deserialization failed: {}", e)))?;
 
-        // Put the channel back and mark operation
-        ep.put_channel(from, channel);
         ep.channels.mark_operation(&from, "Recv");
 
         Ok(msg)
@@ -579,6 +764,8 @@ where
         label: Label,
     ) -> Result<()> {
         tracing::debug!(?who, ?label, "Choosing branch");
+        ep.channels
+            .step_session(|session| session.select(who, label.0))?;
 
         // Take the channel for this peer
         let channel_box = ep.take_channel(&who).ok_or_else(|| {
@@ -593,7 +780,7 @@ where
         })?;
 
         // Serialize and send the label
-        let serialized = bincode::serialize(&label.0).map_err(|e| {
+        let serialized = bincode::serialize(&self.label_ids.encode(label)).map_err(|e| {
             ChoreographyError::Transport(format!("Label serialization failed: {}", e))
         })?;
 
@@ -611,21 +798,11 @@ where
 
     async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
         tracing::debug!(?from, "Offering choice");
+        ep.channels
+            .step_session(|session| session.expect_branch(from))?;
 
-        // Take the channel for this peer
-        let channel_box = ep.take_channel(&from).ok_or_else(|| {
-            ChoreographyError::Transport(format!("No channel registered for role: {:?}", from))
-        })?;
-
-        // Downcast to SimpleChannel
-        let mut channel = *channel_box.downcast::<SimpleChannel>().map_err(|_| {
-            ChoreographyError::Transport(
-                "Failed to downcast channel - wrong channel type".to_string(),
-            )
-        })?;
-
-        // Receive the serialized label
-        let serialized = channel
+        // Wait on the channel in place, as for `recv`
+        let serialized = waiting_channel(ep, &from)?
             .recv()
             .await
             .map_err(|e| ChoreographyError::Transport(format!("Choice receive failed: {}", e)))?;
@@ -635,16 +812,14 @@ where
             ChoreographyError::Transport(format!("Label deserialization failed: {}", e))
         })?;
 
-        tracing::debug!(?from, label = ?label_string, "Received choice");
+        let label = self.label_ids.decode(label_string);
+        tracing::debug!(?from, ?label, "Received choice");
+        ep.channels
+            .step_session(|session| session.branch(from, label.0))?;
 
-        // Put the channel back and mark operation
-        ep.put_channel(from, channel);
         ep.mark_operation(&from, "Offer");
 
-        // Convert String to &'static str by leaking (labels are small and long-lived)
-        let label_str: &'static str = Box::leak(label_string.into_boxed_str());
-
-        Ok(Label(label_str))
+        Ok(label)
     }
 
     async fn with_timeout<F, T>(
@@ -681,4 +856,10 @@ where
             }
         }
     }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        // Peers waiting on a closed channel fail instead of hanging
+        ep.close_all_channels();
+        Ok(())
+    }
 }
@@ -0,0 +1,449 @@
+// Runtime session types for RumpsteakHandler
+//
+// Rumpsteak's `Send<'q, Q, R, L, S>` values borrow the role they run on,
+// so they cannot live in a type-erased channel bundle. SessionType mirrors
+// the same combinators as data. The handler advances it on every operation,
+// the way `Send<R, M, S>` becomes `S`, and rejects effects that do not match.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+
+use crate::ast::{Condition, LocalType};
+use crate::effects::{ChoreographyError, Label, Result};
+
+/// A local session type, one combinator per rumpsteak type
+///
+/// Messages are named by their Rust type name without the module path, so
+/// `SessionType::send::<Ping>(Role::Bob, ...)` corresponds to
+/// `Send<Bob, Ping, ...>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionType<R> {
+    Send {
+        to: R,
+        message: String,
+        continuation: Box<SessionType<R>>,
+    },
+    Receive {
+        from: R,
+        message: String,
+        continuation: Box<SessionType<R>>,
+    },
+    /// Internal choice, announced to `to`
+    Select {
+        to: R,
+        branches: Vec<(String, SessionType<R>)>,
+    },
+    /// External choice, made by `from`
+    Branch {
+        from: R,
+        branches: Vec<(String, SessionType<R>)>,
+    },
+    Rec {
+        label: String,
+        body: Box<SessionType<R>>,
+    },
+    Var(String),
+    End,
+}
+
+impl<R> SessionType<R> {
+    pub fn send<M: ?Sized>(to: R, continuation: SessionType<R>) -> Self {
+        SessionType::Send {
+            to,
+            message: message_name::<M>().to_string(),
+            continuation: Box::new(continuation),
+        }
+    }
+
+    pub fn receive<M: ?Sized>(from: R, continuation: SessionType<R>) -> Self {
+        SessionType::Receive {
+            from,
+            message: message_name::<M>().to_string(),
+            continuation: Box::new(continuation),
+        }
+    }
+
+    pub fn select(to: R, branches: Vec<(&str, SessionType<R>)>) -> Self {
+        SessionType::Select {
+            to,
+            branches: named(branches),
+        }
+    }
+
+    pub fn branch(from: R, branches: Vec<(&str, SessionType<R>)>) -> Self {
+        SessionType::Branch {
+            from,
+            branches: named(branches),
+        }
+    }
+
+    pub fn rec(label: impl Into<String>, body: SessionType<R>) -> Self {
+        SessionType::Rec {
+            label: label.into(),
+            body: Box::new(body),
+        }
+    }
+
+    pub fn var(label: impl Into<String>) -> Self {
+        SessionType::Var(label.into())
+    }
+
+    /// Replace every `End` with `next`
+    pub fn then(self, next: SessionType<R>) -> Self
+    where
+        R: Clone,
+    {
+        match self {
+            SessionType::Send {
+                to,
+                message,
+                continuation,
+            } => SessionType::Send {
+                to,
+                message,
+                continuation: Box::new(continuation.then(next)),
+            },
+            SessionType::Receive {
+                from,
+                message,
+                continuation,
+            } => SessionType::Receive {
+                from,
+                message,
+                continuation: Box::new(continuation.then(next)),
+            },
+            SessionType::Select { to, branches } => SessionType::Select {
+                to,
+                branches: then_branches(branches, &next),
+            },
+            SessionType::Branch { from, branches } => SessionType::Branch {
+                from,
+                branches: then_branches(branches, &next),
+            },
+            SessionType::Rec { label, body } => SessionType::Rec {
+                label,
+                body: Box::new(body.then(next)),
+            },
+            SessionType::End => next,
+            var @ SessionType::Var(_) => var,
+        }
+    }
+
+    /// Convert a projected local type
+    ///
+    /// `role` maps each peer of the projection to a runtime role. A counted
+    /// loop is unrolled, any other loop repeats its body indefinitely, and a
+    /// `finally` cleanup follows its body. Returns `None` for a local choice,
+    /// which has no observable effect to check, or for an unmapped role.
+    pub fn from_local_type(
+        local: &LocalType,
+        role: &dyn Fn(&crate::ast::Role) -> Option<R>,
+    ) -> Option<Self>
+    where
+        R: Clone,
+    {
+        convert(local, role, &mut 0)
+    }
+}
+
+impl<R: Debug> fmt::Display for SessionType<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let labels = |branches: &[(String, SessionType<R>)]| {
+            branches
+                .iter()
+                .map(|(label, _)| label.as_str())
+                .collect::<Vec<_>>()
+                .join(" | ")
+        };
+        match self {
+            SessionType::Send { to, message, .. } => write!(f, "send {} to {:?}", message, to),
+            SessionType::Receive { from, message, .. } => {
+                write!(f, "receive {} from {:?}", message, from)
+            }
+            SessionType::Select { to, branches } => {
+                write!(f, "select {{ {} }} to {:?}", labels(branches), to)
+            }
+            SessionType::Branch { from, branches } => {
+                write!(f, "branch {{ {} }} from {:?}", labels(branches), from)
+            }
+            SessionType::Rec { label, .. } => write!(f, "rec {}", label),
+            SessionType::Var(label) => write!(f, "continue {}", label),
+            SessionType::End => write!(f, "end"),
+        }
+    }
+}
+
+/// Progress of an endpoint through its session type
+///
+/// Each operation either advances to the continuation or fails with
+/// [`ChoreographyError::SessionMismatch`] and leaves the state unchanged.
+/// Recursion is unfolded on demand.
+///
+/// The reserved labels `sys.abort`, `sys.cancel`, `sys.heartbeat`, and
+/// `sys.resume` may be selected at any point and offered wherever a choice
+/// is expected. They are recorded, and the ones that end the session move
+/// it to `End`; the others leave it where it was.
+#[derive(Debug, Clone)]
+pub struct SessionCursor<R> {
+    current: SessionType<R>,
+    recursion: HashMap<String, SessionType<R>>,
+    steps: usize,
+    system: Vec<(R, Label)>,
+}
+
+impl<R: Clone + PartialEq + Debug> SessionCursor<R> {
+    pub fn new(session: SessionType<R>) -> Self {
+        let mut cursor = Self {
+            current: session,
+            recursion: HashMap::new(),
+            steps: 0,
+            system: Vec::new(),
+        };
+        cursor.unfold();
+        cursor
+    }
+
+    /// The session type still to run
+    pub fn current(&self) -> &SessionType<R> {
+        &self.current
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.current == SessionType::End
+    }
+
+    /// Number of operations performed so far
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// System labels exchanged so far, with the peer they went to or came
+    /// from
+    pub fn system_labels(&self) -> &[(R, Label)] {
+        &self.system
+    }
+
+    pub fn send(&mut self, to: R, message: &str) -> Result<()> {
+        let next = match &self.current {
+            SessionType::Send {
+                to: expected,
+                message: name,
+                continuation,
+            } if *expected == to && name == message => (**continuation).clone(),
+            _ => return Err(self.mismatch(format!("send {} to {:?}", message, to))),
+        };
+        self.advance(next);
+        Ok(())
+    }
+
+    pub fn receive(&mut self, from: R, message: &str) -> Result<()> {
+        let next = match &self.current {
+            SessionType::Receive {
+                from: expected,
+                message: name,
+                continuation,
+            } if *expected == from && name == message => (**continuation).clone(),
+            _ => return Err(self.mismatch(format!("receive {} from {:?}", message, from))),
+        };
+        self.advance(next);
+        Ok(())
+    }
+
+    pub fn select(&mut self, to: R, label: &str) -> Result<()> {
+        if self.system_label(&to, label) {
+            return Ok(());
+        }
+        let next = match &self.current {
+            SessionType::Select {
+                to: expected,
+                branches,
+            } if *expected == to => find_branch(branches, label),
+            _ => None,
+        };
+        match next {
+            Some(next) => {
+                self.advance(next);
+                Ok(())
+            }
+            None => Err(self.mismatch(format!("select {} to {:?}", label, to))),
+        }
+    }
+
+    /// Check that a choice from `from` is expected, before reading its label
+    pub fn expect_branch(&self, from: R) -> Result<()> {
+        match &self.current {
+            SessionType::Branch { from: expected, .. } if *expected == from => Ok(()),
+            _ => Err(self.mismatch(format!("branch from {:?}", from))),
+        }
+    }
+
+    pub fn branch(&mut self, from: R, label: &str) -> Result<()> {
+        if self.system_label(&from, label) {
+            return Ok(());
+        }
+        let next = match &self.current {
+            SessionType::Branch {
+                from: expected,
+                branches,
+            } if *expected == from => find_branch(branches, label),
+            _ => None,
+        };
+        match next {
+            Some(next) => {
+                self.advance(next);
+                Ok(())
+            }
+            None => Err(self.mismatch(format!("branch {} from {:?}", label, from))),
+        }
+    }
+
+    /// Record `label` if it is a known system label, returning whether it was
+    fn system_label(&mut self, peer: &R, label: &str) -> bool {
+        let Some(label) = Label::system(label) else {
+            return false;
+        };
+        self.system.push((peer.clone(), label));
+        self.steps += 1;
+        if label.ends_session() {
+            self.current = SessionType::End;
+        }
+        true
+    }
+
+    fn advance(&mut self, next: SessionType<R>) {
+        self.current = next;
+        self.steps += 1;
+        self.unfold();
+    }
+
+    fn unfold(&mut self) {
+        loop {
+            match &self.current {
+                SessionType::Rec { label, body } => {
+                    let body = (**body).clone();
+                    self.recursion.insert(label.clone(), body.clone());
+                    self.current = body;
+                }
+                SessionType::Var(label) => match self.recursion.get(label) {
+                    Some(body) => self.current = body.clone(),
+                    None => return,
+                },
+                _ => return,
+            }
+        }
+    }
+
+    fn mismatch(&self, found: String) -> ChoreographyError {
+        ChoreographyError::SessionMismatch {
+            expected: self.current.to_string(),
+            found,
+        }
+    }
+}
+
+/// Name a message type the way session types do: the last path segment of
+/// its type name, without generic arguments
+pub fn message_name<M: ?Sized>() -> &'static str {
+    short_type_name(std::any::type_name::<M>())
+}
+
+pub(crate) fn short_type_name(name: &str) -> &str {
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn named<R>(branches: Vec<(&str, SessionType<R>)>) -> Vec<(String, SessionType<R>)> {
+    branches
+        .into_iter()
+        .map(|(label, session)| (label.to_string(), session))
+        .collect()
+}
+
+fn then_branches<R: Clone>(
+    branches: Vec<(String, SessionType<R>)>,
+    next: &SessionType<R>,
+) -> Vec<(String, SessionType<R>)> {
+    branches
+        .into_iter()
+        .map(|(label, session)| (label, session.then(next.clone())))
+        .collect()
+}
+
+fn find_branch<R: Clone>(
+    branches: &[(String, SessionType<R>)],
+    label: &str,
+) -> Option<SessionType<R>> {
+    branches
+        .iter()
+        .find(|(name, _)| name == label)
+        .map(|(_, session)| session.clone())
+}
+
+fn convert<R: Clone>(
+    local: &LocalType,
+    role: &dyn Fn(&crate::ast::Role) -> Option<R>,
+    loops: &mut usize,
+) -> Option<SessionType<R>> {
+    let convert_branches = |branches: &[(proc_macro2::Ident, LocalType)], loops: &mut usize| {
+        branches
+            .iter()
+            .map(|(label, body)| Some((label.to_string(), convert(body, role, loops)?)))
+            .collect::<Option<Vec<_>>>()
+    };
+
+    Some(match local {
+        LocalType::Send {
+            to,
+            message,
+            continuation,
+        } => SessionType::Send {
+            to: role(to)?,
+            message: message.name.to_string(),
+            continuation: Box::new(convert(continuation, role, loops)?),
+        },
+        LocalType::Receive {
+            from,
+            message,
+            continuation,
+        } => SessionType::Receive {
+            from: role(from)?,
+            message: message.name.to_string(),
+            continuation: Box::new(convert(continuation, role, loops)?),
+        },
+        LocalType::Select { to, branches } => SessionType::Select {
+            to: role(to)?,
+            branches: convert_branches(branches, loops)?,
+        },
+        LocalType::Branch { from, branches } => SessionType::Branch {
+            from: role(from)?,
+            branches: convert_branches(branches, loops)?,
+        },
+        LocalType::LocalChoice { .. } | LocalType::Race { .. } => return None,
+        LocalType::Loop { condition, body } => {
+            let body = convert(body, role, loops)?;
+            match condition {
+                Some(Condition::Count(n)) => {
+                    (0..*n).fold(SessionType::End, |rest, _| body.clone().then(rest))
+                }
+                _ => {
+                    let label = format!("loop{}", loops);
+                    *loops += 1;
+                    SessionType::rec(label.clone(), body.then(SessionType::Var(label)))
+                }
+            }
+        }
+        LocalType::Rec { label, body } => {
+            SessionType::rec(label.to_string(), convert(body, role, loops)?)
+        }
+        LocalType::Var(label) => SessionType::Var(label.to_string()),
+        LocalType::Finally { body, cleanup } => {
+            convert(body, role, loops)?.then(convert(cleanup, role, loops)?)
+        }
+        // The compensation only runs once the session was aborted, and the
+        // else branch of a timeout once the timer has fired
+        LocalType::TryCatch { body, .. } | LocalType::Timeout { body, .. } => {
+            convert(body, role, loops)?
+        }
+        LocalType::End => SessionType::End,
+    })
+}
@@ -0,0 +1,423 @@
+// Mutual TLS effect handler
+//
+// Carries the frames of `WebSocketHandler` over TLS on any byte stream,
+// such as a TCP connection or a Unix socket. Both sides present a
+// certificate issued by a shared CA, and each checks that the other's
+// certificate names the role it expects: Bob's common name must be `Bob`.
+// A peer holding a valid certificate for a different role is refused with
+// `AuthenticationFailed`, so one compromised role cannot pose as another.
+//
+// TLS is rustls, over the stream through tokio-rustls. A background task
+// owns the encrypted stream and exchanges length-prefixed frames with the
+// endpoint over channels, as the WebSocket tasks do.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::unbounded;
+use futures::StreamExt;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::verify_server_cert_signed_by_trust_anchor;
+use rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ParsedCertificate, WebPkiClientVerifier};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::websocket::{Link, WebSocketEndpoint, WebSocketHandler};
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId, WireIds};
+
+/// Largest frame accepted from a peer
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// The certificate a role presents and the CA its peers' must chain to
+#[derive(Clone)]
+pub struct TlsConfig {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+    name: String,
+}
+
+impl TlsConfig {
+    /// Load a role's certificate chain, its private key, and the CA
+    /// certificates peers are checked against, all PEM-encoded
+    pub fn from_pem(certificate: &[u8], key: &[u8], ca: &[u8]) -> Result<Self> {
+        let invalid = |what: &str, e: &dyn std::fmt::Display| {
+            ChoreographyError::Transport(format!("invalid TLS {}: {}", what, e))
+        };
+        let chain = CertificateDer::pem_slice_iter(certificate)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| invalid("certificate", &e))?;
+        let leaf = chain
+            .first()
+            .ok_or_else(|| invalid("certificate", &"no certificate"))?;
+        let name = common_name(leaf).unwrap_or_default();
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| invalid("private key", &e))?;
+        let mut roots = RootCertStore::empty();
+        for root in CertificateDer::pem_slice_iter(ca) {
+            let root = root.map_err(|e| invalid("CA", &e))?;
+            roots.add(root).map_err(|e| invalid("CA", &e))?;
+        }
+        let roots = Arc::new(roots);
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone())
+            .build()
+            .map_err(|e| invalid("CA", &e))?;
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid("context", &e))?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain.clone(), key.clone_key())
+            .map_err(|e| invalid("private key", &e))?;
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid("context", &e))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(RoleVerifier {
+                roots,
+                algorithms: provider.signature_verification_algorithms,
+            }))
+            .with_client_auth_cert(chain, key)
+            .map_err(|e| invalid("private key", &e))?;
+
+        Ok(Self {
+            client: Arc::new(client),
+            server: Arc::new(server),
+            name,
+        })
+    }
+}
+
+/// Checks a server's certificate chains to the CA, leaving its name to the
+/// role check after the handshake
+///
+/// Role certificates name their role in the common name rather than a DNS
+/// name, so the usual host name check does not apply.
+#[derive(Debug)]
+struct RoleVerifier {
+    roots: Arc<RootCertStore>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for RoleVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let certificate = ParsedCertificate::try_from(end_entity)?;
+        verify_server_cert_signed_by_trust_anchor(
+            &certificate,
+            &self.roots,
+            intermediates,
+            now,
+            self.algorithms.all,
+        )?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// TLS connections of one role to its peers
+pub struct TlsEndpoint<R: RoleId> {
+    config: TlsConfig,
+    peers: WebSocketEndpoint<R>,
+}
+
+impl<R: RoleId> TlsEndpoint<R> {
+    /// Fails with `AuthenticationFailed` unless the certificate in `config`
+    /// names `role`
+    pub fn new(role: R, config: TlsConfig) -> Result<Self> {
+        let expected = format!("{:?}", role);
+        if config.name != expected {
+            return Err(ChoreographyError::AuthenticationFailed {
+                role: expected,
+                reason: format!("own certificate is issued to {:?}", config.name),
+            });
+        }
+        Ok(Self {
+            config,
+            peers: WebSocketEndpoint::new(role),
+        })
+    }
+
+    pub fn local_role(&self) -> R {
+        self.peers.local_role()
+    }
+
+    /// Start TLS as the client on `stream`, an open connection to `peer`
+    pub async fn connect<S>(&mut self, peer: R, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.establish(peer, stream, false).await
+    }
+
+    /// Start TLS as the server on `stream`, a connection `peer` opened
+    pub async fn accept<S>(&mut self, peer: R, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.establish(peer, stream, true).await
+    }
+
+    pub fn is_connected(&self, peer: &R) -> bool {
+        self.peers.is_connected(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &R> {
+        self.peers.peers()
+    }
+
+    /// Close the connection to `peer`, returning whether there was one
+    pub fn disconnect(&mut self, peer: &R) -> bool {
+        self.peers.disconnect(peer)
+    }
+
+    async fn establish<S>(&mut self, peer: R, stream: S, server: bool) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let expected = format!("{:?}", peer);
+        let failed = |e: std::io::Error| {
+            let refused = e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<rustls::Error>())
+                .filter(|e| {
+                    matches!(
+                        e,
+                        rustls::Error::InvalidCertificate(_)
+                            | rustls::Error::NoCertificatesPresented
+                    )
+                });
+            match refused {
+                Some(e) => ChoreographyError::AuthenticationFailed {
+                    role: expected.clone(),
+                    reason: e.to_string(),
+                },
+                None => ChoreographyError::Transport(format!(
+                    "TLS handshake with {} failed: {}",
+                    expected, e
+                )),
+            }
+        };
+        let (tls, presented): (Box<dyn Stream>, _) = if server {
+            let tls = TlsAcceptor::from(self.config.server.clone())
+                .accept(stream)
+                .await
+                .map_err(failed)?;
+            let presented = tls.get_ref().1.peer_certificates().map(<[_]>::to_vec);
+            (Box::new(tls), presented)
+        } else {
+            // The name only fills in SNI; the peer is checked by its
+            // certificate's common name below
+            let name = ServerName::try_from(expected.clone())
+                .unwrap_or_else(|_| ServerName::try_from("localhost").expect("valid name"));
+            let tls = TlsConnector::from(self.config.client.clone())
+                .connect(name, stream)
+                .await
+                .map_err(failed)?;
+            let presented = tls.get_ref().1.peer_certificates().map(<[_]>::to_vec);
+            (Box::new(tls), presented)
+        };
+
+        let presented = presented
+            .as_deref()
+            .and_then(<[_]>::first)
+            .and_then(common_name);
+        if presented.as_deref() != Some(expected.as_str()) {
+            tracing::warn!(
+                ?peer,
+                ?presented,
+                "TLS peer presented another role's certificate"
+            );
+            return Err(ChoreographyError::AuthenticationFailed {
+                reason: match presented {
+                    Some(name) => format!("certificate is issued to {:?}", name),
+                    None => "certificate has no common name".to_string(),
+                },
+                role: expected,
+            });
+        }
+        tracing::debug!(?peer, server, "TLS connection established");
+
+        let (outgoing, mut to_stream) = unbounded::<Vec<u8>>();
+        let (from_stream, incoming) = unbounded();
+        let (mut reader, mut writer) = tokio::io::split(tls);
+        crate::runtime::spawn(async move {
+            let mut plain = Vec::new();
+            let mut buf = vec![0u8; 16 * 1024];
+            loop {
+                tokio::select! {
+                    frame = to_stream.next() => {
+                        let Some(frame) = frame else {
+                            let _ = writer.shutdown().await;
+                            break;
+                        };
+                        let mut bytes = (frame.len() as u32).to_be_bytes().to_vec();
+                        bytes.extend(frame);
+                        if writer.write_all(&bytes).await.is_err() || writer.flush().await.is_err() {
+                            break;
+                        }
+                    }
+                    read = reader.read(&mut buf) => {
+                        let n = read.unwrap_or(0);
+                        plain.extend_from_slice(&buf[..n]);
+                        while let Some(frame) = take_frame(&mut plain) {
+                            if from_stream.unbounded_send(frame).is_err() {
+                                return;
+                            }
+                        }
+                        if n == 0 || plain.len() >= 4 + MAX_FRAME {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        self.peers.attach_link(peer, Link { outgoing, incoming });
+        Ok(())
+    }
+}
+
+/// A TLS session of either side
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Split the first complete length-prefixed frame off `plain`
+fn take_frame(plain: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u32::from_be_bytes(plain.get(..4)?.try_into().ok()?) as usize;
+    if plain.len() < 4 + len {
+        return None;
+    }
+    let frame = plain[4..4 + len].to_vec();
+    plain.drain(..4 + len);
+    Some(frame)
+}
+
+fn common_name(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let name = certificate.subject().iter_common_name().next()?;
+    name.as_str().ok().map(str::to_string)
+}
+
+/// Handler for [`TlsEndpoint`]s, with the semantics of
+/// [`WebSocketHandler`]
+pub struct TlsHandler<R> {
+    inner: WebSocketHandler<R>,
+}
+
+impl<R> TlsHandler<R> {
+    pub fn new() -> Self {
+        Self {
+            inner: WebSocketHandler::new(),
+        }
+    }
+
+    /// Refuse to send or receive messages that encode to more than `limit`
+    /// bytes, with [`ChoreographyError::MessageTooLarge`]
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.inner = self.inner.with_max_message_size(limit);
+        self
+    }
+
+    /// Send labels as their ids in `ids` rather than their names
+    pub fn with_label_ids(mut self, ids: WireIds) -> Self {
+        self.inner = self.inner.with_label_ids(ids);
+        self
+    }
+}
+
+impl<R> Default for TlsHandler<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<R: RoleId + 'static> ChoreoHandler for TlsHandler<R> {
+    type Role = R;
+    type Endpoint = TlsEndpoint<R>;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        self.inner.send(&mut ep.peers, to, msg).await
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        self.inner.recv(&mut ep.peers, from).await
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.inner.choose(&mut ep.peers, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.inner.offer(&mut ep.peers, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(&mut ep.peers, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(&mut ep.peers).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(&mut ep.peers).await
+    }
+}
@@ -0,0 +1,135 @@
+// Validation effect handler
+//
+// Runs a role's side of a protocol without a transport. Every send and
+// choice is checked and logged, but nothing is delivered, so receives and
+// offers fail instead of pretending to succeed. Use RumpsteakHandler when
+// messages have to reach the other roles.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+
+/// A send or choice accepted by a [`ValidationHandler`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidatedEffect<R: RoleId> {
+    /// A message to `to` that serialized to `size` bytes
+    Send { to: R, size: usize },
+    /// A branch selection made at `at`
+    Choose { at: R, label: Label },
+}
+
+/// Handler that validates outgoing effects without delivering them
+///
+/// Sends are serialized with the same encoding as [`RumpsteakHandler`], so
+/// a message that could not go over the wire fails here too, and sending to
+/// the local role is rejected. Accepted effects are kept in a log; nothing
+/// is dropped silently. `recv` and `offer` always return an error, since no
+/// peer exists to answer them.
+///
+/// [`RumpsteakHandler`]: super::RumpsteakHandler
+#[derive(Debug, Clone)]
+pub struct ValidationHandler<R: RoleId> {
+    role: R,
+    effects: Vec<ValidatedEffect<R>>,
+}
+
+impl<R: RoleId> ValidationHandler<R> {
+    pub fn new(role: R) -> Self {
+        Self {
+            role,
+            effects: Vec::new(),
+        }
+    }
+
+    pub fn role(&self) -> R {
+        self.role
+    }
+
+    /// Effects accepted so far, in order
+    pub fn effects(&self) -> &[ValidatedEffect<R>] {
+        &self.effects
+    }
+
+    pub fn clear(&mut self) {
+        self.effects.clear();
+    }
+
+    fn check_recipient(&self, to: R) -> Result<()> {
+        if to == self.role {
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "{:?} cannot send to itself",
+                to
+            )));
+        }
+        Ok(())
+    }
+
+    fn undeliverable(&self, operation: &str, from: R) -> ChoreographyError {
+        ChoreographyError::Transport(format!(
+            "ValidationHandler for {:?} cannot {} from {:?}: it has no transport, \
+             use RumpsteakHandler to exchange messages",
+            self.role, operation, from
+        ))
+    }
+}
+
+#[async_trait]
+impl<R: RoleId + 'static> ChoreoHandler for ValidationHandler<R> {
+    type Role = R;
+    type Endpoint = ();
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        self.check_recipient(to)?;
+        let size = bincode::serialized_size(msg)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        tracing::debug!(from = ?self.role, ?to, size, "Validated send (not delivered)");
+        self.effects.push(ValidatedEffect::Send {
+            to,
+            size: size as usize,
+        });
+        Ok(())
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        Err(self.undeliverable("receive", from))
+    }
+
+    async fn choose(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        at: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        tracing::debug!(role = ?self.role, ?at, ?label, "Validated choice (not delivered)");
+        self.effects.push(ValidatedEffect::Choose { at, label });
+        Ok(())
+    }
+
+    async fn offer(&mut self, _ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        Err(self.undeliverable("offer", from))
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        _at: Self::Role,
+        _dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        body.await
+    }
+}
@@ -0,0 +1,432 @@
+// WebSocket effect handler
+//
+// Lets a role compiled to WASM run in the browser and talk to peers on a
+// server, and lets the server side speak the same wire format. Each peer
+// gets its own WebSocket. Every frame is a bincode-encoded `WireFrame`, so
+// a label arriving where a message was expected is reported as such rather
+// than as a garbled payload.
+//
+// Browser sockets cannot leave the thread that created them, while handlers
+// and endpoints must be `Send`. The socket is therefore owned by a
+// background task that forwards frames over futures channels, and the
+// endpoint only holds the channels. Natively the task runs on tokio; on wasm
+// it runs on the browser's event loop.
+
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use super::{check_message_size, phases};
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId, WireIds};
+
+/// What travels in one binary WebSocket frame
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum WireFrame {
+    /// A bincode-encoded message
+    Message(Vec<u8>),
+    /// A branch label picked by the sender
+    Label(String),
+}
+
+/// What the background task made of a frame read from the socket
+enum Inbound {
+    Data(Vec<u8>),
+    /// Text, ping, and pong frames carry nothing for the protocol
+    Skip,
+    Closed,
+}
+
+/// Channels to the task that owns one peer's socket
+pub(crate) struct Link {
+    pub(crate) outgoing: UnboundedSender<Vec<u8>>,
+    pub(crate) incoming: UnboundedReceiver<Vec<u8>>,
+}
+
+impl Link {
+    /// Two links joined back to back, for peers in the same process
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn pair() -> (Link, Link) {
+        let (a_out, b_in) = unbounded();
+        let (b_out, a_in) = unbounded();
+        (
+            Link {
+                outgoing: a_out,
+                incoming: a_in,
+            },
+            Link {
+                outgoing: b_out,
+                incoming: b_in,
+            },
+        )
+    }
+
+    /// A link over an established server-side WebSocket
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn native<S>(socket: tokio_tungstenite::WebSocketStream<S>) -> Link
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (link, task) = link(socket, native_frame, native_payload);
+        spawn(task);
+        link
+    }
+}
+
+/// Connections of one role to its peers
+pub struct WebSocketEndpoint<R: RoleId> {
+    role: R,
+    peers: HashMap<R, Link>,
+}
+
+impl<R: RoleId> WebSocketEndpoint<R> {
+    pub fn new(role: R) -> Self {
+        Self {
+            role,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn local_role(&self) -> R {
+        self.role
+    }
+
+    /// Open a WebSocket to `url` and use it for everything exchanged with
+    /// `peer`
+    ///
+    /// Works natively and in the browser. Replaces any existing connection
+    /// to `peer`.
+    pub async fn connect(&mut self, peer: R, url: &str) -> Result<()> {
+        let socket = tokio_tungstenite_wasm::connect(url).await.map_err(|e| {
+            ChoreographyError::Transport(format!("WebSocket connect to {} failed: {}", url, e))
+        })?;
+        tracing::debug!(?peer, url, "WebSocket connected");
+        let (link, task) = link(socket, web_frame, web_payload);
+        spawn(task);
+        self.peers.insert(peer, link);
+        Ok(())
+    }
+
+    /// Complete the WebSocket handshake on an incoming TCP connection from
+    /// `peer`
+    ///
+    /// For server-side roles that browser peers connect to.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn accept(&mut self, peer: R, stream: tokio::net::TcpStream) -> Result<()> {
+        let socket = tokio_tungstenite::accept_async(stream).await.map_err(|e| {
+            ChoreographyError::Transport(format!("WebSocket handshake failed: {}", e))
+        })?;
+        self.attach(peer, socket);
+        Ok(())
+    }
+
+    /// Use an already established server-side WebSocket for `peer`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn attach<S>(&mut self, peer: R, socket: tokio_tungstenite::WebSocketStream<S>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        tracing::debug!(?peer, "WebSocket attached");
+        self.peers.insert(peer, Link::native(socket));
+    }
+
+    /// Use `link` for everything exchanged with `peer`, for transports
+    /// that carry the same frames over another kind of connection
+    #[cfg(all(not(target_arch = "wasm32"), feature = "tls"))]
+    pub(crate) fn attach_link(&mut self, peer: R, link: Link) {
+        self.peers.insert(peer, link);
+    }
+
+    pub fn is_connected(&self, peer: &R) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    /// Roles this endpoint has a connection to
+    pub fn peers(&self) -> impl Iterator<Item = &R> {
+        self.peers.keys()
+    }
+
+    /// Close the connection to `peer`, returning whether there was one
+    pub fn disconnect(&mut self, peer: &R) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+
+    fn send_frame(&mut self, to: R, frame: &WireFrame) -> Result<()> {
+        let bytes = bincode::serialize(frame)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let link = self.link(to)?;
+        link.outgoing
+            .unbounded_send(bytes)
+            .map_err(|_| ChoreographyError::Transport(format!("WebSocket to {:?} is closed", to)))
+    }
+
+    async fn recv_frame(&mut self, from: R) -> Result<WireFrame> {
+        let link = self.link(from)?;
+        let bytes = link.incoming.next().await.ok_or_else(|| {
+            ChoreographyError::Transport(format!("WebSocket from {:?} was closed", from))
+        })?;
+        bincode::deserialize(&bytes).map_err(|e| {
+            ChoreographyError::Transport(format!("Malformed frame from {:?}: {}", from, e))
+        })
+    }
+
+    fn link(&mut self, peer: R) -> Result<&mut Link> {
+        self.peers.get_mut(&peer).ok_or_else(|| {
+            ChoreographyError::Transport(format!("No WebSocket connected for role: {:?}", peer))
+        })
+    }
+}
+
+/// Handler that exchanges messages and labels over WebSockets
+///
+/// Connections live on the [`WebSocketEndpoint`]. Messages are encoded with
+/// bincode, so peers written in other languages must use the same encoding.
+/// When a role chooses a branch itself, the label is sent to every
+/// connected peer; choosing on behalf of another role sends it only to
+/// that role. Heartbeat and resume labels that arrive where a message is
+/// expected are skipped, and abort and cancel labels end the session.
+pub struct WebSocketHandler<R> {
+    max_message_size: Option<usize>,
+    label_ids: WireIds,
+    _phantom: PhantomData<R>,
+}
+
+impl<R> WebSocketHandler<R> {
+    pub fn new() -> Self {
+        Self {
+            max_message_size: None,
+            label_ids: WireIds::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Refuse to send or receive messages that encode to more than `limit`
+    /// bytes, with [`ChoreographyError::MessageTooLarge`]
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// Send labels as their ids in `ids` rather than their names
+    pub fn with_label_ids(mut self, ids: WireIds) -> Self {
+        self.label_ids = ids;
+        self
+    }
+}
+
+impl<R> Default for WebSocketHandler<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<R: RoleId + 'static> ChoreoHandler for WebSocketHandler<R> {
+    type Role = R;
+    type Endpoint = WebSocketEndpoint<R>;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload = phases::serializing(|| bincode::serialize(msg))
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        tracing::debug!(?to, size = payload.len(), "WebSocket send");
+        check_message_size(to, payload.len(), self.max_message_size)?;
+        ep.send_frame(to, &WireFrame::Message(payload))
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        // System labels may arrive where a message is expected
+        loop {
+            match ep.recv_frame(from).await? {
+                WireFrame::Message(payload) => {
+                    tracing::debug!(?from, size = payload.len(), "WebSocket recv");
+                    check_message_size(from, payload.len(), self.max_message_size)?;
+                    return phases::deserializing(|| bincode::deserialize(&payload))
+                        .map_err(|e| ChoreographyError::Serialization(e.to_string()));
+                }
+                WireFrame::Label(label) => match Label::system(&label) {
+                    Some(label) if label.is_keep_alive() => continue,
+                    Some(label) => {
+                        return Err(ChoreographyError::Aborted {
+                            role: format!("{:?}", from),
+                            label: label.0,
+                        })
+                    }
+                    None => {
+                        return Err(ChoreographyError::ProtocolViolation(format!(
+                            "expected a message from {:?}, got branch label {}",
+                            from, label
+                        )))
+                    }
+                },
+            }
+        }
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        let frame = WireFrame::Label(self.label_ids.encode(label));
+        let recipients: Vec<R> = if who == ep.role {
+            ep.peers.keys().copied().collect()
+        } else {
+            vec![who]
+        };
+        tracing::debug!(?recipients, ?label, "WebSocket choose");
+        for peer in recipients {
+            ep.send_frame(peer, &frame)?;
+        }
+        Ok(())
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        match ep.recv_frame(from).await? {
+            WireFrame::Label(label) => {
+                tracing::debug!(?from, %label, "WebSocket offer");
+                Ok(self.label_ids.decode(label))
+            }
+            WireFrame::Message(_) => Err(ChoreographyError::ProtocolViolation(format!(
+                "expected a branch label from {:?}, got a message",
+                from
+            ))),
+        }
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        _ep: &mut Self::Endpoint,
+        _at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            match tokio::time::timeout(dur, body).await {
+                Ok(result) => result,
+                Err(_) => Err(ChoreographyError::Timeout(dur)),
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            use futures::future::{select, Either};
+            use futures::pin_mut;
+            use wasm_timer::Delay;
+
+            let timeout = Delay::new(dur);
+            pin_mut!(body);
+            pin_mut!(timeout);
+
+            match select(body, timeout).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(ChoreographyError::Timeout(dur)),
+            }
+        }
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        // Dropping the links ends the tasks, which close the sockets
+        tracing::debug!(peers = ep.peers.len(), "WebSocket cancel");
+        ep.peers.clear();
+        Ok(())
+    }
+}
+
+/// Channels for a socket plus the task that moves frames between them
+///
+/// The task ends once the endpoint has dropped its side and the peer has
+/// closed the socket.
+fn link<S, M, E>(
+    socket: S,
+    frame: fn(Vec<u8>) -> M,
+    payload: fn(M) -> Inbound,
+) -> (Link, impl std::future::Future<Output = ()>)
+where
+    S: Stream<Item = std::result::Result<M, E>> + Sink<M>,
+{
+    let (outgoing, mut to_socket) = unbounded::<Vec<u8>>();
+    let (from_socket, incoming) = unbounded();
+    let task = async move {
+        let (mut write, mut read) = socket.split();
+        let writer = async move {
+            while let Some(bytes) = to_socket.next().await {
+                if write.send(frame(bytes)).await.is_err() {
+                    break;
+                }
+            }
+            let _ = write.close().await;
+        };
+        let reader = async move {
+            while let Some(Ok(message)) = read.next().await {
+                match payload(message) {
+                    Inbound::Data(bytes) => {
+                        if from_socket.unbounded_send(bytes).is_err() {
+                            break;
+                        }
+                    }
+                    Inbound::Skip => {}
+                    Inbound::Closed => break,
+                }
+            }
+        };
+        futures::future::join(writer, reader).await;
+    };
+    (Link { outgoing, incoming }, task)
+}
+
+fn web_frame(bytes: Vec<u8>) -> tokio_tungstenite_wasm::Message {
+    tokio_tungstenite_wasm::Message::binary(bytes)
+}
+
+fn web_payload(message: tokio_tungstenite_wasm::Message) -> Inbound {
+    use tokio_tungstenite_wasm::Message;
+    match message {
+        Message::Binary(bytes) => Inbound::Data(bytes.to_vec()),
+        Message::Close(_) => Inbound::Closed,
+        Message::Text(_) => Inbound::Skip,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn native_frame(bytes: Vec<u8>) -> tokio_tungstenite::tungstenite::Message {
+    tokio_tungstenite::tungstenite::Message::binary(bytes)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn native_payload(message: tokio_tungstenite::tungstenite::Message) -> Inbound {
+    use tokio_tungstenite::tungstenite::Message;
+    match message {
+        Message::Binary(bytes) => Inbound::Data(bytes.to_vec()),
+        Message::Close(_) => Inbound::Closed,
+        Message::Text(_) | Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => Inbound::Skip,
+    }
+}
+
+/// Run a socket task: on tokio natively, on the browser's event loop on
+/// wasm, where the task is not `Send`
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn(task: impl std::future::Future<Output = ()> + Send + 'static) {
+    crate::runtime::spawn(task);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn spawn(task: impl std::future::Future<Output = ()> + 'static) {
+    crate::runtime::spawn_local(task);
+}
@@ -0,0 +1,452 @@
+// Role identities and certificates
+//
+// A role authenticates with an Ed25519 keypair and a certificate in which an
+// authority binds the role name to the public key. Certificates carry a
+// serial number that increases with every rotation, so a verifier can tell a
+// current key from a retired one. The keystore keeps keys and certificates as
+// files, one pair per role, and moves retired pairs aside on rotation.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Errors from issuing, verifying, or storing identities
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+    #[error("keystore I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed {what}: {reason}")]
+    Malformed { what: &'static str, reason: String },
+
+    #[error("invalid signature by {0}")]
+    BadSignature(String),
+
+    #[error("certificate for {found} presented as {expected}")]
+    RoleMismatch { expected: String, found: String },
+
+    #[error("no identity stored for {0}")]
+    NotFound(String),
+
+    #[error("choreography fingerprint {found} does not match {expected}")]
+    FingerprintMismatch { expected: String, found: String },
+}
+
+pub type IdentityResult<T> = std::result::Result<T, IdentityError>;
+
+/// An Ed25519 public key
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        PublicKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Check `signature` over `message`
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.0) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return false;
+        };
+        key.verify(message, &signature).is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = hex::decode(&encoded).map_err(serde::de::Error::custom)?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("public key must be 32 bytes"))?;
+        Ok(PublicKey(bytes))
+    }
+}
+
+/// An authority's statement that `public_key` belongs to `role`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleCertificate {
+    pub role: String,
+    pub public_key: PublicKey,
+    /// Increases by one with every rotation of the role's key
+    pub serial: u64,
+    /// Seconds since the Unix epoch
+    pub issued_at: u64,
+    pub issuer: String,
+    #[serde(with = "hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl RoleCertificate {
+    /// Check the issuer's signature and that the certificate names `role`
+    pub fn verify(&self, role: &str, authority: &PublicKey) -> IdentityResult<()> {
+        if self.role != role {
+            return Err(IdentityError::RoleMismatch {
+                expected: role.to_string(),
+                found: self.role.clone(),
+            });
+        }
+        if !authority.verify(&self.signed_bytes(), &self.signature) {
+            return Err(IdentityError::BadSignature(self.issuer.clone()));
+        }
+        Ok(())
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for field in [self.role.as_bytes(), self.issuer.as_bytes()] {
+            bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes.extend_from_slice(self.public_key.as_bytes());
+        bytes.extend_from_slice(&self.serial.to_le_bytes());
+        bytes.extend_from_slice(&self.issued_at.to_le_bytes());
+        bytes
+    }
+}
+
+/// Issues role certificates
+pub struct IdentityAuthority {
+    name: String,
+    key: SigningKey,
+}
+
+impl IdentityAuthority {
+    /// Create an authority with a fresh key
+    pub fn generate(name: impl Into<String>) -> IdentityResult<Self> {
+        Ok(Self {
+            name: name.into(),
+            key: generate_key()?,
+        })
+    }
+
+    pub fn from_secret(name: impl Into<String>, secret: [u8; 32]) -> Self {
+        Self {
+            name: name.into(),
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key().to_bytes())
+    }
+
+    /// Certify that `public_key` belongs to `role`
+    pub fn issue(&self, role: &str, public_key: PublicKey, serial: u64) -> RoleCertificate {
+        let mut certificate = RoleCertificate {
+            role: role.to_string(),
+            public_key,
+            serial,
+            issued_at: now(),
+            issuer: self.name.clone(),
+            signature: Vec::new(),
+        };
+        certificate.signature = self
+            .key
+            .sign(&certificate.signed_bytes())
+            .to_bytes()
+            .to_vec();
+        certificate
+    }
+
+    /// Generate a key for `role` and certify it
+    pub fn enroll(&self, role: &str, serial: u64) -> IdentityResult<RoleIdentity> {
+        let key = generate_key()?;
+        let certificate = self.issue(role, PublicKey(key.verifying_key().to_bytes()), serial);
+        Ok(RoleIdentity { key, certificate })
+    }
+}
+
+impl fmt::Debug for IdentityAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdentityAuthority")
+            .field("name", &self.name)
+            .field("public_key", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A role's signing key together with its certificate
+pub struct RoleIdentity {
+    key: SigningKey,
+    certificate: RoleCertificate,
+}
+
+impl RoleIdentity {
+    /// Pair a secret key with its certificate
+    ///
+    /// Fails if the certificate is for a different key.
+    pub fn new(secret: [u8; 32], certificate: RoleCertificate) -> IdentityResult<Self> {
+        let key = SigningKey::from_bytes(&secret);
+        if key.verifying_key().to_bytes() != *certificate.public_key.as_bytes() {
+            return Err(IdentityError::Malformed {
+                what: "identity",
+                reason: format!(
+                    "secret key does not match the certificate for {}",
+                    certificate.role
+                ),
+            });
+        }
+        Ok(Self { key, certificate })
+    }
+
+    pub fn role(&self) -> &str {
+        &self.certificate.role
+    }
+
+    pub fn certificate(&self) -> &RoleCertificate {
+        &self.certificate
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.certificate.public_key
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.key.sign(message).to_bytes().to_vec()
+    }
+
+    /// Check a signature made by the holder of `certificate`
+    ///
+    /// The certificate itself is verified against `authority` first, so a
+    /// valid signature under an uncertified key is rejected.
+    pub fn verify(
+        certificate: &RoleCertificate,
+        role: &str,
+        authority: &PublicKey,
+        message: &[u8],
+        signature: &[u8],
+    ) -> IdentityResult<()> {
+        certificate.verify(role, authority)?;
+        if !certificate.public_key.verify(message, signature) {
+            return Err(IdentityError::BadSignature(certificate.role.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RoleIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoleIdentity")
+            .field("certificate", &self.certificate)
+            .finish_non_exhaustive()
+    }
+}
+
+/// File-based storage for role identities
+///
+/// Each role has `<role>.key`, holding the hex-encoded secret key, and
+/// `<role>.cert`, holding the certificate as JSON. Rotation moves the
+/// current pair to `retired/<role>.<serial>.*` so signatures made before
+/// the rotation can still be checked against [`KeyStore::certificates`].
+#[derive(Debug, Clone)]
+pub struct KeyStore {
+    dir: PathBuf,
+}
+
+impl KeyStore {
+    /// Open a keystore, creating the directory if needed
+    pub fn open(dir: impl Into<PathBuf>) -> IdentityResult<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("retired"))?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn store(&self, identity: &RoleIdentity) -> IdentityResult<()> {
+        let role = identity.role();
+        write_secret(&self.key_path(role), &identity.key.to_bytes())?;
+        fs::write(self.cert_path(role), to_json(&identity.certificate)?)?;
+        Ok(())
+    }
+
+    pub fn load(&self, role: &str) -> IdentityResult<RoleIdentity> {
+        let key_path = self.key_path(role);
+        if !key_path.exists() {
+            return Err(IdentityError::NotFound(role.to_string()));
+        }
+        let secret = read_secret(&key_path)?;
+        let certificate = read_certificate(&self.cert_path(role))?;
+        if certificate.role != role {
+            return Err(IdentityError::RoleMismatch {
+                expected: role.to_string(),
+                found: certificate.role,
+            });
+        }
+        RoleIdentity::new(secret, certificate)
+    }
+
+    /// Replace a role's key with a fresh one certified by `authority`
+    ///
+    /// The new certificate's serial is one more than the current one. A role
+    /// with no stored identity is enrolled with serial 1.
+    pub fn rotate(
+        &self,
+        role: &str,
+        authority: &IdentityAuthority,
+    ) -> IdentityResult<RoleIdentity> {
+        let serial = match self.load(role) {
+            Ok(current) => {
+                let serial = current.certificate.serial;
+                let retired = self.dir.join("retired");
+                fs::rename(
+                    self.key_path(role),
+                    retired.join(format!("{}.{}.key", role, serial)),
+                )?;
+                fs::rename(
+                    self.cert_path(role),
+                    retired.join(format!("{}.{}.cert", role, serial)),
+                )?;
+                serial + 1
+            }
+            Err(IdentityError::NotFound(_)) => 1,
+            Err(e) => return Err(e),
+        };
+        let identity = authority.enroll(role, serial)?;
+        self.store(&identity)?;
+        tracing::info!(role, serial, "rotated role identity");
+        Ok(identity)
+    }
+
+    /// Current and retired certificates for a role, newest first
+    pub fn certificates(&self, role: &str) -> IdentityResult<Vec<RoleCertificate>> {
+        let mut certificates = Vec::new();
+        let current = self.cert_path(role);
+        if current.exists() {
+            certificates.push(read_certificate(&current)?);
+        }
+        let prefix = format!("{}.", role);
+        for entry in fs::read_dir(self.dir.join("retired"))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(serial) = name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".cert"))
+            else {
+                continue;
+            };
+            if serial.parse::<u64>().is_ok() {
+                certificates.push(read_certificate(&path)?);
+            }
+        }
+        certificates.sort_by_key(|certificate| std::cmp::Reverse(certificate.serial));
+        Ok(certificates)
+    }
+
+    fn key_path(&self, role: &str) -> PathBuf {
+        self.dir.join(format!("{}.key", role))
+    }
+
+    fn cert_path(&self, role: &str) -> PathBuf {
+        self.dir.join(format!("{}.cert", role))
+    }
+}
+
+fn generate_key() -> IdentityResult<SigningKey> {
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| IdentityError::Malformed {
+        what: "random seed",
+        reason: e.to_string(),
+    })?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn to_json(certificate: &RoleCertificate) -> IdentityResult<String> {
+    serde_json::to_string_pretty(certificate).map_err(|e| IdentityError::Malformed {
+        what: "certificate",
+        reason: e.to_string(),
+    })
+}
+
+fn read_certificate(path: &Path) -> IdentityResult<RoleCertificate> {
+    let json = fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| IdentityError::Malformed {
+        what: "certificate",
+        reason: e.to_string(),
+    })
+}
+
+fn read_secret(path: &Path) -> IdentityResult<[u8; 32]> {
+    let malformed = |reason: String| IdentityError::Malformed {
+        what: "secret key",
+        reason,
+    };
+    let encoded = fs::read_to_string(path)?;
+    let bytes = hex::decode(encoded.trim()).map_err(|e| malformed(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| malformed("secret key must be 32 bytes".to_string()))
+}
+
+fn write_secret(path: &Path, secret: &[u8; 32]) -> IdentityResult<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)?
+        .write_all(hex::encode(secret).as_bytes())?;
+    Ok(())
+}
+
+pub(crate) mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
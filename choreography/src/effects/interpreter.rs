@@ -5,12 +5,22 @@
 
 use async_recursion::async_recursion;
 use async_trait::async_trait;
+use futures::future::Either;
+use futures::stream::{self, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
 use std::any::TypeId;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::effects::algebra::{Effect, InterpretResult, InterpreterState, Program, ProgramMessage};
-use crate::effects::{ChoreoHandler, ChoreographyError, Result, RoleId};
+use crate::effects::algebra::{
+    Effect, InterpretResult, InterpreterState, Program, ProgramMessage, UnknownLabel,
+};
+use crate::effects::cancel::CancellationToken;
+use crate::effects::compute::Computation;
+use crate::effects::guard::GuardContext;
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+use crate::runtime::{SessionId, SessionManager, SessionStatus, SpawnHandle};
 
 /// Interpret a choreographic program using a concrete handler
 pub async fn interpret<H, R, M>(
@@ -23,53 +33,747 @@ where
     R: RoleId,
     M: ProgramMessage + Serialize + DeserializeOwned + 'static,
 {
-    let mut interpreter = Interpreter::new();
-    interpreter.run(handler, endpoint, program).await
+    Interpreter::new(handler, endpoint).run(program).await
+}
+
+/// Interpret a program until it finishes or `token` is cancelled
+///
+/// Cancelling the token stops the program at the effect it is running,
+/// even one waiting for a peer, and the result is
+/// [`InterpreterState::Cancelled`] with the values received so far. The
+/// cleanups of the `finally` blocks it was in still run, innermost first,
+/// and are not cancelled themselves; a cleanup already running is let
+/// finish. The handler's [`on_cancel`](ChoreoHandler::on_cancel) is
+/// called afterwards to tear down its transport state. Peers are not
+/// told; use a `cancel` effect or a handler that notifies them for that.
+pub async fn interpret_with_cancel<H, R, M>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    program: Program<R, M>,
+    token: &CancellationToken,
+) -> Result<InterpretResult<M>>
+where
+    H: ChoreoHandler<Role = R> + Send,
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    Interpreter::new(handler, endpoint)
+        .cancel(token)
+        .run(program)
+        .await
+}
+
+/// Interpret a program whose guarded choices are decided on `guards`
+///
+/// A guard naming a variable the context does not set fails the program.
+/// Child sessions it spawns start without variables.
+pub async fn interpret_with_guards<H, R, M>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    program: Program<R, M>,
+    guards: GuardContext,
+) -> Result<InterpretResult<M>>
+where
+    H: ChoreoHandler<Role = R> + Send,
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    Interpreter::new(handler, endpoint)
+        .guards(guards)
+        .run(program)
+        .await
+}
+
+/// Interpret a program, reporting its progress to `hooks`
+///
+/// Hooks see program structure that handler middleware cannot: which
+/// branch was taken, which loop iteration is running, and where in the
+/// program each effect sits.
+pub async fn interpret_with_hooks<H, R, M>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    program: Program<R, M>,
+    hooks: &mut dyn InterpreterHooks<R, M>,
+) -> Result<InterpretResult<M>>
+where
+    H: ChoreoHandler<Role = R> + Send,
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    Interpreter::new(handler, endpoint)
+        .hooks(hooks)
+        .run(program)
+        .await
+}
+
+/// One run of a program on a handler, with any of the options the
+/// `interpret_with_*` functions set one at a time
+///
+/// ```ignore
+/// let result = Interpreter::new(&mut handler, &mut endpoint)
+///     .cancel(&token)
+///     .guards(GuardContext::new().with_var("balance", 120))
+///     .hooks(&mut observer)
+///     .run(program)
+///     .await?;
+/// ```
+pub struct Interpreter<'a, H: ChoreoHandler, M> {
+    handler: &'a mut H,
+    endpoint: &'a mut H::Endpoint,
+    token: Option<CancellationToken>,
+    guards: GuardContext,
+    hooks: Option<&'a mut dyn InterpreterHooks<H::Role, M>>,
+}
+
+impl<'a, H, M> Interpreter<'a, H, M>
+where
+    H: ChoreoHandler + Send,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    pub fn new(handler: &'a mut H, endpoint: &'a mut H::Endpoint) -> Self {
+        Self {
+            handler,
+            endpoint,
+            token: None,
+            guards: GuardContext::new(),
+            hooks: None,
+        }
+    }
+
+    /// Stop the program when `token` is cancelled, as
+    /// [`interpret_with_cancel`] does
+    pub fn cancel(mut self, token: &CancellationToken) -> Self {
+        self.token = Some(token.clone());
+        self
+    }
+
+    /// Decide guarded choices on `guards`, as [`interpret_with_guards`]
+    /// does
+    pub fn guards(mut self, guards: GuardContext) -> Self {
+        self.guards = guards;
+        self
+    }
+
+    /// Report the program's progress to `hooks`, as
+    /// [`interpret_with_hooks`] does
+    pub fn hooks(mut self, hooks: &'a mut dyn InterpreterHooks<H::Role, M>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Run `program` to the end, or until it is cancelled
+    pub async fn run(self, program: Program<H::Role, M>) -> Result<InterpretResult<M>> {
+        let mut machine = Machine::new(None);
+        machine.token = self.token;
+        machine.guards = self.guards;
+        machine
+            .run_root(self.handler, self.endpoint, self.hooks, program)
+            .await
+    }
+}
+
+/// Interpret a program as the session `context` names, so that it can
+/// spawn child sessions
+///
+/// Each `spawn` effect connects a new handler and endpoint for the child
+/// through the context and runs the child on the runtime under the
+/// context's manager. The program carries on at once, and an `await`
+/// effect later waits for the child and adds the values it received.
+/// How the session ended is recorded with the manager.
+pub async fn interpret_in_session<H, R, M>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    program: Program<R, M>,
+    context: &SessionContext<H>,
+) -> Result<InterpretResult<M>>
+where
+    H: ChoreoHandler<Role = R> + Send + 'static,
+    H::Endpoint: Send + 'static,
+    R: RoleId + 'static,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    let mut interpreter = Machine::new(Some(Arc::new(context.clone())));
+    interpreter.session = Some(context.id.to_string());
+    let result = interpreter.run_root(handler, endpoint, None, program).await;
+    let status = match &result {
+        Ok(InterpretResult {
+            final_state: InterpreterState::Completed,
+            ..
+        }) => SessionStatus::Completed,
+        Ok(InterpretResult {
+            final_state: InterpreterState::Failed(msg),
+            ..
+        }) => SessionStatus::Failed(msg.clone()),
+        Ok(InterpretResult {
+            final_state: InterpreterState::Cancelled,
+            ..
+        }) => SessionStatus::Failed("cancelled".to_string()),
+        Ok(_) => SessionStatus::Failed("timed out".to_string()),
+        Err(e) => SessionStatus::Failed(e.to_string()),
+    };
+    context.manager.finish(&context.id, status);
+    result
+}
+
+/// Connects child sessions for a handler type
+type Connect<H> = Arc<dyn Fn(&SessionId) -> (H, <H as ChoreoHandler>::Endpoint) + Send + Sync>;
+
+/// The session a program runs as, and how to connect its children
+///
+/// `connect` is called with a child's id and returns the handler and
+/// endpoint it runs on. Peers derive the same child ids, so the id can be
+/// used to find the child's channels on each side.
+pub struct SessionContext<H: ChoreoHandler> {
+    manager: SessionManager,
+    id: SessionId,
+    connect: Connect<H>,
+}
+
+impl<H: ChoreoHandler> SessionContext<H> {
+    /// Register `id` as a running root session with `manager`
+    pub fn new(
+        manager: SessionManager,
+        id: SessionId,
+        connect: impl Fn(&SessionId) -> (H, H::Endpoint) + Send + Sync + 'static,
+    ) -> Self {
+        manager.open(id.clone());
+        Self {
+            manager,
+            id,
+            connect: Arc::new(connect),
+        }
+    }
+
+    pub fn id(&self) -> &SessionId {
+        &self.id
+    }
+
+    pub fn manager(&self) -> &SessionManager {
+        &self.manager
+    }
+}
+
+impl<H: ChoreoHandler> Clone for SessionContext<H> {
+    fn clone(&self) -> Self {
+        Self {
+            manager: self.manager.clone(),
+            id: self.id.clone(),
+            connect: Arc::clone(&self.connect),
+        }
+    }
+}
+
+/// Starts the child sessions of `spawn` effects, without naming the
+/// handler type
+trait SpawnChild<R: RoleId, M>: Send + Sync {
+    fn spawn(&self, handle: &'static str, program: Program<R, M>) -> SpawnHandle<Vec<M>>;
+}
+
+impl<H, R, M> SpawnChild<R, M> for SessionContext<H>
+where
+    H: ChoreoHandler<Role = R> + Send + 'static,
+    H::Endpoint: Send + 'static,
+    R: RoleId + 'static,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    fn spawn(&self, handle: &'static str, program: Program<R, M>) -> SpawnHandle<Vec<M>> {
+        let parent = self.clone();
+        self.manager.spawn(&self.id, handle, move |id| {
+            let (mut handler, mut endpoint) = (parent.connect)(&id);
+            let context = SessionContext { id, ..parent };
+            async move {
+                let result =
+                    interpret_in_session(&mut handler, &mut endpoint, program, &context).await?;
+                if result.final_state == InterpreterState::Cancelled {
+                    return Err(ChoreographyError::Cancelled);
+                }
+                nested_outcome(result.final_state, result.error)?;
+                Ok(result.received_values)
+            }
+        })
+    }
+}
+
+/// Interpret many sessions concurrently, one program each
+///
+/// Built for load tests and batch jobs that run the same program many
+/// times. `factory` is called with the session index and returns the
+/// handler and endpoint for that session; anything the sessions should
+/// share, such as a connection pool or the counters of a cloned
+/// [`Metrics`](crate::effects::middleware::Metrics) handler, is set up once
+/// outside it and cloned in. Results come back in the order of `programs`.
+///
+/// Sessions run on the calling task, so handlers do not need to be `Send`
+/// or `'static`, but a handler that blocks the thread stalls every session.
+pub async fn interpret_many<F, H, R, M>(
+    factory: F,
+    programs: impl IntoIterator<Item = Program<R, M>>,
+) -> Vec<Result<InterpretResult<M>>>
+where
+    F: FnMut(usize) -> (H, H::Endpoint),
+    H: ChoreoHandler<Role = R> + Send,
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    interpret_many_limited(factory, programs, usize::MAX).await
+}
+
+/// Like [`interpret_many`], with at most `limit` sessions in flight
+///
+/// A session's handler is only created when it starts, so a long batch
+/// holds at most `limit` handlers at a time.
+pub async fn interpret_many_limited<F, H, R, M>(
+    mut factory: F,
+    programs: impl IntoIterator<Item = Program<R, M>>,
+    limit: usize,
+) -> Vec<Result<InterpretResult<M>>>
+where
+    F: FnMut(usize) -> (H, H::Endpoint),
+    H: ChoreoHandler<Role = R> + Send,
+    R: RoleId,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+{
+    stream::iter(programs.into_iter().enumerate())
+        .map(|(index, program)| {
+            let (mut handler, mut endpoint) = factory(index);
+            async move { interpret(&mut handler, &mut endpoint, program).await }
+        })
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
+
+/// Observer of program execution, called by the interpreter itself
+///
+/// All methods default to doing nothing. `on_effect_end` receives the
+/// effect's outcome; for effects with nested programs it runs after the
+/// nested program has finished.
+#[async_trait]
+pub trait InterpreterHooks<R: RoleId, M: Send + Sync>: Send {
+    async fn on_effect_start(&mut self, _effect: &Effect<R, M>, _ctx: &EffectContext) {}
+
+    async fn on_effect_end(
+        &mut self,
+        _effect: &Effect<R, M>,
+        _ctx: &EffectContext,
+        _result: &Result<()>,
+    ) {
+    }
+
+    /// Called when a branch effect selects the arm labelled `label`
+    async fn on_branch_taken(&mut self, _label: Label, _ctx: &EffectContext) {}
+}
+
+/// Where an effect sits in the running program
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EffectContext {
+    /// Enclosing structures, outermost first
+    pub scopes: Vec<Scope>,
+    /// Position of the effect in its innermost program
+    pub index: usize,
+}
+
+/// A structure the interpreter has entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Branch(Label),
+    /// Zero-based loop iteration
+    Iteration(usize),
+    /// Arm of a parallel effect
+    Parallel(usize),
+    Timeout,
+    /// The `else` of a timeout, after the time ran out
+    OnTimeout,
+    FinallyBody,
+    FinallyCleanup,
+    TryBody,
+    /// Compensation run after the try body failed
+    Catch,
+    /// Body of the `rec` with this label
+    Rec(&'static str),
+}
+
+impl EffectContext {
+    /// Iteration of the innermost enclosing loop
+    pub fn iteration(&self) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| match scope {
+            Scope::Iteration(i) => Some(*i),
+            _ => None,
+        })
+    }
+
+    /// Label of the innermost enclosing branch
+    pub fn branch(&self) -> Option<Label> {
+        self.scopes.iter().rev().find_map(|scope| match scope {
+            Scope::Branch(label) => Some(*label),
+            _ => None,
+        })
+    }
+}
+
+/// Where in a session a step that failed was
+///
+/// The interpreter adds this to every error a step raises, as
+/// [`ChoreographyError::InContext`], so that a timeout deep in a loop names
+/// the receive that waited and the iteration it was in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// Session the program ran as, when it ran in one
+    pub session: Option<String>,
+    /// One-based count of the effects the interpreter had started
+    pub step: usize,
+    /// The failing effect, without its nested programs
+    pub effect: String,
+    /// Structures the effect sat in, outermost first
+    pub scopes: Vec<Scope>,
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(session) = &self.session {
+            write!(f, "session {}, ", session)?;
+        }
+        write!(f, "step {}: {}", self.step, self.effect)?;
+        if !self.scopes.is_empty() {
+            let scopes: Vec<String> = self.scopes.iter().map(ToString::to_string).collect();
+            write!(f, " ({})", scopes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Branch(label) => write!(f, "branch {}", label.0),
+            Scope::Iteration(i) => write!(f, "loop iteration {}", i),
+            Scope::Parallel(arm) => write!(f, "parallel arm {}", arm),
+            Scope::Timeout => f.write_str("timeout"),
+            Scope::OnTimeout => f.write_str("timeout else"),
+            Scope::FinallyBody => f.write_str("finally body"),
+            Scope::FinallyCleanup => f.write_str("cleanup"),
+            Scope::TryBody => f.write_str("try"),
+            Scope::Catch => f.write_str("compensation"),
+            Scope::Rec(label) => write!(f, "rec {}", label),
+        }
+    }
+}
+
+/// Description of an effect for error contexts; a send leaves out its
+/// payload, which can be large
+pub(crate) fn step_name<R: RoleId, M: std::fmt::Debug>(effect: &Effect<R, M>) -> String {
+    match effect {
+        Effect::Send { to, .. } => format!("send to {:?}", to),
+        Effect::Route { router, .. } => format!("route by {}", router.name),
+        other => other.head(),
+    }
+}
+
+/// Hooks borrowed for one call; `'h` is the lifetime of the hooks object
+type Hooks<'a, 'h, R, M> = Option<&'a mut (dyn InterpreterHooks<R, M> + 'h)>;
+
+/// Pass on how a nested program stopped, with the error it raised
+///
+/// The error keeps the step that raised it and, for timeouts, the deadline
+/// that expired.
+fn nested_outcome(state: InterpreterState, error: Option<ChoreographyError>) -> Result<()> {
+    match (state, error) {
+        (InterpreterState::Completed | InterpreterState::Cancelled, _) => Ok(()),
+        (_, Some(e)) => Err(e),
+        (InterpreterState::Failed(msg), None) => Err(ChoreographyError::Transport(msg)),
+        (InterpreterState::Timeout, None) => {
+            unreachable!("a program stops on a timeout only with the error that raised it")
+        }
+    }
 }
 
 /// Internal interpreter state
-struct Interpreter<M> {
+struct Machine<R: RoleId, M> {
     received_values: Vec<M>,
+    /// Values kept under a name for later effects
+    bindings: HashMap<&'static str, M>,
     #[allow(dead_code)]
     type_registry: HashMap<TypeId, String>,
     /// Track the last received label from an Offer effect
     last_label: Option<crate::effects::Label>,
+    /// Structures entered so far, for hook contexts
+    scopes: Vec<Scope>,
+    /// Results of pure computations in this session, by name and input
+    memo: HashMap<(&'static str, Vec<u8>), M>,
+    /// Starts child sessions, when running inside one
+    sessions: Option<Arc<dyn SpawnChild<R, M>>>,
+    /// Child sessions not awaited yet, by handle
+    children: HashMap<&'static str, SpawnHandle<Vec<M>>>,
+    /// Labels of the `rec` effects being run, innermost last
+    recs: Vec<&'static str>,
+    /// Label of a jump taken, until its `rec` starts over
+    jumping: Option<&'static str>,
+    /// Stops the program when cancelled
+    token: Option<CancellationToken>,
+    /// Set once the program is cancelled; the rest of it is skipped
+    cancelled: bool,
+    /// Cleanups of the `finally` bodies being run, innermost last, with
+    /// the scope depth each `finally` started at
+    cleanups: Vec<(usize, Program<R, M>)>,
+    /// How many cleanups are running, shared with the cancellation race
+    cleaning: Arc<AtomicUsize>,
+    /// The error that failed the program, before nested failures are
+    /// turned into messages on their way out
+    raised: Option<ChoreographyError>,
+    /// Session the program runs as, for error contexts
+    session: Option<String>,
+    /// Effects started so far
+    step: usize,
+    /// Step number and description of the innermost effect started
+    current: (usize, String),
+    /// Variables guarded choices are decided on
+    guards: GuardContext,
 }
 
-impl<M> Interpreter<M> {
-    fn new() -> Self {
+impl<R: RoleId, M> Machine<R, M> {
+    fn new(sessions: Option<Arc<dyn SpawnChild<R, M>>>) -> Self {
         Self {
             received_values: Vec::new(),
+            bindings: HashMap::new(),
             type_registry: HashMap::new(),
             last_label: None,
+            scopes: Vec::new(),
+            memo: HashMap::new(),
+            sessions,
+            children: HashMap::new(),
+            recs: Vec::new(),
+            jumping: None,
+            token: None,
+            cancelled: false,
+            cleanups: Vec::new(),
+            cleaning: Arc::default(),
+            raised: None,
+            session: None,
+            step: 0,
+            current: (0, String::new()),
+            guards: GuardContext::new(),
         }
     }
 
-    #[async_recursion]
-    async fn run<H, R>(
+    /// Context for an error raised by step `step`, described by `effect`,
+    /// in the scopes entered now
+    fn error_context(&self, (step, effect): (usize, String)) -> ErrorContext {
+        ErrorContext {
+            session: self.session.clone(),
+            step,
+            effect,
+            scopes: self.scopes.clone(),
+        }
+    }
+
+    fn context(&self, index: usize) -> EffectContext {
+        EffectContext {
+            scopes: self.scopes.clone(),
+            index,
+        }
+    }
+
+    /// Whether the rest of the current body is skipped, because of a jump
+    /// or a cancellation
+    fn unwinding(&self) -> bool {
+        self.jumping.is_some() || self.cancelled
+    }
+
+    /// Run the whole program, racing it against the cancellation token
+    async fn run_root<'h, H>(
         &mut self,
         handler: &mut H,
         endpoint: &mut H::Endpoint,
+        mut hooks: Hooks<'_, 'h, R, M>,
         program: Program<R, M>,
     ) -> Result<InterpretResult<M>>
     where
         H: ChoreoHandler<Role = R> + Send,
-        R: RoleId,
         M: ProgramMessage + Serialize + DeserializeOwned + 'static,
     {
-        for effect in program.effects {
-            match self.execute_effect(handler, endpoint, effect).await {
-                Ok(()) => continue,
-                Err(ChoreographyError::Timeout(_)) => {
-                    return Ok(InterpretResult {
-                        received_values: self.received_values.clone(),
-                        final_state: InterpreterState::Timeout,
-                    });
+        let result = match self.token.clone() {
+            Some(token) => {
+                let cleaning = Arc::clone(&self.cleaning);
+                let run = self.run(handler, endpoint, hooks.as_deref_mut(), program);
+                match futures::future::select(run, Box::pin(token.cancelled())).await {
+                    Either::Left((result, _)) => Some(result?),
+                    // A cleanup being run is let finish, and the program
+                    // then stops at its next effect
+                    Either::Right((_, run)) if cleaning.load(Ordering::SeqCst) > 0 => {
+                        Some(run.await?)
+                    }
+                    // Otherwise the program is dropped at whatever effect it
+                    // waited on
+                    Either::Right(_) => None,
                 }
+            }
+            None => Some(
+                self.run(handler, endpoint, hooks.as_deref_mut(), program)
+                    .await?,
+            ),
+        };
+        let result = match result {
+            Some(result) if !self.cancelled => {
+                handler.flush(endpoint).await?;
+                return Ok(result);
+            }
+            Some(result) => result,
+            None => {
+                // The bodies left behind still get their cleanups
+                self.jumping = None;
+                self.leave_scopes(handler, endpoint, hooks, 0).await?;
+                self.cancelled = true;
+                InterpretResult {
+                    received_values: self.received_values.clone(),
+                    bindings: self.bindings.clone(),
+                    final_state: InterpreterState::Cancelled,
+                    error: None,
+                }
+            }
+        };
+        tracing::debug!("Session cancelled");
+        if let Err(e) = handler.on_cancel(endpoint).await {
+            tracing::warn!(error = %e, "Handler failed to tear down cancelled session");
+        }
+        Ok(InterpretResult {
+            final_state: InterpreterState::Cancelled,
+            ..result
+        })
+    }
+
+    /// Leave the scopes entered past `depth` by a body that was dropped
+    /// mid-way, running the cleanups of the `finally` bodies among them,
+    /// innermost first
+    ///
+    /// Failures are only logged: the drop that left the cleanups behind
+    /// is what the caller reports.
+    async fn leave_scopes<'h, H>(
+        &mut self,
+        handler: &mut H,
+        endpoint: &mut H::Endpoint,
+        mut hooks: Hooks<'_, 'h, R, M>,
+        depth: usize,
+    ) -> Result<()>
+    where
+        H: ChoreoHandler<Role = R> + Send,
+        M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+    {
+        self.scopes.truncate(depth);
+        while self
+            .cleanups
+            .last()
+            .is_some_and(|(started, _)| *started >= depth)
+        {
+            let (_, cleanup) = self.cleanups.pop().expect("checked above");
+            self.cleaning.fetch_add(1, Ordering::SeqCst);
+            let cleaned = self
+                .run_in(
+                    handler,
+                    endpoint,
+                    hooks.as_deref_mut(),
+                    Scope::FinallyCleanup,
+                    cleanup,
+                )
+                .await;
+            self.cleaning.fetch_sub(1, Ordering::SeqCst);
+            let cleaned = cleaned?;
+            self.received_values.extend(cleaned.received_values);
+            if let Some(e) = cleaned.error {
+                tracing::warn!(error = %e, "Cleanup of a dropped finally body failed");
+                // Not the cause of whatever fails next
+                self.raised = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a nested program inside `scope`
+    ///
+    /// The result only holds the values received by the nested program.
+    async fn run_in<'h, H>(
+        &mut self,
+        handler: &mut H,
+        endpoint: &mut H::Endpoint,
+        hooks: Hooks<'_, 'h, R, M>,
+        scope: Scope,
+        program: Program<R, M>,
+    ) -> Result<InterpretResult<M>>
+    where
+        H: ChoreoHandler<Role = R> + Send,
+        M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+    {
+        self.scopes.push(scope);
+        let start = self.received_values.len();
+        let result = self.run(handler, endpoint, hooks, program).await;
+        self.scopes.pop();
+        // The caller adds them back, so they are not counted twice
+        let received = self.received_values.split_off(start);
+        result.map(|result| InterpretResult {
+            received_values: received,
+            ..result
+        })
+    }
+
+    #[async_recursion]
+    async fn run<'a, 'h: 'a, H>(
+        &'a mut self,
+        handler: &'a mut H,
+        endpoint: &'a mut H::Endpoint,
+        mut hooks: Hooks<'a, 'h, R, M>,
+        program: Program<R, M>,
+    ) -> Result<InterpretResult<M>>
+    where
+        H: ChoreoHandler<Role = R> + Send,
+        M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+    {
+        for (index, effect) in program.effects.into_iter().enumerate() {
+            // Cleanups run to the end even once cancelled
+            if self
+                .token
+                .as_ref()
+                .is_some_and(|token| token.is_cancelled())
+                && !self.scopes.contains(&Scope::FinallyCleanup)
+            {
+                self.cancelled = true;
+                break;
+            }
+            self.step += 1;
+            let here = (self.step, step_name(&effect));
+            self.current = here.clone();
+            let outcome = match hooks.as_deref_mut() {
+                Some(h) => {
+                    let ctx = self.context(index);
+                    let observed = effect.clone();
+                    h.on_effect_start(&observed, &ctx).await;
+                    let outcome = self
+                        .execute_effect(handler, endpoint, Some(&mut *h), effect)
+                        .await;
+                    h.on_effect_end(&observed, &ctx, &outcome).await;
+                    outcome
+                }
+                None => self.execute_effect(handler, endpoint, None, effect).await,
+            };
+            match outcome {
+                // The rest of the body is skipped until the jump's `rec`,
+                // or entirely once cancelled
+                Ok(()) if self.unwinding() => break,
+                Ok(()) => continue,
                 Err(e) => {
+                    // A failure of a nested program already names its step
+                    let e = match self.raised.take() {
+                        Some(raised) => raised,
+                        None => e.in_context(self.error_context(here)),
+                    };
+                    let final_state = match e.root_cause() {
+                        ChoreographyError::Timeout(_) => InterpreterState::Timeout,
+                        _ => InterpreterState::Failed(e.to_string()),
+                    };
+                    self.raised = Some(e.clone());
                     return Ok(InterpretResult {
                         received_values: self.received_values.clone(),
-                        final_state: InterpreterState::Failed(e.to_string()),
+                        bindings: self.bindings.clone(),
+                        final_state,
+                        error: Some(e),
                     });
                 }
             }
@@ -77,20 +781,22 @@ impl<M> Interpreter<M> {
 
         Ok(InterpretResult {
             received_values: self.received_values.clone(),
+            bindings: self.bindings.clone(),
             final_state: InterpreterState::Completed,
+            error: None,
         })
     }
 
     #[async_recursion]
-    async fn execute_effect<H, R>(
-        &mut self,
-        handler: &mut H,
-        endpoint: &mut H::Endpoint,
+    async fn execute_effect<'a, 'h: 'a, H>(
+        &'a mut self,
+        handler: &'a mut H,
+        endpoint: &'a mut H::Endpoint,
+        mut hooks: Hooks<'a, 'h, R, M>,
         effect: Effect<R, M>,
     ) -> Result<()>
     where
         H: ChoreoHandler<Role = R> + Send,
-        R: RoleId,
         M: ProgramMessage + Serialize + DeserializeOwned + 'static,
     {
         match effect {
@@ -98,16 +804,28 @@ impl<M> Interpreter<M> {
                 handler.send(endpoint, to, &msg).await?;
             }
 
+            Effect::SendWith {
+                to,
+                name,
+                computation,
+            } => {
+                let Some(input) = self.bindings.get(name).cloned() else {
+                    return Err(ChoreographyError::ProtocolViolation(format!(
+                        "send {} to {:?} reads {}, which is not bound",
+                        computation.name, to, name
+                    )));
+                };
+                let msg = self.compute(&computation, &input)?;
+                handler.send(endpoint, to, &msg).await?;
+            }
+
             Effect::Recv { from, msg_type } => {
                 // Type-erased receive: attempt to receive as expected type M
                 // Type-specific interpreters or sophisticated type registry needed for full polymorphism
                 tracing::debug!(?from, ?msg_type, "recv effect - type casting required");
 
                 // Attempt to receive as the expected type M
-                match self
-                    .try_recv_as_type::<H, R, M>(handler, endpoint, from)
-                    .await
-                {
+                match self.try_recv_as_type::<H, M>(handler, endpoint, from).await {
                     Ok(value) => {
                         self.received_values.push(value);
                     }
@@ -121,8 +839,55 @@ impl<M> Interpreter<M> {
                 self.last_label = Some(label);
             }
 
+            Effect::ChooseWhen { at, guards } => {
+                let mut chosen = None;
+                for (label, guard) in &guards {
+                    let holds = match guard {
+                        Some(guard) => guard.holds(&self.guards).map_err(|e| {
+                            ChoreographyError::ProtocolViolation(format!(
+                                "Guard of {} cannot be evaluated: {}",
+                                label.0, e
+                            ))
+                        })?,
+                        None => true,
+                    };
+                    if holds {
+                        chosen = Some(*label);
+                        break;
+                    }
+                }
+                let label = chosen.ok_or_else(|| ChoreographyError::GuardUnsatisfied {
+                    role: format!("{:?}", at),
+                    labels: guards
+                        .iter()
+                        .map(|(label, _)| label.0.to_string())
+                        .collect(),
+                })?;
+                tracing::debug!(?at, ?label, "Guard chose label");
+                handler.choose(endpoint, at, label).await?;
+                self.last_label = Some(label);
+            }
+
+            Effect::Route { at, msg, router } => {
+                let to = router.route(&msg);
+                let label = router.label_of(&to).ok_or_else(|| {
+                    ChoreographyError::ProtocolViolation(format!(
+                        "{} routed the message to {:?}, which is not one of its candidates",
+                        router.name, to
+                    ))
+                })?;
+                tracing::debug!(?at, ?to, ?label, "Router picked a candidate");
+                handler.choose(endpoint, at, label).await?;
+                handler.send(endpoint, to, &msg).await?;
+                self.last_label = Some(label);
+            }
+
             Effect::Offer { from } => {
-                let label = handler.offer(endpoint, from).await?;
+                let mut label = handler.offer(endpoint, from).await?;
+                while label.is_keep_alive() {
+                    tracing::debug!(?from, ?label, "Skipping keep-alive label");
+                    label = handler.offer(endpoint, from).await?;
+                }
                 // Store the received label for control flow decisions in subsequent Branch effects
                 tracing::debug!(?from, ?label, "Received offer label");
                 self.last_label = Some(label);
@@ -131,6 +896,7 @@ impl<M> Interpreter<M> {
             Effect::Branch {
                 choosing_role,
                 branches,
+                unknown,
             } => {
                 // Handle branching based on choice
                 // The choosing role has already executed Choose effect to select a branch
@@ -142,47 +908,78 @@ impl<M> Interpreter<M> {
                 );
 
                 // Get the label from the last Choose/Offer effect
-                let label = self.last_label.ok_or_else(|| {
+                let mut label = self.last_label.ok_or_else(|| {
                     ChoreographyError::ProtocolViolation(
                         "Branch effect requires a preceding Choose or Offer effect".to_string(),
                     )
                 })?;
 
-                // Find the matching branch by label
-                let selected_branch = branches
-                    .iter()
-                    .find(|(branch_label, _)| branch_label == &label)
-                    .ok_or_else(|| {
-                        ChoreographyError::ProtocolViolation(format!(
-                            "No branch found for label {:?}",
-                            label
-                        ))
-                    })?;
+                // Find the matching branch by label. System labels that end
+                // the session are handled here unless the program lists them;
+                // any other label no branch lists is left to the policy
+                let selected_branch = loop {
+                    if let Some(branch) = branches.iter().find(|(l, _)| l == &label) {
+                        break branch;
+                    }
+                    if label.ends_session() {
+                        return Err(ChoreographyError::Aborted {
+                            role: format!("{:?}", choosing_role),
+                            label: label.0,
+                        });
+                    }
+                    match unknown {
+                        UnknownLabel::Reject => {
+                            return Err(ChoreographyError::ProtocolViolation(format!(
+                                "No branch found for label {:?}",
+                                label
+                            )))
+                        }
+                        UnknownLabel::Skip => {
+                            tracing::warn!(?choosing_role, ?label, "Skipping unknown label");
+                            label = handler.offer(endpoint, choosing_role).await?;
+                            while label.is_keep_alive() {
+                                label = handler.offer(endpoint, choosing_role).await?;
+                            }
+                        }
+                        UnknownLabel::Fallback(fallback) => {
+                            tracing::warn!(
+                                ?choosing_role,
+                                ?label,
+                                ?fallback,
+                                "Taking the fallback branch for an unknown label"
+                            );
+                            label = fallback;
+                            if !branches.iter().any(|(l, _)| l == &label) {
+                                return Err(ChoreographyError::ProtocolViolation(format!(
+                                    "Fallback label {:?} is not one of the branches",
+                                    label
+                                )));
+                            }
+                        }
+                    }
+                };
 
                 tracing::debug!(selected_label = ?label, "Executing selected branch");
+                if let Some(h) = hooks.as_deref_mut() {
+                    h.on_branch_taken(label, &self.context(0)).await;
+                }
 
                 // Execute the selected branch
                 let result = self
-                    .run(handler, endpoint, selected_branch.1.clone())
+                    .run_in(
+                        handler,
+                        endpoint,
+                        hooks,
+                        Scope::Branch(label),
+                        selected_branch.1.clone(),
+                    )
                     .await?;
                 self.received_values.extend(result.received_values);
 
                 // Clear the label after use
                 self.last_label = None;
 
-                if !matches!(result.final_state, InterpreterState::Completed) {
-                    match result.final_state {
-                        InterpreterState::Failed(msg) => {
-                            return Err(ChoreographyError::Transport(msg));
-                        }
-                        InterpreterState::Timeout => {
-                            return Err(ChoreographyError::Timeout(
-                                std::time::Duration::from_secs(0),
-                            ));
-                        }
-                        InterpreterState::Completed => {}
-                    }
-                }
+                nested_outcome(result.final_state, result.error)?;
             }
 
             Effect::Loop { iterations, body } => {
@@ -192,32 +989,115 @@ impl<M> Interpreter<M> {
                 let count = iterations.unwrap_or(1); // Default to 1 iteration if None
                 for iteration in 0..count {
                     tracing::debug!(iteration, "Loop iteration");
-                    let result = self.run(handler, endpoint, (*body).clone()).await?;
+                    let result = self
+                        .run_in(
+                            handler,
+                            endpoint,
+                            hooks.as_deref_mut(),
+                            Scope::Iteration(iteration),
+                            (*body).clone(),
+                        )
+                        .await?;
                     self.received_values.extend(result.received_values);
 
-                    if !matches!(result.final_state, InterpreterState::Completed) {
-                        match result.final_state {
-                            InterpreterState::Failed(msg) => {
-                                return Err(ChoreographyError::Transport(msg));
-                            }
-                            InterpreterState::Timeout => {
-                                return Err(ChoreographyError::Timeout(
-                                    std::time::Duration::from_secs(0),
-                                ));
-                            }
-                            InterpreterState::Completed => {}
-                        }
+                    nested_outcome(result.final_state, result.error)?;
+                    if self.unwinding() {
+                        break;
+                    }
+                }
+            }
+
+            Effect::LoopWhile { condition, body } => {
+                tracing::debug!(condition = condition.name, "Executing loop while effect");
+
+                let mut iteration = 0;
+                while condition.holds(iteration, self.received_values.last()) {
+                    tracing::debug!(iteration, "Loop iteration");
+                    let result = self
+                        .run_in(
+                            handler,
+                            endpoint,
+                            hooks.as_deref_mut(),
+                            Scope::Iteration(iteration),
+                            (*body).clone(),
+                        )
+                        .await?;
+                    self.received_values.extend(result.received_values);
+
+                    nested_outcome(result.final_state, result.error)?;
+                    if self.unwinding() {
+                        break;
+                    }
+                    iteration += 1;
+                }
+            }
+
+            Effect::Rec { label, body } => {
+                tracing::debug!(label, "Executing rec effect");
+
+                self.recs.push(label);
+                let outcome = loop {
+                    let result = match self
+                        .run_in(
+                            handler,
+                            endpoint,
+                            hooks.as_deref_mut(),
+                            Scope::Rec(label),
+                            (*body).clone(),
+                        )
+                        .await
+                    {
+                        Ok(result) => result,
+                        Err(e) => break Err(e),
+                    };
+                    self.received_values.extend(result.received_values);
+
+                    if let Err(e) = nested_outcome(result.final_state, result.error) {
+                        break Err(e);
+                    }
+                    // A jump to an outer `rec` leaves this one as well
+                    if self.jumping == Some(label) {
+                        tracing::debug!(label, "Jumping back to rec");
+                        self.jumping = None;
+                        continue;
                     }
+                    break Ok(());
+                };
+                self.recs.pop();
+                outcome?;
+            }
+
+            Effect::Jump { label } => {
+                if !self.recs.contains(&label) {
+                    return Err(ChoreographyError::ProtocolViolation(format!(
+                        "jump to {} outside of a rec with that label",
+                        label
+                    )));
                 }
+                self.jumping = Some(label);
             }
 
-            Effect::Timeout { at, dur, body } => {
+            Effect::Timeout {
+                at,
+                dur,
+                body,
+                on_timeout,
+            } => {
                 // Execute the body with a timeout
                 tracing::debug!(?at, ?dur, "Executing timeout effect");
+                let depth = self.scopes.len();
+                let cleaning = self.cleaning.load(Ordering::SeqCst);
 
                 #[cfg(not(target_arch = "wasm32"))]
                 let timeout_result = {
-                    tokio::time::timeout(dur, Box::pin(self.run(handler, endpoint, *body))).await
+                    let body = self.run_in(
+                        handler,
+                        endpoint,
+                        hooks.as_deref_mut(),
+                        Scope::Timeout,
+                        *body,
+                    );
+                    tokio::time::timeout(dur, Box::pin(body)).await
                 };
 
                 #[cfg(target_arch = "wasm32")]
@@ -226,7 +1106,13 @@ impl<M> Interpreter<M> {
                     use futures::pin_mut;
                     use wasm_timer::Delay;
 
-                    let body_future = Box::pin(self.run(handler, endpoint, *body));
+                    let body_future = Box::pin(self.run_in(
+                        handler,
+                        endpoint,
+                        hooks.as_deref_mut(),
+                        Scope::Timeout,
+                        *body,
+                    ));
                     let timeout = Delay::new(dur);
                     pin_mut!(body_future);
                     pin_mut!(timeout);
@@ -241,24 +1127,27 @@ impl<M> Interpreter<M> {
                     Ok(Ok(result)) => {
                         // Success - merge the results
                         self.received_values.extend(result.received_values);
-                        if !matches!(result.final_state, InterpreterState::Completed) {
-                            // Propagate non-completed state by updating our state
-                            // and returning an error for Failed/Timeout
-                            match result.final_state {
-                                InterpreterState::Failed(msg) => {
-                                    return Err(ChoreographyError::Transport(msg));
-                                }
-                                InterpreterState::Timeout => {
-                                    return Err(ChoreographyError::Timeout(dur));
-                                }
-                                InterpreterState::Completed => {}
-                            }
-                        }
+                        nested_outcome(result.final_state, result.error)?;
                     }
                     Ok(Err(e)) => return Err(e),
                     Err(_) => {
-                        // Timeout occurred
-                        return Err(ChoreographyError::Timeout(dur));
+                        // The body was dropped in the step it waited on,
+                        // inside the scopes it had entered
+                        let waited = self.error_context(self.current.clone());
+                        // A cleanup it was in the middle of never finishes,
+                        // and the finally bodies it was in are left too
+                        self.cleaning.store(cleaning, Ordering::SeqCst);
+                        self.leave_scopes(handler, endpoint, hooks.as_deref_mut(), depth)
+                            .await?;
+                        let Some(on_timeout) = on_timeout else {
+                            return Err(ChoreographyError::Timeout(dur).in_context(waited));
+                        };
+                        tracing::debug!(?at, ?dur, "Timed out, running the else branch");
+                        let result = self
+                            .run_in(handler, endpoint, hooks, Scope::OnTimeout, *on_timeout)
+                            .await?;
+                        self.received_values.extend(result.received_values);
+                        nested_outcome(result.final_state, result.error)?;
                     }
                 }
             }
@@ -271,22 +1160,168 @@ impl<M> Interpreter<M> {
 
                 // Try to execute in parallel, fall back to sequential if needed
                 // Sequential execution is still correct, just less performant
-                for program in programs {
-                    let result = self.run(handler, endpoint, program).await?;
+                for (arm, program) in programs.into_iter().enumerate() {
+                    let result = self
+                        .run_in(
+                            handler,
+                            endpoint,
+                            hooks.as_deref_mut(),
+                            Scope::Parallel(arm),
+                            program,
+                        )
+                        .await?;
                     self.received_values.extend(result.received_values);
 
-                    match result.final_state {
-                        InterpreterState::Failed(msg) => {
-                            return Err(ChoreographyError::Transport(msg));
-                        }
-                        InterpreterState::Timeout => {
-                            return Err(ChoreographyError::Timeout(
-                                std::time::Duration::from_secs(0),
-                            ));
+                    nested_outcome(result.final_state, result.error)?;
+                    if self.unwinding() {
+                        break;
+                    }
+                }
+            }
+
+            Effect::Finally { body, cleanup } => {
+                tracing::debug!("Executing finally effect");
+
+                // Failures in the body are held back until the cleanup has run,
+                // and take precedence over failures in the cleanup itself.
+                // The cleanup waits on the stack in case the body is
+                // dropped by a cancellation
+                self.cleanups.push((self.scopes.len(), *cleanup));
+                let body_result = self
+                    .run_in(
+                        handler,
+                        endpoint,
+                        hooks.as_deref_mut(),
+                        Scope::FinallyBody,
+                        *body,
+                    )
+                    .await?;
+                self.received_values.extend(body_result.received_values);
+                // A jump out of the body or a cancellation waits for the
+                // cleanup too
+                let jumping = self.jumping.take();
+                let cancelled = std::mem::take(&mut self.cancelled);
+                let (_, cleanup) = self.cleanups.pop().expect("cleanup pushed above");
+                self.cleaning.fetch_add(1, Ordering::SeqCst);
+                let cleanup_result = self
+                    .run_in(handler, endpoint, hooks, Scope::FinallyCleanup, cleanup)
+                    .await;
+                self.cleaning.fetch_sub(1, Ordering::SeqCst);
+                let cleanup_result = cleanup_result?;
+                self.received_values.extend(cleanup_result.received_values);
+                if jumping.is_some() {
+                    self.jumping = jumping;
+                }
+                self.cancelled |= cancelled;
+
+                nested_outcome(body_result.final_state, body_result.error)?;
+                nested_outcome(cleanup_result.final_state, cleanup_result.error)?;
+            }
+
+            Effect::TryCatch {
+                body,
+                notify,
+                compensation,
+            } => {
+                tracing::debug!(?notify, "Executing try effect");
+                self.raised = None;
+                let result = self
+                    .run_in(
+                        handler,
+                        endpoint,
+                        hooks.as_deref_mut(),
+                        Scope::TryBody,
+                        *body,
+                    )
+                    .await?;
+                self.received_values.extend(result.received_values);
+                let timed_out = result.final_state == InterpreterState::Timeout;
+                let Err(raised) = nested_outcome(result.final_state, result.error) else {
+                    return Ok(());
+                };
+                if timed_out {
+                    return Err(raised);
+                }
+                self.raised = None;
+                match raised.root_cause() {
+                    // Cancellation ends the session rather than a step of it
+                    ChoreographyError::Aborted { label, .. } if *label == Label::CANCEL.0 => {
+                        return Err(raised);
+                    }
+                    ChoreographyError::Cancelled => return Err(raised),
+                    // The peer that raised the error has told everyone
+                    ChoreographyError::Aborted { role, .. } => {
+                        tracing::debug!(%role, "Peer aborted, compensating");
+                    }
+                    _ => {
+                        tracing::debug!(error = %raised, "Try body failed, compensating");
+                        for peer in notify {
+                            // Peers that are gone cannot compensate anyway
+                            if let Err(e) = handler.choose(endpoint, peer, Label::ABORT).await {
+                                tracing::warn!(?peer, error = %e, "Failed to notify peer of abort");
+                            }
                         }
-                        InterpreterState::Completed => {}
                     }
                 }
+                let result = self
+                    .run_in(handler, endpoint, hooks, Scope::Catch, *compensation)
+                    .await?;
+                self.received_values.extend(result.received_values);
+                nested_outcome(result.final_state, result.error)?;
+            }
+
+            Effect::Compute { computation } => {
+                let Some(input) = self.received_values.last().cloned() else {
+                    return Err(ChoreographyError::ProtocolViolation(format!(
+                        "compute {} has no received value to work on",
+                        computation.name
+                    )));
+                };
+                let output = self.compute(&computation, &input)?;
+                self.received_values.push(output);
+            }
+
+            Effect::Bind { name } => {
+                let Some(value) = self.received_values.last().cloned() else {
+                    return Err(ChoreographyError::ProtocolViolation(format!(
+                        "bind {} has no received value to keep",
+                        name
+                    )));
+                };
+                self.bindings.insert(name, value);
+            }
+
+            Effect::Spawn { handle, program } => {
+                let sessions = self.sessions.clone().ok_or_else(|| {
+                    ChoreographyError::ProtocolViolation(format!(
+                        "spawn {} needs a session context; run the program with interpret_in_session",
+                        handle
+                    ))
+                })?;
+                let child = sessions.spawn(handle, *program);
+                tracing::debug!(handle, child = %child.id(), "Spawned child session");
+                // A child spawned again under the same handle replaces the
+                // earlier one, which keeps running on its own
+                self.children.insert(handle, child);
+            }
+
+            Effect::Await { handle } => {
+                let child = self.children.remove(handle).ok_or_else(|| {
+                    ChoreographyError::ProtocolViolation(format!(
+                        "await {} without a child session spawned under that handle",
+                        handle
+                    ))
+                })?;
+                let values = child.await?;
+                self.received_values.extend(values);
+            }
+
+            Effect::Cancel { notify } => {
+                tracing::debug!(?notify, "Cancelling session");
+                for peer in notify {
+                    handler.choose(endpoint, peer, Label::CANCEL).await?;
+                }
+                self.cancelled = true;
             }
 
             Effect::End => {
@@ -297,7 +1332,42 @@ impl<M> Interpreter<M> {
         Ok(())
     }
 
-    async fn try_recv_as_type<H, R, T>(
+    /// Run `computation`, reusing earlier results if it is pure
+    fn compute(&mut self, computation: &Computation<M>, input: &M) -> Result<M>
+    where
+        M: ProgramMessage + Serialize + DeserializeOwned,
+    {
+        if !computation.pure {
+            return Ok(computation.apply(input));
+        }
+        let key = serde_json::to_vec(input)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let memo_key = (computation.name, key);
+        if let Some(output) = self.memo.get(&memo_key) {
+            return Ok(output.clone());
+        }
+        let (name, key) = &memo_key;
+        let cached = computation
+            .cache()
+            .and_then(|cache| cache.get(name, key))
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+        let output = match cached {
+            Some(output) => output,
+            None => {
+                let output = computation.apply(input);
+                if let Some(cache) = computation.cache() {
+                    let bytes = serde_json::to_vec(&output)
+                        .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+                    cache.put(name, key, bytes);
+                }
+                output
+            }
+        };
+        self.memo.insert(memo_key, output.clone());
+        Ok(output)
+    }
+
+    async fn try_recv_as_type<H, T>(
         &mut self,
         handler: &mut H,
         endpoint: &mut H::Endpoint,
@@ -305,7 +1375,6 @@ impl<M> Interpreter<M> {
     ) -> Result<T>
     where
         H: ChoreoHandler<Role = R>,
-        R: RoleId,
         T: DeserializeOwned + Send,
     {
         handler.recv(endpoint, from).await
@@ -337,6 +1406,11 @@ pub mod testing {
     use super::*;
     use std::collections::VecDeque;
 
+    pub use crate::effects::fuzz::{
+        arbitrary_choreography, check_choreography, fuzz_choreography, run_shuffled, FuzzConfig,
+        FuzzCounterexample, FuzzFailure, FuzzReport,
+    };
+
     /// A mock handler that records operations and provides scripted responses
     pub struct MockHandler<R: RoleId> {
         #[allow(dead_code)]
@@ -0,0 +1,139 @@
+// Peers introducing themselves at session setup
+//
+// Two processes wired to each other by mistake, or running different builds
+// of a choreography, otherwise only find out when a message fails to decode
+// somewhere in the middle of a session. Before the protocol starts, every
+// participant sends each peer an `Introduction`: the fingerprint of the
+// choreography it was compiled from, the role it plays, the codecs it can
+// use, and the largest message it accepts. A peer that claims another role,
+// runs another fingerprint, or shares no codec fails the setup right away.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::ast::Choreography;
+use crate::compiler::cache::fingerprint;
+use crate::effects::{ChoreoHandler, ChoreographyError, Result, RoleId};
+
+/// What a participant tells its peers about itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Introduction {
+    /// [`Fingerprint`](crate::compiler::Fingerprint) of the choreography
+    pub fingerprint: String,
+    /// The role played, named as its `Debug` output
+    pub role: String,
+    /// Codecs the participant can use, most preferred first
+    pub codecs: Vec<String>,
+    /// Largest encoded message in bytes the participant accepts, if limited
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+}
+
+/// What a participant and one of its peers settled on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Agreement {
+    /// The codec to use with the peer
+    pub codec: String,
+    /// The smaller of the two sides' message size limits, if either has one
+    pub max_message_size: Option<usize>,
+}
+
+impl Introduction {
+    /// Introduce `role` of `choreography`, offering its wire format and
+    /// its `@max_size(...)` limit
+    pub fn new<R: RoleId>(choreography: &Choreography, role: R) -> Self {
+        Self {
+            fingerprint: fingerprint(choreography).to_string(),
+            role: format!("{:?}", role),
+            codecs: vec![choreography.wire_format().name().to_string()],
+            max_message_size: choreography.max_message_size(),
+        }
+    }
+
+    /// Accept messages of up to `limit` bytes instead
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// Offer `codecs` instead, most preferred first
+    pub fn with_codecs<I, S>(mut self, codecs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.codecs = codecs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Check what `peer` said about itself against what it should be
+    ///
+    /// Returns what to use with the peer: the first of our codecs that it
+    /// supports, and the smaller of the two message size limits.
+    pub fn check<R: RoleId>(&self, peer: R, remote: &Introduction) -> Result<Agreement> {
+        let expected = format!("{:?}", peer);
+        if remote.role != expected {
+            tracing::warn!(?peer, claimed = %remote.role, "peer claims another role");
+            return Err(ChoreographyError::UnknownRole(remote.role.clone()));
+        }
+        if remote.fingerprint != self.fingerprint {
+            return Err(ChoreographyError::VersionMismatch {
+                role: expected,
+                expected: format!("fingerprint {}", self.fingerprint),
+                found: format!("fingerprint {}", remote.fingerprint),
+            });
+        }
+        let codec = self
+            .codecs
+            .iter()
+            .find(|codec| remote.codecs.contains(codec))
+            .cloned()
+            .ok_or_else(|| ChoreographyError::VersionMismatch {
+                role: expected,
+                expected: format!("one of the codecs {}", self.codecs.join(", ")),
+                found: format!("codecs {}", remote.codecs.join(", ")),
+            })?;
+        let max_message_size = match (self.max_message_size, remote.max_message_size) {
+            (Some(local), Some(remote)) => Some(local.min(remote)),
+            (local, remote) => local.or(remote),
+        };
+        Ok(Agreement {
+            codec,
+            max_message_size,
+        })
+    }
+}
+
+/// Exchange introductions with `peers` before running a protocol
+///
+/// Sends `local` to every peer, then checks the introduction each peer
+/// sends back with [`Introduction::check`]. Fails on the first peer that
+/// claims a role other than the one it is reached as, with
+/// [`ChoreographyError::UnknownRole`], or that runs another fingerprint or
+/// shares no codec, with [`ChoreographyError::VersionMismatch`]. Returns
+/// the [`Agreement`] reached with each peer. The exchange is not part of
+/// the choreography, so run it on an endpoint that does not track a session
+/// type.
+pub async fn introduce<H: ChoreoHandler>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    peers: &[H::Role],
+    local: &Introduction,
+) -> Result<HashMap<H::Role, Agreement>> {
+    for peer in peers {
+        handler.send(endpoint, *peer, local).await?;
+    }
+    let mut agreements = HashMap::new();
+    for peer in peers {
+        let remote: Introduction = handler.recv(endpoint, *peer).await?;
+        let agreement = local.check(*peer, &remote)?;
+        tracing::debug!(
+            ?peer,
+            codec = %agreement.codec,
+            max_message_size = ?agreement.max_message_size,
+            "peer introduced itself"
+        );
+        agreements.insert(*peer, agreement);
+    }
+    Ok(agreements)
+}
@@ -0,0 +1,235 @@
+// Runtime membership for role groups
+//
+// A choreography declares `Worker[N]`, but a deployment may run however
+// many workers are alive. A membership view tracks which members of each
+// group have been heard from recently. Members refresh themselves with
+// heartbeats, either directly against a shared view (registry-backed) or by
+// exchanging digests with peers (gossip), where the higher heartbeat count
+// for a member wins. Departures are kept as tombstones so they spread too.
+// Scatter and gather programs are built from the live members, not from
+// the declared group size.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::effects::{ChoreoHandler, Program, Result, RoleId};
+
+type Groups<R> = BTreeMap<String, HashMap<R, MemberState>>;
+
+#[derive(Debug, Clone)]
+struct MemberState {
+    heartbeat: u64,
+    left: bool,
+    last_seen: Instant,
+    /// Position among the group's members when this view first heard of it
+    joined: usize,
+}
+
+/// One member's entry in a gossip digest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberDigest<R> {
+    pub group: String,
+    pub member: R,
+    pub heartbeat: u64,
+    pub left: bool,
+}
+
+/// Live members of role groups
+///
+/// A member is live if it joined, has not left, and was refreshed within
+/// the failure timeout. Clones share the same view.
+pub struct Membership<R> {
+    groups: Arc<RwLock<Groups<R>>>,
+    timeout: Duration,
+}
+
+impl<R> Clone for Membership<R> {
+    fn clone(&self) -> Self {
+        Self {
+            groups: Arc::clone(&self.groups),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<R: RoleId> Membership<R> {
+    /// Create an empty view; members not refreshed within `timeout` are
+    /// considered failed
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            groups: Arc::new(RwLock::new(BTreeMap::new())),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Add a member, or bring back one that left
+    pub fn join(&self, group: &str, member: R) {
+        self.update(group, member, false);
+        tracing::debug!(group, ?member, "member joined");
+    }
+
+    /// Refresh a member's liveness
+    pub fn heartbeat(&self, group: &str, member: R) {
+        self.update(group, member, false);
+    }
+
+    /// Mark a member as departed
+    pub fn leave(&self, group: &str, member: R) {
+        self.update(group, member, true);
+        tracing::debug!(group, ?member, "member left");
+    }
+
+    /// Live members of a group, in the order this view first heard of them
+    ///
+    /// A member that leaves and joins again keeps its place.
+    pub fn live(&self, group: &str) -> Vec<R> {
+        let now = Instant::now();
+        let groups = self.read();
+        let mut live: Vec<_> = groups
+            .get(group)
+            .into_iter()
+            .flatten()
+            .filter(|(_, state)| !state.left && now - state.last_seen <= self.timeout)
+            .collect();
+        live.sort_by_key(|(_, state)| state.joined);
+        live.into_iter().map(|(member, _)| *member).collect()
+    }
+
+    pub fn is_live(&self, group: &str, member: &R) -> bool {
+        let groups = self.read();
+        groups
+            .get(group)
+            .and_then(|members| members.get(member))
+            .is_some_and(|state| !state.left && state.last_seen.elapsed() <= self.timeout)
+    }
+
+    /// Smallest majority of the group's live members
+    ///
+    /// A group with no live members has a quorum of 0, since there is
+    /// nobody left to agree.
+    pub fn quorum(&self, group: &str) -> usize {
+        match self.live(group).len() {
+            0 => 0,
+            live => live / 2 + 1,
+        }
+    }
+
+    /// Program sending `msg` to every live member of `group`
+    ///
+    /// Membership is read when the program is built, so build it just
+    /// before interpreting.
+    pub fn scatter<M: Clone>(&self, group: &str, msg: M) -> Program<R, M> {
+        self.live(group)
+            .into_iter()
+            .fold(Program::new(), |program, member| {
+                program.send(member, msg.clone())
+            })
+    }
+
+    /// Program receiving a `T` from every live member of `group`
+    pub fn gather<T: 'static, M>(&self, group: &str) -> Program<R, M> {
+        self.live(group)
+            .into_iter()
+            .fold(Program::new(), |program, member| program.recv::<T>(member))
+    }
+
+    /// Everything this view knows, for sending to a peer
+    pub fn digest(&self) -> Vec<MemberDigest<R>> {
+        let groups = self.read();
+        groups
+            .iter()
+            .flat_map(|(group, members)| {
+                members.iter().map(move |(member, state)| MemberDigest {
+                    group: group.clone(),
+                    member: *member,
+                    heartbeat: state.heartbeat,
+                    left: state.left,
+                })
+            })
+            .collect()
+    }
+
+    /// Adopt every entry of a peer's digest that is newer than ours
+    ///
+    /// Returns the number of entries adopted.
+    pub fn merge(&self, digest: &[MemberDigest<R>]) -> usize {
+        let now = Instant::now();
+        let mut groups = self.write();
+        let mut adopted = 0;
+        for entry in digest {
+            let members = groups.entry(entry.group.clone()).or_default();
+            let joined = members.len();
+            let newer = members
+                .get(&entry.member)
+                .map_or(true, |state| entry.heartbeat > state.heartbeat);
+            if newer {
+                let state = members.entry(entry.member).or_insert(MemberState {
+                    heartbeat: 0,
+                    left: false,
+                    last_seen: now,
+                    joined,
+                });
+                state.heartbeat = entry.heartbeat;
+                state.left = entry.left;
+                state.last_seen = now;
+                adopted += 1;
+            }
+        }
+        adopted
+    }
+
+    /// One gossip round with `peer`: send our digest, then merge theirs
+    ///
+    /// Both sides must call this for the round to complete. Each node should
+    /// heartbeat itself before a round, or peers will time it out.
+    pub async fn gossip<H>(
+        &self,
+        handler: &mut H,
+        endpoint: &mut H::Endpoint,
+        peer: R,
+    ) -> Result<usize>
+    where
+        H: ChoreoHandler<Role = R>,
+        R: Serialize + DeserializeOwned + Send + Sync,
+    {
+        handler.send(endpoint, peer, &self.digest()).await?;
+        let digest: Vec<MemberDigest<R>> = handler.recv(endpoint, peer).await?;
+        let adopted = self.merge(&digest);
+        tracing::trace!(?peer, adopted, "gossip round");
+        Ok(adopted)
+    }
+
+    fn update(&self, group: &str, member: R, left: bool) {
+        let mut groups = self.write();
+        let members = groups.entry(group.to_string()).or_default();
+        let joined = members.len();
+        let state = members.entry(member).or_insert(MemberState {
+            heartbeat: 0,
+            left,
+            last_seen: Instant::now(),
+            joined,
+        });
+        state.heartbeat += 1;
+        state.left = left;
+        state.last_seen = Instant::now();
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Groups<R>> {
+        self.groups
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Groups<R>> {
+        self.groups
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,236 @@
+// Adaptive message batching middleware
+//
+// Holds back messages to the same peer and sends them as one batch, so a run
+// of small messages pays the transport's per-message cost once. A batch goes
+// out when it reaches its target size, when a send finds its oldest message
+// older than the maximum delay, or before the role waits on anything - a
+// receive, a branch, a timeout, cancellation or the end of the program - so a
+// held message never keeps a peer waiting.
+//
+// Each peer's target size follows how the role sends to it. A batch that fills
+// doubles the target, up to the maximum batch size; one that goes out by the
+// delay, or less than half full, halves it. Both ends of a channel must use
+// this middleware, as the inner handler carries batches rather than messages.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::effects::{ChoreoHandler, ChoreoHandlerExt, ChoreographyError, Label, Result};
+
+/// Default longest time a message is held back while the role keeps sending
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(5);
+
+/// Default largest number of messages sent as one batch
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 64;
+
+/// Messages held back for one peer
+struct Outgoing {
+    messages: Vec<Vec<u8>>,
+    oldest: Option<Instant>,
+    target: usize,
+}
+
+impl Outgoing {
+    fn new() -> Self {
+        Self {
+            messages: Vec::new(),
+            oldest: None,
+            target: 1,
+        }
+    }
+}
+
+/// Middleware that sends runs of messages to the same peer as one batch
+pub struct Batching<H: ChoreoHandler> {
+    inner: H,
+    max_delay: Duration,
+    max_batch_size: usize,
+    outgoing: HashMap<H::Role, Outgoing>,
+    incoming: HashMap<H::Role, VecDeque<Vec<u8>>>,
+}
+
+impl<H: ChoreoHandler> Batching<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            max_delay: DEFAULT_MAX_DELAY,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Send a batch once its oldest message has been held for `max_delay`
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Never send more than `max_batch_size` messages as one batch
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size.max(1);
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// How many messages to `peer` the next batch waits for
+    pub fn batch_size(&self, peer: &H::Role) -> usize {
+        self.outgoing.get(peer).map_or(1, |batch| batch.target)
+    }
+
+    /// How many messages to `peer` are being held back
+    pub fn pending(&self, peer: &H::Role) -> usize {
+        self.outgoing
+            .get(peer)
+            .map_or(0, |batch| batch.messages.len())
+    }
+
+    /// Send the batch held for `peer`, if any
+    async fn send_batch(&mut self, ep: &mut H::Endpoint, peer: H::Role) -> Result<()> {
+        let Some(batch) = self.outgoing.get_mut(&peer) else {
+            return Ok(());
+        };
+        if batch.messages.is_empty() {
+            return Ok(());
+        }
+        let messages = std::mem::take(&mut batch.messages);
+        batch.oldest = None;
+        tracing::trace!(?peer, size = messages.len(), "sending batch");
+        self.inner.send(ep, peer, &messages).await
+    }
+
+    /// Send every batch held back, before the role waits on anything
+    async fn send_all(&mut self, ep: &mut H::Endpoint) -> Result<()> {
+        let peers: Vec<_> = self
+            .outgoing
+            .iter()
+            .filter(|(_, batch)| !batch.messages.is_empty())
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in peers {
+            // Sent early, so a batch well short of its target was too large
+            if let Some(batch) = self.outgoing.get_mut(&peer) {
+                if batch.messages.len() * 2 < batch.target {
+                    batch.target = (batch.target / 2).max(1);
+                }
+            }
+            self.send_batch(ep, peer).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Batching<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload =
+            bincode::serialize(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let max_delay = self.max_delay;
+        let max_batch_size = self.max_batch_size;
+        let batch = self.outgoing.entry(to).or_insert_with(Outgoing::new);
+        batch.messages.push(payload);
+        let oldest = *batch.oldest.get_or_insert_with(Instant::now);
+
+        if batch.messages.len() >= batch.target {
+            batch.target = (batch.target * 2).min(max_batch_size);
+        } else if oldest.elapsed() >= max_delay {
+            batch.target = (batch.target / 2).max(1);
+        } else {
+            return Ok(());
+        }
+        self.send_batch(ep, to).await
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let queued = self
+            .incoming
+            .get_mut(&from)
+            .and_then(|inbox| inbox.pop_front());
+        let payload = match queued {
+            Some(payload) => payload,
+            None => {
+                self.send_all(ep).await?;
+                let batch: Vec<Vec<u8>> = self.inner.recv(ep, from).await?;
+                let inbox = self.incoming.entry(from).or_default();
+                inbox.extend(batch);
+                inbox.pop_front().ok_or_else(|| {
+                    ChoreographyError::Transport(format!("empty batch from {:?}", from))
+                })?
+            }
+        };
+        bincode::deserialize(&payload).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.send_all(ep).await?;
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.send_all(ep).await?;
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.send_all(ep).await?;
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.send_all(ep).await?;
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.send_all(ep).await?;
+        self.inner.flush(ep).await
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandlerExt + Send> ChoreoHandlerExt for Batching<H> {
+    async fn setup(&mut self, role: Self::Role) -> Result<Self::Endpoint> {
+        self.inner.setup(role).await
+    }
+
+    async fn teardown(&mut self, mut ep: Self::Endpoint) -> Result<()> {
+        self.send_all(&mut ep).await?;
+        self.incoming.clear();
+        self.inner.teardown(ep).await
+    }
+}
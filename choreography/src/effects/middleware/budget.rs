@@ -0,0 +1,239 @@
+// Resource budget middleware for effect handlers
+//
+// Accounts the effects, bytes, and wall time a session uses and fails the
+// next operation once a limit is crossed, so the program takes its error
+// path (and runs any `finally` cleanup) instead of running unbounded.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// Limits for one session; `None` means unlimited
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BudgetLimits {
+    /// Sends, receives, choices, and offers combined
+    pub max_effects: Option<u64>,
+    /// Serialized size of all sent messages
+    pub max_bytes_sent: Option<u64>,
+    pub max_messages_received: Option<u64>,
+    /// Time since the first operation
+    pub max_wall_time: Option<Duration>,
+}
+
+impl BudgetLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_effects(mut self, max: u64) -> Self {
+        self.max_effects = Some(max);
+        self
+    }
+
+    pub fn with_max_bytes_sent(mut self, max: u64) -> Self {
+        self.max_bytes_sent = Some(max);
+        self
+    }
+
+    pub fn with_max_messages_received(mut self, max: u64) -> Self {
+        self.max_messages_received = Some(max);
+        self
+    }
+
+    pub fn with_max_wall_time(mut self, max: Duration) -> Self {
+        self.max_wall_time = Some(max);
+        self
+    }
+}
+
+/// Resources used so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BudgetUsage {
+    pub effects: u64,
+    pub bytes_sent: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Time since the first operation
+    pub elapsed: Duration,
+}
+
+#[derive(Default)]
+struct Account {
+    usage: BudgetUsage,
+    started: Option<Instant>,
+}
+
+/// Budget middleware
+///
+/// Wrap one handler per session. Limits are checked before each operation
+/// is passed on, counting the operation itself and the size of the message
+/// about to be sent. An operation that would go over a limit fails with
+/// [`ChoreographyError::BudgetExceeded`] and never reaches the transport.
+///
+/// Received bytes are not accounted: `recv` only sees the deserialized
+/// value, so the received message count is the receive-side limit.
+/// Clones share the same account.
+#[derive(Clone)]
+pub struct Budget<H> {
+    inner: H,
+    limits: BudgetLimits,
+    account: Arc<Mutex<Account>>,
+}
+
+impl<H> Budget<H> {
+    pub fn new(inner: H, limits: BudgetLimits) -> Self {
+        Self {
+            inner,
+            limits,
+            account: Arc::new(Mutex::new(Account::default())),
+        }
+    }
+
+    pub fn limits(&self) -> &BudgetLimits {
+        &self.limits
+    }
+
+    /// Snapshot of the resources used so far
+    pub fn usage(&self) -> BudgetUsage {
+        let account = self.lock();
+        let mut usage = account.usage.clone();
+        usage.elapsed = account
+            .started
+            .map(|started| started.elapsed())
+            .unwrap_or_default();
+        usage
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Account> {
+        self.account
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Start an operation, charging one effect and `bytes` sent bytes
+    fn charge(&self, bytes: u64) -> Result<()> {
+        let mut account = self.lock();
+        let started = *account.started.get_or_insert_with(Instant::now);
+
+        if let Some(max) = self.limits.max_wall_time {
+            let elapsed = started.elapsed();
+            if elapsed > max {
+                return Err(exceeded(
+                    "wall time (ms)",
+                    max.as_millis() as u64,
+                    elapsed.as_millis() as u64,
+                ));
+            }
+        }
+        check(
+            "effects",
+            self.limits.max_effects,
+            account.usage.effects + 1,
+        )?;
+        check(
+            "bytes sent",
+            self.limits.max_bytes_sent,
+            account.usage.bytes_sent + bytes,
+        )?;
+
+        account.usage.effects += 1;
+        account.usage.bytes_sent += bytes;
+        Ok(())
+    }
+
+    fn charge_receive(&self) -> Result<()> {
+        let mut account = self.lock();
+        check(
+            "messages received",
+            self.limits.max_messages_received,
+            account.usage.messages_received + 1,
+        )?;
+        account.usage.messages_received += 1;
+        Ok(())
+    }
+}
+
+fn check(resource: &'static str, limit: Option<u64>, used: u64) -> Result<()> {
+    match limit {
+        Some(limit) if used > limit => Err(exceeded(resource, limit, used)),
+        _ => Ok(()),
+    }
+}
+
+fn exceeded(resource: &'static str, limit: u64, used: u64) -> ChoreographyError {
+    tracing::warn!(resource, limit, used, "session budget exceeded");
+    ChoreographyError::BudgetExceeded {
+        resource,
+        limit,
+        used,
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Budget<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let size = bincode::serialized_size(msg)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        self.charge(size)?;
+        self.inner.send(ep, to, msg).await?;
+        self.lock().usage.messages_sent += 1;
+        Ok(())
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        self.charge(0)?;
+        self.charge_receive()?;
+        self.inner.recv(ep, from).await
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.charge(0)?;
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.charge(0)?;
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
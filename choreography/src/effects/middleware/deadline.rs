@@ -0,0 +1,222 @@
+// Deadline propagation middleware
+//
+// Stamps every message with the absolute time by which the whole
+// choreography must finish, and gives every send and receive only the time
+// that is left. A role without a deadline of its own adopts the one on the
+// first message it receives, and a role with a later one moves it forward,
+// so a deadline set where a request enters the system holds at every hop.
+// Once it passes, operations fail at once with `Timeout` instead of waiting
+// on peers that have already given up.
+//
+// Deadlines are wall-clock times, so the roles' clocks must roughly agree.
+// Branch labels carry no stamp, though offers are still cut short.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// A serialized message and the deadline it was sent under, as the inner
+/// handler carries it
+#[derive(Serialize, Deserialize)]
+struct Stamped {
+    /// Milliseconds since the Unix epoch
+    deadline: Option<u64>,
+    payload: Vec<u8>,
+}
+
+/// Middleware that propagates a deadline with messages and enforces it
+pub struct Deadline<H: ChoreoHandler> {
+    inner: H,
+    deadline: Option<SystemTime>,
+    /// Time the role had when it took on the deadline, for errors
+    budget: Duration,
+}
+
+impl<H: ChoreoHandler> Deadline<H> {
+    /// Wrap `inner` without a deadline; it takes one from the first stamped
+    /// message it receives
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            deadline: None,
+            budget: Duration::ZERO,
+        }
+    }
+
+    /// Finish within `budget` from now
+    pub fn with_budget(self, budget: Duration) -> Self {
+        self.with_deadline(SystemTime::now() + budget)
+    }
+
+    /// Finish by `deadline`
+    pub fn with_deadline(mut self, deadline: SystemTime) -> Self {
+        self.set_deadline(deadline);
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// The deadline in force, if any
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    /// Time left before the deadline; zero once it has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    fn set_deadline(&mut self, deadline: SystemTime) {
+        self.deadline = Some(deadline);
+        self.budget = deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+    }
+
+    /// Time left, or `Timeout` if there is none
+    fn check(&self) -> Result<Option<Duration>> {
+        match self.remaining() {
+            Some(remaining) if remaining.is_zero() => Err(ChoreographyError::Timeout(self.budget)),
+            remaining => Ok(remaining),
+        }
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Deadline<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let remaining = self.check()?;
+        let stamped = Stamped {
+            deadline: self
+                .deadline
+                .and_then(|deadline| deadline.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_millis() as u64),
+            payload: bincode::serialize(msg)
+                .map_err(|e| ChoreographyError::Serialization(e.to_string()))?,
+        };
+        let send = self.inner.send(ep, to, &stamped);
+        match remaining {
+            Some(remaining) => bounded(remaining, self.budget, send).await,
+            None => send.await,
+        }
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let remaining = self.check()?;
+        let recv = self.inner.recv::<Stamped>(ep, from);
+        let stamped = match remaining {
+            Some(remaining) => bounded(remaining, self.budget, recv).await?,
+            None => recv.await?,
+        };
+
+        // The sender's deadline binds this role too
+        if let Some(millis) = stamped.deadline {
+            let deadline = UNIX_EPOCH + Duration::from_millis(millis);
+            if self.deadline.map_or(true, |current| deadline < current) {
+                tracing::debug!(?from, ?deadline, "deadline taken from peer");
+                self.set_deadline(deadline);
+            }
+        }
+        if self.check().is_err() {
+            tracing::warn!(?from, "deadline passed before the message arrived");
+            return Err(ChoreographyError::Timeout(self.budget));
+        }
+        bincode::deserialize(&stamped.payload)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.check()?;
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        let remaining = self.check()?;
+        let offer = self.inner.offer(ep, from);
+        match remaining {
+            Some(remaining) => bounded(remaining, self.budget, offer).await,
+            None => offer.await,
+        }
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
+
+/// Run `operation`, failing with `Timeout(budget)` if it takes longer than
+/// `remaining`
+async fn bounded<T>(
+    remaining: Duration,
+    budget: Duration,
+    operation: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        match tokio::time::timeout(remaining, operation).await {
+            Ok(result) => result,
+            Err(_) => Err(ChoreographyError::Timeout(budget)),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        use futures::future::{select, Either};
+        use futures::pin_mut;
+
+        let timeout = wasm_timer::Delay::new(remaining);
+        pin_mut!(operation);
+        pin_mut!(timeout);
+        match select(operation, timeout).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(ChoreographyError::Timeout(budget)),
+        }
+    }
+}
@@ -0,0 +1,369 @@
+// Payload encryption middleware
+//
+// Encrypts every message with ChaCha20-Poly1305 under a key shared with the
+// peer alone, so a choreography can run over a transport that anyone may
+// read. Keys are agreed at setup: each role sends each peer a fresh X25519
+// key signed with its role identity, and both sides derive one key per
+// direction from the Diffie-Hellman secret. A peer whose certificate was not
+// issued by the authority, or that signs for another role, fails setup.
+//
+// Nonces count the messages sent in each direction. A message that was
+// altered, dropped, replayed or reordered does not decrypt, and fails with
+// `IntegrityViolation`. Branch labels still travel in the clear for the
+// inner handler to branch on, but each is followed by a sealed copy in the
+// same count, and a label that does not match its copy fails the same way.
+
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::Duration;
+use x25519_dalek::{PublicKey as ExchangeKey, StaticSecret};
+
+use crate::effects::{
+    ChoreoHandler, ChoreoHandlerExt, ChoreographyError, Label, PublicKey, Result, RoleCertificate,
+    RoleIdentity,
+};
+
+/// Prefix of every signed key offer and of the key derivation input
+const DOMAIN: &[u8] = b"rumpsteak encrypted v1";
+
+/// Associated data of sealed labels, so no message passes for one
+const LABEL: &[u8] = b"label";
+
+/// A role's half of the key exchange with one peer
+#[derive(Serialize, Deserialize)]
+struct KeyOffer {
+    certificate: RoleCertificate,
+    ephemeral: [u8; 32],
+    signature: Vec<u8>,
+}
+
+/// An encrypted message, as the inner handler carries it
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    ciphertext: Vec<u8>,
+}
+
+/// Keys and message counts for one peer
+struct Channel {
+    outgoing: ChaCha20Poly1305,
+    incoming: ChaCha20Poly1305,
+    sent: u64,
+    received: u64,
+}
+
+/// Middleware that encrypts messages with keys agreed with each peer
+pub struct Encrypted<H: ChoreoHandler> {
+    inner: H,
+    identity: RoleIdentity,
+    authority: PublicKey,
+    peers: Vec<H::Role>,
+    channels: HashMap<H::Role, Channel>,
+}
+
+impl<H: ChoreoHandler> Encrypted<H> {
+    /// Wrap `inner` for the role `identity` belongs to, trusting peers
+    /// certified by `authority`
+    pub fn new(inner: H, identity: RoleIdentity, authority: PublicKey) -> Self {
+        Self {
+            inner,
+            identity,
+            authority,
+            peers: Vec::new(),
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Agree keys with `peers` during setup
+    pub fn with_peers(mut self, peers: impl IntoIterator<Item = H::Role>) -> Self {
+        self.peers = peers.into_iter().collect();
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// Whether keys have been agreed with `peer`
+    pub fn is_established(&self, peer: &H::Role) -> bool {
+        self.channels.contains_key(peer)
+    }
+
+    /// Agree fresh keys with every peer over `ep`
+    ///
+    /// [`setup`](ChoreoHandlerExt::setup) does this once the inner handler
+    /// is set up; call it directly for handlers without a setup phase. Each
+    /// peer must run the exchange at the same time. Fails with
+    /// [`ChoreographyError::AuthenticationFailed`] naming the first peer
+    /// whose offer is not signed by an identity the authority certified
+    /// for that role. The exchange is not part of the choreography, so run
+    /// it on an endpoint that does not track a session type.
+    pub async fn exchange_keys(&mut self, ep: &mut H::Endpoint) -> Result<()> {
+        let local = self.identity.role().to_string();
+        let mut secrets = HashMap::new();
+        for peer in self.peers.clone() {
+            let secret = StaticSecret::from(random_bytes()?);
+            let ephemeral = ExchangeKey::from(&secret).to_bytes();
+            let offer = KeyOffer {
+                certificate: self.identity.certificate().clone(),
+                ephemeral,
+                signature: self.identity.sign(&offer_bytes(
+                    &local,
+                    &format!("{:?}", peer),
+                    &ephemeral,
+                )),
+            };
+            self.inner.send(ep, peer, &offer).await?;
+            secrets.insert(peer, (secret, ephemeral));
+        }
+
+        for peer in self.peers.clone() {
+            let name = format!("{:?}", peer);
+            let offer: KeyOffer = self.inner.recv(ep, peer).await?;
+            let signed = offer_bytes(&name, &local, &offer.ephemeral);
+            RoleIdentity::verify(
+                &offer.certificate,
+                &name,
+                &self.authority,
+                &signed,
+                &offer.signature,
+            )
+            .map_err(|e| {
+                tracing::warn!(?peer, error = %e, "key offer rejected");
+                ChoreographyError::AuthenticationFailed {
+                    role: name.clone(),
+                    reason: e.to_string(),
+                }
+            })?;
+
+            let (secret, ephemeral) = &secrets[&peer];
+            let shared = secret.diffie_hellman(&ExchangeKey::from(offer.ephemeral));
+            if !shared.was_contributory() {
+                return Err(ChoreographyError::AuthenticationFailed {
+                    role: name,
+                    reason: "key offer is a low-order point".to_string(),
+                });
+            }
+            let outgoing = derive_key(
+                shared.as_bytes(),
+                &local,
+                &name,
+                ephemeral,
+                &offer.ephemeral,
+            );
+            let incoming = derive_key(
+                shared.as_bytes(),
+                &name,
+                &local,
+                &offer.ephemeral,
+                ephemeral,
+            );
+            tracing::debug!(?peer, "session keys agreed");
+            self.channels.insert(
+                peer,
+                Channel {
+                    outgoing,
+                    incoming,
+                    sent: 0,
+                    received: 0,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn channel(&mut self, peer: H::Role) -> Result<&mut Channel> {
+        self.channels
+            .get_mut(&peer)
+            .ok_or_else(|| ChoreographyError::AuthenticationFailed {
+                role: format!("{:?}", peer),
+                reason: "no keys have been agreed with this role".to_string(),
+            })
+    }
+
+    /// Encrypt `msg` as the next message to `to` and send it
+    async fn seal(
+        &mut self,
+        ep: &mut H::Endpoint,
+        to: H::Role,
+        msg: &[u8],
+        aad: &[u8],
+    ) -> Result<()>
+    where
+        H: Send,
+    {
+        let channel = self.channel(to)?;
+        let ciphertext = channel
+            .outgoing
+            .encrypt(&nonce(channel.sent), Payload { msg, aad })
+            .map_err(|_| ChoreographyError::Serialization("encryption failed".to_string()))?;
+        self.inner.send(ep, to, &Sealed { ciphertext }).await?;
+        // A message that never left does not use up its nonce, or the peer
+        // could decrypt nothing after it
+        self.channel(to)?.sent += 1;
+        Ok(())
+    }
+
+    /// Receive the next message from `from` and decrypt it
+    async fn open(&mut self, ep: &mut H::Endpoint, from: H::Role, aad: &[u8]) -> Result<Vec<u8>>
+    where
+        H: Send,
+    {
+        self.channel(from)?;
+        let sealed: Sealed = self.inner.recv(ep, from).await?;
+        let channel = self.channel(from)?;
+        let msg = channel
+            .incoming
+            .decrypt(
+                &nonce(channel.received),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                tracing::warn!(?from, "message failed to decrypt");
+                ChoreographyError::IntegrityViolation {
+                    role: format!("{:?}", from),
+                    reason: "message does not decrypt".to_string(),
+                }
+            })?;
+        channel.received += 1;
+        Ok(msg)
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Encrypted<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload =
+            bincode::serialize(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        self.seal(ep, to, &payload, &[]).await
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let payload = self.open(ep, from, &[]).await?;
+        bincode::deserialize(&payload).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.channel(who)?;
+        self.inner.choose(ep, who, label).await?;
+        self.seal(ep, who, label.0.as_bytes(), LABEL).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.channel(from)?;
+        let label = self.inner.offer(ep, from).await?;
+        let sealed = self.open(ep, from, LABEL).await?;
+        if sealed != label.0.as_bytes() {
+            tracing::warn!(?from, ?label, "label does not match its sealed copy");
+            return Err(ChoreographyError::IntegrityViolation {
+                role: format!("{:?}", from),
+                reason: "label does not match its sealed copy".to_string(),
+            });
+        }
+        Ok(label)
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandlerExt + Send> ChoreoHandlerExt for Encrypted<H> {
+    async fn setup(&mut self, role: Self::Role) -> Result<Self::Endpoint> {
+        let mut ep = self.inner.setup(role).await?;
+        self.exchange_keys(&mut ep).await?;
+        Ok(ep)
+    }
+
+    async fn teardown(&mut self, ep: Self::Endpoint) -> Result<()> {
+        self.channels.clear();
+        self.inner.teardown(ep).await
+    }
+}
+
+/// What a role signs to offer `ephemeral` to `to`
+fn offer_bytes(from: &str, to: &str, ephemeral: &[u8; 32]) -> Vec<u8> {
+    let mut bytes = DOMAIN.to_vec();
+    for name in [from, to] {
+        bytes.extend_from_slice(&(name.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes.extend_from_slice(ephemeral);
+    bytes
+}
+
+/// The key for messages from `from` to `to`
+fn derive_key(
+    shared: &[u8; 32],
+    from: &str,
+    to: &str,
+    from_ephemeral: &[u8; 32],
+    to_ephemeral: &[u8; 32],
+) -> ChaCha20Poly1305 {
+    let mut info = offer_bytes(from, to, from_ephemeral);
+    info.extend_from_slice(to_ephemeral);
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(&key)
+}
+
+/// The nonce of the `count`th message in one direction
+fn nonce(count: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..8].copy_from_slice(&count.to_le_bytes());
+    nonce
+}
+
+fn random_bytes() -> Result<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| ChoreographyError::Transport(e.to_string()))?;
+    Ok(bytes)
+}
@@ -1,6 +1,7 @@
 // Fault injection middleware for testing
 //
-// Allows injecting failures and delays for chaos engineering and testing.
+// Allows injecting failures and delays for chaos engineering and testing,
+// either at random or following a scripted FaultScenario.
 
 #[cfg(feature = "test-utils")]
 use async_trait::async_trait;
@@ -10,7 +11,9 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::time::Duration;
 
 #[cfg(feature = "test-utils")]
-use crate::effects::{ChoreoHandler, Label, Result};
+use super::fault_scenario::{FaultScenario, FaultSchedule};
+#[cfg(feature = "test-utils")]
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
 
 /// Fault injection middleware for testing
 #[cfg(feature = "test-utils")]
@@ -19,6 +22,7 @@ pub struct FaultInjection<H> {
     failure_rate: f32,
     delay_range: Option<(Duration, Duration)>,
     rng: rand::rngs::StdRng,
+    schedule: Option<FaultSchedule>,
 }
 
 #[cfg(feature = "test-utils")]
@@ -30,6 +34,7 @@ impl<H> FaultInjection<H> {
             failure_rate,
             delay_range: None,
             rng: rand::rngs::StdRng::from_entropy(),
+            schedule: None,
         }
     }
 
@@ -37,6 +42,40 @@ impl<H> FaultInjection<H> {
         self.delay_range = Some((min, max));
         self
     }
+
+    /// Also inject the faults of `scenario`, as seen by the role `local`
+    pub fn with_scenario(mut self, scenario: FaultScenario, local: impl std::fmt::Debug) -> Self {
+        self.schedule = Some(FaultSchedule::new(scenario, format!("{:?}", local)));
+        self
+    }
+
+    fn check_partition(&mut self, peer: impl std::fmt::Debug) -> Result<()> {
+        let peer = format!("{:?}", peer);
+        let partitioned = self
+            .schedule
+            .as_mut()
+            .is_some_and(|schedule| schedule.is_partitioned(&peer));
+        if partitioned {
+            return Err(ChoreographyError::Transport(format!(
+                "Injected fault: partitioned from {}",
+                peer
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "test-utils")]
+async fn sleep(delay: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tokio::time::sleep(delay).await;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        wasm_timer::Delay::new(delay).await.ok();
+    }
 }
 
 #[cfg(feature = "test-utils")]
@@ -56,24 +95,32 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for FaultInjection<H> {
         // Inject random delay
         if let Some((min, max)) = self.delay_range {
             let delay_ms = self.rng.gen_range(min.as_millis()..=max.as_millis());
-            let delay = Duration::from_millis(delay_ms as u64);
-
-            #[cfg(not(target_arch = "wasm32"))]
-            {
-                tokio::time::sleep(delay).await;
-            }
-
-            #[cfg(target_arch = "wasm32")]
-            {
-                wasm_timer::Delay::new(delay).await.ok();
-            }
+            sleep(Duration::from_millis(delay_ms as u64)).await;
         }
 
         // Inject random failure
         if self.rng.gen::<f32>() < self.failure_rate {
-            return Err(crate::effects::ChoreographyError::Transport(
-                "Injected fault".into(),
-            ));
+            return Err(ChoreographyError::Transport("Injected fault".into()));
+        }
+
+        // Inject scripted faults
+        if let Some(schedule) = &mut self.schedule {
+            let peer = format!("{:?}", to);
+            let faults = schedule.on_send(&peer);
+            if let Some(delay) = faults.delay {
+                sleep(delay).await;
+            }
+            if faults.fail {
+                return Err(ChoreographyError::Transport(format!(
+                    "Injected fault: message {} to {}",
+                    schedule.sent(),
+                    peer
+                )));
+            }
+            if faults.drop {
+                tracing::debug!(?to, message = schedule.sent(), "Injected fault: dropped");
+                return Ok(());
+            }
         }
 
         self.inner.send(ep, to, msg).await
@@ -84,6 +131,7 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for FaultInjection<H> {
         ep: &mut Self::Endpoint,
         from: Self::Role,
     ) -> Result<M> {
+        self.check_partition(from)?;
         self.inner.recv(ep, from).await
     }
 
@@ -93,10 +141,12 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for FaultInjection<H> {
         who: Self::Role,
         label: Label,
     ) -> Result<()> {
+        self.check_partition(who)?;
         self.inner.choose(ep, who, label).await
     }
 
     async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.check_partition(from)?;
         self.inner.offer(ep, from).await
     }
 
@@ -112,4 +162,12 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for FaultInjection<H> {
     {
         self.inner.with_timeout(ep, at, dur, body).await
     }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
 }
@@ -0,0 +1,377 @@
+// Scripted fault scenarios
+//
+// Chaos tests read better as a list of timed faults than as code that
+// counts messages by hand. A scenario is a sequence of steps, each a
+// trigger and an action, written one per line or separated by `;`:
+//
+//     at message 3 drop
+//     at message 5 delay 200ms
+//     at 2s partition {Alice} from {Bob, Carol} for 5s
+//     at 10s fail
+//
+// Scenarios can also be loaded from any serde format, so teams can keep
+// them next to the tests that share them. A `FaultSchedule` tracks one
+// handler's progress through a scenario and says which faults apply to
+// each operation.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// When a step takes effect
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultTrigger {
+    /// On the n-th message sent, counting from 1
+    Message(u64),
+    /// On the first message sent once this much time has passed since the
+    /// handler's first operation
+    After(Duration),
+}
+
+/// What happens when a step's trigger fires
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultAction {
+    /// The message is discarded without an error, as if lost on the wire
+    Drop,
+    /// The send fails with a transport error
+    Fail,
+    /// The message is held back before it is sent
+    Delay(Duration),
+    /// Operations between the two groups fail until the duration elapses
+    Partition {
+        side: BTreeSet<String>,
+        other: BTreeSet<String>,
+        duration: Duration,
+    },
+}
+
+/// One line of a scenario
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaultStep {
+    pub trigger: FaultTrigger,
+    pub action: FaultAction,
+}
+
+/// A sequence of timed faults
+///
+/// Roles are named as they print with `Debug`, so `Role::Alice` is `Alice`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaultScenario {
+    pub steps: Vec<FaultStep>,
+}
+
+/// A scenario that does not parse
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("fault scenario step {step}: {message}")]
+pub struct ScenarioError {
+    /// 1-based position of the step in the script
+    pub step: usize,
+    pub message: String,
+}
+
+impl FaultScenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, trigger: FaultTrigger, action: FaultAction) -> Self {
+        self.steps.push(FaultStep { trigger, action });
+        self
+    }
+
+    /// Parse the scenario language described in the module docs
+    pub fn parse(script: &str) -> Result<Self, ScenarioError> {
+        let steps = script
+            .split(['\n', ';'])
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                parse_step(line).map_err(|message| ScenarioError {
+                    step: i + 1,
+                    message,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { steps })
+    }
+}
+
+impl FromStr for FaultScenario {
+    type Err = ScenarioError;
+
+    fn from_str(script: &str) -> Result<Self, Self::Err> {
+        Self::parse(script)
+    }
+}
+
+impl fmt::Display for FaultScenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            match &step.trigger {
+                FaultTrigger::Message(n) => write!(f, "at message {} ", n)?,
+                FaultTrigger::After(t) => write!(f, "at {} ", format_duration(*t))?,
+            }
+            match &step.action {
+                FaultAction::Drop => writeln!(f, "drop")?,
+                FaultAction::Fail => writeln!(f, "fail")?,
+                FaultAction::Delay(d) => writeln!(f, "delay {}", format_duration(*d))?,
+                FaultAction::Partition {
+                    side,
+                    other,
+                    duration,
+                } => writeln!(
+                    f,
+                    "partition {{{}}} from {{{}}} for {}",
+                    join(side),
+                    join(other),
+                    format_duration(*duration)
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_step(line: &str) -> Result<FaultStep, String> {
+    let rest = line
+        .strip_prefix("at ")
+        .ok_or_else(|| format!("expected `at`, found `{}`", line))?
+        .trim_start();
+
+    let (trigger, rest) = if let Some(rest) = rest.strip_prefix("message ") {
+        let (n, rest) = next_word(rest);
+        let n = n
+            .parse::<u64>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("invalid message number `{}`", n))?;
+        (FaultTrigger::Message(n), rest)
+    } else {
+        let (t, rest) = next_word(rest);
+        (FaultTrigger::After(parse_duration(t)?), rest)
+    };
+
+    let (verb, rest) = next_word(rest);
+    let action = match verb {
+        "drop" => FaultAction::Drop,
+        "fail" => FaultAction::Fail,
+        "delay" => {
+            let (d, rest) = next_word(rest);
+            expect_end(rest)?;
+            return Ok(FaultStep {
+                trigger,
+                action: FaultAction::Delay(parse_duration(d)?),
+            });
+        }
+        "partition" => {
+            let (side, rest) = parse_group(rest)?;
+            let rest = rest
+                .trim_start()
+                .strip_prefix("from")
+                .ok_or("expected `from` after the first partition group")?;
+            let (other, rest) = parse_group(rest)?;
+            let rest = rest
+                .trim_start()
+                .strip_prefix("for ")
+                .ok_or("expected `for <duration>` after the partition groups")?;
+            let (d, rest) = next_word(rest);
+            expect_end(rest)?;
+            if let Some(role) = side.intersection(&other).next() {
+                return Err(format!("{} is on both sides of the partition", role));
+            }
+            return Ok(FaultStep {
+                trigger,
+                action: FaultAction::Partition {
+                    side,
+                    other,
+                    duration: parse_duration(d)?,
+                },
+            });
+        }
+        "" => return Err("missing action".to_string()),
+        other => {
+            return Err(format!(
+                "unknown action `{}`, expected drop, fail, delay, or partition",
+                other
+            ))
+        }
+    };
+    expect_end(rest)?;
+    Ok(FaultStep { trigger, action })
+}
+
+fn next_word(input: &str) -> (&str, &str) {
+    let input = input.trim_start();
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    (&input[..end], &input[end..])
+}
+
+fn expect_end(rest: &str) -> Result<(), String> {
+    match rest.trim() {
+        "" => Ok(()),
+        extra => Err(format!("unexpected `{}`", extra)),
+    }
+}
+
+fn parse_group(input: &str) -> Result<(BTreeSet<String>, &str), String> {
+    let input = input
+        .trim_start()
+        .strip_prefix('{')
+        .ok_or("expected `{` to start a role group")?;
+    let end = input.find('}').ok_or("unclosed role group")?;
+    let roles: BTreeSet<String> = input[..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|role| !role.is_empty())
+        .map(str::to_string)
+        .collect();
+    if roles.is_empty() {
+        return Err("empty role group".to_string());
+    }
+    Ok((roles, &input[end + 1..]))
+}
+
+/// Parse `250ms`, `2s`, or `1m`
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "invalid duration `{}`, expected e.g. 250ms, 2s, or 1m",
+            text
+        )
+    };
+    let (digits, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit()).unwrap_or(0));
+    let n: u64 = digits.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        _ => Err(invalid()),
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    if d.subsec_millis() == 0 && d.as_secs() > 0 {
+        format!("{}s", d.as_secs())
+    } else {
+        format!("{}ms", d.as_millis())
+    }
+}
+
+fn join(roles: &BTreeSet<String>) -> String {
+    roles
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Faults that apply to one send
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SendFaults {
+    pub delay: Option<Duration>,
+    pub drop: bool,
+    pub fail: bool,
+}
+
+/// One handler's progress through a scenario
+///
+/// The clock starts at the first operation the schedule sees.
+#[derive(Debug, Clone)]
+pub struct FaultSchedule {
+    scenario: FaultScenario,
+    local: String,
+    started: Option<Instant>,
+    sent: u64,
+    fired: Vec<Option<Instant>>,
+}
+
+impl FaultSchedule {
+    /// Follow `scenario` for the handler of the role named `local`
+    pub fn new(scenario: FaultScenario, local: impl Into<String>) -> Self {
+        Self {
+            fired: vec![None; scenario.steps.len()],
+            scenario,
+            local: local.into(),
+            started: None,
+            sent: 0,
+        }
+    }
+
+    pub fn scenario(&self) -> &FaultScenario {
+        &self.scenario
+    }
+
+    /// Messages sent so far
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Count a message to `to` and return the faults that apply to it
+    pub fn on_send(&mut self, to: &str) -> SendFaults {
+        let now = Instant::now();
+        let elapsed = now - *self.started.get_or_insert(now);
+        self.sent += 1;
+
+        let mut faults = SendFaults::default();
+        for (step, fired) in self.scenario.steps.iter().zip(&mut self.fired) {
+            let due = match step.trigger {
+                FaultTrigger::Message(n) => self.sent == n,
+                FaultTrigger::After(t) => fired.is_none() && elapsed >= t,
+            };
+            if !due {
+                continue;
+            }
+            *fired = Some(now);
+            match &step.action {
+                FaultAction::Drop => faults.drop = true,
+                FaultAction::Fail => faults.fail = true,
+                FaultAction::Delay(d) => faults.delay = Some(faults.delay.unwrap_or_default() + *d),
+                FaultAction::Partition { .. } => {}
+            }
+        }
+        if self.is_partitioned_at(to, now) {
+            faults.fail = true;
+        }
+        faults
+    }
+
+    /// Whether a partition separates the local role from `peer` right now
+    ///
+    /// Partitions with a time trigger begin at their offset even if no
+    /// message has been sent since.
+    pub fn is_partitioned(&mut self, peer: &str) -> bool {
+        let now = Instant::now();
+        self.started.get_or_insert(now);
+        self.is_partitioned_at(peer, now)
+    }
+
+    fn is_partitioned_at(&self, peer: &str, now: Instant) -> bool {
+        let local = self.local.as_str();
+        self.scenario
+            .steps
+            .iter()
+            .zip(&self.fired)
+            .any(|(step, fired)| match &step.action {
+                FaultAction::Partition {
+                    side,
+                    other,
+                    duration,
+                } => {
+                    let began = match (&step.trigger, fired, self.started) {
+                        (FaultTrigger::After(t), _, Some(started)) => Some(started + *t),
+                        (FaultTrigger::Message(_), Some(fired), _) => Some(*fired),
+                        _ => None,
+                    };
+                    let separated = (side.contains(local) && other.contains(peer))
+                        || (other.contains(local) && side.contains(peer));
+                    separated && began.is_some_and(|began| now >= began && now < began + *duration)
+                }
+                _ => false,
+            })
+    }
+}
@@ -0,0 +1,203 @@
+// Message provenance middleware
+//
+// Sends every message with the trail of roles it has passed through and the
+// time each one sent it on. A role set to forward, such as a relay in a ring
+// or a stage of a pipeline, carries the trail of the last message it received
+// on to the next message it sends, with itself added; any other role starts a
+// new trail from itself. The receiver can ask where the latest message from
+// each peer came from, and every trail received is logged for audit.
+//
+// Timestamps are wall-clock times, so they only line up across hosts whose
+// clocks roughly agree. Both ends of a link must use this middleware, and
+// branch labels carry no trail.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// One role a message passed through
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hop {
+    pub role: String,
+    /// When the role sent the message on
+    pub at: SystemTime,
+}
+
+/// The roles a message passed through, from the one it started at
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageProvenance {
+    pub hops: Vec<Hop>,
+}
+
+impl MessageProvenance {
+    /// The role the message started at
+    pub fn origin(&self) -> &str {
+        self.hops.first().map_or("", |hop| hop.role.as_str())
+    }
+
+    /// When the message left its origin
+    pub fn sent_at(&self) -> Option<SystemTime> {
+        self.hops.first().map(|hop| hop.at)
+    }
+
+    /// Time from the origin to the last hop
+    pub fn elapsed(&self) -> Duration {
+        match (self.hops.first(), self.hops.last()) {
+            (Some(first), Some(last)) => last.at.duration_since(first.at).unwrap_or_default(),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for MessageProvenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let roles: Vec<_> = self.hops.iter().map(|hop| hop.role.as_str()).collect();
+        write!(f, "{}", roles.join(" -> "))
+    }
+}
+
+/// A serialized message and its provenance, as the inner handler carries it
+#[derive(Serialize, Deserialize)]
+struct Tracked {
+    provenance: MessageProvenance,
+    payload: Vec<u8>,
+}
+
+/// Middleware that records the roles each message passes through
+pub struct Hops<H: ChoreoHandler> {
+    inner: H,
+    role: String,
+    forwarding: bool,
+    last: Option<MessageProvenance>,
+    received: HashMap<H::Role, MessageProvenance>,
+}
+
+impl<H: ChoreoHandler> Hops<H> {
+    /// Wrap the handler of `role`, which starts a new trail with every send
+    pub fn new(inner: H, role: H::Role) -> Self {
+        Self {
+            inner,
+            role: format!("{:?}", role),
+            forwarding: false,
+            last: None,
+            received: HashMap::new(),
+        }
+    }
+
+    /// Continue the trail of the last message received with every message
+    /// sent, as a relay does
+    pub fn with_forwarding(mut self) -> Self {
+        self.forwarding = true;
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// Provenance of the last message received from any peer
+    pub fn last_provenance(&self) -> Option<&MessageProvenance> {
+        self.last.as_ref()
+    }
+
+    /// Provenance of the last message received from `peer`
+    pub fn provenance(&self, peer: &H::Role) -> Option<&MessageProvenance> {
+        self.received.get(peer)
+    }
+
+    /// The trail the next message sent carries
+    fn outgoing(&self) -> MessageProvenance {
+        let mut provenance = match &self.last {
+            Some(last) if self.forwarding => last.clone(),
+            _ => MessageProvenance { hops: Vec::new() },
+        };
+        provenance.hops.push(Hop {
+            role: self.role.clone(),
+            at: SystemTime::now(),
+        });
+        provenance
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Hops<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let tracked = Tracked {
+            provenance: self.outgoing(),
+            payload: bincode::serialize(msg)
+                .map_err(|e| ChoreographyError::Serialization(e.to_string()))?,
+        };
+        self.inner.send(ep, to, &tracked).await
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let tracked: Tracked = self.inner.recv(ep, from).await?;
+        let provenance = tracked.provenance;
+        tracing::info!(
+            role = %self.role,
+            ?from,
+            origin = provenance.origin(),
+            trail = %provenance,
+            elapsed = ?provenance.elapsed(),
+            "message provenance"
+        );
+        self.received.insert(from, provenance.clone());
+        self.last = Some(provenance);
+        bincode::deserialize(&tracked.payload)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
@@ -0,0 +1,524 @@
+// Journaling middleware
+//
+// Appends every send, receive, choice and offer a role performs to a file,
+// one JSON line per effect with the encoded payload, and syncs the file
+// before the effect returns. `replay_journal` reads the file back into a
+// handler that plays the recorded effects to a re-run of the same program,
+// so a role that crashed can catch up to where it stopped without talking
+// to its peers again, and then carries on through its live handler. The
+// same journal lets a failed run be stepped through after the fact.
+//
+// Effects are logged once they complete, so a crash between an effect and
+// its entry makes the re-run perform it again. Effects cut short, as by a
+// timeout, are logged as abandoned, and their replay never completes, so
+// the re-run is cut short at the same point. Both ends of a link must use
+// this middleware, as the inner handler carries encoded payloads rather
+// than messages.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::effects::handlers::session::message_name;
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// One effect as it was journaled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JournalEffect {
+    Send {
+        to: String,
+        message: String,
+        #[serde(with = "crate::effects::identity::hex_bytes")]
+        payload: Vec<u8>,
+    },
+    Recv {
+        from: String,
+        message: String,
+        /// Empty unless the receive completed
+        #[serde(with = "crate::effects::identity::hex_bytes")]
+        payload: Vec<u8>,
+    },
+    Choose {
+        to: String,
+        label: String,
+    },
+    Offer {
+        from: String,
+        /// `None` unless the offer completed
+        label: Option<String>,
+    },
+}
+
+impl fmt::Display for JournalEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalEffect::Send { to, message, .. } => write!(f, "send {} to {}", message, to),
+            JournalEffect::Recv { from, message, .. } => {
+                write!(f, "receive {} from {}", message, from)
+            }
+            JournalEffect::Choose { to, label } => write!(f, "choose {} at {}", label, to),
+            JournalEffect::Offer { from, .. } => write!(f, "offer from {}", from),
+        }
+    }
+}
+
+/// How a journaled effect ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum JournalOutcome {
+    Completed,
+    Failed(String),
+    /// The effect was dropped before it finished
+    Abandoned,
+}
+
+/// One line of a journal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Position in the journal, from 0
+    pub seq: u64,
+    /// When the effect ended
+    pub at: SystemTime,
+    pub role: String,
+    pub effect: JournalEffect,
+    pub outcome: JournalOutcome,
+}
+
+fn journal_error(path: &Path, e: impl fmt::Display) -> ChoreographyError {
+    ChoreographyError::Transport(format!("journal {}: {}", path.display(), e))
+}
+
+/// The complete lines of a journal file, without a line torn by a crash
+/// while it was written
+fn complete_lines(path: &Path) -> Result<(String, usize)> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut text)
+                .map_err(|e| journal_error(path, e))?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(journal_error(path, e)),
+    }
+    let complete = text.rfind('\n').map_or(0, |end| end + 1);
+    text.truncate(complete);
+    Ok((text, complete))
+}
+
+/// Read every entry of the journal at `path`, oldest first
+///
+/// A missing file is an empty journal. A last line cut short by a crash is
+/// left out.
+pub fn read_journal(path: impl AsRef<Path>) -> Result<Vec<JournalEntry>> {
+    let path = path.as_ref();
+    let (text, _) = complete_lines(path)?;
+    text.lines()
+        .enumerate()
+        .map(|(line, json)| {
+            serde_json::from_str(json).map_err(|e| {
+                ChoreographyError::Serialization(format!(
+                    "journal {} line {}: {}",
+                    path.display(),
+                    line + 1,
+                    e
+                ))
+            })
+        })
+        .collect()
+}
+
+/// The open journal file
+struct Log {
+    file: File,
+    path: PathBuf,
+    role: String,
+    seq: u64,
+}
+
+impl Log {
+    fn append(&mut self, effect: JournalEffect, outcome: JournalOutcome) -> Result<()> {
+        let entry = JournalEntry {
+            seq: self.seq,
+            at: SystemTime::now(),
+            role: self.role.clone(),
+            effect,
+            outcome,
+        };
+        let mut line = serde_json::to_vec(&entry)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| journal_error(&self.path, e))?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+/// An effect under way, journaled as abandoned if it is dropped before
+/// [`finish`](Self::finish)
+struct Pending<'a> {
+    log: &'a mut Log,
+    effect: Option<JournalEffect>,
+}
+
+impl<'a> Pending<'a> {
+    fn start(log: &'a mut Log, effect: JournalEffect) -> Self {
+        Self {
+            log,
+            effect: Some(effect),
+        }
+    }
+
+    /// Journal `effect` with the outcome of `result`, failing if the
+    /// journal cannot be written
+    fn finish<T>(mut self, effect: JournalEffect, result: Result<T>) -> Result<T> {
+        self.effect = None;
+        let outcome = match &result {
+            Ok(_) => JournalOutcome::Completed,
+            Err(e) => JournalOutcome::Failed(e.to_string()),
+        };
+        self.log.append(effect, outcome)?;
+        result
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(effect) = self.effect.take() {
+            if let Err(e) = self.log.append(effect, JournalOutcome::Abandoned) {
+                tracing::warn!(error = %e, "failed to journal abandoned effect");
+            }
+        }
+    }
+}
+
+/// Middleware that journals every effect to an append-only file
+pub struct Journal<H> {
+    inner: H,
+    log: Log,
+}
+
+impl<H: ChoreoHandler> Journal<H> {
+    /// Journal the effects of `role` to the file at `path`, appending to
+    /// whatever it already holds
+    pub fn open(inner: H, role: H::Role, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (text, complete) = complete_lines(&path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| journal_error(&path, e))?;
+        // Drop a line torn by a crash, so the next entry starts a line
+        file.set_len(complete as u64)
+            .map_err(|e| journal_error(&path, e))?;
+        Ok(Self {
+            inner,
+            log: Log {
+                file,
+                path,
+                role: format!("{:?}", role),
+                seq: text.lines().count() as u64,
+            },
+        })
+    }
+}
+
+impl<H> Journal<H> {
+    pub fn path(&self) -> &Path {
+        &self.log.path
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Journal<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload =
+            bincode::serialize(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let effect = JournalEffect::Send {
+            to: format!("{:?}", to),
+            message: message_name::<M>().to_string(),
+            payload: payload.clone(),
+        };
+        let pending = Pending::start(&mut self.log, effect.clone());
+        let result = self.inner.send(ep, to, &payload).await;
+        pending.finish(effect, result)
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let effect = |payload| JournalEffect::Recv {
+            from: format!("{:?}", from),
+            message: message_name::<M>().to_string(),
+            payload,
+        };
+        let pending = Pending::start(&mut self.log, effect(Vec::new()));
+        let result: Result<Vec<u8>> = self.inner.recv(ep, from).await;
+        let payload = match result {
+            Ok(payload) => payload,
+            Err(e) => return pending.finish(effect(Vec::new()), Err(e)),
+        };
+        let msg = bincode::deserialize(&payload)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()));
+        pending.finish(effect(payload), msg)
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        let effect = JournalEffect::Choose {
+            to: format!("{:?}", who),
+            label: label.0.to_string(),
+        };
+        let pending = Pending::start(&mut self.log, effect.clone());
+        let result = self.inner.choose(ep, who, label).await;
+        pending.finish(effect, result)
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        let effect = |label: Option<Label>| JournalEffect::Offer {
+            from: format!("{:?}", from),
+            label: label.map(|label| label.0.to_string()),
+        };
+        let pending = Pending::start(&mut self.log, effect(None));
+        let result = self.inner.offer(ep, from).await;
+        pending.finish(effect(result.as_ref().ok().copied()), result)
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
+
+/// Handler that plays back a journal, then hands over to `inner`
+pub struct Replay<H> {
+    inner: H,
+    entries: VecDeque<JournalEntry>,
+}
+
+/// Play the journal at `path` back to a re-run of the program that wrote
+/// it, and run the rest of the program on `inner`
+///
+/// To keep journaling after the log runs out, give a [`Journal`] on the
+/// same file as `inner`; it is opened after the journal has been read.
+pub fn replay_journal<H: ChoreoHandler>(path: impl AsRef<Path>, inner: H) -> Result<Replay<H>> {
+    Ok(Replay {
+        inner,
+        entries: read_journal(path)?.into(),
+    })
+}
+
+impl<H> Replay<H> {
+    /// Entries not played back yet
+    pub fn remaining(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the journal has run out and effects go to the inner handler
+    pub fn is_live(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// Take the next entry, which must be the effect `found`
+    ///
+    /// An abandoned effect never completes, as it did not the first time.
+    async fn next(&mut self, found: &JournalEffect) -> Result<JournalEffect> {
+        let entry = self.entries.pop_front().expect("replay is not live");
+        let matches = match (&entry.effect, found) {
+            (
+                JournalEffect::Send { to, message, .. },
+                JournalEffect::Send {
+                    to: to2,
+                    message: message2,
+                    ..
+                },
+            ) => to == to2 && message == message2,
+            (
+                JournalEffect::Recv { from, message, .. },
+                JournalEffect::Recv {
+                    from: from2,
+                    message: message2,
+                    ..
+                },
+            ) => from == from2 && message == message2,
+            (JournalEffect::Choose { .. }, JournalEffect::Choose { .. }) => entry.effect == *found,
+            (JournalEffect::Offer { from, .. }, JournalEffect::Offer { from: from2, .. }) => {
+                from == from2
+            }
+            _ => false,
+        };
+        if !matches {
+            return Err(ChoreographyError::SessionMismatch {
+                expected: format!("{} (journal entry {})", entry.effect, entry.seq),
+                found: found.to_string(),
+            });
+        }
+        match entry.outcome {
+            JournalOutcome::Completed => Ok(entry.effect),
+            JournalOutcome::Failed(error) => Err(ChoreographyError::Transport(error)),
+            JournalOutcome::Abandoned => std::future::pending().await,
+        }
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Replay<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        if self.is_live() {
+            return self.inner.send(ep, to, msg).await;
+        }
+        self.next(&JournalEffect::Send {
+            to: format!("{:?}", to),
+            message: message_name::<M>().to_string(),
+            payload: Vec::new(),
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        if self.is_live() {
+            return self.inner.recv(ep, from).await;
+        }
+        let effect = self
+            .next(&JournalEffect::Recv {
+                from: format!("{:?}", from),
+                message: message_name::<M>().to_string(),
+                payload: Vec::new(),
+            })
+            .await?;
+        match effect {
+            JournalEffect::Recv { payload, .. } => bincode::deserialize(&payload)
+                .map_err(|e| ChoreographyError::Serialization(e.to_string())),
+            _ => unreachable!("entry matched a receive"),
+        }
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        if self.is_live() {
+            return self.inner.choose(ep, who, label).await;
+        }
+        self.next(&JournalEffect::Choose {
+            to: format!("{:?}", who),
+            label: label.0.to_string(),
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        if self.is_live() {
+            return self.inner.offer(ep, from).await;
+        }
+        let effect = self
+            .next(&JournalEffect::Offer {
+                from: format!("{:?}", from),
+                label: None,
+            })
+            .await?;
+        match effect {
+            JournalEffect::Offer {
+                label: Some(label), ..
+            } => {
+                Ok(Label::system(&label)
+                    .unwrap_or_else(|| Label(Box::leak(label.into_boxed_str()))))
+            }
+            _ => Err(ChoreographyError::ProtocolViolation(
+                "journaled offer has no label".to_string(),
+            )),
+        }
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
@@ -1,20 +1,143 @@
 // Metrics collection middleware for effect handlers
 //
 // Tracks counts of sends, receives, and errors for monitoring and analysis.
+//
+// Each operation is also broken down by peer, message and label, with
+// histograms of latency and sent payload size. Sent messages are named by
+// their enum variant, received ones by the type the receive expects, since
+// the middleware only sees them once they are decoded.
+//
+// Latency is further split into the time the handler spent serializing,
+// in transport, and deserializing, so a slow step can be put down to the
+// codec, the network or the protocol waiting on its peer. Handlers report
+// their codec time through `handlers::phases`; the rest is transport.
 
 use async_trait::async_trait;
-use serde::{de::DeserializeOwned, Serialize};
-use std::time::Duration;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::effects::bridge::message_name as variant_name;
+use crate::effects::handlers::phases::{self, Phases};
+use crate::effects::handlers::session::message_name;
 use crate::effects::{ChoreoHandler, Label, Result};
 
+/// Kind of choreographic operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Send,
+    Recv,
+    Choose,
+    Offer,
+}
+
+/// What an operation's metrics are kept under
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct MetricsKey {
+    pub operation: Operation,
+    pub peer: String,
+    /// Message sent or received; `None` for choices and offers
+    pub message: Option<String>,
+    /// Label chosen or offered; `None` for messages and failed offers
+    pub label: Option<String>,
+}
+
+/// Counts of recorded values in power-of-two buckets
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    /// Non-empty buckets as `(upper bound, count)`, in ascending order
+    pub buckets: Vec<(u64, u64)>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        self.min = if self.count == 0 {
+            value
+        } else {
+            self.min.min(value)
+        };
+        self.max = self.max.max(value);
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        let bound = value.checked_next_power_of_two().unwrap_or(u64::MAX);
+        match self.buckets.binary_search_by_key(&bound, |(b, _)| *b) {
+            Ok(i) => self.buckets[i].1 += 1,
+            Err(i) => self.buckets.insert(i, (bound, 1)),
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, for `q` in 0..=1
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return (*bound).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Metrics of one kind of operation with one peer
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationMetrics {
+    pub count: u64,
+    pub errors: u64,
+    /// Time the inner handler took, in microseconds
+    pub latency_us: Histogram,
+    /// Encoded size of sent messages, in bytes
+    pub payload_bytes: Histogram,
+    /// Part of the latency spent serializing sent messages, in
+    /// microseconds; empty for handlers that do not report it
+    #[serde(default)]
+    pub serialize_us: Histogram,
+    /// Part of the latency spent neither serializing nor deserializing, in
+    /// microseconds: the transport, and waiting for the peer
+    #[serde(default)]
+    pub transport_us: Histogram,
+    /// Part of the latency spent deserializing received messages, in
+    /// microseconds; empty for handlers that do not report it
+    #[serde(default)]
+    pub deserialize_us: Histogram,
+}
+
+/// Everything the middleware has recorded, for dashboards
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsReport {
+    pub sends: u64,
+    pub recvs: u64,
+    pub errors: u64,
+    /// Breakdown by operation, peer, message and label, sorted by key
+    pub operations: Vec<(MetricsKey, OperationMetrics)>,
+}
+
 /// Metrics collection middleware
+///
+/// Clones share the same metrics.
 #[derive(Clone)]
 pub struct Metrics<H> {
     inner: H,
     send_count: std::sync::Arc<std::sync::atomic::AtomicU64>,
     recv_count: std::sync::Arc<std::sync::atomic::AtomicU64>,
     error_count: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    breakdown: Arc<Mutex<HashMap<MetricsKey, OperationMetrics>>>,
 }
 
 impl<H> Metrics<H> {
@@ -24,9 +147,14 @@ impl<H> Metrics<H> {
             send_count: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
             recv_count: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
             error_count: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            breakdown: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
     pub fn send_count(&self) -> u64 {
         self.send_count.load(std::sync::atomic::Ordering::Relaxed)
     }
@@ -38,6 +166,86 @@ impl<H> Metrics<H> {
     pub fn error_count(&self) -> u64 {
         self.error_count.load(std::sync::atomic::Ordering::Relaxed)
     }
+
+    /// Copy of everything recorded so far
+    pub fn snapshot(&self) -> MetricsReport {
+        let mut operations: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(key, metrics)| (key.clone(), metrics.clone()))
+            .collect();
+        operations.sort_by(|(a, _), (b, _)| a.cmp(b));
+        MetricsReport {
+            sends: self.send_count(),
+            recvs: self.recv_count(),
+            errors: self.error_count(),
+            operations,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<MetricsKey, OperationMetrics>> {
+        self.breakdown
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record<T>(
+        &self,
+        key: MetricsKey,
+        (latency, phases): (Duration, Phases),
+        payload: Option<usize>,
+        result: &Result<T>,
+    ) {
+        let mut breakdown = self.lock();
+        let metrics = breakdown.entry(key).or_default();
+        metrics.count += 1;
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        metrics.latency_us.record(latency.as_micros() as u64);
+        if let Some(size) = payload {
+            metrics.payload_bytes.record(size as u64);
+        }
+        let mut transport = latency;
+        if let Some(serialize) = phases.serialize {
+            metrics.serialize_us.record(serialize.as_micros() as u64);
+            transport = transport.saturating_sub(serialize);
+        }
+        if let Some(deserialize) = phases.deserialize {
+            metrics
+                .deserialize_us
+                .record(deserialize.as_micros() as u64);
+            transport = transport.saturating_sub(deserialize);
+        }
+        metrics.transport_us.record(transport.as_micros() as u64);
+    }
+}
+
+/// Run an operation of the inner handler, returning its latency and the
+/// time it spent in codecs
+async fn timed<F: Future + Unpin>(operation: F) -> (F::Output, (Duration, Phases)) {
+    let phases = Arc::new(Mutex::new(Phases::default()));
+    let started = Instant::now();
+    let output = phases::measure(operation, phases.clone()).await;
+    let latency = started.elapsed();
+    let phases = *phases
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    (output, (latency, phases))
+}
+
+fn key(
+    operation: Operation,
+    peer: impl std::fmt::Debug,
+    message: Option<String>,
+    label: Option<Label>,
+) -> MetricsKey {
+    MetricsKey {
+        operation,
+        peer: format!("{:?}", peer),
+        message,
+        label: label.map(|label| label.0.to_string()),
+    }
 }
 
 #[async_trait]
@@ -51,7 +259,18 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Metrics<H> {
         to: Self::Role,
         msg: &M,
     ) -> Result<()> {
-        let result = self.inner.send(ep, to, msg).await;
+        let message = match serde_json::to_value(msg) {
+            Ok(value) => variant_name::<M>(&value),
+            Err(_) => message_name::<M>().to_string(),
+        };
+        let size = bincode::serialized_size(msg).ok().map(|size| size as usize);
+        let (result, timing) = timed(self.inner.send(ep, to, msg)).await;
+        self.record(
+            key(Operation::Send, to, Some(message), None),
+            timing,
+            size,
+            &result,
+        );
         if result.is_ok() {
             self.send_count
                 .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -67,7 +286,14 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Metrics<H> {
         ep: &mut Self::Endpoint,
         from: Self::Role,
     ) -> Result<M> {
-        let result = self.inner.recv(ep, from).await;
+        let (result, timing) = timed(self.inner.recv(ep, from)).await;
+        let message = message_name::<M>().to_string();
+        self.record(
+            key(Operation::Recv, from, Some(message), None),
+            timing,
+            None,
+            &result,
+        );
         if result.is_ok() {
             self.recv_count
                 .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -84,11 +310,26 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Metrics<H> {
         who: Self::Role,
         label: Label,
     ) -> Result<()> {
-        self.inner.choose(ep, who, label).await
+        let (result, timing) = timed(self.inner.choose(ep, who, label)).await;
+        self.record(
+            key(Operation::Choose, who, None, Some(label)),
+            timing,
+            None,
+            &result,
+        );
+        result
     }
 
     async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
-        self.inner.offer(ep, from).await
+        let (result, timing) = timed(self.inner.offer(ep, from)).await;
+        let label = result.as_ref().ok().copied();
+        self.record(
+            key(Operation::Offer, from, None, label),
+            timing,
+            None,
+            &result,
+        );
+        result
     }
 
     async fn with_timeout<F, T>(
@@ -103,4 +344,12 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Metrics<H> {
     {
         self.inner.with_timeout(ep, at, dur, body).await
     }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
 }
@@ -6,15 +6,57 @@
 // Middleware follows the decorator pattern, wrapping inner handlers and forwarding
 // operations while adding additional behavior.
 
+// Middleware is used through the re-exports below
+#[doc(hidden)]
+pub mod batching;
+#[doc(hidden)]
+pub mod budget;
+#[doc(hidden)]
+pub mod deadline;
+#[doc(hidden)]
+pub mod encrypted;
+#[doc(hidden)]
 pub mod fault_injection;
+#[doc(hidden)]
+pub mod fault_scenario;
+#[doc(hidden)]
+pub mod hops;
+#[doc(hidden)]
+pub mod journal;
+#[doc(hidden)]
 pub mod metrics;
+#[cfg(feature = "otel")]
+#[doc(hidden)]
+pub mod otel;
+#[doc(hidden)]
 pub mod retry;
+#[doc(hidden)]
+pub mod signed;
+#[doc(hidden)]
+pub mod tenant;
+#[doc(hidden)]
 pub mod trace;
 
 // Re-export middleware types for convenience
-pub use metrics::Metrics;
+pub use batching::Batching;
+pub use budget::{Budget, BudgetLimits, BudgetUsage};
+pub use deadline::Deadline;
+pub use encrypted::Encrypted;
+pub use fault_scenario::{
+    FaultAction, FaultScenario, FaultSchedule, FaultStep, FaultTrigger, ScenarioError, SendFaults,
+};
+pub use hops::{Hop, Hops, MessageProvenance};
+pub use journal::{
+    read_journal, replay_journal, Journal, JournalEffect, JournalEntry, JournalOutcome, Replay,
+};
+pub use metrics::{Histogram, Metrics, MetricsKey, MetricsReport, Operation, OperationMetrics};
 pub use retry::Retry;
-pub use trace::Trace;
+pub use signed::Signed;
+pub use tenant::{TenancyError, TenantConfig, TenantGuard, TenantId, TenantRegistry};
+pub use trace::{Redaction, Trace};
+
+#[cfg(feature = "otel")]
+pub use otel::Otel;
 
 #[cfg(feature = "test-utils")]
 pub use fault_injection::FaultInjection;
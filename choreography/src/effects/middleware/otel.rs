@@ -0,0 +1,264 @@
+// OpenTelemetry tracing middleware
+//
+// Records every send, receive, choice and offer as an OpenTelemetry span
+// carrying the local role, the peer, and the message type and payload size
+// or the label. Each message travels with the trace context of the span that
+// sent it, so the receive span on the other side joins the same trace, and a
+// role that sends after receiving continues the trace it received. Sends made
+// inside a span of the application's own join that span instead.
+//
+// Both ends of a link must use this middleware, as the inner handler carries
+// envelopes rather than messages. Branch labels carry no trace context.
+
+use async_trait::async_trait;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::trace::{
+    Span, SpanBuilder, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId,
+    TraceState, Tracer,
+};
+use opentelemetry::{Context, KeyValue};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::effects::handlers::session::message_name;
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// Name of the tracer used unless [`Otel::with_tracer`] gives another
+pub const TRACER_NAME: &str = "rumpsteak";
+
+/// The sending span, as W3C trace context puts it
+#[derive(Serialize, Deserialize)]
+struct TraceParent {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    flags: u8,
+}
+
+/// A serialized message and the context it was sent in, as the inner
+/// handler carries it
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    parent: Option<TraceParent>,
+    payload: Vec<u8>,
+}
+
+/// Middleware that exports choreographic operations as OpenTelemetry spans
+pub struct Otel<H, T = BoxedTracer> {
+    inner: H,
+    tracer: T,
+    role: String,
+    /// Context of the last message received, which later sends continue
+    received: Option<Context>,
+}
+
+impl<H: ChoreoHandler> Otel<H> {
+    /// Trace the handler of `role` with the globally installed tracer provider
+    pub fn new(inner: H, role: H::Role) -> Self {
+        Self {
+            inner,
+            tracer: global::tracer(TRACER_NAME),
+            role: format!("{:?}", role),
+            received: None,
+        }
+    }
+}
+
+impl<H, T> Otel<H, T> {
+    /// Create spans with `tracer` instead of the global one
+    pub fn with_tracer<U: Tracer>(self, tracer: U) -> Otel<H, U> {
+        Otel {
+            inner: self.inner,
+            tracer,
+            role: self.role,
+            received: self.received,
+        }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H: ChoreoHandler, T: Tracer> Otel<H, T> {
+    /// A span with the role, `peer` and `attributes`
+    fn span(
+        &self,
+        name: &'static str,
+        kind: SpanKind,
+        peer: H::Role,
+        attributes: impl IntoIterator<Item = KeyValue>,
+    ) -> SpanBuilder {
+        let mut all = vec![
+            KeyValue::new("rumpsteak.role", self.role.clone()),
+            KeyValue::new("rumpsteak.peer", format!("{:?}", peer)),
+        ];
+        all.extend(attributes);
+        SpanBuilder::from_name(name)
+            .with_kind(kind)
+            .with_attributes(all)
+    }
+
+    /// Context a span starts in: the application's current span if there is
+    /// one, otherwise the trace of the last message received
+    fn parent(&self) -> Context {
+        let current = Context::current();
+        match &self.received {
+            Some(received) if !current.has_active_span() => received.clone(),
+            _ => current,
+        }
+    }
+}
+
+/// End `span` with the outcome of the operation it covers
+fn finish<S: Span, V>(mut span: S, result: &Result<V>) {
+    if let Err(e) = result {
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+}
+
+#[async_trait]
+impl<H, T> ChoreoHandler for Otel<H, T>
+where
+    H: ChoreoHandler + Send,
+    T: Tracer + Send + Sync,
+    T::Span: Send + Sync,
+{
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload =
+            bincode::serialize(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let builder = self.span(
+            "send",
+            SpanKind::Producer,
+            to,
+            [
+                KeyValue::new("rumpsteak.message.type", message_name::<M>()),
+                KeyValue::new("rumpsteak.message.size", payload.len() as i64),
+            ],
+        );
+        let span = self.tracer.build_with_context(builder, &self.parent());
+        let context = span.span_context();
+        let envelope = Envelope {
+            parent: context.is_valid().then(|| TraceParent {
+                trace_id: context.trace_id().to_bytes(),
+                span_id: context.span_id().to_bytes(),
+                flags: context.trace_flags().to_u8(),
+            }),
+            payload,
+        };
+        let result = self.inner.send(ep, to, &envelope).await;
+        finish(span, &result);
+        result
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        let started = SystemTime::now();
+        let builder = self
+            .span(
+                "recv",
+                SpanKind::Consumer,
+                from,
+                [KeyValue::new("rumpsteak.message.type", message_name::<M>())],
+            )
+            .with_start_time(started);
+        let envelope: Envelope = match self.inner.recv(ep, from).await {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                let span = self.tracer.build_with_context(builder, &self.parent());
+                let result = Err(e);
+                finish(span, &result);
+                return result;
+            }
+        };
+
+        let parent = match envelope.parent {
+            Some(parent) => {
+                let remote = Context::new().with_remote_span_context(SpanContext::new(
+                    TraceId::from_bytes(parent.trace_id),
+                    SpanId::from_bytes(parent.span_id),
+                    TraceFlags::new(parent.flags),
+                    true,
+                    TraceState::default(),
+                ));
+                self.received = Some(remote.clone());
+                remote
+            }
+            None => self.parent(),
+        };
+        let mut span = self.tracer.build_with_context(builder, &parent);
+        span.set_attribute(KeyValue::new(
+            "rumpsteak.message.size",
+            envelope.payload.len() as i64,
+        ));
+        let result = bincode::deserialize(&envelope.payload)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()));
+        finish(span, &result);
+        result
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        let builder = self.span(
+            "choose",
+            SpanKind::Producer,
+            who,
+            [KeyValue::new("rumpsteak.label", label.0)],
+        );
+        let span = self.tracer.build_with_context(builder, &self.parent());
+        let result = self.inner.choose(ep, who, label).await;
+        finish(span, &result);
+        result
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        let builder = self.span("offer", SpanKind::Consumer, from, []);
+        let mut span = self.tracer.build_with_context(builder, &self.parent());
+        let result = self.inner.offer(ep, from).await;
+        if let Ok(label) = &result {
+            span.set_attribute(KeyValue::new("rumpsteak.label", label.0));
+        }
+        finish(span, &result);
+        result
+    }
+
+    async fn with_timeout<F, V>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<V>
+    where
+        F: std::future::Future<Output = Result<V>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
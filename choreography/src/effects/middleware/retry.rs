@@ -104,4 +104,12 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Retry<H> {
     {
         self.inner.with_timeout(ep, at, dur, body).await
     }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
 }
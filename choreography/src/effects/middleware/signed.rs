@@ -0,0 +1,209 @@
+// Message authentication middleware
+//
+// Appends an HMAC-SHA256 tag to every message sent and checks the tag of
+// every message received, using a key shared with the peer at the other
+// end. A message altered in transit, or sent by someone without the key,
+// fails with `IntegrityViolation` instead of reaching the program. Both
+// sides of a link must wrap their handlers with the same key for it.
+//
+// Tags cover the sender, the receiver and how many messages came before in
+// that direction, so a message replayed, reordered or reflected back at its
+// sender fails too. Branch labels travel as the inner handler sends them,
+// each followed by its own tag in the same count.
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// What a tag is for, so a message's tag never passes for a label's
+const MESSAGE: &str = "message";
+const LABEL: &str = "label";
+
+/// A serialized message and its tag, as the inner handler carries it
+#[derive(Serialize, Deserialize)]
+struct SignedMessage {
+    payload: Vec<u8>,
+    tag: [u8; 32],
+}
+
+/// Middleware that signs outgoing messages and verifies incoming ones
+pub struct Signed<H: ChoreoHandler> {
+    inner: H,
+    role: H::Role,
+    keys: HashMap<H::Role, Vec<u8>>,
+    default_key: Option<Vec<u8>>,
+    sent: HashMap<H::Role, u64>,
+    received: HashMap<H::Role, u64>,
+}
+
+impl<H: ChoreoHandler> Signed<H> {
+    /// Wrap `inner` for `role` with no keys; add them with `with_key` or
+    /// `with_default_key`
+    pub fn new(inner: H, role: H::Role) -> Self {
+        Self {
+            inner,
+            role,
+            keys: HashMap::new(),
+            default_key: None,
+            sent: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    /// Sign and check messages exchanged with `peer` using `key`
+    pub fn with_key(mut self, peer: H::Role, key: impl Into<Vec<u8>>) -> Self {
+        self.keys.insert(peer, key.into());
+        self
+    }
+
+    /// Key for peers without one of their own
+    pub fn with_default_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.default_key = Some(key.into());
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    fn key(&self, peer: H::Role) -> Result<&[u8]> {
+        self.keys
+            .get(&peer)
+            .or(self.default_key.as_ref())
+            .map(Vec::as_slice)
+            .ok_or_else(|| ChoreographyError::IntegrityViolation {
+                role: format!("{:?}", peer),
+                reason: "no key is shared with this role".to_string(),
+            })
+    }
+
+    /// Tag of the next `kind` sent to `to`
+    fn sign(&self, to: H::Role, kind: &str, payload: &[u8]) -> Result<[u8; 32]> {
+        let sequence = self.sent.get(&to).copied().unwrap_or(0);
+        let mac = mac(self.key(to)?, kind, self.role, to, sequence, payload);
+        Ok(mac.finalize().into_bytes().into())
+    }
+
+    /// Check `tag` of the next `kind` received from `from`
+    fn verify(&mut self, from: H::Role, kind: &str, payload: &[u8], tag: &[u8]) -> Result<()> {
+        let sequence = self.received.get(&from).copied().unwrap_or(0);
+        mac(self.key(from)?, kind, from, self.role, sequence, payload)
+            .verify_slice(tag)
+            .map_err(|_| {
+                tracing::warn!(?from, "{} failed authentication", kind);
+                ChoreographyError::IntegrityViolation {
+                    role: format!("{:?}", from),
+                    reason: format!("{} tag does not match", kind),
+                }
+            })?;
+        *self.received.entry(from).or_default() += 1;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for Signed<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        let payload =
+            bincode::serialize(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        let tag = self.sign(to, MESSAGE, &payload)?;
+        self.inner
+            .send(ep, to, &SignedMessage { payload, tag })
+            .await?;
+        // A message that never left does not count, or the peer could check
+        // nothing after it
+        *self.sent.entry(to).or_default() += 1;
+        Ok(())
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        self.key(from)?;
+        let signed: SignedMessage = self.inner.recv(ep, from).await?;
+        self.verify(from, MESSAGE, &signed.payload, &signed.tag)?;
+        bincode::deserialize(&signed.payload)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        let tag = self.sign(who, LABEL, label.0.as_bytes())?;
+        self.inner.choose(ep, who, label).await?;
+        self.inner.send(ep, who, &tag).await?;
+        *self.sent.entry(who).or_default() += 1;
+        Ok(())
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.key(from)?;
+        let label = self.inner.offer(ep, from).await?;
+        let tag: [u8; 32] = self.inner.recv(ep, from).await?;
+        self.verify(from, LABEL, label.0.as_bytes(), &tag)?;
+        Ok(label)
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
+
+/// HMAC-SHA256 over `payload`, as the `sequence`th thing sent from `from`
+/// to `to`
+fn mac<R: std::fmt::Debug>(
+    key: &[u8],
+    kind: &str,
+    from: R,
+    to: R,
+    sequence: u64,
+    payload: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in [kind.to_string(), format!("{:?}", from), format!("{:?}", to)] {
+        mac.update(&(part.len() as u64).to_le_bytes());
+        mac.update(part.as_bytes());
+    }
+    mac.update(&sequence.to_le_bytes());
+    mac.update(payload);
+    mac
+}
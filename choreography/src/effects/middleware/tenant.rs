@@ -0,0 +1,335 @@
+// Multi-tenant isolation for effect handlers
+//
+// A server hosting sessions for several tenants keeps a registry of tenant
+// configurations and of which tenant owns each role. Sessions are wrapped
+// in a guard that refuses to address roles owned by anyone else, paces
+// operations to the tenant's rate limit, and applies the tenant's budget.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use super::budget::{Budget, BudgetLimits};
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+
+/// Identifier of a tenant
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TenantId(pub String);
+
+impl fmt::Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for TenantId {
+    fn from(id: &str) -> Self {
+        TenantId(id.to_string())
+    }
+}
+
+/// Per-tenant handler configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantConfig {
+    pub id: TenantId,
+    /// Named secrets that transports built for this tenant should use
+    pub credentials: BTreeMap<String, String>,
+    /// Operations per second for each session; `None` means unpaced
+    pub max_ops_per_second: Option<u32>,
+    /// Limits applied to each session
+    pub budget: BudgetLimits,
+    /// Value of the `tenant` field on tracing events; defaults to the id
+    pub metrics_label: String,
+}
+
+impl TenantConfig {
+    pub fn new(id: impl Into<TenantId>) -> Self {
+        let id = id.into();
+        Self {
+            metrics_label: id.0.clone(),
+            id,
+            credentials: BTreeMap::new(),
+            max_ops_per_second: None,
+            budget: BudgetLimits::default(),
+        }
+    }
+
+    pub fn with_credential(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.credentials.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_rate_limit(mut self, ops_per_second: u32) -> Self {
+        self.max_ops_per_second = Some(ops_per_second);
+        self
+    }
+
+    pub fn with_budget(mut self, budget: BudgetLimits) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn with_metrics_label(mut self, label: impl Into<String>) -> Self {
+        self.metrics_label = label.into();
+        self
+    }
+}
+
+/// Errors from registry operations
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TenancyError {
+    #[error("unknown tenant {0}")]
+    UnknownTenant(TenantId),
+
+    #[error("role {role} already belongs to tenant {owner}")]
+    RoleOwned { role: String, owner: TenantId },
+}
+
+struct Registry<R> {
+    tenants: BTreeMap<TenantId, TenantConfig>,
+    owners: HashMap<R, TenantId>,
+}
+
+/// Tenant configurations and the role directory they are isolated by
+///
+/// Clones share the same registry, so roles assigned or released after a
+/// session starts take effect on its next operation.
+pub struct TenantRegistry<R> {
+    inner: Arc<RwLock<Registry<R>>>,
+}
+
+impl<R> Clone for TenantRegistry<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<R: RoleId> Default for TenantRegistry<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: RoleId> TenantRegistry<R> {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Registry {
+                tenants: BTreeMap::new(),
+                owners: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Add a tenant, or replace its configuration
+    pub fn register(&self, config: TenantConfig) {
+        self.write().tenants.insert(config.id.clone(), config);
+    }
+
+    pub fn config(&self, tenant: &TenantId) -> Option<TenantConfig> {
+        self.read().tenants.get(tenant).cloned()
+    }
+
+    /// Give `tenant` ownership of `role`
+    ///
+    /// Fails if another tenant already owns the role.
+    pub fn assign(&self, role: R, tenant: &TenantId) -> std::result::Result<(), TenancyError> {
+        let mut registry = self.write();
+        if !registry.tenants.contains_key(tenant) {
+            return Err(TenancyError::UnknownTenant(tenant.clone()));
+        }
+        match registry.owners.get(&role) {
+            Some(owner) if owner != tenant => Err(TenancyError::RoleOwned {
+                role: format!("{:?}", role),
+                owner: owner.clone(),
+            }),
+            _ => {
+                registry.owners.insert(role, tenant.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove a role from the directory
+    pub fn release(&self, role: &R) -> Option<TenantId> {
+        self.write().owners.remove(role)
+    }
+
+    pub fn owner(&self, role: &R) -> Option<TenantId> {
+        self.read().owners.get(role).cloned()
+    }
+
+    /// Roles owned by a tenant, in no particular order
+    pub fn roles(&self, tenant: &TenantId) -> Vec<R> {
+        self.read()
+            .owners
+            .iter()
+            .filter(|(_, owner)| *owner == tenant)
+            .map(|(role, _)| *role)
+            .collect()
+    }
+
+    /// Wrap a session's handler with the tenant's isolation and budget
+    pub fn session<H>(
+        &self,
+        tenant: &TenantId,
+        handler: H,
+    ) -> std::result::Result<Budget<TenantGuard<H>>, TenancyError>
+    where
+        H: ChoreoHandler<Role = R>,
+    {
+        let config = self
+            .config(tenant)
+            .ok_or_else(|| TenancyError::UnknownTenant(tenant.clone()))?;
+        let guard = TenantGuard {
+            inner: handler,
+            tenant: config.id,
+            label: config.metrics_label,
+            registry: self.clone(),
+            pacing: config
+                .max_ops_per_second
+                .filter(|ops| *ops > 0)
+                .map(|ops| Pacing {
+                    interval: Duration::from_secs(1) / ops,
+                    next: None,
+                }),
+        };
+        Ok(Budget::new(guard, config.budget))
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Registry<R>> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Registry<R>> {
+        self.inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+struct Pacing {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+/// Middleware confining a session to its tenant's roles
+///
+/// Every operation must address a role the tenant owns in the registry,
+/// otherwise it fails with [`ChoreographyError::TenantViolation`].
+/// Operations are spaced to the tenant's rate limit.
+pub struct TenantGuard<H: ChoreoHandler> {
+    inner: H,
+    tenant: TenantId,
+    label: String,
+    registry: TenantRegistry<H::Role>,
+    pacing: Option<Pacing>,
+}
+
+impl<H: ChoreoHandler> TenantGuard<H> {
+    pub fn tenant(&self) -> &TenantId {
+        &self.tenant
+    }
+
+    pub fn metrics_label(&self) -> &str {
+        &self.label
+    }
+
+    async fn admit(&mut self, peer: H::Role, operation: &'static str) -> Result<()> {
+        if self.registry.owner(&peer).as_ref() != Some(&self.tenant) {
+            tracing::warn!(tenant = %self.label, ?peer, operation, "cross-tenant access refused");
+            return Err(ChoreographyError::TenantViolation {
+                tenant: self.tenant.to_string(),
+                role: format!("{:?}", peer),
+            });
+        }
+
+        if let Some(pacing) = &mut self.pacing {
+            let now = Instant::now();
+            let start = pacing.next.map_or(now, |next| next.max(now));
+            pacing.next = Some(start + pacing.interval);
+            let delay = start - now;
+            if !delay.is_zero() {
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    tokio::time::sleep(delay).await;
+                }
+
+                #[cfg(target_arch = "wasm32")]
+                {
+                    wasm_timer::Delay::new(delay).await.ok();
+                }
+            }
+        }
+
+        tracing::trace!(tenant = %self.label, ?peer, operation, "tenant operation");
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<H: ChoreoHandler + Send> ChoreoHandler for TenantGuard<H> {
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &M,
+    ) -> Result<()> {
+        self.admit(to, "send").await?;
+        self.inner.send(ep, to, msg).await
+    }
+
+    async fn recv<M: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<M> {
+        self.admit(from, "recv").await?;
+        self.inner.recv(ep, from).await
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        self.admit(who, "choose").await?;
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        self.admit(from, "offer").await?;
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
@@ -1,12 +1,17 @@
 // Tracing middleware for effect handlers
 //
 // Logs all choreographic operations with timing information for debugging and monitoring.
+//
+// Payloads are only logged once a `Redaction` is set, so fields tagged
+// `@sensitive` in the choreography never reach the logs by accident.
 
 use async_trait::async_trait;
 use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use tracing::{debug, trace, warn};
 
+use crate::effects::handlers::session::message_name;
 use crate::effects::{ChoreoHandler, Label, Result};
 
 /// Tracing middleware that logs all choreographic operations
@@ -14,6 +19,7 @@ use crate::effects::{ChoreoHandler, Label, Result};
 pub struct Trace<H> {
     inner: H,
     prefix: String,
+    redaction: Option<Redaction>,
 }
 
 impl<H> Trace<H> {
@@ -25,7 +31,65 @@ impl<H> Trace<H> {
         Self {
             inner,
             prefix: prefix.into(),
+            redaction: None,
+        }
+    }
+
+    /// Also log sent payloads as JSON, hiding the fields in `redaction`
+    ///
+    /// Generated protocols with `@sensitive` fields provide a `redaction()`
+    /// function listing them.
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = Some(redaction);
+        self
+    }
+}
+
+/// Payload fields to hide when logging messages
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    /// Field names by message name
+    fields: HashMap<String, HashSet<String>>,
+}
+
+impl Redaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hide `field` of the message named `message`
+    pub fn with_field(mut self, message: impl Into<String>, field: impl Into<String>) -> Self {
+        self.fields
+            .entry(message.into())
+            .or_default()
+            .insert(field.into());
+        self
+    }
+
+    pub fn is_redacted(&self, message: &str, field: &str) -> bool {
+        self.fields
+            .get(message)
+            .is_some_and(|fields| fields.contains(field))
+    }
+
+    /// `msg` as JSON with every hidden field replaced by `"<redacted>"`
+    ///
+    /// Messages are matched by the last segment of their type name.
+    pub fn render<M: Serialize + ?Sized>(&self, msg: &M) -> String {
+        let mut value = match serde_json::to_value(msg) {
+            Ok(value) => value,
+            Err(e) => return format!("<unserializable: {}>", e),
+        };
+        if let (Some(hidden), Some(object)) =
+            (self.fields.get(message_name::<M>()), value.as_object_mut())
+        {
+            for (field, v) in object.iter_mut() {
+                if hidden.contains(field) {
+                    *v = serde_json::Value::from("<redacted>");
+                }
+            }
         }
+        value.to_string()
     }
 }
 
@@ -42,6 +106,9 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Trace<H> {
     ) -> Result<()> {
         let start = Instant::now();
         trace!(prefix = %self.prefix, ?to, "send: start");
+        if let Some(redaction) = &self.redaction {
+            debug!(prefix = %self.prefix, ?to, payload = %redaction.render(msg), "send: payload");
+        }
         let result = self.inner.send(ep, to, msg).await;
         let duration = start.elapsed();
         match &result {
@@ -104,4 +171,13 @@ impl<H: ChoreoHandler + Send> ChoreoHandler for Trace<H> {
         }
         result
     }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        debug!(prefix = %self.prefix, "cancel");
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
 }
@@ -7,27 +7,97 @@
 //! represented as data structures that can be analyzed, transformed, and interpreted.
 
 pub mod algebra;
+pub mod approval;
+pub mod bridge;
+pub mod cancel;
+pub mod compute;
+mod conformance;
+pub mod differential;
+pub mod dry_run;
+pub mod fuzz;
+pub mod guard;
 pub mod handler;
 pub mod handlers;
+pub mod identity;
 pub mod interpreter;
+pub mod introduction;
+pub mod membership;
 pub mod middleware;
+#[cfg(all(not(target_arch = "wasm32"), feature = "profiling"))]
+pub mod profiling;
+pub mod stub;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testkit;
+pub mod wire_ids;
 
 // Re-export core effect system types explicitly
 pub use algebra::{
-    Effect, InterpretResult, InterpreterState, Program, ProgramError, ProgramMessage,
+    Effect, InterpretResult, InterpreterState, Program, ProgramError, ProgramMessage, UnknownLabel,
 };
+pub use cancel::CancellationToken;
+pub use compute::{Computation, ComputeCache, InMemoryComputeCache, LoopCondition, Router};
+pub use guard::{Guard, GuardContext, GuardError, GuardValue};
 pub use handler::{
     ChoreoHandler, ChoreoHandlerExt, ChoreographyError, Endpoint, Label, NoOpHandler, Result,
     RoleId,
 };
-pub use interpreter::interpret;
+pub use interpreter::{
+    interpret, interpret_in_session, interpret_many, interpret_many_limited, interpret_with_cancel,
+    interpret_with_guards, interpret_with_hooks, testing, EffectContext, ErrorContext, Interpreter,
+    InterpreterHooks, Scope, SessionContext,
+};
 
 // Re-export handler implementations for convenience
-pub use handlers::{InMemoryHandler, RecordedEvent, RecordingHandler};
+pub use handlers::{GrpcAcceptor, GrpcEndpoint, GrpcEnvelope, GrpcFailures, GrpcHandler};
 pub use handlers::{HasRoute, RumpsteakEndpoint, RumpsteakHandler, SimpleChannel};
+pub use handlers::{InMemoryHandler, RecordedEvent, RecordingHandler};
+pub use handlers::{SessionCursor, SessionType};
+#[cfg(all(not(target_arch = "wasm32"), feature = "tls"))]
+pub use handlers::{TlsConfig, TlsEndpoint, TlsHandler};
+pub use handlers::{ValidatedEffect, ValidationHandler};
+pub use handlers::{WebSocketEndpoint, WebSocketHandler};
+#[cfg(not(target_arch = "wasm32"))]
+pub use testkit::{KitDriver, KitReport};
+
+// Re-export differential testing
+pub use differential::{
+    Differential, Divergence, RoleTrace, SessionTrace, TraceEvent, TraceRecorder,
+};
+pub use dry_run::{DryRun, DryRunStep};
+pub use stub::StubRole;
+pub use wire_ids::WireIds;
+
+// Re-export per-phase profiling
+#[cfg(all(not(target_arch = "wasm32"), feature = "profiling"))]
+pub use profiling::{PhaseProfile, PhaseProfiler, ProfileError};
+
+// Re-export cross-choreography bridging
+pub use bridge::{BridgeConfig, BridgeHandler, BridgeRoute};
+
+// Re-export role group membership
+pub use membership::{MemberDigest, Membership};
+
+// Re-export role identity types
+pub use approval::{handshake, ChoreographyManifest, SignedChoreography};
+pub use identity::{
+    IdentityAuthority, IdentityError, KeyStore, PublicKey, RoleCertificate, RoleIdentity,
+};
+pub use introduction::{introduce, Agreement, Introduction};
 
 // Re-export middleware for convenience
-pub use middleware::{Metrics, Retry, Trace};
+pub use middleware::{
+    read_journal, replay_journal, Journal, JournalEffect, JournalEntry, JournalOutcome, Replay,
+};
+pub use middleware::{
+    Batching, Budget, BudgetLimits, BudgetUsage, Deadline, Encrypted, Histogram, Hop, Hops,
+    MessageProvenance, Metrics, MetricsKey, MetricsReport, Operation, OperationMetrics, Redaction,
+    Retry, Signed, Trace,
+};
+pub use middleware::{FaultScenario, FaultSchedule, ScenarioError};
+pub use middleware::{TenancyError, TenantConfig, TenantGuard, TenantId, TenantRegistry};
+
+#[cfg(feature = "otel")]
+pub use middleware::Otel;
 
 #[cfg(feature = "test-utils")]
 pub use middleware::FaultInjection;
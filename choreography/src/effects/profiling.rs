@@ -0,0 +1,233 @@
+// Per-phase CPU profiling of choreographies
+//
+// Runs a program under pprof's sampling profiler and keeps a separate
+// profile for each top-level effect of the program, so that a slowdown can
+// be put down to the step that causes it. Effects nested in a branch, loop
+// or other structure count towards the phase of the structure they sit in.
+//
+// The profiler samples every thread of the process, and only one can run at
+// a time, so profile one role per process or run roles one after another.
+
+use async_trait::async_trait;
+use pprof::{ProfilerGuard, ProfilerGuardBuilder, Report};
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::effects::algebra::{Effect, InterpretResult, Program, ProgramMessage};
+use crate::effects::interpreter::{interpret_with_hooks, step_name};
+use crate::effects::{ChoreoHandler, ChoreographyError, EffectContext, InterpreterHooks, RoleId};
+
+/// Samples per second taken unless [`PhaseProfiler::with_frequency`] says
+/// otherwise
+pub const DEFAULT_FREQUENCY: i32 = 997;
+
+/// Errors from a profiled run
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("profiler error: {0}")]
+    Profiler(#[from] pprof::Error),
+
+    #[error("flamegraph I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Choreography(#[from] ChoreographyError),
+}
+
+pub type ProfileResult<T> = std::result::Result<T, ProfileError>;
+
+/// The profile of one top-level effect
+pub struct PhaseProfile {
+    /// Position of the effect in the program
+    pub index: usize,
+    /// The effect, as error contexts name it
+    pub name: String,
+    /// Wall-clock time the phase took
+    pub elapsed: Duration,
+    report: Report,
+}
+
+impl PhaseProfile {
+    /// Number of samples taken during the phase
+    pub fn samples(&self) -> usize {
+        self.report
+            .data
+            .values()
+            .map(|&count| count.max(0) as usize)
+            .sum()
+    }
+
+    /// The pprof report, for output other than flamegraphs
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    /// Render the phase as a flamegraph SVG
+    pub fn flamegraph<W: std::io::Write>(&self, writer: W) -> ProfileResult<()> {
+        Ok(self.report.flamegraph(writer)?)
+    }
+
+    /// File name the phase's flamegraph is written under
+    pub fn file_name(&self) -> String {
+        let slug: String = self
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        format!("{:03}-{}.svg", self.index, slug)
+    }
+}
+
+/// A phase still being sampled
+struct Running {
+    index: usize,
+    name: String,
+    started: Instant,
+    guard: ProfilerGuard<'static>,
+}
+
+/// Profiler that records one profile per top-level effect of a program
+///
+/// Use [`run`](PhaseProfiler::run), or pass the profiler as hooks to
+/// [`interpret_with_hooks`] alongside a program of your own.
+pub struct PhaseProfiler {
+    frequency: i32,
+    running: Option<Running>,
+    phases: Vec<PhaseProfile>,
+    error: Option<pprof::Error>,
+}
+
+impl PhaseProfiler {
+    pub fn new() -> Self {
+        Self {
+            frequency: DEFAULT_FREQUENCY,
+            running: None,
+            phases: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Take `frequency` samples per second
+    pub fn with_frequency(mut self, frequency: i32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Interpret `program` and profile each of its phases
+    ///
+    /// Profiles from an earlier run are discarded. Fails with
+    /// [`ProfileError::Profiler`] if the profiler could not start or
+    /// report, for example because another profiler is running.
+    pub async fn run<H, R, M>(
+        &mut self,
+        handler: &mut H,
+        endpoint: &mut H::Endpoint,
+        program: Program<R, M>,
+    ) -> ProfileResult<InterpretResult<M>>
+    where
+        H: ChoreoHandler<Role = R> + Send,
+        R: RoleId,
+        M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+    {
+        self.phases.clear();
+        self.error = None;
+        let result = interpret_with_hooks(handler, endpoint, program, self).await;
+        // A program that failed leaves its last phase running
+        self.finish();
+        if let Some(error) = self.error.take() {
+            return Err(error.into());
+        }
+        Ok(result?)
+    }
+
+    /// Profiles of the phases run so far, in program order
+    pub fn phases(&self) -> &[PhaseProfile] {
+        &self.phases
+    }
+
+    /// Write a flamegraph for each phase into `dir`, creating it if needed
+    ///
+    /// Phases too short to be sampled have no flamegraph. Returns the paths
+    /// written, in program order.
+    pub fn write_flamegraphs(&self, dir: impl AsRef<Path>) -> ProfileResult<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let mut paths = Vec::with_capacity(self.phases.len());
+        for phase in self.phases.iter().filter(|phase| phase.samples() > 0) {
+            let path = dir.join(phase.file_name());
+            phase.flamegraph(std::fs::File::create(&path)?)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    fn start(&mut self, index: usize, name: String) {
+        self.finish();
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(self.frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build();
+        match guard {
+            Ok(guard) => {
+                self.running = Some(Running {
+                    index,
+                    name,
+                    started: Instant::now(),
+                    guard,
+                })
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn finish(&mut self) {
+        let Some(running) = self.running.take() else {
+            return;
+        };
+        let elapsed = running.started.elapsed();
+        match running.guard.report().build() {
+            Ok(report) => {
+                tracing::debug!(phase = %running.name, ?elapsed, "phase profiled");
+                self.phases.push(PhaseProfile {
+                    index: running.index,
+                    name: running.name,
+                    elapsed,
+                    report,
+                });
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn fail(&mut self, error: pprof::Error) {
+        tracing::warn!(%error, "profiling failed");
+        self.error.get_or_insert(error);
+    }
+}
+
+impl Default for PhaseProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<R: RoleId, M: ProgramMessage> InterpreterHooks<R, M> for PhaseProfiler {
+    async fn on_effect_start(&mut self, effect: &Effect<R, M>, ctx: &EffectContext) {
+        if ctx.scopes.is_empty() {
+            self.start(ctx.index, step_name(effect));
+        }
+    }
+
+    async fn on_effect_end(
+        &mut self,
+        _effect: &Effect<R, M>,
+        ctx: &EffectContext,
+        _result: &crate::effects::Result<()>,
+    ) {
+        if ctx.scopes.is_empty() {
+            self.finish();
+        }
+    }
+}
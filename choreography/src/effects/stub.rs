@@ -0,0 +1,171 @@
+// Record-and-replay stubs for external roles
+//
+// Some roles stand for systems a team does not control, such as a payment
+// provider or a legacy service. Tests should not depend on them, but a
+// hand-written mock drifts from what the real system does. A stub is
+// recorded once from a real run, by wrapping the handler of the adapter
+// that plays the external role in a `TraceRecorder`, and then replayed as
+// a participant. Replays are checked step by step against the role's
+// session type, so a recording that no longer fits the protocol fails
+// loudly instead of passing stale behaviour off as correct.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::marker::PhantomData;
+
+use crate::effects::conformance::debug_name;
+use crate::effects::differential::{RoleTrace, TraceEvent, TraceRecorder};
+use crate::effects::handlers::session::{SessionCursor, SessionType};
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result, RoleId};
+
+/// A recorded external role that can stand in for the real one
+///
+/// `M` is the message type the role sends; recorded payloads are decoded
+/// into it before they are replayed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StubRole<R, M> {
+    pub role: R,
+    pub events: Vec<TraceEvent<R>>,
+    #[serde(skip)]
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<R, M> StubRole<R, M>
+where
+    R: RoleId,
+    M: Serialize + DeserializeOwned + std::fmt::Debug + Send + Sync,
+{
+    /// Stub `role` with the effects it performed in a recorded run
+    pub fn new(role: R, events: Vec<TraceEvent<R>>) -> Self {
+        Self {
+            role,
+            events,
+            _message: PhantomData,
+        }
+    }
+
+    /// Stub `role` with everything `recorder` has seen so far
+    pub fn from_recorder<H: ChoreoHandler<Role = R>>(role: R, recorder: &TraceRecorder<H>) -> Self {
+        Self::new(role, recorder.events())
+    }
+
+    /// Stub the role of a trace from a differential run
+    pub fn from_trace(trace: &RoleTrace<R>) -> Self {
+        Self::new(trace.role, trace.events.clone())
+    }
+
+    /// Check the recording against the role's session type without
+    /// running it
+    ///
+    /// Receives are only checked for their sender, since the recording
+    /// does not hold the messages the role received.
+    pub fn check_against(&self, session: &SessionType<R>) -> Result<()> {
+        let mut cursor = SessionCursor::new(session.clone());
+        for event in &self.events {
+            match event {
+                TraceEvent::Send { to, message } => {
+                    let msg = decode::<M>(message)?;
+                    cursor.send(*to, &debug_name(&msg))?;
+                }
+                TraceEvent::Recv { from, .. } => match cursor.current() {
+                    SessionType::Receive {
+                        from: expected,
+                        message,
+                        ..
+                    } if expected == from => {
+                        let message = message.clone();
+                        cursor.receive(*from, &message)?;
+                    }
+                    _ => {
+                        return Err(ChoreographyError::SessionMismatch {
+                            expected: cursor.current().to_string(),
+                            found: format!("receive from {:?}", from),
+                        })
+                    }
+                },
+                TraceEvent::Choose { at, label } => cursor.select(*at, label)?,
+                TraceEvent::Offer { from, label } => cursor.branch(*from, label)?,
+            }
+        }
+        finished(&cursor)
+    }
+
+    /// Play the recorded role against live peers
+    ///
+    /// Sends the recorded messages and choices, and receives and offers
+    /// where the recording did. Every step is checked against `session`,
+    /// including the names of the messages actually received. Fails with a
+    /// protocol violation if a peer chooses a different branch than in the
+    /// recording, since the stub has nothing recorded for that branch.
+    pub async fn serve<H>(
+        &self,
+        handler: &mut H,
+        endpoint: &mut H::Endpoint,
+        session: &SessionType<R>,
+    ) -> Result<()>
+    where
+        H: ChoreoHandler<Role = R>,
+    {
+        let mut cursor = SessionCursor::new(session.clone());
+        for event in &self.events {
+            match event {
+                TraceEvent::Send { to, message } => {
+                    let msg = decode::<M>(message)?;
+                    cursor.send(*to, &debug_name(&msg))?;
+                    handler.send(endpoint, *to, &msg).await?;
+                }
+                TraceEvent::Recv { from, .. } => {
+                    let msg: M = handler.recv(endpoint, *from).await?;
+                    cursor.receive(*from, &debug_name(&msg))?;
+                }
+                TraceEvent::Choose { at, label } => {
+                    cursor.select(*at, label)?;
+                    let label = Label(Box::leak(label.clone().into_boxed_str()));
+                    handler.choose(endpoint, *at, label).await?;
+                }
+                TraceEvent::Offer { from, label } => {
+                    let chosen = handler.offer(endpoint, *from).await?;
+                    if chosen.0 != label {
+                        return Err(ChoreographyError::ProtocolViolation(format!(
+                            "stub for {:?} recorded branch {} from {:?}, but {} was chosen",
+                            self.role, label, from, chosen.0
+                        )));
+                    }
+                    cursor.branch(*from, label)?;
+                }
+            }
+        }
+        tracing::debug!(role = ?self.role, steps = cursor.steps(), "stub replay complete");
+        finished(&cursor)
+    }
+}
+
+impl<R, M> StubRole<R, M>
+where
+    R: Serialize + DeserializeOwned,
+{
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+}
+
+fn decode<M: DeserializeOwned>(message: &serde_json::Value) -> Result<M> {
+    serde_json::from_value(message.clone()).map_err(|e| {
+        ChoreographyError::Serialization(format!("recorded message does not decode: {}", e))
+    })
+}
+
+fn finished<R: Clone + PartialEq + std::fmt::Debug>(cursor: &SessionCursor<R>) -> Result<()> {
+    if cursor.is_complete() {
+        Ok(())
+    } else {
+        Err(ChoreographyError::SessionMismatch {
+            expected: cursor.current().to_string(),
+            found: "end of recording".to_string(),
+        })
+    }
+}
@@ -0,0 +1,276 @@
+// Driver for wire-compatibility test kits
+//
+// Plays every role of a kit except the one under test, following the
+// kit's sequences, and speaks to the implementation under test with the
+// same frames as `WebSocketHandler`. Driven roles reach each other over
+// in-process links. A driven role keeps the sequences that agree with
+// everything it has seen so far: it takes the first one's label when it
+// chooses, and narrows them by what arrives when it receives. The first
+// frame no remaining sequence allows ends the run with an error naming the
+// role and step.
+
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use crate::compiler::testkit::{KitStep, TestKit};
+use crate::effects::handlers::websocket::{Link, WireFrame};
+use crate::effects::{ChoreographyError, Label, Result};
+
+/// Plays the peers of the role a [`TestKit`] was generated for
+pub struct KitDriver {
+    kit: TestKit,
+    step_timeout: Duration,
+}
+
+/// What the driven roles did in a successful run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KitReport {
+    /// Steps taken by each driven role
+    pub steps: BTreeMap<String, usize>,
+}
+
+impl KitDriver {
+    pub fn new(kit: TestKit) -> Self {
+        Self {
+            kit,
+            step_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// How long to wait for a connection or for a frame the sequences
+    /// expect (10 seconds by default)
+    pub fn with_step_timeout(mut self, timeout: Duration) -> Self {
+        self.step_timeout = timeout;
+        self
+    }
+
+    /// Accept the implementation's WebSockets on `listener`, one per peer
+    /// at path `/<Peer>`, then play the session
+    // The handshake callback's error type is tungstenite's HTTP response
+    #[allow(clippy::result_large_err)]
+    pub async fn run(self, listener: TcpListener) -> Result<KitReport> {
+        let driven: Vec<String> = self
+            .kit
+            .roles
+            .iter()
+            .filter(|r| **r != self.kit.role)
+            .cloned()
+            .collect();
+        let mut links: HashMap<String, HashMap<String, Link>> =
+            driven.iter().map(|r| (r.clone(), HashMap::new())).collect();
+        for (i, a) in driven.iter().enumerate() {
+            for b in &driven[i + 1..] {
+                let (a_side, b_side) = Link::pair();
+                links
+                    .get_mut(a)
+                    .expect("driven role")
+                    .insert(b.clone(), a_side);
+                links
+                    .get_mut(b)
+                    .expect("driven role")
+                    .insert(a.clone(), b_side);
+            }
+        }
+
+        let mut waiting = self.kit.peers();
+        while !waiting.is_empty() {
+            let (stream, _) = tokio::time::timeout(self.step_timeout, listener.accept())
+                .await
+                .map_err(|_| ChoreographyError::Timeout(self.step_timeout))?
+                .map_err(|e| ChoreographyError::Transport(format!("accept failed: {}", e)))?;
+            let mut path = String::new();
+            let socket = tokio_tungstenite::accept_hdr_async(
+                stream,
+                |request: &tokio_tungstenite::tungstenite::handshake::server::Request, response| {
+                    path = request.uri().path().trim_start_matches('/').to_string();
+                    Ok(response)
+                },
+            )
+            .await
+            .map_err(|e| {
+                ChoreographyError::Transport(format!("WebSocket handshake failed: {}", e))
+            })?;
+            let Some(position) = waiting.iter().position(|p| *p == path) else {
+                return Err(ChoreographyError::ProtocolViolation(format!(
+                    "{} connected to /{}, but expects one of: {}",
+                    self.kit.role,
+                    path,
+                    waiting.join(", ")
+                )));
+            };
+            let peer = waiting.remove(position);
+            tracing::debug!(%peer, role = %self.kit.role, "test kit peer connected");
+            links
+                .get_mut(&peer)
+                .expect("peers are driven roles")
+                .insert(self.kit.role.clone(), Link::native(socket));
+        }
+
+        let mut tasks = Vec::new();
+        for role in driven {
+            let player = Player {
+                traces: self
+                    .kit
+                    .sequences(&role)
+                    .map(|s| s.traces.clone())
+                    .unwrap_or_default(),
+                links: links.remove(&role).unwrap_or_default(),
+                kit: self.kit.clone(),
+                step_timeout: self.step_timeout,
+                role: role.clone(),
+            };
+            tasks.push((role, tokio::spawn(player.play())));
+        }
+        let mut report = KitReport::default();
+        for (role, task) in tasks {
+            let steps = task.await.map_err(|e| {
+                ChoreographyError::Transport(format!("driver for {} stopped: {}", role, e))
+            })??;
+            report.steps.insert(role, steps);
+        }
+        Ok(report)
+    }
+}
+
+/// One driven role
+struct Player {
+    role: String,
+    traces: Vec<Vec<KitStep>>,
+    links: HashMap<String, Link>,
+    kit: TestKit,
+    step_timeout: Duration,
+}
+
+impl Player {
+    /// Follow the sequences to their end, returning the steps taken
+    async fn play(mut self) -> Result<usize> {
+        let mut step = 0;
+        loop {
+            let Some(next) = self.traces.iter().find_map(|t| t.get(step)).cloned() else {
+                return Ok(step);
+            };
+            if self.traces.iter().any(|t| t.len() == step) {
+                // The sequences part here on whether the role is done,
+                // which nothing it could receive would settle
+                return Ok(step);
+            }
+            if next.is_inbound() {
+                self.receive(step, next.peer()).await?;
+            } else {
+                let frame = match &next {
+                    KitStep::Send { message, .. } => WireFrame::Message(self.sample(message)?),
+                    KitStep::Choose { label, .. } => WireFrame::Label(label.clone()),
+                    _ => unreachable!("inbound steps are received"),
+                };
+                self.send(next.peer(), &frame)?;
+                self.traces.retain(|t| t.get(step) == Some(&next));
+            }
+            step += 1;
+        }
+    }
+
+    /// Take the next frame from `peer` and keep the sequences it matches
+    async fn receive(&mut self, step: usize, peer: &str) -> Result<()> {
+        let peer = peer.to_string();
+        let received = loop {
+            let frame = self.recv(&peer).await?;
+            let received = match frame {
+                WireFrame::Message(payload) => KitStep::Recv {
+                    peer: peer.clone(),
+                    message: self.message_name(&payload),
+                },
+                WireFrame::Label(label) => {
+                    if Label::system(&label).is_some_and(|l| l.is_keep_alive()) {
+                        continue;
+                    }
+                    KitStep::Offer {
+                        peer: peer.clone(),
+                        label,
+                    }
+                }
+            };
+            break received;
+        };
+        let mut expected: Vec<String> = self
+            .traces
+            .iter()
+            .filter_map(|t| t.get(step))
+            .map(|s| s.to_string())
+            .collect();
+        self.traces.retain(|t| t.get(step) == Some(&received));
+        if self.traces.is_empty() {
+            expected.sort();
+            expected.dedup();
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "{} step {}: expected {}, got {}",
+                self.role,
+                step,
+                expected.join(" or "),
+                received
+            )));
+        }
+        Ok(())
+    }
+
+    /// Name of the message a payload encodes, from its variant index
+    fn message_name(&self, payload: &[u8]) -> String {
+        payload
+            .get(..4)
+            .map(|index| u32::from_le_bytes([index[0], index[1], index[2], index[3]]))
+            .and_then(|index| self.kit.messages.iter().find(|m| m.index == index))
+            .map(|m| m.name.clone())
+            .unwrap_or_else(|| format!("an unknown message ({} bytes)", payload.len()))
+    }
+
+    fn sample(&self, message: &str) -> Result<Vec<u8>> {
+        let hex = self
+            .kit
+            .message(message)
+            .and_then(|m| m.sample_hex.as_deref())
+            .ok_or_else(|| {
+                ChoreographyError::Serialization(format!(
+                    "messages.json has no sample_hex for {}",
+                    message
+                ))
+            })?;
+        hex::decode(hex).map_err(|e| {
+            ChoreographyError::Serialization(format!("sample_hex of {}: {}", message, e))
+        })
+    }
+
+    fn link(&mut self, peer: &str) -> Result<&mut Link> {
+        let role = &self.role;
+        self.links.get_mut(peer).ok_or_else(|| {
+            ChoreographyError::Transport(format!("{} has no link to {}", role, peer))
+        })
+    }
+
+    fn send(&mut self, peer: &str, frame: &WireFrame) -> Result<()> {
+        let bytes = bincode::serialize(frame)
+            .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+        self.link(peer)?
+            .outgoing
+            .unbounded_send(bytes)
+            .map_err(|_| ChoreographyError::Transport(format!("link to {} is closed", peer)))
+    }
+
+    async fn recv(&mut self, peer: &str) -> Result<WireFrame> {
+        let timeout = self.step_timeout;
+        let role = self.role.clone();
+        let link = self.link(peer)?;
+        let bytes = tokio::time::timeout(timeout, link.incoming.next())
+            .await
+            .map_err(|_| {
+                tracing::warn!(%role, %peer, "test kit driver got nothing in time");
+                ChoreographyError::Timeout(timeout)
+            })?
+            .ok_or_else(|| {
+                ChoreographyError::Transport(format!("link from {} was closed", peer))
+            })?;
+        bincode::deserialize(&bytes).map_err(|e| {
+            ChoreographyError::Transport(format!("Malformed frame from {}: {}", peer, e))
+        })
+    }
+}
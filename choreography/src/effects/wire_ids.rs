@@ -0,0 +1,92 @@
+// Stable numeric ids for labels and messages on the wire
+//
+// By default a label travels as its name and a generated `Message` enum as
+// its variant index, which follows the order the messages are sorted in.
+// Renaming a branch or adding a message then silently changes what peers
+// built from the old choreography read. A choreography can instead give
+// each message and label an id of its own with `id Name = n`; generated
+// code encodes messages by those ids and hands the label ids to its
+// handlers, so both stay readable across any reordering of the DSL.
+//
+// Labels keep travelling as text: an id is sent in decimal, which no label
+// name can be mistaken for, and labels without an id, such as the runtime's
+// `sys.` labels, are sent by name as before.
+
+use crate::effects::Label;
+use serde::de::{self, DeserializeSeed, Deserializer, Visitor};
+use std::fmt;
+
+/// Wire ids of a choreography's labels or messages, by name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct WireIds(pub &'static [(&'static str, u32)]);
+
+impl WireIds {
+    /// The id `name` travels under, if it has one
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.0.iter().find(|(n, _)| *n == name).map(|(_, id)| *id)
+    }
+
+    /// The name given the id `id`, if any
+    pub fn name(&self, id: u32) -> Option<&'static str> {
+        self.0.iter().find(|(_, i)| *i == id).map(|(name, _)| *name)
+    }
+
+    /// The text `label` is sent as: its id in decimal, or its name if it
+    /// has no id
+    pub fn encode(&self, label: Label) -> String {
+        match self.id(label.0) {
+            Some(id) => id.to_string(),
+            None => label.0.to_string(),
+        }
+    }
+
+    /// The label sent as `wire`
+    ///
+    /// Ids map back to the name they were declared for. An id this side
+    /// does not know, as a newer peer may send, comes back as its decimal
+    /// text, so the offer's unknown-label policy decides what to do with
+    /// it.
+    pub fn decode(&self, wire: String) -> Label {
+        let known = match wire.parse::<u32>() {
+            Ok(id) => self.name(id).map(Label),
+            Err(_) => self
+                .0
+                .iter()
+                .map(|(name, _)| Label(name))
+                .find(|l| l.0 == wire),
+        };
+        // Labels are few and long-lived, so others are leaked
+        known
+            .or_else(|| Label::system(&wire))
+            .unwrap_or_else(|| Label(Box::leak(wire.into_boxed_str())))
+    }
+}
+
+/// Reads the variant of a generated `Message` enum as its id
+///
+/// Binary formats carry the id itself; self-describing ones such as JSON
+/// carry the variant name, which is looked up.
+impl<'de> DeserializeSeed<'de> for WireIds {
+    type Value = u32;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<u32, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for WireIds {
+    type Value = u32;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a message id or name")
+    }
+
+    fn visit_u64<E: de::Error>(self, id: u64) -> Result<u32, E> {
+        u32::try_from(id).map_err(|_| E::custom(format!("message id {} is out of range", id)))
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<u32, E> {
+        self.id(name)
+            .ok_or_else(|| E::custom(format!("unknown message {}", name)))
+    }
+}
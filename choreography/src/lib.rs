@@ -7,24 +7,64 @@
 //! global viewpoint, with automatic generation of local session types for each
 //! participant. This includes an effect handler system that decouples protocol
 //! logic from transport implementation.
+//!
+//! Most users only need the [`prelude`].
 
 pub mod ast;
 pub mod compiler;
 pub mod effects;
+pub mod prelude;
 pub mod runtime;
 
 // Re-export main APIs
-pub use ast::{Choreography, MessageType, Protocol, Role};
-pub use compiler::generate_effects_protocol;
-pub use effects::middleware::{Metrics, Retry, Trace};
+pub use ast::{Choreography, MessageType, PayloadField, Protocol, Role};
+pub use compiler::analysis::codes as diagnostic_codes;
+pub use compiler::{
+    analyze, generate_effects_protocol, AnalysisPass, AnalysisReport, Analyzer, CheckResult,
+    CompileConfig, Diagnostic, Severity,
+};
+#[cfg(feature = "otel")]
+pub use effects::middleware::Otel;
+pub use effects::middleware::{
+    read_journal, replay_journal, Journal, JournalEffect, JournalEntry, JournalOutcome, Replay,
+};
+pub use effects::middleware::{
+    Batching, Budget, BudgetLimits, BudgetUsage, Deadline, Encrypted, Histogram, Hop, Hops,
+    MessageProvenance, Metrics, MetricsKey, MetricsReport, Operation, OperationMetrics, Redaction,
+    Retry, Signed, Trace,
+};
+pub use effects::middleware::{FaultScenario, FaultSchedule};
+pub use effects::middleware::{TenancyError, TenantConfig, TenantGuard, TenantId, TenantRegistry};
+pub use effects::testing;
+pub use effects::Membership;
 pub use effects::NoOpHandler;
+pub use effects::WireIds;
 pub use effects::{
-    interpret, ChoreoHandler, ChoreoHandlerExt, ChoreographyError, Effect, Endpoint,
-    InterpretResult, InterpreterState, Label, Program, ProgramMessage, Result, RoleId,
+    interpret, interpret_many, interpret_many_limited, interpret_with_cancel,
+    interpret_with_guards, interpret_with_hooks, CancellationToken, ChoreoHandler,
+    ChoreoHandlerExt, ChoreographyError, Effect, EffectContext, Endpoint, ErrorContext,
+    InterpretResult, Interpreter, InterpreterHooks, InterpreterState, Label, Program,
+    ProgramMessage, Result, RoleId, UnknownLabel,
 };
+pub use effects::{Agreement, ChoreographyManifest, Introduction, SignedChoreography};
+pub use effects::{Computation, ComputeCache, InMemoryComputeCache, LoopCondition, Router};
+pub use effects::{Differential, Divergence, SessionTrace, StubRole};
+pub use effects::{DryRun, DryRunStep};
+pub use effects::{GrpcAcceptor, GrpcEndpoint, GrpcEnvelope, GrpcFailures, GrpcHandler};
+pub use effects::{Guard, GuardContext, GuardValue};
+pub use effects::{IdentityAuthority, KeyStore, RoleCertificate, RoleIdentity};
 pub use effects::{InMemoryHandler, RecordedEvent, RecordingHandler};
+#[cfg(all(not(target_arch = "wasm32"), feature = "profiling"))]
+pub use effects::{PhaseProfile, PhaseProfiler, ProfileError};
 pub use effects::{RumpsteakEndpoint, RumpsteakHandler, SimpleChannel};
+pub use effects::{SessionCursor, SessionType};
+#[cfg(all(not(target_arch = "wasm32"), feature = "tls"))]
+pub use effects::{TlsConfig, TlsEndpoint, TlsHandler};
+pub use effects::{ValidatedEffect, ValidationHandler};
+pub use effects::{WebSocketEndpoint, WebSocketHandler};
 pub use runtime::{spawn, spawn_local};
+#[cfg(not(target_arch = "wasm32"))]
+pub use runtime::{ExecutorConfig, SessionExecutor, SessionHandle, UpgradeCoordinator};
 
 // Re-export macros from rumpsteak-macros
 pub use rumpsteak_macros::choreography;
@@ -47,6 +87,18 @@ mod tests {
         let _label: Option<Label> = None;
     }
 
+    #[test]
+    fn test_prelude_exports() {
+        use crate::prelude::*;
+
+        let _choreography: Option<Choreography> = None;
+        let _handler: Option<InMemoryHandler<()>> = None;
+        let _mock: Option<MockHandler<()>> = None;
+        let _config = CompileConfig::new();
+        let program: Program<(), ()> = Program::new().send((), ()).end();
+        assert_eq!(program.send_count(), 1);
+    }
+
     #[test]
     fn test_free_algebra_integration() {
         use std::time::Duration;
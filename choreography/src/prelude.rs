@@ -0,0 +1,29 @@
+//! Commonly used items, for glob import
+//!
+//! ```
+//! use rumpsteak_choreography::prelude::*;
+//! ```
+//!
+//! Covers the AST and compiler entry points, the effect program builder and
+//! interpreter, the bundled handlers and middleware, and the mock handler for
+//! tests. `Result` is left out so the glob import does not shadow
+//! `std::result::Result`; use `rumpsteak_choreography::Result` explicitly.
+
+pub use crate::ast::{Choreography, MessageType, Protocol, Role};
+pub use crate::compiler::parser::parse_choreography_str;
+pub use crate::compiler::{
+    analyze, generate_effects_protocol, parse_choreography_str_with_config, project,
+    AnalysisReport, CompileConfig,
+};
+pub use crate::effects::testing::{MockHandler, MockResponse};
+pub use crate::effects::{
+    interpret, interpret_with_hooks, Budget, BudgetLimits, ChoreoHandler, ChoreoHandlerExt,
+    ChoreographyError, Effect, EffectContext, Endpoint, InMemoryHandler, InterpretResult,
+    InterpreterHooks, InterpreterState, Label, Metrics, NoOpHandler, Program, ProgramMessage,
+    RecordedEvent, RecordingHandler, Retry, RoleId, RumpsteakEndpoint, RumpsteakHandler, Trace,
+    ValidatedEffect, ValidationHandler,
+};
+pub use rumpsteak_macros::choreography;
+
+#[cfg(feature = "test-utils")]
+pub use crate::effects::FaultInjection;
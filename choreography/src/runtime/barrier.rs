@@ -0,0 +1,199 @@
+// Startup barriers
+//
+// Roles launched together race each other: a role that sends its first
+// message before the peer has registered its channel fails with a
+// transport error that says nothing about the real cause. A barrier holds
+// every role back until all declared roles are ready, or fails after a
+// timeout naming the roles that never showed up.
+//
+// `Barrier` is for roles running as tasks in one process, which arrive once
+// their channels are registered. `DistributedBarrier` is for roles in
+// different processes: each role exchanges a ready message with every peer
+// over its handler, retrying while the connections are still being set up.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Result, RoleId};
+
+fn not_ready<R: RoleId>(missing: &[R], waited: Duration) -> ChoreographyError {
+    ChoreographyError::NotReady {
+        missing: missing.iter().map(|role| format!("{:?}", role)).collect(),
+        waited,
+    }
+}
+
+/// Barrier for roles running as tasks in one process
+///
+/// Clones share the barrier, so one can be moved into each role's task.
+#[derive(Clone)]
+pub struct Barrier<R> {
+    roles: Arc<Vec<R>>,
+    arrived: Arc<Mutex<HashSet<R>>>,
+    notify: Arc<Notify>,
+    timeout: Duration,
+}
+
+impl<R: RoleId> Barrier<R> {
+    /// A barrier for `roles`, each of which waits at most `timeout`
+    pub fn new(roles: impl IntoIterator<Item = R>, timeout: Duration) -> Self {
+        Self {
+            roles: Arc::new(roles.into_iter().collect()),
+            arrived: Arc::new(Mutex::new(HashSet::new())),
+            notify: Arc::new(Notify::new()),
+            timeout,
+        }
+    }
+
+    /// Declared roles that have not arrived yet, in declaration order
+    pub fn missing(&self) -> Vec<R> {
+        let arrived = self.lock();
+        self.roles
+            .iter()
+            .filter(|role| !arrived.contains(role))
+            .copied()
+            .collect()
+    }
+
+    /// Mark `role` as ready, then wait until every declared role is
+    ///
+    /// Fails with [`ChoreographyError::NotReady`] listing the roles still
+    /// missing once the timeout has passed.
+    pub async fn arrive(&self, role: R) -> Result<()> {
+        if !self.roles.contains(&role) {
+            return Err(ChoreographyError::UnknownRole(format!("{:?}", role)));
+        }
+        self.lock().insert(role);
+        self.notify.notify_waiters();
+        tracing::debug!(?role, missing = ?self.missing(), "role arrived at barrier");
+
+        let all_arrived = async {
+            loop {
+                // Registered before the check, so no arrival is missed
+                let notified = self.notify.notified();
+                if self.missing().is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(self.timeout, all_arrived)
+            .await
+            .map_err(|_| not_ready(&self.missing(), self.timeout))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<R>> {
+        self.arrived
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// What a role sends each peer once it is ready
+#[derive(Debug, Serialize, Deserialize)]
+struct Ready;
+
+/// Barrier for roles in different processes
+///
+/// Each role sends a ready message to every peer and waits for one from
+/// each. The exchange is not part of the choreography, so run it on an
+/// endpoint that does not track a session type.
+#[derive(Debug, Clone)]
+pub struct DistributedBarrier<R> {
+    peers: Vec<R>,
+    timeout: Duration,
+    retry_interval: Duration,
+}
+
+impl<R: RoleId> DistributedBarrier<R> {
+    /// A barrier with every role in `peers`, waiting at most `timeout`
+    pub fn new(peers: impl IntoIterator<Item = R>, timeout: Duration) -> Self {
+        Self {
+            peers: peers.into_iter().collect(),
+            timeout,
+            retry_interval: Duration::from_millis(50),
+        }
+    }
+
+    /// How long to wait before retrying a peer that is not connected yet
+    pub fn with_retry_interval(mut self, interval: Duration) -> Self {
+        self.retry_interval = interval;
+        self
+    }
+
+    /// Exchange ready messages with every peer
+    ///
+    /// Sends and receives that fail, e.g. because the peer's channel is not
+    /// registered yet, are retried until the timeout. Then fails with
+    /// [`ChoreographyError::NotReady`] listing the peers not sent to or
+    /// not heard from.
+    pub async fn wait<H>(&self, handler: &mut H, endpoint: &mut H::Endpoint) -> Result<()>
+    where
+        H: ChoreoHandler<Role = R>,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut unsent = self.peers.clone();
+        let mut unheard = self.peers.clone();
+        let missing = |unsent: &[R], unheard: &[R]| {
+            let peers: Vec<R> = self
+                .peers
+                .iter()
+                .filter(|peer| unsent.contains(peer) || unheard.contains(peer))
+                .copied()
+                .collect();
+            not_ready(&peers, self.timeout)
+        };
+
+        while !unsent.is_empty() || !unheard.is_empty() {
+            let mut progressed = false;
+            let mut index = 0;
+            while index < unsent.len() {
+                match handler.send(endpoint, unsent[index], &Ready).await {
+                    Ok(()) => {
+                        unsent.remove(index);
+                        progressed = true;
+                    }
+                    Err(e) => {
+                        tracing::trace!(peer = ?unsent[index], error = %e, "peer not reachable yet");
+                        index += 1;
+                    }
+                }
+            }
+            if unsent.is_empty() {
+                let mut index = 0;
+                while index < unheard.len() {
+                    let peer = unheard[index];
+                    let received =
+                        tokio::time::timeout_at(deadline, handler.recv::<Ready>(endpoint, peer))
+                            .await
+                            .map_err(|_| missing(&unsent, &unheard))?;
+                    match received {
+                        Ok(Ready) => {
+                            unheard.remove(index);
+                            progressed = true;
+                            tracing::debug!(?peer, "peer ready");
+                        }
+                        Err(e) => {
+                            tracing::trace!(?peer, error = %e, "peer not ready yet");
+                            index += 1;
+                        }
+                    }
+                }
+            }
+            if unsent.is_empty() && unheard.is_empty() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(missing(&unsent, &unheard));
+            }
+            if !progressed {
+                tokio::time::sleep(self.retry_interval.min(deadline - Instant::now())).await;
+            }
+        }
+        Ok(())
+    }
+}
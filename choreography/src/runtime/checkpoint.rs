@@ -0,0 +1,89 @@
+// Checkpoints of a role's progress through a session
+//
+// A role that cannot stay resident, such as one hosted behind stateless
+// HTTP, rebuilds its place in the session on every activation. The
+// checkpoint is a journal of every step the role has taken, with the
+// messages and labels it received. Rerunning the role's program against
+// the journal brings it back to where it stopped without repeating any
+// send, after which it carries on live. Programs must be deterministic for
+// this to work: the same inputs must lead to the same steps.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::effects::{ChoreographyError, Result};
+use crate::runtime::{SessionId, SessionStatus};
+
+/// One step a role has taken, with what replaying it needs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalEntry<R> {
+    Sent {
+        to: R,
+    },
+    /// A message was received, in its JSON form
+    Received {
+        from: R,
+        message: serde_json::Value,
+    },
+    Chose {
+        at: R,
+        label: String,
+    },
+    Offered {
+        from: R,
+        label: String,
+    },
+}
+
+/// Everything needed to resume one role of a session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint<R> {
+    pub session: SessionId,
+    pub journal: Vec<JournalEntry<R>>,
+    pub status: SessionStatus,
+    /// Version of the stored session this checkpoint was loaded from
+    #[serde(skip)]
+    pub version: u64,
+}
+
+impl<R> Checkpoint<R> {
+    /// A session that has not taken any steps yet
+    pub fn new(session: SessionId) -> Self {
+        Self {
+            session,
+            journal: Vec::new(),
+            status: SessionStatus::Running,
+            version: 0,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.status == SessionStatus::Running
+    }
+}
+
+impl<R: Serialize + DeserializeOwned> Checkpoint<R> {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+    }
+}
+
+/// Where checkpoints are kept between activations
+///
+/// Every [`SessionStore`](crate::runtime::SessionStore) keeps checkpoints.
+#[async_trait]
+pub trait CheckpointStore<R>: Send + Sync {
+    async fn load_checkpoint(&self, session: &SessionId) -> Result<Option<Checkpoint<R>>>;
+
+    /// Store `checkpoint` in place of the one it was loaded from
+    ///
+    /// Fails if the session was saved since, so that only one of two
+    /// concurrent resumptions carries the session forward. On success
+    /// `checkpoint` takes the new version, so it can be saved again.
+    async fn save_checkpoint(&self, checkpoint: &mut Checkpoint<R>) -> Result<()>;
+}
@@ -0,0 +1,285 @@
+// Role directory and presence
+//
+// A deployment that scales its services up and down cannot list the
+// address of every role in advance. Each instance registers the address its
+// role can be reached at in a shared directory, with a time to live, and
+// keeps refreshing it while it runs. Peers resolve roles to addresses when
+// they set up a session and only see instances that refreshed within their
+// TTL, so an instance that crashes drops out once its TTL runs out.
+//
+// Roles are named as they print with `Debug`. The `redis` feature adds a
+// directory kept in Redis, which instances on different hosts can share.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::effects::{ChoreographyError, Result, RoleId};
+
+/// Where roles publish the addresses they can be reached at
+#[async_trait]
+pub trait RoleDirectory<R: RoleId>: Send + Sync {
+    /// Publish `address` for `role` for the next `ttl`
+    ///
+    /// Registering an address again refreshes it.
+    async fn register(&self, role: R, address: &str, ttl: Duration) -> Result<()>;
+
+    /// Withdraw `address` before its TTL runs out
+    async fn deregister(&self, role: R, address: &str) -> Result<()>;
+
+    /// Live addresses of `role`, in no particular order
+    async fn resolve(&self, role: R) -> Result<Vec<String>>;
+
+    /// One live address of `role`, failing if there is none
+    ///
+    /// Picks the lowest address, so peers resolving at the same time agree.
+    async fn lookup(&self, role: R) -> Result<String>
+    where
+        R: 'async_trait,
+    {
+        self.resolve(role).await?.into_iter().min().ok_or_else(|| {
+            ChoreographyError::Transport(format!("no live instance of role {:?}", role))
+        })
+    }
+}
+
+/// Directory kept in memory, for tests and single-process deployments
+///
+/// Clones share the same registrations.
+#[derive(Clone)]
+pub struct InMemoryDirectory<R> {
+    entries: Arc<Mutex<HashMap<R, HashMap<String, Instant>>>>,
+}
+
+impl<R: RoleId> InMemoryDirectory<R> {
+    pub fn new() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<R, HashMap<String, Instant>>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<R: RoleId> Default for InMemoryDirectory<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<R: RoleId> RoleDirectory<R> for InMemoryDirectory<R> {
+    async fn register(&self, role: R, address: &str, ttl: Duration) -> Result<()> {
+        self.lock()
+            .entry(role)
+            .or_default()
+            .insert(address.to_string(), Instant::now() + ttl);
+        Ok(())
+    }
+
+    async fn deregister(&self, role: R, address: &str) -> Result<()> {
+        if let Some(addresses) = self.lock().get_mut(&role) {
+            addresses.remove(address);
+        }
+        Ok(())
+    }
+
+    async fn resolve(&self, role: R) -> Result<Vec<String>> {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        let Some(addresses) = entries.get_mut(&role) else {
+            return Ok(Vec::new());
+        };
+        addresses.retain(|_, expires| *expires > now);
+        Ok(addresses.keys().cloned().collect())
+    }
+}
+
+/// A registration kept alive on the runtime
+///
+/// Dropping it stops the refreshes and withdraws the address in the
+/// background. [`leave`](Self::leave) waits until it is withdrawn.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Presence {
+    stop: Option<futures::channel::oneshot::Sender<()>>,
+    done: futures::channel::oneshot::Receiver<()>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Presence {
+    /// Register `address` for `role` and refresh it three times per `ttl`
+    ///
+    /// A refresh that fails is logged and tried again at the next one.
+    pub fn start<R, D>(
+        directory: Arc<D>,
+        role: R,
+        address: impl Into<String>,
+        ttl: Duration,
+    ) -> Self
+    where
+        R: RoleId + 'static,
+        D: RoleDirectory<R> + ?Sized + 'static,
+    {
+        use futures::channel::oneshot;
+
+        let address = address.into();
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let (finished, done) = oneshot::channel();
+        crate::runtime::spawn(async move {
+            loop {
+                if let Err(e) = directory.register(role, &address, ttl).await {
+                    tracing::warn!(?role, %address, error = %e, "presence refresh failed");
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(ttl / 3) => {}
+                    _ = &mut stopped => break,
+                }
+            }
+            if let Err(e) = directory.deregister(role, &address).await {
+                tracing::warn!(?role, %address, error = %e, "presence withdrawal failed");
+            }
+            let _ = finished.send(());
+        });
+        Self {
+            stop: Some(stop),
+            done,
+        }
+    }
+
+    /// Withdraw the address and wait until it is gone
+    pub async fn leave(mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        let _ = (&mut self.done).await;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for Presence {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+#[cfg(all(not(target_arch = "wasm32"), feature = "redis"))]
+pub use redis_directory::RedisDirectory;
+
+#[cfg(all(not(target_arch = "wasm32"), feature = "redis"))]
+mod redis_directory {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Directory kept in Redis
+    ///
+    /// Each role is a sorted set of its addresses, scored by the time in
+    /// milliseconds at which they expire. Expired addresses are removed
+    /// when the role is resolved, and the set itself expires once its last
+    /// address would have. Needs Redis 7 or later.
+    #[derive(Clone)]
+    pub struct RedisDirectory {
+        connection: redis::aio::MultiplexedConnection,
+        prefix: String,
+    }
+
+    fn directory_error(e: redis::RedisError) -> ChoreographyError {
+        ChoreographyError::Transport(format!("role directory: {}", e))
+    }
+
+    fn now_millis() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64)
+    }
+
+    impl RedisDirectory {
+        /// Keys start with `choreography:roles` unless given another prefix
+        pub fn new(connection: redis::aio::MultiplexedConnection) -> Self {
+            Self {
+                connection,
+                prefix: "choreography:roles".to_string(),
+            }
+        }
+
+        pub async fn connect(url: &str) -> Result<Self> {
+            let client = redis::Client::open(url).map_err(directory_error)?;
+            let connection = client
+                .get_multiplexed_async_connection()
+                .await
+                .map_err(directory_error)?;
+            Ok(Self::new(connection))
+        }
+
+        /// Keep this directory's keys apart from others in the same database
+        pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+            self.prefix = prefix.into();
+            self
+        }
+
+        fn key<R: RoleId>(&self, role: R) -> String {
+            format!("{}:{:?}", self.prefix, role)
+        }
+    }
+
+    #[async_trait]
+    impl<R: RoleId + 'static> RoleDirectory<R> for RedisDirectory {
+        async fn register(&self, role: R, address: &str, ttl: Duration) -> Result<()> {
+            let key = self.key(role);
+            let ttl = ttl.as_millis() as i64;
+            let mut connection = self.connection.clone();
+            // GT keeps the set alive for the longest-lived address
+            redis::pipe()
+                .atomic()
+                .zadd(&key, address, now_millis() + ttl)
+                .cmd("PEXPIRE")
+                .arg(&key)
+                .arg(ttl)
+                .arg("GT")
+                .cmd("PEXPIRE")
+                .arg(&key)
+                .arg(ttl)
+                .arg("NX")
+                .query_async::<()>(&mut connection)
+                .await
+                .map_err(directory_error)
+        }
+
+        async fn deregister(&self, role: R, address: &str) -> Result<()> {
+            let mut connection = self.connection.clone();
+            redis::cmd("ZREM")
+                .arg(self.key(role))
+                .arg(address)
+                .query_async::<()>(&mut connection)
+                .await
+                .map_err(directory_error)
+        }
+
+        async fn resolve(&self, role: R) -> Result<Vec<String>> {
+            let key = self.key(role);
+            let now = now_millis();
+            let mut connection = self.connection.clone();
+            let (addresses,): (Vec<String>,) = redis::pipe()
+                .atomic()
+                .cmd("ZREMRANGEBYSCORE")
+                .arg(&key)
+                .arg("-inf")
+                .arg(now)
+                .ignore()
+                .cmd("ZRANGEBYSCORE")
+                .arg(&key)
+                .arg(format!("({}", now))
+                .arg("+inf")
+                .query_async(&mut connection)
+                .await
+                .map_err(directory_error)?;
+            Ok(addresses)
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// Sharded session execution
+//
+// Spawning one task per session is simple but does not scale to many
+// thousands of mostly idle sessions on a server. The executor runs sessions
+// on a fixed set of worker tasks instead. Each worker owns a queue and polls
+// up to a fixed number of sessions concurrently; a worker with spare
+// capacity and an empty queue steals queued sessions from the others.
+
+use futures::channel::oneshot;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Errors reported through a [`SessionHandle`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ExecutorError {
+    #[error("session panicked")]
+    Panicked,
+
+    #[error("executor shut down before the session finished")]
+    ShutDown,
+}
+
+/// Sizing of a [`SessionExecutor`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutorConfig {
+    /// Number of worker tasks; defaults to the available parallelism
+    pub workers: usize,
+    /// Sessions each worker polls at once before leaving the rest queued
+    pub sessions_per_worker: usize,
+}
+
+impl Default for ExecutorConfig {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            sessions_per_worker: 1024,
+        }
+    }
+}
+
+impl ExecutorConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn with_sessions_per_worker(mut self, sessions: usize) -> Self {
+        self.sessions_per_worker = sessions.max(1);
+        self
+    }
+}
+
+/// Counters describing the executor's work so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutorStats {
+    pub submitted: u64,
+    pub completed: u64,
+    /// Sessions a worker took from another worker's queue
+    pub stolen: u64,
+    /// Sessions waiting in queues, not yet polled by any worker
+    pub queued: usize,
+}
+
+struct Shared {
+    queues: Vec<Mutex<VecDeque<Job>>>,
+    notify: Notify,
+    closed: AtomicBool,
+    next: AtomicUsize,
+    submitted: AtomicU64,
+    completed: AtomicU64,
+    stolen: AtomicU64,
+}
+
+impl Shared {
+    fn queue(&self, index: usize) -> std::sync::MutexGuard<'_, VecDeque<Job>> {
+        self.queues[index]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Next job for `worker`: its own queue first, then the back of the
+    /// longest other queue
+    fn take(&self, worker: usize) -> Option<Job> {
+        if let Some(job) = self.queue(worker).pop_front() {
+            return Some(job);
+        }
+        let victim = (0..self.queues.len())
+            .filter(|&i| i != worker)
+            .max_by_key(|&i| self.queue(i).len())?;
+        let job = self.queue(victim).pop_back()?;
+        self.stolen.fetch_add(1, Ordering::Relaxed);
+        Some(job)
+    }
+
+    fn queued(&self) -> usize {
+        (0..self.queues.len()).map(|i| self.queue(i).len()).sum()
+    }
+}
+
+/// Runs many sessions on a bounded set of worker tasks
+///
+/// Must be created inside a tokio runtime. Sessions are assigned to workers
+/// round-robin; idle workers steal from busy ones. Dropping the executor
+/// lets the workers finish every submitted session; use
+/// [`SessionExecutor::shutdown`] to wait for that.
+pub struct SessionExecutor {
+    shared: Arc<Shared>,
+    config: ExecutorConfig,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl SessionExecutor {
+    pub fn new(config: ExecutorConfig) -> Self {
+        let shared = Arc::new(Shared {
+            queues: (0..config.workers.max(1))
+                .map(|_| Mutex::new(VecDeque::new()))
+                .collect(),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            next: AtomicUsize::new(0),
+            submitted: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            stolen: AtomicU64::new(0),
+        });
+        let workers = (0..shared.queues.len())
+            .map(|index| {
+                tokio::spawn(run_worker(
+                    Arc::clone(&shared),
+                    index,
+                    config.sessions_per_worker.max(1),
+                ))
+            })
+            .collect();
+        Self {
+            shared,
+            config,
+            workers,
+        }
+    }
+
+    pub fn config(&self) -> &ExecutorConfig {
+        &self.config
+    }
+
+    /// Queue a session; the handle resolves to its output
+    pub fn submit<F, T>(&self, session: F) -> SessionHandle<T>
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let shared = Arc::clone(&self.shared);
+        let job: Job = Box::pin(async move {
+            let outcome = AssertUnwindSafe(session)
+                .catch_unwind()
+                .await
+                .map_err(|_| ExecutorError::Panicked);
+            if outcome.is_err() {
+                tracing::error!("session panicked on executor worker");
+            }
+            shared.completed.fetch_add(1, Ordering::Relaxed);
+            let _ = tx.send(outcome);
+        });
+
+        let index = self.shared.next.fetch_add(1, Ordering::Relaxed) % self.shared.queues.len();
+        self.shared.queue(index).push_back(job);
+        self.shared.submitted.fetch_add(1, Ordering::Relaxed);
+        self.shared.notify.notify_waiters();
+        SessionHandle { rx }
+    }
+
+    pub fn stats(&self) -> ExecutorStats {
+        ExecutorStats {
+            submitted: self.shared.submitted.load(Ordering::Relaxed),
+            completed: self.shared.completed.load(Ordering::Relaxed),
+            stolen: self.shared.stolen.load(Ordering::Relaxed),
+            queued: self.shared.queued(),
+        }
+    }
+
+    /// Stop accepting work and wait for every submitted session to finish
+    pub async fn shutdown(mut self) {
+        self.close();
+        for worker in self.workers.drain(..) {
+            let _ = worker.await;
+        }
+    }
+
+    fn close(&self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.notify.notify_waiters();
+    }
+}
+
+impl Drop for SessionExecutor {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+async fn run_worker(shared: Arc<Shared>, index: usize, capacity: usize) {
+    let mut active = FuturesUnordered::new();
+    loop {
+        // Register for wakeups before looking at the queues, so a session
+        // submitted in between is not missed
+        let notified = shared.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        while active.len() < capacity {
+            match shared.take(index) {
+                Some(job) => active.push(job),
+                None => break,
+            }
+        }
+
+        if active.is_empty() {
+            if shared.closed.load(Ordering::Acquire) && shared.queued() == 0 {
+                break;
+            }
+            notified.await;
+            continue;
+        }
+
+        tokio::select! {
+            Some(()) = active.next() => {}
+            _ = &mut notified => {}
+        }
+    }
+    tracing::trace!(worker = index, "executor worker stopped");
+}
+
+/// Output of a session submitted to a [`SessionExecutor`]
+pub struct SessionHandle<T> {
+    rx: oneshot::Receiver<Result<T, ExecutorError>>,
+}
+
+impl<T> Future for SessionHandle<T> {
+    type Output = Result<T, ExecutorError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.rx
+            .poll_unpin(cx)
+            .map(|result| result.unwrap_or(Err(ExecutorError::ShutDown)))
+    }
+}
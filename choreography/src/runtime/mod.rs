@@ -0,0 +1,87 @@
+// Runtime abstraction layer for cross-platform async execution
+//
+// Provides platform-specific implementations for spawning tasks and running futures.
+// Supports both native (Tokio) and WASM (wasm-bindgen-futures) targets.
+
+use std::future::Future;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod barrier;
+pub mod checkpoint;
+pub mod directory;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod executor;
+pub mod sessions;
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    any(feature = "sqlite", feature = "postgres")
+))]
+pub mod sql;
+pub mod store;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod upgrade;
+pub mod web;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use barrier::{Barrier, DistributedBarrier};
+pub use checkpoint::{Checkpoint, CheckpointStore, JournalEntry};
+#[cfg(not(target_arch = "wasm32"))]
+pub use directory::Presence;
+#[cfg(all(not(target_arch = "wasm32"), feature = "redis"))]
+pub use directory::RedisDirectory;
+pub use directory::{InMemoryDirectory, RoleDirectory};
+#[cfg(not(target_arch = "wasm32"))]
+pub use executor::{ExecutorConfig, ExecutorError, ExecutorStats, SessionExecutor, SessionHandle};
+pub use sessions::{SessionId, SessionInfo, SessionManager, SessionStatus, SpawnHandle};
+#[cfg(all(not(target_arch = "wasm32"), feature = "postgres"))]
+pub use sql::PostgresSessionStore;
+#[cfg(all(not(target_arch = "wasm32"), feature = "sqlite"))]
+pub use sql::SqliteSessionStore;
+pub use store::{InMemorySessionStore, SessionRecord, SessionStore};
+#[cfg(not(target_arch = "wasm32"))]
+pub use upgrade::{
+    coordinate_upgrade, participate_in_upgrade, SessionLease, UpgradeCoordinator, UpgradeError,
+    UpgradeMessage, UpgradeProgress, UPGRADE_PROTOCOL,
+};
+pub use web::{WebHost, WebMessage, WebRequest, WebResponse};
+
+/// Marker trait for runtime implementations (not used as trait object)
+pub trait AsyncRuntime: Send + Sync + 'static {}
+
+/// Helper function to spawn a task using platform-specific runtime
+///
+/// On native targets, uses `tokio::spawn`.
+/// On WASM targets, uses `wasm_bindgen_futures::spawn_local`.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tokio::spawn(future);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+}
+
+/// Helper function to spawn a local task using platform-specific runtime
+///
+/// On native targets, uses `tokio::task::spawn_local`.
+/// On WASM targets, uses `wasm_bindgen_futures::spawn_local`.
+pub fn spawn_local<F>(future: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tokio::task::spawn_local(future);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+}
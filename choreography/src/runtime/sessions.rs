@@ -0,0 +1,309 @@
+// Child sessions and their correlation with the sessions that started them
+//
+// A fan-out step in a protocol can start a sub-protocol as an independent
+// session and carry on without waiting for it. The manager gives each child
+// an id derived from its parent's, so every participant of the child names
+// it the same way without coordinating, and keeps track of which sessions
+// are still running and how the others ended.
+
+use futures::channel::oneshot;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::effects::{ChoreographyError, Result};
+use crate::runtime::store::{SessionRecord, SessionStore};
+
+/// Identifies one run of a choreography
+///
+/// Root sessions get an id from the application or a random one. A child
+/// started as `audit` is `<parent>/audit.<n>`, where `n` counts the children
+/// of that name started by the parent so far.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SessionId(String);
+
+impl SessionId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    /// A fresh id for a root session
+    pub fn random() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The id of the `index`th child named `name`
+    pub fn child(&self, name: &str, index: usize) -> Self {
+        Self(format!("{}/{}.{}", self.0, name, index))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How far a session has got
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    Running,
+    Completed,
+    Failed(String),
+}
+
+/// What the manager knows about one session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// The session that spawned this one, if any
+    pub parent: Option<SessionId>,
+    pub status: SessionStatus,
+}
+
+#[derive(Default)]
+struct Registry {
+    sessions: HashMap<SessionId, SessionInfo>,
+    /// Children started so far, by parent and name
+    spawned: HashMap<(SessionId, String), usize>,
+}
+
+/// Registry of running sessions and the children they spawned
+///
+/// Clones share the registry, so one manager can be handed to every session
+/// of a process.
+#[derive(Clone, Default)]
+pub struct SessionManager {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn registry(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.registry
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Register a root session as running
+    pub fn open(&self, id: SessionId) {
+        self.registry().sessions.insert(
+            id,
+            SessionInfo {
+                parent: None,
+                status: SessionStatus::Running,
+            },
+        );
+    }
+
+    /// Record how a session ended
+    pub fn finish(&self, id: &SessionId, status: SessionStatus) {
+        if let Some(info) = self.registry().sessions.get_mut(id) {
+            info.status = status;
+        }
+    }
+
+    /// Start a child of `parent` named `name` on the runtime
+    ///
+    /// `start` is given the child's id and returns the session to run. The
+    /// child runs whether or not the handle is ever awaited.
+    pub fn spawn<F, T>(
+        &self,
+        parent: &SessionId,
+        name: &str,
+        start: impl FnOnce(SessionId) -> F,
+    ) -> SpawnHandle<T>
+    where
+        F: Future<Output = Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let id = {
+            let mut registry = self.registry();
+            let count = registry
+                .spawned
+                .entry((parent.clone(), name.to_string()))
+                .or_default();
+            let id = parent.child(name, *count);
+            *count += 1;
+            registry.sessions.insert(
+                id.clone(),
+                SessionInfo {
+                    parent: Some(parent.clone()),
+                    status: SessionStatus::Running,
+                },
+            );
+            id
+        };
+        tracing::debug!(%parent, child = %id, "spawning child session");
+
+        let (tx, rx) = oneshot::channel();
+        let session = start(id.clone());
+        let manager = self.clone();
+        let child = id.clone();
+        crate::runtime::spawn(async move {
+            let outcome = AssertUnwindSafe(session)
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|_| {
+                    Err(ChoreographyError::Transport(format!(
+                        "child session {} panicked",
+                        child
+                    )))
+                });
+            let status = match &outcome {
+                Ok(_) => SessionStatus::Completed,
+                Err(e) => SessionStatus::Failed(e.to_string()),
+            };
+            manager.finish(&child, status);
+            let _ = tx.send(outcome);
+        });
+        SpawnHandle { id, rx }
+    }
+
+    pub fn status(&self, id: &SessionId) -> Option<SessionStatus> {
+        self.registry()
+            .sessions
+            .get(id)
+            .map(|info| info.status.clone())
+    }
+
+    pub fn info(&self, id: &SessionId) -> Option<SessionInfo> {
+        self.registry().sessions.get(id).cloned()
+    }
+
+    /// Sessions spawned by `parent`, in no particular order
+    pub fn children(&self, parent: &SessionId) -> Vec<SessionId> {
+        self.registry()
+            .sessions
+            .iter()
+            .filter(|(_, info)| info.parent.as_ref() == Some(parent))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Number of sessions still running
+    pub fn running(&self) -> usize {
+        self.registry()
+            .sessions
+            .values()
+            .filter(|info| info.status == SessionStatus::Running)
+            .count()
+    }
+
+    /// Record the parent and status of every session in `store`
+    ///
+    /// Checkpoints already stored are kept. A session the store has as
+    /// finished is left as it is.
+    pub async fn persist<S: SessionStore + ?Sized>(&self, store: &S) -> Result<()> {
+        let sessions: Vec<_> = self
+            .registry()
+            .sessions
+            .iter()
+            .map(|(id, info)| (id.clone(), info.clone()))
+            .collect();
+        for (id, info) in sessions {
+            // Only the manager's fields change, so a stale save is retried
+            loop {
+                let mut record = store
+                    .load(&id)
+                    .await?
+                    .unwrap_or_else(|| SessionRecord::new(id.clone()));
+                if record.version > 0 && record.status != SessionStatus::Running {
+                    break;
+                }
+                if record.version > 0
+                    && record.parent == info.parent
+                    && record.status == info.status
+                {
+                    break;
+                }
+                record.parent = info.parent.clone();
+                record.status = info.status.clone();
+                match store.save(&record).await {
+                    Ok(_) => break,
+                    Err(ChoreographyError::StaleSession { .. }) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Register the sessions in `store`, returning how many there were
+    ///
+    /// Children spawned afterwards are numbered after the stored ones, so
+    /// their ids stay unique.
+    pub async fn restore<S: SessionStore + ?Sized>(&self, store: &S) -> Result<usize> {
+        let records = store.sessions().await?;
+        let mut registry = self.registry();
+        for record in &records {
+            if let Some(parent) = &record.parent {
+                let numbered = record
+                    .id
+                    .as_str()
+                    .strip_prefix(parent.as_str())
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .and_then(|rest| rest.rsplit_once('.'))
+                    .and_then(|(name, n)| Some((name, n.parse::<usize>().ok()?)));
+                if let Some((name, index)) = numbered {
+                    let count = registry
+                        .spawned
+                        .entry((parent.clone(), name.to_string()))
+                        .or_default();
+                    *count = (*count).max(index + 1);
+                }
+            }
+            registry.sessions.insert(
+                record.id.clone(),
+                SessionInfo {
+                    parent: record.parent.clone(),
+                    status: record.status.clone(),
+                },
+            );
+        }
+        tracing::debug!(sessions = records.len(), "restored sessions");
+        Ok(records.len())
+    }
+}
+
+/// Outcome of a child session started with [`SessionManager::spawn`]
+pub struct SpawnHandle<T> {
+    id: SessionId,
+    rx: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> SpawnHandle<T> {
+    pub fn id(&self) -> &SessionId {
+        &self.id
+    }
+}
+
+impl<T> Future for SpawnHandle<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.rx.poll_unpin(cx).map(|result| {
+            result.unwrap_or_else(|_| {
+                Err(ChoreographyError::Transport(format!(
+                    "child session {} stopped before finishing",
+                    this.id
+                )))
+            })
+        })
+    }
+}
@@ -0,0 +1,173 @@
+// Session stores kept in SQL databases
+//
+// Sessions live in one table, created by `create_table`:
+//
+//     CREATE TABLE choreography_sessions (
+//         id TEXT PRIMARY KEY,
+//         parent TEXT,
+//         status TEXT NOT NULL,
+//         checkpoint TEXT,
+//         version BIGINT NOT NULL
+//     )
+//
+// The status is the JSON form of `SessionStatus`. A save is a single
+// statement that only matches the row at the version the record was based
+// on, so the database settles which of two concurrent saves wins.
+
+use async_trait::async_trait;
+
+use crate::effects::{ChoreographyError, Result};
+use crate::runtime::store::{stale, SessionRecord, SessionStore};
+use crate::runtime::{SessionId, SessionStatus};
+
+/// Name of the table sessions are kept in
+pub const SESSIONS_TABLE: &str = "choreography_sessions";
+
+/// A row of the sessions table
+type Row = (String, Option<String>, String, Option<String>, i64);
+
+fn storage_error(e: sqlx::Error) -> ChoreographyError {
+    ChoreographyError::Transport(format!("session store: {}", e))
+}
+
+fn into_record((id, parent, status, checkpoint, version): Row) -> Result<SessionRecord> {
+    let status: SessionStatus = serde_json::from_str(&status)
+        .map_err(|e| ChoreographyError::Serialization(e.to_string()))?;
+    Ok(SessionRecord {
+        id: SessionId::new(id),
+        parent: parent.map(SessionId::new),
+        status,
+        checkpoint,
+        version: u64::try_from(version).unwrap_or_default(),
+    })
+}
+
+fn status_json(record: &SessionRecord) -> Result<String> {
+    serde_json::to_string(&record.status)
+        .map_err(|e| ChoreographyError::Serialization(e.to_string()))
+}
+
+/// The same statements serve both databases, which accept `$n` parameters
+macro_rules! sql_session_store {
+    ($(#[$doc:meta])* $name:ident, $pool:ty) => {
+        $(#[$doc])*
+        #[derive(Clone)]
+        pub struct $name {
+            pool: $pool,
+        }
+
+        impl $name {
+            pub fn new(pool: $pool) -> Self {
+                Self { pool }
+            }
+
+            pub fn pool(&self) -> &$pool {
+                &self.pool
+            }
+
+            /// Create the sessions table unless it exists
+            pub async fn create_table(&self) -> Result<()> {
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        id TEXT PRIMARY KEY,
+                        parent TEXT,
+                        status TEXT NOT NULL,
+                        checkpoint TEXT,
+                        version BIGINT NOT NULL
+                    )",
+                    SESSIONS_TABLE
+                ))
+                .execute(&self.pool)
+                .await
+                .map_err(storage_error)?;
+                Ok(())
+            }
+
+            async fn stored_version(&self, id: &SessionId) -> Result<u64> {
+                Ok(SessionStore::load(self, id)
+                    .await?
+                    .map_or(0, |record| record.version))
+            }
+        }
+
+        #[async_trait]
+        impl SessionStore for $name {
+            async fn load(&self, id: &SessionId) -> Result<Option<SessionRecord>> {
+                let row: Option<Row> = sqlx::query_as(&format!(
+                    "SELECT id, parent, status, checkpoint, version FROM {} WHERE id = $1",
+                    SESSIONS_TABLE
+                ))
+                .bind(id.as_str())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(storage_error)?;
+                row.map(into_record).transpose()
+            }
+
+            async fn save(&self, record: &SessionRecord) -> Result<u64> {
+                let status = status_json(record)?;
+                let parent = record.parent.as_ref().map(SessionId::as_str);
+                let expected = i64::try_from(record.version)
+                    .map_err(|_| stale(record, u64::MAX))?;
+                let saved = if record.version == 0 {
+                    sqlx::query(&format!(
+                        "INSERT INTO {} (id, parent, status, checkpoint, version)
+                         VALUES ($1, $2, $3, $4, 1)
+                         ON CONFLICT (id) DO NOTHING",
+                        SESSIONS_TABLE
+                    ))
+                    .bind(record.id.as_str())
+                    .bind(parent)
+                    .bind(status)
+                    .bind(record.checkpoint.as_deref())
+                    .execute(&self.pool)
+                    .await
+                } else {
+                    sqlx::query(&format!(
+                        "UPDATE {} SET parent = $2, status = $3, checkpoint = $4,
+                         version = version + 1
+                         WHERE id = $1 AND version = $5",
+                        SESSIONS_TABLE
+                    ))
+                    .bind(record.id.as_str())
+                    .bind(parent)
+                    .bind(status)
+                    .bind(record.checkpoint.as_deref())
+                    .bind(expected)
+                    .execute(&self.pool)
+                    .await
+                }
+                .map_err(storage_error)?;
+                if saved.rows_affected() == 0 {
+                    return Err(stale(record, self.stored_version(&record.id).await?));
+                }
+                Ok(record.version + 1)
+            }
+
+            async fn sessions(&self) -> Result<Vec<SessionRecord>> {
+                let rows: Vec<Row> = sqlx::query_as(&format!(
+                    "SELECT id, parent, status, checkpoint, version FROM {}",
+                    SESSIONS_TABLE
+                ))
+                .fetch_all(&self.pool)
+                .await
+                .map_err(storage_error)?;
+                rows.into_iter().map(into_record).collect()
+            }
+        }
+    };
+}
+
+#[cfg(feature = "sqlite")]
+sql_session_store!(
+    /// Sessions kept in a SQLite database
+    SqliteSessionStore,
+    sqlx::SqlitePool
+);
+
+#[cfg(feature = "postgres")]
+sql_session_store!(
+    /// Sessions kept in a PostgreSQL database
+    PostgresSessionStore,
+    sqlx::PgPool
+);
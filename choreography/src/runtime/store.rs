@@ -0,0 +1,169 @@
+// Persistent session state
+//
+// Sessions that outlive a process, or that are resumed by whichever process
+// receives the next request, need their state kept somewhere shared. A
+// `SessionStore` keeps one record per session: the session that started
+// it, how far it has got, and the checkpoint of the role that resumes it.
+// Every save bumps the record's version. A save names the version it was
+// based on and fails if the session was saved elsewhere in between, so two
+// processes resuming the same session cannot both carry it forward.
+//
+// The `sqlite` and `postgres` features add stores kept in those databases.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::effects::{ChoreographyError, Result};
+use crate::runtime::checkpoint::{Checkpoint, CheckpointStore};
+use crate::runtime::{SessionId, SessionStatus};
+
+/// Everything stored about one session
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRecord {
+    pub id: SessionId,
+    pub parent: Option<SessionId>,
+    pub status: SessionStatus,
+    /// Checkpoint of the role that resumes the session, as JSON
+    pub checkpoint: Option<String>,
+    /// Number of times the session has been saved
+    pub version: u64,
+}
+
+impl SessionRecord {
+    /// A running session that has never been saved
+    pub fn new(id: SessionId) -> Self {
+        Self {
+            id,
+            parent: None,
+            status: SessionStatus::Running,
+            checkpoint: None,
+            version: 0,
+        }
+    }
+}
+
+/// Where session records are kept
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn load(&self, id: &SessionId) -> Result<Option<SessionRecord>>;
+
+    /// Store `record` if the stored session is still at `record.version`
+    ///
+    /// A record at version 0 is only stored if there is none for its
+    /// session yet. Returns the new version, or
+    /// [`ChoreographyError::StaleSession`] if the session was saved in
+    /// between.
+    async fn save(&self, record: &SessionRecord) -> Result<u64>;
+
+    /// Every session in the store, in no particular order
+    async fn sessions(&self) -> Result<Vec<SessionRecord>>;
+}
+
+pub(crate) fn stale(record: &SessionRecord, found: u64) -> ChoreographyError {
+    ChoreographyError::StaleSession {
+        session: record.id.to_string(),
+        expected: record.version,
+        found,
+    }
+}
+
+/// Checkpoints are kept in the record of their session
+///
+/// Saving a checkpoint fails with [`ChoreographyError::StaleSession`] if the
+/// session was saved after the checkpoint was loaded.
+#[async_trait]
+impl<R, S> CheckpointStore<R> for S
+where
+    R: Serialize + DeserializeOwned + Send + Sync,
+    S: SessionStore,
+{
+    async fn load_checkpoint(&self, session: &SessionId) -> Result<Option<Checkpoint<R>>> {
+        let Some(record) = self.load(session).await? else {
+            return Ok(None);
+        };
+        let mut checkpoint = match &record.checkpoint {
+            Some(json) => Checkpoint::from_json(json)?,
+            None => Checkpoint::new(record.id),
+        };
+        checkpoint.status = record.status;
+        checkpoint.version = record.version;
+        Ok(Some(checkpoint))
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &mut Checkpoint<R>) -> Result<()> {
+        let mut record = self
+            .load(&checkpoint.session)
+            .await?
+            .unwrap_or_else(|| SessionRecord::new(checkpoint.session.clone()));
+        if record.version != checkpoint.version {
+            return Err(ChoreographyError::StaleSession {
+                session: checkpoint.session.to_string(),
+                expected: checkpoint.version,
+                found: record.version,
+            });
+        }
+        record.status = checkpoint.status.clone();
+        record.checkpoint = Some(checkpoint.to_json()?);
+        checkpoint.version = self.save(&record).await?;
+        Ok(())
+    }
+}
+
+/// Sessions kept in memory, for tests and single-process deployments
+///
+/// Clones share the same sessions.
+#[derive(Clone, Default)]
+pub struct InMemorySessionStore {
+    records: Arc<Mutex<HashMap<SessionId, SessionRecord>>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of sessions stored
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, SessionRecord>> {
+        self.records
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn load(&self, id: &SessionId) -> Result<Option<SessionRecord>> {
+        Ok(self.lock().get(id).cloned())
+    }
+
+    async fn save(&self, record: &SessionRecord) -> Result<u64> {
+        let mut records = self.lock();
+        let found = records.get(&record.id).map_or(0, |stored| stored.version);
+        if found != record.version {
+            return Err(stale(record, found));
+        }
+        let version = found + 1;
+        records.insert(
+            record.id.clone(),
+            SessionRecord {
+                version,
+                ..record.clone()
+            },
+        );
+        Ok(version)
+    }
+
+    async fn sessions(&self) -> Result<Vec<SessionRecord>> {
+        Ok(self.lock().values().cloned().collect())
+    }
+}
@@ -0,0 +1,366 @@
+// Zero-downtime protocol upgrades
+//
+// A live service cannot stop every session to move to a new version of its
+// choreography. The upgrade coordinator keeps track of which version each
+// running session uses. Once an upgrade starts, new sessions get the new
+// version while sessions on the old one run to completion; the old version
+// is retired when its last session ends.
+//
+// Services agree on when to upgrade with the `Upgrade` choreography in
+// UPGRADE_PROTOCOL: a coordinator asks every participant whether it is
+// ready, then either commits, after which each participant switches and
+// reports once drained, or aborts.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::effects::{ChoreoHandler, ChoreographyError, Label, Result};
+
+/// The coordination protocol driven by [`coordinate_upgrade`] and
+/// [`participate_in_upgrade`]
+pub const UPGRADE_PROTOCOL: &str = r#"
+choreography Upgrade {
+    roles: Coordinator, Participant
+    Coordinator -> Participant: Prepare
+    Participant -> Coordinator: Ready
+    choice Coordinator {
+        commit: {
+            Coordinator -> Participant: Switch
+            Participant -> Coordinator: Drained
+        }
+        abort: {
+            Coordinator -> Participant: Cancel
+        }
+    }
+}
+"#;
+
+/// Errors from starting or finishing an upgrade
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum UpgradeError {
+    #[error("upgrade from {from} to {to} is already in progress")]
+    InProgress { from: String, to: String },
+
+    #[error("version {0} is already active")]
+    AlreadyActive(String),
+
+    #[error("no upgrade in progress")]
+    NotUpgrading,
+}
+
+/// Where an upgrade stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeProgress {
+    /// Version given to new sessions
+    pub active: String,
+    /// Version being retired, if an upgrade is in progress
+    pub draining: Option<String>,
+    /// Sessions still running on the retiring version
+    pub remaining: usize,
+    /// Sessions started on the active version since the upgrade began
+    pub started: usize,
+    /// Time since the upgrade began
+    pub elapsed: Duration,
+}
+
+impl UpgradeProgress {
+    pub fn is_drained(&self) -> bool {
+        self.draining.is_none() || self.remaining == 0
+    }
+}
+
+struct Upgrade {
+    from: String,
+    began: Instant,
+    started: usize,
+}
+
+struct State {
+    active: String,
+    upgrade: Option<Upgrade>,
+    sessions: BTreeMap<String, usize>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    drained: Notify,
+}
+
+/// Assigns protocol versions to sessions and drains old versions
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct UpgradeCoordinator {
+    shared: Arc<Shared>,
+}
+
+impl UpgradeCoordinator {
+    pub fn new(version: impl Into<String>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    active: version.into(),
+                    upgrade: None,
+                    sessions: BTreeMap::new(),
+                }),
+                drained: Notify::new(),
+            }),
+        }
+    }
+
+    /// Version given to new sessions
+    pub fn active_version(&self) -> String {
+        self.lock().active.clone()
+    }
+
+    /// Register a new session on the active version
+    ///
+    /// The session counts as running until the lease is dropped.
+    pub fn begin(&self) -> SessionLease {
+        let mut state = self.lock();
+        let version = state.active.clone();
+        *state.sessions.entry(version.clone()).or_default() += 1;
+        if let Some(upgrade) = &mut state.upgrade {
+            upgrade.started += 1;
+        }
+        SessionLease {
+            shared: Arc::clone(&self.shared),
+            version,
+        }
+    }
+
+    /// Send new sessions to `to` and start draining the current version
+    pub fn start_upgrade(&self, to: impl Into<String>) -> std::result::Result<(), UpgradeError> {
+        let to = to.into();
+        let mut state = self.lock();
+        if let Some(upgrade) = &state.upgrade {
+            return Err(UpgradeError::InProgress {
+                from: upgrade.from.clone(),
+                to: state.active.clone(),
+            });
+        }
+        if state.active == to {
+            return Err(UpgradeError::AlreadyActive(to));
+        }
+        let from = std::mem::replace(&mut state.active, to);
+        tracing::info!(%from, to = %state.active, "protocol upgrade started");
+        state.upgrade = Some(Upgrade {
+            from,
+            began: Instant::now(),
+            started: 0,
+        });
+        drop(state);
+        // Nothing may be running on the old version
+        self.shared.drained.notify_waiters();
+        Ok(())
+    }
+
+    /// Send new sessions back to the version being drained
+    ///
+    /// Sessions already started on the new version keep running.
+    pub fn rollback(&self) -> std::result::Result<(), UpgradeError> {
+        let mut state = self.lock();
+        let upgrade = state.upgrade.take().ok_or(UpgradeError::NotUpgrading)?;
+        tracing::warn!(from = %state.active, to = %upgrade.from, "protocol upgrade rolled back");
+        state.active = upgrade.from;
+        drop(state);
+        self.shared.drained.notify_waiters();
+        Ok(())
+    }
+
+    pub fn progress(&self) -> UpgradeProgress {
+        progress_of(&self.lock())
+    }
+
+    /// Wait until no session runs on the version being drained, then finish
+    /// the upgrade
+    ///
+    /// Returns immediately if no upgrade is in progress.
+    pub async fn drain(&self) -> UpgradeProgress {
+        loop {
+            let notified = self.shared.drained.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(progress) = self.finish_if_drained() {
+                return progress;
+            }
+            notified.await;
+        }
+    }
+
+    fn finish_if_drained(&self) -> Option<UpgradeProgress> {
+        let mut state = self.lock();
+        let progress = progress_of(&state);
+        if !progress.is_drained() {
+            return None;
+        }
+        if let Some(upgrade) = state.upgrade.take() {
+            tracing::info!(
+                from = %upgrade.from,
+                to = %state.active,
+                elapsed = ?upgrade.began.elapsed(),
+                "protocol upgrade complete"
+            );
+        }
+        Some(progress)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn progress_of(state: &State) -> UpgradeProgress {
+    let upgrade = state.upgrade.as_ref();
+    UpgradeProgress {
+        active: state.active.clone(),
+        draining: upgrade.map(|u| u.from.clone()),
+        remaining: upgrade
+            .and_then(|u| state.sessions.get(&u.from))
+            .copied()
+            .unwrap_or(0),
+        started: upgrade.map_or(0, |u| u.started),
+        elapsed: upgrade.map_or(Duration::ZERO, |u| u.began.elapsed()),
+    }
+}
+
+/// A running session's claim on its protocol version
+pub struct SessionLease {
+    shared: Arc<Shared>,
+    version: String,
+}
+
+impl SessionLease {
+    /// Version this session must run
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+impl Drop for SessionLease {
+    fn drop(&mut self) {
+        let mut state = self
+            .shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let remaining = state.sessions.get_mut(&self.version).map(|count| {
+            *count = count.saturating_sub(1);
+            *count
+        });
+        if remaining == Some(0) {
+            state.sessions.remove(&self.version);
+            drop(state);
+            self.shared.drained.notify_waiters();
+        }
+    }
+}
+
+/// Messages of the `Upgrade` choreography
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpgradeMessage {
+    Prepare { to: String },
+    Ready { ready: bool },
+    Switch,
+    Cancel,
+    Drained { from: String },
+}
+
+/// Coordinator side of [`UPGRADE_PROTOCOL`]
+///
+/// Asks every participant to prepare for version `to`. If all of them are
+/// ready, commits and waits until each reports its old sessions drained;
+/// otherwise aborts. Returns whether the upgrade was committed.
+pub async fn coordinate_upgrade<H: ChoreoHandler>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    participants: &[H::Role],
+    to: &str,
+) -> Result<bool> {
+    let prepare = UpgradeMessage::Prepare { to: to.to_string() };
+    for participant in participants {
+        handler.send(endpoint, *participant, &prepare).await?;
+    }
+    let mut all_ready = true;
+    for participant in participants {
+        match handler.recv(endpoint, *participant).await? {
+            UpgradeMessage::Ready { ready } => all_ready &= ready,
+            other => return Err(unexpected("Ready", &other)),
+        }
+    }
+
+    let (label, message) = if all_ready {
+        (Label("commit"), UpgradeMessage::Switch)
+    } else {
+        (Label("abort"), UpgradeMessage::Cancel)
+    };
+    for participant in participants {
+        handler.choose(endpoint, *participant, label).await?;
+        handler.send(endpoint, *participant, &message).await?;
+    }
+    if all_ready {
+        for participant in participants {
+            match handler.recv(endpoint, *participant).await? {
+                UpgradeMessage::Drained { from } => {
+                    tracing::info!(?participant, %from, to, "participant drained")
+                }
+                other => return Err(unexpected("Drained", &other)),
+            }
+        }
+    }
+    Ok(all_ready)
+}
+
+/// Participant side of [`UPGRADE_PROTOCOL`]
+///
+/// Reports ready unless `local` is already upgrading or runs the requested
+/// version. On commit, starts the upgrade locally and replies once the old
+/// version's sessions have drained. Returns whether the upgrade happened.
+pub async fn participate_in_upgrade<H: ChoreoHandler>(
+    handler: &mut H,
+    endpoint: &mut H::Endpoint,
+    coordinator: H::Role,
+    local: &UpgradeCoordinator,
+) -> Result<bool> {
+    let to = match handler.recv(endpoint, coordinator).await? {
+        UpgradeMessage::Prepare { to } => to,
+        other => return Err(unexpected("Prepare", &other)),
+    };
+    let progress = local.progress();
+    let ready = progress.draining.is_none() && progress.active != to;
+    handler
+        .send(endpoint, coordinator, &UpgradeMessage::Ready { ready })
+        .await?;
+
+    let label = handler.offer(endpoint, coordinator).await?;
+    let message: UpgradeMessage = handler.recv(endpoint, coordinator).await?;
+    match (label.0, message) {
+        ("commit", UpgradeMessage::Switch) => {
+            let from = local.active_version();
+            local
+                .start_upgrade(to)
+                .map_err(|e| ChoreographyError::ProtocolViolation(e.to_string()))?;
+            local.drain().await;
+            handler
+                .send(endpoint, coordinator, &UpgradeMessage::Drained { from })
+                .await?;
+            Ok(true)
+        }
+        ("abort", UpgradeMessage::Cancel) => Ok(false),
+        (label, other) => Err(unexpected(label, &other)),
+    }
+}
+
+fn unexpected(expected: &str, found: &UpgradeMessage) -> ChoreographyError {
+    ChoreographyError::ProtocolViolation(format!(
+        "upgrade protocol expected {}, received {:?}",
+        expected, found
+    ))
+}
@@ -0,0 +1,389 @@
+// Sessions advanced by HTTP requests
+//
+// The common way to run a workflow behind a web server is to keep no state
+// in the server at all. A `WebHost` hosts one role of a choreography this
+// way: each request may carry the client's next message or branch label,
+// and the host reruns the role's program from the session's checkpoint,
+// feeds it the input, and lets it run until it needs the client again. The
+// messages and labels it produced for the client form the response, and
+// the extended checkpoint is saved for the next request.
+//
+// The host is independent of the web framework. `WebRequest` and
+// `WebResponse` are plain serde types, so an actix-web or axum handler only
+// has to decode one and encode the other.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::effects::{
+    interpret, ChoreoHandler, ChoreographyError, InterpreterState, Label, Program, ProgramMessage,
+    Result, RoleId,
+};
+use crate::runtime::checkpoint::{Checkpoint, CheckpointStore, JournalEntry};
+use crate::runtime::{SessionId, SessionStatus};
+
+/// A message or branch label exchanged with the client
+///
+/// Messages are in the JSON form of the choreography's message type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebMessage {
+    Message(Value),
+    Label(String),
+}
+
+/// One request from the client
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WebRequest {
+    /// The session to advance; a new session is started without one
+    #[serde(default)]
+    pub session: Option<SessionId>,
+    /// The client's next message or label, if the session is waiting for it
+    #[serde(default)]
+    pub input: Option<WebMessage>,
+}
+
+/// What the hosted role did in response to a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebResponse {
+    pub session: SessionId,
+    /// Messages and labels for the client, in order
+    pub outputs: Vec<WebMessage>,
+    /// `Running` while the session waits for the client's next request
+    pub status: SessionStatus,
+}
+
+/// One role of a choreography hosted behind stateless requests
+///
+/// `client` is the role played by whoever sends the requests. The hosted
+/// role's other peers are reached through the handler passed to
+/// [`handle`](Self::handle); pass a `NoOpHandler` if there are none.
+pub struct WebHost<R: RoleId, M, S> {
+    client: R,
+    program: Program<R, M>,
+    store: S,
+}
+
+impl<R, M, S> WebHost<R, M, S>
+where
+    R: RoleId + 'static,
+    M: ProgramMessage + Serialize + DeserializeOwned + 'static,
+    S: CheckpointStore<R>,
+{
+    /// Host the role that runs `program`, keeping checkpoints in `store`
+    ///
+    /// The program is rerun from the start on every request, so it must
+    /// take the same steps given the same inputs.
+    pub fn new(client: R, program: Program<R, M>, store: S) -> Self {
+        Self {
+            client,
+            program,
+            store,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Advance a session by one request
+    ///
+    /// Fails without saving anything if the session is unknown or has
+    /// ended, if the program no longer matches the checkpoint, or if the
+    /// input is not what the session was waiting for, so the client can
+    /// retry with a different input.
+    pub async fn handle<H>(
+        &self,
+        handler: H,
+        endpoint: &mut H::Endpoint,
+        request: WebRequest,
+    ) -> Result<WebResponse>
+    where
+        H: ChoreoHandler<Role = R>,
+    {
+        let mut checkpoint = match &request.session {
+            Some(id) => self.store.load_checkpoint(id).await?.ok_or_else(|| {
+                ChoreographyError::ProtocolViolation(format!("unknown session {}", id))
+            })?,
+            None => Checkpoint::new(SessionId::random()),
+        };
+        if !checkpoint.is_running() {
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "session {} has already ended",
+                checkpoint.session
+            )));
+        }
+
+        let recorded = checkpoint.journal.len();
+        let mut scoped = RequestScoped::<H, M> {
+            inner: handler,
+            client: self.client,
+            journal: std::mem::take(&mut checkpoint.journal),
+            replayed: 0,
+            input: request.input,
+            outputs: Vec::new(),
+            waiting: false,
+            rejected: None,
+            _message: PhantomData,
+        };
+        let result = interpret(&mut scoped, endpoint, self.program.clone()).await?;
+
+        if let Some(reason) = scoped.rejected {
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "session {} {}",
+                checkpoint.session, reason
+            )));
+        }
+        if scoped.replayed < recorded {
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "checkpoint of session {} has steps the program no longer takes",
+                checkpoint.session
+            )));
+        }
+        if scoped.input.is_some() {
+            return Err(ChoreographyError::ProtocolViolation(format!(
+                "session {} was not waiting for input from {:?}",
+                checkpoint.session, self.client
+            )));
+        }
+        let status = match result.final_state {
+            InterpreterState::Completed => SessionStatus::Completed,
+            InterpreterState::Failed(_) if scoped.waiting => SessionStatus::Running,
+            InterpreterState::Failed(reason) => SessionStatus::Failed(reason),
+            InterpreterState::Timeout => SessionStatus::Failed("timed out".to_string()),
+            InterpreterState::Cancelled => SessionStatus::Failed("cancelled".to_string()),
+        };
+
+        checkpoint.journal = scoped.journal;
+        checkpoint.status = status.clone();
+        self.store.save_checkpoint(&mut checkpoint).await?;
+        tracing::debug!(
+            session = %checkpoint.session,
+            steps = checkpoint.journal.len(),
+            ?status,
+            "web request handled"
+        );
+        Ok(WebResponse {
+            session: checkpoint.session,
+            outputs: scoped.outputs,
+            status,
+        })
+    }
+}
+
+/// Replays the journal, then serves the client from the request and
+/// everyone else through the wrapped handler
+struct RequestScoped<H: ChoreoHandler, M> {
+    inner: H,
+    client: H::Role,
+    journal: Vec<JournalEntry<H::Role>>,
+    /// Journal entries replayed so far
+    replayed: usize,
+    input: Option<WebMessage>,
+    outputs: Vec<WebMessage>,
+    /// Set when the program stopped to wait for the client
+    waiting: bool,
+    /// Why the client's input does not fit the step it was given to
+    rejected: Option<String>,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<H: ChoreoHandler, M> RequestScoped<H, M> {
+    /// The next journal entry, while there are any left to replay
+    fn recorded(&mut self) -> Option<JournalEntry<H::Role>> {
+        let entry = self.journal.get(self.replayed).cloned();
+        if entry.is_some() {
+            self.replayed += 1;
+        }
+        entry
+    }
+
+    fn mismatch(&self, entry: &JournalEntry<H::Role>, step: String) -> ChoreographyError {
+        ChoreographyError::ProtocolViolation(format!(
+            "checkpoint does not match the program: step {} was {:?}, but the program tries to {}",
+            self.replayed, entry, step
+        ))
+    }
+
+    fn reject(&mut self, reason: String) -> ChoreographyError {
+        self.rejected = Some(reason.clone());
+        ChoreographyError::ProtocolViolation(reason)
+    }
+
+    /// The client's input, or stop here until the next request brings it
+    fn take_input(&mut self) -> Result<WebMessage> {
+        self.input.take().ok_or_else(|| {
+            self.waiting = true;
+            ChoreographyError::Transport(format!(
+                "waiting for the next request from {:?}",
+                self.client
+            ))
+        })
+    }
+}
+
+fn to_json<T: Serialize>(msg: &T) -> Result<Value> {
+    serde_json::to_value(msg).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(value: Value) -> Result<T> {
+    serde_json::from_value(value).map_err(|e| ChoreographyError::Serialization(e.to_string()))
+}
+
+fn label(name: String) -> Label {
+    // Labels are few and long-lived, as in the rumpsteak handler
+    Label::system(&name).unwrap_or_else(|| Label(Box::leak(name.into_boxed_str())))
+}
+
+#[async_trait]
+impl<H, M> ChoreoHandler for RequestScoped<H, M>
+where
+    H: ChoreoHandler,
+    M: Serialize + DeserializeOwned + Send + Sync,
+{
+    type Role = H::Role;
+    type Endpoint = H::Endpoint;
+
+    async fn send<T: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: Self::Role,
+        msg: &T,
+    ) -> Result<()> {
+        match self.recorded() {
+            Some(JournalEntry::Sent { to: recorded }) if recorded == to => return Ok(()),
+            Some(entry) => return Err(self.mismatch(&entry, format!("send to {:?}", to))),
+            None => {}
+        }
+        if to == self.client {
+            self.outputs.push(WebMessage::Message(to_json(msg)?));
+        } else {
+            self.inner.send(ep, to, msg).await?;
+        }
+        self.journal.push(JournalEntry::Sent { to });
+        self.replayed = self.journal.len();
+        Ok(())
+    }
+
+    async fn recv<T: DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: Self::Role,
+    ) -> Result<T> {
+        match self.recorded() {
+            Some(JournalEntry::Received {
+                from: recorded,
+                message,
+            }) if recorded == from => return from_json(message),
+            Some(entry) => return Err(self.mismatch(&entry, format!("receive from {:?}", from))),
+            None => {}
+        }
+        let message = if from == self.client {
+            match self.take_input()? {
+                WebMessage::Message(message) => message,
+                WebMessage::Label(label) => {
+                    return Err(self.reject(format!(
+                        "expected a message from {:?}, got branch label {}",
+                        from, label
+                    )))
+                }
+            }
+        } else {
+            let msg: M = self.inner.recv(ep, from).await?;
+            to_json(&msg)?
+        };
+        let msg = match from_json(message.clone()) {
+            Ok(msg) => msg,
+            Err(e) if from == self.client => {
+                return Err(self.reject(format!(
+                    "got a message from {:?} that does not decode: {}",
+                    from, e
+                )))
+            }
+            Err(e) => return Err(e),
+        };
+        self.journal.push(JournalEntry::Received { from, message });
+        self.replayed = self.journal.len();
+        Ok(msg)
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: Self::Role,
+        label: Label,
+    ) -> Result<()> {
+        match self.recorded() {
+            Some(JournalEntry::Chose {
+                at,
+                label: recorded,
+            }) if at == who && recorded == label.0 => return Ok(()),
+            Some(entry) => return Err(self.mismatch(&entry, format!("choose {}", label.0))),
+            None => {}
+        }
+        self.outputs.push(WebMessage::Label(label.0.to_string()));
+        if who != self.client {
+            self.inner.choose(ep, who, label).await?;
+        }
+        self.journal.push(JournalEntry::Chose {
+            at: who,
+            label: label.0.to_string(),
+        });
+        self.replayed = self.journal.len();
+        Ok(())
+    }
+
+    async fn offer(&mut self, ep: &mut Self::Endpoint, from: Self::Role) -> Result<Label> {
+        match self.recorded() {
+            Some(JournalEntry::Offered {
+                from: recorded,
+                label: chosen,
+            }) if recorded == from => return Ok(label(chosen)),
+            Some(entry) => return Err(self.mismatch(&entry, format!("offer from {:?}", from))),
+            None => {}
+        }
+        let chosen = if from == self.client {
+            match self.take_input()? {
+                WebMessage::Label(chosen) => label(chosen),
+                WebMessage::Message(_) => {
+                    return Err(self.reject(format!(
+                        "expected a branch label from {:?}, got a message",
+                        from
+                    )))
+                }
+            }
+        } else {
+            self.inner.offer(ep, from).await?
+        };
+        self.journal.push(JournalEntry::Offered {
+            from,
+            label: chosen.0.to_string(),
+        });
+        self.replayed = self.journal.len();
+        Ok(chosen)
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: Self::Role,
+        dur: Duration,
+        body: F,
+    ) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+
+    async fn on_cancel(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.on_cancel(ep).await
+    }
+
+    async fn flush(&mut self, ep: &mut Self::Endpoint) -> Result<()> {
+        self.inner.flush(ep).await
+    }
+}
@@ -0,0 +1,103 @@
+// Integration tests for startup barriers
+
+use rumpsteak_choreography::effects::{ChoreographyError, InMemoryHandler};
+use rumpsteak_choreography::runtime::{Barrier, DistributedBarrier};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Client,
+    Server,
+    Auditor,
+}
+
+#[tokio::test]
+async fn test_local_barrier_releases_once_every_role_arrived() {
+    let barrier = Barrier::new(
+        [Role::Client, Role::Server, Role::Auditor],
+        Duration::from_secs(5),
+    );
+
+    let tasks: Vec<_> = [Role::Client, Role::Server, Role::Auditor]
+        .into_iter()
+        .enumerate()
+        .map(|(i, role)| {
+            let barrier = barrier.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20 * i as u64)).await;
+                barrier.arrive(role).await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    assert!(barrier.missing().is_empty());
+}
+
+#[tokio::test]
+async fn test_local_barrier_names_missing_roles() {
+    let barrier = Barrier::new(
+        [Role::Client, Role::Server, Role::Auditor],
+        Duration::from_millis(50),
+    );
+
+    let server = {
+        let barrier = barrier.clone();
+        tokio::spawn(async move { barrier.arrive(Role::Server).await })
+    };
+    match barrier.arrive(Role::Client).await {
+        Err(ChoreographyError::NotReady { missing, .. }) => {
+            assert_eq!(missing, vec!["Auditor".to_string()])
+        }
+        other => panic!("expected the auditor to be missing, got {:?}", other),
+    }
+    let error = server.await.unwrap().unwrap_err();
+    assert!(error.to_string().contains("Auditor"), "{}", error);
+
+    let solo = Barrier::new([Role::Client], Duration::from_millis(50));
+    assert!(matches!(
+        solo.arrive(Role::Server).await,
+        Err(ChoreographyError::UnknownRole(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_distributed_barrier_waits_for_late_peers() {
+    let channels = Arc::default();
+    let choices = Arc::default();
+    let handler =
+        |role| InMemoryHandler::with_channels(role, Arc::clone(&channels), Arc::clone(&choices));
+    let mut client = handler(Role::Client);
+    let mut server = handler(Role::Server);
+
+    let barrier = DistributedBarrier::new([Role::Server], Duration::from_secs(5))
+        .with_retry_interval(Duration::from_millis(5));
+    let late = tokio::spawn(async move {
+        // The server starts after the client is already waiting for it
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        DistributedBarrier::new([Role::Client], Duration::from_secs(5))
+            .wait(&mut server, &mut ())
+            .await
+    });
+    barrier.wait(&mut client, &mut ()).await.unwrap();
+    late.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_distributed_barrier_names_peers_never_heard_from() {
+    let channels = Arc::default();
+    let choices = Arc::default();
+    let mut client = InMemoryHandler::with_channels(Role::Client, channels, choices);
+
+    let barrier = DistributedBarrier::new([Role::Server, Role::Auditor], Duration::from_millis(60))
+        .with_retry_interval(Duration::from_millis(5));
+    match barrier.wait(&mut client, &mut ()).await {
+        Err(ChoreographyError::NotReady { missing, waited }) => {
+            assert_eq!(missing, vec!["Server".to_string(), "Auditor".to_string()]);
+            assert_eq!(waited, Duration::from_millis(60));
+        }
+        other => panic!("expected both peers to be missing, got {:?}", other),
+    }
+}
@@ -0,0 +1,208 @@
+// Integration tests for bridging sessions of two choreographies
+
+use rumpsteak_choreography::effects::{BridgeConfig, InMemoryHandler};
+use rumpsteak_choreography::{interpret, InterpreterState, Label, Program, RoleId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// The ordering choreography, where the bridge stands in for fulfilment
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Shop {
+    Buyer,
+    Fulfilment,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum ShopMessage {
+    Order { item: String, quantity: u32 },
+    Confirmation { tracking: String },
+}
+
+// The fulfilment choreography split out of it, where the bridge is the shop
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Warehouse {
+    Shop,
+    Picker,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum WarehouseMessage {
+    Pick { item: String, quantity: u32 },
+    Shipped { tracking: String },
+}
+
+type Channels<R> = (
+    Arc<Mutex<HashMap<(R, R), Pairs<Vec<u8>>>>>,
+    Arc<Mutex<HashMap<(R, R), Pairs<Label>>>>,
+);
+type Pairs<T> = (
+    futures::channel::mpsc::UnboundedSender<T>,
+    futures::channel::mpsc::UnboundedReceiver<T>,
+);
+
+fn handler<R: RoleId + 'static>(role: R, channels: &Channels<R>) -> InMemoryHandler<R> {
+    InMemoryHandler::with_channels(role, Arc::clone(&channels.0), Arc::clone(&channels.1))
+}
+
+fn config() -> BridgeConfig<Shop, Warehouse> {
+    BridgeConfig::new()
+        .forward(Shop::Buyer, "Order", Warehouse::Picker, "Pick")
+        .backward(Warehouse::Picker, "Shipped", Shop::Buyer, "Confirmation")
+}
+
+#[tokio::test]
+async fn test_bridge_forwards_routed_messages_both_ways() {
+    let shop: Channels<Shop> = Default::default();
+    let warehouse: Channels<Warehouse> = Default::default();
+
+    // The buyer's order and the picker's reply are waiting before the bridge starts
+    let buyer = Program::<Shop, ShopMessage>::new()
+        .send(
+            Shop::Fulfilment,
+            ShopMessage::Order {
+                item: "tea".to_string(),
+                quantity: 2,
+            },
+        )
+        .end();
+    interpret(&mut handler(Shop::Buyer, &shop), &mut (), buyer)
+        .await
+        .unwrap();
+    let picker_reply = Program::<Warehouse, WarehouseMessage>::new()
+        .send(
+            Warehouse::Shop,
+            WarehouseMessage::Shipped {
+                tracking: "TRK-1".to_string(),
+            },
+        )
+        .end();
+    interpret(
+        &mut handler(Warehouse::Picker, &warehouse),
+        &mut (),
+        picker_reply,
+    )
+    .await
+    .unwrap();
+
+    let (mut side_a, mut side_b) = config().split::<_, _, ShopMessage, WarehouseMessage>(
+        handler(Shop::Fulfilment, &shop),
+        handler(Warehouse::Shop, &warehouse),
+    );
+    let shop_side = Program::<Shop, ShopMessage>::new()
+        .recv::<ShopMessage>(Shop::Buyer)
+        .send(
+            Shop::Buyer,
+            ShopMessage::Confirmation {
+                tracking: String::new(),
+            },
+        )
+        .end();
+    let warehouse_side = Program::<Warehouse, WarehouseMessage>::new()
+        .send(
+            Warehouse::Picker,
+            WarehouseMessage::Pick {
+                item: String::new(),
+                quantity: 0,
+            },
+        )
+        .recv::<WarehouseMessage>(Warehouse::Picker)
+        .end();
+    let (a, b) = tokio::join!(
+        async {
+            let result = interpret(&mut side_a, &mut (), shop_side).await;
+            (result, side_a.forwarded())
+        },
+        async {
+            let result = interpret(&mut side_b, &mut (), warehouse_side).await;
+            (result, side_b.forwarded())
+        },
+    );
+    let (a, forwarded_a) = a;
+    let (b, forwarded_b) = b;
+
+    assert_eq!(
+        a.unwrap().received_values,
+        vec![ShopMessage::Order {
+            item: "tea".to_string(),
+            quantity: 2
+        }]
+    );
+    assert_eq!(forwarded_a, 1);
+    assert_eq!(forwarded_b, 1);
+    assert_eq!(
+        b.unwrap().received_values,
+        vec![WarehouseMessage::Shipped {
+            tracking: "TRK-1".to_string()
+        }]
+    );
+
+    let picker = Program::<Warehouse, WarehouseMessage>::new()
+        .recv::<WarehouseMessage>(Warehouse::Shop)
+        .end();
+    let picked = interpret(&mut handler(Warehouse::Picker, &warehouse), &mut (), picker)
+        .await
+        .unwrap();
+    assert_eq!(
+        picked.received_values,
+        vec![WarehouseMessage::Pick {
+            item: "tea".to_string(),
+            quantity: 2
+        }]
+    );
+
+    let buyer = Program::<Shop, ShopMessage>::new()
+        .recv::<ShopMessage>(Shop::Fulfilment)
+        .end();
+    let confirmed = interpret(&mut handler(Shop::Buyer, &shop), &mut (), buyer)
+        .await
+        .unwrap();
+    assert_eq!(
+        confirmed.received_values,
+        vec![ShopMessage::Confirmation {
+            tracking: "TRK-1".to_string()
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_bridge_side_fails_when_other_side_ends_first() {
+    let shop: Channels<Shop> = Default::default();
+    let warehouse: Channels<Warehouse> = Default::default();
+    let (side_a, mut side_b) = config().split::<_, _, ShopMessage, WarehouseMessage>(
+        handler(Shop::Fulfilment, &shop),
+        handler(Warehouse::Shop, &warehouse),
+    );
+
+    // The shop side is gone without receiving an order, so there is
+    // nothing to send on
+    drop(side_a);
+    let program = Program::<Warehouse, WarehouseMessage>::new()
+        .send(
+            Warehouse::Picker,
+            WarehouseMessage::Pick {
+                item: String::new(),
+                quantity: 0,
+            },
+        )
+        .end();
+    let result = interpret(&mut side_b, &mut (), program).await.unwrap();
+    assert!(matches!(
+        result.final_state,
+        InterpreterState::Failed(ref reason) if reason.contains("bridged session ended")
+    ));
+}
+
+#[test]
+fn test_bridge_config_round_trips_through_json() {
+    let json = r#"{
+        "forward": [
+            { "from": "Buyer", "message": "Order", "to": "Picker", "as_message": "Pick" }
+        ]
+    }"#;
+    let parsed = BridgeConfig::<Shop, Warehouse>::from_json(json).unwrap();
+    assert_eq!(parsed.forward, config().forward);
+    assert!(parsed.backward.is_empty());
+    let again = BridgeConfig::<Shop, Warehouse>::from_json(&config().to_json().unwrap()).unwrap();
+    assert_eq!(again, config());
+}
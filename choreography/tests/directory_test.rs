@@ -0,0 +1,76 @@
+// Integration tests for the role directory
+
+use rumpsteak_choreography::effects::ChoreographyError;
+use rumpsteak_choreography::runtime::{InMemoryDirectory, Presence, RoleDirectory};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Gateway,
+    Worker,
+}
+
+#[tokio::test]
+async fn test_registrations_expire_after_their_ttl() {
+    let directory = InMemoryDirectory::new();
+    directory
+        .register(Role::Worker, "ws://10.0.0.2:9000", Duration::from_secs(60))
+        .await
+        .unwrap();
+    directory
+        .register(
+            Role::Worker,
+            "ws://10.0.0.1:9000",
+            Duration::from_millis(20),
+        )
+        .await
+        .unwrap();
+
+    let mut workers = directory.resolve(Role::Worker).await.unwrap();
+    workers.sort();
+    assert_eq!(workers, vec!["ws://10.0.0.1:9000", "ws://10.0.0.2:9000"]);
+    assert_eq!(
+        directory.lookup(Role::Worker).await.unwrap(),
+        "ws://10.0.0.1:9000"
+    );
+
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert_eq!(
+        directory.resolve(Role::Worker).await.unwrap(),
+        vec!["ws://10.0.0.2:9000"]
+    );
+
+    directory
+        .deregister(Role::Worker, "ws://10.0.0.2:9000")
+        .await
+        .unwrap();
+    assert!(directory.resolve(Role::Worker).await.unwrap().is_empty());
+    assert!(matches!(
+        directory.lookup(Role::Gateway).await,
+        Err(ChoreographyError::Transport(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_presence_refreshes_until_it_leaves() {
+    let directory = Arc::new(InMemoryDirectory::new());
+    let ttl = Duration::from_millis(60);
+
+    let presence = Presence::start(Arc::clone(&directory), Role::Gateway, "ws://gw:80", ttl);
+    tokio::time::sleep(ttl * 3).await;
+    assert_eq!(
+        directory.resolve(Role::Gateway).await.unwrap(),
+        vec!["ws://gw:80"]
+    );
+
+    presence.leave().await;
+    assert!(directory.resolve(Role::Gateway).await.unwrap().is_empty());
+
+    // Dropping a presence withdraws it too, without waiting
+    let presence = Presence::start(Arc::clone(&directory), Role::Worker, "ws://w:80", ttl);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    drop(presence);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(directory.resolve(Role::Worker).await.unwrap().is_empty());
+}
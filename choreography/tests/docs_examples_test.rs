@@ -0,0 +1,120 @@
+// Tests that the choreographies shown in the docs parse and project
+
+use rumpsteak_choreography::compiler::parser::parse_choreography_str_with_config;
+use rumpsteak_choreography::compiler::{expand_roles, project, CompileConfig, ExpansionError};
+use rumpsteak_choreography::Choreography;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Every fenced choreography in the docs, as (file and line, source)
+///
+/// A block is taken whole when it starts with a choreography, after any
+/// annotations, and otherwise each `r#"..."#` string in it that holds one.
+/// Fragments such as bare `protocol` definitions are left out.
+fn doc_examples() -> Vec<(String, String)> {
+    let docs = Path::new(env!("CARGO_MANIFEST_DIR")).join("../docs");
+    let mut files: Vec<_> = fs::read_dir(&docs)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "md"))
+        .collect();
+    files.sort();
+
+    let mut examples = Vec::new();
+    for path in files {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let text = fs::read_to_string(&path).unwrap();
+        let mut block: Option<(usize, String)> = None;
+        for (number, line) in text.lines().enumerate() {
+            if line.trim_start().starts_with("```") {
+                match block.take() {
+                    Some((start, body)) => {
+                        for source in sources(&body) {
+                            examples.push((format!("{}:{}", name, start), source));
+                        }
+                    }
+                    None => block = Some((number + 1, String::new())),
+                }
+            } else if let Some((_, body)) = &mut block {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+    }
+    examples
+}
+
+fn sources(block: &str) -> Vec<String> {
+    let first = block
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with('@'));
+    if first.is_some_and(|line| line.starts_with("choreography ")) {
+        return vec![block.to_string()];
+    }
+    block
+        .split("r#\"")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("\"#").map(|(source, _)| source))
+        .filter(|source| source.contains("choreography "))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses `source`, giving each constant declared without a default the
+/// value 2
+fn parse(source: &str) -> Result<Choreography, String> {
+    let mut config = CompileConfig::default();
+    for line in source.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix("const ")
+            .and_then(|decl| decl.strip_suffix(": usize"))
+        {
+            config = config.with_const(name, 2);
+        }
+    }
+    parse_choreography_str_with_config(source, &config).map_err(|e| e.to_string())
+}
+
+/// Expands the choreography's role families, giving each symbolic size or
+/// loop count its own value, so families of different sizes stay apart
+fn expand(choreography: &Choreography) -> Result<Choreography, ExpansionError> {
+    let mut bindings = HashMap::new();
+    loop {
+        match expand_roles(choreography, &bindings) {
+            Ok(expanded) => return Ok(expanded),
+            Err(ExpansionError::UnboundSize { size: name, .. })
+            | Err(ExpansionError::UnboundCount(name)) => {
+                let value = bindings.len() + 2;
+                bindings.insert(name, value);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[test]
+fn test_doc_examples_project() {
+    let examples = doc_examples();
+    assert!(examples.len() > 30, "found {} examples", examples.len());
+
+    let mut failures = Vec::new();
+    for (origin, source) in &examples {
+        let expanded =
+            parse(source).and_then(|choreography| expand(&choreography).map_err(|e| e.to_string()));
+        let choreography = match expanded {
+            Ok(choreography) => choreography,
+            Err(e) => {
+                failures.push(format!("{}: {}", origin, e));
+                continue;
+            }
+        };
+        for role in &choreography.roles {
+            if let Err(e) = project(&choreography, role) {
+                failures.push(format!("{}: projecting {}: {}", origin, role.name, e));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
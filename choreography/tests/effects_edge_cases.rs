@@ -421,3 +421,2286 @@ fn test_large_composition() {
         assert_eq!(handler.send_count(), 10);
     });
 }
+
+// Test 21: Cleanup runs even when the body fails
+#[test]
+fn test_finally_runs_after_failure() {
+    executor::block_on(async {
+        let program = Program::<TestRole, TestMessage>::new()
+            .with_finally(
+                Program::new().recv::<TestMessage>(TestRole::Bob),
+                Program::new().send(TestRole::Bob, TestMessage::Quit),
+            )
+            .end();
+
+        let mut handler = RecordingHandler::new(TestRole::Alice);
+        let mut endpoint = ();
+
+        let result = interpret(&mut handler, &mut endpoint, program)
+            .await
+            .unwrap();
+
+        // The body's failure is still reported after cleanup
+        assert!(matches!(
+            result.final_state,
+            rumpsteak_choreography::InterpreterState::Failed(_)
+        ));
+
+        let events = handler.events();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[1],
+            rumpsteak_choreography::RecordedEvent::Send { .. }
+        ));
+    });
+}
+
+// Test 22: Validation handler logs sends and refuses to fake receives
+#[test]
+fn test_validation_handler_does_not_drop_effects() {
+    use rumpsteak_choreography::{InterpreterState, ValidatedEffect, ValidationHandler};
+
+    executor::block_on(async {
+        let program = Program::<TestRole, TestMessage>::new()
+            .send(TestRole::Bob, TestMessage::Hello("hi".into()))
+            .choose(TestRole::Alice, Label("accept"))
+            .end();
+
+        let mut handler = ValidationHandler::new(TestRole::Alice);
+        let result = interpret(&mut handler, &mut (), program).await.unwrap();
+        assert!(matches!(result.final_state, InterpreterState::Completed));
+        assert_eq!(handler.effects().len(), 2);
+        assert!(matches!(
+            handler.effects()[0],
+            ValidatedEffect::Send { to: TestRole::Bob, size } if size > 0
+        ));
+
+        // Receiving has no source of messages
+        let program = Program::<TestRole, TestMessage>::new()
+            .recv::<TestMessage>(TestRole::Bob)
+            .end();
+        let result = interpret(&mut handler, &mut (), program).await.unwrap();
+        assert!(matches!(result.final_state, InterpreterState::Failed(_)));
+
+        // Sending to the local role is a protocol violation
+        let program = Program::<TestRole, TestMessage>::new()
+            .send(TestRole::Alice, TestMessage::Quit)
+            .end();
+        let result = interpret(&mut handler, &mut (), program).await.unwrap();
+        assert!(matches!(result.final_state, InterpreterState::Failed(_)));
+        assert_eq!(handler.effects().len(), 2);
+    });
+}
+
+// Test 23: Programs are checked against the session type they implement
+#[test]
+fn test_program_checked_against_session_type() {
+    use rumpsteak_choreography::{effects::ProgramError, SessionType};
+
+    // Bob's side: receive Hello, then Alice picks `more` (Data) or `stop`
+    let session = SessionType::receive::<TestMessage>(
+        TestRole::Alice,
+        SessionType::branch(
+            TestRole::Alice,
+            vec![
+                (
+                    "more",
+                    SessionType::Send {
+                        to: TestRole::Alice,
+                        message: "Data".to_string(),
+                        continuation: Box::new(SessionType::End),
+                    },
+                ),
+                ("stop", SessionType::End),
+            ],
+        ),
+    );
+
+    let program = |more: Program<TestRole, TestMessage>| {
+        Program::new()
+            .recv::<TestMessage>(TestRole::Alice)
+            .offer(TestRole::Alice)
+            .branch(
+                TestRole::Alice,
+                vec![(Label("more"), more), (Label("stop"), Program::new())],
+            )
+            .end()
+    };
+
+    let conforming = program(Program::new().send(TestRole::Alice, TestMessage::Data(1)));
+    assert_eq!(conforming.check_against(&session), Ok(()));
+
+    // Sending the wrong variant in one arm is caught without running anything
+    let drifted = program(Program::new().send(TestRole::Alice, TestMessage::Quit));
+    assert!(matches!(
+        drifted.check_against(&session),
+        Err(ProgramError::SessionMismatch { found, .. }) if found.contains("Quit")
+    ));
+
+    // Stopping early leaves part of the session unused
+    let truncated = program(Program::new());
+    assert!(matches!(
+        truncated.check_against(&session),
+        Err(ProgramError::SessionMismatch { expected, .. }) if expected.contains("Data")
+    ));
+}
+
+// Test 24: Programs render as an indented tree and as DOT
+#[test]
+fn test_program_display_and_dot() {
+    let program = Program::<TestRole, TestMessage>::new()
+        .send(TestRole::Bob, TestMessage::Hello("hi".into()))
+        .choose(TestRole::Bob, Label("more"))
+        .branch(
+            TestRole::Alice,
+            vec![
+                (
+                    Label("more"),
+                    Program::new().loop_n(2, Program::new().recv::<TestMessage>(TestRole::Bob)),
+                ),
+                (Label("stop"), Program::new()),
+            ],
+        )
+        .end();
+
+    assert_eq!(
+        program.to_string(),
+        "send Hello(\"hi\") to Bob\n\
+         choose more at Bob\n\
+         branch on Alice\n  \
+           more:\n    \
+             loop x2\n      \
+               recv TestMessage from Bob\n  \
+           stop:\n\
+         end\n"
+    );
+
+    let dot = program.to_dot();
+    assert!(dot.starts_with("digraph Program {"));
+    assert!(dot.contains("label=\"send Hello(\\\"hi\\\") to Bob\""));
+    assert!(dot.contains("[label=\"more\"]"));
+    assert!(dot.contains("[label=\"repeat\", style=dashed]"));
+    // The empty `stop` arm connects straight to the end node
+    assert!(dot.contains("[label=\"stop\"]"));
+}
+
+// Test 25: Interpreter hooks see branches and loop iterations
+#[test]
+fn test_interpreter_hooks_observe_program_structure() {
+    use async_trait::async_trait;
+    use rumpsteak_choreography::{
+        interpret_with_hooks, ChoreographyError, Effect, EffectContext, InterpreterHooks,
+    };
+
+    #[derive(Default)]
+    struct Observer {
+        sends: Vec<(Option<Label>, Option<usize>)>,
+        branches: Vec<Label>,
+        ends: usize,
+    }
+
+    #[async_trait]
+    impl InterpreterHooks<TestRole, TestMessage> for Observer {
+        async fn on_effect_start(
+            &mut self,
+            effect: &Effect<TestRole, TestMessage>,
+            ctx: &EffectContext,
+        ) {
+            if let Effect::Send { .. } = effect {
+                self.sends.push((ctx.branch(), ctx.iteration()));
+            }
+        }
+
+        async fn on_effect_end(
+            &mut self,
+            _effect: &Effect<TestRole, TestMessage>,
+            _ctx: &EffectContext,
+            _result: &Result<(), ChoreographyError>,
+        ) {
+            self.ends += 1;
+        }
+
+        async fn on_branch_taken(&mut self, label: Label, _ctx: &EffectContext) {
+            self.branches.push(label);
+        }
+    }
+
+    executor::block_on(async {
+        let program = Program::<TestRole, TestMessage>::new()
+            .choose(TestRole::Alice, Label("go"))
+            .branch(
+                TestRole::Alice,
+                vec![
+                    (
+                        Label("go"),
+                        Program::new()
+                            .loop_n(2, Program::new().send(TestRole::Bob, TestMessage::Quit)),
+                    ),
+                    (Label("stop"), Program::new()),
+                ],
+            )
+            .end();
+
+        let mut handler = NoOpHandler::new();
+        let mut observer = Observer::default();
+        interpret_with_hooks(&mut handler, &mut (), program, &mut observer)
+            .await
+            .unwrap();
+
+        assert_eq!(observer.branches, vec![Label("go")]);
+        assert_eq!(
+            observer.sends,
+            vec![(Some(Label("go")), Some(0)), (Some(Label("go")), Some(1))]
+        );
+        // choose, branch, loop, two sends, end
+        assert_eq!(observer.ends, 6);
+    });
+}
+
+// Test 26: Exceeding a budget aborts the program but still runs cleanup
+#[test]
+fn test_budget_aborts_session() {
+    use rumpsteak_choreography::{Budget, BudgetLimits, InterpreterState};
+
+    executor::block_on(async {
+        let program = Program::<TestRole, TestMessage>::new()
+            .with_finally(
+                Program::new().loop_n(5, Program::new().send(TestRole::Bob, TestMessage::Data(7))),
+                Program::new().send(TestRole::Bob, TestMessage::Quit),
+            )
+            .end();
+
+        let recording = RecordingHandler::new(TestRole::Alice);
+        let mut handler = Budget::new(recording.clone(), BudgetLimits::new().with_max_effects(3));
+        let result = interpret(&mut handler, &mut (), program).await.unwrap();
+
+        match result.final_state {
+            InterpreterState::Failed(msg) => assert!(msg.contains("effects"), "{}", msg),
+            other => panic!("expected budget failure, got {:?}", other),
+        }
+        // Three data messages went out; the fourth and the cleanup were refused
+        assert_eq!(recording.events().len(), 3);
+        let usage = handler.usage();
+        assert_eq!(usage.effects, 3);
+        assert_eq!(usage.messages_sent, 3);
+        assert!(usage.bytes_sent > 0);
+    });
+
+    executor::block_on(async {
+        let program = Program::<TestRole, TestMessage>::new()
+            .send(TestRole::Bob, TestMessage::Hello("x".repeat(64)))
+            .end();
+        let mut handler = Budget::new(
+            NoOpHandler::new(),
+            BudgetLimits::new().with_max_bytes_sent(16),
+        );
+        let result = interpret(&mut handler, &mut (), program).await.unwrap();
+        assert!(matches!(result.final_state, InterpreterState::Failed(_)));
+        assert_eq!(handler.usage().bytes_sent, 0);
+    });
+}
+
+// Test 27: Tenant sessions may only address their own roles
+#[tokio::test]
+async fn test_tenant_isolation() {
+    use rumpsteak_choreography::{
+        BudgetLimits, InterpreterState, TenancyError, TenantConfig, TenantId, TenantRegistry,
+    };
+
+    let registry = TenantRegistry::new();
+    registry.register(
+        TenantConfig::new("acme")
+            .with_rate_limit(1_000)
+            .with_budget(BudgetLimits::new().with_max_effects(10))
+            .with_metrics_label("tenant-acme"),
+    );
+    registry.register(TenantConfig::new("globex"));
+
+    let acme = TenantId::from("acme");
+    let globex = TenantId::from("globex");
+    registry.assign(TestRole::Alice, &acme).unwrap();
+    registry.assign(TestRole::Bob, &acme).unwrap();
+    registry.assign(TestRole::Charlie, &globex).unwrap();
+    assert!(matches!(
+        registry.assign(TestRole::Charlie, &acme),
+        Err(TenancyError::RoleOwned { .. })
+    ));
+
+    let recording = RecordingHandler::new(TestRole::Alice);
+    let mut handler = registry.session(&acme, recording.clone()).unwrap();
+
+    let program = Program::<TestRole, TestMessage>::new()
+        .send(TestRole::Bob, TestMessage::Data(1))
+        .send(TestRole::Charlie, TestMessage::Data(2))
+        .end();
+    let result = interpret(&mut handler, &mut (), program).await.unwrap();
+
+    match result.final_state {
+        InterpreterState::Failed(msg) => assert!(msg.contains("Charlie"), "{}", msg),
+        other => panic!("expected tenant violation, got {:?}", other),
+    }
+    // Only the message to Bob reached the transport
+    assert_eq!(recording.events().len(), 1);
+    assert_eq!(handler.usage().effects, 2);
+
+    // Releasing a role takes effect for running sessions
+    registry.release(&TestRole::Bob);
+    let program = Program::<TestRole, TestMessage>::new()
+        .send(TestRole::Bob, TestMessage::Quit)
+        .end();
+    let result = interpret(&mut handler, &mut (), program).await.unwrap();
+    assert!(matches!(result.final_state, InterpreterState::Failed(_)));
+    assert!(registry
+        .session(&TenantId::from("initech"), recording)
+        .is_err());
+}
+
+// Test 28: Role identities sign, verify, and rotate through the keystore
+#[test]
+fn test_role_identity_rotation() {
+    use rumpsteak_choreography::effects::identity::IdentityError;
+    use rumpsteak_choreography::{IdentityAuthority, KeyStore, RoleIdentity};
+
+    let dir = tempfile::tempdir().unwrap();
+    let store = KeyStore::open(dir.path()).unwrap();
+    let authority = IdentityAuthority::generate("root").unwrap();
+    let trust = authority.public_key();
+
+    let first = store.rotate("Alice", &authority).unwrap();
+    assert_eq!(first.certificate().serial, 1);
+    let old_signature = first.sign(b"hello");
+    RoleIdentity::verify(
+        first.certificate(),
+        "Alice",
+        &trust,
+        b"hello",
+        &old_signature,
+    )
+    .unwrap();
+
+    // A certificate cannot be presented for another role
+    assert!(matches!(
+        first.certificate().verify("Bob", &trust),
+        Err(IdentityError::RoleMismatch { .. })
+    ));
+    // Nor trusted under a different authority
+    let rogue = IdentityAuthority::generate("rogue").unwrap();
+    assert!(first
+        .certificate()
+        .verify("Alice", &rogue.public_key())
+        .is_err());
+
+    let loaded = store.load("Alice").unwrap();
+    assert_eq!(loaded.public_key(), first.public_key());
+
+    let second = store.rotate("Alice", &authority).unwrap();
+    assert_eq!(second.certificate().serial, 2);
+    assert_ne!(second.public_key(), first.public_key());
+    assert!(RoleIdentity::verify(
+        second.certificate(),
+        "Alice",
+        &trust,
+        b"hello",
+        &old_signature
+    )
+    .is_err());
+
+    // The retired certificate still verifies signatures made before rotation
+    let certificates = store.certificates("Alice").unwrap();
+    assert_eq!(
+        certificates.iter().map(|c| c.serial).collect::<Vec<_>>(),
+        vec![2, 1]
+    );
+    RoleIdentity::verify(&certificates[1], "Alice", &trust, b"hello", &old_signature).unwrap();
+    assert!(matches!(store.load("Bob"), Err(IdentityError::NotFound(_))));
+}
+
+// Test 29: Role group membership tracks live workers
+#[test]
+fn test_membership_registry_and_gossip() {
+    use rumpsteak_choreography::Membership;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Node {
+        Worker(u8),
+    }
+
+    let view = Membership::new(Duration::from_millis(50));
+    for i in 0..3 {
+        view.join("Worker", Node::Worker(i));
+    }
+    view.leave("Worker", Node::Worker(1));
+    let mut live = view.live("Worker");
+    live.sort_by_key(|node| format!("{:?}", node));
+    assert_eq!(live, vec![Node::Worker(0), Node::Worker(2)]);
+    assert_eq!(view.quorum("Worker"), 2);
+
+    // Scatter and gather follow the live membership, not a static group size
+    let program = view
+        .scatter("Worker", TestMessage::Quit)
+        .then(view.gather::<TestMessage, TestMessage>("Worker"));
+    assert_eq!(program.send_count(), 2);
+    assert_eq!(program.recv_count(), 2);
+
+    // Members that stop sending heartbeats time out
+    std::thread::sleep(Duration::from_millis(60));
+    view.heartbeat("Worker", Node::Worker(0));
+    assert_eq!(view.live("Worker"), vec![Node::Worker(0)]);
+    assert!(!view.is_live("Worker", &Node::Worker(2)));
+}
+
+#[test]
+fn test_membership_lists_live_members_in_join_order() {
+    use rumpsteak_choreography::Membership;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Node {
+        Worker(u8),
+    }
+
+    let view = Membership::new(Duration::from_secs(60));
+    assert!(view.live("Worker").is_empty());
+    assert_eq!(view.quorum("Worker"), 0);
+
+    for i in [2, 0, 1] {
+        view.join("Worker", Node::Worker(i));
+    }
+    view.leave("Worker", Node::Worker(0));
+    view.join("Worker", Node::Worker(0));
+    assert_eq!(
+        view.live("Worker"),
+        vec![Node::Worker(2), Node::Worker(0), Node::Worker(1)]
+    );
+
+    view.leave("Worker", Node::Worker(2));
+    view.leave("Worker", Node::Worker(0));
+    view.leave("Worker", Node::Worker(1));
+    assert_eq!(view.quorum("Worker"), 0);
+}
+
+// Test 30: Fault scenarios are scripted declaratively
+#[test]
+fn test_fault_scenario_script() {
+    use rumpsteak_choreography::{FaultScenario, FaultSchedule};
+
+    let scenario = FaultScenario::parse(
+        "at message 2 drop
+         at message 3 delay 5ms; at message 3 fail
+         # Charlie is cut off once the fourth message goes out
+         at message 4 partition {Alice} from {Bob, Charlie} for 50ms",
+    )
+    .unwrap();
+    assert_eq!(scenario.steps.len(), 4);
+
+    // The text and serde forms round-trip
+    assert_eq!(
+        scenario.to_string().parse::<FaultScenario>().unwrap(),
+        scenario
+    );
+    let json = serde_json::to_string(&scenario).unwrap();
+    assert_eq!(
+        serde_json::from_str::<FaultScenario>(&json).unwrap(),
+        scenario
+    );
+
+    let mut schedule = FaultSchedule::new(scenario, format!("{:?}", TestRole::Alice));
+    assert_eq!(schedule.on_send("Bob"), Default::default());
+    assert!(schedule.on_send("Bob").drop);
+    let third = schedule.on_send("Bob");
+    assert!(third.fail);
+    assert_eq!(third.delay, Some(Duration::from_millis(5)));
+    assert!(!schedule.is_partitioned("Charlie"));
+    schedule.on_send("Bob");
+    assert!(schedule.is_partitioned("Charlie"));
+    assert!(schedule.on_send("Charlie").fail);
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(!schedule.is_partitioned("Charlie"));
+
+    // Time triggers count from the first operation
+    let mut schedule = FaultSchedule::new(
+        FaultScenario::parse("at 0ms partition {Bob} from {Alice} for 1m").unwrap(),
+        "Alice",
+    );
+    assert!(schedule.is_partitioned("Bob"));
+    assert!(!schedule.is_partitioned("Charlie"));
+
+    let error = FaultScenario::parse("at message 1 drop\nat 2s explode").unwrap_err();
+    assert_eq!(error.step, 2);
+    assert!(error.message.contains("explode"));
+    assert!(FaultScenario::parse("at 2 parsecs drop").is_err());
+    assert!(FaultScenario::parse("at 1s partition {A} from {A} for 1s").is_err());
+}
+
+// Test 31: Batches of sessions share setup and keep their order
+#[test]
+fn test_interpret_many_shares_handler_state() {
+    use rumpsteak_choreography::{interpret_many, interpret_many_limited, InterpreterState};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    executor::block_on(async {
+        let shared = Metrics::new(RecordingHandler::new(TestRole::Alice));
+        let programs: Vec<_> = (0..16)
+            .map(|i| {
+                Program::<TestRole, TestMessage>::new()
+                    .send(TestRole::Bob, TestMessage::Data(i))
+                    .end()
+            })
+            .collect();
+
+        let created = Arc::new(AtomicUsize::new(0));
+        let results = interpret_many(
+            |_| {
+                created.fetch_add(1, Ordering::SeqCst);
+                (shared.clone(), ())
+            },
+            programs.clone(),
+        )
+        .await;
+        assert_eq!(results.len(), 16);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r.as_ref().unwrap().final_state, InterpreterState::Completed)));
+        assert_eq!(created.load(Ordering::SeqCst), 16);
+        assert_eq!(shared.send_count(), 16);
+
+        // The factory decides per session what is shared
+        let results = interpret_many_limited(
+            |i| {
+                let handler = if i == 2 {
+                    Metrics::new(RecordingHandler::new(TestRole::Charlie))
+                } else {
+                    shared.clone()
+                };
+                (handler, ())
+            },
+            programs.into_iter().take(4),
+            2,
+        )
+        .await;
+        assert_eq!(results.len(), 4);
+        assert_eq!(shared.send_count(), 16 + 3);
+    });
+}
+
+// Test 32: Pure computations are memoized within and across sessions
+#[test]
+fn test_pure_compute_is_memoized() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockResponse};
+    use rumpsteak_choreography::{Computation, InMemoryComputeCache, InterpreterState};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = |calls: &Arc<AtomicUsize>| {
+        let calls = calls.clone();
+        move |msg: &TestMessage| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            msg.clone()
+        }
+    };
+    let cache = Arc::new(InMemoryComputeCache::new());
+    let validate = Computation::pure("validate", counted(&calls)).with_cache(cache.clone());
+    let program = Program::<TestRole, TestMessage>::new()
+        .recv::<TestMessage>(TestRole::Bob)
+        .loop_n(3, Program::new().compute(validate))
+        .end();
+    assert!(program.to_string().contains("compute validate (pure)"));
+
+    let run = |program: Program<TestRole, TestMessage>| {
+        let mut handler = MockHandler::new(TestRole::Alice);
+        handler.add_response(MockResponse::Message(
+            bincode::serialize(&TestMessage::Data(7)).unwrap(),
+        ));
+        executor::block_on(interpret(&mut handler, &mut (), program)).unwrap()
+    };
+
+    let result = run(program.clone());
+    assert!(result
+        .received_values
+        .iter()
+        .all(|v| *v == TestMessage::Data(7)));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // A second session finds the result in the shared cache
+    run(program);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.hits(), 1);
+    assert_eq!(cache.len(), 1);
+
+    // Impure computations run every time
+    let impure_calls = Arc::new(AtomicUsize::new(0));
+    let stamp = Computation::new("stamp", counted(&impure_calls));
+    run(Program::new()
+        .recv::<TestMessage>(TestRole::Bob)
+        .loop_n(3, Program::new().compute(stamp.clone()))
+        .end());
+    assert_eq!(impure_calls.load(Ordering::SeqCst), 3);
+
+    // There is nothing to compute on before the first receive
+    let result = executor::block_on(interpret(
+        &mut NoOpHandler::new(),
+        &mut (),
+        Program::<TestRole, TestMessage>::new().compute(stamp).end(),
+    ))
+    .unwrap();
+    assert!(matches!(result.final_state, InterpreterState::Failed(_)));
+}
+
+// Test 33: Redaction hides sensitive fields of logged payloads
+#[test]
+fn test_redaction_hides_sensitive_fields() {
+    use rumpsteak_choreography::Redaction;
+
+    #[derive(Serialize)]
+    struct Order {
+        card: String,
+        amount: u64,
+    }
+
+    let redaction = Redaction::new().with_field("Order", "card");
+    let order = Order {
+        card: "4111 1111 1111 1111".to_string(),
+        amount: 30,
+    };
+    let rendered = redaction.render(&order);
+    assert!(!rendered.contains("4111"));
+    assert!(rendered.contains(r#""card":"<redacted>""#));
+    assert!(rendered.contains(r#""amount":30"#));
+
+    // Other messages with a field of the same name are left alone
+    #[derive(Serialize)]
+    struct Receipt {
+        card: String,
+    }
+    let receipt = Receipt {
+        card: "ending 1111".to_string(),
+    };
+    assert!(redaction.render(&receipt).contains("ending 1111"));
+    assert!(redaction.is_redacted("Order", "card"));
+    assert!(!redaction.is_redacted("Receipt", "card"));
+}
+
+// Test 34: System labels are handled without the program listing them
+#[test]
+fn test_system_labels_handled_by_interpreter() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockResponse};
+    use rumpsteak_choreography::InterpreterState;
+
+    let program = || {
+        Program::<TestRole, TestMessage>::new()
+            .offer(TestRole::Alice)
+            .branch(
+                TestRole::Alice,
+                vec![(
+                    Label("accept"),
+                    Program::new().send(TestRole::Alice, TestMessage::Quit),
+                )],
+            )
+            .end()
+    };
+
+    executor::block_on(async {
+        let mut handler = MockHandler::new(TestRole::Bob);
+        handler.add_response(MockResponse::Label("sys.heartbeat".into()));
+        handler.add_response(MockResponse::Label("accept".into()));
+        let result = interpret(&mut handler, &mut (), program()).await.unwrap();
+        assert_eq!(result.final_state, InterpreterState::Completed);
+        // Both labels were offered, and the accept branch ran
+        assert_eq!(handler.operations().len(), 3);
+    });
+
+    executor::block_on(async {
+        let mut handler = MockHandler::new(TestRole::Bob);
+        handler.add_response(MockResponse::Label(Label::ABORT.0.into()));
+        let result = interpret(&mut handler, &mut (), program()).await.unwrap();
+        match result.final_state {
+            InterpreterState::Failed(msg) => assert!(msg.contains("sys.abort"), "{}", msg),
+            other => panic!("expected the session to end, got {:?}", other),
+        }
+    });
+}
+
+// Test 35: Spawned child sessions run on their own and are awaited later
+#[tokio::test]
+async fn test_spawned_child_sessions() {
+    use rumpsteak_choreography::effects::{interpret_in_session, InMemoryHandler, SessionContext};
+    use rumpsteak_choreography::runtime::{SessionId, SessionManager, SessionStatus};
+    use rumpsteak_choreography::InterpreterState;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+
+    // Every child session gets its own channels, found by its id
+    type Pairs<T> =
+        Arc<Mutex<HashMap<(TestRole, TestRole), (UnboundedSender<T>, UnboundedReceiver<T>)>>>;
+    type Channels = (Pairs<Vec<u8>>, Pairs<Label>);
+    let sessions: Arc<Mutex<HashMap<SessionId, Channels>>> = Arc::default();
+    let context = |role: TestRole| {
+        let sessions = Arc::clone(&sessions);
+        SessionContext::new(
+            SessionManager::new(),
+            SessionId::new("checkout"),
+            move |id: &SessionId| {
+                let (channels, choices) = sessions
+                    .lock()
+                    .unwrap()
+                    .entry(id.clone())
+                    .or_default()
+                    .clone();
+                (InMemoryHandler::with_channels(role, channels, choices), ())
+            },
+        )
+    };
+
+    let alice = context(TestRole::Alice);
+    let program = Program::<TestRole, TestMessage>::new()
+        .spawn(
+            "audit",
+            Program::new().send(TestRole::Bob, TestMessage::Data(7)),
+        )
+        .await_child("audit")
+        .end();
+    let result = interpret_in_session(
+        &mut InMemoryHandler::new(TestRole::Alice),
+        &mut (),
+        program,
+        &alice,
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    let child = SessionId::new("checkout").child("audit", 0);
+    assert_eq!(alice.manager().children(alice.id()), vec![child.clone()]);
+    assert_eq!(
+        alice.manager().status(&child),
+        Some(SessionStatus::Completed)
+    );
+
+    // Bob's side derives the same child id and receives in it
+    let bob = context(TestRole::Bob);
+    let program = Program::<TestRole, TestMessage>::new()
+        .spawn("audit", Program::new().recv::<TestMessage>(TestRole::Alice))
+        .await_child("audit")
+        .end();
+    let result = interpret_in_session(
+        &mut InMemoryHandler::new(TestRole::Bob),
+        &mut (),
+        program,
+        &bob,
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.received_values, vec![TestMessage::Data(7)]);
+    assert_eq!(bob.manager().running(), 0);
+
+    // Without a session context there is nowhere to run a child
+    let program = Program::<TestRole, TestMessage>::new()
+        .spawn("audit", Program::new())
+        .end();
+    let result = interpret(&mut NoOpHandler::new(), &mut (), program)
+        .await
+        .unwrap();
+    assert!(matches!(result.final_state, InterpreterState::Failed(_)));
+}
+
+// Test 36: An offer runs the continuation of the label it receives
+#[test]
+fn test_offer_branches_runs_matching_continuation() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockOperation, MockResponse};
+    use rumpsteak_choreography::InterpreterState;
+
+    let program = || {
+        Program::<TestRole, TestMessage>::new()
+            .offer_branches(
+                TestRole::Alice,
+                vec![
+                    (
+                        Label("accept"),
+                        Program::new().send(TestRole::Charlie, TestMessage::Data(1)),
+                    ),
+                    (
+                        Label("reject"),
+                        Program::new().send(TestRole::Alice, TestMessage::Quit),
+                    ),
+                ],
+            )
+            .send(TestRole::Alice, TestMessage::Hello("done".into()))
+            .end()
+    };
+
+    executor::block_on(async {
+        let mut handler = MockHandler::new(TestRole::Bob);
+        handler.add_response(MockResponse::Label("reject".into()));
+        let result = interpret(&mut handler, &mut (), program()).await.unwrap();
+        assert_eq!(result.final_state, InterpreterState::Completed);
+        let sent: Vec<_> = handler
+            .operations()
+            .iter()
+            .filter_map(|op| match op {
+                MockOperation::Send { to, .. } => Some(*to),
+                _ => None,
+            })
+            .collect();
+        // Only the reject branch ran, then the rest of the program
+        assert_eq!(sent, vec![TestRole::Alice, TestRole::Alice]);
+    });
+
+    executor::block_on(async {
+        let mut handler = MockHandler::new(TestRole::Bob);
+        handler.add_response(MockResponse::Label("later".into()));
+        let result = interpret(&mut handler, &mut (), program()).await.unwrap();
+        match result.final_state {
+            InterpreterState::Failed(msg) => assert!(msg.contains("later"), "{}", msg),
+            other => panic!("expected an unknown label to fail, got {:?}", other),
+        }
+    });
+}
+
+// Test 37: Values bound by name feed the messages of later sends
+#[tokio::test]
+async fn test_bound_values_feed_later_sends() {
+    use rumpsteak_choreography::effects::InMemoryHandler;
+    use rumpsteak_choreography::{Computation, InterpreterState};
+    use std::sync::Arc;
+
+    let channels = Arc::default();
+    let choices = Arc::default();
+    let mut alice = InMemoryHandler::with_channels(
+        TestRole::Alice,
+        Arc::clone(&channels),
+        Arc::clone(&choices),
+    );
+    let mut bob = InMemoryHandler::with_channels(TestRole::Bob, channels, choices);
+
+    let greet = Computation::new("greet", |msg: &TestMessage| match msg {
+        TestMessage::Hello(name) => TestMessage::Hello(format!("hi {}", name)),
+        other => other.clone(),
+    });
+    let count = Computation::pure("count", |msg: &TestMessage| match msg {
+        TestMessage::Hello(name) => TestMessage::Data(name.len() as i32),
+        other => other.clone(),
+    });
+    let bob_program = Program::new()
+        .recv_into::<TestMessage>(TestRole::Alice, "name")
+        .compute(count)
+        .bind("length")
+        .send(TestRole::Charlie, TestMessage::Quit)
+        .send_with(TestRole::Alice, "name", greet)
+        .end();
+    // The in-memory channels only exist once something was sent on them
+    interpret(
+        &mut alice,
+        &mut (),
+        Program::new().send(TestRole::Bob, TestMessage::Hello("ada".into())),
+    )
+    .await
+    .unwrap();
+    let bob_result = interpret(&mut bob, &mut (), bob_program).await;
+    let alice_result = interpret(
+        &mut alice,
+        &mut (),
+        Program::<TestRole, TestMessage>::new().recv::<TestMessage>(TestRole::Bob),
+    )
+    .await;
+    let bob_result = bob_result.unwrap();
+    assert_eq!(bob_result.final_state, InterpreterState::Completed);
+    assert_eq!(
+        bob_result.bindings.get("name"),
+        Some(&TestMessage::Hello("ada".into()))
+    );
+    assert_eq!(
+        bob_result.bindings.get("length"),
+        Some(&TestMessage::Data(3))
+    );
+    assert_eq!(
+        alice_result.unwrap().received_values,
+        vec![TestMessage::Hello("hi ada".into())]
+    );
+
+    // A send that reads a name nothing was bound to fails the program
+    let program = Program::<TestRole, TestMessage>::new()
+        .send_with(
+            TestRole::Bob,
+            "order",
+            Computation::new("copy", TestMessage::clone),
+        )
+        .end();
+    let result = interpret(&mut NoOpHandler::new(), &mut (), program)
+        .await
+        .unwrap();
+    match result.final_state {
+        InterpreterState::Failed(msg) => assert!(msg.contains("order"), "{}", msg),
+        other => panic!("expected an unbound name to fail, got {:?}", other),
+    }
+}
+
+// Test 38: Recursion and conditional loops run under the interpreter
+#[test]
+fn test_rec_jump_and_loop_while() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockOperation, MockResponse};
+    use rumpsteak_choreography::{InterpreterState, LoopCondition, SessionType};
+
+    let message = |msg: TestMessage| MockResponse::Message(bincode::serialize(&msg).unwrap());
+    let sends = |handler: &MockHandler<TestRole>| {
+        handler
+            .operations()
+            .iter()
+            .filter(|op| matches!(op, MockOperation::Send { .. }))
+            .count()
+    };
+
+    // Poll until Alice says she is done, then say goodbye once
+    let polling = Program::<TestRole, TestMessage>::new()
+        .rec(
+            "poll",
+            Program::new()
+                .recv::<TestMessage>(TestRole::Alice)
+                .offer_branches(
+                    TestRole::Alice,
+                    vec![
+                        (Label("more"), Program::new().jump("poll")),
+                        (
+                            Label("done"),
+                            Program::new().send(TestRole::Alice, TestMessage::Quit),
+                        ),
+                    ],
+                ),
+        )
+        .end();
+    assert!(polling.validate().is_ok());
+    assert!(polling.to_string().contains("rec poll"));
+    assert!(polling.to_dot().contains("style=dashed"));
+
+    // A jump has to bring the session back to where its rec started
+    let session = SessionType::rec(
+        "Poll",
+        SessionType::receive::<TestMessage>(
+            TestRole::Alice,
+            SessionType::branch(
+                TestRole::Alice,
+                vec![
+                    ("more", SessionType::var("Poll")),
+                    (
+                        "done",
+                        SessionType::Send {
+                            to: TestRole::Alice,
+                            message: "Quit".to_string(),
+                            continuation: Box::new(SessionType::End),
+                        },
+                    ),
+                ],
+            ),
+        ),
+    );
+    assert_eq!(polling.check_against(&session), Ok(()));
+    let early_jump = Program::<TestRole, TestMessage>::new()
+        .rec(
+            "poll",
+            Program::new()
+                .recv::<TestMessage>(TestRole::Alice)
+                .jump("poll"),
+        )
+        .end();
+    assert!(early_jump.check_against(&session).is_err());
+
+    let mut handler = MockHandler::new(TestRole::Bob);
+    handler.add_response(message(TestMessage::Data(1)));
+    handler.add_response(MockResponse::Label("more".into()));
+    handler.add_response(message(TestMessage::Data(2)));
+    handler.add_response(MockResponse::Label("more".into()));
+    handler.add_response(message(TestMessage::Data(3)));
+    handler.add_response(MockResponse::Label("done".into()));
+    let result = executor::block_on(interpret(&mut handler, &mut (), polling)).unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    assert_eq!(
+        result.received_values,
+        vec![
+            TestMessage::Data(1),
+            TestMessage::Data(2),
+            TestMessage::Data(3)
+        ]
+    );
+    assert_eq!(sends(&handler), 1);
+
+    // Receive until the value says to stop; the body may not run at all
+    let until_zero = Program::<TestRole, TestMessage>::new()
+        .loop_while(
+            LoopCondition::new("not zero", |_, last| last != Some(&TestMessage::Data(0))),
+            Program::new()
+                .recv::<TestMessage>(TestRole::Alice)
+                .send(TestRole::Alice, TestMessage::Quit),
+        )
+        .end();
+    let mut handler = MockHandler::new(TestRole::Bob);
+    handler.add_response(message(TestMessage::Data(4)));
+    handler.add_response(message(TestMessage::Data(0)));
+    let result = executor::block_on(interpret(&mut handler, &mut (), until_zero)).unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    assert_eq!(result.received_values.len(), 2);
+    assert_eq!(sends(&handler), 2);
+
+    let never = Program::<TestRole, TestMessage>::new()
+        .loop_while(
+            LoopCondition::new("never", |_, _| false),
+            Program::new().send(TestRole::Alice, TestMessage::Quit),
+        )
+        .repeat(2, Program::new().send(TestRole::Bob, TestMessage::Quit))
+        .end();
+    let mut handler = MockHandler::new(TestRole::Charlie);
+    let result = executor::block_on(interpret(&mut handler, &mut (), never)).unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    assert_eq!(sends(&handler), 2);
+
+    // A jump needs an enclosing rec with its label
+    let stray = Program::<TestRole, TestMessage>::new()
+        .rec("outer", Program::new().jump("inner"))
+        .end();
+    assert!(stray.validate().is_err());
+    let result = executor::block_on(interpret(&mut NoOpHandler::new(), &mut (), stray)).unwrap();
+    match result.final_state {
+        InterpreterState::Failed(msg) => assert!(msg.contains("inner"), "{}", msg),
+        other => panic!("expected a stray jump to fail, got {:?}", other),
+    }
+}
+
+// Test 39: A cancel effect stops the program but not its cleanup
+#[test]
+fn test_cancel_effect_runs_cleanup() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockOperation};
+    use rumpsteak_choreography::InterpreterState;
+
+    let program = Program::<TestRole, TestMessage>::new()
+        .send(TestRole::Bob, TestMessage::Data(1))
+        .with_finally(
+            Program::new()
+                .cancel(vec![TestRole::Bob, TestRole::Charlie])
+                .send(TestRole::Bob, TestMessage::Data(2)),
+            Program::new().send(TestRole::Charlie, TestMessage::Quit),
+        )
+        .send(TestRole::Bob, TestMessage::Data(3))
+        .end();
+    assert!(program
+        .to_string()
+        .contains("cancel, notifying [Bob, Charlie]"));
+
+    let mut handler = MockHandler::new(TestRole::Alice);
+    let result = executor::block_on(interpret(&mut handler, &mut (), program)).unwrap();
+    assert_eq!(result.final_state, InterpreterState::Cancelled);
+    let cancel = |at| MockOperation::Choose {
+        at,
+        label: "sys.cancel".to_string(),
+    };
+    let ops = handler.operations();
+    assert_eq!(ops.len(), 4);
+    assert_eq!(ops[1], cancel(TestRole::Bob));
+    assert_eq!(ops[2], cancel(TestRole::Charlie));
+    assert!(matches!(
+        ops[3],
+        MockOperation::Send {
+            to: TestRole::Charlie,
+            ..
+        }
+    ));
+}
+
+// Test 40: A try body's failure runs the compensation, and only a local
+// failure is announced to peers
+#[test]
+fn test_try_catch_compensates() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockOperation, MockResponse};
+    use rumpsteak_choreography::{Computation, InterpreterState};
+
+    let program = |body: Program<TestRole, TestMessage>| {
+        Program::new()
+            .try_catch(
+                body,
+                vec![TestRole::Bob, TestRole::Charlie],
+                Program::new().send(TestRole::Charlie, TestMessage::Quit),
+            )
+            .send(TestRole::Bob, TestMessage::Data(9))
+            .end()
+    };
+    let waiting = || {
+        program(Program::new().offer_branches(TestRole::Bob, vec![(Label("go"), Program::new())]))
+    };
+    let sends = |ops: &[MockOperation<TestRole>]| {
+        ops.iter()
+            .filter(|op| matches!(op, MockOperation::Send { .. }))
+            .count()
+    };
+
+    // Bob aborted, so he has told everyone already
+    let mut handler = MockHandler::new(TestRole::Alice);
+    handler.add_response(MockResponse::Label("sys.abort".to_string()));
+    let result = executor::block_on(interpret(&mut handler, &mut (), waiting())).unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    let ops = handler.operations();
+    assert_eq!(ops.len(), 3);
+    assert!(matches!(
+        ops[1],
+        MockOperation::Send {
+            to: TestRole::Charlie,
+            ..
+        }
+    ));
+
+    // Cancellation is not an error the protocol can recover from
+    let mut handler = MockHandler::new(TestRole::Alice);
+    handler.add_response(MockResponse::Label("sys.cancel".to_string()));
+    let result = executor::block_on(interpret(&mut handler, &mut (), waiting())).unwrap();
+    assert!(
+        matches!(result.final_state, InterpreterState::Failed(ref msg) if msg.contains("sys.cancel"))
+    );
+    assert_eq!(sends(handler.operations()), 0);
+
+    // A local failure is announced before compensating
+    let failing = program(Program::new().compute(Computation::new("check", Clone::clone)));
+    let mut handler = MockHandler::new(TestRole::Alice);
+    let result = executor::block_on(interpret(&mut handler, &mut (), failing)).unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    let abort = |at| MockOperation::Choose {
+        at,
+        label: "sys.abort".to_string(),
+    };
+    let ops = handler.operations();
+    assert_eq!(&ops[..2], &[abort(TestRole::Bob), abort(TestRole::Charlie)]);
+    assert_eq!(sends(ops), 2);
+}
+
+// Test 41: Errors name the step that raised them and where it sat
+#[tokio::test]
+async fn test_errors_carry_the_failing_step() {
+    use rumpsteak_choreography::effects::{interpret_in_session, SessionContext};
+    use rumpsteak_choreography::runtime::{SessionId, SessionManager};
+    use rumpsteak_choreography::{ChoreographyError, InterpreterState};
+
+    // Bob answers twice, so Alice's third receive waits until the timeout
+    let alice = Program::<TestRole, TestMessage>::new()
+        .with_timeout(
+            TestRole::Alice,
+            Duration::from_millis(100),
+            Program::new().loop_n(
+                3,
+                Program::new()
+                    .send(TestRole::Bob, TestMessage::Quit)
+                    .recv::<TestMessage>(TestRole::Bob),
+            ),
+        )
+        .end();
+    let context = SessionContext::new(
+        SessionManager::new(),
+        SessionId::new("orders"),
+        |_: &SessionId| (Stalling { answered: 0 }, ()),
+    );
+    let alice = interpret_in_session(&mut Stalling { answered: 0 }, &mut (), alice, &context)
+        .await
+        .unwrap();
+    assert_eq!(alice.final_state, InterpreterState::Timeout);
+    let error = alice.error.unwrap();
+    assert!(matches!(error.root_cause(), ChoreographyError::Timeout(_)));
+    assert_eq!(error.context().unwrap().step, 8);
+    assert_eq!(
+        error.to_string(),
+        "session orders, step 8: recv TestMessage from Bob (timeout, loop iteration 2): Timeout after 100ms"
+    );
+
+    // A failure is wrapped once, where it happened, not again on the way out
+    let program = Program::<TestRole, TestMessage>::new()
+        .loop_n(2, Program::new().spawn("audit", Program::new()))
+        .end();
+    let result = interpret(&mut NoOpHandler::new(), &mut (), program)
+        .await
+        .unwrap();
+    let error = result.error.unwrap();
+    assert_eq!(error.context().unwrap().step, 2);
+    assert!(matches!(
+        error.root_cause(),
+        ChoreographyError::ProtocolViolation(_)
+    ));
+    assert!(
+        matches!(result.final_state, InterpreterState::Failed(ref msg) if msg.starts_with("step 2: spawn audit (loop iteration 0): "))
+    );
+
+    // A child's timeout reaches the parent with the deadline that expired
+    let parent = Program::<TestRole, TestMessage>::new()
+        .spawn(
+            "stalled",
+            Program::new().with_timeout(
+                TestRole::Alice,
+                Duration::from_millis(30),
+                Program::new().loop_n(
+                    3,
+                    Program::new()
+                        .send(TestRole::Bob, TestMessage::Quit)
+                        .recv::<TestMessage>(TestRole::Bob),
+                ),
+            ),
+        )
+        .await_child("stalled")
+        .end();
+    let result = interpret_in_session(&mut Stalling { answered: 0 }, &mut (), parent, &context)
+        .await
+        .unwrap();
+    let error = result.error.unwrap();
+    assert!(matches!(
+        error.root_cause(),
+        ChoreographyError::Timeout(d) if *d == Duration::from_millis(30)
+    ));
+}
+
+// Test 42: Labels no branch lists follow the offer's policy
+#[test]
+fn test_unknown_labels_follow_the_policy() {
+    use rumpsteak_choreography::effects::testing::{MockHandler, MockResponse};
+    use rumpsteak_choreography::{InterpreterState, UnknownLabel};
+
+    let program = |unknown| {
+        Program::<TestRole, TestMessage>::new()
+            .offer_branches_or(
+                TestRole::Alice,
+                vec![
+                    (
+                        Label("accept"),
+                        Program::new().send(TestRole::Alice, TestMessage::Quit),
+                    ),
+                    (
+                        Label("other"),
+                        Program::new().send(TestRole::Alice, TestMessage::Data(0)),
+                    ),
+                ],
+                unknown,
+            )
+            .end()
+    };
+    let run = |unknown, labels: &[&str]| {
+        executor::block_on(async {
+            let mut handler = MockHandler::new(TestRole::Bob);
+            for label in labels {
+                handler.add_response(MockResponse::Label(label.to_string()));
+            }
+            let result = interpret(&mut handler, &mut (), program(unknown))
+                .await
+                .unwrap();
+            (result.final_state, handler.operations().len())
+        })
+    };
+
+    // Rejecting is the default, and fails the program
+    let (state, _) = run(UnknownLabel::Reject, &["counter"]);
+    assert!(matches!(state, InterpreterState::Failed(ref msg) if msg.contains("counter")));
+
+    // Skipping waits for the next label
+    let (state, operations) = run(UnknownLabel::Skip, &["counter", "accept"]);
+    assert_eq!(state, InterpreterState::Completed);
+    assert_eq!(operations, 3);
+
+    // Falling back runs the catch-all branch in its place
+    let (state, operations) = run(UnknownLabel::Fallback(Label("other")), &["counter"]);
+    assert_eq!(state, InterpreterState::Completed);
+    assert_eq!(operations, 2);
+
+    // Labels that end the session are not covered by the policy
+    let (state, _) = run(UnknownLabel::Skip, &[Label::ABORT.0]);
+    assert!(matches!(state, InterpreterState::Failed(ref msg) if msg.contains("sys.abort")));
+
+    // A fallback must name one of the branches
+    let wrong = Program::<TestRole, TestMessage>::new()
+        .offer_branches(TestRole::Alice, vec![(Label("accept"), Program::new())])
+        .on_unknown_labels(UnknownLabel::Fallback(Label("other")));
+    assert!(wrong.validate().is_ok());
+    let wrong = Program::<TestRole, TestMessage>::new().offer_branches_or(
+        TestRole::Alice,
+        vec![(Label("accept"), Program::new())],
+        UnknownLabel::Fallback(Label("other")),
+    );
+    assert!(wrong.validate().is_err());
+}
+
+// Test 43: Guarded choices take the first branch whose guard holds
+#[test]
+fn test_guarded_choice_evaluates_guards() {
+    use rumpsteak_choreography::effects::interpret_with_guards;
+    use rumpsteak_choreography::{ChoreographyError, Guard, GuardContext, GuardValue};
+
+    let guard = |source: &str| Guard::parse(source).unwrap();
+    let context = GuardContext::new()
+        .with_var("balance", 120)
+        .with_var("price", 99.5)
+        .with_var("tier", "gold")
+        .with_var("verified", true);
+
+    assert!(guard("balance > price && verified")
+        .holds(&context)
+        .unwrap());
+    assert!(guard("!(tier == \"silver\") || balance < 0")
+        .holds(&context)
+        .unwrap());
+    assert_eq!(
+        guard("balance - 20 * 2 % 3").eval(&context).unwrap(),
+        GuardValue::Int(119)
+    );
+    assert!(guard("missing > 0").holds(&context).is_err());
+    assert!(guard("balance + 1").holds(&context).is_err());
+    assert!(Guard::parse("balance >").is_err());
+    assert!(Guard::parse("balance.len() > 0").is_err());
+
+    let program = || {
+        Program::<TestRole, TestMessage>::new()
+            .choose_when(
+                TestRole::Alice,
+                vec![
+                    (
+                        Label("buy"),
+                        Some(guard("balance >= price")),
+                        Program::new().send(TestRole::Bob, TestMessage::Data(1)),
+                    ),
+                    (
+                        Label("save"),
+                        Some(guard("balance > 0")),
+                        Program::new().send(TestRole::Bob, TestMessage::Quit),
+                    ),
+                ],
+            )
+            .end()
+    };
+    let run = |context: GuardContext| {
+        executor::block_on(async {
+            let mut handler = RecordingHandler::new(TestRole::Alice);
+            let result = interpret_with_guards(&mut handler, &mut (), program(), context)
+                .await
+                .unwrap();
+            (result, handler.events())
+        })
+    };
+
+    let (result, events) = run(GuardContext::new()
+        .with_var("balance", 50)
+        .with_var("price", 80));
+    assert_eq!(
+        result.final_state,
+        rumpsteak_choreography::InterpreterState::Completed
+    );
+    assert!(format!("{:?}", events).contains("save"));
+
+    let (result, _) = run(GuardContext::new()
+        .with_var("balance", 0)
+        .with_var("price", 80));
+    let error = result.error.unwrap();
+    assert!(matches!(
+        error.root_cause(),
+        ChoreographyError::GuardUnsatisfied { labels, .. } if labels == &["buy", "save"]
+    ));
+}
+
+/// Handler that answers two receives and then never answers again
+struct Stalling {
+    answered: usize,
+}
+
+#[async_trait::async_trait]
+impl rumpsteak_choreography::ChoreoHandler for Stalling {
+    type Role = TestRole;
+    type Endpoint = ();
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        _ep: &mut (),
+        _to: TestRole,
+        _msg: &M,
+    ) -> rumpsteak_choreography::effects::Result<()> {
+        Ok(())
+    }
+
+    async fn recv<M: serde::de::DeserializeOwned + Send>(
+        &mut self,
+        _ep: &mut (),
+        _from: TestRole,
+    ) -> rumpsteak_choreography::effects::Result<M> {
+        if self.answered == 2 {
+            return futures::future::pending().await;
+        }
+        self.answered += 1;
+        let bytes = bincode::serialize(&TestMessage::Data(self.answered as i32)).unwrap();
+        Ok(bincode::deserialize(&bytes).unwrap())
+    }
+
+    async fn choose(
+        &mut self,
+        _ep: &mut (),
+        _who: TestRole,
+        _label: Label,
+    ) -> rumpsteak_choreography::effects::Result<()> {
+        Ok(())
+    }
+
+    async fn offer(
+        &mut self,
+        _ep: &mut (),
+        _from: TestRole,
+    ) -> rumpsteak_choreography::effects::Result<Label> {
+        Ok(Label("default"))
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        _ep: &mut (),
+        _at: TestRole,
+        _dur: Duration,
+        body: F,
+    ) -> rumpsteak_choreography::effects::Result<T>
+    where
+        F: std::future::Future<Output = rumpsteak_choreography::effects::Result<T>> + Send,
+    {
+        body.await
+    }
+}
+
+// Test 44: Signed messages verify only with the key the sender used
+#[tokio::test]
+async fn test_signed_messages() {
+    use rumpsteak_choreography::{ChoreoHandler, ChoreographyError, InMemoryHandler, Signed};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+
+    let mut alice = Signed::new(handler(TestRole::Alice), TestRole::Alice)
+        .with_key(TestRole::Bob, b"alice-bob".to_vec())
+        .with_default_key(b"shared".to_vec());
+    let mut bob = Signed::new(handler(TestRole::Bob), TestRole::Bob)
+        .with_key(TestRole::Alice, b"alice-bob".to_vec());
+    let mut charlie = Signed::new(handler(TestRole::Charlie), TestRole::Charlie)
+        .with_key(TestRole::Alice, b"forged".to_vec());
+
+    let hello = TestMessage::Hello("hi".into());
+    alice.send(&mut (), TestRole::Bob, &hello).await.unwrap();
+    let received: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    assert_eq!(received, hello);
+
+    // Charlie holds the wrong key for Alice, who signs with the default one
+    alice
+        .send(&mut (), TestRole::Charlie, &TestMessage::Quit)
+        .await
+        .unwrap();
+    match charlie.recv::<TestMessage>(&mut (), TestRole::Alice).await {
+        Err(ChoreographyError::IntegrityViolation { role, reason }) => {
+            assert_eq!(role, "Alice");
+            assert_eq!(reason, "message tag does not match");
+        }
+        other => panic!("expected an integrity violation, got {:?}", other),
+    }
+
+    // Bob has no key for Charlie, so refuses to send to them unsigned
+    assert!(matches!(
+        bob.send(&mut (), TestRole::Charlie, &TestMessage::Quit)
+            .await,
+        Err(ChoreographyError::IntegrityViolation { .. })
+    ));
+}
+
+// Test 45: Encrypted messages are unreadable on the wire and keys are only
+// agreed with certified peers
+#[tokio::test]
+async fn test_encrypted_messages() {
+    use rumpsteak_choreography::{
+        ChoreoHandler, ChoreoHandlerExt, ChoreographyError, Encrypted, IdentityAuthority,
+        InMemoryHandler,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+    let authority = IdentityAuthority::generate("ops").unwrap();
+    let authority_key = authority.public_key();
+
+    let mut alice = Encrypted::new(
+        handler(TestRole::Alice),
+        authority.enroll("Alice", 1).unwrap(),
+        authority_key,
+    )
+    .with_peers([TestRole::Bob]);
+    let mut bob = Encrypted::new(
+        handler(TestRole::Bob),
+        authority.enroll("Bob", 1).unwrap(),
+        authority_key,
+    )
+    .with_peers([TestRole::Alice]);
+    let (alice_ep, bob_ep) = tokio::join!(alice.setup(TestRole::Alice), bob.setup(TestRole::Bob));
+    let (mut alice_ep, mut bob_ep) = (alice_ep.unwrap(), bob_ep.unwrap());
+    assert!(alice.is_established(&TestRole::Bob));
+
+    let secret = TestMessage::Hello("the vault code is 1234".into());
+    alice
+        .send(&mut alice_ep, TestRole::Bob, &secret)
+        .await
+        .unwrap();
+    let received: TestMessage = bob.recv(&mut bob_ep, TestRole::Alice).await.unwrap();
+    assert_eq!(received, secret);
+
+    // Someone reading Bob's channel sees only ciphertext
+    let mut eavesdropper = handler(TestRole::Bob);
+    alice
+        .send(&mut alice_ep, TestRole::Bob, &secret)
+        .await
+        .unwrap();
+    let wire: Vec<u8> = eavesdropper.recv(&mut (), TestRole::Alice).await.unwrap();
+    assert!(!wire.windows(4).any(|window| window == b"1234"));
+
+    // Passing it on to Bob is fine, but replaying it is not
+    let mut relay = handler(TestRole::Alice);
+    relay.send(&mut (), TestRole::Bob, &wire).await.unwrap();
+    let received: TestMessage = bob.recv(&mut bob_ep, TestRole::Alice).await.unwrap();
+    assert_eq!(received, secret);
+    relay.send(&mut (), TestRole::Bob, &wire).await.unwrap();
+    match bob.recv::<TestMessage>(&mut bob_ep, TestRole::Alice).await {
+        Err(ChoreographyError::IntegrityViolation { role, reason }) => {
+            assert_eq!(role, "Alice");
+            assert_eq!(reason, "message does not decrypt");
+        }
+        other => panic!("expected an integrity violation, got {:?}", other),
+    }
+
+    // Charlie's certificate comes from another authority
+    let rogue = IdentityAuthority::generate("rogue").unwrap();
+    let mut charlie = Encrypted::new(
+        handler(TestRole::Charlie),
+        rogue.enroll("Charlie", 1).unwrap(),
+        authority_key,
+    )
+    .with_peers([TestRole::Alice]);
+    let mut alice = Encrypted::new(
+        handler(TestRole::Alice),
+        authority.enroll("Alice", 2).unwrap(),
+        authority_key,
+    )
+    .with_peers([TestRole::Charlie]);
+    let (accepted, _) = tokio::join!(
+        alice.setup(TestRole::Alice),
+        charlie.setup(TestRole::Charlie)
+    );
+    assert!(matches!(
+        accepted,
+        Err(ChoreographyError::AuthenticationFailed { ref role, .. }) if role == "Charlie"
+    ));
+
+    // Without agreed keys nothing is sent in the clear
+    assert!(matches!(
+        bob.send(&mut bob_ep, TestRole::Charlie, &secret).await,
+        Err(ChoreographyError::AuthenticationFailed { .. })
+    ));
+}
+
+// Test 46: Batching sends runs of messages together and grows batches while
+// they keep filling
+#[tokio::test]
+async fn test_batching_middleware() {
+    use rumpsteak_choreography::{Batching, ChoreoHandler, InMemoryHandler};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+
+    let mut alice = Batching::new(handler(TestRole::Alice)).with_max_delay(Duration::from_secs(60));
+    let mut bob = Batching::new(handler(TestRole::Bob));
+
+    // Batches of 1, 2 and 4 fill in turn, doubling the target each time
+    for i in 0..8 {
+        alice
+            .send(&mut (), TestRole::Bob, &TestMessage::Data(i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(alice.batch_size(&TestRole::Bob), 8);
+    assert_eq!(alice.pending(&TestRole::Bob), 1);
+
+    // Flushing a batch well short of its target shrinks the target
+    alice.flush(&mut ()).await.unwrap();
+    assert_eq!(alice.pending(&TestRole::Bob), 0);
+    assert_eq!(alice.batch_size(&TestRole::Bob), 4);
+    for i in 0..8 {
+        let received: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+        assert_eq!(received, TestMessage::Data(i));
+    }
+
+    // The batch goes out as one message of the inner handler
+    for i in 0..3 {
+        alice
+            .send(&mut (), TestRole::Bob, &TestMessage::Data(i))
+            .await
+            .unwrap();
+    }
+    alice.flush(&mut ()).await.unwrap();
+    let batch: Vec<Vec<u8>> = handler(TestRole::Bob)
+        .recv(&mut (), TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(batch.len(), 3);
+
+    // Messages held back are sent before the role waits on its peer
+    alice
+        .send(&mut (), TestRole::Bob, &TestMessage::Quit)
+        .await
+        .unwrap();
+    assert_eq!(alice.pending(&TestRole::Bob), 1);
+    let mut alice_ep = ();
+    let (received, reply) = tokio::join!(
+        async {
+            let received: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+            bob.send(&mut (), TestRole::Alice, &TestMessage::Data(99))
+                .await
+                .unwrap();
+            bob.flush(&mut ()).await.unwrap();
+            received
+        },
+        alice.recv::<TestMessage>(&mut alice_ep, TestRole::Bob)
+    );
+    assert_eq!(received, TestMessage::Quit);
+    assert_eq!(reply.unwrap(), TestMessage::Data(99));
+
+    // A program's messages all arrive once it ends
+    let program = Program::new()
+        .send(TestRole::Bob, TestMessage::Data(1))
+        .send(TestRole::Bob, TestMessage::Data(2))
+        .send(TestRole::Bob, TestMessage::Data(3))
+        .end();
+    interpret(&mut alice, &mut (), program).await.unwrap();
+    assert_eq!(alice.pending(&TestRole::Bob), 0);
+    for i in 1..=3 {
+        let received: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+        assert_eq!(received, TestMessage::Data(i));
+    }
+}
+
+// Test 47: A deadline set by the first role holds at every later hop
+#[tokio::test]
+async fn test_deadline_propagation() {
+    use rumpsteak_choreography::{ChoreoHandler, ChoreographyError, Deadline, InMemoryHandler};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+
+    let mut alice = Deadline::new(handler(TestRole::Alice)).with_budget(Duration::from_millis(300));
+    let mut bob = Deadline::new(handler(TestRole::Bob));
+    let mut charlie =
+        Deadline::new(handler(TestRole::Charlie)).with_budget(Duration::from_secs(60));
+    assert!(bob.deadline().is_none());
+
+    // Bob takes Alice's deadline, and Charlie's later one gives way to it
+    alice
+        .send(&mut (), TestRole::Bob, &TestMessage::Data(1))
+        .await
+        .unwrap();
+    let _: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    let deadline = alice.deadline().unwrap();
+    let millis =
+        |t: std::time::SystemTime| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+    assert_eq!(millis(bob.deadline().unwrap()), millis(deadline));
+    bob.send(&mut (), TestRole::Charlie, &TestMessage::Data(2))
+        .await
+        .unwrap();
+    let _: TestMessage = charlie.recv(&mut (), TestRole::Bob).await.unwrap();
+    assert_eq!(millis(charlie.deadline().unwrap()), millis(deadline));
+    assert!(charlie.remaining().unwrap() <= Duration::from_millis(300));
+
+    // Waiting on a peer stops when the deadline passes
+    let started = std::time::Instant::now();
+    let result = charlie.recv::<TestMessage>(&mut (), TestRole::Bob).await;
+    assert!(matches!(result, Err(ChoreographyError::Timeout(_))));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // Afterwards every operation fails at once, and nothing is sent
+    assert!(matches!(
+        alice.send(&mut (), TestRole::Bob, &TestMessage::Quit).await,
+        Err(ChoreographyError::Timeout(_))
+    ));
+    assert!(matches!(
+        bob.choose(&mut (), TestRole::Charlie, Label("done")).await,
+        Err(ChoreographyError::Timeout(_))
+    ));
+    let mut late = Deadline::new(handler(TestRole::Charlie));
+    let pending = tokio::time::timeout(
+        Duration::from_millis(50),
+        late.recv::<TestMessage>(&mut (), TestRole::Alice),
+    )
+    .await;
+    assert!(pending.is_err());
+}
+
+// Test 48: A dry run stubs peers, takes first branches and stops runaway loops
+#[tokio::test]
+async fn test_dry_run() {
+    use rumpsteak_choreography::{ChoreographyError, DryRun, DryRunStep};
+
+    let payload =
+        |msg_type: &str| (msg_type == std::any::type_name::<i32>()).then_some(TestMessage::Data(0));
+    let program = Program::<TestRole, TestMessage>::new()
+        .recv::<i32>(TestRole::Alice)
+        .offer_branches(
+            TestRole::Alice,
+            vec![
+                (
+                    Label("more"),
+                    Program::new().send(TestRole::Charlie, TestMessage::Quit),
+                ),
+                (Label("done"), Program::new()),
+            ],
+        )
+        .choose(TestRole::Bob, Label("ack"))
+        .branch(
+            TestRole::Bob,
+            vec![(
+                Label("ack"),
+                Program::new().send(TestRole::Alice, TestMessage::Hello("ok".into())),
+            )],
+        )
+        .end();
+
+    let mut dry_run = DryRun::new(payload);
+    let result = dry_run.run(program).await.unwrap();
+    assert_eq!(result.received_values, vec![TestMessage::Data(0)]);
+    assert_eq!(
+        dry_run.steps(),
+        &[
+            DryRunStep::Recv {
+                from: TestRole::Alice,
+                message: "i32".to_string(),
+            },
+            DryRunStep::Offer {
+                from: TestRole::Alice,
+                label: Label("more"),
+            },
+            DryRunStep::Send {
+                to: TestRole::Charlie,
+                message: "Quit".to_string(),
+            },
+            DryRunStep::Choose {
+                label: Label("ack")
+            },
+            DryRunStep::Send {
+                to: TestRole::Alice,
+                message: "Hello".to_string(),
+            },
+        ]
+    );
+    assert_eq!(dry_run.steps()[1].to_string(), "offer from Alice: more");
+
+    // A message the stub has no payload for fails the run
+    let program = Program::<TestRole, TestMessage>::new()
+        .recv::<String>(TestRole::Alice)
+        .end();
+    let mut dry_run = DryRun::new(payload);
+    let error = dry_run.run(program).await.unwrap_err();
+    assert!(matches!(
+        error.root_cause(),
+        ChoreographyError::ProtocolViolation(_)
+    ));
+    assert_eq!(dry_run.steps().len(), 1);
+
+    // Always taking the first branch never leaves this loop
+    let program = Program::<TestRole, TestMessage>::new()
+        .rec(
+            "again",
+            Program::new().offer_branches(
+                TestRole::Alice,
+                vec![
+                    (Label("again"), Program::new().jump("again")),
+                    (Label("stop"), Program::new()),
+                ],
+            ),
+        )
+        .end();
+    let mut dry_run = DryRun::new(payload).with_max_steps(10);
+    let error = dry_run.run(program).await.unwrap_err();
+    assert!(matches!(
+        error.root_cause(),
+        ChoreographyError::BudgetExceeded { limit: 10, .. }
+    ));
+    assert_eq!(dry_run.steps().len(), 10);
+}
+
+// Test 49: A relay adds itself to the trail of the messages it forwards
+#[tokio::test]
+async fn test_message_provenance() {
+    use rumpsteak_choreography::{ChoreoHandler, Hops, InMemoryHandler};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+
+    let mut alice = Hops::new(handler(TestRole::Alice), TestRole::Alice);
+    let mut bob = Hops::new(handler(TestRole::Bob), TestRole::Bob).with_forwarding();
+    let mut charlie = Hops::new(handler(TestRole::Charlie), TestRole::Charlie);
+
+    alice
+        .send(&mut (), TestRole::Bob, &TestMessage::Data(1))
+        .await
+        .unwrap();
+    let message: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    bob.send(&mut (), TestRole::Charlie, &message)
+        .await
+        .unwrap();
+    let message: TestMessage = charlie.recv(&mut (), TestRole::Bob).await.unwrap();
+    assert_eq!(message, TestMessage::Data(1));
+
+    let provenance = charlie.provenance(&TestRole::Bob).unwrap();
+    assert_eq!(provenance.origin(), "Alice");
+    assert_eq!(provenance.to_string(), "Alice -> Bob");
+    assert!(provenance.hops[0].at <= provenance.hops[1].at);
+    assert_eq!(provenance.sent_at(), Some(provenance.hops[0].at));
+    assert_eq!(charlie.last_provenance(), Some(provenance));
+    assert!(charlie.provenance(&TestRole::Alice).is_none());
+
+    // A role that does not forward starts a new trail
+    charlie
+        .send(&mut (), TestRole::Alice, &TestMessage::Quit)
+        .await
+        .unwrap();
+    let _: TestMessage = alice.recv(&mut (), TestRole::Charlie).await.unwrap();
+    assert_eq!(alice.last_provenance().unwrap().to_string(), "Charlie");
+}
+
+// Test 50: Metrics break operations down by peer, message and label
+#[tokio::test]
+async fn test_metrics_breakdown() {
+    use rumpsteak_choreography::{
+        ChoreoHandler, InMemoryHandler, MetricsKey, MetricsReport, Operation,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+    let mut alice = Metrics::new(handler(TestRole::Alice));
+    let mut bob = Metrics::new(handler(TestRole::Bob));
+
+    for message in [
+        TestMessage::Data(1),
+        TestMessage::Data(2),
+        TestMessage::Hello("a longer greeting".into()),
+    ] {
+        alice.send(&mut (), TestRole::Bob, &message).await.unwrap();
+    }
+    alice
+        .choose(&mut (), TestRole::Alice, Label("done"))
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        let _: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    }
+    assert!(bob.offer(&mut (), TestRole::Charlie).await.is_err());
+
+    let key = |operation, peer: &str, message: Option<&str>, label: Option<&str>| MetricsKey {
+        operation,
+        peer: peer.to_string(),
+        message: message.map(str::to_string),
+        label: label.map(str::to_string),
+    };
+    let report = alice.snapshot();
+    assert_eq!(report.sends, 3);
+    let keys: Vec<_> = report.operations.iter().map(|(key, _)| key).collect();
+    assert_eq!(
+        keys,
+        [
+            &key(Operation::Send, "Bob", Some("Data"), None),
+            &key(Operation::Send, "Bob", Some("Hello"), None),
+            &key(Operation::Choose, "Alice", None, Some("done")),
+        ]
+    );
+    let data = &report.operations[0].1;
+    assert_eq!(data.count, 2);
+    assert_eq!(data.latency_us.count, 2);
+    let size = bincode::serialize(&TestMessage::Data(1)).unwrap().len() as u64;
+    assert_eq!(data.payload_bytes.min, size);
+    assert_eq!(data.payload_bytes.max, size);
+    assert_eq!(data.payload_bytes.mean(), size as f64);
+    assert_eq!(data.payload_bytes.quantile(0.5), size);
+    let hello = &report.operations[1].1;
+    assert!(hello.payload_bytes.min > size);
+
+    // Receives are named by the type expected, and failures are counted
+    let report = bob.snapshot();
+    assert_eq!(report.recvs, 3);
+    assert_eq!(
+        report.operations[0],
+        (
+            key(Operation::Recv, "Alice", Some("TestMessage"), None),
+            report.operations[0].1.clone()
+        )
+    );
+    assert_eq!(report.operations[0].1.count, 3);
+    assert_eq!(report.operations[0].1.payload_bytes.count, 0);
+    let (offer, metrics) = &report.operations[1];
+    assert_eq!(offer, &key(Operation::Offer, "Charlie", None, None));
+    assert_eq!(metrics.errors, 1);
+
+    // Reports round-trip through JSON for dashboards
+    let json = serde_json::to_string(&report).unwrap();
+    assert!(json.contains("\"operation\":\"offer\""));
+    let parsed: MetricsReport = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, report);
+}
+
+// Test 51: A journal replays a crashed role up to where it stopped
+#[tokio::test]
+async fn test_journal_replay() {
+    use rumpsteak_choreography::{
+        read_journal, replay_journal, ChoreoHandler, ChoreographyError, InMemoryHandler, Journal,
+        JournalEffect, JournalOutcome,
+    };
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bob.journal");
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+    let mut alice = Journal::open(
+        handler(TestRole::Alice),
+        TestRole::Alice,
+        dir.path().join("alice.journal"),
+    )
+    .unwrap();
+    let mut bob = Journal::open(handler(TestRole::Bob), TestRole::Bob, &path).unwrap();
+
+    for message in [TestMessage::Data(1), TestMessage::Data(2)] {
+        alice.send(&mut (), TestRole::Bob, &message).await.unwrap();
+    }
+    let first: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    let second: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    bob.send(&mut (), TestRole::Alice, &TestMessage::Quit)
+        .await
+        .unwrap();
+    // The in-memory handler does not deliver labels, so the offer fails
+    assert!(bob.offer(&mut (), TestRole::Alice).await.is_err());
+    // A receive cut short by a timeout is journaled as abandoned
+    let waiting = tokio::time::timeout(
+        Duration::from_millis(10),
+        bob.recv::<TestMessage>(&mut (), TestRole::Alice),
+    )
+    .await;
+    assert!(waiting.is_err());
+    drop(bob);
+
+    let entries = read_journal(&path).unwrap();
+    let outcomes: Vec<_> = entries.iter().map(|entry| &entry.outcome).collect();
+    assert!(matches!(
+        outcomes[..],
+        [
+            JournalOutcome::Completed,
+            JournalOutcome::Completed,
+            JournalOutcome::Completed,
+            JournalOutcome::Failed(_),
+            JournalOutcome::Abandoned
+        ]
+    ));
+    assert!(entries.iter().all(|entry| entry.role == "Bob"));
+    assert_eq!(
+        entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(),
+        [0, 1, 2, 3, 4]
+    );
+    assert!(matches!(
+        &entries[0].effect,
+        JournalEffect::Recv { from, message, payload }
+            if from == "Alice" && message == "TestMessage" && !payload.is_empty()
+    ));
+
+    // A crash while writing leaves a torn line, which is skipped
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"{\"seq\":5,\"at\"")
+        .unwrap();
+    assert_eq!(read_journal(&path).unwrap().len(), 5);
+
+    // The re-run gets the same messages without Alice sending them again,
+    // and carries on live once the journal runs out
+    let live = Journal::open(handler(TestRole::Bob), TestRole::Bob, &path).unwrap();
+    let mut bob = replay_journal(&path, live).unwrap();
+    assert_eq!(bob.remaining(), 5);
+    let replayed: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    assert_eq!(replayed, first);
+    let replayed: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    assert_eq!(replayed, second);
+    bob.send(&mut (), TestRole::Alice, &TestMessage::Quit)
+        .await
+        .unwrap();
+    assert!(matches!(
+        bob.offer(&mut (), TestRole::Alice).await,
+        Err(ChoreographyError::Transport(_))
+    ));
+    let waiting = tokio::time::timeout(
+        Duration::from_millis(10),
+        bob.recv::<TestMessage>(&mut (), TestRole::Alice),
+    )
+    .await;
+    assert!(waiting.is_err());
+    assert!(bob.is_live());
+
+    // The abandoned receive took the channel from Alice with it
+    channels
+        .lock()
+        .unwrap()
+        .remove(&(TestRole::Alice, TestRole::Bob));
+    alice
+        .send(&mut (), TestRole::Bob, &TestMessage::Data(3))
+        .await
+        .unwrap();
+    let message: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    assert_eq!(message, TestMessage::Data(3));
+    let entries = read_journal(&path).unwrap();
+    assert_eq!(entries.len(), 6);
+    assert_eq!(entries[5].seq, 5);
+
+    // A re-run that strays from the journal is stopped
+    let mut bob = replay_journal(&path, handler(TestRole::Bob)).unwrap();
+    let result = bob.send(&mut (), TestRole::Alice, &TestMessage::Quit).await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::SessionMismatch { .. })
+    ));
+}
+
+// Test 52: A route picks its receiver from the payload and chooses its label
+#[tokio::test]
+async fn test_route_by_payload() {
+    use rumpsteak_choreography::{InterpreterState, RecordedEvent, Router};
+
+    let router = Router::new(
+        "parity",
+        vec![
+            (Label("Bob"), TestRole::Bob),
+            (Label("Charlie"), TestRole::Charlie),
+        ],
+        |msg: &TestMessage| match msg {
+            TestMessage::Data(n) if n % 2 == 0 => TestRole::Bob,
+            TestMessage::Data(_) => TestRole::Charlie,
+            _ => TestRole::Alice,
+        },
+    );
+    let program = |msg| {
+        Program::<TestRole, TestMessage>::new()
+            .route(TestRole::Alice, msg, router.clone())
+            .branch(
+                TestRole::Alice,
+                vec![
+                    (Label("Bob"), Program::new()),
+                    (
+                        Label("Charlie"),
+                        Program::new().send(TestRole::Charlie, TestMessage::Quit),
+                    ),
+                ],
+            )
+            .end()
+    };
+    assert_eq!(program(TestMessage::Data(1)).send_count(), 2);
+
+    let mut handler = RecordingHandler::new(TestRole::Alice);
+    let result = interpret(&mut handler, &mut (), program(TestMessage::Data(3)))
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    let events = handler.events();
+    assert!(matches!(
+        events[..],
+        [
+            RecordedEvent::Choose {
+                label: Label("Charlie"),
+                ..
+            },
+            RecordedEvent::Send {
+                to: TestRole::Charlie,
+                ..
+            },
+            RecordedEvent::Send {
+                to: TestRole::Charlie,
+                ..
+            },
+        ]
+    ));
+
+    // Only the candidates can be picked
+    let mut handler = RecordingHandler::new(TestRole::Alice);
+    let result = interpret(&mut handler, &mut (), program(TestMessage::Quit))
+        .await
+        .unwrap();
+    assert!(matches!(
+        result.final_state,
+        InterpreterState::Failed(ref e) if e.contains("parity routed the message to Alice")
+    ));
+    assert!(handler.events().is_empty());
+}
+
+// Test 53: Metrics split latency into serialization, transport and deserialization
+#[tokio::test]
+async fn test_metrics_split_codec_from_transport() {
+    use rumpsteak_choreography::{ChoreoHandler, InMemoryHandler, OperationMetrics};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |role| InMemoryHandler::with_channels(role, channels.clone(), choices.clone());
+    // Stacked middleware each see the codec time of the handler beneath
+    let mut alice = Metrics::new(Metrics::new(handler(TestRole::Alice)));
+    let mut bob = Metrics::new(handler(TestRole::Bob));
+
+    for _ in 0..2 {
+        let message = TestMessage::Hello("x".repeat(1 << 16));
+        alice.send(&mut (), TestRole::Bob, &message).await.unwrap();
+    }
+    alice
+        .choose(&mut (), TestRole::Bob, Label("done"))
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let _: TestMessage = bob.recv(&mut (), TestRole::Alice).await.unwrap();
+    }
+
+    let phases = |metrics: &OperationMetrics| {
+        (
+            metrics.serialize_us.count,
+            metrics.transport_us.count,
+            metrics.deserialize_us.count,
+        )
+    };
+    for report in [alice.snapshot(), alice.inner().snapshot()] {
+        let (_, send) = &report.operations[0];
+        assert_eq!(phases(send), (2, 2, 0));
+        assert!(send.serialize_us.sum + send.transport_us.sum <= send.latency_us.sum + 2);
+        let (_, choose) = &report.operations[1];
+        assert_eq!(phases(choose), (0, 1, 0));
+    }
+    let report = bob.snapshot();
+    assert_eq!(phases(&report.operations[0].1), (0, 2, 2));
+
+    // Reports written before the split still parse
+    let json = r#"{"count":1,"errors":0,
+        "latency_us":{"count":0,"sum":0,"min":0,"max":0,"buckets":[]},
+        "payload_bytes":{"count":0,"sum":0,"min":0,"max":0,"buckets":[]}}"#;
+    let old: OperationMetrics = serde_json::from_str(json).unwrap();
+    assert_eq!(old.transport_us.count, 0);
+}
+
+// Test 54: Values received in a finally body and its cleanup are kept
+#[tokio::test]
+async fn test_finally_keeps_received_values() {
+    use rumpsteak_choreography::effects::InMemoryHandler;
+    use rumpsteak_choreography::InterpreterState;
+    use std::sync::Arc;
+
+    let channels = Arc::default();
+    let choices = Arc::default();
+    let mut alice = InMemoryHandler::with_channels(
+        TestRole::Alice,
+        Arc::clone(&channels),
+        Arc::clone(&choices),
+    );
+    let mut bob = InMemoryHandler::with_channels(TestRole::Bob, channels, choices);
+
+    interpret(
+        &mut bob,
+        &mut (),
+        Program::<TestRole, TestMessage>::new()
+            .send(TestRole::Alice, TestMessage::Data(1))
+            .send(TestRole::Alice, TestMessage::Data(2))
+            .end(),
+    )
+    .await
+    .unwrap();
+    let program = Program::<TestRole, TestMessage>::new()
+        .with_finally(
+            Program::new().recv::<TestMessage>(TestRole::Bob),
+            Program::new().recv::<TestMessage>(TestRole::Bob),
+        )
+        .end();
+    let result = interpret(&mut alice, &mut (), program).await.unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    assert_eq!(
+        result.received_values,
+        vec![TestMessage::Data(1), TestMessage::Data(2)]
+    );
+}
+
+// Test 55: The interpreter builder combines cancellation, guards and hooks
+#[test]
+fn test_interpreter_builder_combines_options() {
+    use async_trait::async_trait;
+    use rumpsteak_choreography::{
+        CancellationToken, EffectContext, Guard, GuardContext, Interpreter, InterpreterHooks,
+        InterpreterState,
+    };
+
+    #[derive(Default)]
+    struct Branches(Vec<Label>);
+
+    #[async_trait]
+    impl InterpreterHooks<TestRole, TestMessage> for Branches {
+        async fn on_branch_taken(&mut self, label: Label, _ctx: &EffectContext) {
+            self.0.push(label);
+        }
+    }
+
+    let program = || {
+        Program::<TestRole, TestMessage>::new()
+            .choose_when(
+                TestRole::Alice,
+                vec![
+                    (
+                        Label("buy"),
+                        Some(Guard::parse("balance >= price").unwrap()),
+                        Program::new().send(TestRole::Bob, TestMessage::Data(1)),
+                    ),
+                    (Label("save"), None, Program::new()),
+                ],
+            )
+            .end()
+    };
+
+    executor::block_on(async {
+        let token = CancellationToken::new();
+        let mut handler = NoOpHandler::new();
+        let mut hooks = Branches::default();
+        let result = Interpreter::new(&mut handler, &mut ())
+            .cancel(&token)
+            .guards(
+                GuardContext::new()
+                    .with_var("balance", 120)
+                    .with_var("price", 80),
+            )
+            .hooks(&mut hooks)
+            .run(program())
+            .await
+            .unwrap();
+        assert_eq!(result.final_state, InterpreterState::Completed);
+        assert_eq!(hooks.0, vec![Label("buy")]);
+
+        token.cancel();
+        let mut hooks = Branches::default();
+        let result = Interpreter::new(&mut handler, &mut ())
+            .cancel(&token)
+            .guards(GuardContext::new().with_var("balance", 0))
+            .hooks(&mut hooks)
+            .run(program())
+            .await
+            .unwrap();
+        assert_eq!(result.final_state, InterpreterState::Cancelled);
+        assert!(hooks.0.is_empty());
+    });
+}
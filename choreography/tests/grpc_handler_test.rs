@@ -0,0 +1,116 @@
+// Integration tests for GrpcHandler, with endpoints wired the way the
+// generated tonic glue wires them
+
+use futures::StreamExt;
+use rumpsteak_choreography::effects::{
+    ChoreoHandler, ChoreographyError, GrpcEndpoint, GrpcEnvelope, GrpcHandler, Label,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// Pass each endpoint's outgoing stream to the other's acceptor, as the
+/// client and server of a call would
+fn connected() -> (GrpcEndpoint<Role>, GrpcEndpoint<Role>) {
+    let mut client = GrpcEndpoint::new(Role::Client);
+    let mut server = GrpcEndpoint::new(Role::Server);
+    let to_server = client.outgoing(Role::Server).map(Ok);
+    let to_client = server.outgoing(Role::Client).map(Ok);
+    tokio::spawn(server.acceptor().accept(Role::Client, to_server));
+    tokio::spawn(client.acceptor().accept(Role::Server, to_client));
+    (client, server)
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Order {
+    item: String,
+    quantity: u32,
+}
+
+#[tokio::test]
+async fn test_messages_and_choices_round_trip() {
+    let (mut client, mut server) = connected();
+    let mut handler = GrpcHandler::new();
+
+    let order = Order {
+        item: "tea".to_string(),
+        quantity: 2,
+    };
+    handler
+        .send(&mut client, Role::Server, &order)
+        .await
+        .unwrap();
+    let received: Order = handler.recv(&mut server, Role::Client).await.unwrap();
+    assert_eq!(received, order);
+
+    handler
+        .choose(&mut server, Role::Server, Label("accept"))
+        .await
+        .unwrap();
+    let label = handler.offer(&mut client, Role::Server).await.unwrap();
+    assert_eq!(label, Label("accept"));
+}
+
+#[tokio::test]
+async fn test_envelopes_carry_json_and_type_names() {
+    let mut client = GrpcEndpoint::new(Role::Client);
+    let mut outgoing = client.outgoing(Role::Server);
+    let mut handler = GrpcHandler::new();
+
+    handler
+        .send(
+            &mut client,
+            Role::Server,
+            &Order {
+                item: "tea".to_string(),
+                quantity: 2,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        outgoing.next().await,
+        Some(GrpcEnvelope::Message {
+            name: "Order".to_string(),
+            payload: br#"{"item":"tea","quantity":2}"#.to_vec(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_stream_errors() {
+    let (mut client, mut server) = connected();
+    let mut handler = GrpcHandler::new();
+
+    handler
+        .choose(&mut client, Role::Client, Label("quit"))
+        .await
+        .unwrap();
+    let result: Result<u32, _> = handler.recv(&mut server, Role::Client).await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::ProtocolViolation(_))
+    ));
+
+    handler
+        .choose(&mut client, Role::Client, Label::CANCEL)
+        .await
+        .unwrap();
+    let result: Result<u32, _> = handler.recv(&mut server, Role::Client).await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::Aborted {
+            label: "sys.cancel",
+            ..
+        })
+    ));
+
+    assert!(client.disconnect(&Role::Server));
+    let result: Result<u32, _> = handler.recv(&mut server, Role::Client).await;
+    assert!(matches!(result, Err(ChoreographyError::Transport(_))));
+    let result = handler.send(&mut client, Role::Server, &1u32).await;
+    assert!(matches!(result, Err(ChoreographyError::Transport(_))));
+}
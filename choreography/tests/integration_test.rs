@@ -7,7 +7,7 @@ use proc_macro2::{Ident, Span};
 use quote::quote;
 use rumpsteak_choreography::ast::{Branch, Choreography, Condition, MessageType, Protocol, Role};
 use rumpsteak_choreography::compiler::{analyze, project};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 // Helper to create identifiers
 fn ident(s: &str) -> Ident {
@@ -55,6 +55,10 @@ fn test_simple_two_party_protocol() {
         roles: vec![alice.clone(), bob.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     // Validate the choreography
@@ -73,8 +77,7 @@ fn test_simple_two_party_protocol() {
     // Analyze choreography
     let results = analyze(&choreography);
     assert_eq!(results.role_participation.len(), 2, "Should have 2 roles");
-    // Note: Deadlock analysis may not be fully implemented yet
-    let _ = results.is_deadlock_free;
+    assert!(results.is_deadlock_free);
 }
 
 #[test]
@@ -106,6 +109,10 @@ fn test_three_party_protocol() {
         roles: vec![alice.clone(), bob.clone(), carol.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -124,6 +131,7 @@ fn test_broadcast_protocol() {
         from: alice.clone(),
         to_all: vec![bob.clone(), carol.clone()],
         message: msg("Announcement"),
+        abort: false,
         continuation: Box::new(Protocol::End),
     };
 
@@ -132,6 +140,10 @@ fn test_broadcast_protocol() {
         roles: vec![alice.clone(), bob.clone(), carol.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -165,11 +177,13 @@ fn test_choice_protocol() {
             Branch {
                 label: ident("accept"),
                 guard: None,
+                weight: None,
                 protocol: accept_branch,
             },
             Branch {
                 label: ident("reject"),
                 guard: None,
+                weight: None,
                 protocol: reject_branch,
             },
         ],
@@ -180,6 +194,10 @@ fn test_choice_protocol() {
         roles: vec![alice.clone(), bob.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -209,6 +227,10 @@ fn test_loop_protocol() {
         roles: vec![alice.clone(), bob.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -245,6 +267,10 @@ fn test_parallel_protocol() {
         roles: vec![alice.clone(), bob.clone(), carol.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -277,6 +303,10 @@ fn test_recursive_protocol() {
         roles: vec![alice.clone(), bob.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -309,11 +339,13 @@ fn test_complex_negotiation() {
             Branch {
                 label: ident("accept"),
                 guard: None,
+                weight: None,
                 protocol: accept,
             },
             Branch {
                 label: ident("counter"),
                 guard: None,
+                weight: None,
                 protocol: counter,
             },
         ],
@@ -331,6 +363,10 @@ fn test_complex_negotiation() {
         roles: vec![buyer.clone(), seller.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
@@ -358,6 +394,10 @@ fn test_invalid_choreography_missing_role() {
         roles: vec![alice, bob], // Carol missing!
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     // Should fail validation
@@ -384,6 +424,10 @@ fn test_projection_consistency() {
         roles: vec![alice.clone(), bob.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let alice_local = project(&choreography, &alice).expect("Alice projection");
@@ -426,6 +470,10 @@ fn test_analysis_detects_roles() {
         roles: vec![alice, bob, carol],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let analysis = analyze(&choreography);
@@ -451,9 +499,1206 @@ fn test_message_with_payload() {
         roles: vec![alice.clone(), bob.clone()],
         protocol,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     assert!(choreography.validate().is_ok());
     assert!(project(&choreography, &alice).is_ok());
     assert!(project(&choreography, &bob).is_ok());
 }
+
+#[test]
+fn test_analysis_flags_blocking_cleanup() {
+    let alice = Role::new(ident("Alice"));
+    let bob = Role::new(ident("Bob"));
+
+    let protocol = Protocol::Finally {
+        body: Box::new(Protocol::Send {
+            from: alice.clone(),
+            to: bob.clone(),
+            message: msg("Work"),
+            continuation: Box::new(Protocol::End),
+        }),
+        cleanup: Box::new(Protocol::Loop {
+            condition: Some(Condition::RoleDecides(bob.clone())),
+            body: Box::new(Protocol::Send {
+                from: bob.clone(),
+                to: alice.clone(),
+                message: msg("Release"),
+                continuation: Box::new(Protocol::End),
+            }),
+        }),
+    };
+
+    let choreography = Choreography {
+        name: ident("Cleanup"),
+        roles: vec![alice, bob],
+        protocol,
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    let analysis = analyze(&choreography);
+
+    assert!(analysis.warnings.iter().any(|w| matches!(
+        w,
+        rumpsteak_choreography::compiler::AnalysisWarning::BlockingCleanup(_)
+    )));
+}
+
+#[test]
+fn test_analysis_report_output() {
+    use rumpsteak_choreography::{diagnostic_codes, Severity};
+
+    let alice = Role::new(ident("Alice"));
+    let bob = Role::new(ident("Bob"));
+    let carol = Role::new(ident("Carol"));
+
+    let protocol = Protocol::Send {
+        from: alice.clone(),
+        to: bob.clone(),
+        message: msg("Ping"),
+        continuation: Box::new(Protocol::End),
+    };
+
+    let choreography = Choreography {
+        name: ident("Idle"),
+        roles: vec![alice, bob, carol],
+        protocol,
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    let report = analyze(&choreography);
+
+    assert!(report.is_ok());
+    assert!(report.check("deadlock-freedom").unwrap().passed);
+    assert!(report.check("progress").unwrap().passed);
+
+    let unused: Vec<_> = report.diagnostics_with(Severity::Warning).collect();
+    assert_eq!(unused.len(), 1);
+    assert_eq!(unused[0].code, diagnostic_codes::UNUSED_ROLE);
+
+    let text = report.to_string();
+    assert!(text.contains("warning[A003]: role `Carol`"));
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["choreography"], "Idle");
+    assert_eq!(json["ok"], true);
+    assert_eq!(json["diagnostics"][0]["code"], "A003");
+    assert_eq!(json["diagnostics"][0]["severity"], "warning");
+    assert_eq!(json["roles"]["Carol"]["is_active"], false);
+    assert_eq!(json["edges"][0]["message"], "Ping");
+}
+
+#[test]
+fn test_analyzer_with_custom_pass() {
+    use rumpsteak_choreography::ast::LocalType;
+    use rumpsteak_choreography::compiler::{Analyzer, CustomPass, DeadlockCheck};
+
+    let alice = Role::new(ident("Alice"));
+    let bob = Role::new(ident("Bob"));
+    let carol = Role::new(ident("Carol"));
+
+    let protocol = Protocol::Send {
+        from: alice.clone(),
+        to: bob.clone(),
+        message: msg("Ping"),
+        continuation: Box::new(Protocol::End),
+    };
+
+    let choreography = Choreography {
+        name: ident("Idle"),
+        roles: vec![alice, bob, carol],
+        protocol,
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    let analyzer = Analyzer::builder()
+        .with(DeadlockCheck)
+        .with(CustomPass::new("no-idle-roles", |ctx, findings| {
+            for role in &ctx.choreography().roles {
+                if ctx.local_type(role) == Some(&LocalType::End) {
+                    findings.error("X001", format!("{} has nothing to do", role.name));
+                }
+            }
+        }))
+        .build();
+
+    let report = analyzer.analyze(&choreography);
+
+    let names: Vec<_> = report.checks.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["deadlock-freedom", "no-idle-roles"]);
+    assert!(report.check("deadlock-freedom").unwrap().passed);
+    assert!(!report.check("no-idle-roles").unwrap().passed);
+    assert!(!report.is_ok());
+
+    // Only the configured passes run, so the built-in unused-role warning is absent
+    assert!(report.warnings.is_empty());
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].message, "Carol has nothing to do");
+}
+
+#[test]
+fn test_role_timeline() {
+    use rumpsteak_choreography::compiler::{timeline, TimelineAction};
+
+    let client = Role::new(ident("Client"));
+    let server = Role::new(ident("Server"));
+    let logger = Role::new(ident("Logger"));
+
+    let protocol = Protocol::Send {
+        from: client.clone(),
+        to: server.clone(),
+        message: msg("Request"),
+        continuation: Box::new(Protocol::Send {
+            from: server.clone(),
+            to: logger.clone(),
+            message: msg("Log"),
+            continuation: Box::new(Protocol::Send {
+                from: server.clone(),
+                to: client.clone(),
+                message: msg("Response"),
+                continuation: Box::new(Protocol::End),
+            }),
+        }),
+    };
+
+    let choreography = Choreography {
+        name: ident("Logged"),
+        roles: vec![client.clone(), server.clone(), logger.clone()],
+        protocol,
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    let timeline = timeline(&choreography);
+
+    assert_eq!(timeline.phases, 3);
+    assert_eq!(timeline.peers(&server).len(), 2);
+    assert_eq!(timeline.idle_phases(&logger), vec![0, 2]);
+    assert_eq!(
+        timeline.lane(&client).unwrap().entries[1].action,
+        TimelineAction::Receive {
+            from: server.clone(),
+            message: "Response".to_string(),
+        }
+    );
+
+    let table = timeline.to_table();
+    assert!(table.starts_with("phase | Client"));
+    assert!(table.contains("-> Logger: Log"));
+
+    let dot = timeline.to_dot();
+    assert!(dot.contains("subgraph cluster_Server"));
+    assert!(dot.contains("Server_2 -> Client_1 [label=\"Response\"]"));
+}
+
+#[test]
+fn test_min_sync_removes_subsumed_ack() {
+    use rumpsteak_choreography::compiler::optimize;
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let input = r#"
+@optimize(min_sync)
+choreography Upload {
+    roles: Client, Server, Store
+
+    Client -> Server: Begin
+    Server -> Client: Ready
+    Server -> Store: Reserve
+    Server -> Client: Data(Vec<u8>)
+    Client -> Server: Done
+}
+"#;
+
+    let choreography = parse_choreography_str(input).unwrap();
+    let optimization = optimize(&choreography).unwrap();
+
+    assert_eq!(optimization.removed.len(), 1);
+    assert_eq!(optimization.removed[0].message, "Ready");
+    assert_eq!(
+        optimization.diff(),
+        "- Server -> Client: Ready  (subsumed by Server -> Client: Data)\n"
+    );
+
+    let report = optimization.report.expect("checks re-run after rewriting");
+    assert!(report.check("progress").unwrap().passed);
+    assert_eq!(report.communication_graph.edges.len(), 4);
+
+    // Without the annotation nothing changes
+    let plain = rumpsteak_choreography::compiler::parser::parse_choreography_str(
+        &input.replace("@optimize(min_sync)", ""),
+    )
+    .unwrap();
+    let unchanged = optimize(&plain).unwrap();
+    assert!(unchanged.removed.is_empty());
+    assert!(unchanged.report.is_none());
+}
+
+#[test]
+fn test_analysis_flags_names_differing_by_case() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::AnalysisWarning;
+
+    let input = r#"
+choreography Typos {
+    roles: Client, client, Server
+
+    Client -> Server: Request
+    client -> Server: Request
+    Server -> Client: ACK
+    Server -> client: Ack
+    Server -> client: Ack
+}
+"#;
+
+    let analysis = analyze(&parse_choreography_str(input).unwrap());
+
+    let similar: Vec<_> = analysis
+        .warnings
+        .iter()
+        .filter(|w| {
+            matches!(
+                w,
+                AnalysisWarning::SimilarRoleNames(..) | AnalysisWarning::SimilarMessageNames(..)
+            )
+        })
+        .collect();
+    assert_eq!(similar.len(), 2, "{:?}", similar);
+    assert!(analysis
+        .diagnostics
+        .iter()
+        .any(|d| d.code == "A007" && d.message.contains("`ACK` and `Ack`")));
+}
+
+#[test]
+fn test_analysis_rejects_sensitive_fields_to_untrusted_roles() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let input = r#"
+choreography Checkout {
+    roles: Shopper, @trusted Payments, Shop
+
+    Shopper -> Payments: Order(card: String @sensitive, amount: u64)
+    Payments -> Shop: Receipt(card: String @sensitive, amount: u64)
+    Shop -> Shopper: Confirmation
+}
+"#;
+
+    let choreography = parse_choreography_str(input).unwrap();
+    let trusted: Vec<_> = choreography
+        .trusted
+        .iter()
+        .map(|role| role.name.to_string())
+        .collect();
+    assert_eq!(trusted, ["Payments"]);
+    let analysis = analyze(&choreography);
+    let findings: Vec<_> = analysis
+        .diagnostics
+        .iter()
+        .filter(|d| d.code == "A008")
+        .collect();
+    assert_eq!(findings.len(), 1, "{:?}", findings);
+    assert!(findings[0].message.contains("`Receipt` to `Shop`"));
+
+    // Trusting the shop at the choreography level clears the finding
+    let trusted = format!("@trusted(Shop)\n{}", input.trim_start());
+    let analysis = analyze(&parse_choreography_str(&trusted).unwrap());
+    assert!(analysis.diagnostics.iter().all(|d| d.code != "A008"));
+}
+
+#[test]
+fn test_analysis_with_provenance_points_at_statements() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_with_provenance;
+    use rumpsteak_choreography::compiler::{analyze_with_provenance, CompileConfig};
+
+    let input = r#"
+choreography Checkout {
+    roles: Shopper, @trusted Payments, Shop
+
+    Shopper -> Payments: Order(card: String @sensitive, amount: u64)
+    Payments -> Shop: Receipt(card: String @sensitive, amount: u64)
+    Shop -> Shopper: Confirmation
+}
+"#;
+
+    let (choreography, provenance) =
+        parse_choreography_with_provenance(input, &CompileConfig::default()).unwrap();
+    let analysis = analyze_with_provenance(&choreography, &provenance);
+    let finding = analysis
+        .diagnostics
+        .iter()
+        .find(|d| d.code == "A008")
+        .unwrap();
+    let span = finding.span.as_ref().unwrap();
+    assert_eq!(span.line, 6);
+    assert!(
+        span.snippet.contains("Payments -> Shop: Receipt"),
+        "{}",
+        span.snippet
+    );
+    assert!(finding.to_string().contains("6:"), "{}", finding);
+
+    // Without provenance there is nothing to point at
+    let analysis = analyze(&choreography);
+    assert!(analysis.diagnostics.iter().all(|d| d.span.is_none()));
+}
+
+#[test]
+fn test_analysis_checks_races() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let partial = parse_choreography_str(
+        r#"
+choreography Job {
+    roles: Client, Server, Log
+
+    race {
+        Client -> Server: Cancel
+    |
+        Server -> Log: Done
+    }
+}
+"#,
+    )
+    .unwrap();
+    let analysis = analyze(&partial);
+    let findings: Vec<_> = analysis
+        .diagnostics
+        .iter()
+        .filter(|d| d.code == "A009")
+        .collect();
+    assert_eq!(findings.len(), 2, "{:?}", findings);
+    assert!(findings.iter().any(|d| d.message.contains("`Client`")));
+    assert!(findings.iter().any(|d| d.message.contains("`Log`")));
+
+    // One initiator for every arm is just a choice
+    let one_sided = parse_choreography_str(
+        r#"
+choreography Job {
+    roles: Client, Server
+
+    race {
+        Client -> Server: Cancel
+    |
+        Client -> Server: Resume
+    }
+}
+"#,
+    )
+    .unwrap();
+    let analysis = analyze(&one_sided);
+    assert!(analysis
+        .diagnostics
+        .iter()
+        .any(|d| d.code == "A009" && d.message.contains("use a choice")));
+}
+
+#[test]
+fn test_deadlock_check_reports_role_left_waiting() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    // C only hears from A in the `r` branch, but A tells B alone
+    let choreography = parse_choreography_str(
+        r#"
+choreography Lost {
+    roles: A, B, C
+
+    choice A {
+        l: {
+            A -> B: X
+            B -> C: Y
+        }
+        r: {
+            A -> C: Z
+            C -> B: Y
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let report = analyze(&choreography);
+    assert!(!report.is_deadlock_free);
+    let finding = report
+        .diagnostics
+        .iter()
+        .find(|d| d.code == "A001")
+        .unwrap();
+    assert!(finding.message.contains("`C` waits for"), "{}", finding);
+    assert!(finding.message.contains("A chooses r"), "{}", finding);
+}
+
+#[test]
+fn test_deadlock_check_fails_for_unprojectable_role() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    // Logger cannot tell which branch was taken, so it does not project
+    let choreography = parse_choreography_str(
+        r#"
+choreography Trade {
+    roles: Client, Server, Logger
+
+    choice Client {
+        buy: {
+            Client -> Server: Buy
+            Server -> Logger: Bought
+        }
+        sell: {
+            Client -> Server: Sell
+            Client -> Logger: Sold
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let report = analyze(&choreography);
+    assert!(!report.is_deadlock_free);
+    let finding = report
+        .diagnostics
+        .iter()
+        .find(|d| d.code == "A013")
+        .unwrap();
+    assert!(finding.message.contains("role Logger"), "{}", finding);
+    assert!(finding.message.contains("buy"), "{}", finding);
+}
+
+#[test]
+fn test_deadlock_check_notes_symbolic_role_families() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::Severity;
+
+    // The ParameterizedLoop example from the DSL reference
+    let choreography = parse_choreography_str(
+        r#"
+choreography ParameterizedLoop {
+    roles: Master, Worker[N]
+
+    loop (count: N) {
+        Master -> Worker[i]: Work
+        Worker[i] -> Master: Result
+    }
+}
+"#,
+    )
+    .unwrap();
+    let report = analyze(&choreography);
+    assert!(report.is_ok(), "{:?}", report.diagnostics);
+    let note = report
+        .diagnostics
+        .iter()
+        .find(|d| d.code == "A001")
+        .unwrap();
+    assert_eq!(note.severity, Severity::Info);
+    assert!(note.message.contains("expand_roles"), "{}", note);
+}
+
+#[test]
+fn test_deadlock_check_expands_sized_role_families() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let choreography = parse_choreography_str(
+        r#"
+choreography Scatter {
+    roles: Master, Worker[2]
+
+    Master -> Worker[0]: Work
+    Worker[0] -> Master: Result
+    Master -> Worker[1]: Work
+    Worker[1] -> Master: Result
+}
+"#,
+    )
+    .unwrap();
+    let report = analyze(&choreography);
+    assert!(report.is_ok(), "{:?}", report.diagnostics);
+    assert!(report.is_deadlock_free);
+    assert!(report.diagnostics.iter().all(|d| d.code != "A001"));
+}
+
+#[test]
+fn test_find_deadlock_reports_cycle() {
+    use rumpsteak_choreography::ast::LocalType;
+    use rumpsteak_choreography::compiler::find_deadlock;
+
+    let alice = Role::new(ident("Alice"));
+    let bob = Role::new(ident("Bob"));
+    // Each role waits for the other before sending
+    let waits_then_sends = |peer: &Role| LocalType::Receive {
+        from: peer.clone(),
+        message: msg("Ready"),
+        continuation: Box::new(LocalType::Send {
+            to: peer.clone(),
+            message: msg("Ready"),
+            continuation: Box::new(LocalType::End),
+        }),
+    };
+    let (a, b) = (waits_then_sends(&bob), waits_then_sends(&alice));
+
+    let deadlock = find_deadlock(&[(alice.clone(), &a), (bob.clone(), &b)]).unwrap();
+    assert_eq!(deadlock.cycle, vec![alice.clone(), bob.clone()]);
+    assert!(deadlock.trace.is_empty());
+    assert_eq!(
+        deadlock.to_string(),
+        "roles wait on each other in a cycle: `Alice` -> `Bob` -> `Alice` (at the start)"
+    );
+
+    // Sending first breaks the cycle
+    let sends_then_waits = LocalType::Send {
+        to: bob.clone(),
+        message: msg("Ready"),
+        continuation: Box::new(LocalType::Receive {
+            from: bob.clone(),
+            message: msg("Ready"),
+            continuation: Box::new(LocalType::End),
+        }),
+    };
+    assert!(find_deadlock(&[(alice, &sends_then_waits), (bob, &b)]).is_none());
+}
+
+#[test]
+fn test_generated_code_is_namespaced() {
+    use rumpsteak_choreography::compiler::{
+        check_name_collisions, generate_effects_protocol, generate_reexports, module_name,
+        NameCollision,
+    };
+
+    let client = Role::new(ident("Client"));
+    let server = Role::new(ident("Server"));
+    let ping = |name: &str| Choreography {
+        name: ident(name),
+        roles: vec![client.clone(), server.clone()],
+        protocol: Protocol::Send {
+            from: client.clone(),
+            to: server.clone(),
+            message: msg("Ping"),
+            continuation: Box::new(Protocol::End),
+        },
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    let first = ping("PingPong");
+    let second = ping("HTTPProbe");
+    assert_eq!(module_name(&first).to_string(), "ping_pong");
+    assert_eq!(module_name(&second).to_string(), "http_probe");
+    assert!(check_name_collisions(&[&first, &second]).is_ok());
+
+    let code = generate_effects_protocol(&first).to_string();
+    assert!(code.starts_with("pub mod ping_pong"));
+    let reexports = generate_reexports(&first).to_string();
+    assert!(reexports.contains("Role as PingPongRole"));
+
+    // Two choreographies mapping to the same module
+    let clash = ping("Ping_Pong");
+    let err = check_name_collisions(&[&first, &clash]).unwrap_err();
+    assert!(matches!(err, NameCollision::Module { .. }));
+    assert!(err.to_string().contains("'ping_pong'"));
+
+    // A message named after a generated item
+    let mut reserved = ping("Reserved");
+    if let Protocol::Send { message, .. } = &mut reserved.protocol {
+        message.name = ident("Message");
+    }
+    assert!(matches!(
+        check_name_collisions(&[&reserved]),
+        Err(NameCollision::ReservedName { .. })
+    ));
+
+    // A message named after a role's endpoint
+    if let Protocol::Send { message, .. } = &mut reserved.protocol {
+        message.name = ident("ServerEndpoint");
+    }
+    assert!(matches!(
+        check_name_collisions(&[&reserved]),
+        Err(NameCollision::ReservedName { .. })
+    ));
+}
+
+#[test]
+fn test_projection_cache_reuses_outputs() {
+    use rumpsteak_choreography::compiler::{fingerprint, CacheStats, ProjectionCache};
+
+    let dir = tempfile::tempdir().unwrap();
+    let client = Role::new(ident("Client"));
+    let server = Role::new(ident("Server"));
+    let request = |message: &str| Choreography {
+        name: ident("Lookup"),
+        roles: vec![client.clone(), server.clone()],
+        protocol: Protocol::Send {
+            from: client.clone(),
+            to: server.clone(),
+            message: msg(message),
+            continuation: Box::new(Protocol::End),
+        },
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    let original = request("Query");
+    let cache = ProjectionCache::open(dir.path()).unwrap();
+    let first = cache.session_type(&original, &client).unwrap();
+    let second = cache.session_type(&original, &client).unwrap();
+    assert_eq!(first.to_string(), second.to_string());
+    assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+
+    // A fresh cache over the same directory still hits
+    let reopened = ProjectionCache::open(dir.path()).unwrap();
+    reopened.session_type(&original, &client).unwrap();
+    reopened.effects_protocol(&original).unwrap();
+    assert_eq!(reopened.stats(), CacheStats { hits: 1, misses: 1 });
+
+    // Editing the protocol changes the fingerprint
+    let edited = request("Search");
+    assert_ne!(fingerprint(&original), fingerprint(&edited));
+    reopened.session_type(&edited, &client).unwrap();
+    assert_eq!(reopened.stats().misses, 2);
+
+    assert_eq!(reopened.retain(&[fingerprint(&edited)]).unwrap(), 1);
+}
+
+#[test]
+fn test_rendered_code_links_back_to_dsl() {
+    use rumpsteak_choreography::compiler::{
+        parse_choreography_with_provenance, render_effects_protocol, CompileConfig,
+    };
+
+    let source = r#"
+choreography Lookup {
+    roles: Client, Server
+
+    Client -> Server: Query
+    choice Server {
+        found: {
+            Server -> Client: Answer
+        }
+        missing: {
+            Server -> Client: NotFound
+        }
+    }
+}
+"#;
+
+    let (choreography, provenance) =
+        parse_choreography_with_provenance(source, &CompileConfig::default()).unwrap();
+    assert_eq!(provenance.span(&[]).unwrap().line, 5);
+    assert_eq!(provenance.span(&[0, 1]).unwrap().line, 11);
+
+    let code = render_effects_protocol(&choreography, Some(&provenance)).unwrap();
+    assert!(code.lines().count() > 20);
+    assert!(code.contains("/// - line 5: `Client -> Server: Query`"));
+    assert!(code.contains("/// - line 8: `Server -> Client: Answer`"));
+    assert!(code.contains("/// First used at line 11: `Server -> Client: NotFound`"));
+}
+
+#[test]
+fn test_source_map_points_at_dsl_statements() {
+    use rumpsteak_choreography::compiler::{
+        generate_effects_protocol_with_provenance, parse_choreography_with_provenance,
+        CompileConfig, SourceMap,
+    };
+
+    let source = r#"
+choreography Transfer {
+    roles: Bank, Client
+
+    Client -> Bank: Withdraw
+    Bank -> Client: Receipt
+}
+"#;
+
+    let (choreography, provenance) =
+        parse_choreography_with_provenance(source, &CompileConfig::default()).unwrap();
+    let map = SourceMap::build(&choreography, &provenance);
+
+    let step = map.step("Client", 1).unwrap();
+    assert_eq!(step.item, "client_program");
+    assert_eq!(step.interaction, "Bank -> Client: Receipt");
+    assert_eq!(step.span.line, 6);
+
+    let entry = map.find_interaction("Bank", "Client", "Receipt").unwrap();
+    let report = map.explain(entry, "unexpected message `Refund`");
+    assert!(report.contains("6 |     Bank -> Client: Receipt"));
+
+    assert_eq!(map.item("Withdraw").next().unwrap().span.line, 5);
+    assert_eq!(SourceMap::from_json(&map.to_json()).unwrap(), map);
+
+    let code = generate_effects_protocol_with_provenance(&choreography, &provenance).to_string();
+    assert!(code.contains("SOURCE_MAP"));
+}
+
+#[test]
+fn test_simulation_models_latency_and_bandwidth() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::{simulate, Latency, LatencyModel, SimulationConfig};
+    use std::time::Duration;
+
+    let ms = Duration::from_millis;
+    let choreography = parse_choreography_str(
+        r#"
+choreography Upload {
+    roles: Client, Server
+    Client -> Server: Chunk
+    Client -> Server: Chunk
+    Server -> Client: Ack
+}
+"#,
+    )
+    .unwrap();
+
+    // Each 500-byte chunk holds a 1000 B/s link for 500ms, so the second
+    // one queues behind the first
+    let network = LatencyModel::constant(ms(10))
+        .with_bandwidth("Client", "Server", 1000)
+        .with_message_size("Chunk", 500)
+        .with_edge(
+            "Server",
+            "Client",
+            Latency::Uniform {
+                min: ms(10),
+                max: ms(30),
+            },
+        );
+    let config = SimulationConfig::new()
+        .with_runs(2_000)
+        .with_latency(network.clone())
+        .with_deadline(ms(1030))
+        .with_receive_timeout(ms(1015));
+    let report = simulate(&choreography, &config);
+    assert!(report.latency.p50 > ms(1020) && report.latency.p50 < ms(1040));
+    assert!(report.latency.max <= ms(1040));
+    assert!(report.deadline_misses > 0.3 && report.deadline_misses < 0.7);
+    // The server gets the second chunk at 1010ms; the client always waits
+    // longer than 1015ms for the ack
+    assert_eq!(report.receive_timeouts, 1.0);
+
+    // A long-tailed edge spreads completion times
+    let tailed = config.with_latency(network.with_edge(
+        "Server",
+        "Client",
+        Latency::LogNormal {
+            median: ms(20),
+            sigma: 1.0,
+        },
+    ));
+    let report = simulate(&choreography, &tailed);
+    let tail = report.latency.p99 - report.latency.p50;
+    assert!(tail > ms(50), "tail was {:?}", tail);
+    assert_eq!(simulate(&choreography, &tailed), report);
+}
+
+#[test]
+fn test_query_answers_graph_questions() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::Step;
+
+    let choreography = parse_choreography_str(
+        r#"
+choreography Auction {
+    roles: Seller, Buyer, Bank, Auditor
+    Seller -> Buyer: Offer
+    choice Buyer {
+        accept: {
+            Buyer -> Bank: Pay
+            Bank -> Seller: Paid
+        }
+        reject: {
+            Buyer -> Seller: Decline
+            Seller -> Auditor: Report
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let query = choreography.query();
+
+    let between: Vec<_> = query
+        .messages_between("Buyer", "Seller")
+        .iter()
+        .map(|i| i.message.as_str())
+        .collect();
+    assert_eq!(between, ["Offer", "Decline"]);
+
+    let paths = query.paths_reaching("Paid");
+    assert_eq!(paths.len(), 1);
+    let steps: Vec<_> = paths[0].iter().map(Step::to_string).collect();
+    assert_eq!(
+        steps,
+        [
+            "Seller -> Buyer: Offer",
+            "Buyer chooses accept",
+            "Buyer -> Bank: Pay",
+            "Bank -> Seller: Paid",
+        ]
+    );
+    assert!(matches!(&paths[0][1], Step::Branch { path, .. } if path == &vec![0, 0]));
+    assert_eq!(query.paths_reaching("reject").len(), 1);
+    assert!(query.paths_reaching("Missing").is_empty());
+
+    let downstream =
+        |message: &str| -> Vec<String> { query.roles_downstream_of(message).into_iter().collect() };
+    assert_eq!(downstream("Pay"), ["Bank", "Seller"]);
+    assert_eq!(downstream("Decline"), ["Auditor", "Seller"]);
+    assert_eq!(downstream("Offer"), ["Auditor", "Bank", "Buyer", "Seller"]);
+}
+
+#[test]
+fn test_compile_with_timings_reports_phases_and_nodes() {
+    use rumpsteak_choreography::compiler::{compile_with_timings, CompileConfig, CompileError};
+
+    let source = r#"
+choreography Timed {
+    roles: Client, Server, Db
+    Client -> Server: Request
+    choice Server {
+        hit: {
+            Server -> Client: Cached
+        }
+        miss: {
+            Server -> Db: Query
+            Db -> Server: Rows
+            Server -> Client: Fresh
+        }
+    }
+}
+"#;
+    let timings = compile_with_timings(source, &CompileConfig::new()).unwrap();
+    assert_eq!(timings.choreography, "Timed");
+    let roles: Vec<_> = timings.projection.iter().map(|(r, _)| r.as_str()).collect();
+    assert_eq!(roles, ["Client", "Server", "Db"]);
+    assert_eq!(timings.nodes.messages, 5);
+    assert_eq!(timings.nodes.choices, 1);
+    assert_eq!(timings.nodes.branches, 2);
+    assert!(timings.total() >= timings.projection_total());
+    assert!(timings.slowest_projection().is_some());
+    assert!(timings.to_string().contains("projection:"));
+    assert!(timings.to_json().contains("\"nodes\""));
+
+    let err = compile_with_timings("choreography Broken {", &CompileConfig::new()).unwrap_err();
+    assert!(matches!(err, CompileError::Parse(_)));
+}
+
+#[test]
+fn test_project_shared_deduplicates_symmetric_roles() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::{project, project_shared, SharedNode};
+
+    let choreography = parse_choreography_str(
+        r#"
+choreography Farm {
+    roles: Coordinator, W1, W2, W3
+    Coordinator -> W1: Task
+    Coordinator -> W2: Task
+    Coordinator -> W3: Task
+    W1 -> Coordinator: Done
+    W2 -> Coordinator: Done
+    W3 -> Coordinator: Done
+}
+"#,
+    )
+    .unwrap();
+    let shared = project_shared(&choreography).unwrap();
+    let roles = &choreography.roles;
+
+    assert!(shared.same_type(&roles[1], &roles[2]));
+    assert!(shared.same_type(&roles[2], &roles[3]));
+    assert!(!shared.same_type(&roles[0], &roles[1]));
+
+    for role in roles {
+        assert_eq!(
+            shared.local_type(role).unwrap(),
+            project(&choreography, role).unwrap()
+        );
+    }
+
+    // Six coordinator steps, one receive/send chain for all three workers,
+    // and a single End shared by everyone
+    let interner = shared.interner();
+    assert_eq!(interner.len(), 6 + 2 + 1);
+    let worker = shared.get(&roles[1]).unwrap();
+    assert!(matches!(interner.node(worker), SharedNode::Receive { .. }));
+}
+
+#[test]
+fn test_check_library_merges_diagnostics_in_order() {
+    use rumpsteak_choreography::compiler::{check_library, CompileConfig, LibrarySource, Severity};
+
+    let mut sources: Vec<_> = (0..8)
+        .map(|i| {
+            LibrarySource::new(
+                format!("ok{}.choreo", i),
+                format!(
+                    "choreography Ok{} {{\n    roles: A, B\n    A -> B: Ping\n}}",
+                    i
+                ),
+            )
+        })
+        .collect();
+    sources.insert(
+        3,
+        LibrarySource::new("broken.choreo", "choreography Broken {"),
+    );
+    sources.push(LibrarySource::new(
+        "unused.choreo",
+        "choreography Unused {\n    roles: A, B, C\n    A -> B: Ping\n}",
+    ));
+
+    let report = check_library(&sources, &CompileConfig::new());
+    let origins: Vec<_> = report.entries.iter().map(|e| e.origin.as_str()).collect();
+    let expected: Vec<_> = sources.iter().map(|s| s.origin.as_str()).collect();
+    assert_eq!(origins, expected);
+
+    assert!(report.has_errors());
+    let failing: Vec<_> = report
+        .diagnostics()
+        .filter(|(_, d)| d.severity == Severity::Error)
+        .map(|(origin, d)| (origin, d.code.as_str()))
+        .collect();
+    assert_eq!(
+        failing,
+        [("broken.choreo", "P001"), ("unused.choreo", "V005")]
+    );
+    assert_eq!(report.entries[0].choreography.as_deref(), Some("Ok0"));
+    assert_eq!(report.entries[3].choreography, None);
+    assert!(report.to_string().contains("10 source(s), 2 error(s)"));
+}
+
+#[test]
+fn test_analysis_reports_starved_roles() {
+    use rumpsteak_choreography::compiler::find_starved_roles;
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let choreography = parse_choreography_str(
+        r#"
+choreography Trade {
+    roles: Client, Server, Auditor
+
+    Client -> Server: Order
+    choice Server {
+        accept: {
+            Server -> Client: Accepted
+            choice Client {
+                pay: {
+                    Client -> Server: Payment
+                    Server -> Auditor: Record
+                }
+                cancel: {
+                    Client -> Server: Cancel
+                }
+            }
+        }
+        reject: {
+            Server -> Client: Rejected
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+
+    let starved = find_starved_roles(&choreography);
+    assert_eq!(starved.len(), 1);
+    assert_eq!(starved[0].role.name, "Auditor");
+    assert_eq!(starved[0].paths.len(), 2);
+
+    let analysis = analyze(&choreography);
+    assert!(!analysis.check("liveness").unwrap().passed);
+    let finding = analysis
+        .diagnostics
+        .iter()
+        .find(|d| d.code == "A010")
+        .unwrap();
+    assert_eq!(
+        finding.message,
+        "`Auditor` takes no part if `Server` chooses `accept` and then `Client` chooses \
+         `cancel`, or if `Server` chooses `reject`"
+    );
+
+    // Telling the auditor about every outcome keeps it live
+    let told = parse_choreography_str(
+        r#"
+choreography Trade {
+    roles: Client, Server, Auditor
+
+    Client -> Server: Order
+    choice Server {
+        accept: {
+            Server -> Auditor: Accepted
+        }
+        reject: {
+            Server -> Auditor: Rejected
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    assert!(find_starved_roles(&told).is_empty());
+    assert!(analyze(&told).check("liveness").unwrap().passed);
+}
+
+#[test]
+fn test_grpc_proto_has_a_method_per_edge() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::{generate_grpc_glue, generate_grpc_proto};
+
+    let choreography = parse_choreography_str(
+        r#"
+choreography OrderFlow {
+    roles: Buyer, Seller, Shipper
+    Buyer -> Seller: Order
+    choice Seller {
+        accept: {
+            Seller -> Shipper: Ship
+            Shipper -> Buyer: Tracking
+        }
+        reject: {
+            Seller -> Buyer: Refusal
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+
+    let proto = generate_grpc_proto(&choreography);
+    assert!(proto.contains("package order_flow;"));
+    assert!(proto.contains("oneof body"));
+    assert!(proto.contains("service Seller {\n  // Order\n  rpc BuyerToSeller"));
+    assert!(proto
+        .contains("// Refusal, label accept, label reject\n  rpc SellerToBuyer(stream Envelope)"));
+    assert!(proto.contains("rpc SellerToShipper(stream Envelope) returns (stream Envelope);"));
+    assert!(proto.contains("rpc ShipperToBuyer"));
+    assert!(!proto.contains("BuyerToShipper"));
+
+    let glue = generate_grpc_glue(&choreography).to_string();
+    assert!(glue.starts_with("pub mod order_flow_grpc"));
+    assert!(glue.contains("pub struct ShipperService"));
+    assert!(glue.contains("proto :: seller_server :: Seller for SellerService"));
+    assert!(glue.contains("pub async fn connect_seller"));
+    assert!(glue.contains("buyer_client :: BuyerClient"));
+    assert!(glue.contains("seller_to_buyer"));
+}
+
+#[test]
+fn test_analysis_checks_guarded_choices() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let guard_findings = |choices: &str| {
+        let source = format!(
+            "choreography Shop {{\n    roles: Buyer, Seller\n\n    choice Buyer {{\n{}\n    }}\n}}",
+            choices
+        );
+        let analysis = analyze(&parse_choreography_str(&source).unwrap());
+        analysis
+            .diagnostics
+            .into_iter()
+            .filter(|d| d.code == "A011")
+            .map(|d| d.message)
+            .collect::<Vec<_>>()
+    };
+
+    // Complementary guards, over integers, cover every case
+    assert!(guard_findings(
+        r#"
+        low when (price < 10): { Buyer -> Seller: Haggle }
+        exact when (price == 10): { Buyer -> Seller: Buy }
+        high when (price > 10): { Buyer -> Seller: Leave }
+        "#
+    )
+    .is_empty());
+    assert!(guard_findings(
+        r#"
+        buy when (balance > price && !frozen): { Buyer -> Seller: Buy }
+        leave when (frozen || balance <= price): { Buyer -> Seller: Leave }
+        "#
+    )
+    .is_empty());
+
+    assert_eq!(
+        guard_findings(
+            r#"
+            buy when (balance >= 100): { Buyer -> Seller: Buy }
+            wait when (balance < 50): { Buyer -> Seller: Wait }
+            "#
+        ),
+        [
+            "the choice by `Buyer` fails when none of the guards of `buy`, `wait` hold; \
+          add an unguarded branch or a complementary guard"
+        ]
+    );
+    assert_eq!(
+        guard_findings(
+            r#"
+            buy when (tier == "gold" && tier == "silver"): { Buyer -> Seller: Buy }
+            bulk when (count > 10): { Buyer -> Seller: Bulk }
+            some when (count > 20): { Buyer -> Seller: Some }
+            leave: { Buyer -> Seller: Leave }
+            "#
+        ),
+        [
+            "the guard of branch `buy` in the choice by `Buyer` can never hold",
+            "branch `some` in the choice by `Buyer` is never taken, since `buy` or `bulk` \
+             is taken whenever its guard holds",
+        ]
+    );
+}
+
+#[test]
+fn test_analysis_checks_route_candidates() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+
+    let route_findings = |choreo: &Choreography| {
+        analyze(choreo)
+            .diagnostics
+            .into_iter()
+            .filter(|d| d.code == "A012")
+            .map(|d| d.message)
+            .collect::<Vec<_>>()
+    };
+
+    let mut choreo = parse_choreography_str(
+        r#"
+choreography Orders {
+    roles: Gateway, East, West, Audit
+
+    Gateway -> route(East, West): Order
+    Gateway -> Audit: Logged
+}
+"#,
+    )
+    .unwrap();
+    assert!(route_findings(&choreo).is_empty());
+
+    // A candidate the message never reaches
+    choreo.routes[0].candidates[1] = choreo.roles[3].clone();
+    assert_eq!(
+        route_findings(&choreo),
+        ["`Audit` may be routed Order by `Gateway` but never receives it"]
+    );
+}
@@ -0,0 +1,108 @@
+// Integration tests for OpenTelemetry export
+#![cfg(feature = "otel")]
+
+use opentelemetry::trace::{SpanKind, Status, TracerProvider};
+use opentelemetry::{KeyValue, Value};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+use rumpsteak_choreography::{ChoreoHandler, InMemoryHandler, Label, Otel};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Alice,
+    Bob,
+    Charlie,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Order {
+    items: Vec<u32>,
+}
+
+fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+    span.attributes
+        .iter()
+        .find(|KeyValue { key: k, .. }| k.as_str() == key)
+        .map(|kv| &kv.value)
+}
+
+#[tokio::test]
+async fn test_spans_join_one_trace_across_roles() {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+
+    let channels = Arc::new(Mutex::new(HashMap::new()));
+    let choices = Arc::new(Mutex::new(HashMap::new()));
+    let otel = |role| {
+        Otel::new(
+            InMemoryHandler::with_channels(role, channels.clone(), choices.clone()),
+            role,
+        )
+        .with_tracer(provider.tracer("test"))
+    };
+    let mut alice = otel(Role::Alice);
+    let mut bob = otel(Role::Bob);
+    let mut charlie = otel(Role::Charlie);
+
+    let order = Order {
+        items: vec![1, 2, 3],
+    };
+    alice.send(&mut (), Role::Bob, &order).await.unwrap();
+    let received: Order = bob.recv(&mut (), Role::Alice).await.unwrap();
+    assert_eq!(received, order);
+    bob.send(&mut (), Role::Charlie, &received).await.unwrap();
+    let _: Order = charlie.recv(&mut (), Role::Bob).await.unwrap();
+    charlie
+        .choose(&mut (), Role::Alice, Label("ship"))
+        .await
+        .unwrap();
+    // The in-memory handler does not deliver labels, so the offer fails
+    assert!(alice.offer(&mut (), Role::Charlie).await.is_err());
+
+    let spans = exporter.get_finished_spans().unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_ref()).collect();
+    assert_eq!(names, ["send", "recv", "send", "recv", "choose", "offer"]);
+
+    // Each receive is a child of the send it got its message from, and the
+    // relay's send continues the trace it received
+    let trace = spans[0].span_context.trace_id();
+    for span in &spans[..4] {
+        assert_eq!(span.span_context.trace_id(), trace);
+    }
+    assert_eq!(spans[1].parent_span_id, spans[0].span_context.span_id());
+    assert!(spans[1].parent_span_is_remote);
+    assert_eq!(spans[2].parent_span_id, spans[0].span_context.span_id());
+    assert_eq!(spans[3].parent_span_id, spans[2].span_context.span_id());
+
+    let send = &spans[0];
+    assert_eq!(send.span_kind, SpanKind::Producer);
+    assert_eq!(
+        attribute(send, "rumpsteak.role"),
+        Some(&Value::from("Alice"))
+    );
+    assert_eq!(attribute(send, "rumpsteak.peer"), Some(&Value::from("Bob")));
+    assert_eq!(
+        attribute(send, "rumpsteak.message.type"),
+        Some(&Value::from("Order"))
+    );
+    let size = bincode::serialize(&order).unwrap().len() as i64;
+    assert_eq!(
+        attribute(send, "rumpsteak.message.size"),
+        Some(&Value::from(size))
+    );
+    assert_eq!(spans[1].span_kind, SpanKind::Consumer);
+    assert_eq!(
+        attribute(&spans[1], "rumpsteak.message.size"),
+        Some(&Value::from(size))
+    );
+    assert_eq!(
+        attribute(&spans[4], "rumpsteak.label"),
+        Some(&Value::from("ship"))
+    );
+    assert_eq!(spans[4].status, Status::Unset);
+    assert!(matches!(spans[5].status, Status::Error { .. }));
+}
@@ -1,6 +1,9 @@
 // Comprehensive tests for the choreographic DSL parser
 
-use rumpsteak_choreography::compiler::parser::{parse_choreography_str, ParseError};
+use rumpsteak_choreography::compiler::parser::{
+    parse_choreography_file, parse_choreography_str, parse_choreography_str_with_config, ParseError,
+};
+use rumpsteak_choreography::compiler::CompileConfig;
 
 #[test]
 fn test_parse_simple_protocol() {
@@ -78,6 +81,34 @@ choreography Broadcast {
     }
 }
 
+#[test]
+fn test_parse_broadcast_with_exclusions() {
+    use rumpsteak_choreography::ast::{LocalType, Protocol, Role};
+    use rumpsteak_choreography::compiler::projection::project;
+
+    let input = r#"
+choreography Broadcast {
+    roles: Leader, Worker1, Worker2, Observer
+
+    Leader ->* \ {Observer}: Start
+}
+"#;
+
+    let choreo = parse_choreography_str(input).unwrap();
+    let Protocol::Broadcast { to_all, .. } = &choreo.protocol else {
+        panic!("Expected Protocol::Broadcast, got {:?}", choreo.protocol);
+    };
+    let recipient_names: Vec<String> = to_all.iter().map(|r| r.name.to_string()).collect();
+    assert_eq!(recipient_names, ["Worker1", "Worker2"]);
+
+    let observer = Role::new(quote::format_ident!("Observer"));
+    assert_eq!(project(&choreo, &observer).unwrap(), LocalType::End);
+
+    let err =
+        parse_choreography_str(&input.replace("{Observer}", "{Observer, Auditor}")).unwrap_err();
+    assert!(matches!(err, ParseError::UndefinedRole { ref role, .. } if role == "Auditor"));
+}
+
 #[test]
 fn test_parse_choice_two_branches() {
     let input = r#"
@@ -330,6 +361,41 @@ choreography Invalid {
     assert!(err_str.contains("-->")); // Rust-style error format
 }
 
+#[test]
+fn test_undefined_role_fixits() {
+    let input = r#"
+choreography Typo {
+    roles: Alice, Bob
+
+    Alice -> Bbo: Hello
+    Alice -> Carol: Hello
+}
+"#;
+
+    let diagnostic = parse_choreography_str(input).unwrap_err().to_diagnostic();
+    assert_eq!(diagnostic.code, "P003");
+    assert_eq!(diagnostic.span.as_ref().unwrap().line, 5);
+    assert_eq!(diagnostic.fixits.len(), 2);
+    assert_eq!(diagnostic.fixits[0].message, "did you mean `Bob`?");
+
+    let fixed = diagnostic.fixits[0].apply(input).unwrap();
+    assert!(fixed.contains("Alice -> Bob: Hello"));
+
+    let declared = diagnostic.fixits[1].apply(input).unwrap();
+    assert!(
+        declared.contains("roles: Alice, Bob, Bbo\n"),
+        "{}",
+        declared
+    );
+
+    // Applying the suggestion leaves the next undefined role, which has no
+    // close match, so only the declaration fix is offered
+    let diagnostic = parse_choreography_str(&fixed).unwrap_err().to_diagnostic();
+    assert_eq!(diagnostic.fixits.len(), 1);
+    let fixed = diagnostic.fixits[0].apply(&fixed).unwrap();
+    assert!(parse_choreography_str(&fixed).is_ok());
+}
+
 #[test]
 fn test_error_undefined_role_in_choice() {
     let input = r#"
@@ -799,6 +865,99 @@ choreography UndefinedCall {
     assert!(err_msg.contains("NonExistent"));
 }
 
+#[test]
+fn test_call_binds_the_roles_of_a_generic_protocol() {
+    use rumpsteak_choreography::ast::Protocol;
+
+    let input = r#"
+choreography Relay {
+    roles: Alice, Bob, Carol
+
+    protocol Handshake(Client, Server) {
+        Client -> Server: Hello
+        Server -> Client: Welcome
+    }
+
+    call Handshake(Client = Alice, Server = Bob)
+    call Handshake(Client = Bob, Server = Carol)
+}
+"#;
+
+    let choreo = parse_choreography_str(input).unwrap();
+    choreo.validate().unwrap();
+    let mut sends = Vec::new();
+    let mut protocol = &choreo.protocol;
+    while let Protocol::Send {
+        from,
+        to,
+        continuation,
+        ..
+    } = protocol
+    {
+        sends.push(format!("{}->{}", from.name, to.name));
+        protocol = continuation;
+    }
+    assert_eq!(
+        sends,
+        ["Alice->Bob", "Bob->Alice", "Bob->Carol", "Carol->Bob"]
+    );
+
+    // Spawning a generic protocol binds its roles in order
+    let spawned = parse_choreography_str(
+        r#"
+choreography Spawned {
+    roles: Alice, Bob
+    protocol Handshake(Client, Server) {
+        Client -> Server: Hello
+    }
+    spawn Handshake(Alice, Bob) as greeting
+    await greeting
+    Alice -> Bob: Done
+}
+"#,
+    );
+    assert!(spawned.is_ok(), "{:?}", spawned.err());
+}
+
+#[test]
+fn test_call_role_binding_errors() {
+    let parse = |call: &str| {
+        parse_choreography_str(&format!(
+            "choreography Relay {{\n    roles: Alice, Bob\n    protocol Handshake(Client, Server) {{\n        Client -> Server: Hello\n    }}\n    {}\n}}",
+            call
+        ))
+        .unwrap_err()
+        .to_string()
+    };
+
+    for (call, expected) in [
+        (
+            "call Handshake(Client = Alice)",
+            "call to Handshake does not bind Server",
+        ),
+        (
+            "call Handshake(Client = Alice, Server = Alice)",
+            "roles Client and Server of Handshake are both bound to Alice",
+        ),
+        (
+            "call Handshake(Client = Alice, Peer = Bob)",
+            "Handshake has no role Peer, its roles are Client, Server",
+        ),
+        (
+            "call Handshake(Client = Alice, Client = Bob)",
+            "role Client of Handshake is bound more than once",
+        ),
+        ("call Handshake(Client = Alice, Server = Dave)", "Dave"),
+        (
+            "spawn Handshake(Alice) as greeting",
+            "Handshake takes 2 roles",
+        ),
+    ] {
+        let err = parse(call);
+        assert!(err.contains(expected), "{}: {}", call, err);
+    }
+}
+
 #[test]
 fn test_error_duplicate_protocol_def() {
     let input = r#"
@@ -855,6 +1014,19 @@ choreography GuardExample {
     );
 }
 
+#[test]
+fn test_guards_outside_the_guard_language_are_rejected() {
+    let err = parse_choreography_str(
+        "choreography G { roles: A, B choice A { go when (order.items > 0): { A -> B: Go } } }",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("Invalid guard expression"),
+        "{}",
+        err
+    );
+}
+
 #[test]
 fn test_parse_choice_with_multiple_guards() {
     let input = r#"
@@ -1068,6 +1240,68 @@ choreography Verified {
     assert!(value.contains("liveness"));
 }
 
+#[test]
+fn test_parse_wire_annotation() {
+    use rumpsteak_choreography::ast::WireFormat;
+
+    let choreo = parse_choreography_str(
+        "@wire(protobuf) choreography Wired { roles: A, B A -> B: Order(proto::Order) }",
+    )
+    .unwrap();
+    assert_eq!(choreo.wire_format(), WireFormat::Protobuf);
+
+    let plain = parse_choreography_str("choreography Plain { roles: A, B A -> B: Msg }").unwrap();
+    assert_eq!(plain.wire_format(), WireFormat::Bincode);
+
+    let err = parse_choreography_str("@wire(xml) choreography Bad { roles: A, B A -> B: Msg }")
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("unknown wire format 'xml', expected bincode or protobuf"));
+}
+
+#[test]
+fn test_parse_max_size_annotation() {
+    let parse = |size: &str| {
+        parse_choreography_str(&format!(
+            "@max_size({}) choreography Sized {{ roles: A, B A -> B: Msg }}",
+            size
+        ))
+    };
+    assert_eq!(parse("1MB").unwrap().max_message_size(), Some(1 << 20));
+    assert_eq!(parse("64KB").unwrap().max_message_size(), Some(64 << 10));
+    assert_eq!(parse("4096").unwrap().max_message_size(), Some(4096));
+
+    let plain = parse_choreography_str("choreography Plain { roles: A, B A -> B: Msg }").unwrap();
+    assert_eq!(plain.max_message_size(), None);
+
+    let err = parse("lots").unwrap_err();
+    assert!(
+        err.to_string().contains("invalid message size 'lots'"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_parse_unknown_labels_annotation() {
+    let choice = "choice B { Ok: { B -> A: Done } Other: { B -> A: Later } }";
+    let parse = |policy: &str| {
+        parse_choreography_str(&format!(
+            "@unknown_labels({}) choreography Offer {{ roles: A, B {} }}",
+            policy, choice
+        ))
+    };
+
+    assert_eq!(parse("skip").unwrap().unknown_labels(), Some("skip"));
+    assert_eq!(parse("Other").unwrap().unknown_labels(), Some("Other"));
+
+    let err = parse("Missing").unwrap_err();
+    assert!(err.to_string().contains(
+        "unknown label policy 'Missing', expected reject, skip, or the label of a branch"
+    ));
+}
+
 // ============================================================================
 // Type Annotation Tests
 // ============================================================================
@@ -1279,3 +1513,961 @@ choreography ParameterizedLoop {
         result.err()
     );
 }
+
+#[test]
+fn test_parse_finally_block() {
+    let input = r#"
+choreography Lease {
+    roles: Client, Server
+
+    Client -> Server: Acquire
+    Server -> Client: Granted
+
+    finally {
+        Client -> Server: Release
+    }
+}
+"#;
+
+    let result = parse_choreography_str(input);
+    assert!(
+        result.is_ok(),
+        "Failed to parse finally block: {:?}",
+        result.err()
+    );
+
+    let choreo = result.unwrap();
+    match &choreo.protocol {
+        rumpsteak_choreography::ast::Protocol::Finally { body, cleanup } => {
+            assert!(matches!(
+                **body,
+                rumpsteak_choreography::ast::Protocol::Send { .. }
+            ));
+            assert!(matches!(
+                **cleanup,
+                rumpsteak_choreography::ast::Protocol::Send { .. }
+            ));
+        }
+        other => panic!("Expected Finally, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_aliases() {
+    use rumpsteak_choreography::ast::Protocol;
+
+    let input = r#"
+choreography Shop {
+    roles: Customer, Warehouse
+    alias Cust = Customer
+    alias Ack = Acknowledge
+
+    Cust -> Warehouse: Order
+    Warehouse -> Customer: Ack
+}
+"#;
+
+    let choreo = parse_choreography_str(input).expect("aliases should parse");
+    assert_eq!(choreo.roles.len(), 2);
+
+    let Protocol::Send {
+        from, continuation, ..
+    } = &choreo.protocol
+    else {
+        panic!("expected send, got {:?}", choreo.protocol);
+    };
+    assert_eq!(from.name.to_string(), "Customer");
+
+    let Protocol::Send { to, message, .. } = continuation.as_ref() else {
+        panic!("expected send, got {:?}", continuation);
+    };
+    assert_eq!(to.name.to_string(), "Customer");
+    assert_eq!(message.name.to_string(), "Acknowledge");
+    assert!(choreo.validate().is_ok());
+}
+
+#[test]
+fn test_alias_clashing_with_role() {
+    let input = r#"
+choreography Clash {
+    roles: Customer, Warehouse
+    alias Warehouse = Customer
+
+    Customer -> Warehouse: Order
+}
+"#;
+
+    let err = parse_choreography_str(input).unwrap_err();
+    assert!(matches!(err, ParseError::DuplicateRole { ref role, .. } if role == "Warehouse"));
+}
+
+#[test]
+fn test_cfg_blocks() {
+    let input = r#"
+choreography Audited {
+    roles: Client, Server, Auditor
+
+    Client -> Server: Request
+    #[cfg(feature = "audit")] {
+        Server -> Auditor: Report
+    }
+    #[cfg(all(not(feature = "audit"), target = "edge"))] {
+        Server -> Client: Cached
+    }
+    Server -> Client: Response
+}
+"#;
+
+    let count_sends = |protocol: &rumpsteak_choreography::Protocol| {
+        let mut count = 0;
+        let mut current = protocol;
+        while let rumpsteak_choreography::Protocol::Send { continuation, .. } = current {
+            count += 1;
+            current = continuation;
+        }
+        count
+    };
+
+    let plain = parse_choreography_str(input).unwrap();
+    assert_eq!(count_sends(&plain.protocol), 2);
+
+    let audited =
+        parse_choreography_str_with_config(input, &CompileConfig::new().with_feature("audit"))
+            .unwrap();
+    assert_eq!(count_sends(&audited.protocol), 3);
+
+    let edge = parse_choreography_str_with_config(
+        input,
+        &CompileConfig::new().with_value("target", "edge"),
+    )
+    .unwrap();
+    assert_eq!(count_sends(&edge.protocol), 3);
+}
+
+#[test]
+fn test_cfg_block_checks_disabled_body() {
+    let input = r#"
+choreography Audited {
+    roles: Client, Server
+
+    Client -> Server: Request
+    #[cfg(feature = "audit")] {
+        Server -> Auditor: Report
+    }
+}
+"#;
+
+    let err = parse_choreography_str(input).unwrap_err();
+    assert!(matches!(err, ParseError::UndefinedRole { ref role, .. } if role == "Auditor"));
+}
+
+#[test]
+fn test_const_declarations() {
+    let input = r#"
+choreography Fanout {
+    const N: usize = 3
+    const ROUNDS: usize
+    roles: Master, Worker[N]
+
+    loop (count: ROUNDS) {
+        Master -> Worker: Task
+    }
+}
+"#;
+
+    let err = parse_choreography_str(input).unwrap_err();
+    assert!(matches!(err, ParseError::UnresolvedConst { ref name, .. } if name == "ROUNDS"));
+    assert_eq!(err.code(), "P010");
+
+    let config = CompileConfig::new()
+        .with_const("ROUNDS", 5)
+        .with_const("N", 8);
+    let choreo = parse_choreography_str_with_config(input, &config).unwrap();
+
+    let worker = &choreo.roles[1];
+    assert_eq!(worker.array_size.as_ref().unwrap().to_string(), "8");
+    assert!(matches!(
+        choreo.protocol,
+        rumpsteak_choreography::Protocol::Loop {
+            condition: Some(rumpsteak_choreography::ast::Condition::Count(5)),
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_foreach_expands_concrete_ranges() {
+    use rumpsteak_choreography::compiler::{analyze, projection::project};
+    use rumpsteak_choreography::Protocol;
+
+    let input = r#"
+choreography Scatter {
+    const N: usize = 3
+    roles: Master, Worker[N]
+
+    foreach i in 0..N {
+        Master -> Worker[i]: Task
+    }
+    foreach i in 1..N {
+        Worker[i] -> Master: Done
+    }
+}
+"#;
+
+    let choreo = parse_choreography_str(input).unwrap();
+    let mut pairs = Vec::new();
+    let mut current = &choreo.protocol;
+    while let Protocol::Send {
+        from,
+        to,
+        continuation,
+        ..
+    } = current
+    {
+        pairs.push((from.name.to_string(), to.name.to_string()));
+        current = continuation;
+    }
+    assert_eq!(
+        pairs,
+        [
+            ("Master", "Worker0"),
+            ("Master", "Worker1"),
+            ("Master", "Worker2"),
+            ("Worker1", "Master"),
+            ("Worker2", "Master"),
+        ]
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+    );
+
+    // The family is declared member by member, as by `expand_roles`
+    let roles: Vec<_> = choreo.roles.iter().map(|r| r.name.to_string()).collect();
+    assert_eq!(roles, ["Master", "Worker0", "Worker1", "Worker2"]);
+    choreo.validate().unwrap();
+    for role in &choreo.roles {
+        project(&choreo, role).unwrap();
+    }
+    let report = analyze(&choreo);
+    assert!(report.is_ok(), "{:?}", report.diagnostics);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+}
+
+#[test]
+fn test_foreach_stays_symbolic_for_parametric_ranges() {
+    use rumpsteak_choreography::ast::{Condition, LocalType};
+    use rumpsteak_choreography::compiler::projection::{project, project_family};
+    use rumpsteak_choreography::compiler::{analyze, expand_roles};
+    use rumpsteak_choreography::Protocol;
+    use std::collections::HashMap;
+
+    let input = r#"
+choreography Scatter {
+    roles: Master, Worker[N]
+
+    foreach i in 0..N {
+        Master -> Worker[i]: Task
+    }
+}
+"#;
+
+    let choreo = parse_choreography_str(input).unwrap();
+    let Protocol::Loop {
+        condition: Some(Condition::Custom(count)),
+        body,
+    } = &choreo.protocol
+    else {
+        panic!("expected a counted loop, got {:?}", choreo.protocol);
+    };
+    assert_eq!(count.to_string(), "N");
+    let Protocol::Send { to, .. } = body.as_ref() else {
+        panic!("expected a send");
+    };
+    assert_eq!(to.name.to_string(), "Worker_i");
+
+    // Projection needs the family expanded, or one member projected for all
+    let expanded = expand_roles(&choreo, &HashMap::from([("N".to_string(), 2)])).unwrap();
+    for role in &expanded.roles {
+        project(&expanded, role).unwrap();
+    }
+    assert!(analyze(&expanded).is_ok());
+    let worker = project_family(&choreo, &choreo.roles[1]).unwrap();
+    assert!(
+        matches!(worker.local_type, LocalType::Receive { .. }),
+        "{:?}",
+        worker.local_type
+    );
+}
+
+#[test]
+fn test_branch_weights_drive_simulation() {
+    use rumpsteak_choreography::compiler::{simulate, LatencyModel, SimulationConfig};
+    use std::time::Duration;
+
+    let input = r#"
+choreography Lookup {
+    roles: Client, Server, Db
+
+    Client -> Server: Request
+    choice Server {
+        @weight(0.9)
+        hit: {
+            Server -> Client: Cached
+        }
+        miss: {
+            Server -> Db: Query
+            Db -> Server: Rows
+            Server -> Client: Fresh
+        }
+    }
+}
+"#;
+    let choreo = parse_choreography_str(input).unwrap();
+    let rumpsteak_choreography::Protocol::Send { continuation, .. } = &choreo.protocol else {
+        panic!("expected a send");
+    };
+    let rumpsteak_choreography::Protocol::Choice { branches, .. } = continuation.as_ref() else {
+        panic!("expected a choice");
+    };
+    assert_eq!(branches[0].weight, Some(0.9));
+    assert_eq!(branches[1].weight, None);
+
+    let config = SimulationConfig::new()
+        .with_runs(10_000)
+        .with_seed(7)
+        .with_latency(LatencyModel::constant(Duration::from_millis(10)));
+    let report = simulate(&choreo, &config);
+
+    // The unweighted miss branch takes the remaining 0.1
+    assert!((report.branches["Server.hit"] - 0.9).abs() < 0.02);
+    assert!((report.mean_messages - 2.2).abs() < 0.05);
+    assert_eq!(report.max_messages, 4);
+    assert!((report.edges[&("Server".to_string(), "Db".to_string())] - 0.1).abs() < 0.02);
+    assert!((report.mean_round_trips - 1.1).abs() < 0.05);
+    assert_eq!(report.latency.p50, Duration::from_millis(20));
+    assert_eq!(report.latency.p99, Duration::from_millis(40));
+    assert_eq!(report.latency.max, Duration::from_millis(40));
+
+    // Runs are seeded
+    assert_eq!(simulate(&choreo, &config), report);
+
+    let invalid = input.replace("@weight(0.9)", "@weight(fast)");
+    let err = parse_choreography_str(&invalid).unwrap_err();
+    assert!(err.to_string().contains("Invalid branch weight"));
+}
+
+#[test]
+fn test_abort_broadcasts_to_remaining_roles() {
+    use rumpsteak_choreography::ast::Protocol;
+
+    let input = r#"
+choreography Payment {
+    roles: Client, Bank, Shop, Auditor
+
+    Client -> Bank: Pay
+    choice Bank {
+        ok: {
+            Bank -> Shop: Ship
+        }
+        declined: {
+            Bank -> Auditor: Declined
+            abort at Bank: Cancelled
+        }
+    }
+}
+"#;
+    let choreo = parse_choreography_str(input).unwrap();
+    let Protocol::Send { continuation, .. } = &choreo.protocol else {
+        panic!("expected a send");
+    };
+    let Protocol::Choice { branches, .. } = continuation.as_ref() else {
+        panic!("expected a choice");
+    };
+    let Protocol::Send { continuation, .. } = &branches[1].protocol else {
+        panic!("expected the Declined message");
+    };
+    let Protocol::Broadcast { from, to_all, .. } = continuation.as_ref() else {
+        panic!("expected the abort broadcast, got {:?}", continuation);
+    };
+    assert_eq!(from.name, "Bank");
+    // Only Shop has anything left to do; Client and Auditor are done
+    let recipients: Vec<String> = to_all.iter().map(|r| r.name.to_string()).collect();
+    assert_eq!(recipients, ["Shop"]);
+}
+
+#[test]
+fn test_abort_placement_errors() {
+    let cases = [
+        ("abort at A: Stop", "must be inside a choice"),
+        (
+            "choice A { go: { A -> B: Go } stop: { abort at A: Stop\n A -> B: Late } }",
+            "Statements after an abort",
+        ),
+        (
+            "loop (count: 2) { choice A { go: { A -> B: Go } stop: { abort at A: Stop } } }",
+            "cannot leave a loop",
+        ),
+    ];
+    for (body, expected) in cases {
+        let input = format!("choreography Abort {{\n    roles: A, B\n    {}\n}}", body);
+        let err = parse_choreography_str(&input).unwrap_err();
+        assert!(err.to_string().contains(expected), "{}: {}", body, err);
+    }
+}
+
+#[test]
+fn test_spawn_starts_a_child_session() {
+    use rumpsteak_choreography::ast::Protocol;
+    use rumpsteak_choreography::compiler::projection::project;
+
+    let input = r#"
+choreography Checkout {
+    roles: Buyer, Shop, Auditor
+
+    protocol Audit {
+        Shop -> Auditor: Record
+        Auditor -> Shop: Logged
+    }
+
+    Buyer -> Shop: Order
+    spawn Audit(Shop, Auditor) as audit
+    Shop -> Buyer: Receipt
+    await audit
+}
+"#;
+    let choreo = parse_choreography_str(input).unwrap();
+    choreo.validate().unwrap();
+
+    let Protocol::Send { continuation, .. } = &choreo.protocol else {
+        panic!("expected the order");
+    };
+    let Protocol::Spawn {
+        handle,
+        roles,
+        body,
+        continuation,
+        ..
+    } = continuation.as_ref()
+    else {
+        panic!("expected the spawn, got {:?}", continuation);
+    };
+    assert_eq!(handle, "audit");
+    assert_eq!(roles.len(), 2);
+    assert!(matches!(body.as_ref(), Protocol::Send { .. }));
+    let Protocol::Send { continuation, .. } = continuation.as_ref() else {
+        panic!("expected the receipt");
+    };
+    let Protocol::Await { roles, .. } = continuation.as_ref() else {
+        panic!("expected the await, got {:?}", continuation);
+    };
+    assert_eq!(roles.len(), 2);
+
+    // The child is projected on its own; the parent only sees the receipt
+    let children = choreo.children();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].1.name, "Audit");
+    let auditor = &choreo.roles[2];
+    let local = project(&choreo, auditor).unwrap();
+    assert_eq!(local, rumpsteak_choreography::ast::LocalType::End);
+    let local = project(&children[0].1, auditor).unwrap();
+    assert!(matches!(
+        local,
+        rumpsteak_choreography::ast::LocalType::Receive { .. }
+    ));
+}
+
+#[test]
+fn test_spawn_and_await_errors() {
+    let audit = "protocol Audit { Shop -> Auditor: Record }";
+    let cases = [
+        ("await audit", "has no spawn before it"),
+        (
+            "spawn Audit(Shop, Auditor) as audit\n spawn Audit(Shop, Auditor) as audit",
+            "spawned again before it is awaited",
+        ),
+        (
+            "spawn Audit(Shop, Auditor) as audit\n loop (count: 2) { await audit }",
+            "has no spawn before it",
+        ),
+        (
+            "spawn Audit(Shop) as audit",
+            "uses role Auditor, which is not passed to spawn",
+        ),
+        (
+            "spawn Missing(Shop) as audit",
+            "Undefined protocol 'Missing'",
+        ),
+    ];
+    for (body, expected) in cases {
+        let input = format!(
+            "choreography Checkout {{\n    roles: Shop, Auditor\n    {}\n    {}\n}}",
+            audit, body
+        );
+        let err = parse_choreography_str(&input).unwrap_err();
+        assert!(err.to_string().contains(expected), "{}: {}", body, err);
+    }
+}
+
+#[test]
+fn test_returns_declarations() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Purchase {
+    roles: Buyer, Seller
+    alias Offer = Quote
+    returns Offer at Buyer
+    returns Order at Seller
+
+    Buyer -> Seller: Request
+    Seller -> Buyer: Quote(u64)
+    Buyer -> Seller: Order
+}
+"#,
+    )
+    .unwrap();
+    let returns: Vec<_> = choreo
+        .returns
+        .iter()
+        .map(|r| (r.role.name.to_string(), r.message.to_string()))
+        .collect();
+    assert_eq!(
+        returns,
+        [
+            ("Buyer".to_string(), "Quote".to_string()),
+            ("Seller".to_string(), "Order".to_string())
+        ]
+    );
+    assert_eq!(
+        choreo.returned_by(&choreo.roles[0]).unwrap().to_string(),
+        "Quote"
+    );
+
+    let cases = [
+        (
+            "returns Quote at Seller",
+            "role 'Seller' never receives Quote",
+        ),
+        ("returns Quote at Broker", "Undefined role 'Broker'"),
+        (
+            "returns Quote at Buyer\n returns Request at Buyer",
+            "role 'Buyer' already returns Quote",
+        ),
+    ];
+    for (decl, expected) in cases {
+        let input = format!(
+            "choreography Purchase {{\n    roles: Buyer, Seller\n    {}\n    Buyer -> Seller: Request\n    Seller -> Buyer: Quote\n}}",
+            decl
+        );
+        let err = parse_choreography_str(&input).unwrap_err();
+        assert!(err.to_string().contains(expected), "{}: {}", decl, err);
+    }
+}
+
+#[test]
+fn test_import_calls_protocols_of_another_file() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("commit.choreo"),
+        r#"
+choreography Commit {
+    roles: Coordinator, Participant
+
+    protocol Vote {
+        Coordinator -> Participant: Prepare
+        Participant -> Coordinator: Ready
+    }
+}
+"#,
+    )
+    .unwrap();
+    let main = dir.path().join("bank.choreo");
+    std::fs::write(
+        &main,
+        r#"
+import "commit.choreo" as Commit
+
+choreography Bank {
+    roles: Leader, Participant
+
+    call Commit.Vote with { Coordinator = Leader }
+    Leader -> Participant: Commit
+}
+"#,
+    )
+    .unwrap();
+
+    let choreo = parse_choreography_file(&main).unwrap();
+    choreo.validate().unwrap();
+    let first = match &choreo.protocol {
+        rumpsteak_choreography::ast::Protocol::Send { from, message, .. } => {
+            (from.name.to_string(), message.name.to_string())
+        }
+        other => panic!("Expected Send, got: {:?}", other),
+    };
+    assert_eq!(first, ("Leader".to_string(), "Prepare".to_string()));
+}
+
+#[test]
+fn test_import_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |name: &str, source: &str| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, source).unwrap();
+        path
+    };
+
+    // Files that import each other are rejected rather than loaded forever
+    let a = write(
+        "a.choreo",
+        "import \"b.choreo\" as B\nchoreography A { roles: X, Y\n X -> Y: Ping }",
+    );
+    write(
+        "b.choreo",
+        "import \"a.choreo\" as A\nchoreography B { roles: X, Y\n X -> Y: Pong }",
+    );
+    let err = parse_choreography_file(&a).unwrap_err();
+    assert!(matches!(err, ParseError::ImportCycle { .. }), "{}", err);
+    assert_eq!(err.code(), "P012");
+
+    let missing = write(
+        "missing.choreo",
+        "import \"nowhere.choreo\" as N\nchoreography M { roles: X, Y\n X -> Y: Ping }",
+    );
+    let err = parse_choreography_file(&missing).unwrap_err();
+    assert!(matches!(err, ParseError::Import { ref path, .. } if path == "nowhere.choreo"));
+
+    // Only protocols the imported file defines can be called
+    write(
+        "empty.choreo",
+        "choreography Empty { roles: X, Y\n X -> Y: Ping }",
+    );
+    let unknown = write(
+        "unknown.choreo",
+        "import \"empty.choreo\" as E\nchoreography U { roles: X, Y\n call E.Vote }",
+    );
+    let err = parse_choreography_file(&unknown).unwrap_err();
+    assert!(
+        matches!(err, ParseError::UndefinedProtocol { ref protocol, .. } if protocol == "E.Vote")
+    );
+}
+
+#[test]
+fn test_protocol_size_limits() {
+    use rumpsteak_choreography::ast::{ProtocolLimits, ValidationError};
+
+    // A choice wider than the limit
+    let branches: String = (0..300)
+        .map(|i| format!("        L{i}: {{ A -> B: M{i} }}\n"))
+        .collect();
+    let wide =
+        format!("choreography Wide {{\n    roles: A, B\n    choice A {{\n{branches}    }}\n}}");
+    let choreo = parse_choreography_str(&wide).unwrap();
+    let err = choreo.validate().unwrap_err();
+    assert!(matches!(
+        err,
+        ValidationError::TooManyBranches { ref choice, count: 300, limit: 256 } if choice == "Choice at A"
+    ));
+    assert_eq!(err.code(), "V010");
+    assert_eq!(
+        err.to_string(),
+        "Choice at A has 300 branches, more than the limit of 256"
+    );
+    let limits = ProtocolLimits::default().with_max_branches(300);
+    assert!(choreo.validate_with_limits(&limits).is_ok());
+
+    // Loops nested deeper than the limit
+    let nested = |depth: usize| {
+        format!(
+            "choreography Deep {{\n    roles: A, B\n{}A -> B: Ping\n{}}}",
+            "loop { ".repeat(depth),
+            "} ".repeat(depth)
+        )
+    };
+    let choreo = parse_choreography_str(&nested(64)).unwrap();
+    assert!(choreo.validate().is_ok());
+    let choreo = parse_choreography_str(&nested(70)).unwrap();
+    let err = choreo.validate().unwrap_err();
+    assert!(matches!(
+        err,
+        ValidationError::TooDeep {
+            depth: 70,
+            limit: 64
+        }
+    ));
+    assert_eq!(err.code(), "V011");
+
+    // Nesting that would overflow the parser's stack fails before parsing
+    let err = parse_choreography_str(&nested(100_000)).unwrap_err();
+    assert!(
+        matches!(err, ParseError::Syntax { ref message, .. } if message.contains("nesting limit of 64")),
+        "{}",
+        err
+    );
+    let config = CompileConfig::new().with_limits(ProtocolLimits::default().with_max_depth(2));
+    assert!(parse_choreography_str_with_config(&nested(10), &config).is_err());
+
+    // Each instance of a role array counts
+    let crowd = "choreography Crowd {\n    roles: A, W[300]\n    A -> W[0]: Ping\n}";
+    let err = parse_choreography_str(crowd)
+        .unwrap()
+        .validate()
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ValidationError::TooManyRoles {
+            count: 301,
+            limit: 256
+        }
+    ));
+    assert_eq!(err.code(), "V009");
+}
+
+#[test]
+fn test_topics_publish_to_current_subscribers() {
+    use rumpsteak_choreography::ast::{LocalType, Protocol};
+    use rumpsteak_choreography::compiler::projection::project;
+
+    let input = r#"
+choreography Feed {
+    roles: Exchange, Trader, Auditor, Clerk
+    topic prices: Auditor
+
+    Exchange -> topic(prices): Update
+    subscribe Trader to prices
+    Exchange -> topic(prices): Update
+    unsubscribe Auditor from prices
+    Exchange -> Clerk: Close
+}
+"#;
+    let choreo = parse_choreography_str(input).unwrap();
+    choreo.validate().unwrap();
+
+    let Protocol::Broadcast {
+        from,
+        to_all,
+        message,
+        continuation,
+        ..
+    } = &choreo.protocol
+    else {
+        panic!("expected a broadcast, got {:?}", choreo.protocol);
+    };
+    assert_eq!(from.name, "Exchange");
+    assert_eq!(message.name, "Update");
+    let recipients: Vec<String> = to_all.iter().map(|r| r.name.to_string()).collect();
+    assert_eq!(recipients, ["Auditor"]);
+
+    // Subscribing tells the publishers
+    let Protocol::Send {
+        from,
+        to,
+        message,
+        continuation,
+    } = continuation.as_ref()
+    else {
+        panic!("expected the subscription, got {:?}", continuation);
+    };
+    assert_eq!(
+        (from.name.to_string(), to.name.to_string()),
+        ("Trader".to_string(), "Exchange".to_string())
+    );
+    assert_eq!(message.name, "SubscribePrices");
+
+    let Protocol::Broadcast {
+        to_all,
+        continuation,
+        ..
+    } = continuation.as_ref()
+    else {
+        panic!("expected a broadcast, got {:?}", continuation);
+    };
+    let recipients: Vec<String> = to_all.iter().map(|r| r.name.to_string()).collect();
+    assert_eq!(recipients, ["Trader", "Auditor"]);
+    assert!(matches!(
+        continuation.as_ref(),
+        Protocol::Send { message, .. } if message.name == "UnsubscribePrices"
+    ));
+
+    // The trader receives only the update published after it subscribed
+    let trader = project(&choreo, &choreo.roles[1]).unwrap();
+    let LocalType::Send {
+        to, continuation, ..
+    } = trader
+    else {
+        panic!("expected the trader to subscribe, got {:?}", trader);
+    };
+    assert_eq!(to.name, "Exchange");
+    assert!(matches!(
+        *continuation,
+        LocalType::Receive { ref message, ref continuation, .. }
+            if message.name == "Update" && matches!(**continuation, LocalType::End)
+    ));
+
+    // Roles that never subscribe receive no updates
+    let clerk = project(&choreo, &choreo.roles[3]).unwrap();
+    assert!(
+        matches!(clerk, LocalType::Receive { ref message, .. } if message.name == "Close"),
+        "got {:?}",
+        clerk
+    );
+}
+
+#[test]
+fn test_topic_errors() {
+    let cases = [
+        ("A -> topic(news): Item", "undeclared topic 'news'"),
+        ("subscribe B to prices", "already subscribed"),
+        ("unsubscribe A from prices", "not subscribed"),
+        (
+            "loop (count: 2) { subscribe A to prices }",
+            "cannot change inside a loop",
+        ),
+        (
+            "choice A { on: { subscribe A to prices } off: { A -> B: Off } }\nA -> topic(prices): Tick",
+            "different subscribers to 'prices'",
+        ),
+    ];
+    for (body, expected) in cases {
+        let input = format!(
+            "choreography Topics {{\n    roles: A, B\n    topic prices: B\n    {}\n}}",
+            body
+        );
+        let err = parse_choreography_str(&input).unwrap_err();
+        assert!(err.to_string().contains(expected), "{}: {}", body, err);
+    }
+
+    let err = parse_choreography_str(
+        "choreography Topics {\n    roles: A, B\n    topic prices: C\n    A -> B: Ping\n}",
+    )
+    .unwrap_err();
+    assert!(matches!(err, ParseError::UndefinedRole { ref role, .. } if role == "C"));
+    let err = parse_choreography_str(
+        "choreography Topics {\n    roles: A, B\n    topic prices\n    topic prices\n    A -> B: Ping\n}",
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("already declared"), "{}", err);
+}
+
+#[test]
+fn test_route_branches_on_the_candidate() {
+    use rumpsteak_choreography::ast::{LocalType, Protocol};
+    use rumpsteak_choreography::compiler::projection::project;
+
+    let input = r#"
+choreography Orders {
+    roles: Client, Gateway, East, West
+
+    Client -> Gateway: Order
+    Gateway -> route(East, West): Order
+    Gateway -> Client: Accepted
+}
+"#;
+    let choreo = parse_choreography_str(input).unwrap();
+    choreo.validate().unwrap();
+    let [route] = choreo.routes.as_slice() else {
+        panic!("expected one route, got {:?}", choreo.routes);
+    };
+    assert_eq!(route.from.name, "Gateway");
+    assert_eq!(route.message, "Order");
+    let candidates: Vec<_> = route
+        .candidates
+        .iter()
+        .map(|r| r.name.to_string())
+        .collect();
+    assert_eq!(candidates, ["East", "West"]);
+
+    let Protocol::Send { continuation, .. } = &choreo.protocol else {
+        panic!("expected a send");
+    };
+    let Protocol::Choice { role, branches } = continuation.as_ref() else {
+        panic!("expected the route's choice, got {:?}", continuation);
+    };
+    assert_eq!(role.name, "Gateway");
+    let labels: Vec<String> = branches.iter().map(|b| b.label.to_string()).collect();
+    assert_eq!(labels, ["East", "West"]);
+
+    // Each candidate branches on where the order went, and receives it in
+    // its own branch
+    for (i, candidate) in ["East", "West"].iter().enumerate() {
+        let role = &choreo.roles[i + 2];
+        let LocalType::Branch { from, branches } = project(&choreo, role).unwrap() else {
+            panic!("expected {} to branch", candidate);
+        };
+        assert_eq!(from.name, "Gateway");
+        let LocalType::Receive { continuation, .. } = &branches[i].1 else {
+            panic!("expected the label, got {:?}", branches[i].1);
+        };
+        assert!(matches!(
+            continuation.as_ref(),
+            LocalType::Receive { message, .. } if message.name == "Order"
+        ));
+    }
+
+    // The rest of the protocol follows either way, and the client is not
+    // told where the order went
+    let client = project(&choreo, &choreo.roles[0]).unwrap();
+    let LocalType::Send { continuation, .. } = client else {
+        panic!("expected the client to send");
+    };
+    assert!(matches!(
+        *continuation,
+        LocalType::Receive { ref message, .. } if message.name == "Accepted"
+    ));
+
+    for (route, expected) in [
+        (
+            "A -> route(A, B): Order",
+            "cannot route a message to itself",
+        ),
+        ("A -> route(B, B): Order", "already a candidate"),
+    ] {
+        let input = format!("choreography Route {{\n    roles: A, B\n    {}\n}}", route);
+        let err = parse_choreography_str(&input).unwrap_err();
+        assert!(err.to_string().contains(expected), "{}: {}", route, err);
+    }
+}
+
+#[test]
+fn test_parse_wire_ids() {
+    let parse = |ids: &str| {
+        parse_choreography_str(&format!(
+            "choreography Quote {{ roles: A, B {} A -> B: Request \
+             choice B {{ Accept: {{ B -> A: Accept }} Reject: {{ B -> A: Reason }} }} }}",
+            ids
+        ))
+    };
+
+    // Ids may be given through an alias; labels and messages are numbered
+    // separately
+    let choreo =
+        parse("alias Why = Reason id Request = 1 id Accept = 2 id Why = 3 id Reject = 1").unwrap();
+    let ids: Vec<_> = choreo
+        .wire_ids
+        .iter()
+        .map(|(name, id)| (name.to_string(), *id))
+        .collect();
+    assert_eq!(
+        ids,
+        [
+            ("Accept".to_string(), 2),
+            ("Reason".to_string(), 3),
+            ("Reject".to_string(), 1),
+            ("Request".to_string(), 1)
+        ]
+    );
+    assert!(parse("").unwrap().wire_ids.is_empty());
+
+    let error = |ids: &str| parse(ids).unwrap_err().to_string();
+    assert!(
+        error("id Request = 1 id Request = 2").contains("'Request' already has wire id 1"),
+        "{}",
+        error("id Request = 1 id Request = 2")
+    );
+    assert!(
+        error("id Request = 1 id Accept = 2 id Reason = 3 id Reject = 4 id Refund = 5")
+            .contains("'Refund' is neither a message nor a branch label")
+    );
+    assert!(
+        error("id Request = 1 id Accept = 2 id Reason = 1 id Reject = 3")
+            .contains("wire id 1 is already given to 'Request'")
+    );
+    assert!(error("id Request = 1 id Accept = 2 id Reason = 3").contains("'Reject' has no wire id"));
+}
@@ -0,0 +1,54 @@
+// Integration tests for per-phase profiling
+#![cfg(all(feature = "profiling", not(target_arch = "wasm32")))]
+
+use rumpsteak_choreography::{Label, PhaseProfiler, Program, ValidationHandler};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Alice,
+    Bob,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Message {
+    Data(Vec<u8>),
+}
+
+#[tokio::test]
+async fn test_one_profile_per_top_level_effect() {
+    let program = Program::<Role, Message>::new()
+        .send(Role::Bob, Message::Data(vec![0; 16]))
+        .loop_n(
+            300,
+            Program::new().send(Role::Bob, Message::Data(vec![1; 64 * 1024])),
+        )
+        .choose(Role::Bob, Label("done"))
+        .end();
+
+    let mut profiler = PhaseProfiler::new();
+    let mut handler = ValidationHandler::new(Role::Alice);
+    let result = profiler.run(&mut handler, &mut (), program).await.unwrap();
+    assert!(result.error.is_none());
+
+    // The loop's sends are part of the loop's phase
+    let phases = profiler.phases();
+    assert_eq!(phases.len(), 4);
+    assert_eq!(
+        (phases[0].index, phases[0].name.as_str()),
+        (0, "send to Bob")
+    );
+    assert_eq!(phases[1].index, 1);
+    assert!(phases[1].name.starts_with("loop"), "{}", phases[1].name);
+    assert!(phases[1].samples() > 0);
+    assert!(phases[1].elapsed > phases[0].elapsed);
+
+    // Only phases that were sampled get a flamegraph
+    let dir = tempfile::tempdir().unwrap();
+    let paths = profiler.write_flamegraphs(dir.path()).unwrap();
+    let loop_graph = dir.path().join(phases[1].file_name());
+    assert!(paths.contains(&loop_graph));
+    assert!(std::fs::read_to_string(loop_graph)
+        .unwrap()
+        .contains("<svg"));
+}
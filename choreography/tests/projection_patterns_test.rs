@@ -4,13 +4,23 @@
 // 1. Choice branches without initial Send (local choices)
 // 2. Loop conditions preserved in projections
 // 3. Improved parallel branch merging with conflict detection
+// 4. Merging choice branches for roles not told about the choice
+// 5. Notifications inserted for `if`/`else`
+// 6. Races that either role may start
+// 7. Aborts received wherever the protocol could otherwise continue
+// 8. Compensations for the roles of a try body
+// 9. Timeouts owned by one role
+// 10. Role families expanded to one role per index
+// 11. Role families projected once for any index
 
 use quote::{format_ident, quote};
 use rumpsteak_choreography::ast::{
     protocol::Condition, Branch, Choreography, LocalType, MessageType, Protocol, Role,
 };
-use rumpsteak_choreography::compiler::projection::project;
-use std::collections::HashMap;
+use rumpsteak_choreography::compiler::expand::{expand_roles, ExpansionError};
+use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+use rumpsteak_choreography::compiler::projection::{project, project_family, ProjectionError};
+use std::collections::{BTreeMap, HashMap};
 
 #[test]
 fn test_local_choice_without_send() {
@@ -27,16 +37,22 @@ fn test_local_choice_without_send() {
                 Branch {
                     label: format_ident!("option1"),
                     guard: None,
+                    weight: None,
                     protocol: Protocol::End, // No Send - local decision
                 },
                 Branch {
                     label: format_ident!("option2"),
                     guard: None,
+                    weight: None,
                     protocol: Protocol::End,
                 },
             ],
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let projected = project(&choreo, &alice).unwrap();
@@ -76,6 +92,10 @@ fn test_loop_with_condition() {
             }),
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let alice_proj = project(&choreo, &alice).unwrap();
@@ -132,6 +152,10 @@ fn test_parallel_no_conflict() {
             ],
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     // Alice's projection should succeed (no conflict - different recipients)
@@ -183,6 +207,10 @@ fn test_parallel_with_conflict() {
             ],
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     // Alice's projection should fail (conflict detected)
@@ -205,6 +233,7 @@ fn test_mixed_choice_communicated_vs_local() {
                 Branch {
                     label: format_ident!("yes"),
                     guard: None,
+                    weight: None,
                     protocol: Protocol::Send {
                         from: alice.clone(),
                         to: bob.clone(),
@@ -219,6 +248,7 @@ fn test_mixed_choice_communicated_vs_local() {
                 Branch {
                     label: format_ident!("no"),
                     guard: None,
+                    weight: None,
                     protocol: Protocol::Send {
                         from: alice.clone(),
                         to: bob.clone(),
@@ -233,6 +263,10 @@ fn test_mixed_choice_communicated_vs_local() {
             ],
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     // Alice should get Select (communicated choice)
@@ -270,6 +304,10 @@ fn test_loop_without_condition() {
             body: Box::new(Protocol::End),
         },
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let projected = project(&choreo, &alice).unwrap();
@@ -277,3 +315,755 @@ fn test_loop_without_condition() {
     // Since body is End and Alice doesn't participate, should project to End
     assert_eq!(projected, LocalType::End);
 }
+
+#[test]
+fn test_finally_projection() {
+    // Test: Cleanup is kept separate for roles that take part in it
+    // and dropped for roles that don't
+    let client = Role::new(format_ident!("Client"));
+    let server = Role::new(format_ident!("Server"));
+    let auditor = Role::new(format_ident!("Auditor"));
+
+    let send = |from: &Role, to: &Role, name: &str| Protocol::Send {
+        from: from.clone(),
+        to: to.clone(),
+        message: MessageType {
+            name: format_ident!("{}", name),
+            type_annotation: None,
+            payload: None,
+        },
+        continuation: Box::new(Protocol::End),
+    };
+
+    let choreo = Choreography {
+        name: format_ident!("FinallyTest"),
+        roles: vec![client.clone(), server.clone(), auditor.clone()],
+        protocol: Protocol::Finally {
+            body: Box::new(Protocol::Parallel {
+                protocols: vec![
+                    send(&client, &server, "Acquire"),
+                    send(&client, &auditor, "Log"),
+                ],
+            }),
+            cleanup: Box::new(send(&client, &server, "Release")),
+        },
+        attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
+    };
+
+    match project(&choreo, &server).unwrap() {
+        LocalType::Finally { body, cleanup } => {
+            assert!(matches!(*body, LocalType::Receive { .. }));
+            assert!(matches!(*cleanup, LocalType::Receive { .. }));
+        }
+        other => panic!("Expected Finally, got: {:?}", other),
+    }
+
+    assert!(matches!(
+        project(&choreo, &auditor).unwrap(),
+        LocalType::Receive { .. }
+    ));
+}
+
+#[test]
+fn test_non_participant_merges_distinct_branch_labels() {
+    // Logger never learns Client's choice, but Server tells it what happened
+    // with a label that differs per branch, so the branches merge
+    let choreo = parse_choreography_str(
+        r#"
+choreography Trade {
+    roles: Client, Server, Logger
+
+    choice Client {
+        buy: {
+            Client -> Server: Buy
+            choice Server {
+                bought: {
+                    Server -> Logger: Bought
+                }
+            }
+        }
+        sell: {
+            Client -> Server: Sell
+            choice Server {
+                sold: {
+                    Server -> Logger: Sold
+                }
+            }
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let logger = Role::new(format_ident!("Logger"));
+
+    match project(&choreo, &logger).unwrap() {
+        LocalType::Branch { from, branches } => {
+            assert_eq!(from.name.to_string(), "Server");
+            let labels: Vec<_> = branches.iter().map(|(l, _)| l.to_string()).collect();
+            assert_eq!(labels, ["bought", "sold"]);
+        }
+        other => panic!("Expected merged Branch, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_non_participant_with_unmergeable_branches() {
+    // Logger hears from a different role depending on a choice it cannot see
+    let choreo = parse_choreography_str(
+        r#"
+choreography Trade {
+    roles: Client, Server, Logger
+
+    choice Client {
+        buy: {
+            Client -> Server: Buy
+            Server -> Logger: Bought
+        }
+        sell: {
+            Client -> Server: Sell
+            Client -> Logger: Sold
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let logger = Role::new(format_ident!("Logger"));
+
+    match project(&choreo, &logger) {
+        Err(ProjectionError::UnmergeableBranches { role, labels }) => {
+            assert_eq!(role, "Logger");
+            assert_eq!(labels, ("buy".to_string(), "sell".to_string()));
+        }
+        other => panic!("Expected UnmergeableBranches, got: {:?}", other),
+    }
+
+    // Roles that make or receive the choice still project
+    assert!(project(&choreo, &Role::new(format_ident!("Server"))).is_ok());
+}
+
+#[test]
+fn test_unmergeable_branches_name_the_conflicting_pair() {
+    // Logger can tell the first branch from either other one by its
+    // message, but not the last two from each other
+    let choreo = parse_choreography_str(
+        r#"
+choreography Trade {
+    roles: Client, Server, Logger
+
+    choice Client {
+        buy: {
+            Client -> Server: Buy
+            Server -> Logger: Bought
+        }
+        sell: {
+            Client -> Server: Sell
+            Server -> Logger: Sold
+        }
+        short: {
+            Client -> Server: Short
+            Server -> Logger: Sold
+            Logger -> Server: Ack
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let logger = Role::new(format_ident!("Logger"));
+
+    match project(&choreo, &logger) {
+        Err(ProjectionError::UnmergeableBranches { role, labels }) => {
+            assert_eq!(role, "Logger");
+            assert_eq!(labels, ("sell".to_string(), "short".to_string()));
+        }
+        other => panic!("Expected UnmergeableBranches, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_if_else_notifies_peers_that_need_to_know() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Lookup {
+    roles: Client, Server, Cache, Db
+
+    Client -> Server: Request
+    if (hit) at Server {
+        Server -> Client: Cached
+    } else {
+        Server -> Db: Query
+        Db -> Server: Rows
+        Server -> Client: Fresh
+    }
+}
+"#,
+    )
+    .unwrap();
+    let role = |name: &str| Role::new(format_ident!("{}", name));
+    let labels = |branches: &[(proc_macro2::Ident, LocalType)]| {
+        branches
+            .iter()
+            .map(|(l, _)| l.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let told = |ty: LocalType| match ty {
+        LocalType::Branch { from, branches } => {
+            assert_eq!(from.name.to_string(), "Server");
+            assert_eq!(labels(&branches), ["Then", "Else"]);
+        }
+        other => panic!("Expected Branch, got: {:?}", other),
+    };
+
+    // Client and Db act differently per branch, so both are told
+    match project(&choreo, &role("Client")).unwrap() {
+        LocalType::Send { continuation, .. } => told(*continuation),
+        other => panic!("Expected Send, got: {:?}", other),
+    }
+    told(project(&choreo, &role("Db")).unwrap());
+
+    // Cache takes no part and hears nothing
+    assert_eq!(project(&choreo, &role("Cache")).unwrap(), LocalType::End);
+
+    match project(&choreo, &role("Server")).unwrap() {
+        LocalType::Receive { continuation, .. } => {
+            assert!(matches!(*continuation, LocalType::Select { .. }));
+        }
+        other => panic!("Expected Receive, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_if_else_reuses_leading_message_as_label() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Login {
+    roles: Client, Server
+
+    if (valid) at Server {
+        Server -> Client: Welcome
+    } else {
+        Server -> Client: Denied
+    }
+}
+"#,
+    )
+    .unwrap();
+    let client = Role::new(format_ident!("Client"));
+
+    match project(&choreo, &client).unwrap() {
+        LocalType::Branch { branches, .. } => {
+            // No extra `Then`/`Else` message before the real one
+            for (_, ty) in &branches {
+                let LocalType::Receive {
+                    message,
+                    continuation,
+                    ..
+                } = ty
+                else {
+                    panic!("Expected Receive, got: {:?}", ty);
+                };
+                assert!(["Welcome", "Denied"].contains(&message.name.to_string().as_str()));
+                assert_eq!(**continuation, LocalType::End);
+            }
+        }
+        other => panic!("Expected Branch, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_race_projects_to_selective_receive() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Job {
+    roles: Client, Server
+
+    Client -> Server: Start
+    race {
+        Client -> Server: Cancel
+    |
+        Server -> Client: Done
+        Client -> Server: Ack
+    }
+}
+"#,
+    )
+    .unwrap();
+    choreo.validate().unwrap();
+
+    for name in ["Client", "Server"] {
+        let (LocalType::Receive { continuation, .. } | LocalType::Send { continuation, .. }) =
+            project(&choreo, &Role::new(format_ident!("{}", name))).unwrap()
+        else {
+            panic!("Expected the Start message first");
+        };
+        let LocalType::Race { branches } = *continuation else {
+            panic!("Expected Race for {}, got: {:?}", name, continuation);
+        };
+        let labels: Vec<_> = branches.iter().map(|(l, _)| l.to_string()).collect();
+        assert_eq!(labels, vec!["Cancel", "Done"]);
+    }
+}
+
+#[test]
+fn test_race_arms_must_start_with_distinct_sends() {
+    let missing_send = r#"
+choreography Job {
+    roles: Client, Server
+
+    race {
+        Client -> Server: Cancel
+    |
+        loop (count: 2) {
+            Server -> Client: Tick
+        }
+    }
+}
+"#;
+    assert!(parse_choreography_str(missing_send).is_err());
+
+    let duplicate = r#"
+choreography Job {
+    roles: Client, Server
+
+    race {
+        Client -> Server: Stop
+    |
+        Server -> Client: Stop
+    }
+}
+"#;
+    assert!(parse_choreography_str(duplicate).is_err());
+}
+
+#[test]
+fn test_race_role_missing_an_arm_is_rejected() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Job {
+    roles: Client, Server, Log
+
+    race {
+        Client -> Server: Cancel
+    |
+        Server -> Log: Done
+    }
+}
+"#,
+    )
+    .unwrap();
+
+    // Client sends Cancel but cannot tell when Done has been sent
+    match project(&choreo, &Role::new(format_ident!("Client"))) {
+        Err(ProjectionError::UnobservedRaceArm { role, label }) => {
+            assert_eq!(role, "Client");
+            assert_eq!(label, "Done");
+        }
+        other => panic!("Expected UnobservedRaceArm, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_abort_is_received_at_the_next_receive_point() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Payment {
+    roles: Client, Bank, Shop
+
+    Client -> Bank: Pay
+    choice Bank {
+        ok: {
+            Bank -> Client: Paid
+            Bank -> Shop: Ship
+        }
+        declined: {
+            Bank -> Client: Declined
+            abort at Bank: Cancelled
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    choreo.validate().unwrap();
+
+    // Shop is not told about the choice, but can wait for either message
+    let shop = project(&choreo, &Role::new(format_ident!("Shop"))).unwrap();
+    let LocalType::Branch { from, branches } = shop else {
+        panic!("Expected Branch for Shop, got: {:?}", shop);
+    };
+    assert_eq!(from.name, "Bank");
+    let labels: Vec<_> = branches.iter().map(|(l, _)| l.to_string()).collect();
+    assert_eq!(labels, vec!["Ship", "Cancelled"]);
+
+    assert!(rumpsteak_choreography::compiler::analyze(&choreo).is_deadlock_free);
+}
+
+#[test]
+fn test_abort_is_raced_against_a_receive_from_another_role() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Relay {
+    roles: A, B, C
+
+    A -> B: Req
+    choice A {
+        ok: {
+            A -> B: Go
+            B -> C: Fwd
+        }
+        fail: {
+            abort at A: Reason
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    choreo.validate().unwrap();
+
+    // C waits on B if A carries on, so it takes the abort at that receive
+    let c = project(&choreo, &Role::new(format_ident!("C"))).unwrap();
+    let LocalType::Race { branches } = c else {
+        panic!("Expected Race for C, got: {:?}", c);
+    };
+    let arms: Vec<_> = branches
+        .iter()
+        .map(|(label, arm)| match arm {
+            LocalType::Receive { from, .. } => format!("{} from {}", label, from.name),
+            other => panic!(
+                "Expected each arm to start with a receive, got: {:?}",
+                other
+            ),
+        })
+        .collect();
+    assert_eq!(arms, vec!["Fwd from B", "Reason from A"]);
+}
+
+#[test]
+fn test_try_catch_projection() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Booking {
+    roles: Client, Hotel, Airline
+
+    try {
+        Client -> Hotel: Reserve
+        Client -> Airline: Book
+    } catch {
+        Client -> Hotel: Release
+    }
+}
+"#,
+    )
+    .unwrap();
+    choreo.validate().unwrap();
+    let role = |name: &str| Role::new(format_ident!("{}", name));
+
+    // The client tells both peers in the body if it fails
+    match project(&choreo, &role("Client")).unwrap() {
+        LocalType::TryCatch {
+            body,
+            notify,
+            compensation,
+        } => {
+            assert!(matches!(*body, LocalType::Send { .. }));
+            let notify: Vec<_> = notify.iter().map(|r| r.name.to_string()).collect();
+            assert_eq!(notify, vec!["Hotel", "Airline"]);
+            assert!(matches!(*compensation, LocalType::Send { .. }));
+        }
+        other => panic!("Expected TryCatch, got: {:?}", other),
+    }
+
+    // The airline has nothing to undo but still learns of the failure
+    match project(&choreo, &role("Airline")).unwrap() {
+        LocalType::TryCatch { compensation, .. } => {
+            assert_eq!(*compensation, LocalType::End)
+        }
+        other => panic!("Expected TryCatch, got: {:?}", other),
+    }
+
+    // A role outside the body never hears about the error
+    let outsider = parse_choreography_str(
+        r#"
+choreography Booking {
+    roles: Client, Hotel, Auditor
+
+    try {
+        Client -> Hotel: Reserve
+    } catch {
+        Client -> Auditor: Failed
+    }
+}
+"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        outsider.validate(),
+        Err(rumpsteak_choreography::ast::ValidationError::InvalidCompensation(role)) if role == "Auditor"
+    ));
+}
+
+#[test]
+fn test_timeout_projection() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Quote {
+    roles: Client, Server
+
+    timeout(2s) at Client {
+        Client -> Server: Request
+        Server -> Client: Response
+    } else {
+        Client -> Server: Give
+    }
+}
+"#,
+    )
+    .unwrap();
+    choreo.validate().unwrap();
+    let role = |name: &str| Role::new(format_ident!("{}", name));
+
+    // The timer owns the deadline and tells the server when it gives up
+    match project(&choreo, &role("Client")).unwrap() {
+        LocalType::Timeout {
+            duration,
+            body,
+            notify,
+            on_timeout,
+        } => {
+            assert_eq!(duration, std::time::Duration::from_secs(2));
+            assert!(matches!(*body, LocalType::Send { .. }));
+            let notify: Vec<_> = notify.iter().map(|r| r.name.to_string()).collect();
+            assert_eq!(notify, vec!["Server"]);
+            assert!(matches!(*on_timeout, LocalType::Send { .. }));
+        }
+        other => panic!("Expected Timeout, got: {:?}", other),
+    }
+
+    // The server runs the else branch once the client aborts
+    match project(&choreo, &role("Server")).unwrap() {
+        LocalType::TryCatch {
+            notify,
+            compensation,
+            ..
+        } => {
+            assert!(notify.is_empty());
+            assert!(matches!(*compensation, LocalType::Receive { .. }));
+        }
+        other => panic!("Expected TryCatch, got: {:?}", other),
+    }
+
+    // Only roles of the body hear that the time ran out
+    let outsider = parse_choreography_str(
+        r#"
+choreography Quote {
+    roles: Client, Server, Auditor
+
+    timeout(2s) at Client {
+        Client -> Server: Request
+    } else {
+        Client -> Auditor: Late
+    }
+}
+"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        outsider.validate(),
+        Err(rumpsteak_choreography::ast::ValidationError::InvalidTimeout(role)) if role == "Auditor"
+    ));
+}
+
+#[test]
+fn test_expand_roles_unrolls_loops_over_a_family() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Scatter {
+    roles: Master, Worker[N]
+
+    foreach i in 0..N {
+        Master -> Worker[i]: Task
+        Worker[i] -> Master: Result
+    }
+    Master ->* : Done
+}
+"#,
+    )
+    .unwrap();
+    let bindings = HashMap::from([("N".to_string(), 3)]);
+    let expanded = expand_roles(&choreo, &bindings).unwrap();
+    expanded.validate().unwrap();
+    let names: Vec<_> = expanded.roles.iter().map(|r| r.name.to_string()).collect();
+    assert_eq!(names, vec!["Master", "Worker0", "Worker1", "Worker2"]);
+
+    // Each worker gets its own task and hears that the scatter is done
+    for worker in &expanded.roles[1..] {
+        match project(&expanded, worker).unwrap() {
+            LocalType::Receive {
+                from, continuation, ..
+            } => {
+                assert_eq!(from.name, "Master");
+                assert!(matches!(*continuation, LocalType::Send { .. }));
+            }
+            other => panic!("Expected Receive, got: {:?}", other),
+        }
+    }
+
+    // The master deals with the workers in order
+    let mut peers = Vec::new();
+    let mut local = project(&expanded, &expanded.roles[0]).unwrap();
+    while let LocalType::Send {
+        to, continuation, ..
+    }
+    | LocalType::Receive {
+        from: to,
+        continuation,
+        ..
+    } = local
+    {
+        peers.push(to.name.to_string());
+        local = *continuation;
+    }
+    assert_eq!(
+        peers,
+        vec!["Worker0", "Worker0", "Worker1", "Worker1", "Worker2", "Worker2"]
+    );
+}
+
+#[test]
+fn test_expand_roles_renames_fixed_indices() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Pair {
+    roles: Master, Worker[3]
+
+    Master -> Worker[0]: Task
+    Worker[0] -> Worker[2]: Forward
+}
+"#,
+    )
+    .unwrap();
+    let expanded = expand_roles(&choreo, &HashMap::new()).unwrap();
+    match &expanded.protocol {
+        Protocol::Send {
+            to, continuation, ..
+        } => {
+            assert_eq!(to.name, "Worker0");
+            assert!(matches!(
+                continuation.as_ref(),
+                Protocol::Send { from, to, .. } if from.name == "Worker0" && to.name == "Worker2"
+            ));
+        }
+        other => panic!("Expected Send, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_expand_roles_reports_missing_sizes_and_bad_indices() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Scatter {
+    roles: Master, Worker[N]
+
+    Master -> Worker[4]: Task
+}
+"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        expand_roles(&choreo, &HashMap::new()),
+        Err(ExpansionError::UnboundSize { role, size }) if role == "Worker" && size == "N"
+    ));
+    let bindings = HashMap::from([("N".to_string(), 2)]);
+    assert!(matches!(
+        expand_roles(&choreo, &bindings),
+        Err(ExpansionError::IndexOutOfRange {
+            index: 4,
+            size: 2,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_project_family_matches_every_expanded_member() {
+    let choreo = parse_choreography_str(
+        r#"
+choreography Scatter {
+    roles: Master, Worker[N]
+
+    Master ->* : Start
+    foreach i in 0..N {
+        Master -> Worker[i]: Task
+        Worker[i] -> Master: Result
+    }
+}
+"#,
+    )
+    .unwrap();
+    let family = project_family(&choreo, &choreo.roles[1]).unwrap();
+    assert_eq!(family.index, "i");
+    match &family.local_type {
+        LocalType::Receive {
+            from, continuation, ..
+        } => {
+            assert_eq!(from.name, "Master");
+            assert!(matches!(continuation.as_ref(), LocalType::Receive { .. }));
+        }
+        other => panic!("Expected Receive, got: {:?}", other),
+    }
+
+    // One projection stands for each worker of the expanded protocol
+    let bindings = HashMap::from([("N".to_string(), 3)]);
+    let expanded = expand_roles(&choreo, &bindings).unwrap();
+    for index in 0..3 {
+        let (role, local_type) = family.instantiate(index);
+        assert_eq!(project(&expanded, &role).unwrap(), local_type);
+    }
+}
+
+#[test]
+fn test_project_family_rejects_index_dependent_protocols() {
+    let leader = parse_choreography_str(
+        r#"
+choreography Leader {
+    roles: Master, Worker[N]
+
+    Master -> Worker[0]: Lead
+}
+"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        project_family(&leader, &leader.roles[1]),
+        Err(ProjectionError::IndexDependent { role, .. }) if role == "Worker"
+    ));
+
+    let partial = parse_choreography_str(
+        r#"
+choreography Partial {
+    roles: Master, Worker[N]
+
+    foreach i in 1..N {
+        Master -> Worker[i]: Task
+    }
+}
+"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        project_family(&partial, &partial.roles[1]),
+        Err(ProjectionError::IndexDependent { .. })
+    ));
+    assert!(matches!(
+        project_family(&partial, &partial.roles[0]),
+        Err(ProjectionError::UndeclaredFamily(role)) if role == "Master"
+    ));
+}
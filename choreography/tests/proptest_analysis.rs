@@ -10,7 +10,7 @@ use proptest::prelude::*;
 use quote::{format_ident, quote};
 use rumpsteak_choreography::ast::{Branch, Choreography, MessageType, Protocol, Role};
 use rumpsteak_choreography::compiler::analysis::analyze;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 // Reuse strategies from projection tests
 fn role_strategy() -> impl Strategy<Value = Role> {
@@ -86,6 +86,7 @@ fn simple_protocol_strategy() -> impl Strategy<Value = Protocol> {
                                 .map(|(i, msg)| Branch {
                                     label: format_ident!("branch{}", i),
                                     guard: None,
+                                    weight: None,
                                     protocol: Protocol::Send {
                                         from: chooser.clone(),
                                         to: other.clone(),
@@ -155,6 +156,10 @@ fn choreography_strategy() -> impl Strategy<Value = Choreography> {
             roles,
             protocol,
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         }
     })
 }
@@ -285,6 +290,10 @@ proptest! {
                 }),
             },
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         let result = analyze(&choreo);
@@ -304,6 +313,10 @@ proptest! {
             roles: roles.clone(),
             protocol: Protocol::End,
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         let result = analyze(&choreo);
@@ -334,6 +347,10 @@ proptest! {
                 continuation: Box::new(Protocol::End),
             },
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         let result = analyze(&choreo);
@@ -385,6 +402,10 @@ mod unit_tests {
                 continuation: Box::new(Protocol::End),
             },
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         let result = analyze(&choreo);
@@ -420,6 +441,10 @@ mod unit_tests {
                 continuation: Box::new(Protocol::End),
             },
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         let result = analyze(&choreo);
@@ -6,7 +6,7 @@ use proptest::prelude::*;
 use quote::format_ident;
 use rumpsteak_choreography::ast::{Choreography, LocalType, Protocol, Role};
 use rumpsteak_choreography::compiler::projection::project;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 fn simple_role_strategy() -> impl Strategy<Value = Role> {
     prop_oneof![
@@ -24,6 +24,10 @@ proptest! {
             roles: vec![role.clone()],
             protocol: Protocol::End,
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         // Projection should complete without panicking
@@ -39,8 +43,127 @@ fn test_end_projection() {
         roles: vec![alice.clone()],
         protocol: Protocol::End,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let projected = project(&choreo, &alice).unwrap();
     assert_eq!(projected, LocalType::End);
 }
+
+mod fuzzing {
+    use super::*;
+    use rumpsteak_choreography::ast::{Branch, MessageType};
+    use rumpsteak_choreography::testing::{
+        arbitrary_choreography, check_choreography, fuzz_choreography, run_shuffled, FuzzConfig,
+        FuzzFailure,
+    };
+
+    fn role(name: &str) -> Role {
+        Role::new(format_ident!("{}", name))
+    }
+
+    fn send(from: &str, to: &str, message: &str, continuation: Protocol) -> Protocol {
+        Protocol::Send {
+            from: role(from),
+            to: role(to),
+            message: MessageType {
+                name: format_ident!("{}", message),
+                type_annotation: None,
+                payload: None,
+            },
+            continuation: Box::new(continuation),
+        }
+    }
+
+    fn branch(label: &str, protocol: Protocol) -> Branch {
+        Branch {
+            label: format_ident!("{}", label),
+            guard: None,
+            weight: None,
+            protocol,
+        }
+    }
+
+    proptest! {
+        /// Property: Generated choreographies validate, and every schedule
+        /// runs their projections to the end
+        #[test]
+        fn generated_choreographies_run(seed in any::<u64>()) {
+            let config = FuzzConfig::new().with_seed(seed);
+            let choreo = arbitrary_choreography(seed, &config);
+            prop_assert!(choreo.validate().is_ok(), "{:#?}", choreo.protocol);
+            if let Err(failure) = check_choreography(&choreo, &config) {
+                return Err(TestCaseError::fail(failure.to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzzing_is_reproducible() {
+        let config = FuzzConfig::new()
+            .with_cases(50)
+            .with_seed(7)
+            .with_max_roles(5);
+        let report = fuzz_choreography(&config).unwrap_or_else(|failure| panic!("{}", failure));
+        assert_eq!(report.cases, 50);
+        assert_eq!(report.runs, 200);
+        assert!(report.steps > 0);
+        assert_eq!(fuzz_choreography(&config).unwrap(), report);
+    }
+
+    #[test]
+    fn test_failures_shrink_to_the_choice_that_deadlocks() {
+        // B is only told about the first branch and C only about the
+        // second, so whichever branch A takes, one of them waits forever
+        let choice = Protocol::Choice {
+            role: role("A"),
+            branches: vec![
+                branch(
+                    "Left",
+                    send("A", "B", "Left", send("B", "C", "Noise", Protocol::End)),
+                ),
+                branch("Right", send("A", "C", "Right", Protocol::End)),
+            ],
+        };
+        let choreo = Choreography {
+            name: format_ident!("Broken"),
+            roles: vec![role("A"), role("B"), role("C")],
+            protocol: send("A", "B", "Hello", send("C", "A", "Reply", choice)),
+            attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
+        };
+        assert!(matches!(
+            run_shuffled(&choreo, 0, 1000),
+            Err(FuzzFailure::Deadlock(_))
+        ));
+
+        let failure = check_choreography(&choreo, &FuzzConfig::new()).unwrap_err();
+        assert!(matches!(failure.failure, FuzzFailure::Deadlock(_)));
+        assert_eq!(
+            format!("{:?}", failure.original.protocol),
+            format!("{:?}", choreo.protocol)
+        );
+        let Protocol::Choice { branches, .. } = &failure.shrunk.protocol else {
+            panic!(
+                "Expected the choice alone, got: {:#?}",
+                failure.shrunk.protocol
+            );
+        };
+        assert_eq!(branches.len(), 2);
+        // Protocols have no equality, so compare them as printed
+        assert_eq!(
+            format!("{:?}", branches[0].protocol),
+            format!("{:?}", send("A", "B", "Left", Protocol::End))
+        );
+        assert_eq!(
+            format!("{:?}", branches[1].protocol),
+            format!("{:?}", send("A", "C", "Right", Protocol::End))
+        );
+    }
+}
@@ -6,7 +6,7 @@ use proptest::prelude::*;
 use quote::format_ident;
 use rumpsteak_choreography::ast::{Choreography, LocalType, Protocol, Role};
 use rumpsteak_choreography::compiler::projection::project;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 fn simple_role_strategy() -> impl Strategy<Value = Role> {
     prop_oneof![
@@ -24,6 +24,10 @@ proptest! {
             roles: vec![role.clone()],
             protocol: Protocol::End,
             attrs: HashMap::new(),
+            returns: Vec::new(),
+            routes: Vec::new(),
+            wire_ids: BTreeMap::new(),
+            trusted: Vec::new(),
         };
 
         // Projection should complete without panicking
@@ -39,6 +43,10 @@ fn test_end_projection() {
         roles: vec![alice.clone()],
         protocol: Protocol::End,
         attrs: HashMap::new(),
+        returns: Vec::new(),
+        routes: Vec::new(),
+        wire_ids: BTreeMap::new(),
+        trusted: Vec::new(),
     };
 
     let projected = project(&choreo, &alice).unwrap();
@@ -0,0 +1,124 @@
+// Tests for reference vectors exported from choreographies
+
+use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+use rumpsteak_choreography::compiler::{generate_reference_vectors, ReferenceVectors, VectorError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const SHOP: &str = r#"
+choreography Shop {
+    roles: Buyer, Seller
+
+    Buyer -> Seller: Order(id: u64, note: Option<String>, price: f64, tags: Vec<String>)
+    Seller -> Buyer: Quote(char)
+    Seller -> Buyer: Stock(BTreeMap<String, u16>)
+    Buyer -> Seller: Ping
+    Buyer -> Seller: Invoice(billing::Invoice)
+}
+"#;
+
+// The message types `generate_effects_protocol` emits for `Shop`
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Invoice(String);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Order {
+    id: u64,
+    note: Option<String>,
+    price: f64,
+    tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Ping(String);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Quote(char);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Stock(BTreeMap<String, u16>);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Invoice(Invoice),
+    Order(Order),
+    Ping(Ping),
+    Quote(Quote),
+    Stock(Stock),
+}
+
+fn shop_vectors() -> ReferenceVectors {
+    generate_reference_vectors(&parse_choreography_str(SHOP).unwrap())
+}
+
+#[test]
+fn test_vectors_match_the_serde_encodings() {
+    let vectors = shop_vectors();
+    let messages: Vec<_> = vectors.vectors.iter().map(|v| v.message.as_str()).collect();
+    assert_eq!(messages, ["Order", "Ping", "Quote", "Stock"]);
+    assert_eq!(vectors.skipped, ["Invoice"]);
+
+    let order = vectors.vectors_for("Order").next().unwrap();
+    assert_eq!(
+        order.json,
+        r#"{"Order":{"id":0,"note":null,"price":0.0,"tags":[]}}"#
+    );
+    let default_order = Message::Order(Order {
+        id: 0,
+        note: None,
+        price: 0.0,
+        tags: Vec::new(),
+    });
+    assert_eq!(
+        order.bincode,
+        hex::encode(bincode::serialize(&default_order).unwrap())
+    );
+
+    vectors.verify::<Message>().unwrap();
+}
+
+#[test]
+fn test_vectors_catch_a_type_that_encodes_differently() {
+    // With fields in another order every vector still decodes, since
+    // default payloads are all zero bytes in bincode, but JSON gives the
+    // fields back in the wrong order
+    #[derive(Serialize, Deserialize)]
+    struct Reordered {
+        note: Option<String>,
+        id: u64,
+        price: f64,
+        tags: Vec<String>,
+    }
+    #[derive(Serialize, Deserialize)]
+    enum Drifted {
+        Invoice(Invoice),
+        Order(Reordered),
+        Ping(Ping),
+        Quote(Quote),
+        Stock(Stock),
+    }
+
+    match shop_vectors().verify::<Drifted>() {
+        Err(VectorError::Mismatch {
+            message, format, ..
+        }) => {
+            assert_eq!(message, "Order");
+            assert_eq!(format, "json");
+        }
+        other => panic!("expected the Order vector to fail, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_vectors_from_real_values_round_trip() {
+    let invoice = Message::Invoice(Invoice("INV-7".to_string()));
+    let vectors = shop_vectors()
+        .with_vector("Invoice", "an invoice number", &invoice)
+        .unwrap();
+    assert!(vectors.skipped.is_empty());
+
+    let reloaded = ReferenceVectors::from_json(&vectors.to_json()).unwrap();
+    assert_eq!(reloaded, vectors);
+    reloaded.verify::<Message>().unwrap();
+}
@@ -6,7 +6,7 @@ use rumpsteak_choreography::effects::{
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 enum TestRole {
     Alice,
     Bob,
@@ -228,6 +228,133 @@ async fn test_large_message() {
     assert_eq!(received.content, large_content);
 }
 
+#[tokio::test]
+async fn test_message_size_limits() {
+    use rumpsteak_choreography::ChoreographyError;
+
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler =
+        RumpsteakHandler::<TestRole, TestMessage>::new().with_max_message_size(1024);
+    let large = TestMessage {
+        content: "x".repeat(4096),
+    };
+
+    // Bob refuses to send it...
+    match bob_handler
+        .send(&mut bob_endpoint, TestRole::Alice, &large)
+        .await
+    {
+        Err(ChoreographyError::MessageTooLarge { role, size, limit }) => {
+            assert_eq!(role, "Alice");
+            assert!(size > 4096);
+            assert_eq!(limit, 1024);
+        }
+        other => panic!("expected the send to be refused, got {:?}", other),
+    }
+
+    // ...and to take it from an unlimited sender
+    alice_handler
+        .send(&mut alice_endpoint, TestRole::Bob, &large)
+        .await
+        .unwrap();
+    let received: Result<TestMessage, _> =
+        bob_handler.recv(&mut bob_endpoint, TestRole::Alice).await;
+    assert!(matches!(
+        received,
+        Err(ChoreographyError::MessageTooLarge { ref role, .. }) if role == "Alice"
+    ));
+
+    // Messages under the limit still get through
+    let small = TestMessage {
+        content: "hello".into(),
+    };
+    alice_handler
+        .send(&mut alice_endpoint, TestRole::Bob, &small)
+        .await
+        .unwrap();
+    let received: TestMessage = bob_handler
+        .recv(&mut bob_endpoint, TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(received, small);
+}
+
+#[tokio::test]
+async fn test_slow_consumers_are_reported() {
+    use rumpsteak_choreography::effects::Label;
+    use rumpsteak_choreography::ChoreographyError;
+
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new()
+        .with_lag_warning(2)
+        .with_max_lag(3);
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let update = |i: usize| TestMessage {
+        content: format!("update {}", i),
+    };
+
+    // Bob reads nothing while Alice sends three updates
+    for i in 0..3 {
+        alice_handler
+            .send(&mut alice_endpoint, TestRole::Bob, &update(i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(alice_endpoint.lag(&TestRole::Bob), Some(3));
+    match alice_handler
+        .send(&mut alice_endpoint, TestRole::Bob, &update(3))
+        .await
+    {
+        Err(ChoreographyError::SlowConsumer { role, lag, limit }) => {
+            assert_eq!(role, "Bob");
+            assert_eq!(lag, 3);
+            assert_eq!(limit, 3);
+        }
+        other => panic!("expected Bob to be reported as slow, got {:?}", other),
+    }
+
+    // Alice can still tell Bob she is backing off
+    alice_handler
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("throttle"))
+        .await
+        .unwrap();
+    assert_eq!(alice_endpoint.lag(&TestRole::Bob), Some(4));
+
+    // Once Bob catches up, sends go through again
+    for i in 0..3 {
+        let received: TestMessage = bob_handler
+            .recv(&mut bob_endpoint, TestRole::Alice)
+            .await
+            .unwrap();
+        assert_eq!(received, update(i));
+    }
+    let label = bob_handler
+        .offer(&mut bob_endpoint, TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(label.0, "throttle");
+    assert_eq!(alice_endpoint.lag(&TestRole::Bob), Some(0));
+    alice_handler
+        .send(&mut alice_endpoint, TestRole::Bob, &update(3))
+        .await
+        .unwrap();
+    assert_eq!(
+        RumpsteakEndpoint::new(TestRole::Bob).lag(&TestRole::Alice),
+        None
+    );
+}
+
 #[tokio::test]
 async fn test_choice_selection() {
     use rumpsteak_choreography::effects::Label;
@@ -545,3 +672,1318 @@ async fn test_drop_cleanup() {
     // Drop implementation should have cleaned up
     // (verified by lack of panic and proper tracing output)
 }
+
+#[tokio::test]
+async fn test_session_type_progression() {
+    use rumpsteak_choreography::effects::{ChoreographyError, Label, SessionType};
+
+    // Alice: send TestMessage to Bob, then choose `done`; Bob mirrors it
+    let alice_session = SessionType::send::<TestMessage>(
+        TestRole::Bob,
+        SessionType::select(TestRole::Bob, vec![("done", SessionType::End)]),
+    );
+    let bob_session = SessionType::receive::<TestMessage>(
+        TestRole::Alice,
+        SessionType::branch(TestRole::Alice, vec![("done", SessionType::End)]),
+    );
+
+    let mut alice_endpoint = RumpsteakEndpoint::with_session_type(TestRole::Alice, alice_session);
+    let mut bob_endpoint = RumpsteakEndpoint::with_session_type(TestRole::Bob, bob_session);
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    // Choosing before sending does not match the session type
+    let err = alice_handler
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("done"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ChoreographyError::SessionMismatch { .. }));
+
+    // Sending the wrong message type is rejected before anything is sent
+    let err = alice_handler
+        .send(&mut alice_endpoint, TestRole::Bob, &"not a TestMessage")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ChoreographyError::SessionMismatch { .. }));
+
+    let msg = TestMessage {
+        content: "typed".to_string(),
+    };
+    alice_handler
+        .send(&mut alice_endpoint, TestRole::Bob, &msg)
+        .await
+        .unwrap();
+    let received: TestMessage = bob_handler
+        .recv(&mut bob_endpoint, TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(received, msg);
+
+    alice_handler
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("done"))
+        .await
+        .unwrap();
+    let label = bob_handler
+        .offer(&mut bob_endpoint, TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(label, Label("done"));
+
+    let alice = alice_endpoint.session().unwrap();
+    assert!(alice.is_complete());
+    assert_eq!(alice.steps(), 2);
+    assert!(bob_endpoint.session().unwrap().is_complete());
+}
+
+#[test]
+fn test_session_type_from_projection() {
+    use rumpsteak_choreography::compiler::{parser::parse_choreography_str, project};
+    use rumpsteak_choreography::effects::{SessionCursor, SessionType};
+
+    let choreo = parse_choreography_str(
+        r#"
+        choreography PingPong {
+            roles: Alice, Bob
+            loop (count: 2) {
+                Alice -> Bob: Ping
+                Bob -> Alice: Pong
+            }
+        }
+        "#,
+    )
+    .unwrap();
+    let alice = choreo.roles[0].clone();
+    let local = project(&choreo, &alice).unwrap();
+
+    let session =
+        SessionType::from_local_type(&local, &|role| match role.name.to_string().as_str() {
+            "Alice" => Some(TestRole::Alice),
+            "Bob" => Some(TestRole::Bob),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut cursor = SessionCursor::new(session);
+    for _ in 0..2 {
+        cursor.send(TestRole::Bob, "Ping").unwrap();
+        cursor.receive(TestRole::Bob, "Pong").unwrap();
+    }
+    assert!(cursor.is_complete());
+    assert!(cursor.send(TestRole::Bob, "Ping").is_err());
+}
+
+#[test]
+fn test_session_cursor_records_system_labels() {
+    use rumpsteak_choreography::effects::{SessionCursor, SessionType};
+    use rumpsteak_choreography::Label;
+
+    let session = SessionType::branch(
+        TestRole::Bob,
+        vec![(
+            "Go",
+            SessionType::send::<u32>(TestRole::Bob, SessionType::End),
+        )],
+    );
+    let mut cursor = SessionCursor::new(session.clone());
+
+    cursor.select(TestRole::Bob, "sys.heartbeat").unwrap();
+    cursor.branch(TestRole::Bob, "sys.resume").unwrap();
+    assert_eq!(cursor.current(), &session);
+
+    cursor.branch(TestRole::Bob, "sys.abort").unwrap();
+    assert!(cursor.is_complete());
+    assert_eq!(
+        cursor.system_labels(),
+        &[
+            (TestRole::Bob, Label::HEARTBEAT),
+            (TestRole::Bob, Label::RESUME),
+            (TestRole::Bob, Label::ABORT),
+        ]
+    );
+
+    // Only the reserved labels are exempt
+    let mut cursor = SessionCursor::new(session);
+    assert!(cursor.branch(TestRole::Bob, "sys.other").is_err());
+}
+
+#[tokio::test]
+async fn test_handshake_rejects_unapproved_choreography() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::effects::handshake;
+    use rumpsteak_choreography::{ChoreographyError, ChoreographyManifest, IdentityAuthority};
+
+    let approved = parse_choreography_str(
+        r#"
+        choreography PingPong {
+            roles: Alice, Bob
+            Alice -> Bob: Ping
+            Bob -> Alice: Pong
+        }
+        "#,
+    )
+    .unwrap();
+    let edited = parse_choreography_str(
+        r#"
+        choreography PingPong {
+            roles: Alice, Bob
+            Alice -> Bob: Ping
+        }
+        "#,
+    )
+    .unwrap();
+
+    let authority = IdentityAuthority::generate("release").unwrap();
+    let approver = authority.enroll("ReleaseManager", 1).unwrap();
+    let trust = authority.public_key();
+    let signed = ChoreographyManifest::new(&approved)
+        .with_metadata("revision", "abc123")
+        .sign(&approver)
+        .unwrap();
+    signed.verify_for(&approved, &trust).unwrap();
+    assert!(signed.verify_for(&edited, &trust).is_err());
+
+    // Both sides approved: the handshake succeeds
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    let (alice, bob) = tokio::join!(
+        handshake(
+            &mut alice_handler,
+            &mut alice_endpoint,
+            &[TestRole::Bob],
+            &signed,
+            &trust
+        ),
+        handshake(
+            &mut bob_handler,
+            &mut bob_endpoint,
+            &[TestRole::Alice],
+            &signed,
+            &trust
+        ),
+    );
+    alice.unwrap();
+    bob.unwrap();
+
+    // Bob runs an edited protocol that was never approved by this authority
+    let rogue = IdentityAuthority::generate("rogue").unwrap();
+    let bob_signed = ChoreographyManifest::new(&edited)
+        .sign(&rogue.enroll("Bob", 1).unwrap())
+        .unwrap();
+    let (alice, _) = tokio::join!(
+        handshake(
+            &mut alice_handler,
+            &mut alice_endpoint,
+            &[TestRole::Bob],
+            &signed,
+            &trust
+        ),
+        handshake(
+            &mut bob_handler,
+            &mut bob_endpoint,
+            &[TestRole::Alice],
+            &bob_signed,
+            &trust
+        ),
+    );
+    assert!(matches!(
+        alice,
+        Err(ChoreographyError::UnapprovedProtocol { ref role, .. }) if role == "Bob"
+    ));
+}
+
+#[test]
+fn test_introductions_catch_miswired_peers() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::effects::introduce;
+    use rumpsteak_choreography::{ChoreographyError, Introduction};
+
+    let ping_pong = parse_choreography_str(
+        r#"
+        choreography PingPong {
+            roles: Alice, Bob
+            Alice -> Bob: Ping
+            Bob -> Alice: Pong
+        }
+        "#,
+    )
+    .unwrap();
+    let edited = parse_choreography_str(
+        r#"
+        choreography PingPong {
+            roles: Alice, Bob
+            Alice -> Bob: Ping
+        }
+        "#,
+    )
+    .unwrap();
+
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut exchange = |alice: Introduction, bob: Introduction| {
+        let (alice_channel, bob_channel) = SimpleChannel::pair();
+        let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+        let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+        alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+        bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+        futures::executor::block_on(async {
+            futures::join!(
+                introduce(
+                    &mut alice_handler,
+                    &mut alice_endpoint,
+                    &[TestRole::Bob],
+                    &alice
+                ),
+                introduce(
+                    &mut bob_handler,
+                    &mut bob_endpoint,
+                    &[TestRole::Alice],
+                    &bob
+                ),
+            )
+        })
+    };
+    let alice_intro =
+        Introduction::new(&ping_pong, TestRole::Alice).with_codecs(["json", "bincode"]);
+
+    // Same build on both sides: they settle on the first codec both know
+    let (alice, bob) = exchange(
+        alice_intro.clone(),
+        Introduction::new(&ping_pong, TestRole::Bob),
+    );
+    assert_eq!(alice.unwrap()[&TestRole::Bob].codec, "bincode");
+    assert_eq!(bob.unwrap()[&TestRole::Alice].codec, "bincode");
+
+    // The process on Bob's channel was started as Charlie
+    let (alice, _) = exchange(
+        alice_intro.clone(),
+        Introduction {
+            role: "Charlie".to_string(),
+            ..Introduction::new(&ping_pong, TestRole::Bob)
+        },
+    );
+    assert!(matches!(alice, Err(ChoreographyError::UnknownRole(ref role)) if role == "Charlie"));
+
+    // Bob was built from an edited choreography
+    let (alice, _) = exchange(
+        alice_intro.clone(),
+        Introduction::new(&edited, TestRole::Bob),
+    );
+    assert!(matches!(
+        alice,
+        Err(ChoreographyError::VersionMismatch { ref role, .. }) if role == "Bob"
+    ));
+
+    // No codec in common
+    let bob_intro = Introduction::new(&ping_pong, TestRole::Bob).with_codecs(["cbor"]);
+    let err = alice_intro.check(TestRole::Bob, &bob_intro).unwrap_err();
+    assert!(err.to_string().contains("cbor"), "{}", err);
+
+    // Each side advertises its size limit and they keep the smaller one
+    let unlimited = Introduction::new(&ping_pong, TestRole::Bob);
+    assert_eq!(
+        alice_intro
+            .check(TestRole::Bob, &unlimited)
+            .unwrap()
+            .max_message_size,
+        None
+    );
+    let limited = unlimited.clone().with_max_message_size(4096);
+    assert_eq!(
+        alice_intro
+            .check(TestRole::Bob, &limited)
+            .unwrap()
+            .max_message_size,
+        Some(4096)
+    );
+    let (alice, bob) = exchange(alice_intro.clone().with_max_message_size(1024), limited);
+    assert_eq!(alice.unwrap()[&TestRole::Bob].max_message_size, Some(1024));
+    assert_eq!(bob.unwrap()[&TestRole::Alice].max_message_size, Some(1024));
+}
+
+#[tokio::test]
+async fn test_membership_spreads_by_gossip() {
+    use rumpsteak_choreography::Membership;
+    use std::time::Duration;
+
+    let alice_view = Membership::new(Duration::from_secs(10));
+    let bob_view = Membership::new(Duration::from_secs(10));
+    alice_view.join("Peers", TestRole::Alice);
+    bob_view.join("Peers", TestRole::Bob);
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    let (alice, bob) = tokio::join!(
+        alice_view.gossip(&mut alice_handler, &mut alice_endpoint, TestRole::Bob),
+        bob_view.gossip(&mut bob_handler, &mut bob_endpoint, TestRole::Alice),
+    );
+    assert_eq!(alice.unwrap(), 1);
+    assert_eq!(bob.unwrap(), 1);
+    for view in [&alice_view, &bob_view] {
+        assert!(view.is_live("Peers", &TestRole::Alice));
+        assert!(view.is_live("Peers", &TestRole::Bob));
+    }
+
+    // A departure spreads the same way
+    bob_view.leave("Peers", TestRole::Bob);
+    let (alice, bob) = tokio::join!(
+        alice_view.gossip(&mut alice_handler, &mut alice_endpoint, TestRole::Bob),
+        bob_view.gossip(&mut bob_handler, &mut bob_endpoint, TestRole::Alice),
+    );
+    assert_eq!(alice.unwrap(), 1);
+    assert_eq!(bob.unwrap(), 0);
+    assert_eq!(alice_view.live("Peers"), vec![TestRole::Alice]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_session_executor_runs_sessions_on_worker_pool() {
+    use rumpsteak_choreography::runtime::{ExecutorConfig, ExecutorError, SessionExecutor};
+
+    let executor = SessionExecutor::new(
+        ExecutorConfig::new()
+            .with_workers(2)
+            .with_sessions_per_worker(4),
+    );
+
+    let handles: Vec<_> = (0..200)
+        .map(|i| {
+            executor.submit(async move {
+                let (alice_channel, bob_channel) = SimpleChannel::pair();
+                let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+                let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+                alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+                bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+                let mut alice = RumpsteakHandler::<TestRole, TestMessage>::new();
+                let mut bob = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+                let msg = TestMessage {
+                    content: format!("session {}", i),
+                };
+                let (sent, received) = tokio::join!(
+                    alice.send(&mut alice_endpoint, TestRole::Bob, &msg),
+                    bob.recv::<TestMessage>(&mut bob_endpoint, TestRole::Alice),
+                );
+                sent.unwrap();
+                received.unwrap().content
+            })
+        })
+        .collect();
+    let failing = executor.submit(async { panic!("session bug") });
+
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.await.unwrap(), format!("session {}", i));
+    }
+    // A panicking session is reported on its handle and does not take the
+    // worker down with it
+    assert_eq!(failing.await, Err(ExecutorError::Panicked));
+    assert_eq!(executor.submit(async { 7 }).await, Ok(7));
+
+    let stats = executor.stats();
+    assert_eq!(stats.submitted, 202);
+    assert_eq!(stats.completed, 202);
+    assert_eq!(stats.queued, 0);
+    executor.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_upgrade_drains_old_sessions_before_reporting() {
+    use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+    use rumpsteak_choreography::compiler::project;
+    use rumpsteak_choreography::runtime::{
+        coordinate_upgrade, participate_in_upgrade, UpgradeCoordinator, UPGRADE_PROTOCOL,
+    };
+    use std::time::Duration;
+
+    // The shipped pattern is a valid choreography
+    let pattern = parse_choreography_str(UPGRADE_PROTOCOL).unwrap();
+    for role in &pattern.roles {
+        project(&pattern, role).unwrap();
+    }
+
+    let service = UpgradeCoordinator::new("v1");
+    let old_session = service.begin();
+    assert_eq!(old_session.version(), "v1");
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    let (committed, upgraded, ()) = tokio::join!(
+        coordinate_upgrade(&mut alice, &mut alice_endpoint, &[TestRole::Bob], "v2"),
+        participate_in_upgrade(&mut bob, &mut bob_endpoint, TestRole::Alice, &service),
+        async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // New sessions already run v2 while the v1 session drains
+            let progress = service.progress();
+            assert_eq!(progress.draining.as_deref(), Some("v1"));
+            assert_eq!(progress.remaining, 1);
+            assert_eq!(service.begin().version(), "v2");
+            assert_eq!(service.progress().started, 1);
+            drop(old_session);
+        },
+    );
+    assert!(committed.unwrap());
+    assert!(upgraded.unwrap());
+    assert_eq!(service.progress().draining, None);
+    assert_eq!(service.active_version(), "v2");
+
+    // A participant already on the requested version is not ready: abort
+    let (committed, upgraded) = tokio::join!(
+        coordinate_upgrade(&mut alice, &mut alice_endpoint, &[TestRole::Bob], "v2"),
+        participate_in_upgrade(&mut bob, &mut bob_endpoint, TestRole::Alice, &service),
+    );
+    assert!(!committed.unwrap());
+    assert!(!upgraded.unwrap());
+}
+
+/// Handler that sends every message twice when `duplicate` is set
+struct Duplicating {
+    inner: RumpsteakHandler<TestRole, TestMessage>,
+    duplicate: bool,
+}
+
+#[async_trait::async_trait]
+impl ChoreoHandler for Duplicating {
+    type Role = TestRole;
+    type Endpoint = RumpsteakEndpoint<TestRole>;
+
+    async fn send<M: Serialize + Send + Sync>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        to: TestRole,
+        msg: &M,
+    ) -> rumpsteak_choreography::effects::Result<()> {
+        self.inner.send(ep, to, msg).await?;
+        if self.duplicate {
+            self.inner.send(ep, to, msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv<M: serde::de::DeserializeOwned + Send>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: TestRole,
+    ) -> rumpsteak_choreography::effects::Result<M> {
+        self.inner.recv(ep, from).await
+    }
+
+    async fn choose(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        who: TestRole,
+        label: rumpsteak_choreography::effects::Label,
+    ) -> rumpsteak_choreography::effects::Result<()> {
+        self.inner.choose(ep, who, label).await
+    }
+
+    async fn offer(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        from: TestRole,
+    ) -> rumpsteak_choreography::effects::Result<rumpsteak_choreography::effects::Label> {
+        self.inner.offer(ep, from).await
+    }
+
+    async fn with_timeout<F, T>(
+        &mut self,
+        ep: &mut Self::Endpoint,
+        at: TestRole,
+        dur: std::time::Duration,
+        body: F,
+    ) -> rumpsteak_choreography::effects::Result<T>
+    where
+        F: std::future::Future<Output = rumpsteak_choreography::effects::Result<T>> + Send,
+    {
+        self.inner.with_timeout(ep, at, dur, body).await
+    }
+}
+
+fn connected_endpoints() -> Vec<RumpsteakEndpoint<TestRole>> {
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    vec![alice_endpoint, bob_endpoint]
+}
+
+#[tokio::test]
+async fn test_differential_run_catches_duplicated_messages() {
+    use rumpsteak_choreography::effects::{Program, TraceEvent};
+    use rumpsteak_choreography::{Differential, Divergence};
+
+    let message = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+    let differential = Differential::new()
+        .role(
+            TestRole::Alice,
+            Program::new()
+                .send(TestRole::Bob, message("first"))
+                .send(TestRole::Bob, message("second"))
+                .end(),
+        )
+        .role(
+            TestRole::Bob,
+            Program::new()
+                .recv::<TestMessage>(TestRole::Alice)
+                .recv::<TestMessage>(TestRole::Alice)
+                .end(),
+        );
+    let setup = |duplicate: bool| {
+        move |roles: &[TestRole]| {
+            assert_eq!(roles, [TestRole::Alice, TestRole::Bob]);
+            connected_endpoints()
+                .into_iter()
+                .map(|endpoint| {
+                    let handler = Duplicating {
+                        inner: RumpsteakHandler::new(),
+                        duplicate: duplicate && *endpoint.local_role() == TestRole::Alice,
+                    };
+                    (handler, endpoint)
+                })
+                .collect()
+        }
+    };
+
+    // Two sound transports agree
+    let trace = differential.run(setup(false)).await.unwrap();
+    assert!(trace.diff(&trace).is_empty());
+    let bob = trace.role(TestRole::Bob).unwrap();
+    assert_eq!(bob.received.len(), 2);
+    assert!(matches!(
+        &bob.events[0],
+        TraceEvent::Recv { from: TestRole::Alice, msg_type } if msg_type == "TestMessage"
+    ));
+    let divergences = differential
+        .compare(setup(false), setup(false))
+        .await
+        .unwrap();
+    assert!(divergences.is_empty(), "{:?}", divergences);
+
+    // A transport that duplicates messages hands Bob the first one twice
+    let divergences = differential
+        .compare(setup(false), setup(true))
+        .await
+        .unwrap();
+    assert_eq!(divergences.len(), 1, "{:?}", divergences);
+    assert!(matches!(
+        &divergences[0],
+        Divergence::Received { role: TestRole::Bob, index: 1, left: Some(left), right: Some(right) }
+            if left["content"] == "second" && right["content"] == "first"
+    ));
+    assert!(divergences[0]
+        .to_string()
+        .starts_with("Bob received value 1"));
+}
+
+#[tokio::test]
+async fn test_stub_role_replays_recorded_external_service() {
+    use rumpsteak_choreography::effects::{ChoreographyError, Label, SessionType, TraceRecorder};
+    use rumpsteak_choreography::StubRole;
+
+    // Bob stands for an external payment service
+    let bob_session = SessionType::branch(
+        TestRole::Alice,
+        vec![
+            (
+                "pay",
+                SessionType::receive::<TestMessage>(
+                    TestRole::Alice,
+                    SessionType::send::<TestMessage>(TestRole::Alice, SessionType::End),
+                ),
+            ),
+            ("cancel", SessionType::End),
+        ],
+    );
+    let message = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+
+    async fn alice(
+        endpoint: &mut RumpsteakEndpoint<TestRole>,
+        label: &'static str,
+    ) -> rumpsteak_choreography::effects::Result<Option<TestMessage>> {
+        let mut handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+        handler
+            .choose(endpoint, TestRole::Bob, Label(label))
+            .await?;
+        if label == "cancel" {
+            return Ok(None);
+        }
+        let charge = TestMessage {
+            content: "charge 10".to_string(),
+        };
+        handler.send(endpoint, TestRole::Bob, &charge).await?;
+        Ok(Some(handler.recv(endpoint, TestRole::Bob).await?))
+    }
+
+    // Record the real service once
+    let mut endpoints = connected_endpoints();
+    let mut bob_endpoint = endpoints.pop().unwrap();
+    let mut alice_endpoint = endpoints.pop().unwrap();
+    let mut recorder = TraceRecorder::new(RumpsteakHandler::<TestRole, TestMessage>::new());
+    let real_bob = async {
+        recorder.offer(&mut bob_endpoint, TestRole::Alice).await?;
+        let charge: TestMessage = recorder.recv(&mut bob_endpoint, TestRole::Alice).await?;
+        let receipt = message(&format!("receipt for {}", charge.content));
+        recorder
+            .send(&mut bob_endpoint, TestRole::Alice, &receipt)
+            .await
+    };
+    let (receipt, recorded) = tokio::join!(alice(&mut alice_endpoint, "pay"), real_bob);
+    recorded.unwrap();
+    assert_eq!(receipt.unwrap(), Some(message("receipt for charge 10")));
+
+    let stub = StubRole::<TestRole, TestMessage>::from_recorder(TestRole::Bob, &recorder);
+    stub.check_against(&bob_session).unwrap();
+    let stub: StubRole<TestRole, TestMessage> =
+        StubRole::from_json(&stub.to_json().unwrap()).unwrap();
+
+    // The stub plays Bob without the real service
+    let mut endpoints = connected_endpoints();
+    let mut bob_endpoint = endpoints.pop().unwrap();
+    let mut alice_endpoint = endpoints.pop().unwrap();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let (receipt, served) = tokio::join!(
+        alice(&mut alice_endpoint, "pay"),
+        stub.serve(&mut bob_handler, &mut bob_endpoint, &bob_session),
+    );
+    served.unwrap();
+    assert_eq!(receipt.unwrap(), Some(message("receipt for charge 10")));
+
+    // A branch that was never recorded is reported, not guessed
+    let mut endpoints = connected_endpoints();
+    let mut bob_endpoint = endpoints.pop().unwrap();
+    let mut alice_endpoint = endpoints.pop().unwrap();
+    let (_, served) = tokio::join!(
+        alice(&mut alice_endpoint, "cancel"),
+        stub.serve(&mut bob_handler, &mut bob_endpoint, &bob_session),
+    );
+    assert!(matches!(
+        served,
+        Err(ChoreographyError::ProtocolViolation(_))
+    ));
+
+    // A recording that no longer fits the protocol is rejected up front
+    let changed = SessionType::branch(
+        TestRole::Alice,
+        vec![(
+            "pay",
+            SessionType::receive::<TestMessage>(TestRole::Alice, SessionType::End),
+        )],
+    );
+    assert!(matches!(
+        stub.check_against(&changed),
+        Err(ChoreographyError::SessionMismatch { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_cancellation_tears_down_channels() {
+    use rumpsteak_choreography::effects::{interpret, interpret_with_cancel, Label, Program};
+    use rumpsteak_choreography::{CancellationToken, InterpreterState};
+    use std::time::Duration;
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    // Bob waits for a message Alice never sends, until his caller gives up
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        canceller.cancel();
+    });
+    let waiting = Program::<TestRole, TestMessage>::new().recv::<TestMessage>(TestRole::Alice);
+    let result = interpret_with_cancel(&mut bob_handler, &mut bob_endpoint, waiting, &token)
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Cancelled);
+    assert!(bob_endpoint.is_all_closed());
+
+    // With Bob's side gone, Alice fails instead of waiting forever
+    let reply = alice_handler
+        .recv::<TestMessage>(&mut alice_endpoint, TestRole::Bob)
+        .await;
+    assert!(reply.is_err());
+
+    // A cancel effect tells the peer, whose offer then ends the session
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let cancelling = Program::<TestRole, TestMessage>::new()
+        .cancel(vec![TestRole::Alice])
+        .end();
+    let result = interpret(&mut bob_handler, &mut bob_endpoint, cancelling)
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Cancelled);
+    let offering = Program::<TestRole, TestMessage>::new()
+        .offer_branches(TestRole::Bob, vec![(Label("go"), Program::new())])
+        .end();
+    let result = interpret(&mut alice_handler, &mut alice_endpoint, offering)
+        .await
+        .unwrap();
+    match result.final_state {
+        InterpreterState::Failed(msg) => assert!(msg.contains("sys.cancel"), "{}", msg),
+        other => panic!("expected the offer to end the session, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_cancellation_runs_pending_cleanups() {
+    use rumpsteak_choreography::effects::{interpret_with_cancel, Program};
+    use rumpsteak_choreography::{CancellationToken, InterpreterState};
+    use std::time::Duration;
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    let message = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+    // Bob is cancelled while waiting inside two finally blocks
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        canceller.cancel();
+    });
+    let waiting = Program::<TestRole, TestMessage>::new()
+        .with_finally(
+            Program::new().with_finally(
+                Program::new().recv::<TestMessage>(TestRole::Alice),
+                Program::new().send(TestRole::Alice, message("inner")),
+            ),
+            Program::new().send(TestRole::Alice, message("outer")),
+        )
+        .end();
+    let result = interpret_with_cancel(&mut bob_handler, &mut bob_endpoint, waiting, &token)
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Cancelled);
+
+    // Both cleanups ran, innermost first
+    for expected in ["inner", "outer"] {
+        let received = alice_handler
+            .recv::<TestMessage>(&mut alice_endpoint, TestRole::Bob)
+            .await
+            .unwrap();
+        assert_eq!(received, message(expected));
+    }
+}
+
+#[tokio::test]
+async fn test_cancellation_after_timed_out_finally() {
+    use rumpsteak_choreography::effects::{interpret_with_cancel, Program};
+    use rumpsteak_choreography::{CancellationToken, InterpreterState};
+    use std::time::Duration;
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    let message = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+    // Bob's finally body times out, and he is cancelled while waiting
+    // after it, inside another finally
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        canceller.cancel();
+    });
+    let waiting = Program::<TestRole, TestMessage>::new()
+        .with_timeout_else(
+            TestRole::Bob,
+            Duration::from_millis(20),
+            Program::new().with_finally(
+                Program::new().recv::<TestMessage>(TestRole::Alice),
+                Program::new().send(TestRole::Alice, message("timed out")),
+            ),
+            Program::new(),
+        )
+        .with_finally(
+            Program::new().recv::<TestMessage>(TestRole::Alice),
+            Program::new().send(TestRole::Alice, message("cancelled")),
+        )
+        .end();
+    let result = interpret_with_cancel(&mut bob_handler, &mut bob_endpoint, waiting, &token)
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Cancelled);
+
+    // The cleanup of the timed-out body ran once, when it was dropped
+    for expected in ["timed out", "cancelled"] {
+        let received = alice_handler
+            .recv::<TestMessage>(&mut alice_endpoint, TestRole::Bob)
+            .await
+            .unwrap();
+        assert_eq!(received, message(expected));
+    }
+    let rest = alice_handler
+        .recv::<TestMessage>(&mut alice_endpoint, TestRole::Bob)
+        .await;
+    assert!(rest.is_err());
+}
+
+#[tokio::test]
+async fn test_compensation_after_peer_failure() {
+    use rumpsteak_choreography::effects::{interpret, Label, Program};
+    use rumpsteak_choreography::{Computation, InterpreterState};
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let msg = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+
+    let alice = Program::new()
+        .try_catch(
+            Program::new()
+                .send(TestRole::Bob, msg("order"))
+                .offer_branches(TestRole::Bob, vec![(Label("confirmed"), Program::new())]),
+            vec![TestRole::Bob],
+            Program::new().recv::<TestMessage>(TestRole::Bob),
+        )
+        .end();
+    // Bob fails building his reply from a value he never bound
+    let bob = Program::new()
+        .try_catch(
+            Program::new()
+                .recv::<TestMessage>(TestRole::Alice)
+                .send_with(
+                    TestRole::Alice,
+                    "quote",
+                    Computation::new("price", Clone::clone),
+                ),
+            vec![TestRole::Alice],
+            Program::new().send(TestRole::Alice, msg("refund")),
+        )
+        .end();
+
+    let (alice_result, bob_result) = tokio::join!(
+        interpret(&mut alice_handler, &mut alice_endpoint, alice),
+        interpret(&mut bob_handler, &mut bob_endpoint, bob),
+    );
+    let (alice_result, bob_result) = (alice_result.unwrap(), bob_result.unwrap());
+    assert_eq!(bob_result.final_state, InterpreterState::Completed);
+    assert_eq!(alice_result.final_state, InterpreterState::Completed);
+    assert_eq!(alice_result.received_values, vec![msg("refund")]);
+}
+
+#[tokio::test]
+async fn test_timeout_else_aborts_the_peer() {
+    use rumpsteak_choreography::effects::{interpret, Label, Program};
+    use rumpsteak_choreography::InterpreterState;
+    use std::time::Duration;
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let msg = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+
+    // Alice waits for a reply Bob never sends, then gives up
+    let alice = Program::new()
+        .with_timeout_else(
+            TestRole::Alice,
+            Duration::from_millis(20),
+            Program::new().recv::<TestMessage>(TestRole::Bob),
+            Program::new()
+                .choose(TestRole::Bob, Label::ABORT)
+                .send(TestRole::Bob, msg("given up")),
+        )
+        .end();
+    let bob = Program::<TestRole, TestMessage>::new()
+        .try_catch(
+            Program::new().offer_branches(TestRole::Alice, vec![(Label("go"), Program::new())]),
+            vec![],
+            Program::new().recv::<TestMessage>(TestRole::Alice),
+        )
+        .end();
+
+    let (alice_result, bob_result) = tokio::join!(
+        interpret(&mut alice_handler, &mut alice_endpoint, alice),
+        interpret(&mut bob_handler, &mut bob_endpoint, bob),
+    );
+    let (alice_result, bob_result) = (alice_result.unwrap(), bob_result.unwrap());
+    assert_eq!(alice_result.final_state, InterpreterState::Completed);
+    assert_eq!(bob_result.final_state, InterpreterState::Completed);
+    assert_eq!(bob_result.received_values, vec![msg("given up")]);
+}
+
+#[tokio::test]
+async fn test_timeout_runs_cleanups_of_dropped_finally() {
+    use rumpsteak_choreography::effects::{interpret, Program};
+    use rumpsteak_choreography::InterpreterState;
+    use std::time::Duration;
+
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+    let msg = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+    let waiting = || {
+        Program::new().with_finally(
+            Program::new().recv::<TestMessage>(TestRole::Alice),
+            Program::new().send(TestRole::Alice, msg("cleanup")),
+        )
+    };
+
+    // The cleanup of the dropped body runs before the else branch
+    let bob = Program::<TestRole, TestMessage>::new()
+        .with_timeout_else(
+            TestRole::Bob,
+            Duration::from_millis(20),
+            waiting(),
+            Program::new().send(TestRole::Alice, msg("else")),
+        )
+        .end();
+    let result = interpret(&mut bob_handler, &mut bob_endpoint, bob)
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Completed);
+    for expected in ["cleanup", "else"] {
+        let received = alice_handler
+            .recv::<TestMessage>(&mut alice_endpoint, TestRole::Bob)
+            .await
+            .unwrap();
+        assert_eq!(received, msg(expected));
+    }
+
+    // And before the timeout is reported, with no else branch
+    let bob = Program::<TestRole, TestMessage>::new()
+        .with_timeout(TestRole::Bob, Duration::from_millis(20), waiting())
+        .end();
+    let result = interpret(&mut bob_handler, &mut bob_endpoint, bob)
+        .await
+        .unwrap();
+    assert_eq!(result.final_state, InterpreterState::Timeout);
+    let received = alice_handler
+        .recv::<TestMessage>(&mut alice_endpoint, TestRole::Bob)
+        .await
+        .unwrap();
+    assert_eq!(received, msg("cleanup"));
+}
+
+#[tokio::test]
+async fn test_signed_labels_and_order_are_checked() {
+    use rumpsteak_choreography::effects::Label;
+    use rumpsteak_choreography::{ChoreographyError, Signed};
+
+    // Everything Alice sends Bob passes through a relay
+    let (alice_channel, mut relay_alice) = SimpleChannel::pair();
+    let (mut relay_bob, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    // It replays Alice's first message, then turns her "buy" into "sell"
+    let buy = bincode::serialize("buy").unwrap();
+    let sell = bincode::serialize("sell").unwrap();
+    tokio::spawn(async move {
+        let first = relay_alice.recv().await.unwrap();
+        relay_bob.send(first.clone()).await.unwrap();
+        relay_bob.send(first).await.unwrap();
+        while let Ok(bytes) = relay_alice.recv().await {
+            let bytes = if bytes == buy { sell.clone() } else { bytes };
+            relay_bob.send(bytes).await.unwrap();
+        }
+    });
+
+    let mut alice = Signed::new(
+        RumpsteakHandler::<TestRole, TestMessage>::new(),
+        TestRole::Alice,
+    )
+    .with_key(TestRole::Bob, b"alice-bob".to_vec());
+    let mut bob = Signed::new(
+        RumpsteakHandler::<TestRole, TestMessage>::new(),
+        TestRole::Bob,
+    )
+    .with_key(TestRole::Alice, b"alice-bob".to_vec());
+
+    let hello = TestMessage {
+        content: "hello".to_string(),
+    };
+    alice
+        .send(&mut alice_endpoint, TestRole::Bob, &hello)
+        .await
+        .unwrap();
+    let received: TestMessage = bob.recv(&mut bob_endpoint, TestRole::Alice).await.unwrap();
+    assert_eq!(received, hello);
+    match bob
+        .recv::<TestMessage>(&mut bob_endpoint, TestRole::Alice)
+        .await
+    {
+        Err(ChoreographyError::IntegrityViolation { role, reason }) => {
+            assert_eq!(role, "Alice");
+            assert_eq!(reason, "message tag does not match");
+        }
+        other => panic!("expected the replay to be rejected, got {:?}", other),
+    }
+
+    // Labels the relay leaves alone are taken, and the one it changes is not
+    alice
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("browse"))
+        .await
+        .unwrap();
+    let label = bob.offer(&mut bob_endpoint, TestRole::Alice).await.unwrap();
+    assert_eq!(label, Label("browse"));
+    alice
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("buy"))
+        .await
+        .unwrap();
+    match bob.offer(&mut bob_endpoint, TestRole::Alice).await {
+        Err(ChoreographyError::IntegrityViolation { role, reason }) => {
+            assert_eq!(role, "Alice");
+            assert_eq!(reason, "label tag does not match");
+        }
+        other => panic!("expected an integrity violation, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_encrypted_labels_are_checked() {
+    use rumpsteak_choreography::effects::Label;
+    use rumpsteak_choreography::{ChoreographyError, Encrypted, IdentityAuthority};
+
+    // Everything between Alice and Bob passes through a relay
+    let (alice_channel, mut relay_alice) = SimpleChannel::pair();
+    let (mut relay_bob, bob_channel) = SimpleChannel::pair();
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+    // It passes the key offers on, then turns Alice's "buy" into "sell"
+    let buy = bincode::serialize("buy").unwrap();
+    let sell = bincode::serialize("sell").unwrap();
+    tokio::spawn(async move {
+        let offer = relay_alice.recv().await.unwrap();
+        relay_bob.send(offer).await.unwrap();
+        let offer = relay_bob.recv().await.unwrap();
+        relay_alice.send(offer).await.unwrap();
+        while let Ok(bytes) = relay_alice.recv().await {
+            let bytes = if bytes == buy { sell.clone() } else { bytes };
+            relay_bob.send(bytes).await.unwrap();
+        }
+    });
+
+    let authority = IdentityAuthority::generate("ops").unwrap();
+    let mut alice = Encrypted::new(
+        RumpsteakHandler::<TestRole, TestMessage>::new().with_max_message_size(4096),
+        authority.enroll("Alice", 1).unwrap(),
+        authority.public_key(),
+    )
+    .with_peers([TestRole::Bob]);
+    let mut bob = Encrypted::new(
+        RumpsteakHandler::<TestRole, TestMessage>::new(),
+        authority.enroll("Bob", 1).unwrap(),
+        authority.public_key(),
+    )
+    .with_peers([TestRole::Alice]);
+    let (alice_keys, bob_keys) = tokio::join!(
+        alice.exchange_keys(&mut alice_endpoint),
+        bob.exchange_keys(&mut bob_endpoint)
+    );
+    alice_keys.unwrap();
+    bob_keys.unwrap();
+
+    // A message that fails to go out does not throw the peer's count off
+    let message = |content: String| TestMessage { content };
+    let oversized = alice
+        .send(
+            &mut alice_endpoint,
+            TestRole::Bob,
+            &message("x".repeat(8192)),
+        )
+        .await;
+    assert!(oversized.is_err());
+    alice
+        .send(&mut alice_endpoint, TestRole::Bob, &message("hello".into()))
+        .await
+        .unwrap();
+    let received: TestMessage = bob.recv(&mut bob_endpoint, TestRole::Alice).await.unwrap();
+    assert_eq!(received, message("hello".into()));
+
+    // Labels the relay leaves alone are taken, and the one it changes is not
+    alice
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("browse"))
+        .await
+        .unwrap();
+    let label = bob.offer(&mut bob_endpoint, TestRole::Alice).await.unwrap();
+    assert_eq!(label, Label("browse"));
+    alice
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("buy"))
+        .await
+        .unwrap();
+    match bob.offer(&mut bob_endpoint, TestRole::Alice).await {
+        Err(ChoreographyError::IntegrityViolation { role, reason }) => {
+            assert_eq!(role, "Alice");
+            assert_eq!(reason, "label does not match its sealed copy");
+        }
+        other => panic!("expected an integrity violation, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_differential_run_rejects_swapped_handlers() {
+    use rumpsteak_choreography::effects::{InMemoryHandler, Program};
+    use rumpsteak_choreography::{ChoreographyError, Differential};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    enum Desk {
+        Buyer,
+        Seller,
+        Broker,
+    }
+
+    let message = |content: &str| TestMessage {
+        content: content.to_string(),
+    };
+    let differential = Differential::new()
+        .role(
+            Desk::Buyer,
+            Program::new().send(Desk::Broker, message("bid")).end(),
+        )
+        .role(
+            Desk::Seller,
+            Program::new().send(Desk::Broker, message("ask")).end(),
+        )
+        .role(
+            Desk::Broker,
+            Program::new()
+                .recv::<TestMessage>(Desk::Buyer)
+                .recv::<TestMessage>(Desk::Seller)
+                .end(),
+        );
+    let setup = |order: [Desk; 3]| {
+        move |_: &[Desk]| {
+            let channels = Arc::new(Mutex::new(HashMap::new()));
+            let choices = Arc::new(Mutex::new(HashMap::new()));
+            order
+                .into_iter()
+                .map(|desk| {
+                    let handler =
+                        InMemoryHandler::with_channels(desk, channels.clone(), choices.clone());
+                    (handler, ())
+                })
+                .collect()
+        }
+    };
+
+    let trace = differential
+        .run(setup([Desk::Buyer, Desk::Seller, Desk::Broker]))
+        .await
+        .unwrap();
+    assert_eq!(trace.role(Desk::Broker).unwrap().received.len(), 2);
+
+    // A copy-paste slip gives the buyer's program the seller's handler
+    match differential
+        .run(setup([Desk::Seller, Desk::Buyer, Desk::Broker]))
+        .await
+    {
+        Err(ChoreographyError::ProtocolViolation(problem)) => assert_eq!(
+            problem,
+            "Broker received a message from Seller on its link from Buyer: the handler or \
+             endpoint built for Buyer was given to Seller"
+        ),
+        other => panic!("expected the swap to be reported, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_labels_travel_as_their_wire_ids() {
+    use rumpsteak_choreography::{Label, WireIds};
+
+    const IDS: WireIds = WireIds(&[("Accept", 7), ("Reject", 8)]);
+
+    let mut alice_endpoint = RumpsteakEndpoint::new(TestRole::Alice);
+    let mut bob_endpoint = RumpsteakEndpoint::new(TestRole::Bob);
+    let (alice_channel, bob_channel) = SimpleChannel::pair();
+    alice_endpoint.register_channel(TestRole::Bob, alice_channel);
+    bob_endpoint.register_channel(TestRole::Alice, bob_channel);
+
+    let mut alice_handler = RumpsteakHandler::<TestRole, TestMessage>::new().with_label_ids(IDS);
+    let mut bob_handler = RumpsteakHandler::<TestRole, TestMessage>::new().with_label_ids(IDS);
+    let mut plain_handler = RumpsteakHandler::<TestRole, TestMessage>::new();
+
+    alice_handler
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("Accept"))
+        .await
+        .unwrap();
+    let label = bob_handler
+        .offer(&mut bob_endpoint, TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(label, Label("Accept"));
+
+    // A peer without the ids sees only the number
+    alice_handler
+        .choose(&mut alice_endpoint, TestRole::Bob, Label("Reject"))
+        .await
+        .unwrap();
+    let label = plain_handler
+        .offer(&mut bob_endpoint, TestRole::Alice)
+        .await
+        .unwrap();
+    assert_eq!(label.0, "8");
+
+    // Labels without an id, such as the runtime's, still go by name
+    assert_eq!(IDS.encode(Label::ABORT), "sys.abort");
+    assert_eq!(IDS.decode("sys.abort".to_string()), Label::ABORT);
+    assert_eq!(IDS.decode("Reject".to_string()), Label("Reject"));
+}
@@ -0,0 +1,157 @@
+// Integration tests for persistent session stores
+
+use rumpsteak_choreography::effects::ChoreographyError;
+use rumpsteak_choreography::runtime::{
+    Checkpoint, CheckpointStore, InMemorySessionStore, JournalEntry, SessionId, SessionManager,
+    SessionRecord, SessionStatus, SessionStore,
+};
+
+/// What every store must do, whatever keeps its records
+async fn check_store<S: SessionStore>(store: &S) {
+    let id = SessionId::new("order-1");
+    assert_eq!(store.load(&id).await.unwrap(), None);
+
+    let mut record = SessionRecord::new(id.clone());
+    assert_eq!(store.save(&record).await.unwrap(), 1);
+    // A second process creating the same session loses
+    assert!(matches!(
+        store.save(&record).await,
+        Err(ChoreographyError::StaleSession {
+            expected: 0,
+            found: 1,
+            ..
+        })
+    ));
+
+    record = store.load(&id).await.unwrap().unwrap();
+    assert_eq!(record.version, 1);
+    record.status = SessionStatus::Failed("no stock".to_string());
+    record.checkpoint = Some("{}".to_string());
+    let resumed = record.clone();
+    assert_eq!(store.save(&record).await.unwrap(), 2);
+    assert!(matches!(
+        store.save(&resumed).await,
+        Err(ChoreographyError::StaleSession {
+            expected: 1,
+            found: 2,
+            ..
+        })
+    ));
+
+    let stored = store.load(&id).await.unwrap().unwrap();
+    assert_eq!(stored.status, SessionStatus::Failed("no stock".to_string()));
+    assert_eq!(stored.checkpoint.as_deref(), Some("{}"));
+    assert_eq!(stored.version, 2);
+    assert_eq!(store.sessions().await.unwrap(), vec![stored]);
+}
+
+#[tokio::test]
+async fn test_in_memory_store_versions_saves() {
+    let store = InMemorySessionStore::new();
+    check_store(&store).await;
+    assert_eq!(store.len(), 1);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn test_sqlite_store_versions_saves() {
+    use rumpsteak_choreography::runtime::SqliteSessionStore;
+
+    // Each connection to an in-memory database gets a database of its own
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let store = SqliteSessionStore::new(pool);
+    store.create_table().await.unwrap();
+    store.create_table().await.unwrap();
+    check_store(&store).await;
+}
+
+#[tokio::test]
+async fn test_only_one_resumption_of_a_checkpoint_is_saved() {
+    let store = InMemorySessionStore::new();
+    let id = SessionId::new("order-2");
+    let mut checkpoint = Checkpoint::<String>::new(id.clone());
+    let mut duplicate = checkpoint.clone();
+    store.save_checkpoint(&mut checkpoint).await.unwrap();
+    // A new session is created once
+    duplicate.journal.push(JournalEntry::Sent {
+        to: "Warehouse".to_string(),
+    });
+    assert!(store.save_checkpoint(&mut duplicate).await.is_err());
+
+    let mut first: Checkpoint<String> = store.load_checkpoint(&id).await.unwrap().unwrap();
+    let mut second = first.clone();
+    assert_eq!(first.version, 1);
+
+    first.journal.push(JournalEntry::Sent {
+        to: "Warehouse".to_string(),
+    });
+    store.save_checkpoint(&mut first).await.unwrap();
+    second.journal.push(JournalEntry::Offered {
+        from: "Client".to_string(),
+        label: "cancel".to_string(),
+    });
+    assert!(matches!(
+        store.save_checkpoint(&mut second).await,
+        Err(ChoreographyError::StaleSession { .. })
+    ));
+
+    let stored: Checkpoint<String> = store.load_checkpoint(&id).await.unwrap().unwrap();
+    assert_eq!(stored.journal, first.journal);
+    assert_eq!(stored.version, 2);
+}
+
+#[tokio::test]
+async fn test_a_saved_checkpoint_can_be_saved_again() {
+    let store = InMemorySessionStore::new();
+    let id = SessionId::new("order-4");
+    let mut checkpoint = Checkpoint::<String>::new(id.clone());
+    for to in ["Warehouse", "Courier"] {
+        checkpoint
+            .journal
+            .push(JournalEntry::Sent { to: to.to_string() });
+        store.save_checkpoint(&mut checkpoint).await.unwrap();
+    }
+    assert_eq!(checkpoint.version, 2);
+
+    let stored: Checkpoint<String> = store.load_checkpoint(&id).await.unwrap().unwrap();
+    assert_eq!(stored.journal, checkpoint.journal);
+    assert_eq!(stored.version, 2);
+}
+
+#[tokio::test]
+async fn test_manager_persists_and_restores_sessions() {
+    let store = InMemorySessionStore::new();
+    let root = SessionId::new("order-3");
+
+    let mut checkpoint = Checkpoint::<String>::new(root.clone());
+    store.save_checkpoint(&mut checkpoint).await.unwrap();
+
+    let manager = SessionManager::new();
+    manager.open(root.clone());
+    let audit = manager.spawn(&root, "audit", |_| async { Ok(()) });
+    let child = audit.id().clone();
+    audit.await.unwrap();
+    manager.persist(&store).await.unwrap();
+
+    // The checkpoint survives, and a finished session is not reopened
+    let record = store.load(&root).await.unwrap().unwrap();
+    assert!(record.checkpoint.is_some());
+    let reopened = SessionManager::new();
+    reopened.open(child.clone());
+    reopened.persist(&store).await.unwrap();
+    let stored = store.load(&child).await.unwrap().unwrap();
+    assert_eq!(stored.status, SessionStatus::Completed);
+    assert_eq!(stored.parent, Some(root.clone()));
+
+    let restored = SessionManager::new();
+    assert_eq!(restored.restore(&store).await.unwrap(), 2);
+    assert_eq!(restored.status(&root), Some(SessionStatus::Running));
+    assert_eq!(restored.children(&root), vec![child]);
+    let next = restored.spawn(&root, "audit", |_| async { Ok(()) });
+    assert_eq!(next.id(), &root.child("audit", 1));
+    next.await.unwrap();
+}
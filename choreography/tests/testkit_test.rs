@@ -0,0 +1,225 @@
+// Integration tests for wire-compatibility test kits
+
+use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+use rumpsteak_choreography::compiler::{generate_test_kit, KitStep, TestKit};
+use rumpsteak_choreography::effects::{
+    ChoreoHandler, ChoreographyError, KitDriver, WebSocketEndpoint, WebSocketHandler,
+};
+use rumpsteak_choreography::{interpret, Label, Program, Role as DslRole};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const LOOKUP: &str = r#"
+choreography Lookup {
+    roles: Client, Server, Audit
+
+    Client -> Server: Query(key: String, limit: u32)
+
+    choice Server {
+        found: {
+            Server -> Client: Hit(u64)
+            Server -> Audit: Logged
+        }
+        missing: {
+            Server -> Client: Miss
+        }
+    }
+}
+"#;
+
+fn client_kit() -> TestKit {
+    let choreography = parse_choreography_str(LOOKUP).unwrap();
+    let client = DslRole::new(quote::format_ident!("Client"));
+    generate_test_kit(&choreography, &client).unwrap()
+}
+
+fn send(peer: &str, message: &str) -> KitStep {
+    KitStep::Send {
+        peer: peer.into(),
+        message: message.into(),
+    }
+}
+
+fn recv(peer: &str, message: &str) -> KitStep {
+    KitStep::Recv {
+        peer: peer.into(),
+        message: message.into(),
+    }
+}
+
+fn choose(peer: &str, label: &str) -> KitStep {
+    KitStep::Choose {
+        peer: peer.into(),
+        label: label.into(),
+    }
+}
+
+fn offer(peer: &str, label: &str) -> KitStep {
+    KitStep::Offer {
+        peer: peer.into(),
+        label: label.into(),
+    }
+}
+
+#[test]
+fn test_kit_lists_messages_and_sequences() {
+    let kit = client_kit();
+    assert_eq!(kit.roles, ["Client", "Server", "Audit"]);
+    assert_eq!(kit.peers(), ["Server"]);
+
+    // Messages are numbered as in the generated enum, sorted by name
+    let names: Vec<_> = kit.messages.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, ["Hit", "Logged", "Miss", "Query"]);
+    let query = kit.message("Query").unwrap();
+    assert_eq!(query.index, 3);
+    assert_eq!(query.sample, serde_json::json!({ "key": "", "limit": 0 }));
+    assert_eq!(
+        query.sample_hex.as_deref(),
+        Some("03000000000000000000000000000000")
+    );
+
+    assert_eq!(
+        kit.sequences("Client").unwrap().traces,
+        vec![
+            vec![
+                send("Server", "Query"),
+                offer("Server", "found"),
+                recv("Server", "Hit")
+            ],
+            vec![
+                send("Server", "Query"),
+                offer("Server", "missing"),
+                recv("Server", "Miss")
+            ],
+        ]
+    );
+    assert_eq!(
+        kit.sequences("Server").unwrap().traces[0],
+        vec![
+            recv("Client", "Query"),
+            choose("Client", "found"),
+            choose("Audit", "found"),
+            send("Client", "Hit"),
+            send("Audit", "Logged"),
+        ]
+    );
+    assert_eq!(
+        kit.sequences("Audit").unwrap().traces,
+        vec![
+            vec![offer("Server", "found"), recv("Server", "Logged")],
+            vec![offer("Server", "missing")],
+        ]
+    );
+}
+
+#[test]
+fn test_kit_round_trips_through_its_files() {
+    let kit = client_kit();
+    let dir = tempfile::tempdir().unwrap();
+    kit.write_to(dir.path()).unwrap();
+
+    for file in [
+        "README.md",
+        "messages.json",
+        "sequences/Audit.json",
+        "driver/Cargo.toml",
+        "driver/src/main.rs",
+    ] {
+        assert!(dir.path().join(file).exists(), "{} was not written", file);
+    }
+    assert_eq!(TestKit::read_from(dir.path()).unwrap(), kit);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Query {
+    key: String,
+    limit: u32,
+}
+
+/// The generated `Message` enum of `Lookup`, as the client would write it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Hit(u64),
+    Logged(String),
+    Miss(String),
+    Query(Query),
+}
+
+/// Start the driver on a loopback port and connect the client to it
+async fn driven_client(
+    kit: TestKit,
+) -> (
+    WebSocketEndpoint<Role>,
+    tokio::task::JoinHandle<
+        rumpsteak_choreography::Result<rumpsteak_choreography::effects::KitReport>,
+    >,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/Server", listener.local_addr().unwrap());
+    let driver = tokio::spawn(
+        KitDriver::new(kit)
+            .with_step_timeout(Duration::from_secs(5))
+            .run(listener),
+    );
+    let mut endpoint = WebSocketEndpoint::new(Role::Client);
+    endpoint.connect(Role::Server, &url).await.unwrap();
+    (endpoint, driver)
+}
+
+#[tokio::test]
+async fn test_driver_accepts_a_conforming_implementation() {
+    let (mut endpoint, driver) = driven_client(client_kit()).await;
+
+    let program = Program::new()
+        .send(
+            Role::Server,
+            Message::Query(Query {
+                key: "alice".into(),
+                limit: 10,
+            }),
+        )
+        .offer_branches(
+            Role::Server,
+            vec![
+                (Label("found"), Program::new().recv::<u64>(Role::Server)),
+                (
+                    Label("missing"),
+                    Program::new().recv::<String>(Role::Server),
+                ),
+            ],
+        )
+        .end();
+    let result = interpret(&mut WebSocketHandler::new(), &mut endpoint, program)
+        .await
+        .unwrap();
+    assert_eq!(result.received_values, vec![Message::Hit(0)]);
+
+    let report = driver.await.unwrap().unwrap();
+    assert_eq!(report.steps["Server"], 5);
+    assert_eq!(report.steps["Audit"], 2);
+}
+
+#[tokio::test]
+async fn test_driver_reports_the_first_unexpected_step() {
+    let (mut endpoint, driver) = driven_client(client_kit()).await;
+
+    WebSocketHandler::new()
+        .send(&mut endpoint, Role::Server, &Message::Miss(String::new()))
+        .await
+        .unwrap();
+
+    match driver.await.unwrap() {
+        Err(ChoreographyError::ProtocolViolation(message)) => assert_eq!(
+            message,
+            "Server step 0: expected <- Client: Query, got <- Client: Miss"
+        ),
+        other => panic!("expected a protocol violation, got {:?}", other),
+    }
+}
@@ -0,0 +1,133 @@
+// Integration tests for the mutual TLS handler
+#![cfg(feature = "tls")]
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+use rumpsteak_choreography::{
+    ChoreoHandler, ChoreographyError, Label, TlsConfig, TlsEndpoint, TlsHandler,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Alice,
+    Bob,
+    Carol,
+}
+
+struct Authority {
+    certificate: Certificate,
+    key: KeyPair,
+}
+
+impl Authority {
+    fn new() -> Self {
+        let (certificate, key) = issue("Test CA", None);
+        Self { certificate, key }
+    }
+
+    /// A config for a certificate issued to `name`
+    fn config(&self, name: &str) -> TlsConfig {
+        self.config_trusting(name, self)
+    }
+
+    /// A config for a certificate issued to `name` by this authority, for a
+    /// role that checks its peers against `trusted`
+    fn config_trusting(&self, name: &str, trusted: &Authority) -> TlsConfig {
+        let (certificate, key) = issue(name, Some(self));
+        TlsConfig::from_pem(
+            certificate.pem().as_bytes(),
+            key.serialize_pem().as_bytes(),
+            trusted.certificate.pem().as_bytes(),
+        )
+        .unwrap()
+    }
+}
+
+fn issue(name: &str, issuer: Option<&Authority>) -> (Certificate, KeyPair) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, name);
+    let certificate = match issuer {
+        Some(authority) => params
+            .signed_by(&key, &authority.certificate, &authority.key)
+            .unwrap(),
+        None => {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.self_signed(&key).unwrap()
+        }
+    };
+    (certificate, key)
+}
+
+#[tokio::test]
+async fn test_tls_roles_exchange_messages_and_labels() {
+    let ca = Authority::new();
+    let mut alice = TlsEndpoint::new(Role::Alice, ca.config("Alice")).unwrap();
+    let mut bob = TlsEndpoint::new(Role::Bob, ca.config("Bob")).unwrap();
+
+    // Any byte stream will do; a duplex pipe stands in for TCP
+    let (alice_side, bob_side) = tokio::io::duplex(4096);
+    let (accepted, connected) = tokio::join!(
+        alice.accept(Role::Bob, alice_side),
+        bob.connect(Role::Alice, bob_side)
+    );
+    accepted.unwrap();
+    connected.unwrap();
+
+    let mut handler = TlsHandler::new();
+    let big = "x".repeat(100_000);
+    handler.send(&mut bob, Role::Alice, &big).await.unwrap();
+    handler
+        .choose(&mut alice, Role::Alice, Label("accept"))
+        .await
+        .unwrap();
+    let received: String = handler.recv(&mut alice, Role::Bob).await.unwrap();
+    assert_eq!(received, big);
+    let label = handler.offer(&mut bob, Role::Alice).await.unwrap();
+    assert_eq!(label.0, "accept");
+}
+
+#[tokio::test]
+async fn test_tls_rejects_a_peer_with_another_roles_certificate() {
+    let ca = Authority::new();
+    // Carol answers a connection Bob meant for Alice
+    let mut carol = TlsEndpoint::new(Role::Carol, ca.config("Carol")).unwrap();
+    let mut bob = TlsEndpoint::new(Role::Bob, ca.config("Bob")).unwrap();
+
+    let (carol_side, bob_side) = tokio::io::duplex(4096);
+    let (_, connected) = tokio::join!(
+        carol.accept(Role::Bob, carol_side),
+        bob.connect(Role::Alice, bob_side)
+    );
+    match connected {
+        Err(ChoreographyError::AuthenticationFailed { role, reason }) => {
+            assert_eq!(role, "Alice");
+            assert_eq!(reason, "certificate is issued to \"Carol\"");
+        }
+        other => panic!("expected Carol to be refused, got {:?}", other.err()),
+    }
+    assert!(!bob.is_connected(&Role::Alice));
+}
+
+#[tokio::test]
+async fn test_tls_rejects_certificates_from_another_authority() {
+    let ca = Authority::new();
+    let rogue = Authority::new();
+    let mut alice = TlsEndpoint::new(Role::Alice, ca.config("Alice")).unwrap();
+    let mut bob = TlsEndpoint::new(Role::Bob, rogue.config_trusting("Bob", &ca)).unwrap();
+
+    let (alice_side, bob_side) = tokio::io::duplex(4096);
+    let (accepted, _) = tokio::join!(
+        alice.accept(Role::Bob, alice_side),
+        bob.connect(Role::Alice, bob_side)
+    );
+    assert!(matches!(
+        accepted,
+        Err(ChoreographyError::AuthenticationFailed { ref role, .. }) if role == "Bob"
+    ));
+
+    // An endpoint also refuses a certificate issued to another role
+    assert!(matches!(
+        TlsEndpoint::new(Role::Alice, ca.config("Bob")),
+        Err(ChoreographyError::AuthenticationFailed { .. })
+    ));
+}
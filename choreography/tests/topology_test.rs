@@ -0,0 +1,194 @@
+// Tests for the ring and pipeline topology combinators
+
+use quote::format_ident;
+use rumpsteak_choreography::ast::topology::{self, Termination, TopologyError};
+use rumpsteak_choreography::ast::{protocol::Condition, LocalType, MessageType, Protocol, Role};
+use rumpsteak_choreography::compiler::projection::project;
+
+fn roles(names: &[&str]) -> Vec<Role> {
+    names
+        .iter()
+        .map(|name| Role::new(format_ident!("{}", name)))
+        .collect()
+}
+
+fn message(name: &str) -> MessageType {
+    MessageType {
+        name: format_ident!("{}", name),
+        type_annotation: None,
+        payload: None,
+    }
+}
+
+/// (from, to, message) of each send, following continuations
+fn sends(mut protocol: &Protocol) -> Vec<(String, String, String)> {
+    let mut sends = Vec::new();
+    while let Protocol::Send {
+        from,
+        to,
+        message,
+        continuation,
+    } = protocol
+    {
+        sends.push((from.to_string(), to.to_string(), message.name.to_string()));
+        protocol = continuation;
+    }
+    sends
+}
+
+fn hop(from: &str, to: &str, message: &str) -> (String, String, String) {
+    (from.to_string(), to.to_string(), message.to_string())
+}
+
+#[test]
+fn test_ring_passes_each_hop_message_around() {
+    let choreo = topology::ring(roles(&["A", "B", "C"]), message("Add"))
+        .with_hop(2, message("Sub"))
+        .build(format_ident!("Ring"))
+        .unwrap();
+    choreo.validate().unwrap();
+
+    assert_eq!(
+        sends(&choreo.protocol),
+        [
+            hop("A", "B", "Add"),
+            hop("B", "C", "Add"),
+            hop("C", "A", "Sub")
+        ]
+    );
+
+    let a = project(&choreo, &choreo.roles[0]).unwrap();
+    match a {
+        LocalType::Send {
+            to, continuation, ..
+        } => {
+            assert_eq!(to.to_string(), "B");
+            assert!(matches!(
+                *continuation,
+                LocalType::Receive { ref from, ref message, .. }
+                    if from.to_string() == "C" && message.name == "Sub"
+            ));
+        }
+        other => panic!("Expected A to send first, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_pipeline_repeats_for_a_number_of_rounds() {
+    let choreo = topology::pipeline(
+        roles(&["Source", "Filter", "Sink"]),
+        [message("Raw"), message("Clean")],
+    )
+    .with_termination(Termination::Rounds(3))
+    .build(format_ident!("Pipeline"))
+    .unwrap();
+    choreo.validate().unwrap();
+
+    match &choreo.protocol {
+        Protocol::Loop { condition, body } => {
+            assert_eq!(condition, &Some(Condition::Count(3)));
+            assert_eq!(
+                sends(body),
+                [
+                    hop("Source", "Filter", "Raw"),
+                    hop("Filter", "Sink", "Clean")
+                ]
+            );
+        }
+        other => panic!("Expected a loop, got: {:?}", other),
+    }
+
+    let sink = project(&choreo, &choreo.roles[2]).unwrap();
+    assert!(matches!(sink, LocalType::Loop { .. }), "got: {:?}", sink);
+}
+
+#[test]
+fn test_ring_forever_recurses_after_each_round() {
+    let choreo = topology::ring(roles(&["A", "B"]), message("Token"))
+        .with_termination(Termination::Forever)
+        .build(format_ident!("Ring"))
+        .unwrap();
+    choreo.validate().unwrap();
+
+    match &choreo.protocol {
+        Protocol::Rec { label, body } => {
+            assert_eq!(sends(body).len(), 2);
+            let mut last = &**body;
+            while let Protocol::Send { continuation, .. } = last {
+                last = continuation;
+            }
+            assert!(matches!(last, Protocol::Var(var) if var == label));
+        }
+        other => panic!("Expected rec, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_ring_until_stop_tells_every_role() {
+    let choreo = topology::ring(roles(&["A", "B", "C"]), message("Token"))
+        .with_termination(Termination::Until(message("Done")))
+        .build(format_ident!("Ring"))
+        .unwrap();
+    choreo.validate().unwrap();
+
+    match project(&choreo, &choreo.roles[0]).unwrap() {
+        LocalType::Rec { body, .. } => match *body {
+            LocalType::Select { to, branches } => {
+                assert_eq!(to.to_string(), "B");
+                let labels: Vec<_> = branches.iter().map(|(l, _)| l.to_string()).collect();
+                assert_eq!(labels, ["Continue", "Stop"]);
+            }
+            other => panic!("Expected A to select, got: {:?}", other),
+        },
+        other => panic!("Expected rec, got: {:?}", other),
+    }
+
+    // C is told which way the round went by the message B passes on
+    match project(&choreo, &choreo.roles[2]).unwrap() {
+        LocalType::Rec { body, .. } => {
+            assert!(
+                matches!(*body, LocalType::Branch { ref from, .. } if from.to_string() == "B"),
+                "got: {:?}",
+                body
+            );
+        }
+        other => panic!("Expected rec, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_invalid_topologies_are_rejected() {
+    assert_eq!(
+        topology::ring(roles(&["A"]), message("Token"))
+            .protocol()
+            .unwrap_err(),
+        TopologyError::TooFewRoles {
+            shape: "ring",
+            count: 1
+        }
+    );
+    assert_eq!(
+        topology::ring(roles(&["A", "B", "A"]), message("Token"))
+            .protocol()
+            .unwrap_err(),
+        TopologyError::DuplicateRole("A".to_string())
+    );
+    assert_eq!(
+        topology::pipeline(roles(&["A", "B", "C"]), [message("Raw")])
+            .protocol()
+            .unwrap_err(),
+        TopologyError::HopCount {
+            shape: "pipeline",
+            roles: 3,
+            expected: 2,
+            count: 1
+        }
+    );
+    assert_eq!(
+        topology::ring(roles(&["A", "B"]), message("Token"))
+            .with_termination(Termination::Until(message("Token")))
+            .protocol()
+            .unwrap_err(),
+        TopologyError::AmbiguousStop("Token".to_string())
+    );
+}
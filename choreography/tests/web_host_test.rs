@@ -0,0 +1,226 @@
+// Integration tests for hosting a role behind stateless requests
+
+use rumpsteak_choreography::effects::{ChoreographyError, RecordedEvent, RecordingHandler};
+use rumpsteak_choreography::runtime::{
+    Checkpoint, CheckpointStore, InMemorySessionStore, SessionId, SessionStatus, WebHost,
+    WebMessage, WebRequest,
+};
+use rumpsteak_choreography::{Label, Program};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Role {
+    Client,
+    Shop,
+    Warehouse,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Order { item: String },
+    Reserve,
+    Quote(u32),
+    Pay,
+    Receipt(String),
+}
+
+/// The shop quotes an order, reserving stock with the warehouse, and takes
+/// payment if the client accepts
+fn shop() -> WebHost<Role, Message, InMemorySessionStore> {
+    let program = Program::new()
+        .recv::<Message>(Role::Client)
+        .send(Role::Warehouse, Message::Reserve)
+        .send(Role::Client, Message::Quote(12))
+        .offer_branches(
+            Role::Client,
+            vec![
+                (
+                    Label("accept"),
+                    Program::new()
+                        .recv::<Message>(Role::Client)
+                        .send(Role::Client, Message::Receipt("R-1".to_string())),
+                ),
+                (Label("reject"), Program::new()),
+            ],
+        )
+        .end();
+    WebHost::new(Role::Client, program, InMemorySessionStore::new())
+}
+
+type Events = Arc<Mutex<Vec<RecordedEvent<Role>>>>;
+
+/// A fresh backend handler per request, with its sends visible afterwards
+fn backend() -> (RecordingHandler<Role>, Events) {
+    let handler = RecordingHandler::new(Role::Shop);
+    let events = Arc::clone(&handler.events);
+    (handler, events)
+}
+
+fn message(value: serde_json::Value) -> Option<WebMessage> {
+    Some(WebMessage::Message(value))
+}
+
+#[tokio::test]
+async fn test_requests_advance_a_checkpointed_session() {
+    let host = shop();
+
+    let (handler, events) = backend();
+    let first = host
+        .handle(
+            handler,
+            &mut (),
+            WebRequest {
+                session: None,
+                input: message(json!({ "Order": { "item": "tea" } })),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(first.status, SessionStatus::Running);
+    assert_eq!(
+        first.outputs,
+        vec![WebMessage::Message(json!({ "Quote": 12 }))]
+    );
+    assert_eq!(events.lock().unwrap().len(), 1);
+
+    // Replaying the first request does not reserve stock again
+    let (handler, events) = backend();
+    let second = host
+        .handle(
+            handler,
+            &mut (),
+            WebRequest {
+                session: Some(first.session.clone()),
+                input: Some(WebMessage::Label("accept".to_string())),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(second.status, SessionStatus::Running);
+    assert!(second.outputs.is_empty());
+    assert!(events.lock().unwrap().is_empty());
+
+    let (handler, _) = backend();
+    let third = host
+        .handle(
+            handler,
+            &mut (),
+            WebRequest {
+                session: Some(first.session.clone()),
+                input: message(json!("Pay")),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(third.status, SessionStatus::Completed);
+    assert_eq!(
+        third.outputs,
+        vec![WebMessage::Message(json!({ "Receipt": "R-1" }))]
+    );
+
+    let checkpoint: Checkpoint<Role> = host
+        .store()
+        .load_checkpoint(&first.session)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(checkpoint.status, SessionStatus::Completed);
+    assert_eq!(checkpoint.journal.len(), 6);
+
+    let (handler, _) = backend();
+    let result = host
+        .handle(
+            handler,
+            &mut (),
+            WebRequest {
+                session: Some(first.session),
+                input: None,
+            },
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::ProtocolViolation(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_bad_requests_leave_the_session_untouched() {
+    let host = shop();
+
+    let (handler, _) = backend();
+    let result = host
+        .handle(
+            handler,
+            &mut (),
+            WebRequest {
+                session: Some(SessionId::new("missing")),
+                input: None,
+            },
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::ProtocolViolation(_))
+    ));
+
+    // Without an order the session starts and waits for one
+    let (handler, _) = backend();
+    let started = host
+        .handle(handler, &mut (), WebRequest::default())
+        .await
+        .unwrap();
+    assert_eq!(started.status, SessionStatus::Running);
+    assert!(started.outputs.is_empty());
+
+    // A label where the order belongs, and an order that does not decode
+    for input in [
+        Some(WebMessage::Label("accept".to_string())),
+        message(json!({ "Order": { "amount": 3 } })),
+    ] {
+        let (handler, _) = backend();
+        let result = host
+            .handle(
+                handler,
+                &mut (),
+                WebRequest {
+                    session: Some(started.session.clone()),
+                    input,
+                },
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(ChoreographyError::ProtocolViolation(_))
+        ));
+    }
+    let checkpoint: Checkpoint<Role> = host
+        .store()
+        .load_checkpoint(&started.session)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(checkpoint.journal.is_empty());
+    assert!(checkpoint.is_running());
+
+    let (handler, _) = backend();
+    let quoted = host
+        .handle(
+            handler,
+            &mut (),
+            WebRequest {
+                session: Some(started.session.clone()),
+                input: message(json!({ "Order": { "item": "tea" } })),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(quoted.outputs.len(), 1);
+
+    let request: WebRequest =
+        serde_json::from_str(&format!(r#"{{ "session": "{}" }}"#, started.session)).unwrap();
+    assert_eq!(request.session, Some(started.session));
+    assert_eq!(request.input, None);
+}
@@ -0,0 +1,125 @@
+// Integration tests for WebSocketHandler over loopback sockets
+
+use rumpsteak_choreography::effects::{
+    ChoreoHandler, ChoreographyError, Label, WebSocketEndpoint, WebSocketHandler,
+};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Browser,
+    Server,
+}
+
+/// A server endpoint and a client endpoint connected to it
+async fn connected() -> (WebSocketEndpoint<Role>, WebSocketEndpoint<Role>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ep = WebSocketEndpoint::new(Role::Server);
+        ep.accept(Role::Browser, stream).await.unwrap();
+        ep
+    });
+    let mut browser = WebSocketEndpoint::new(Role::Browser);
+    browser.connect(Role::Server, &url).await.unwrap();
+    (browser, server.await.unwrap())
+}
+
+#[tokio::test]
+async fn test_messages_and_choices_round_trip() {
+    let (mut browser, mut server) = connected().await;
+    let mut browser_handler = WebSocketHandler::new();
+    let mut server_handler = WebSocketHandler::new();
+
+    browser_handler
+        .send(&mut browser, Role::Server, &("login".to_string(), 42u32))
+        .await
+        .unwrap();
+    let request: (String, u32) = server_handler
+        .recv(&mut server, Role::Browser)
+        .await
+        .unwrap();
+    assert_eq!(request, ("login".to_string(), 42));
+
+    server_handler
+        .choose(&mut server, Role::Server, Label("accept"))
+        .await
+        .unwrap();
+    let label = browser_handler
+        .offer(&mut browser, Role::Server)
+        .await
+        .unwrap();
+    assert_eq!(label, Label("accept"));
+}
+
+#[tokio::test]
+async fn test_label_where_message_expected_is_a_violation() {
+    let (mut browser, mut server) = connected().await;
+    let mut handler = WebSocketHandler::new();
+
+    handler
+        .choose(&mut browser, Role::Browser, Label("quit"))
+        .await
+        .unwrap();
+    let result: Result<u32, _> = handler.recv(&mut server, Role::Browser).await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::ProtocolViolation(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_timeout_and_closed_peer() {
+    let (mut browser, mut server) = connected().await;
+    let mut handler = WebSocketHandler::new();
+
+    let result: Result<u32, _> = handler
+        .with_timeout(
+            &mut server,
+            Role::Server,
+            Duration::from_millis(50),
+            async { futures::future::pending().await },
+        )
+        .await;
+    assert!(matches!(result, Err(ChoreographyError::Timeout(_))));
+
+    assert!(browser.disconnect(&Role::Server));
+    let result: Result<u32, _> = handler.recv(&mut server, Role::Browser).await;
+    assert!(matches!(result, Err(ChoreographyError::Transport(_))));
+
+    let result = handler.send(&mut browser, Role::Server, &1u32).await;
+    assert!(matches!(result, Err(ChoreographyError::Transport(_))));
+}
+
+#[tokio::test]
+async fn test_system_labels_at_receive_points() {
+    let (mut browser, mut server) = connected().await;
+    let mut handler = WebSocketHandler::new();
+
+    handler
+        .choose(&mut browser, Role::Browser, Label::HEARTBEAT)
+        .await
+        .unwrap();
+    handler
+        .send(&mut browser, Role::Server, &7u32)
+        .await
+        .unwrap();
+    let value: u32 = handler.recv(&mut server, Role::Browser).await.unwrap();
+    assert_eq!(value, 7);
+
+    handler
+        .choose(&mut browser, Role::Browser, Label::CANCEL)
+        .await
+        .unwrap();
+    let result: Result<u32, _> = handler.recv(&mut server, Role::Browser).await;
+    assert!(matches!(
+        result,
+        Err(ChoreographyError::Aborted {
+            label: "sys.cancel",
+            ..
+        })
+    ));
+}
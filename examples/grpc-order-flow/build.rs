@@ -0,0 +1,23 @@
+// Generates the `.proto` and the tonic glue of `order_flow.choreo`, and
+// compiles the `.proto` with tonic
+
+use rumpsteak_choreography::compiler::parser::parse_choreography_str;
+use rumpsteak_choreography::compiler::{generate_grpc_glue, generate_grpc_proto};
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=order_flow.choreo");
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let source = std::fs::read_to_string("order_flow.choreo").unwrap();
+    let choreography = parse_choreography_str(&source).unwrap();
+
+    let proto = out.join("order_flow.proto");
+    std::fs::write(&proto, generate_grpc_proto(&choreography)).unwrap();
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_prost_build::configure()
+        .compile_protos(&[&proto], &[&out])
+        .unwrap();
+
+    let file: syn::File = syn::parse2(generate_grpc_glue(&choreography)).unwrap();
+    std::fs::write(out.join("order_flow_grpc.rs"), prettyplease::unparse(&file)).unwrap();
+}
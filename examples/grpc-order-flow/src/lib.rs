@@ -0,0 +1,18 @@
+// The tonic glue `generate_grpc_glue` emits for `order_flow.choreo`,
+// compiled against the prost types of the `.proto` generated next to it
+
+pub mod proto {
+    tonic::include_proto!("order_flow");
+}
+
+/// The roles of the choreography, as `generate_effects_protocol` names them
+pub mod order_flow {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum Role {
+        Buyer,
+        Seller,
+        Shipper,
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/order_flow_grpc.rs"));
@@ -0,0 +1,112 @@
+// Runs the generated glue over TCP
+
+use grpc_order_flow::order_flow::Role;
+use grpc_order_flow::order_flow_grpc::{connect_buyer, SellerService, ShipperService};
+use grpc_order_flow::proto::{seller_server::SellerServer, shipper_server::ShipperServer};
+use rumpsteak_choreography::{ChoreoHandler, ChoreographyError, GrpcEndpoint, GrpcHandler};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Endpoint, Server};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Order {
+    item: String,
+}
+
+async fn listen() -> (TcpListenerStream, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (TcpListenerStream::new(listener), addr)
+}
+
+fn endpoint(addr: SocketAddr) -> Endpoint {
+    Endpoint::from_shared(format!("http://{}", addr)).unwrap()
+}
+
+#[tokio::test]
+async fn test_buyer_reaches_seller() {
+    let mut seller = GrpcEndpoint::new(Role::Seller);
+    let (incoming, addr) = listen().await;
+    let service = SellerServer::new(SellerService::new(seller.acceptor()));
+    tokio::spawn(
+        Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming),
+    );
+
+    let mut buyer = GrpcEndpoint::new(Role::Buyer);
+    connect_buyer(&mut buyer, |_| endpoint(addr)).await.unwrap();
+
+    let mut handler = GrpcHandler::new();
+    let order = Order {
+        item: "tea".to_string(),
+    };
+    handler
+        .send(&mut buyer, Role::Seller, &order)
+        .await
+        .unwrap();
+    let received: Order = handler.recv(&mut seller, Role::Buyer).await.unwrap();
+    assert_eq!(received, order);
+}
+
+#[tokio::test]
+async fn test_unreachable_peer_fails_to_connect() {
+    let (incoming, addr) = listen().await;
+    drop(incoming);
+
+    let mut buyer = GrpcEndpoint::new(Role::Buyer);
+    match connect_buyer(&mut buyer, |_| endpoint(addr)).await {
+        Err(ChoreographyError::Transport(message)) => {
+            assert!(
+                message.starts_with("gRPC connection to Seller failed"),
+                "{}",
+                message
+            )
+        }
+        other => panic!("expected a connection failure, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_failed_call_is_reported_to_the_endpoint() {
+    // The server at the seller's address does not serve `Seller`
+    let shipper = GrpcEndpoint::new(Role::Shipper);
+    let (incoming, addr) = listen().await;
+    let service = ShipperServer::new(ShipperService::new(shipper.acceptor()));
+    tokio::spawn(
+        Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming),
+    );
+
+    let mut buyer = GrpcEndpoint::new(Role::Buyer);
+    connect_buyer(&mut buyer, |_| endpoint(addr)).await.unwrap();
+
+    let mut handler = GrpcHandler::new();
+    let order = Order {
+        item: "tea".to_string(),
+    };
+    let failure = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match handler.send(&mut buyer, Role::Seller, &order).await {
+                Ok(()) => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(e) => return e,
+            }
+        }
+    })
+    .await
+    .unwrap();
+    match failure {
+        ChoreographyError::Transport(message) => {
+            assert!(
+                message.starts_with("gRPC call to Seller failed"),
+                "{}",
+                message
+            )
+        }
+        other => panic!("expected the call's failure, got {:?}", other),
+    }
+}
@@ -25,6 +25,9 @@ pub fn choreography(input: TokenStream) -> Result<TokenStream> {
     // Otherwise, fall back to syn-based parsing
     let protocol: ProtocolDef = syn::parse2(input)?;
 
+    // Generated items share one module, so their names must not clash
+    check_collisions(&protocol)?;
+
     // Generate role structs
     let role_structs = generate_role_structs(&protocol);
 
@@ -43,15 +46,108 @@ pub fn choreography(input: TokenStream) -> Result<TokenStream> {
         use ::futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
     };
 
+    // Each protocol gets its own module so that several protocols can live
+    // side by side, with prefixed re-exports of the generic names
+    let name = &protocol.name;
+    let module = quote::format_ident!("{}", snake_case(&name.to_string()));
+    let label_alias = quote::format_ident!("{}Label", name);
+    let roles_alias = quote::format_ident!("{}Roles", name);
+
     Ok(quote! {
-        #imports
-        #role_structs
-        #message_types
-        #session_types
-        #setup_fn
+        pub mod #module {
+            use super::*;
+            #imports
+            #role_structs
+            #message_types
+            #session_types
+            #setup_fn
+        }
+
+        pub use #module::{Label as #label_alias, Roles as #roles_alias};
     })
 }
 
+/// Names the macro defines in every protocol module
+const RESERVED: &[&str] = &["Label", "Roles", "Channel"];
+
+/// Reject message names that clash with roles, generated items, or each other
+fn check_collisions(protocol: &ProtocolDef) -> Result<()> {
+    let mut payloads: Vec<(&Ident, String)> = Vec::new();
+
+    for interaction in &protocol.interactions {
+        let Interaction::Send {
+            message, payload, ..
+        } = interaction
+        else {
+            continue;
+        };
+
+        if protocol.roles.iter().any(|r| r.name == *message) {
+            return Err(Error::new(
+                message.span(),
+                format!(
+                    "message `{}` has the same name as a role in protocol `{}`",
+                    message, protocol.name
+                ),
+            ));
+        }
+        let as_session = protocol
+            .roles
+            .iter()
+            .any(|r| *message == format!("{}Session", r.name));
+        if RESERVED.iter().any(|r| message == r) || as_session {
+            return Err(Error::new(
+                message.span(),
+                format!(
+                    "message `{}` clashes with an item generated for protocol `{}`",
+                    message, protocol.name
+                ),
+            ));
+        }
+
+        let payload = payload
+            .as_ref()
+            .as_ref()
+            .map(|ty| quote!(#ty).to_string())
+            .unwrap_or_default();
+        match payloads.iter().find(|(name, _)| *name == message) {
+            Some((_, seen)) if *seen != payload => {
+                return Err(Error::new(
+                    message.span(),
+                    format!(
+                        "message `{}` is used with different payload types in protocol `{}`",
+                        message, protocol.name
+                    ),
+                ));
+            }
+            Some(_) => {}
+            None => payloads.push((message, payload)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Module name for a protocol: `PingPong` becomes `ping_pong`
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_uppercase() && next_is_lower)
+            {
+                out.push('_');
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
 /// Parse choreography from DSL string
 fn choreography_from_dsl_string(_dsl: String) -> Result<TokenStream> {
     // This is a placeholder implementation
@@ -237,13 +333,15 @@ fn generate_role_structs(protocol: &ProtocolDef) -> TokenStream {
 fn generate_message_types(protocol: &ProtocolDef) -> TokenStream {
     let mut messages = Vec::new();
 
-    // Extract messages from interactions
+    // Extract messages from interactions, once per name
     for interaction in &protocol.interactions {
         if let Interaction::Send {
             message, payload, ..
         } = interaction
         {
-            messages.push((message, payload.as_ref().as_ref()));
+            if !messages.iter().any(|(name, _)| *name == message) {
+                messages.push((message, payload.as_ref().as_ref()));
+            }
         }
     }
 
@@ -98,6 +98,11 @@ pub fn session(attr: TokenStream, input: TokenStream) -> TokenStream {
 /// Generates role definitions, message types, and session types from a
 /// choreographic protocol specification.
 ///
+/// Items are generated inside a module named after the protocol in snake
+/// case (`simple` below), with `Label` and `Roles` re-exported as
+/// `SimpleLabel` and `SimpleRoles`. Message names that clash with roles or
+/// generated items are rejected at compile time.
+///
 /// # Example
 ///
 /// ```rust,ignore